@@ -0,0 +1,121 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! Compare the cost of waiting for a pin level to change when each check of the pin
+//! is a dynamic call versus when waiting for each transition is a single dynamic call
+//! to `DataPin::wait_while_level`.
+//!
+//! Run with `cargo bench --bench pin_wait`.
+
+use std::cell::Cell;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use strudel::sensor::{DataPin, Level, PinMode};
+
+/// Cycles a pin stays at each level, about what a DHT22 produces on a Raspberry PI
+const CYCLES_PER_LEVEL: u32 = 500;
+/// Number of low/high transitions in a single read of a DHT22
+const TRANSITIONS_PER_READ: u32 = 82;
+const READS: u32 = 2_000;
+
+/// Pin that is low for `CYCLES_PER_LEVEL` checks, then high for the same, and so on
+#[derive(Default)]
+struct SimulatedPin {
+    checks: Cell<u32>,
+}
+
+impl SimulatedPin {
+    fn level(&self) -> Level {
+        let n = self.checks.get();
+        self.checks.set(n.wrapping_add(1));
+        if (n / CYCLES_PER_LEVEL) % 2 == 1 {
+            Level::High
+        } else {
+            Level::Low
+        }
+    }
+}
+
+impl DataPin for SimulatedPin {
+    fn is_low(&self) -> bool {
+        self.level() == Level::Low
+    }
+
+    fn is_high(&self) -> bool {
+        self.level() == Level::High
+    }
+
+    fn pin(&self) -> u8 {
+        0
+    }
+
+    fn set_high(&mut self) {}
+
+    fn set_low(&mut self) {}
+
+    fn set_mode(&mut self, _mode: PinMode) {}
+}
+
+/// Wait for each transition by calling `is_low` and `is_high` through the trait object
+/// for every check, the way sensor reads were implemented before `wait_while_level`.
+#[inline(never)]
+fn per_check(pin: &dyn DataPin) -> Duration {
+    let start = Instant::now();
+    for _ in 0..READS {
+        for i in 0..TRANSITIONS_PER_READ {
+            let mut cycles = 0u32;
+            if i % 2 == 0 {
+                while pin.is_low() {
+                    cycles += 1;
+                }
+            } else {
+                while pin.is_high() {
+                    cycles += 1;
+                }
+            }
+
+            black_box(cycles);
+        }
+    }
+
+    start.elapsed()
+}
+
+/// Wait for each transition with a single call to `wait_while_level` through the
+/// trait object.
+#[inline(never)]
+fn per_transition(pin: &dyn DataPin) -> Duration {
+    let start = Instant::now();
+    for _ in 0..READS {
+        for i in 0..TRANSITIONS_PER_READ {
+            let level = if i % 2 == 0 { Level::Low } else { Level::High };
+            let _ = black_box(pin.wait_while_level(level, u32::MAX));
+        }
+    }
+
+    start.elapsed()
+}
+
+fn main() {
+    // Hide the concrete type of the pins so that calls can't be devirtualized
+    let before = per_check(black_box(&SimulatedPin::default()));
+    let after = per_transition(black_box(&SimulatedPin::default()));
+
+    println!("dynamic call per check: {:?} per read", before / READS);
+    println!("dynamic call per transition: {:?} per read", after / READS);
+}
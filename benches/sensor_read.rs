@@ -0,0 +1,96 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! Compare the cost of reading a `DHT22Sensor` using a concrete pin type versus a
+//! boxed pin (`DynDHT22Sensor`). Since the boxed pin only adds a dynamic call for each
+//! transition of the pin, not each check of it, the difference is expected to be small.
+//!
+//! Run with `cargo bench --bench sensor_read`.
+
+use std::hint::black_box;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+use strudel::sensor::{DHT22Sensor, DataPin, DynDHT22Sensor, PinMode};
+
+/// Cycles a pin stays at each level, about what a DHT22 produces on a Raspberry PI
+const CYCLES_PER_LEVEL: u32 = 500;
+const READS: u32 = 2_000;
+
+/// Pin that is low for `CYCLES_PER_LEVEL` checks, then high for the same, and so on
+#[derive(Default)]
+struct SimulatedPin {
+    checks: AtomicU32,
+}
+
+impl SimulatedPin {
+    fn is_level(&self, low: bool) -> bool {
+        let n = self.checks.fetch_add(1, Ordering::Relaxed);
+        let high = (n / CYCLES_PER_LEVEL) % 2 == 1;
+        high != low
+    }
+}
+
+impl DataPin for SimulatedPin {
+    fn is_low(&self) -> bool {
+        self.is_level(true)
+    }
+
+    fn is_high(&self) -> bool {
+        self.is_level(false)
+    }
+
+    fn pin(&self) -> u8 {
+        0
+    }
+
+    fn set_high(&mut self) {}
+
+    fn set_low(&mut self) {}
+
+    fn set_mode(&mut self, _mode: PinMode) {}
+}
+
+fn run<P: DataPin>(mut sensor: DHT22Sensor<P>) -> Duration {
+    let start = Instant::now();
+    for _ in 0..READS {
+        // Reads fail since the pin doesn't produce valid data, only the time matters
+        let _ = black_box(sensor.read());
+    }
+
+    start.elapsed()
+}
+
+fn main() {
+    let generic = DHT22Sensor::builder(SimulatedPin::default())
+        .wake_high_ms(0)
+        .start_low_ms(0)
+        .start_high_us(0)
+        .build();
+
+    let boxed = DynDHT22Sensor::builder(Box::new(SimulatedPin::default()))
+        .wake_high_ms(0)
+        .start_low_ms(0)
+        .start_high_us(0)
+        .build();
+
+    let generic = run(generic);
+    let boxed = run(boxed);
+
+    println!("generic pin: {:?} per read", generic / READS);
+    println!("boxed pin: {:?} per read", boxed / READS);
+}
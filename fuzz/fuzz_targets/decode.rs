@@ -0,0 +1,60 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! Decode arbitrary cycle counts as a DHT22 read to make sure decoding never panics, no
+//! matter how noisy the pulses on the data pin are.
+//!
+//! The first byte of the input picks how bits are decoded. When it's odd, timing calibration
+//! is used with the number of cycles per microsecond in the next 8 bytes (a little endian
+//! `f64`). Otherwise bits are decoded relative to the average low pulse. The rest of the input
+//! is the cycle counts, 4 little endian bytes each, and counts without enough bytes are 0.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use strudel::sensor::{decode_counts, TimingCalibration};
+
+/// Low and high pulses of the response to the start signal and of each of the 40 data bits
+const COUNTS: usize = 82;
+
+fuzz_target!(|data: &[u8]| {
+    let (timing, data) = match data.split_first() {
+        Some((strategy, rest)) if strategy % 2 == 1 && rest.len() >= 8 => {
+            let (cycles, rest) = rest.split_at(8);
+            let cycles = f64::from_le_bytes(cycles.try_into().unwrap());
+            (Some(TimingCalibration::new(cycles)), rest)
+        }
+        Some((_, rest)) => (None, rest),
+        None => return,
+    };
+
+    let mut counts = [0u32; COUNTS];
+    for (count, bytes) in counts.iter_mut().zip(data.chunks(4)) {
+        let mut buf = [0; 4];
+        buf[..bytes.len()].copy_from_slice(bytes);
+        *count = u32::from_le_bytes(buf);
+    }
+
+    if let Ok((temperature, humidity)) = decode_counts(&counts, timing.as_ref()) {
+        // Both are tenths decoded from 16 bits, using the highest bit of temperature as its sign
+        let temperature = f64::from(temperature);
+        let humidity = f64::from(humidity);
+        assert!((-3276.7..=3276.7).contains(&temperature), "temperature {}", temperature);
+        assert!((0.0..=6553.5).contains(&humidity), "humidity {}", humidity);
+    }
+});
@@ -16,124 +16,112 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 //
 
-use axum::routing::get;
-use axum::Router;
 use clap::Parser;
-use prometheus_client::registry::Registry;
-use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
 use std::{io, process};
-use strudel::http::RequestState;
-use strudel::metrics::TemperatureMetrics;
-use strudel::sensor::{open_pin, DHT22Sensor};
+use strudel::app::{Application, Config};
+use strudel::cli::{self, StrudelApplication, EXIT_USAGE};
+use strudel::systemd;
 use tokio::signal::unix::{self, SignalKind};
-use tokio::task;
-use tower_http::trace::TraceLayer;
-use tracing::{Instrument, Level};
-
-const DEFAULT_REFRESH_SECS: u64 = 30;
-const DEFAULT_LOG_LEVEL: Level = Level::INFO;
-const DEFAULT_BIND_ADDR: ([u8; 4], u16) = ([0, 0, 0, 0], 9781);
-
-/// Expose temperature and humidity from a DHT22 sensor as Prometheus metrics
-///
-/// Read temperature and humidity data from a DHT22 sensor connected to a data pin
-/// of a local machine, usually a Raspberry PI, and expose them as Prometheus
-/// metrics. Several other metrics are emitted as well to help diagnose failures
-/// reading the sensor.
-///
-/// The sensor must be connected to one of the General Purpose IO pins (GPIO). The
-/// numbering of these pins (and how the pin number is provided to strudel) is based
-/// on the Broadcom SOC channel.
-#[derive(Debug, Parser)]
-#[clap(name = "strudel", version = clap::crate_version ! ())]
-struct StrudelApplication {
-    /// BCM GPIO pin number the DHT22 sensor data line is connected to
-    #[arg(long)]
-    bcm_pin: u8,
-
-    /// Read the sensor at this interval, in seconds
-    #[arg(long, default_value_t = DEFAULT_REFRESH_SECS)]
-    refresh_secs: u64,
-
-    /// Logging verbosity. Allowed values are 'trace', 'debug', 'info', 'warn', and 'error'
-    /// (case insensitive)
-    #[arg(long, default_value_t = DEFAULT_LOG_LEVEL)]
-    log_level: Level,
-
-    /// Address to bind to. By default, strudel will bind to public address since
-    /// the purpose is to expose metrics to an external system (Prometheus or another
-    /// agent for ingestion)
-    #[arg(long, default_value_t = DEFAULT_BIND_ADDR.into())]
-    bind: SocketAddr,
-}
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let opts = StrudelApplication::parse();
+async fn main() {
+    let args = StrudelApplication::parse();
+    let print_config = args.print_config;
+    let opts = cli::validate(args, systemd::is_activated()).unwrap_or_else(|errors| {
+        for e in errors {
+            eprintln!("error: {}", e);
+        }
+
+        eprintln!("\nFor more information, try '--help'.");
+        process::exit(EXIT_USAGE)
+    });
+
+    if print_config {
+        // Config only contains types that can be represented as TOML
+        print!("{}", toml::to_string(&opts).unwrap());
+        return;
+    }
+
+    init_tracing(&opts);
+
+    let app = Application::build(opts).await.unwrap_or_else(|e| {
+        tracing::error!(message = "unable to start strudel", error = %e);
+        process::exit(i32::from(e.code()))
+    });
+
+    // Wait for either SIGTERM or SIGINT to shutdown
+    let signals = async {
+        tokio::select! {
+            _ = sigterm() => {}
+            _ = sigint() => {}
+        }
+    };
+
+    let res = app.run(signals).await;
+
+    // Export spans that haven't been sent yet, blocking until they are
+    #[cfg(feature = "otel")]
+    let _ = tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider).await;
+
+    if let Err(e) = res {
+        tracing::error!(message = "strudel stopped because of an error", error = %e);
+        process::exit(1)
+    }
+}
+
+/// Log to stdout at the configured level.
+#[cfg(not(feature = "otel"))]
+fn init_tracing(opts: &Config) {
     tracing::subscriber::set_global_default(
         tracing_subscriber::FmtSubscriber::builder()
             .with_max_level(opts.log_level)
             .finish(),
     )
     .expect("failed to set tracing subscriber");
+}
 
-    let pin = open_pin(opts.bcm_pin).unwrap_or_else(|e| {
-        tracing::error!(message = "failed to initialize data pin", bcm_pin = opts.bcm_pin, error = %e);
-        process::exit(1)
-    });
-
-    let mut registry = <Registry>::default();
-    let metrics = TemperatureMetrics::new(&mut registry);
-    let sensor = Arc::new(Mutex::new(DHT22Sensor::from_pin(pin)));
-
-    // Periodically read from the sensor and update metrics based on the readings.
-    task::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(opts.refresh_secs));
-
-        loop {
-            let _ = interval.tick().await;
-            let sensor_ref = sensor.clone();
-
-            let res = task::spawn_blocking(move || {
-                let mut s = sensor_ref.lock().unwrap();
-                s.read()
-            })
-            .instrument(tracing::span!(Level::DEBUG, "sensor_read"))
-            .await
-            .unwrap(); // TODO: Handle this error?!
+/// Log to stdout at the configured level and, if spans can be exported, record spans
+/// for reads of the sensor using OpenTelemetry so that error metrics can carry the trace
+/// ID of failed reads.
+#[cfg(feature = "otel")]
+fn init_tracing(opts: &Config) {
+    use tracing_subscriber::filter::LevelFilter;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::Layer;
+
+    let (tracer, error) = match span_tracer(opts) {
+        Some(Ok(tracer)) => (Some(tracer), None),
+        Some(Err(e)) => (None, Some(e)),
+        None => (None, None),
+    };
+
+    let subscriber = tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(LevelFilter::from_level(opts.log_level)))
+        .with(tracer.map(|t| tracing_opentelemetry::layer().with_tracer(t)));
+
+    tracing::subscriber::set_global_default(subscriber).expect("failed to set tracing subscriber");
+    if let Some(e) = error {
+        tracing::warn!(message = "unable to export spans, errors won't have exemplars", error = %e);
+    }
+}
 
-            metrics.update(res);
-        }
-    });
+/// Tracer that exports spans to the collector set by --otlp-endpoint, `None` without one.
+/// Spans aren't recorded when they can't be exported so that exemplars never point to
+/// traces that don't exist anywhere.
+#[cfg(all(feature = "otel", feature = "otlp"))]
+fn span_tracer(opts: &Config) -> Option<Result<opentelemetry_sdk::trace::Tracer, opentelemetry::trace::TraceError>> {
+    let endpoint = opts.otlp_endpoint.as_ref()?;
+    Some(strudel::otlp::span_tracer(
+        endpoint,
+        opts.otlp_protocol.into(),
+        &opts.instance_id,
+    ))
+}
 
-    let state = Arc::new(RequestState { registry });
-    let app = Router::new()
-        .route("/metrics", get(strudel::http::text_metrics_handler))
-        .layer(TraceLayer::new_for_http())
-        .with_state(state.clone());
-
-    let server = axum::Server::try_bind(&opts.bind)
-        .map(|s| {
-            s.serve(app.into_make_service()).with_graceful_shutdown(async {
-                // Wait for either SIGTERM or SIGINT to shutdown
-                tokio::select! {
-                    _ = sigterm() => {}
-                    _ = sigint() => {}
-                }
-            })
-        })
-        .unwrap_or_else(|e| {
-            tracing::error!(message = "error starting server", address = %opts.bind, err = %e);
-            process::exit(1)
-        });
-
-    tracing::info!(message = "starting server", address = %opts.bind);
-    server.await.unwrap();
-
-    tracing::info!("server shutdown");
-    Ok(())
+/// Spans can only be exported with the `otlp` feature.
+#[cfg(all(feature = "otel", not(feature = "otlp")))]
+fn span_tracer(_opts: &Config) -> Option<Result<opentelemetry_sdk::trace::Tracer, opentelemetry::trace::TraceError>> {
+    None
 }
 
 /// Return after the first SIGTERM signal received by this process
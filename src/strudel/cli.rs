@@ -0,0 +1,2525 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2021-2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! Command line options of `strudel` and their validation into the `Config` used to build
+//! an `Application`, along with parsing of values shared by every option that takes them
+//! so the same input is accepted the same way everywhere.
+
+use crate::app::{CanaryStrategy, Config, GpioBackend, OtlpProtocol, ReadingFilter, MIN_REFRESH_SECS};
+use crate::fusion::FuseGroup;
+use crate::http::{DEFAULT_EVENT_LOG_CAPACITY, DEFAULT_LIVE_MAX_CONNECTIONS, DEFAULT_SCRAPE_TIMEOUT};
+use crate::identity;
+use crate::metrics::{MetricsConfig, TemperatureMetrics};
+use crate::sensor::{
+    SensorSpec, TemperatureUnit, DEFAULT_ERROR_LOG_INTERVAL, DEFAULT_MAD_REACCEPT_AFTER, DEFAULT_MAD_THRESHOLD,
+    DEFAULT_SPIKE_THRESHOLD,
+};
+use crate::version;
+use axum::http::{HeaderValue, Uri};
+use clap::builder::BoolishValueParser;
+use clap::{ArgAction, Parser};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::Level;
+
+/// Exit status when options are invalid, see `validate`.
+pub const EXIT_USAGE: i32 = 2;
+
+const DEFAULT_REFRESH_SECS: u64 = 30;
+const DEFAULT_TREND_WINDOW_SECS: u64 = 15 * 60;
+const MAX_BCM_PIN: u8 = 27;
+const DEFAULT_LOG_LEVEL: Level = Level::INFO;
+const DEFAULT_BIND_ADDR: ([u8; 4], u16) = ([0, 0, 0, 0], 9781);
+const DEFAULT_STATSD_PREFIX: &str = "strudel";
+const DEFAULT_GRAPHITE_PREFIX: &str = "strudel";
+const DEFAULT_PUSH_INTERVAL_SECS: u64 = 60;
+const DEFAULT_DEGRADED_AFTER: u32 = 5;
+const DEFAULT_HEALTHY_AFTER: u32 = 2;
+const DEFAULT_STUCK_AFTER_READS: u32 = 20;
+const DEFAULT_HUMIDITY_SATURATION_WARN_SECS: u64 = 60 * 60;
+const DEFAULT_POWER_CYCLE_MS: u64 = 2000;
+const DEFAULT_POWER_CYCLE_COOLDOWN_SECS: u64 = 60 * 60;
+const MAX_BACKFILL_READINGS: usize = 1_000_000;
+const MAX_WS_CONNECTIONS: usize = 1024;
+const MAX_SSE_REPLAY_EVENTS: usize = 100_000;
+const DEFAULT_DHT_WAKE_HIGH_MS: u64 = 10;
+const DEFAULT_DHT_START_LOW_MS: u64 = 20;
+const DEFAULT_DHT_START_HIGH_US: u64 = 30;
+const DEFAULT_DHT_MAX_CYCLES: u32 = 32_000;
+const DEFAULT_DHT_MIN_READ_INTERVAL_MS: u64 = 0;
+const DEFAULT_READ_RETRIES: u32 = 0;
+const DEFAULT_STARTUP_PROBE_ATTEMPTS: u32 = 5;
+const DEFAULT_SUMMARY_EVERY: u32 = 20;
+const DEFAULT_SAMPLES_PER_REFRESH: u32 = 1;
+const DEFAULT_MIN_SAMPLES: u32 = 1;
+const DEFAULT_FILTER_WINDOW: usize = 20;
+const MIN_FILTER_WINDOW: usize = 3;
+const DEFAULT_GPIO_CHIP: &str = "/dev/gpiochip0";
+const DEFAULT_OTLP_INTERVAL_SECS: u64 = 60;
+const DEFAULT_STATE_MAX_AGE_SECS: u64 = 10 * 60;
+const DEFAULT_PUBLISH_MAX_INTERVAL_SECS: u64 = 5 * 60;
+
+/// Expose temperature and humidity from a DHT22 sensor as Prometheus metrics
+///
+/// Read temperature and humidity data from a DHT22 sensor connected to a data pin
+/// of a local machine, usually a Raspberry PI, and expose them as Prometheus
+/// metrics. Several other metrics are emitted as well to help diagnose failures
+/// reading the sensor.
+///
+/// The sensor must be connected to one of the General Purpose IO pins (GPIO). The
+/// numbering of these pins (and how the pin number is provided to strudel) is based
+/// on the Broadcom SOC channel.
+///
+/// All options may also be set with environment variables prefixed with `STRUDEL_`.
+/// Options given on the command line take precedence over environment variables.
+#[derive(Debug, Parser)]
+#[clap(name = "strudel", version = version::VERSION, long_version = version::LONG_VERSION)]
+pub struct StrudelApplication {
+    /// BCM GPIO pin number the DHT22 sensor data line is connected to. When using the
+    /// 'cdev' GPIO backend, this is the line offset of the GPIO chip. Either this or
+    /// --sensor must be set
+    #[arg(long, env = "STRUDEL_BCM_PIN")]
+    bcm_pin: Option<u8>,
+
+    /// Sensor to read as comma separated key=value pairs, for example
+    /// 'pin=17,name=indoor,refresh=15'. 'pin' is required and is used in place of --bcm-pin.
+    /// 'name' labels readings of the sensor. 'refresh' is the interval to read the sensor
+    /// at, in seconds, and defaults to --refresh-secs. May be repeated to read more than
+    /// one sensor, each of them named, separate sensors with ';' in the environment variable
+    #[arg(long, env = "STRUDEL_SENSOR", value_delimiter = ';')]
+    sensor: Vec<SensorSpec>,
+
+    /// How to access the GPIO pin the sensor is connected to. 'rppal' requires root access
+    /// and only works on a Raspberry PI. 'cdev' uses the GPIO character device given by
+    /// --gpio-chip
+    #[arg(long, env = "STRUDEL_GPIO_BACKEND", value_enum, default_value_t = GpioBackend::Rppal)]
+    gpio_backend: GpioBackend,
+
+    /// GPIO character device to use with the 'cdev' GPIO backend
+    #[arg(long, env = "STRUDEL_GPIO_CHIP", default_value_t = DEFAULT_GPIO_CHIP.to_owned())]
+    gpio_chip: String,
+
+    /// Read the sensor at this interval, in seconds, unless set by --sensor. The DHT22
+    /// sensor can be read at most once every two seconds so this must be at least two
+    #[arg(long, env = "STRUDEL_REFRESH_SECS", default_value_t = DEFAULT_REFRESH_SECS)]
+    refresh_secs: u64,
+
+    /// Identity of this machine included with metrics and readings pushed to other systems:
+    /// the 'instance' grouping label for the Pushgateway, an 'instance' tag for DogStatsD,
+    /// the host in Graphite paths, and the 'service.instance.id' resource attribute for
+    /// OpenTelemetry. Defaults to the hostname, or the first eight characters of
+    /// /etc/machine-id if the hostname isn't available
+    #[arg(long, env = "STRUDEL_INSTANCE_ID")]
+    instance_id: Option<String>,
+
+    /// Logging verbosity. Allowed values are 'trace', 'debug', 'info', 'warn', and 'error'
+    /// (case insensitive)
+    #[arg(long, env = "STRUDEL_LOG_LEVEL", default_value_t = DEFAULT_LOG_LEVEL)]
+    log_level: Level,
+
+    /// Address to bind to. By default, strudel will bind to public address 0.0.0.0:9781
+    /// since the purpose is to expose metrics to an external system (Prometheus or another
+    /// agent for ingestion). When started via systemd socket activation (LISTEN_FDS and
+    /// LISTEN_PID are set), the socket passed by systemd is used instead and this option
+    /// must not be set
+    #[arg(long, env = "STRUDEL_BIND")]
+    bind: Option<SocketAddr>,
+
+    /// Allow binding to port 0, letting the operating system pick a random port
+    #[arg(long, env = "STRUDEL_ALLOW_RANDOM_PORT", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    allow_random_port: bool,
+
+    /// Don't start the HTTP server, only publishing readings to outputs like --statsd-addr,
+    /// --graphite-addr, --pushgateway-url, or --otlp-endpoint. Can't be combined with
+    /// options for the HTTP server like --bind or --enable-lifecycle
+    #[arg(long, env = "STRUDEL_NO_HTTP", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    no_http: bool,
+
+    /// Origin allowed to fetch readings from a browser, for example 'https://example.com',
+    /// or '*' to allow any origin. May be specified multiple times or as a comma separated
+    /// list. If not set, no CORS headers are sent
+    #[arg(long, env = "STRUDEL_CORS_ALLOW_ORIGIN", value_delimiter = ',')]
+    cors_allow_origin: Vec<String>,
+
+    /// Send CORS headers for /metrics as well as JSON endpoints like /readings. Requires
+    /// --cors-allow-origin
+    #[arg(long, env = "STRUDEL_CORS_ALL_ROUTES", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    cors_all_routes: bool,
+
+    /// Give up on scrapes of /metrics, including reads for --read-on-scrape, after this many
+    /// seconds when the scraper doesn't send X-Prometheus-Scrape-Timeout-Seconds. Scrapes
+    /// that take too long get a 503 response
+    #[arg(long, env = "STRUDEL_HTTP_TIMEOUT_SECS", default_value_t = DEFAULT_SCRAPE_TIMEOUT.as_secs())]
+    http_timeout_secs: u64,
+
+    /// Allow a graceful shutdown to be requested with 'POST /-/quit', the same as sending
+    /// SIGTERM. Disabled by default, in which case the endpoint responds with 403
+    #[arg(long, env = "STRUDEL_ENABLE_LIFECYCLE", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    enable_lifecycle: bool,
+
+    /// Require lifecycle endpoints to be called with 'Authorization: Bearer <token>'.
+    /// Replacing the sensor with 'POST /-/sensors' is only allowed when this is set.
+    /// Requires --enable-lifecycle
+    #[arg(long, env = "STRUDEL_LIFECYCLE_TOKEN")]
+    lifecycle_token: Option<String>,
+
+    /// Address of a DogStatsD agent to send temperature and humidity gauges to after
+    /// each successful read of the sensor. If not set, no gauges will be sent
+    #[arg(long, env = "STRUDEL_STATSD_ADDR")]
+    statsd_addr: Option<SocketAddr>,
+
+    /// Prefix for the names of gauges sent to DogStatsD
+    #[arg(long, env = "STRUDEL_STATSD_PREFIX", default_value_t = DEFAULT_STATSD_PREFIX.to_owned())]
+    statsd_prefix: String,
+
+    /// Tag to include with gauges sent to DogStatsD, in the form 'key:value'. May be
+    /// specified multiple times or as a comma separated list
+    #[arg(long, env = "STRUDEL_STATSD_TAG", value_delimiter = ',')]
+    statsd_tag: Vec<String>,
+
+    /// Address of a Graphite server to send temperature and humidity to using the
+    /// plaintext protocol after each successful read of the sensor. If not set, nothing
+    /// will be sent
+    #[arg(long, env = "STRUDEL_GRAPHITE_ADDR")]
+    graphite_addr: Option<SocketAddr>,
+
+    /// Prefix for the paths of metrics sent to Graphite
+    #[arg(long, env = "STRUDEL_GRAPHITE_PREFIX", default_value_t = DEFAULT_GRAPHITE_PREFIX.to_owned())]
+    graphite_prefix: String,
+
+    /// Host name to include in the paths of metrics sent to Graphite. If not set, the
+    /// instance ID will be used
+    #[arg(long, env = "STRUDEL_GRAPHITE_HOST")]
+    graphite_host: Option<String>,
+
+    /// Only send readings to DogStatsD and Graphite when the temperature has changed by at
+    /// least this many degrees Celsius since the last reading sent, or the humidity has
+    /// changed by at least --publish-deadband-humidity. Prometheus metrics are always
+    /// updated. By default, every reading is sent
+    #[arg(long, env = "STRUDEL_PUBLISH_DEADBAND_TEMP", default_value_t = 0.0, value_parser = parse_float)]
+    publish_deadband_temp: f64,
+
+    /// Only send readings to DogStatsD and Graphite when the humidity has changed by at
+    /// least this many percent since the last reading sent, see --publish-deadband-temp
+    #[arg(long, env = "STRUDEL_PUBLISH_DEADBAND_HUMIDITY", default_value_t = 0.0, value_parser = parse_float)]
+    publish_deadband_humidity: f64,
+
+    /// Send a reading to DogStatsD and Graphite at least this often, in seconds, even if
+    /// it hasn't changed by more than --publish-deadband-temp or --publish-deadband-humidity
+    #[arg(long, env = "STRUDEL_PUBLISH_MAX_INTERVAL_SECS", default_value_t = DEFAULT_PUBLISH_MAX_INTERVAL_SECS)]
+    publish_max_interval_secs: u64,
+
+    /// URL of a Prometheus Pushgateway to periodically push all metrics to, for example
+    /// 'http://localhost:9091'. Only plain HTTP is supported. If not set, metrics will
+    /// not be pushed
+    #[arg(long, env = "STRUDEL_PUSHGATEWAY_URL")]
+    pushgateway_url: Option<String>,
+
+    /// Push metrics to the Pushgateway at this interval, in seconds
+    #[arg(long, env = "STRUDEL_PUSH_INTERVAL_SECS", default_value_t = DEFAULT_PUSH_INTERVAL_SECS)]
+    push_interval_secs: u64,
+
+    /// Extra grouping label for metrics pushed to the Pushgateway, in the form 'key=value'.
+    /// May be specified multiple times or as a comma separated list
+    #[arg(long, env = "STRUDEL_PUSH_GROUP", value_parser = parse_group, value_delimiter = ',')]
+    push_group: Vec<(String, String)>,
+
+    /// Username for basic authentication with the Pushgateway
+    #[arg(long, env = "STRUDEL_PUSH_USERNAME", requires = "push_password")]
+    push_username: Option<String>,
+
+    /// Password for basic authentication with the Pushgateway
+    #[arg(
+        long,
+        env = "STRUDEL_PUSH_PASSWORD",
+        hide_env_values = true,
+        requires = "push_username"
+    )]
+    push_password: Option<String>,
+
+    /// Delete pushed metrics from the Pushgateway when shutting down
+    #[arg(long, env = "STRUDEL_PUSH_DELETE_ON_EXIT", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    push_delete_on_exit: bool,
+
+    /// Mark the sensor as degraded after this many consecutive failed reads
+    #[arg(long, env = "STRUDEL_DEGRADED_AFTER_FAILURES", default_value_t = DEFAULT_DEGRADED_AFTER)]
+    degraded_after_failures: u32,
+
+    /// Mark a degraded sensor as healthy again after this many consecutive successful reads
+    #[arg(long, env = "STRUDEL_HEALTHY_AFTER_SUCCESSES", default_value_t = DEFAULT_HEALTHY_AFTER)]
+    healthy_after_successes: u32,
+
+    /// Mark the sensor as stuck after this many consecutive successful reads returned
+    /// identical bytes, including the decimal digits of each value
+    #[arg(long, env = "STRUDEL_STUCK_AFTER_READS", default_value_t = DEFAULT_STUCK_AFTER_READS)]
+    stuck_after_reads: u32,
+
+    /// Log a warning when humidity has been pinned at 0% or 100% for this many seconds,
+    /// which usually means the sensor has failed. Must be at least 1
+    #[arg(long, env = "STRUDEL_HUMIDITY_SATURATION_WARN_SECS", default_value_t = DEFAULT_HUMIDITY_SATURATION_WARN_SECS)]
+    humidity_saturation_warn_secs: u64,
+
+    /// Reset the sensor when it's marked as stuck, the same as sending SIGHUP
+    #[arg(long, env = "STRUDEL_RESET_WHEN_STUCK", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    reset_when_stuck: bool,
+
+    /// BCM GPIO pin switching power to the sensor, for example through a transistor or
+    /// relay. It's driven high to power the sensor and pulled low to cut power when the
+    /// sensor is stuck or humidity is pinned at 0% or 100%. If not set, power is never cut
+    #[arg(long, env = "STRUDEL_POWER_PIN")]
+    power_pin: Option<u8>,
+
+    /// Cut power to the sensor for this many milliseconds during each power cycle
+    #[arg(long, env = "STRUDEL_POWER_CYCLE_MS", default_value_t = DEFAULT_POWER_CYCLE_MS)]
+    power_cycle_ms: u64,
+
+    /// Power cycle the sensor automatically at most once per this many seconds. Power
+    /// cycles requested with POST /-/power-cycle ignore this but still restart it
+    #[arg(long, env = "STRUDEL_POWER_CYCLE_COOLDOWN_SECS", default_value_t = DEFAULT_POWER_CYCLE_COOLDOWN_SECS)]
+    power_cycle_cooldown_secs: u64,
+
+    /// URL to send a JSON POST request to each time the sensor changes between healthy
+    /// and degraded states. Only plain HTTP is supported. If not set, no requests are sent
+    #[arg(long, env = "STRUDEL_STATE_WEBHOOK_URL")]
+    state_webhook_url: Option<Uri>,
+
+    /// How long to hold the data pin high to wake the sensor up before a read, in
+    /// milliseconds
+    #[arg(long, env = "STRUDEL_DHT_WAKE_HIGH_MS", default_value_t = DEFAULT_DHT_WAKE_HIGH_MS)]
+    dht_wake_high_ms: u64,
+
+    /// How long to hold the data pin low to signal the start of a read, in milliseconds.
+    /// Some sensors need a longer start signal than the default
+    #[arg(long, env = "STRUDEL_DHT_START_LOW_MS", default_value_t = DEFAULT_DHT_START_LOW_MS)]
+    dht_start_low_ms: u64,
+
+    /// How long to hold the data pin high before waiting for the sensor to respond, in
+    /// microseconds
+    #[arg(long, env = "STRUDEL_DHT_START_HIGH_US", default_value_t = DEFAULT_DHT_START_HIGH_US)]
+    dht_start_high_us: u64,
+
+    /// Maximum number of cycles to wait for the data pin to change state during a read
+    /// before giving up
+    #[arg(long, env = "STRUDEL_DHT_MAX_CYCLES", default_value_t = DEFAULT_DHT_MAX_CYCLES)]
+    dht_max_cycles: u32,
+
+    /// Minimum time between reads of the sensor, in milliseconds, enforced even when
+    /// reads are requested more often
+    #[arg(long, env = "STRUDEL_DHT_MIN_READ_INTERVAL_MS", default_value_t = DEFAULT_DHT_MIN_READ_INTERVAL_MS)]
+    dht_min_read_interval_ms: u64,
+
+    /// Measure how fast the data pin can be checked at startup and on SIGHUP, and use it
+    /// to decode bits using timings from the DHT22 datasheet instead of the timing of each
+    /// read. Useful when reads fail on slow or fast devices
+    #[arg(long, env = "STRUDEL_DHT_CALIBRATE_TIMING", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    dht_calibrate_timing: bool,
+
+    /// Fail reads with a 'no_response' error unless the sensor answers the start signal
+    /// with low and high pulses of 60-100us each. Requires --dht-calibrate-timing
+    #[arg(long, env = "STRUDEL_DHT_VALIDATE_RESPONSE", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    dht_validate_response: bool,
+
+    /// Fail reads with a 'frame' error, even if their checksum is valid, unless they were
+    /// decoded from one low and one high pulse per bit, are within the range of the DHT22,
+    /// and their high pulses are clearly either 0 or 1 bits
+    #[arg(long, env = "STRUDEL_STRICT_FRAMES", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    strict_frames: bool,
+
+    /// Read the sensor a second time two seconds after each successful read, decoding
+    /// pulses with this strategy, and export how it compares to the published reading.
+    /// Must differ from how reads decode pulses. Can't be used with --read-on-scrape
+    #[arg(long, env = "STRUDEL_CANARY_STRATEGY", value_enum)]
+    canary_strategy: Option<CanaryStrategy>,
+
+    /// Retry failed reads of the sensor up to this many times before giving up until the
+    /// next refresh. Retries wait two seconds since the sensor can't be read more often
+    #[arg(long, env = "STRUDEL_READ_RETRIES", default_value_t = DEFAULT_READ_RETRIES)]
+    read_retries: u32,
+
+    /// Read the sensor this many times each refresh, two seconds apart, and publish the
+    /// median temperature and humidity of the successful reads. Retries of a failed read
+    /// don't count as extra samples
+    #[arg(long, env = "STRUDEL_SAMPLES_PER_REFRESH", default_value_t = DEFAULT_SAMPLES_PER_REFRESH)]
+    samples_per_refresh: u32,
+
+    /// Minimum number of samples that must be read successfully each refresh to publish
+    /// a reading. Must be at most --samples-per-refresh
+    #[arg(long, env = "STRUDEL_MIN_SAMPLES", default_value_t = DEFAULT_MIN_SAMPLES)]
+    min_samples: u32,
+
+    /// Give up on reading the sensor, including any remaining retries and samples, after
+    /// this many seconds. Must be at most --refresh-secs. Defaults to half of --refresh-secs
+    /// or two seconds per sample, whichever is longer
+    #[arg(long, env = "STRUDEL_READ_BUDGET_SECS")]
+    read_budget_secs: Option<u64>,
+
+    /// Reject implausible readings, reporting them as failed reads with the 'implausible'
+    /// error type. 'mad' rejects readings that deviate from the median of the last
+    /// --filter-window readings by more than --filter-threshold median absolute deviations
+    #[arg(long, env = "STRUDEL_FILTER", value_enum, default_value_t = ReadingFilter::None)]
+    filter: ReadingFilter,
+
+    /// Number of recently accepted readings to compare new readings to with '--filter mad'.
+    /// Every reading is accepted until there are this many. Must be at least 3
+    #[arg(long, env = "STRUDEL_FILTER_WINDOW", default_value_t = DEFAULT_FILTER_WINDOW)]
+    filter_window: usize,
+
+    /// Number of median absolute deviations from the median a reading may be with
+    /// '--filter mad' before being rejected. Must be greater than zero
+    #[arg(long, env = "STRUDEL_FILTER_THRESHOLD", default_value_t = DEFAULT_MAD_THRESHOLD, value_parser = parse_float)]
+    filter_threshold: f64,
+
+    /// Accept readings again after this many consecutive readings were rejected with
+    /// '--filter mad', so that real sudden changes eventually get through. Must be at least 1
+    #[arg(long, env = "STRUDEL_FILTER_REACCEPT_AFTER", default_value_t = DEFAULT_MAD_REACCEPT_AFTER)]
+    filter_reaccept_after: u32,
+
+    /// Hold each reading until the next read and replace it with the average of the readings
+    /// before and after it if its temperature is more than --spike-threshold above or below
+    /// both of them. Readings are published one refresh later, with the time they were read.
+    /// Can't be used with --read-on-scrape
+    #[arg(long, env = "STRUDEL_PUBLISH_DELAY_ONE_SAMPLE", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    publish_delay_one_sample: bool,
+
+    /// Degrees celsius a reading must be above or below both of the readings around it to be
+    /// replaced with --publish-delay-one-sample. Must be greater than zero
+    #[arg(long, env = "STRUDEL_SPIKE_THRESHOLD", default_value_t = DEFAULT_SPIKE_THRESHOLD, value_parser = parse_float)]
+    spike_threshold: f64,
+
+    /// Log the same error reading the sensor at most once per this many seconds while it
+    /// keeps happening, with the number of times it was repeated. Different errors and
+    /// recovering are always logged right away. Set to 0 to log every error
+    #[arg(long, env = "STRUDEL_ERROR_LOG_INTERVAL_SECS", default_value_t = DEFAULT_ERROR_LOG_INTERVAL.as_secs())]
+    error_log_interval_secs: u64,
+
+    /// Read the sensor when metrics are scraped instead of every --refresh-secs. Scrapes
+    /// within two seconds of the previous read reuse it, and scrapes that wait longer than
+    /// the read budget for a read are served the previous reading instead
+    #[arg(long, env = "STRUDEL_READ_ON_SCRAPE", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    read_on_scrape: bool,
+
+    /// Keep this many of the most recent successful readings in memory and serve them with
+    /// timestamps at /metrics/backfill, for backfilling scrapes Prometheus missed. If 0,
+    /// readings aren't kept
+    #[arg(long, env = "STRUDEL_BACKFILL_READINGS", default_value_t = 0)]
+    backfill_readings: usize,
+
+    /// Allow at most this many WebSocket clients at once to be pushed readings as they
+    /// happen from /ws. If 0, /ws is disabled
+    #[arg(long, env = "STRUDEL_WS_MAX_CONNECTIONS", default_value_t = DEFAULT_LIVE_MAX_CONNECTIONS)]
+    ws_max_connections: usize,
+
+    /// Stream reads of the sensor as Server-Sent Events from /events, keeping this many of
+    /// the most recent events for clients that reconnect. If 0, /events is disabled
+    #[arg(long, env = "STRUDEL_SSE_REPLAY_EVENTS", default_value_t = DEFAULT_EVENT_LOG_CAPACITY)]
+    sse_replay_events: usize,
+
+    /// Read the sensor before starting the HTTP server and exit with an error if it can't
+    /// be read. By default, strudel starts even if the sensor can't be read
+    #[arg(long, env = "STRUDEL_REQUIRE_SENSOR_AT_STARTUP", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    require_sensor_at_startup: bool,
+
+    /// Number of times to try reading the sensor at startup when --require-sensor-at-startup
+    /// is set, waiting two seconds between attempts
+    #[arg(long, env = "STRUDEL_STARTUP_PROBE_ATTEMPTS", default_value_t = DEFAULT_STARTUP_PROBE_ATTEMPTS)]
+    startup_probe_attempts: u32,
+
+    /// Save the most recent successful reading to this file after every read and use it
+    /// at startup until the sensor is read, if it's recent enough. By default, metrics
+    /// are missing after a restart until the sensor is read
+    #[arg(long, env = "STRUDEL_STATE_FILE")]
+    state_file: Option<PathBuf>,
+
+    /// Only use the reading saved to --state-file at startup if it's at most this many
+    /// seconds old
+    #[arg(long, env = "STRUDEL_STATE_MAX_AGE_SECS", default_value_t = DEFAULT_STATE_MAX_AGE_SECS)]
+    state_max_age_secs: u64,
+
+    /// Save the values of counters like strudel_collections_total to --state-file and
+    /// restore them at startup so they don't reset when strudel restarts. Saved counters
+    /// are restored regardless of how old they are
+    #[arg(long, env = "STRUDEL_PERSIST_COUNTERS", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    persist_counters: bool,
+
+    /// Number of reads of the sensor, successful or not, between summaries of recent reads
+    /// logged at INFO level
+    #[arg(long, env = "STRUDEL_SUMMARY_EVERY", default_value_t = DEFAULT_SUMMARY_EVERY)]
+    summary_every: u32,
+
+    /// Unit to report temperature in. Allowed values are 'celsius', 'fahrenheit', and
+    /// 'kelvin'
+    #[arg(long, env = "STRUDEL_TEMPERATURE_UNIT", default_value_t = TemperatureUnit::Celsius)]
+    temperature_unit: TemperatureUnit,
+
+    /// Difference between the temperature of leaves and the air, in degrees celsius, used
+    /// when computing vapour pressure deficit. Usually negative since leaves are cooler
+    #[arg(
+        long,
+        env = "STRUDEL_LEAF_TEMP_OFFSET",
+        default_value_t = 0.0,
+        allow_hyphen_values = true,
+        value_parser = parse_float
+    )]
+    leaf_temp_offset: f64,
+
+    /// Compute the rate of change of temperature and humidity from readings taken within
+    /// this many seconds. Must be at least --refresh-secs so that there are enough readings
+    #[arg(long, env = "STRUDEL_TREND_WINDOW_SECS", default_value_t = DEFAULT_TREND_WINDOW_SECS)]
+    trend_window_secs: u64,
+
+    /// Comma separated upper bounds, in celsius, of the buckets of the temperature
+    /// histogram. Must be increasing. Defaults to -10 to 40 in steps of 2
+    #[arg(
+        long,
+        env = "STRUDEL_TEMP_BUCKETS",
+        value_delimiter = ',',
+        allow_hyphen_values = true,
+        value_parser = parse_float
+    )]
+    temp_buckets: Vec<f64>,
+
+    /// Comma separated upper bounds of the buckets of the relative humidity histogram.
+    /// Must be increasing. Defaults to 0 to 100 in steps of 5
+    #[arg(long, env = "STRUDEL_HUMIDITY_BUCKETS", value_delimiter = ',', value_parser = parse_float)]
+    humidity_buckets: Vec<f64>,
+
+    /// Also expose metrics using the names from pitemp, the predecessor of strudel, so that
+    /// existing dashboards keep working. Temperature is only included when using celsius
+    #[arg(long, env = "STRUDEL_LEGACY_METRIC_NAMES", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    legacy_metric_names: bool,
+
+    /// Expose the bytes decoded from every read of the sensor as gauges, including reads
+    /// with an invalid checksum, to diagnose readings with unexpected values
+    #[arg(long, env = "STRUDEL_DEBUG_METRICS", action = ArgAction::SetTrue, value_parser = BoolishValueParser::new())]
+    debug_metrics: bool,
+
+    /// Comma separated families of metrics to leave out of the output entirely, to reduce
+    /// cardinality. May be repeated. One of: vapour_pressure_deficit, histograms, error_ratio,
+    /// pulse_width_ratio, trend, debug, process, build, config, read_loop, health, push, http,
+    /// fusion, canary, spikes
+    #[arg(long, env = "STRUDEL_DISABLE_METRIC", value_delimiter = ',')]
+    disable_metric: Vec<String>,
+
+    /// Publish an estimate fused from named sensors measuring the same thing, in the form
+    /// 'sensor,sensor=name', weighting each sensor by how noisy its recent readings are.
+    /// May be repeated, separate groups with ';' in the environment variable
+    #[arg(long, env = "STRUDEL_FUSE", value_delimiter = ';')]
+    fuse: Vec<FuseGroup>,
+
+    /// Push metrics about readings to an OpenTelemetry collector at this URL, for example
+    /// 'http://localhost:4317'. Spans of reads are sent too when built with the 'otel'
+    /// feature. Requires strudel to be built with the 'otlp' feature
+    #[arg(long, env = "STRUDEL_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+
+    /// Protocol to use when pushing metrics to an OpenTelemetry collector
+    #[arg(long, env = "STRUDEL_OTLP_PROTOCOL", value_enum, default_value_t = OtlpProtocol::Grpc)]
+    otlp_protocol: OtlpProtocol,
+
+    /// Push metrics to an OpenTelemetry collector at this interval, in seconds
+    #[arg(long, env = "STRUDEL_OTLP_INTERVAL_SECS", default_value_t = DEFAULT_OTLP_INTERVAL_SECS)]
+    otlp_interval_secs: u64,
+
+    /// Serve the most recent reading as Modbus TCP holding registers on this address, for
+    /// example '0.0.0.0:5020'. Requires strudel to be built with the 'modbus' feature
+    #[arg(long, env = "STRUDEL_MODBUS_BIND")]
+    modbus_bind: Option<SocketAddr>,
+
+    /// Answer simple UDP queries for the most recent reading on this address, for example
+    /// '0.0.0.0:9101'. Queries are 'GET temperature', 'GET humidity', or 'GET all'
+    #[arg(long, env = "STRUDEL_UDP_QUERY_BIND")]
+    udp_query_bind: Option<SocketAddr>,
+
+    /// Print the effective configuration as TOML, after validation, and exit
+    #[arg(long)]
+    pub print_config: bool,
+}
+
+/// Validate parsed options, returning all problems with them instead of just the first.
+///
+/// The bind address is `None` when the process has been passed a socket by systemd.
+pub fn validate(opts: StrudelApplication, socket_activated: bool) -> Result<Config, Vec<String>> {
+    let mut errors = Vec::new();
+
+    if let Err(e) = SensorSpec::check_unique_names(&opts.sensor) {
+        errors.push(format!("--sensor {}", e));
+    }
+
+    // Readings are stored under the name of their sensor so only a lone sensor can go without
+    if opts.sensor.len() > 1 && opts.sensor.iter().any(|s| s.name.is_none()) {
+        errors.push("--sensor name must be set for every sensor when there's more than one".to_owned());
+    }
+
+    let mut pins = Vec::new();
+    for spec in opts.sensor.iter() {
+        if pins.contains(&spec.pin) {
+            errors.push(format!("--sensor pin {} is used by more than one sensor", spec.pin));
+        }
+
+        pins.push(spec.pin);
+    }
+
+    let sensors = match (opts.bcm_pin, opts.sensor.is_empty()) {
+        (Some(pin), true) => vec![SensorSpec::new(pin)],
+        (None, false) => opts.sensor.clone(),
+        (Some(_), false) => {
+            errors.push("--bcm-pin must not be set when using --sensor".to_owned());
+            opts.sensor.clone()
+        }
+        (None, true) => {
+            errors.push("--bcm-pin or --sensor must be set".to_owned());
+            vec![SensorSpec::new(0)]
+        }
+    };
+
+    let pin_arg = if opts.sensor.is_empty() {
+        "--bcm-pin"
+    } else {
+        "--sensor pin"
+    };
+    for spec in sensors.iter() {
+        errors.extend(validate_spec(spec, opts.gpio_backend, pin_arg));
+    }
+
+    if let Some(pin) = opts.power_pin {
+        if sensors.iter().any(|s| s.pin == pin) {
+            errors.push(format!("--power-pin must be different from {} ({})", pin_arg, pin));
+        }
+
+        if opts.gpio_backend == GpioBackend::Rppal && pin > MAX_BCM_PIN {
+            errors.push(format!(
+                "--power-pin must be a BCM GPIO pin number from 0 to {}, got {}",
+                MAX_BCM_PIN, pin
+            ));
+        }
+    }
+
+    if opts.backfill_readings > MAX_BACKFILL_READINGS {
+        errors.push(format!(
+            "--backfill-readings must be at most {}, got {}",
+            MAX_BACKFILL_READINGS, opts.backfill_readings
+        ));
+    }
+
+    if opts.ws_max_connections > MAX_WS_CONNECTIONS {
+        errors.push(format!(
+            "--ws-max-connections must be at most {}, got {}",
+            MAX_WS_CONNECTIONS, opts.ws_max_connections
+        ));
+    }
+
+    if opts.sse_replay_events > MAX_SSE_REPLAY_EVENTS {
+        errors.push(format!(
+            "--sse-replay-events must be at most {}, got {}",
+            MAX_SSE_REPLAY_EVENTS, opts.sse_replay_events
+        ));
+    }
+
+    if opts.power_cycle_ms == 0 {
+        errors.push("--power-cycle-ms must be at least 1".to_owned());
+    }
+
+    if opts.power_cycle_cooldown_secs == 0 {
+        errors.push("--power-cycle-cooldown-secs must be at least 1".to_owned());
+    }
+
+    if opts.refresh_secs < MIN_REFRESH_SECS {
+        errors.push(format!(
+            "--refresh-secs must be at least {} since the sensor can't be read more often, got {}",
+            MIN_REFRESH_SECS, opts.refresh_secs
+        ));
+    }
+
+    // Reads of every sensor have to fit in its refresh interval, the shortest one is the
+    // hardest to fit in
+    let default_refresh = Duration::from_secs(opts.refresh_secs);
+    let refresh = sensors
+        .iter()
+        .map(|s| s.refresh_or(default_refresh))
+        .min()
+        .unwrap_or(default_refresh);
+
+    let instance_id = match opts.instance_id.as_deref().map(identity::sanitize) {
+        Some(id) if id.is_empty() => {
+            errors.push("--instance-id must not be empty".to_owned());
+            id
+        }
+        Some(id) => id,
+        None => identity::instance_id(),
+    };
+
+    let bind = match (opts.bind, socket_activated) {
+        (Some(addr), true) => {
+            errors.push(format!(
+                "--bind must not be set when using systemd socket activation, got {}",
+                addr
+            ));
+            None
+        }
+        (_, true) => None,
+        (_, false) if opts.no_http => None,
+        (addr, false) => Some(addr.unwrap_or_else(|| DEFAULT_BIND_ADDR.into())),
+    };
+
+    if let Some(addr) = bind.filter(|a| a.port() == 0 && !opts.allow_random_port) {
+        errors.push(format!(
+            "--bind must use a non-zero port unless --allow-random-port is set, got {}",
+            addr
+        ));
+    }
+
+    if opts.no_http {
+        if socket_activated {
+            errors.push("--no-http can't be used with systemd socket activation".to_owned());
+        }
+
+        let http_only = [
+            ("--bind", opts.bind.is_some()),
+            ("--allow-random-port", opts.allow_random_port),
+            ("--cors-allow-origin", !opts.cors_allow_origin.is_empty()),
+            ("--cors-all-routes", opts.cors_all_routes),
+            ("--enable-lifecycle", opts.enable_lifecycle),
+            ("--lifecycle-token", opts.lifecycle_token.is_some()),
+            ("--read-on-scrape", opts.read_on_scrape),
+            ("--backfill-readings", opts.backfill_readings > 0),
+        ];
+        for (flag, _) in http_only.iter().filter(|(_, set)| *set) {
+            errors.push(format!(
+                "--no-http can't be combined with {}, it requires the HTTP server",
+                flag
+            ));
+        }
+    }
+
+    for origin in opts.cors_allow_origin.iter() {
+        let valid = origin == "*"
+            || ((origin.starts_with("http://") || origin.starts_with("https://"))
+                && HeaderValue::from_str(origin).is_ok());
+        if !valid {
+            errors.push(format!(
+                "--cors-allow-origin must be '*' or an 'http://' or 'https://' origin, got '{}'",
+                origin
+            ));
+        }
+    }
+
+    if opts.cors_allow_origin.len() > 1 && opts.cors_allow_origin.iter().any(|o| o == "*") {
+        errors.push("--cors-allow-origin '*' can't be combined with other origins".to_owned());
+    }
+
+    if opts.cors_all_routes && opts.cors_allow_origin.is_empty() {
+        errors.push("--cors-all-routes requires --cors-allow-origin".to_owned());
+    }
+
+    if opts.http_timeout_secs == 0 {
+        errors.push("--http-timeout-secs must be at least 1".to_owned());
+    }
+
+    if opts.lifecycle_token.is_some() && !opts.enable_lifecycle {
+        errors.push("--lifecycle-token requires --enable-lifecycle".to_owned());
+    } else if opts.lifecycle_token.as_deref() == Some("") {
+        errors.push("--lifecycle-token must not be empty".to_owned());
+    }
+
+    if opts.persist_counters && opts.state_file.is_none() {
+        errors.push("--persist-counters requires --state-file".to_owned());
+    }
+
+    if opts.gpio_chip.is_empty() {
+        errors.push("--gpio-chip must not be empty".to_owned());
+    }
+
+    if opts.statsd_prefix.is_empty() {
+        errors.push("--statsd-prefix must not be empty".to_owned());
+    }
+
+    if opts.graphite_prefix.is_empty() {
+        errors.push("--graphite-prefix must not be empty".to_owned());
+    }
+
+    if opts.publish_deadband_temp < 0.0 {
+        errors.push(format!(
+            "--publish-deadband-temp must be a number of at least 0, got {}",
+            opts.publish_deadband_temp
+        ));
+    }
+
+    if opts.publish_deadband_humidity < 0.0 {
+        errors.push(format!(
+            "--publish-deadband-humidity must be a number of at least 0, got {}",
+            opts.publish_deadband_humidity
+        ));
+    }
+
+    if opts.publish_max_interval_secs == 0 {
+        errors.push("--publish-max-interval-secs must be at least 1".to_owned());
+    }
+
+    if let Some(url) = &opts.pushgateway_url {
+        match url.parse::<Uri>() {
+            Ok(u) if u.scheme_str() == Some("http") => {}
+            _ => errors.push(format!("--pushgateway-url must be an 'http://' URL, got '{}'", url)),
+        }
+    }
+
+    if opts.push_interval_secs == 0 {
+        errors.push("--push-interval-secs must be at least 1".to_owned());
+    }
+
+    if opts.degraded_after_failures == 0 {
+        errors.push("--degraded-after-failures must be at least 1".to_owned());
+    }
+
+    if opts.healthy_after_successes == 0 {
+        errors.push("--healthy-after-successes must be at least 1".to_owned());
+    }
+
+    if opts.stuck_after_reads < 2 {
+        errors.push("--stuck-after-reads must be at least 2".to_owned());
+    }
+
+    if opts.humidity_saturation_warn_secs == 0 {
+        errors.push("--humidity-saturation-warn-secs must be at least 1".to_owned());
+    }
+
+    if let Some(url) = &opts.state_webhook_url {
+        if url.scheme_str() != Some("http") {
+            errors.push(format!("--state-webhook-url must be an 'http://' URL, got '{}'", url));
+        }
+    }
+
+    if opts.dht_max_cycles == 0 {
+        errors.push("--dht-max-cycles must be at least 1".to_owned());
+    }
+
+    if opts.dht_validate_response && !opts.dht_calibrate_timing {
+        errors.push("--dht-validate-response requires --dht-calibrate-timing".to_owned());
+    }
+
+    match opts.read_budget_secs {
+        Some(0) => errors.push("--read-budget-secs must be at least 1".to_owned()),
+        Some(b) if b > refresh.as_secs() => errors.push(format!(
+            "--read-budget-secs must be at most the refresh interval ({}), got {}",
+            refresh.as_secs(),
+            b
+        )),
+        _ => {}
+    }
+
+    // Samples are read two seconds apart and all of them need to happen before the
+    // next refresh. A refresh interval that's too short has already been reported.
+    let samples_window = Duration::from_secs(MIN_REFRESH_SECS) * opts.samples_per_refresh;
+    if opts.samples_per_refresh == 0 {
+        errors.push("--samples-per-refresh must be at least 1".to_owned());
+    } else if refresh.as_secs() >= MIN_REFRESH_SECS && samples_window > refresh {
+        errors.push(format!(
+            "--samples-per-refresh must fit in the refresh interval ({}) at {} seconds per sample, got {}",
+            refresh.as_secs(),
+            MIN_REFRESH_SECS,
+            opts.samples_per_refresh
+        ));
+    }
+
+    // Canary reads happen two seconds after the last sample so they need room for one
+    // more read. Anything that doesn't fit without them has already been reported.
+    if let Some(canary) = opts.canary_strategy {
+        match (canary, opts.dht_calibrate_timing) {
+            (CanaryStrategy::Timed, true) => errors.push(
+                "--canary-strategy 'timed' decodes pulses the same way as reads with --dht-calibrate-timing".to_owned(),
+            ),
+            (CanaryStrategy::Relative, false) => errors.push(
+                "--canary-strategy 'relative' decodes pulses the same way as reads without --dht-calibrate-timing"
+                    .to_owned(),
+            ),
+            _ => {}
+        }
+
+        if opts.read_on_scrape {
+            errors.push("--canary-strategy can't be used with --read-on-scrape".to_owned());
+        }
+
+        let canary_window = samples_window + Duration::from_secs(MIN_REFRESH_SECS);
+        if opts.samples_per_refresh > 0 && samples_window <= refresh && canary_window > refresh {
+            errors.push(format!(
+                "--canary-strategy needs {} seconds for reads and the canary read, more than the refresh interval ({})",
+                canary_window.as_secs(),
+                refresh.as_secs()
+            ));
+        }
+    }
+
+    if opts.min_samples == 0 || opts.min_samples > opts.samples_per_refresh.max(1) {
+        errors.push(format!(
+            "--min-samples must be from 1 to --samples-per-refresh ({}), got {}",
+            opts.samples_per_refresh, opts.min_samples
+        ));
+    }
+
+    if opts.filter_window < MIN_FILTER_WINDOW {
+        errors.push(format!(
+            "--filter-window must be at least {}, got {}",
+            MIN_FILTER_WINDOW, opts.filter_window
+        ));
+    }
+
+    if opts.filter_threshold <= 0.0 {
+        errors.push(format!(
+            "--filter-threshold must be greater than zero, got {}",
+            opts.filter_threshold
+        ));
+    }
+
+    if opts.filter_reaccept_after == 0 {
+        errors.push("--filter-reaccept-after must be at least 1".to_owned());
+    }
+
+    if opts.spike_threshold <= 0.0 {
+        errors.push(format!(
+            "--spike-threshold must be greater than zero, got {}",
+            opts.spike_threshold
+        ));
+    }
+
+    if opts.publish_delay_one_sample && opts.read_on_scrape {
+        errors.push("--publish-delay-one-sample can't be used with --read-on-scrape".to_owned());
+    }
+
+    if opts.startup_probe_attempts == 0 {
+        errors.push("--startup-probe-attempts must be at least 1".to_owned());
+    }
+
+    if opts.state_max_age_secs == 0 {
+        errors.push("--state-max-age-secs must be at least 1".to_owned());
+    }
+
+    if opts.summary_every == 0 {
+        errors.push("--summary-every must be at least 1".to_owned());
+    }
+
+    if opts.trend_window_secs < refresh.as_secs() {
+        errors.push(format!(
+            "--trend-window-secs must be at least the refresh interval ({}), got {}",
+            refresh.as_secs(),
+            opts.trend_window_secs
+        ));
+    }
+
+    if let Some(url) = &opts.otlp_endpoint {
+        match url.parse::<Uri>() {
+            Ok(u) if u.scheme_str() == Some("http") => {}
+            _ => errors.push(format!("--otlp-endpoint must be an 'http://' URL, got '{}'", url)),
+        }
+
+        if !cfg!(feature = "otlp") {
+            errors.push("--otlp-endpoint requires strudel to be built with the 'otlp' feature".to_owned());
+        }
+    }
+
+    if opts.otlp_interval_secs == 0 {
+        errors.push("--otlp-interval-secs must be at least 1".to_owned());
+    }
+
+    if let Some(addr) = opts.modbus_bind {
+        if opts.bind == Some(addr) {
+            errors.push(format!("--modbus-bind must be different from --bind, got {}", addr));
+        }
+
+        if !cfg!(feature = "modbus") {
+            errors.push("--modbus-bind requires strudel to be built with the 'modbus' feature".to_owned());
+        }
+    }
+
+    let temp_buckets = if opts.temp_buckets.is_empty() {
+        TemperatureMetrics::default_temperature_buckets()
+    } else {
+        opts.temp_buckets
+    };
+
+    if let Err(e) = validate_buckets(&temp_buckets) {
+        errors.push(format!("--temp-buckets {}", e));
+    }
+
+    let humidity_buckets = if opts.humidity_buckets.is_empty() {
+        TemperatureMetrics::default_humidity_buckets()
+    } else {
+        opts.humidity_buckets
+    };
+
+    if let Err(e) = validate_buckets(&humidity_buckets) {
+        errors.push(format!("--humidity-buckets {}", e));
+    }
+
+    let mut metrics = MetricsConfig::default();
+    for name in &opts.disable_metric {
+        match MetricsConfig::family(name) {
+            Some(f) => metrics = metrics.disable(f),
+            None => errors.push(format!(
+                "--disable-metric '{}' is not a family of metrics, must be one of: {}",
+                name,
+                MetricsConfig::FAMILIES
+                    .iter()
+                    .map(|(n, _)| *n)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+        }
+    }
+
+    // Fused values are labeled the same way as sensors so the names of groups must not
+    // clash with them
+    let sensor_names: Vec<&str> = sensors.iter().filter_map(|s| s.name.as_deref()).collect();
+    let mut group_names = Vec::new();
+    for group in opts.fuse.iter() {
+        for sensor in group.sensors.iter() {
+            if !sensor_names.contains(&sensor.as_str()) {
+                errors.push(format!(
+                    "--fuse group '{}' includes sensor '{}' which isn't configured, name sensors with --sensor",
+                    group.name, sensor
+                ));
+            }
+        }
+
+        if sensor_names.contains(&group.name.as_str()) || group_names.contains(&&group.name) {
+            errors.push(format!(
+                "--fuse group name '{}' must be different from other groups and sensors",
+                group.name
+            ));
+        }
+
+        group_names.push(&group.name);
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    Ok(Config {
+        sensors,
+        gpio_backend: opts.gpio_backend,
+        gpio_chip: opts.gpio_chip,
+        refresh: default_refresh,
+        instance_id,
+        log_level: opts.log_level,
+        bind,
+        no_http: opts.no_http,
+        cors_allow_origin: opts.cors_allow_origin,
+        cors_all_routes: opts.cors_all_routes,
+        http_timeout: Duration::from_secs(opts.http_timeout_secs),
+        enable_lifecycle: opts.enable_lifecycle,
+        lifecycle_token: opts.lifecycle_token,
+        statsd_addr: opts.statsd_addr,
+        statsd_prefix: opts.statsd_prefix,
+        statsd_tags: opts.statsd_tag,
+        graphite_addr: opts.graphite_addr,
+        graphite_prefix: opts.graphite_prefix,
+        graphite_host: opts.graphite_host,
+        publish_deadband_temp: opts.publish_deadband_temp,
+        publish_deadband_humidity: opts.publish_deadband_humidity,
+        publish_max_interval: Duration::from_secs(opts.publish_max_interval_secs),
+        pushgateway_url: opts.pushgateway_url,
+        push_interval: Duration::from_secs(opts.push_interval_secs),
+        push_groups: opts.push_group,
+        push_auth: opts.push_username.zip(opts.push_password),
+        push_delete_on_exit: opts.push_delete_on_exit,
+        degraded_after_failures: opts.degraded_after_failures,
+        healthy_after_successes: opts.healthy_after_successes,
+        stuck_after_reads: opts.stuck_after_reads,
+        humidity_saturation_warn: Duration::from_secs(opts.humidity_saturation_warn_secs),
+        reset_when_stuck: opts.reset_when_stuck,
+        power_pin: opts.power_pin,
+        power_cycle_ms: opts.power_cycle_ms,
+        power_cycle_cooldown: Duration::from_secs(opts.power_cycle_cooldown_secs),
+        state_webhook_url: opts.state_webhook_url,
+        dht_wake_high_ms: opts.dht_wake_high_ms,
+        dht_start_low_ms: opts.dht_start_low_ms,
+        dht_start_high_us: opts.dht_start_high_us,
+        dht_max_cycles: opts.dht_max_cycles,
+        dht_min_read_interval_ms: opts.dht_min_read_interval_ms,
+        dht_calibrate_timing: opts.dht_calibrate_timing,
+        dht_validate_response: opts.dht_validate_response,
+        strict_frames: opts.strict_frames,
+        canary_strategy: opts.canary_strategy,
+        read_retries: opts.read_retries,
+        samples_per_refresh: opts.samples_per_refresh,
+        min_samples: opts.min_samples,
+        read_budget: opts
+            .read_budget_secs
+            .map(Duration::from_secs)
+            .unwrap_or_else(|| (refresh / 2).max(samples_window)),
+        filter: opts.filter,
+        filter_window: opts.filter_window,
+        filter_threshold: opts.filter_threshold,
+        filter_reaccept_after: opts.filter_reaccept_after,
+        publish_delay_one_sample: opts.publish_delay_one_sample,
+        spike_threshold: opts.spike_threshold,
+        error_log_interval: Duration::from_secs(opts.error_log_interval_secs),
+        read_on_scrape: opts.read_on_scrape,
+        backfill_readings: opts.backfill_readings,
+        ws_max_connections: opts.ws_max_connections,
+        sse_replay_events: opts.sse_replay_events,
+        require_sensor_at_startup: opts.require_sensor_at_startup,
+        startup_probe_attempts: opts.startup_probe_attempts,
+        state_file: opts.state_file,
+        state_max_age: Duration::from_secs(opts.state_max_age_secs),
+        persist_counters: opts.persist_counters,
+        summary_every: opts.summary_every,
+        temperature_unit: opts.temperature_unit,
+        leaf_temp_offset: opts.leaf_temp_offset,
+        trend_window: Duration::from_secs(opts.trend_window_secs),
+        temp_buckets,
+        humidity_buckets,
+        legacy_metric_names: opts.legacy_metric_names,
+        debug_metrics: opts.debug_metrics,
+        metrics,
+        fuse: opts.fuse,
+        otlp_endpoint: opts.otlp_endpoint,
+        otlp_protocol: opts.otlp_protocol,
+        otlp_interval: Duration::from_secs(opts.otlp_interval_secs),
+        modbus_bind: opts.modbus_bind,
+        udp_query_bind: opts.udp_query_bind,
+    })
+}
+
+/// Check that a sensor can be read as described by `spec` using `backend`, returning all
+/// problems with it. `pin_arg` is the option the pin was set with, for error messages.
+/// Also used to check specs of sensors replaced at runtime.
+pub fn validate_spec(spec: &SensorSpec, backend: GpioBackend, pin_arg: &str) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    if backend == GpioBackend::Rppal && spec.pin > MAX_BCM_PIN {
+        errors.push(format!(
+            "{} must be a BCM GPIO pin number from 0 to {}, got {}",
+            pin_arg, MAX_BCM_PIN, spec.pin
+        ));
+    }
+
+    if let Some(r) = spec.refresh.filter(|r| r.as_secs() < MIN_REFRESH_SECS) {
+        errors.push(format!(
+            "--sensor refresh must be at least {} since the sensor can't be read more often, got {}",
+            MIN_REFRESH_SECS,
+            r.as_secs()
+        ));
+    }
+
+    errors
+}
+
+/// Make sure histogram bucket bounds are finite and strictly increasing
+fn validate_buckets(buckets: &[f64]) -> Result<(), String> {
+    if let Some(b) = buckets.iter().find(|b| !b.is_finite()) {
+        return Err(format!("must be finite numbers, got {}", b));
+    }
+
+    if let Some(w) = buckets.windows(2).find(|w| w[0] >= w[1]) {
+        return Err(format!("must be increasing, got {} followed by {}", w[0], w[1]));
+    }
+
+    Ok(())
+}
+
+/// Parse a 'key=value' Pushgateway grouping label
+fn parse_group(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .filter(|(k, _)| !k.is_empty())
+        .map(|(k, v)| (k.to_owned(), v.to_owned()))
+        .ok_or_else(|| format!("expected 'key=value', got '{}'", s))
+}
+
+/// Parse a finite number, accepting either `.` or `,` as the decimal separator.
+///
+/// Surrounding whitespace is ignored and scientific notation like `1.5e3` is allowed.
+/// Grouping separators aren't, so a number may only have a single decimal separator.
+/// NaN and infinity are rejected with the rest of the invalid input. Options taking
+/// comma separated lists of numbers split them before each number is parsed, so those
+/// numbers can only use `.` as the decimal separator.
+pub fn parse_float(s: &str) -> Result<f64, String> {
+    let trimmed = s.trim();
+    let normalized = match (trimmed.matches(',').count(), trimmed.contains('.')) {
+        (0, _) => Some(trimmed.to_owned()),
+        (1, false) => Some(trimmed.replace(',', ".")),
+        _ => None,
+    };
+
+    normalized
+        .and_then(|n| n.parse::<f64>().ok())
+        .filter(|v| v.is_finite())
+        .ok_or_else(|| {
+            format!(
+                "invalid number '{}', expected a finite decimal number like 1.5, 1,5, -2, or 1.5e3",
+                s
+            )
+        })
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::{
+        parse_float, validate, validate_buckets, validate_spec, StrudelApplication, DEFAULT_PUBLISH_MAX_INTERVAL_SECS,
+        DEFAULT_STATE_MAX_AGE_SECS, DEFAULT_STUCK_AFTER_READS, DEFAULT_SUMMARY_EVERY,
+    };
+    use crate::app::{CanaryStrategy, Config, GpioBackend, OtlpProtocol, ReadingFilter};
+    use crate::device::PulseTiming;
+    use crate::metrics::MetricsConfig;
+    use crate::sensor::{SensorSpec, TemperatureUnit};
+    use clap::error::ErrorKind;
+    use clap::Parser;
+    use std::env;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    // Environment variables are global to the process so tests that set them
+    // must not run concurrently.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Set environment variables for the lifetime of this object, removing them on drop
+    struct ScopedEnv {
+        keys: Vec<&'static str>,
+    }
+
+    impl ScopedEnv {
+        fn new(vars: &[(&'static str, &str)]) -> Self {
+            for (k, v) in vars {
+                env::set_var(k, v);
+            }
+
+            Self {
+                keys: vars.iter().map(|(k, _)| *k).collect(),
+            }
+        }
+    }
+
+    impl Drop for ScopedEnv {
+        fn drop(&mut self) {
+            for k in self.keys.iter() {
+                env::remove_var(k);
+            }
+        }
+    }
+
+    #[test]
+    fn test_env_used_when_flag_missing() {
+        let _lock = ENV_LOCK.lock().unwrap();
+        let _env = ScopedEnv::new(&[
+            ("STRUDEL_BCM_PIN", "17"),
+            ("STRUDEL_REFRESH_SECS", "10"),
+            ("STRUDEL_PUSH_DELETE_ON_EXIT", "1"),
+            ("STRUDEL_STATSD_TAG", "sensor:indoor,room:office"),
+        ]);
+
+        let opts = StrudelApplication::try_parse_from(["strudel"]).unwrap();
+
+        assert_eq!(Some(17), opts.bcm_pin);
+        assert_eq!(10, opts.refresh_secs);
+        assert!(opts.push_delete_on_exit);
+        assert_eq!(vec!["sensor:indoor", "room:office"], opts.statsd_tag);
+    }
+
+    #[test]
+    fn test_flag_overrides_env() {
+        let _lock = ENV_LOCK.lock().unwrap();
+        let _env = ScopedEnv::new(&[("STRUDEL_BCM_PIN", "17"), ("STRUDEL_PUSH_DELETE_ON_EXIT", "false")]);
+
+        let opts = StrudelApplication::try_parse_from(["strudel", "--bcm-pin", "4"]).unwrap();
+
+        assert_eq!(Some(4), opts.bcm_pin);
+        assert_eq!(30, opts.refresh_secs);
+        assert!(!opts.push_delete_on_exit);
+    }
+
+    #[test]
+    fn test_invalid_env() {
+        let _lock = ENV_LOCK.lock().unwrap();
+        let _env = ScopedEnv::new(&[("STRUDEL_BCM_PIN", "seventeen")]);
+
+        let err = StrudelApplication::try_parse_from(["strudel"]).unwrap_err();
+
+        assert_eq!(ErrorKind::ValueValidation, err.kind());
+        assert!(err
+            .to_string()
+            .contains("invalid value 'seventeen' for '--bcm-pin <BCM_PIN>'"));
+    }
+
+    #[test]
+    fn test_invalid_env_bool() {
+        let _lock = ENV_LOCK.lock().unwrap();
+        let _env = ScopedEnv::new(&[("STRUDEL_BCM_PIN", "17"), ("STRUDEL_PUSH_DELETE_ON_EXIT", "maybe")]);
+
+        let err = StrudelApplication::try_parse_from(["strudel"]).unwrap_err();
+
+        assert_eq!(ErrorKind::ValueValidation, err.kind());
+        assert!(err
+            .to_string()
+            .contains("invalid value 'maybe' for '--push-delete-on-exit'"));
+    }
+
+    pub(crate) fn parse_and_validate(args: &[&str]) -> Result<Config, Vec<String>> {
+        let _lock = ENV_LOCK.lock().unwrap();
+        let mut all = vec!["strudel"];
+        all.extend_from_slice(args);
+        validate(StrudelApplication::try_parse_from(all).unwrap(), false)
+    }
+
+    fn assert_invalid(args: &[&str], expected: &str) {
+        let errors = parse_and_validate(args).unwrap_err();
+        assert_eq!(1, errors.len(), "unexpected errors: {:?}", errors);
+        assert!(errors[0].starts_with(expected), "unexpected error: {}", errors[0]);
+    }
+
+    fn assert_unparseable(args: &[&str], expected: &str) {
+        let mut all = vec!["strudel"];
+        all.extend_from_slice(args);
+        let err = StrudelApplication::try_parse_from(all).unwrap_err().to_string();
+        assert!(err.contains(expected), "unexpected error: {}", err);
+    }
+
+    #[test]
+    fn test_validate_defaults() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+
+        assert_eq!(vec![SensorSpec::new(17)], opts.sensors);
+        assert_eq!(Some(([0, 0, 0, 0], 9781).into()), opts.bind);
+        assert_eq!(Duration::from_secs(30), opts.refresh);
+        assert_eq!(Duration::from_secs(60), opts.push_interval);
+        assert_eq!(None, opts.push_auth);
+    }
+
+    #[test]
+    fn test_validate_bcm_pin() {
+        assert!(parse_and_validate(&["--bcm-pin", "27"]).is_ok());
+        assert_invalid(
+            &["--bcm-pin", "58"],
+            "--bcm-pin must be a BCM GPIO pin number from 0 to 27",
+        );
+    }
+
+    #[test]
+    fn test_validate_gpio_backend() {
+        let opts = parse_and_validate(&["--bcm-pin", "58", "--gpio-backend", "cdev"]).unwrap();
+        assert_eq!(GpioBackend::Cdev, opts.gpio_backend);
+        assert_eq!("/dev/gpiochip0", opts.gpio_chip);
+
+        assert_invalid(
+            &["--bcm-pin", "17", "--gpio-backend", "cdev", "--gpio-chip", ""],
+            "--gpio-chip must not be empty",
+        );
+    }
+
+    #[test]
+    fn test_validate_refresh_secs() {
+        assert!(parse_and_validate(&["--bcm-pin", "17", "--refresh-secs", "2"]).is_ok());
+        assert_invalid(
+            &["--bcm-pin", "17", "--refresh-secs", "0"],
+            "--refresh-secs must be at least 2",
+        );
+    }
+
+    #[test]
+    fn test_validate_sensor() {
+        let opts = parse_and_validate(&["--sensor", "pin=17,name=indoor,refresh=15,temp_offset=-0.8"]).unwrap();
+        assert_eq!(1, opts.sensors.len());
+        assert_eq!(17, opts.sensors[0].pin);
+        assert_eq!(Some("indoor".to_owned()), opts.sensors[0].name);
+        assert_eq!(-0.8, opts.sensors[0].temp_offset);
+        assert_eq!(Some(Duration::from_secs(15)), opts.sensors[0].refresh);
+        assert_eq!(Duration::from_secs(30), opts.refresh);
+        assert_eq!(Duration::from_millis(7500), opts.read_budget);
+
+        // The global refresh interval is the default for sensors that don't set one
+        let opts = parse_and_validate(&["--sensor", "pin=4", "--refresh-secs", "120"]).unwrap();
+        assert_eq!(4, opts.sensors[0].pin);
+        assert_eq!(None, opts.sensors[0].name);
+        assert_eq!(None, opts.sensors[0].refresh);
+        assert_eq!(Duration::from_secs(120), opts.refresh);
+
+        assert_invalid(&[], "--bcm-pin or --sensor must be set");
+        assert_invalid(
+            &["--bcm-pin", "17", "--sensor", "pin=4"],
+            "--bcm-pin must not be set when using --sensor",
+        );
+        assert_invalid(
+            &["--sensor", "pin=58"],
+            "--sensor pin must be a BCM GPIO pin number from 0 to 27",
+        );
+        assert_invalid(&["--sensor", "pin=17,refresh=1"], "--sensor refresh must be at least 2");
+        assert_invalid(
+            &["--sensor", "pin=17,refresh=10", "--read-budget-secs", "11"],
+            "--read-budget-secs must be at most the refresh interval (10), got 11",
+        );
+        assert_invalid(
+            &["--sensor", "pin=17,name=indoor", "--sensor", "pin=4,name=indoor"],
+            "--sensor duplicate sensor name 'indoor'",
+        );
+    }
+
+    #[test]
+    fn test_validate_multiple_sensors() {
+        let opts = parse_and_validate(&[
+            "--sensor",
+            "pin=17,name=indoor,refresh=15",
+            "--sensor",
+            "pin=4,name=outdoor",
+            "--refresh-secs",
+            "120",
+        ])
+        .unwrap();
+        assert_eq!(2, opts.sensors.len());
+        assert_eq!(Some("indoor".to_owned()), opts.sensors[0].name);
+        assert_eq!(Duration::from_secs(15), opts.sensors[0].refresh_or(opts.refresh));
+        assert_eq!(Some("outdoor".to_owned()), opts.sensors[1].name);
+        assert_eq!(Duration::from_secs(120), opts.sensors[1].refresh_or(opts.refresh));
+        // Reads have to fit in the shortest refresh interval
+        assert_eq!(Duration::from_millis(7500), opts.read_budget);
+
+        assert_invalid(
+            &["--sensor", "pin=17,name=indoor", "--sensor", "pin=4"],
+            "--sensor name must be set for every sensor when there's more than one",
+        );
+        assert_invalid(
+            &["--sensor", "pin=17,name=indoor", "--sensor", "pin=17,name=outdoor"],
+            "--sensor pin 17 is used by more than one sensor",
+        );
+        assert_invalid(
+            &[
+                "--sensor",
+                "pin=17,name=indoor",
+                "--sensor",
+                "pin=4,name=outdoor,refresh=10",
+                "--read-budget-secs",
+                "11",
+            ],
+            "--read-budget-secs must be at most the refresh interval (10), got 11",
+        );
+        assert_invalid(
+            &[
+                "--sensor",
+                "pin=17,name=indoor",
+                "--sensor",
+                "pin=4,name=outdoor",
+                "--power-pin",
+                "4",
+            ],
+            "--power-pin must be different from --sensor pin (4)",
+        );
+    }
+
+    #[test]
+    fn test_invalid_sensor_spec() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        let err = StrudelApplication::try_parse_from(["strudel", "--sensor", "pin=17,colour=blue"]).unwrap_err();
+        assert_eq!(ErrorKind::ValueValidation, err.kind());
+        assert!(err.to_string().contains("unknown key 'colour'"));
+
+        let err = StrudelApplication::try_parse_from(["strudel", "--sensor", "pin=17,pin=4"]).unwrap_err();
+        assert_eq!(ErrorKind::ValueValidation, err.kind());
+        assert!(err.to_string().contains("duplicate key 'pin'"));
+    }
+
+    #[test]
+    fn test_validate_instance_id() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert!(!opts.instance_id.is_empty());
+
+        let opts = parse_and_validate(&["--bcm-pin", "17", "--instance-id", "pi-office"]).unwrap();
+        assert_eq!("pi-office", opts.instance_id);
+
+        let opts = parse_and_validate(&["--bcm-pin", "17", "--instance-id", "office/pi #2"]).unwrap();
+        assert_eq!("office_pi__2", opts.instance_id);
+
+        assert_invalid(
+            &["--bcm-pin", "17", "--instance-id", " "],
+            "--instance-id must not be empty",
+        );
+    }
+
+    #[test]
+    fn test_validate_bind_port() {
+        assert_invalid(
+            &["--bcm-pin", "17", "--bind", "127.0.0.1:0"],
+            "--bind must use a non-zero port",
+        );
+        assert!(parse_and_validate(&["--bcm-pin", "17", "--bind", "127.0.0.1:0", "--allow-random-port"]).is_ok());
+    }
+
+    #[test]
+    fn test_validate_socket_activation() {
+        let _lock = ENV_LOCK.lock().unwrap();
+
+        let opts = validate(
+            StrudelApplication::try_parse_from(["strudel", "--bcm-pin", "17"]).unwrap(),
+            true,
+        );
+        assert_eq!(None, opts.unwrap().bind);
+
+        let args = StrudelApplication::try_parse_from(["strudel", "--bcm-pin", "17", "--bind", "127.0.0.1:9781"]);
+        let errors = validate(args.unwrap(), true).unwrap_err();
+        assert_eq!(1, errors.len());
+        assert!(errors[0].starts_with("--bind must not be set when using systemd socket activation"));
+    }
+
+    #[test]
+    fn test_validate_no_http() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert!(!opts.no_http);
+
+        let opts = parse_and_validate(&["--bcm-pin", "17", "--no-http", "--statsd-addr", "127.0.0.1:8125"]).unwrap();
+        assert!(opts.no_http);
+        assert_eq!(None, opts.bind);
+
+        assert_invalid(
+            &["--bcm-pin", "17", "--no-http", "--bind", "127.0.0.1:9781"],
+            "--no-http can't be combined with --bind",
+        );
+        assert_invalid(
+            &["--bcm-pin", "17", "--no-http", "--enable-lifecycle"],
+            "--no-http can't be combined with --enable-lifecycle",
+        );
+        assert_invalid(
+            &["--bcm-pin", "17", "--no-http", "--cors-allow-origin", "*"],
+            "--no-http can't be combined with --cors-allow-origin",
+        );
+        assert_invalid(
+            &["--bcm-pin", "17", "--no-http", "--read-on-scrape"],
+            "--no-http can't be combined with --read-on-scrape",
+        );
+        assert_invalid(
+            &["--bcm-pin", "17", "--no-http", "--backfill-readings", "10"],
+            "--no-http can't be combined with --backfill-readings",
+        );
+
+        let errors = parse_and_validate(&[
+            "--bcm-pin",
+            "17",
+            "--no-http",
+            "--enable-lifecycle",
+            "--lifecycle-token",
+            "secret",
+        ])
+        .unwrap_err();
+        assert_eq!(2, errors.len(), "unexpected errors: {:?}", errors);
+        assert!(errors[1].starts_with("--no-http can't be combined with --lifecycle-token"));
+
+        let _lock = ENV_LOCK.lock().unwrap();
+        let args = StrudelApplication::try_parse_from(["strudel", "--bcm-pin", "17", "--no-http"]);
+        let errors = validate(args.unwrap(), true).unwrap_err();
+        assert_eq!(
+            vec!["--no-http can't be used with systemd socket activation".to_owned()],
+            errors
+        );
+    }
+
+    #[test]
+    fn test_validate_prefixes() {
+        assert_invalid(
+            &["--bcm-pin", "17", "--statsd-prefix", ""],
+            "--statsd-prefix must not be empty",
+        );
+        assert_invalid(
+            &["--bcm-pin", "17", "--graphite-prefix", ""],
+            "--graphite-prefix must not be empty",
+        );
+    }
+
+    #[test]
+    fn test_validate_pushgateway() {
+        assert!(parse_and_validate(&["--bcm-pin", "17", "--pushgateway-url", "http://localhost:9091"]).is_ok());
+        assert_invalid(
+            &["--bcm-pin", "17", "--pushgateway-url", "https://localhost:9091"],
+            "--pushgateway-url must be an 'http://' URL",
+        );
+        assert_invalid(
+            &["--bcm-pin", "17", "--push-interval-secs", "0"],
+            "--push-interval-secs must be at least 1",
+        );
+    }
+
+    #[test]
+    fn test_validate_health_thresholds() {
+        assert_invalid(
+            &["--bcm-pin", "17", "--degraded-after-failures", "0"],
+            "--degraded-after-failures must be at least 1",
+        );
+        assert_invalid(
+            &["--bcm-pin", "17", "--healthy-after-successes", "0"],
+            "--healthy-after-successes must be at least 1",
+        );
+    }
+
+    #[test]
+    fn test_validate_dht_max_cycles() {
+        assert_invalid(
+            &["--bcm-pin", "17", "--dht-max-cycles", "0"],
+            "--dht-max-cycles must be at least 1",
+        );
+    }
+
+    #[test]
+    fn test_dht_calibrate_timing() {
+        let config = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert!(!config.dht_calibrate_timing);
+
+        let config = parse_and_validate(&["--bcm-pin", "17", "--dht-calibrate-timing"]).unwrap();
+        assert!(config.dht_calibrate_timing);
+    }
+
+    #[test]
+    fn test_dht_validate_response() {
+        let config = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert!(!config.dht_validate_response);
+
+        let config =
+            parse_and_validate(&["--bcm-pin", "17", "--dht-calibrate-timing", "--dht-validate-response"]).unwrap();
+        assert!(config.dht_validate_response);
+    }
+
+    #[test]
+    fn test_strict_frames() {
+        let config = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert!(!config.strict_frames);
+
+        let config = parse_and_validate(&["--bcm-pin", "17", "--strict-frames"]).unwrap();
+        assert!(config.strict_frames);
+    }
+
+    #[test]
+    fn test_validate_dht_validate_response() {
+        assert_invalid(
+            &["--bcm-pin", "17", "--dht-validate-response"],
+            "--dht-validate-response requires --dht-calibrate-timing",
+        );
+    }
+
+    #[test]
+    fn test_stuck_after_reads() {
+        let config = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert_eq!(DEFAULT_STUCK_AFTER_READS, config.stuck_after_reads);
+        assert!(!config.reset_when_stuck);
+
+        let config =
+            parse_and_validate(&["--bcm-pin", "17", "--stuck-after-reads", "5", "--reset-when-stuck"]).unwrap();
+        assert_eq!(5, config.stuck_after_reads);
+        assert!(config.reset_when_stuck);
+    }
+
+    #[test]
+    fn test_validate_stuck_after_reads() {
+        assert_invalid(
+            &["--bcm-pin", "17", "--stuck-after-reads", "1"],
+            "--stuck-after-reads must be at least 2",
+        );
+    }
+
+    #[test]
+    fn test_validate_startup_probe_attempts() {
+        assert_invalid(
+            &["--bcm-pin", "17", "--startup-probe-attempts", "0"],
+            "--startup-probe-attempts must be at least 1",
+        );
+    }
+
+    #[test]
+    fn test_validate_summary_every() {
+        assert_invalid(
+            &["--bcm-pin", "17", "--summary-every", "0"],
+            "--summary-every must be at least 1",
+        );
+
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert_eq!(DEFAULT_SUMMARY_EVERY, opts.summary_every);
+    }
+
+    #[test]
+    fn test_validate_state_file() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert_eq!(None, opts.state_file);
+        assert_eq!(Duration::from_secs(DEFAULT_STATE_MAX_AGE_SECS), opts.state_max_age);
+
+        let opts = parse_and_validate(&[
+            "--bcm-pin",
+            "17",
+            "--state-file",
+            "/var/lib/strudel/state.json",
+            "--state-max-age-secs",
+            "300",
+        ])
+        .unwrap();
+        assert_eq!(Some(PathBuf::from("/var/lib/strudel/state.json")), opts.state_file);
+        assert_eq!(Duration::from_secs(300), opts.state_max_age);
+
+        assert_invalid(
+            &["--bcm-pin", "17", "--state-max-age-secs", "0"],
+            "--state-max-age-secs must be at least 1",
+        );
+    }
+
+    #[test]
+    fn test_validate_persist_counters() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert!(!opts.persist_counters);
+
+        let opts = parse_and_validate(&[
+            "--bcm-pin",
+            "17",
+            "--state-file",
+            "/var/lib/strudel/state.json",
+            "--persist-counters",
+        ])
+        .unwrap();
+        assert!(opts.persist_counters);
+
+        assert_invalid(
+            &["--bcm-pin", "17", "--persist-counters"],
+            "--persist-counters requires --state-file",
+        );
+    }
+
+    #[test]
+    fn test_temperature_unit() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert_eq!(TemperatureUnit::Celsius, opts.temperature_unit);
+
+        let opts = parse_and_validate(&["--bcm-pin", "17", "--temperature-unit", "kelvin"]).unwrap();
+        assert_eq!(TemperatureUnit::Kelvin, opts.temperature_unit);
+
+        let _lock = ENV_LOCK.lock().unwrap();
+        let err = StrudelApplication::try_parse_from(["strudel", "--bcm-pin", "17", "--temperature-unit", "rankine"]);
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn test_legacy_metric_names() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert!(!opts.legacy_metric_names);
+
+        let opts = parse_and_validate(&["--bcm-pin", "17", "--legacy-metric-names"]).unwrap();
+        assert!(opts.legacy_metric_names);
+    }
+
+    #[test]
+    fn test_read_on_scrape() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert!(!opts.read_on_scrape);
+
+        let opts = parse_and_validate(&["--bcm-pin", "17", "--read-on-scrape", "--read-retries", "2"]).unwrap();
+        assert!(opts.read_on_scrape);
+        assert_eq!(2, opts.read_retries);
+    }
+
+    #[test]
+    fn test_enable_lifecycle() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert!(!opts.enable_lifecycle);
+
+        let opts = parse_and_validate(&["--bcm-pin", "17", "--enable-lifecycle"]).unwrap();
+        assert!(opts.enable_lifecycle);
+    }
+
+    #[test]
+    fn test_validate_lifecycle_token() {
+        let opts = parse_and_validate(&["--bcm-pin", "17", "--enable-lifecycle"]).unwrap();
+        assert_eq!(None, opts.lifecycle_token);
+
+        let opts =
+            parse_and_validate(&["--bcm-pin", "17", "--enable-lifecycle", "--lifecycle-token", "secret"]).unwrap();
+        assert_eq!(Some("secret".to_owned()), opts.lifecycle_token);
+
+        assert_invalid(
+            &["--bcm-pin", "17", "--lifecycle-token", "secret"],
+            "--lifecycle-token requires --enable-lifecycle",
+        );
+        assert_invalid(
+            &["--bcm-pin", "17", "--enable-lifecycle", "--lifecycle-token", ""],
+            "--lifecycle-token must not be empty",
+        );
+    }
+
+    #[test]
+    fn test_validate_spec() {
+        let spec: SensorSpec = "pin=17,refresh=15".parse().unwrap();
+        assert!(validate_spec(&spec, GpioBackend::Rppal, "--sensor pin").is_empty());
+
+        // The same messages are used for specs checked at startup and at runtime
+        let spec: SensorSpec = "pin=40,refresh=1".parse().unwrap();
+        let errors = validate_spec(&spec, GpioBackend::Rppal, "--sensor pin");
+        assert_eq!(
+            vec![
+                "--sensor pin must be a BCM GPIO pin number from 0 to 27, got 40".to_owned(),
+                "--sensor refresh must be at least 2 since the sensor can't be read more often, got 1".to_owned(),
+            ],
+            errors
+        );
+        assert_eq!(
+            errors,
+            parse_and_validate(&["--sensor", "pin=40,refresh=1"]).unwrap_err()
+        );
+
+        // Line offsets of GPIO chips aren't limited to BCM pin numbers
+        let spec: SensorSpec = "pin=40".parse().unwrap();
+        assert!(validate_spec(&spec, GpioBackend::Cdev, "--sensor pin").is_empty());
+    }
+
+    #[test]
+    fn test_validate_cors() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert!(opts.cors_allow_origin.is_empty());
+        assert!(!opts.cors_all_routes);
+
+        let opts = parse_and_validate(&[
+            "--bcm-pin",
+            "17",
+            "--cors-allow-origin",
+            "http://dashboard.local",
+            "--cors-allow-origin",
+            "https://example.com:8443",
+            "--cors-all-routes",
+        ])
+        .unwrap();
+        assert_eq!(
+            vec!["http://dashboard.local", "https://example.com:8443"],
+            opts.cors_allow_origin
+        );
+        assert!(opts.cors_all_routes);
+
+        let opts = parse_and_validate(&["--bcm-pin", "17", "--cors-allow-origin", "*"]).unwrap();
+        assert_eq!(vec!["*"], opts.cors_allow_origin);
+
+        assert_invalid(
+            &["--bcm-pin", "17", "--cors-allow-origin", "dashboard.local"],
+            "--cors-allow-origin must be '*' or an 'http://' or 'https://' origin, got 'dashboard.local'",
+        );
+        assert_invalid(
+            &["--bcm-pin", "17", "--cors-allow-origin", "*,http://dashboard.local"],
+            "--cors-allow-origin '*' can't be combined with other origins",
+        );
+        assert_invalid(
+            &["--bcm-pin", "17", "--cors-all-routes"],
+            "--cors-all-routes requires --cors-allow-origin",
+        );
+    }
+
+    #[test]
+    fn test_debug_metrics() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert!(!opts.debug_metrics);
+
+        let opts = parse_and_validate(&["--bcm-pin", "17", "--debug-metrics"]).unwrap();
+        assert!(opts.debug_metrics);
+    }
+
+    #[test]
+    fn test_validate_otlp() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert_eq!(None, opts.otlp_endpoint);
+        assert_eq!(OtlpProtocol::Grpc, opts.otlp_protocol);
+        assert_eq!(Duration::from_secs(60), opts.otlp_interval);
+
+        assert_invalid(
+            &["--bcm-pin", "17", "--otlp-interval-secs", "0"],
+            "--otlp-interval-secs must be at least 1",
+        );
+    }
+
+    #[cfg(feature = "otlp")]
+    #[test]
+    fn test_validate_otlp_enabled() {
+        let opts = parse_and_validate(&[
+            "--bcm-pin",
+            "17",
+            "--otlp-endpoint",
+            "http://localhost:4318",
+            "--otlp-protocol",
+            "http",
+        ])
+        .unwrap();
+
+        assert_eq!(Some("http://localhost:4318".to_owned()), opts.otlp_endpoint);
+        assert_eq!(OtlpProtocol::Http, opts.otlp_protocol);
+
+        assert_invalid(
+            &["--bcm-pin", "17", "--otlp-endpoint", "localhost:4317"],
+            "--otlp-endpoint must be an 'http://' URL",
+        );
+    }
+
+    #[cfg(not(feature = "otlp"))]
+    #[test]
+    fn test_validate_otlp_disabled() {
+        assert_invalid(
+            &["--bcm-pin", "17", "--otlp-endpoint", "http://localhost:4317"],
+            "--otlp-endpoint requires strudel to be built with the 'otlp' feature",
+        );
+    }
+
+    #[cfg(feature = "modbus")]
+    #[test]
+    fn test_validate_modbus_enabled() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert_eq!(None, opts.modbus_bind);
+
+        let opts = parse_and_validate(&["--bcm-pin", "17", "--modbus-bind", "0.0.0.0:5020"]).unwrap();
+        assert_eq!(Some(([0, 0, 0, 0], 5020).into()), opts.modbus_bind);
+
+        assert_invalid(
+            &[
+                "--bcm-pin",
+                "17",
+                "--bind",
+                "0.0.0.0:5020",
+                "--modbus-bind",
+                "0.0.0.0:5020",
+            ],
+            "--modbus-bind must be different from --bind",
+        );
+    }
+
+    #[cfg(not(feature = "modbus"))]
+    #[test]
+    fn test_validate_modbus_disabled() {
+        assert_invalid(
+            &["--bcm-pin", "17", "--modbus-bind", "0.0.0.0:5020"],
+            "--modbus-bind requires strudel to be built with the 'modbus' feature",
+        );
+    }
+
+    #[test]
+    fn test_validate_udp_query_bind() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert_eq!(None, opts.udp_query_bind);
+
+        // UDP and TCP ports are separate so the same address as --bind is fine
+        let opts = parse_and_validate(&[
+            "--bcm-pin",
+            "17",
+            "--bind",
+            "0.0.0.0:9101",
+            "--udp-query-bind",
+            "0.0.0.0:9101",
+        ])
+        .unwrap();
+        assert_eq!(Some(([0, 0, 0, 0], 9101).into()), opts.udp_query_bind);
+
+        assert_unparseable(
+            &["--bcm-pin", "17", "--udp-query-bind", "9101"],
+            "invalid socket address",
+        );
+    }
+
+    #[test]
+    fn test_validate_read_budget_secs() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert_eq!(Duration::from_secs(15), opts.read_budget);
+
+        let opts = parse_and_validate(&["--bcm-pin", "17", "--refresh-secs", "5"]).unwrap();
+        assert_eq!(Duration::from_millis(2500), opts.read_budget);
+
+        let opts = parse_and_validate(&["--bcm-pin", "17", "--read-budget-secs", "20"]).unwrap();
+        assert_eq!(Duration::from_secs(20), opts.read_budget);
+
+        assert_invalid(
+            &["--bcm-pin", "17", "--read-budget-secs", "0"],
+            "--read-budget-secs must be at least 1",
+        );
+        assert_invalid(
+            &["--bcm-pin", "17", "--refresh-secs", "10", "--read-budget-secs", "11"],
+            "--read-budget-secs must be at most the refresh interval (10), got 11",
+        );
+    }
+
+    #[test]
+    fn test_validate_filter() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert_eq!(ReadingFilter::None, opts.filter);
+        assert_eq!(20, opts.filter_window);
+        assert_eq!(5.0, opts.filter_threshold);
+        assert_eq!(3, opts.filter_reaccept_after);
+
+        let opts = parse_and_validate(&[
+            "--bcm-pin",
+            "17",
+            "--filter",
+            "mad",
+            "--filter-window",
+            "10",
+            "--filter-threshold",
+            "3.5",
+            "--filter-reaccept-after",
+            "5",
+        ])
+        .unwrap();
+        assert_eq!(ReadingFilter::Mad, opts.filter);
+        assert_eq!(10, opts.filter_window);
+        assert_eq!(3.5, opts.filter_threshold);
+        assert_eq!(5, opts.filter_reaccept_after);
+
+        assert_invalid(
+            &["--bcm-pin", "17", "--filter-window", "2"],
+            "--filter-window must be at least 3, got 2",
+        );
+        assert_invalid(
+            &["--bcm-pin", "17", "--filter-threshold", "0"],
+            "--filter-threshold must be greater than zero, got 0",
+        );
+        assert_unparseable(
+            &["--bcm-pin", "17", "--filter-threshold", "NaN"],
+            "invalid number 'NaN'",
+        );
+        assert_invalid(
+            &["--bcm-pin", "17", "--filter-reaccept-after", "0"],
+            "--filter-reaccept-after must be at least 1",
+        );
+    }
+
+    #[test]
+    fn test_validate_publish_delay_one_sample() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert!(!opts.publish_delay_one_sample);
+        assert_eq!(2.0, opts.spike_threshold);
+
+        let opts = parse_and_validate(&[
+            "--bcm-pin",
+            "17",
+            "--publish-delay-one-sample",
+            "--spike-threshold",
+            "1.5",
+        ])
+        .unwrap();
+        assert!(opts.publish_delay_one_sample);
+        assert_eq!(1.5, opts.spike_threshold);
+
+        assert_invalid(
+            &["--bcm-pin", "17", "--spike-threshold", "0"],
+            "--spike-threshold must be greater than zero, got 0",
+        );
+        assert_invalid(
+            &["--bcm-pin", "17", "--read-on-scrape", "--publish-delay-one-sample"],
+            "--publish-delay-one-sample can't be used with --read-on-scrape",
+        );
+    }
+
+    #[test]
+    fn test_validate_samples_per_refresh() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert_eq!(1, opts.samples_per_refresh);
+        assert_eq!(1, opts.min_samples);
+
+        let opts =
+            parse_and_validate(&["--bcm-pin", "17", "--samples-per-refresh", "3", "--min-samples", "2"]).unwrap();
+        assert_eq!(3, opts.samples_per_refresh);
+        assert_eq!(2, opts.min_samples);
+        assert_eq!(Duration::from_secs(15), opts.read_budget);
+
+        // The default budget is long enough to read every sample
+        let opts =
+            parse_and_validate(&["--bcm-pin", "17", "--refresh-secs", "6", "--samples-per-refresh", "3"]).unwrap();
+        assert_eq!(Duration::from_secs(6), opts.read_budget);
+
+        assert_invalid(
+            &["--bcm-pin", "17", "--samples-per-refresh", "0"],
+            "--samples-per-refresh must be at least 1",
+        );
+        assert_invalid(
+            &["--bcm-pin", "17", "--refresh-secs", "5", "--samples-per-refresh", "3"],
+            "--samples-per-refresh must fit in the refresh interval (5) at 2 seconds per sample, got 3",
+        );
+        assert_invalid(
+            &["--sensor", "pin=17,refresh=4", "--samples-per-refresh", "3"],
+            "--samples-per-refresh must fit in the refresh interval (4)",
+        );
+        assert_invalid(
+            &["--bcm-pin", "17", "--min-samples", "0"],
+            "--min-samples must be from 1 to --samples-per-refresh (1), got 0",
+        );
+        assert_invalid(
+            &["--bcm-pin", "17", "--samples-per-refresh", "3", "--min-samples", "4"],
+            "--min-samples must be from 1 to --samples-per-refresh (3), got 4",
+        );
+    }
+
+    #[test]
+    fn test_validate_canary_strategy() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert_eq!(None, opts.canary_strategy);
+
+        let opts = parse_and_validate(&["--bcm-pin", "17", "--canary-strategy", "timed"]).unwrap();
+        assert_eq!(Some(CanaryStrategy::Timed), opts.canary_strategy);
+        assert_eq!(PulseTiming::Calibrated, CanaryStrategy::Timed.timing());
+
+        let opts = parse_and_validate(&[
+            "--bcm-pin",
+            "17",
+            "--dht-calibrate-timing",
+            "--canary-strategy",
+            "relative",
+        ])
+        .unwrap();
+        assert_eq!(Some(CanaryStrategy::Relative), opts.canary_strategy);
+
+        // The read and the canary read two seconds later fit exactly
+        let opts =
+            parse_and_validate(&["--bcm-pin", "17", "--refresh-secs", "4", "--canary-strategy", "timed"]).unwrap();
+        assert_eq!(Duration::from_secs(4), opts.refresh);
+
+        assert_invalid(
+            &["--bcm-pin", "17", "--canary-strategy", "relative"],
+            "--canary-strategy 'relative' decodes pulses the same way as reads without --dht-calibrate-timing",
+        );
+        assert_invalid(
+            &[
+                "--bcm-pin",
+                "17",
+                "--dht-calibrate-timing",
+                "--canary-strategy",
+                "timed",
+            ],
+            "--canary-strategy 'timed' decodes pulses the same way as reads with --dht-calibrate-timing",
+        );
+        assert_invalid(
+            &["--bcm-pin", "17", "--read-on-scrape", "--canary-strategy", "timed"],
+            "--canary-strategy can't be used with --read-on-scrape",
+        );
+        assert_invalid(
+            &["--bcm-pin", "17", "--refresh-secs", "3", "--canary-strategy", "timed"],
+            "--canary-strategy needs 4 seconds for reads and the canary read, more than the refresh interval (3)",
+        );
+        assert_invalid(
+            &[
+                "--bcm-pin",
+                "17",
+                "--refresh-secs",
+                "6",
+                "--samples-per-refresh",
+                "3",
+                "--canary-strategy",
+                "timed",
+            ],
+            "--canary-strategy needs 8 seconds",
+        );
+    }
+
+    #[test]
+    fn test_validate_trend_window_secs() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert_eq!(Duration::from_secs(900), opts.trend_window);
+
+        assert_invalid(
+            &["--bcm-pin", "17", "--refresh-secs", "60", "--trend-window-secs", "30"],
+            "--trend-window-secs must be at least the refresh interval (60), got 30",
+        );
+    }
+
+    #[test]
+    fn test_validate_buckets() {
+        assert_eq!(Ok(()), validate_buckets(&[]));
+        assert_eq!(Ok(()), validate_buckets(&[-5.0, 0.0, 2.5]));
+        assert_eq!(
+            Err("must be increasing, got 2 followed by 2".to_owned()),
+            validate_buckets(&[1.0, 2.0, 2.0])
+        );
+        assert_eq!(
+            Err("must be increasing, got 3 followed by 1".to_owned()),
+            validate_buckets(&[3.0, 1.0])
+        );
+        assert_eq!(
+            Err("must be finite numbers, got inf".to_owned()),
+            validate_buckets(&[1.0, f64::INFINITY])
+        );
+        assert_eq!(
+            Err("must be finite numbers, got NaN".to_owned()),
+            validate_buckets(&[f64::NAN])
+        );
+    }
+
+    #[test]
+    fn test_validate_temp_buckets() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert_eq!(26, opts.temp_buckets.len());
+        assert_eq!(21, opts.humidity_buckets.len());
+
+        let opts = parse_and_validate(&[
+            "--bcm-pin",
+            "17",
+            "--temp-buckets",
+            "-5,0,5",
+            "--humidity-buckets",
+            "50",
+        ])
+        .unwrap();
+        assert_eq!(vec![-5.0, 0.0, 5.0], opts.temp_buckets);
+        assert_eq!(vec![50.0], opts.humidity_buckets);
+
+        assert_invalid(
+            &["--bcm-pin", "17", "--temp-buckets", "10,5"],
+            "--temp-buckets must be increasing",
+        );
+        assert_unparseable(
+            &["--bcm-pin", "17", "--humidity-buckets", "10,inf"],
+            "invalid number 'inf'",
+        );
+    }
+
+    #[test]
+    fn test_validate_disable_metric() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert_eq!(MetricsConfig::default(), opts.metrics);
+
+        let opts = parse_and_validate(&[
+            "--bcm-pin",
+            "17",
+            "--disable-metric",
+            "process,debug",
+            "--disable-metric",
+            "histograms",
+        ])
+        .unwrap();
+        assert_eq!(vec!["histograms", "debug", "process"], opts.metrics.disabled_names());
+        assert!(!opts.metrics.is_enabled(MetricsConfig::PROCESS));
+        assert!(opts.metrics.is_enabled(MetricsConfig::HTTP));
+
+        assert_invalid(
+            &["--bcm-pin", "17", "--disable-metric", "process,temperature_fahrenheit"],
+            "--disable-metric 'temperature_fahrenheit' is not a family of metrics, must be one of: vapour_pressure_deficit,",
+        );
+    }
+
+    #[test]
+    fn test_validate_error_log_interval_secs() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert_eq!(Duration::from_secs(300), opts.error_log_interval);
+
+        let opts = parse_and_validate(&["--bcm-pin", "17", "--error-log-interval-secs", "0"]).unwrap();
+        assert_eq!(Duration::ZERO, opts.error_log_interval);
+    }
+
+    #[test]
+    fn test_validate_ws_max_connections() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert_eq!(8, opts.ws_max_connections);
+
+        let opts = parse_and_validate(&["--bcm-pin", "17", "--ws-max-connections", "0"]).unwrap();
+        assert_eq!(0, opts.ws_max_connections);
+
+        assert_invalid(
+            &["--bcm-pin", "17", "--ws-max-connections", "1025"],
+            "--ws-max-connections must be at most 1024, got 1025",
+        );
+    }
+
+    #[test]
+    fn test_validate_sse_replay_events() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert_eq!(100, opts.sse_replay_events);
+
+        let opts = parse_and_validate(&["--bcm-pin", "17", "--sse-replay-events", "0"]).unwrap();
+        assert_eq!(0, opts.sse_replay_events);
+
+        assert_invalid(
+            &["--bcm-pin", "17", "--sse-replay-events", "100001"],
+            "--sse-replay-events must be at most 100000, got 100001",
+        );
+    }
+
+    #[test]
+    fn test_validate_backfill_readings() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert_eq!(0, opts.backfill_readings);
+
+        let opts = parse_and_validate(&["--bcm-pin", "17", "--backfill-readings", "240"]).unwrap();
+        assert_eq!(240, opts.backfill_readings);
+
+        assert_invalid(
+            &["--bcm-pin", "17", "--backfill-readings", "1000001"],
+            "--backfill-readings must be at most 1000000, got 1000001",
+        );
+    }
+
+    #[test]
+    fn test_validate_power_pin() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert_eq!(None, opts.power_pin);
+        assert_eq!(2000, opts.power_cycle_ms);
+        assert_eq!(Duration::from_secs(3600), opts.power_cycle_cooldown);
+
+        let opts = parse_and_validate(&[
+            "--bcm-pin",
+            "17",
+            "--power-pin",
+            "27",
+            "--power-cycle-ms",
+            "500",
+            "--power-cycle-cooldown-secs",
+            "600",
+        ])
+        .unwrap();
+        assert_eq!(Some(27), opts.power_pin);
+        assert_eq!(500, opts.power_cycle_ms);
+        assert_eq!(Duration::from_secs(600), opts.power_cycle_cooldown);
+
+        assert_invalid(
+            &["--bcm-pin", "17", "--power-pin", "17"],
+            "--power-pin must be different from --bcm-pin (17)",
+        );
+        assert_invalid(
+            &["--bcm-pin", "17", "--power-pin", "40"],
+            "--power-pin must be a BCM GPIO pin number from 0 to 27, got 40",
+        );
+        assert_invalid(
+            &["--bcm-pin", "17", "--power-cycle-ms", "0"],
+            "--power-cycle-ms must be at least 1",
+        );
+        assert_invalid(
+            &["--bcm-pin", "17", "--power-cycle-cooldown-secs", "0"],
+            "--power-cycle-cooldown-secs must be at least 1",
+        );
+    }
+
+    #[test]
+    fn test_validate_humidity_saturation_warn_secs() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert_eq!(Duration::from_secs(3600), opts.humidity_saturation_warn);
+
+        let opts = parse_and_validate(&["--bcm-pin", "17", "--humidity-saturation-warn-secs", "600"]).unwrap();
+        assert_eq!(Duration::from_secs(600), opts.humidity_saturation_warn);
+
+        assert_invalid(
+            &["--bcm-pin", "17", "--humidity-saturation-warn-secs", "0"],
+            "--humidity-saturation-warn-secs must be at least 1",
+        );
+    }
+
+    #[test]
+    fn test_validate_http_timeout_secs() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert_eq!(Duration::from_secs(10), opts.http_timeout);
+
+        let opts = parse_and_validate(&["--bcm-pin", "17", "--http-timeout-secs", "3"]).unwrap();
+        assert_eq!(Duration::from_secs(3), opts.http_timeout);
+
+        assert_invalid(
+            &["--bcm-pin", "17", "--http-timeout-secs", "0"],
+            "--http-timeout-secs must be at least 1",
+        );
+    }
+
+    #[test]
+    fn test_validate_fuse() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert!(opts.fuse.is_empty());
+
+        let opts = parse_and_validate(&[
+            "--sensor",
+            "pin=17,name=indoor_a",
+            "--sensor",
+            "pin=4,name=indoor_b",
+            "--fuse",
+            "indoor_a,indoor_b=indoor",
+        ])
+        .unwrap();
+        assert_eq!(1, opts.fuse.len());
+        assert_eq!("indoor", opts.fuse[0].name);
+        assert_eq!(vec!["indoor_a", "indoor_b"], opts.fuse[0].sensors);
+
+        assert_invalid(
+            &[
+                "--sensor",
+                "pin=17,name=indoor_a",
+                "--sensor",
+                "pin=4,name=outdoor",
+                "--fuse",
+                "indoor_a,indoor_b=indoor",
+            ],
+            "--fuse group 'indoor' includes sensor 'indoor_b' which isn't configured",
+        );
+        assert_invalid(
+            &[
+                "--sensor",
+                "pin=17,name=indoor_b",
+                "--sensor",
+                "pin=4,name=outdoor",
+                "--fuse",
+                "indoor_a,indoor_b=indoor",
+            ],
+            "--fuse group 'indoor' includes sensor 'indoor_a' which isn't configured",
+        );
+
+        let errors = parse_and_validate(&[
+            "--sensor",
+            "pin=17,name=indoor",
+            "--sensor",
+            "pin=4,name=indoor_b",
+            "--fuse",
+            "indoor,indoor_b=indoor",
+        ])
+        .unwrap_err();
+        assert!(
+            errors.contains(&"--fuse group name 'indoor' must be different from other groups and sensors".to_owned())
+        );
+        let errors = parse_and_validate(&["--bcm-pin", "17", "--fuse", "a,b=ab", "--fuse", "c,d=ab"]).unwrap_err();
+        assert!(errors.contains(&"--fuse group name 'ab' must be different from other groups and sensors".to_owned()));
+        assert!(
+            StrudelApplication::try_parse_from(["strudel", "--bcm-pin", "17", "--fuse", "indoor_a=indoor"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_validate_publish_deadband() {
+        let opts = parse_and_validate(&["--bcm-pin", "17"]).unwrap();
+        assert_eq!(0.0, opts.publish_deadband_temp);
+        assert_eq!(0.0, opts.publish_deadband_humidity);
+        assert_eq!(
+            Duration::from_secs(DEFAULT_PUBLISH_MAX_INTERVAL_SECS),
+            opts.publish_max_interval
+        );
+
+        let opts = parse_and_validate(&[
+            "--bcm-pin",
+            "17",
+            "--publish-deadband-temp",
+            "0.1",
+            "--publish-deadband-humidity",
+            "0.5",
+            "--publish-max-interval-secs",
+            "600",
+        ])
+        .unwrap();
+        assert_eq!(0.1, opts.publish_deadband_temp);
+        assert_eq!(0.5, opts.publish_deadband_humidity);
+        assert_eq!(Duration::from_secs(600), opts.publish_max_interval);
+
+        let opts = parse_and_validate(&[
+            "--bcm-pin",
+            "17",
+            "--publish-deadband-temp",
+            "0,1",
+            "--publish-deadband-humidity",
+            " 5e-1 ",
+        ])
+        .unwrap();
+        assert_eq!(0.1, opts.publish_deadband_temp);
+        assert_eq!(0.5, opts.publish_deadband_humidity);
+
+        assert_invalid(
+            &["--bcm-pin", "17", "--publish-deadband-temp=-0.1"],
+            "--publish-deadband-temp must be a number of at least 0",
+        );
+        assert_unparseable(
+            &["--bcm-pin", "17", "--publish-deadband-humidity", "NaN"],
+            "invalid number 'NaN'",
+        );
+        assert_invalid(
+            &["--bcm-pin", "17", "--publish-max-interval-secs", "0"],
+            "--publish-max-interval-secs must be at least 1",
+        );
+    }
+
+    #[test]
+    fn test_validate_leaf_temp_offset() {
+        let opts = parse_and_validate(&["--bcm-pin", "17", "--leaf-temp-offset", "-2.5"]).unwrap();
+        assert_eq!(-2.5, opts.leaf_temp_offset);
+
+        let opts = parse_and_validate(&["--bcm-pin", "17", "--leaf-temp-offset", "-1,3"]).unwrap();
+        assert_eq!(-1.3, opts.leaf_temp_offset);
+
+        assert_unparseable(
+            &["--bcm-pin", "17", "--leaf-temp-offset", "NaN"],
+            "invalid number 'NaN'",
+        );
+    }
+
+    #[test]
+    fn test_validate_webhook() {
+        assert_invalid(
+            &["--bcm-pin", "17", "--state-webhook-url", "ftp://example.com/hook"],
+            "--state-webhook-url must be an 'http://' URL",
+        );
+    }
+
+    #[test]
+    fn test_validate_all_errors() {
+        let errors =
+            parse_and_validate(&["--bcm-pin", "58", "--refresh-secs", "1", "--push-interval-secs", "0"]).unwrap_err();
+
+        assert_eq!(3, errors.len());
+    }
+
+    #[test]
+    fn test_config_toml_redacted() {
+        let opts = parse_and_validate(&[
+            "--bcm-pin",
+            "17",
+            "--push-username",
+            "user",
+            "--push-password",
+            "hunter2",
+            "--push-group",
+            "room=office",
+            "--enable-lifecycle",
+            "--lifecycle-token",
+            "tr0ub4dor",
+        ])
+        .unwrap();
+
+        let out = toml::to_string(&opts).unwrap();
+
+        assert!(out.contains("[[sensors]]\npin = 17\ntype = \"dht22\"\ntemp_offset = 0.0\n"));
+        assert!(out.contains("refresh_secs = 30\n"));
+        assert!(out.contains("log_level = \"INFO\"\n"));
+        assert!(out.contains("push_groups = [\"room=office\"]\n"));
+        assert!(out.contains("username = \"user\"\n"));
+        assert!(out.contains("password = \"<redacted>\"\n"));
+        assert!(!out.contains("hunter2"));
+        assert!(out.contains("lifecycle_token = \"<redacted>\"\n"));
+        assert!(!out.contains("tr0ub4dor"));
+    }
+
+    #[test]
+    fn test_parse_float_separators() {
+        assert_eq!(Ok(1.5), parse_float("1.5"));
+        assert_eq!(Ok(1.5), parse_float("1,5"));
+        assert_eq!(Ok(-1.3), parse_float("-1,3"));
+        assert_eq!(Ok(-1.3), parse_float("-1.3"));
+        assert_eq!(Ok(2.0), parse_float("2"));
+        assert_eq!(Ok(0.5), parse_float(",5"));
+        assert_eq!(Ok(0.25), parse_float(" 0,25\t"));
+    }
+
+    #[test]
+    fn test_parse_float_scientific() {
+        assert_eq!(Ok(1500.0), parse_float("1.5e3"));
+        assert_eq!(Ok(1500.0), parse_float("1,5e3"));
+        assert_eq!(Ok(0.015), parse_float("1.5E-2"));
+        assert_eq!(Ok(-0.015), parse_float("-1,5e-2"));
+    }
+
+    #[test]
+    fn test_parse_float_rejected() {
+        for input in [
+            "", " ", "abc", "1.5.2", "1,5,2", "1.000,5", "1,000.5", "NaN", "inf", "-inf", "infinity", "1e400",
+        ] {
+            let err = parse_float(input).unwrap_err();
+            assert_eq!(
+                format!(
+                    "invalid number '{}', expected a finite decimal number like 1.5, 1,5, -2, or 1.5e3",
+                    input
+                ),
+                err,
+            );
+        }
+    }
+}
@@ -0,0 +1,224 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+use crate::version;
+use prometheus_client::collector::Collector;
+use prometheus_client::metrics::gauge::ConstGauge;
+use prometheus_client::registry::{Descriptor, LocalMetric, Registry};
+use prometheus_client::MaybeOwned;
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Source of the current time.
+///
+/// The system clock can be stepped forwards or backwards at any time, for example when
+/// NTP corrects it, so it's only used for timestamps that are exported. Anything that
+/// computes how long ago something happened uses the monotonic clock instead.
+pub trait Clock: fmt::Debug + Send + Sync {
+    /// Current time of a clock that never goes backwards, for measuring durations.
+    fn now_monotonic(&self) -> Instant;
+
+    /// Current time of the system clock, for exported timestamps.
+    fn now_wall(&self) -> SystemTime;
+}
+
+/// `Clock` backed by the real monotonic and system clocks.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl SystemClock {
+    /// Get a shared `SystemClock` for things that accept any `Clock`.
+    pub fn shared() -> Arc<dyn Clock> {
+        Arc::new(SystemClock)
+    }
+}
+
+impl Clock for SystemClock {
+    fn now_monotonic(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn now_wall(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// `Clock` that only changes when told to, for tests. Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    inner: Arc<Mutex<(Instant, SystemTime)>>,
+}
+
+impl MockClock {
+    /// Create a clock with the system clock set to `wall`.
+    pub fn new(wall: SystemTime) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new((Instant::now(), wall))),
+        }
+    }
+
+    /// Move both the monotonic and system clocks forward by `d`.
+    pub fn advance(&self, d: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.0 += d;
+        inner.1 += d;
+    }
+
+    /// Set the system clock to `wall` without moving the monotonic clock, like NTP
+    /// stepping the clock.
+    pub fn step_wall(&self, wall: SystemTime) {
+        self.inner.lock().unwrap().1 = wall;
+    }
+}
+
+impl Clock for MockClock {
+    fn now_monotonic(&self) -> Instant {
+        self.inner.lock().unwrap().0
+    }
+
+    fn now_wall(&self) -> SystemTime {
+        self.inner.lock().unwrap().1
+    }
+}
+
+/// Check if the system clock is plausibly synchronized.
+///
+/// Machines without a real time clock, like a Raspberry PI, start with a clock that's
+/// far in the past until it's set by NTP. Any time before `epoch`, usually when `strudel`
+/// was built, can't be the real time so the clock is assumed not to be synchronized yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockCheck {
+    epoch: SystemTime,
+}
+
+impl ClockCheck {
+    /// Create a check that treats times before `epoch` as unsynchronized.
+    pub fn new(epoch: SystemTime) -> Self {
+        Self { epoch }
+    }
+
+    /// Create a check that treats times before `strudel` was built as unsynchronized.
+    pub fn since_build() -> Self {
+        Self::new(version::build_time())
+    }
+
+    /// Return true if the system clock is synchronized now.
+    pub fn is_synchronized(&self) -> bool {
+        self.is_synchronized_at(SystemTime::now())
+    }
+
+    /// Return true if `now` is a plausible time for a synchronized clock.
+    pub fn is_synchronized_at(&self, now: SystemTime) -> bool {
+        now >= self.epoch
+    }
+}
+
+/// Metric for whether the system clock is synchronized, computed each time metrics
+/// are collected.
+#[derive(Debug)]
+pub struct ClockMetrics {
+    check: ClockCheck,
+}
+
+impl ClockMetrics {
+    /// Register a gauge for whether the system clock is synchronized based on `check`.
+    pub fn register(reg: &mut Registry, check: ClockCheck) {
+        reg.register_collector(Box::new(Self { check }));
+    }
+}
+
+impl Collector for ClockMetrics {
+    fn collect<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = (Cow<'a, Descriptor>, MaybeOwned<'a, Box<dyn LocalMetric>>)> + 'a> {
+        let desc = Descriptor::new(
+            "strudel_clock_synchronized",
+            "Whether the system clock is synchronized (1) or not (0)",
+            None,
+            None,
+            Vec::new(),
+        );
+        let value = if self.check.is_synchronized() { 1 } else { 0 };
+        let metric: Box<dyn LocalMetric> = Box::new(ConstGauge::new(value));
+        Box::new(std::iter::once((Cow::Owned(desc), MaybeOwned::Owned(metric))))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Clock, ClockCheck, ClockMetrics, MockClock};
+    use prometheus_client::encoding::text;
+    use prometheus_client::registry::Registry;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    fn encode(check: ClockCheck) -> String {
+        let mut reg = Registry::default();
+        ClockMetrics::register(&mut reg, check);
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &reg).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1000));
+        let start = clock.now_monotonic();
+
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(Duration::from_secs(30), clock.now_monotonic() - start);
+        assert_eq!(UNIX_EPOCH + Duration::from_secs(1030), clock.now_wall());
+
+        // Stepping the system clock doesn't affect the monotonic clock
+        clock.step_wall(UNIX_EPOCH + Duration::from_secs(10));
+        assert_eq!(Duration::from_secs(30), clock.now_monotonic() - start);
+        assert_eq!(UNIX_EPOCH + Duration::from_secs(10), clock.clone().now_wall());
+    }
+
+    #[test]
+    fn test_clock_check_pre_sync() {
+        let check = ClockCheck::new(UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        assert!(!check.is_synchronized_at(UNIX_EPOCH + Duration::from_secs(15)));
+        assert!(!check.is_synchronized_at(UNIX_EPOCH + Duration::from_secs(1_599_999_999)));
+    }
+
+    #[test]
+    fn test_clock_check_jump() {
+        let check = ClockCheck::new(UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        assert!(!check.is_synchronized_at(UNIX_EPOCH + Duration::from_secs(30)));
+        assert!(check.is_synchronized_at(UNIX_EPOCH + Duration::from_secs(1_700_000_000)));
+    }
+
+    #[test]
+    fn test_clock_check_already_synced() {
+        let check = ClockCheck::new(UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        assert!(check.is_synchronized_at(UNIX_EPOCH + Duration::from_secs(1_600_000_000)));
+        assert!(ClockCheck::since_build().is_synchronized());
+    }
+
+    #[test]
+    fn test_clock_metrics() {
+        let buf = encode(ClockCheck::new(UNIX_EPOCH));
+        assert!(buf.contains("strudel_clock_synchronized 1\n"));
+
+        let buf = encode(ClockCheck::new(SystemTime::now() + Duration::from_secs(86400)));
+        assert!(buf.contains("strudel_clock_synchronized 0\n"));
+    }
+}
@@ -0,0 +1,213 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! Detection of the device `strudel` is running on and known problems reading the
+//! sensor on particular devices.
+
+use prometheus_client::encoding::EncodeLabelSet;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::registry::Registry;
+use std::fmt;
+
+/// Model and SoC of the Raspberry Pi `strudel` is running on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    /// Model, like "Raspberry Pi Zero 2 W".
+    pub model: String,
+    /// System on a chip, like "BCM2837A1".
+    pub soc: String,
+}
+
+impl DeviceInfo {
+    /// Detect the Raspberry Pi `strudel` is running on. Returns `None`, without failing,
+    /// if it isn't a Raspberry Pi that can be identified or `strudel` was built without
+    /// the `rppal` feature.
+    #[cfg(feature = "rppal")]
+    pub fn detect() -> Option<Self> {
+        match rppal::system::DeviceInfo::new() {
+            Ok(info) => Some(Self {
+                model: info.model().to_string(),
+                soc: info.soc().to_string(),
+            }),
+            Err(e) => {
+                tracing::debug!(message = "unable to detect device model", error = %e);
+                None
+            }
+        }
+    }
+
+    /// Detect the Raspberry Pi `strudel` is running on. Always `None` since `strudel`
+    /// was built without the `rppal` feature.
+    #[cfg(not(feature = "rppal"))]
+    pub fn detect() -> Option<Self> {
+        None
+    }
+}
+
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.model, self.soc)
+    }
+}
+
+/// How the widths of pulses sent by the DHT22 are decoded into bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PulseTiming {
+    /// Compare the number of cycles each pulse of a read took to the others in the same
+    /// read. This is the default.
+    Relative,
+    /// Convert cycles to microseconds using the speed of the device measured at startup
+    /// and compare them to the timings from the datasheet, see `--dht-calibrate-timing`.
+    Calibrated,
+}
+
+/// A device that's known to have problems decoding reads with a particular pulse timing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimingProblem {
+    /// Model of the device, as detected by `DeviceInfo::detect`.
+    pub model: &'static str,
+    /// Pulse timing that has problems on the device.
+    pub timing: PulseTiming,
+    /// Pulse timing to use instead.
+    pub recommended: PulseTiming,
+    /// What goes wrong.
+    pub reason: &'static str,
+}
+
+/// Devices known to have problems decoding reads, by model and pulse timing.
+pub const TIMING_PROBLEMS: &[TimingProblem] = &[
+    TimingProblem {
+        model: "Raspberry Pi Zero 2 W",
+        timing: PulseTiming::Relative,
+        recommended: PulseTiming::Calibrated,
+        reason: "CPU frequency scaling changes how many cycles pulses take in the middle of a read",
+    },
+    TimingProblem {
+        model: "Raspberry Pi Zero",
+        timing: PulseTiming::Relative,
+        recommended: PulseTiming::Calibrated,
+        reason: "the single core is often preempted during a read, stretching some pulses",
+    },
+    TimingProblem {
+        model: "Raspberry Pi Zero W",
+        timing: PulseTiming::Relative,
+        recommended: PulseTiming::Calibrated,
+        reason: "the single core is often preempted during a read, stretching some pulses",
+    },
+];
+
+/// Return the known problem decoding reads on `model` with `timing`, if there is one.
+pub fn timing_problem(model: &str, timing: PulseTiming) -> Option<&'static TimingProblem> {
+    TIMING_PROBLEMS.iter().find(|p| p.model == model && p.timing == timing)
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct DeviceLabels {
+    model: String,
+    soc: String,
+}
+
+/// Gauge with a constant value of `1` and labels describing the device `strudel` is
+/// running on.
+#[derive(Debug)]
+pub struct DeviceMetrics;
+
+impl DeviceMetrics {
+    /// Register device information, using `unknown` for the labels if the device
+    /// couldn't be detected.
+    pub fn register(reg: &mut Registry, info: Option<&DeviceInfo>) {
+        let family = Family::<DeviceLabels, Gauge>::default();
+        let labels = match info {
+            Some(i) => DeviceLabels {
+                model: i.model.clone(),
+                soc: i.soc.clone(),
+            },
+            None => DeviceLabels {
+                model: "unknown".to_owned(),
+                soc: "unknown".to_owned(),
+            },
+        };
+
+        family.get_or_create(&labels).set(1);
+        reg.register(
+            "strudel_device_info",
+            "Model and SoC of the device strudel is running on",
+            family,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{timing_problem, DeviceInfo, DeviceMetrics, PulseTiming, TIMING_PROBLEMS};
+    use prometheus_client::encoding::text;
+    use prometheus_client::registry::Registry;
+
+    fn encode(info: Option<&DeviceInfo>) -> String {
+        let mut reg = Registry::default();
+        DeviceMetrics::register(&mut reg, info);
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &reg).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_timing_problem_known() {
+        let problem = timing_problem("Raspberry Pi Zero 2 W", PulseTiming::Relative).unwrap();
+        assert_eq!(PulseTiming::Calibrated, problem.recommended);
+        assert!(timing_problem("Raspberry Pi Zero W", PulseTiming::Relative).is_some());
+    }
+
+    #[test]
+    fn test_timing_problem_other_timing() {
+        assert_eq!(None, timing_problem("Raspberry Pi Zero 2 W", PulseTiming::Calibrated));
+    }
+
+    #[test]
+    fn test_timing_problem_unknown_model() {
+        assert_eq!(None, timing_problem("Raspberry Pi 4 B", PulseTiming::Relative));
+        assert_eq!(None, timing_problem("", PulseTiming::Relative));
+    }
+
+    #[test]
+    fn test_timing_problems_recommend_another_timing() {
+        for p in TIMING_PROBLEMS {
+            assert_ne!(
+                p.timing, p.recommended,
+                "{} recommends the timing it has problems with",
+                p.model
+            );
+        }
+    }
+
+    #[test]
+    fn test_device_metrics() {
+        let info = DeviceInfo {
+            model: "Raspberry Pi Zero 2 W".to_owned(),
+            soc: "BCM2837A1".to_owned(),
+        };
+
+        let buf = encode(Some(&info));
+        assert!(buf.contains("strudel_device_info{model=\"Raspberry Pi Zero 2 W\",soc=\"BCM2837A1\"} 1\n"));
+
+        let buf = encode(None);
+        assert!(buf.contains("strudel_device_info{model=\"unknown\",soc=\"unknown\"} 1\n"));
+    }
+}
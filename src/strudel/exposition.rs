@@ -0,0 +1,759 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+use crate::sensor::{LatestReading, TemperatureUnit};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt::{self, Formatter, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const EOF_LINE: &str = "# EOF";
+
+const METRIC_TYPES: &[&str] = &[
+    "counter",
+    "gauge",
+    "histogram",
+    "gaugehistogram",
+    "summary",
+    "info",
+    "stateset",
+    "unknown",
+    "untyped",
+];
+
+/// Suffixes of sample names that belong to the family named without them, for
+/// example `strudel_scrapes_total` is a sample of the `strudel_scrapes` family.
+const SAMPLE_SUFFIXES: &[&str] = &[
+    "_total", "_created", "_bucket", "_count", "_sum", "_gcount", "_gsum", "_info",
+];
+
+/// Number of metric families and samples in a valid exposition.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Summary {
+    pub families: usize,
+    pub samples: usize,
+}
+
+/// First problem found validating an exposition and the line (starting at 1) it's on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ParseError {
+    pub line: usize,
+    pub error: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.error)
+    }
+}
+
+impl Error for ParseError {}
+
+/// Validate metrics in the OpenMetrics or Prometheus text format, returning the number
+/// of families and samples if they're valid or the first error if they aren't.
+///
+/// This is a minimal validator for checking the output of `strudel` itself: it checks
+/// the grammar of `HELP`, `TYPE`, `UNIT`, and sample lines, including label syntax and
+/// escaping, and that each family is only described once and isn't split up by other
+/// families. A series may only have multiple samples if they all have timestamps that
+/// increase. It doesn't check that samples make sense for the type of their family.
+pub fn validate(text: &str) -> Result<Summary, ParseError> {
+    let mut validator = Validator::default();
+    let mut lines = text.split('\n').enumerate().peekable();
+
+    while let Some((i, line)) = lines.next() {
+        // Text ending with a newline leaves an empty string after the last line
+        if line.is_empty() && lines.peek().is_none() {
+            break;
+        }
+
+        validator
+            .line(line)
+            .map_err(|error| ParseError { line: i + 1, error })?;
+    }
+
+    Ok(validator.summary)
+}
+
+/// Readings rendered as timestamped samples by `backfill`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backfill {
+    /// Complete exposition in the OpenMetrics text format, ending with `# EOF`.
+    pub text: String,
+    /// Number of readings included.
+    pub readings: usize,
+    /// Time of the first reading left out to keep the exposition under the size limit,
+    /// `None` if every reading was included.
+    pub next: Option<SystemTime>,
+}
+
+/// Render temperature and humidity of `readings`, oldest first, as OpenMetrics gauges
+/// with the time of each reading as the timestamp of its samples. The output is meant
+/// for `promtool tsdb create-blocks-from openmetrics` to backfill scrapes that were missed.
+///
+/// Every sample has `labels`, usually the `job` and `instance` labels Prometheus would
+/// have added when scraping. Readings that aren't newer than the one before them, because
+/// the system clock was stepped back, are skipped since timestamps of a series must
+/// increase. Readings are left out, newest first, to keep the output under `max_bytes`.
+pub fn backfill(
+    readings: &[LatestReading],
+    unit: TemperatureUnit,
+    labels: &[(&str, &str)],
+    max_bytes: usize,
+) -> Backfill {
+    let temperature_name = "strudel_temperature_degrees";
+    let humidity_name = "strudel_relative_humidity";
+    let mut temperature = format!(
+        "# HELP {0} Temperature in {1}\n# TYPE {0} gauge\n",
+        temperature_name, unit
+    );
+    let mut humidity = format!(
+        "# HELP {0} Relative humidity (0-100)\n# TYPE {0} gauge\n",
+        humidity_name
+    );
+    let labels = format_labels(labels);
+
+    let mut size = temperature.len() + humidity.len() + EOF_LINE.len() + 1;
+    let mut included = 0;
+    let mut previous = None;
+    let mut next = None;
+
+    for r in readings {
+        let ts = match r.read_at.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs_f64(),
+            Err(_) => continue,
+        };
+        if previous.map(|p| ts <= p).unwrap_or(false) {
+            continue;
+        }
+
+        let temperature_line = format!(
+            "{}{} {} {:.3}\n",
+            temperature_name,
+            labels,
+            unit.convert(r.temperature),
+            ts
+        );
+        let humidity_line = format!("{}{} {} {:.3}\n", humidity_name, labels, f64::from(r.humidity), ts);
+        size += temperature_line.len() + humidity_line.len();
+        if size > max_bytes {
+            next = Some(r.read_at);
+            break;
+        }
+
+        temperature.push_str(&temperature_line);
+        humidity.push_str(&humidity_line);
+        included += 1;
+        previous = Some(ts);
+    }
+
+    let mut text = String::new();
+    if included > 0 {
+        text.push_str(&temperature);
+        text.push_str(&humidity);
+    }
+    text.push_str(EOF_LINE);
+    text.push('\n');
+
+    Backfill {
+        text,
+        readings: included,
+        next,
+    }
+}
+
+/// Format labels for a sample, including the braces, or an empty string if there are none.
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+
+    let mut out = String::from("{");
+    for (i, (name, value)) in labels.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+
+        let value = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+        let _ = write!(out, "{}=\"{}\"", name, value);
+    }
+    out.push('}');
+    out
+}
+
+/// Name and unescaped value of a label
+type Label = (String, String);
+
+#[derive(Debug, Default)]
+struct Validator {
+    summary: Summary,
+    current: Option<Family>,
+    finished: HashSet<String>,
+    /// Timestamp of the most recent sample of each series, if it had one
+    series: HashMap<String, Option<f64>>,
+    eof: bool,
+}
+
+#[derive(Debug)]
+struct Family {
+    name: String,
+    help: bool,
+    kind: bool,
+    unit: bool,
+    samples: usize,
+}
+
+impl Validator {
+    fn line(&mut self, line: &str) -> Result<(), String> {
+        if self.eof {
+            return Err(format!("unexpected content after '{}'", EOF_LINE));
+        }
+
+        if line.is_empty() {
+            Err("unexpected empty line".to_owned())
+        } else if line == EOF_LINE {
+            self.eof = true;
+            Ok(())
+        } else if let Some(rest) = line.strip_prefix("# HELP ") {
+            self.help(rest)
+        } else if let Some(rest) = line.strip_prefix("# TYPE ") {
+            self.kind(rest)
+        } else if let Some(rest) = line.strip_prefix("# UNIT ") {
+            self.unit(rest)
+        } else if line.starts_with('#') {
+            // Other comments are allowed by the Prometheus text format
+            Ok(())
+        } else {
+            self.sample(line)
+        }
+    }
+
+    fn help(&mut self, rest: &str) -> Result<(), String> {
+        let (name, text) = rest.split_once(' ').unwrap_or((rest, ""));
+        let family = self.family(name)?;
+        if family.help {
+            return Err(format!("duplicate HELP line for '{}'", name));
+        }
+
+        family.help = true;
+        unescape(text, &['\\', 'n', '"']).map_err(|e| format!("{} in HELP text", e))?;
+        Ok(())
+    }
+
+    fn kind(&mut self, rest: &str) -> Result<(), String> {
+        let (name, kind) = rest
+            .split_once(' ')
+            .ok_or_else(|| format!("missing type in TYPE line for '{}'", rest))?;
+        let family = self.family(name)?;
+        if family.kind {
+            return Err(format!("duplicate TYPE line for '{}'", name));
+        }
+        if family.samples > 0 {
+            return Err(format!("TYPE line for '{}' after its samples", name));
+        }
+        if !METRIC_TYPES.contains(&kind) {
+            return Err(format!("unknown type '{}' for '{}'", kind, name));
+        }
+
+        family.kind = true;
+        Ok(())
+    }
+
+    fn unit(&mut self, rest: &str) -> Result<(), String> {
+        let (name, unit) = rest.split_once(' ').unwrap_or((rest, ""));
+        let family = self.family(name)?;
+        if family.unit {
+            return Err(format!("duplicate UNIT line for '{}'", name));
+        }
+        if family.samples > 0 {
+            return Err(format!("UNIT line for '{}' after its samples", name));
+        }
+        if !unit.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':') {
+            return Err(format!("invalid unit '{}' for '{}'", unit, name));
+        }
+
+        family.unit = true;
+        Ok(())
+    }
+
+    fn sample(&mut self, line: &str) -> Result<(), String> {
+        let (name, rest) = split_name(line, is_metric_char);
+        if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err("expected metric name at start of sample".to_owned());
+        }
+
+        let (labels, rest) = match rest.strip_prefix('{') {
+            Some(rest) => parse_labels(rest)?,
+            None => (Vec::new(), rest),
+        };
+
+        let rest = rest
+            .strip_prefix(' ')
+            .ok_or_else(|| format!("expected space before value of '{}'", name))?;
+        let (value, exemplar) = match rest.split_once(" # ") {
+            Some((value, exemplar)) => (value, Some(exemplar)),
+            None => (rest, None),
+        };
+        let timestamp = parse_value_timestamp(value)?;
+
+        if let Some(exemplar) = exemplar {
+            let rest = exemplar
+                .strip_prefix('{')
+                .ok_or_else(|| format!("expected labels for exemplar of '{}'", name))?;
+            let (_, rest) = parse_labels(rest)?;
+            let rest = rest
+                .strip_prefix(' ')
+                .ok_or_else(|| format!("expected space before exemplar value of '{}'", name))?;
+            parse_value_timestamp(rest).map_err(|e| format!("{} in exemplar", e))?;
+        }
+
+        let belongs = match &self.current {
+            Some(f) => name == f.name || SAMPLE_SUFFIXES.iter().any(|s| name.strip_suffix(s) == Some(&f.name)),
+            None => false,
+        };
+        let family = if belongs {
+            self.current.as_mut().unwrap()
+        } else {
+            self.family(name)?
+        };
+        family.samples += 1;
+        self.summary.samples += 1;
+
+        let mut key = labels;
+        key.sort();
+        let series = format!("{}{:?}", name, key);
+        match (self.series.insert(series, timestamp), timestamp) {
+            (None, _) => Ok(()),
+            (Some(Some(previous)), Some(current)) if current > previous => Ok(()),
+            (Some(Some(_)), Some(_)) => Err(format!("timestamp of sample for '{}' doesn't increase", name)),
+            (Some(_), _) => Err(format!("duplicate sample for '{}' with the same labels", name)),
+        }
+    }
+
+    /// Get the family named `name`, starting it if it isn't the current family.
+    fn family(&mut self, name: &str) -> Result<&mut Family, String> {
+        let (valid, rest) = split_name(name, is_metric_char);
+        if valid.is_empty() || !rest.is_empty() || valid.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(format!("invalid metric name '{}'", name));
+        }
+
+        if self.current.as_ref().map(|f| f.name != name).unwrap_or(true) {
+            if self.finished.contains(name) {
+                return Err(format!("family '{}' is split up by other families", name));
+            }
+
+            if let Some(f) = self.current.take() {
+                self.finished.insert(f.name);
+            }
+
+            self.summary.families += 1;
+            self.current = Some(Family {
+                name: name.to_owned(),
+                help: false,
+                kind: false,
+                unit: false,
+                samples: 0,
+            });
+        }
+
+        Ok(self.current.as_mut().unwrap())
+    }
+}
+
+fn is_metric_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == ':'
+}
+
+fn is_label_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Split `s` at the first character that isn't allowed in a name.
+fn split_name(s: &str, allowed: fn(char) -> bool) -> (&str, &str) {
+    let end = s.find(|c| !allowed(c)).unwrap_or(s.len());
+    s.split_at(end)
+}
+
+/// Parse labels following an opening `{`, returning them and the rest of the line
+/// after the closing `}`.
+fn parse_labels(mut s: &str) -> Result<(Vec<Label>, &str), String> {
+    let mut labels: Vec<Label> = Vec::new();
+
+    loop {
+        if let Some(rest) = s.strip_prefix('}') {
+            return Ok((labels, rest));
+        }
+
+        let (name, rest) = split_name(s, is_label_char);
+        if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err("expected label name".to_owned());
+        }
+        if labels.iter().any(|(n, _)| n == name) {
+            return Err(format!("duplicate label '{}'", name));
+        }
+
+        let rest = rest
+            .strip_prefix("=\"")
+            .ok_or_else(|| format!("expected '=\"' after label '{}'", name))?;
+        let end = closing_quote(rest).ok_or_else(|| format!("unterminated value of label '{}'", name))?;
+        let value = unescape(&rest[..end], &['\\', 'n', '"']).map_err(|e| format!("{} in label '{}'", e, name))?;
+        labels.push((name.to_owned(), value));
+
+        s = &rest[end + 1..];
+        if let Some(rest) = s.strip_prefix(',') {
+            s = rest;
+        } else if !s.starts_with('}') {
+            return Err(format!("expected ',' or '}}' after label '{}'", name));
+        }
+    }
+}
+
+/// Find the index of the first `"` in `s` that isn't escaped.
+fn closing_quote(s: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            '"' if !escaped => return Some(i),
+            '\\' => escaped = !escaped,
+            _ => escaped = false,
+        }
+    }
+
+    None
+}
+
+/// Replace escape sequences in `s`, only allowing a backslash to be followed by one
+/// of the characters in `allowed`.
+fn unescape(s: &str, allowed: &[char]) -> Result<String, String> {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') if allowed.contains(&'n') => out.push('\n'),
+            Some(e) if allowed.contains(&e) => out.push(e),
+            Some(e) => return Err(format!("invalid escape sequence '\\{}'", e)),
+            None => return Err("trailing backslash".to_owned()),
+        }
+    }
+
+    Ok(out)
+}
+
+/// Parse a sample value and optional timestamp separated by a single space, returning
+/// the timestamp if there is one.
+fn parse_value_timestamp(s: &str) -> Result<Option<f64>, String> {
+    let mut parts = s.split(' ');
+    let value = parts.next().unwrap_or("");
+    if !is_number(value) {
+        return Err(format!("invalid value '{}'", value));
+    }
+
+    let timestamp = match parts.next() {
+        Some(ts) if !is_number(ts) || ts.contains("Inf") || ts == "NaN" => {
+            return Err(format!("invalid timestamp '{}'", ts));
+        }
+        Some(ts) => ts.parse::<f64>().ok(),
+        None => None,
+    };
+
+    match parts.next() {
+        Some(extra) => Err(format!("unexpected '{}' after value", extra)),
+        None => Ok(timestamp),
+    }
+}
+
+/// Numbers are floats or integers as parsed by Rust except for spellings of infinity
+/// and NaN which are only allowed as `+Inf`, `-Inf`, and `NaN`.
+fn is_number(s: &str) -> bool {
+    match s {
+        "NaN" | "+Inf" | "-Inf" => true,
+        _ => s.parse::<f64>().map(|v| v.is_finite()).unwrap_or(false),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{backfill, validate, Backfill, ParseError, Summary};
+    use crate::metrics::{HttpMetrics, Registries, TemperatureMetrics};
+    use crate::sensor::{Humidity, LatestReading, Measurement, TemperatureCelsius, TemperatureUnit};
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    fn error(line: usize, error: &str) -> Result<Summary, ParseError> {
+        Err(ParseError {
+            line,
+            error: error.to_owned(),
+        })
+    }
+
+    #[test]
+    fn test_validate_valid() {
+        let text = concat!(
+            "# HELP strudel_temperature_degrees Degrees measured by the \\\"sensor\\\".\\nSecond line \\\\.\n",
+            "# TYPE strudel_temperature_degrees gauge\n",
+            "# UNIT strudel_temperature_degrees degrees\n",
+            "strudel_temperature_degrees{sensor=\"indoor\",path=\"C:\\\\tmp\"} 21.5\n",
+            "strudel_temperature_degrees{sensor=\"a \\\"quoted\\\"\\nname\"} -3e-2 1665000000.5\n",
+            "# HELP strudel_errors Total errors.\n",
+            "# TYPE strudel_errors counter\n",
+            "strudel_errors_total{kind=\"timeout\"} 3 # {trace_id=\"abc\"} 1.0\n",
+            "strudel_errors_total{kind=\"checksum\"} 0\n",
+            "strudel_errors_created 1665000000\n",
+            "# TYPE strudel_read_duration_seconds histogram\n",
+            "strudel_read_duration_seconds_bucket{le=\"0.5\"} 1\n",
+            "strudel_read_duration_seconds_bucket{le=\"+Inf\"} 2\n",
+            "strudel_read_duration_seconds_sum NaN\n",
+            "strudel_read_duration_seconds_count 2\n",
+            "untyped_metric +Inf\n",
+            "# EOF\n",
+        );
+
+        assert_eq!(
+            Ok(Summary {
+                families: 4,
+                samples: 10
+            }),
+            validate(text)
+        );
+    }
+
+    #[test]
+    fn test_validate_empty() {
+        assert_eq!(Ok(Summary::default()), validate(""));
+        assert_eq!(Ok(Summary::default()), validate("# EOF\n"));
+    }
+
+    #[test]
+    fn test_validate_encoded_metrics() {
+        let mut registries = Registries::new();
+        let _temperature = TemperatureMetrics::new(registries.group("sensor"));
+        let http = HttpMetrics::new(registries.group("http"));
+        http.scrape();
+
+        let encoded = registries.encode().unwrap();
+        let summary = validate(&encoded.text).unwrap();
+        assert!(summary.families > 0);
+        assert!(summary.samples > 0);
+    }
+
+    #[test]
+    fn test_validate_bad_escaping() {
+        assert_eq!(
+            error(2, "invalid escape sequence '\\t' in label 'sensor'"),
+            validate("# TYPE a gauge\na{sensor=\"in\\tdoor\"} 1\n")
+        );
+        assert_eq!(
+            error(1, "invalid escape sequence '\\x' in HELP text"),
+            validate("# HELP a Some \\x help\n")
+        );
+        assert_eq!(
+            error(1, "trailing backslash in HELP text"),
+            validate("# HELP a Help\\\n")
+        );
+        assert_eq!(
+            error(1, "unterminated value of label 'sensor'"),
+            validate("a{sensor=\"indoor\\\"} 1\n")
+        );
+    }
+
+    #[test]
+    fn test_validate_duplicate_type() {
+        assert_eq!(
+            error(3, "duplicate TYPE line for 'a'"),
+            validate("# HELP a Help.\n# TYPE a gauge\n# TYPE a counter\na 1\n")
+        );
+        assert_eq!(
+            error(3, "TYPE line for 'a' after its samples"),
+            validate("# HELP a Help.\na 1\n# TYPE a gauge\n")
+        );
+        assert_eq!(
+            error(3, "family 'a' is split up by other families"),
+            validate("# TYPE a gauge\n# TYPE b gauge\n# TYPE a gauge\n")
+        );
+    }
+
+    #[test]
+    fn test_validate_bad_samples() {
+        assert_eq!(error(1, "invalid value 'abc'"), validate("a abc\n"));
+        assert_eq!(error(1, "invalid value 'inf'"), validate("a inf\n"));
+        assert_eq!(error(1, "invalid timestamp '+Inf'"), validate("a 1 +Inf\n"));
+        assert_eq!(error(1, "unexpected '3' after value"), validate("a 1 2 3\n"));
+        assert_eq!(
+            error(1, "expected space before value of 'a'"),
+            validate("a{b=\"c\"}1\n")
+        );
+        assert_eq!(error(1, "expected metric name at start of sample"), validate("1a 1\n"));
+        assert_eq!(error(1, "expected label name"), validate("a{=\"c\"} 1\n"));
+        assert_eq!(error(1, "expected '=\"' after label 'b'"), validate("a{b=c} 1\n"));
+        assert_eq!(
+            error(1, "expected ',' or '}' after label 'b'"),
+            validate("a{b=\"c\" d=\"e\"} 1\n")
+        );
+        assert_eq!(error(1, "duplicate label 'b'"), validate("a{b=\"c\",b=\"d\"} 1\n"));
+        assert_eq!(
+            error(2, "duplicate sample for 'a' with the same labels"),
+            validate("a{b=\"c\",d=\"e\"} 1\na{d=\"e\",b=\"c\"} 2\n")
+        );
+        assert_eq!(
+            error(2, "duplicate sample for 'a' with the same labels"),
+            validate("a 1 1000\na 2\n")
+        );
+        assert_eq!(
+            error(3, "timestamp of sample for 'a' doesn't increase"),
+            validate("a 1 1000\na 2 1001.5\na 3 1001.5\n")
+        );
+        assert_eq!(
+            Ok(Summary {
+                families: 1,
+                samples: 3
+            }),
+            validate("a 1 1000\na 2 1001.5\na{b=\"c\"} 3 1000\n")
+        );
+        assert_eq!(
+            error(1, "expected labels for exemplar of 'a_total'"),
+            validate("a_total 1 # trace 1\n")
+        );
+    }
+
+    #[test]
+    fn test_validate_bad_lines() {
+        assert_eq!(error(2, "unexpected empty line"), validate("a 1\n\nb 1\n"));
+        assert_eq!(error(2, "unexpected content after '# EOF'"), validate("# EOF\na 1\n"));
+        assert_eq!(error(1, "invalid metric name 'a-b'"), validate("# TYPE a-b gauge\n"));
+        assert_eq!(error(1, "unknown type 'meter' for 'a'"), validate("# TYPE a meter\n"));
+        assert_eq!(error(1, "missing type in TYPE line for 'a'"), validate("# TYPE a\n"));
+        assert_eq!(
+            error(2, "duplicate HELP line for 'a'"),
+            validate("# HELP a One.\n# HELP a Two.\n")
+        );
+    }
+
+    fn history(readings: &[(u64, f64, f64)]) -> Vec<LatestReading> {
+        readings
+            .iter()
+            .map(|(millis, temperature, humidity)| {
+                LatestReading::new(
+                    Measurement {
+                        temperature: TemperatureCelsius::from(*temperature),
+                        humidity: Humidity::from(*humidity),
+                    },
+                    UNIX_EPOCH + Duration::from_millis(*millis),
+                    Instant::now(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_backfill() {
+        let readings = history(&[
+            (1_665_400_000_000, 21.5, 40.0),
+            (1_665_400_030_500, 21.6, 40.5),
+            // The clock was stepped back, timestamps have to increase
+            (1_665_400_030_000, 21.7, 41.0),
+            (1_665_400_060_000, 21.8, 41.5),
+        ]);
+
+        let res = backfill(
+            &readings,
+            TemperatureUnit::Celsius,
+            &[("job", "strudel"), ("instance", "pi:9781")],
+            usize::MAX,
+        );
+        let expected = concat!(
+            "# HELP strudel_temperature_degrees Temperature in celsius\n",
+            "# TYPE strudel_temperature_degrees gauge\n",
+            "strudel_temperature_degrees{job=\"strudel\",instance=\"pi:9781\"} 21.5 1665400000.000\n",
+            "strudel_temperature_degrees{job=\"strudel\",instance=\"pi:9781\"} 21.6 1665400030.500\n",
+            "strudel_temperature_degrees{job=\"strudel\",instance=\"pi:9781\"} 21.8 1665400060.000\n",
+            "# HELP strudel_relative_humidity Relative humidity (0-100)\n",
+            "# TYPE strudel_relative_humidity gauge\n",
+            "strudel_relative_humidity{job=\"strudel\",instance=\"pi:9781\"} 40 1665400000.000\n",
+            "strudel_relative_humidity{job=\"strudel\",instance=\"pi:9781\"} 40.5 1665400030.500\n",
+            "strudel_relative_humidity{job=\"strudel\",instance=\"pi:9781\"} 41.5 1665400060.000\n",
+            "# EOF\n",
+        );
+
+        assert_eq!(expected, res.text);
+        assert_eq!(3, res.readings);
+        assert_eq!(None, res.next);
+        assert_eq!(
+            Ok(Summary {
+                families: 2,
+                samples: 6
+            }),
+            validate(&res.text)
+        );
+    }
+
+    #[test]
+    fn test_backfill_max_bytes() {
+        let readings = history(&[
+            (1_665_400_000_000, 21.5, 40.0),
+            (1_665_400_030_000, 21.6, 40.5),
+            (1_665_400_060_000, 21.8, 41.5),
+        ]);
+
+        let all = backfill(&readings, TemperatureUnit::Fahrenheit, &[], usize::MAX);
+        let res = backfill(&readings, TemperatureUnit::Fahrenheit, &[], all.text.len() - 1);
+        assert_eq!(2, res.readings);
+        assert_eq!(Some(UNIX_EPOCH + Duration::from_secs(1_665_400_060)), res.next);
+        assert!(res.text.len() < all.text.len());
+        assert!(
+            res.text.contains("strudel_temperature_degrees 70.88 1665400030.000\n"),
+            "{}",
+            res.text
+        );
+        assert_eq!(
+            Ok(Summary {
+                families: 2,
+                samples: 4
+            }),
+            validate(&res.text)
+        );
+    }
+
+    #[test]
+    fn test_backfill_empty() {
+        let res = backfill(&[], TemperatureUnit::Celsius, &[("job", "a\"b\\c")], usize::MAX);
+        assert_eq!(
+            Backfill {
+                text: "# EOF\n".to_owned(),
+                readings: 0,
+                next: None,
+            },
+            res
+        );
+
+        let readings = history(&[(1_665_400_000_000, 21.5, 40.0)]);
+        let res = backfill(&readings, TemperatureUnit::Celsius, &[("job", "a\"b\\c")], usize::MAX);
+        assert!(
+            res.text
+                .contains("strudel_relative_humidity{job=\"a\\\"b\\\\c\"} 40 1665400000.000\n"),
+            "{}",
+            res.text
+        );
+        assert!(validate(&res.text).is_ok());
+    }
+}
@@ -0,0 +1,356 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! Fusion of readings from multiple sensors measuring the same thing into one estimate.
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt::{self, Formatter};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+/// Fewest residuals needed to estimate the variance of a sensor. Until every sensor has
+/// this many, all sensors are weighted equally.
+const MIN_RESIDUALS: usize = 2;
+
+/// Smallest variance used for a sensor so that one that happens to agree exactly with
+/// the estimate for a while doesn't get all the weight.
+const MIN_VARIANCE: f64 = 1e-4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuseGroupError {
+    msg: String,
+}
+
+impl fmt::Display for FuseGroupError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.msg.fmt(f)
+    }
+}
+
+impl Error for FuseGroupError {}
+
+/// Named group of sensors whose readings are fused, parsed from `sensor,sensor=name`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuseGroup {
+    pub name: String,
+    pub sensors: Vec<String>,
+}
+
+impl FromStr for FuseGroup {
+    type Err = FuseGroupError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (sensors, name) = s.rsplit_once('=').ok_or_else(|| FuseGroupError {
+            msg: format!("invalid group '{}', expected 'sensor,sensor=name'", s),
+        })?;
+
+        let name = parse_name(name.trim())?;
+        let mut parsed: Vec<String> = Vec::new();
+        for sensor in sensors.split(',').map(str::trim) {
+            let sensor = parse_name(sensor)?;
+            if parsed.contains(&sensor) {
+                return Err(FuseGroupError {
+                    msg: format!("sensor '{}' is included more than once in group '{}'", sensor, name),
+                });
+            }
+
+            parsed.push(sensor);
+        }
+
+        if parsed.len() < 2 {
+            return Err(FuseGroupError {
+                msg: format!("group '{}' must include at least two sensors", name),
+            });
+        }
+
+        Ok(Self { name, sensors: parsed })
+    }
+}
+
+impl fmt::Display for FuseGroup {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.sensors.join(","), self.name)
+    }
+}
+
+fn parse_name(val: &str) -> Result<String, FuseGroupError> {
+    if val.is_empty() || !val.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        Err(FuseGroupError {
+            msg: format!(
+                "invalid name '{}', must only contain letters, numbers, '-', and '_'",
+                val
+            ),
+        })
+    } else {
+        Ok(val.to_owned())
+    }
+}
+
+/// Fused value of several sensors and the weight given to each of them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Estimate {
+    pub value: f64,
+    /// Weight of each sensor in the order they were given to `FusionEstimator::new`,
+    /// summing to one. Sensors without a recent value have a weight of zero.
+    pub weights: Vec<(String, f64)>,
+}
+
+#[derive(Debug, Clone)]
+struct SensorValues {
+    name: String,
+    latest: Option<(f64, SystemTime)>,
+    residuals: VecDeque<f64>,
+}
+
+/// Variance weighted average of the values of several sensors measuring the same thing.
+///
+/// Each sensor is weighted by the inverse of its variance, estimated from the residuals
+/// of its last `window` values against the recent fused value, the mean of the last
+/// `window` estimates. Comparing to recent estimates rather than the current one lets the
+/// noisier of just two sensors be told apart, since both differ from their average by the
+/// same amount at any moment. Sensors that are noisier than the others are trusted less.
+/// Until every sensor has enough residuals, all sensors are weighted equally.
+///
+/// Only the latest value of each sensor is used and values older than `max_age` are
+/// ignored, so that the estimate tolerates sensors failing to read.
+#[derive(Debug, Clone)]
+pub struct FusionEstimator {
+    window: usize,
+    max_age: Duration,
+    sensors: Vec<SensorValues>,
+    estimates: VecDeque<f64>,
+}
+
+impl FusionEstimator {
+    pub fn new(sensors: &[String], window: usize, max_age: Duration) -> Self {
+        Self {
+            window: window.max(MIN_RESIDUALS),
+            max_age,
+            sensors: sensors
+                .iter()
+                .map(|name| SensorValues {
+                    name: name.clone(),
+                    latest: None,
+                    residuals: VecDeque::new(),
+                })
+                .collect(),
+            estimates: VecDeque::new(),
+        }
+    }
+
+    /// Record `value` read by `sensor` at `at`, returning the fused estimate as of `at`.
+    /// Returns `None` for sensors that aren't part of the estimate.
+    pub fn update(&mut self, sensor: &str, value: f64, at: SystemTime) -> Option<Estimate> {
+        let idx = self.sensors.iter().position(|s| s.name == sensor)?;
+        self.sensors[idx].latest = Some((value, at));
+
+        let estimate = self.estimate(at)?;
+        if !self.estimates.is_empty() {
+            let recent = self.estimates.iter().sum::<f64>() / self.estimates.len() as f64;
+            push_bounded(&mut self.sensors[idx].residuals, value - recent, self.window);
+        }
+
+        push_bounded(&mut self.estimates, estimate.value, self.window);
+        Some(estimate)
+    }
+
+    /// Fused estimate of the latest values of sensors as of `now`, `None` if no sensor
+    /// has a value that's at most `max_age` old.
+    pub fn estimate(&self, now: SystemTime) -> Option<Estimate> {
+        let fresh: Vec<bool> = self
+            .sensors
+            .iter()
+            .map(|s| {
+                s.latest
+                    .map(|(_, at)| now.duration_since(at).map(|d| d <= self.max_age).unwrap_or(true))
+                    .unwrap_or(false)
+            })
+            .collect();
+
+        let variances: Option<Vec<f64>> = self
+            .sensors
+            .iter()
+            .zip(fresh.iter())
+            .filter(|(_, f)| **f)
+            .map(|(s, _)| variance(&s.residuals))
+            .collect();
+
+        let mut raw: Vec<f64> = fresh.iter().map(|f| if *f { 1.0 } else { 0.0 }).collect();
+        if let Some(variances) = variances {
+            let mut variances = variances.into_iter();
+            for w in raw.iter_mut().filter(|w| **w > 0.0) {
+                *w = 1.0 / variances.next().unwrap_or(1.0);
+            }
+        }
+
+        let total: f64 = raw.iter().sum();
+        if total == 0.0 {
+            return None;
+        }
+
+        let value = self
+            .sensors
+            .iter()
+            .zip(raw.iter())
+            .filter_map(|(s, w)| s.latest.map(|(v, _)| v * w))
+            .sum::<f64>()
+            / total;
+
+        Some(Estimate {
+            value,
+            weights: self
+                .sensors
+                .iter()
+                .zip(raw.iter())
+                .map(|(s, w)| (s.name.clone(), w / total))
+                .collect(),
+        })
+    }
+}
+
+fn push_bounded(values: &mut VecDeque<f64>, value: f64, max: usize) {
+    if values.len() == max {
+        values.pop_front();
+    }
+
+    values.push_back(value);
+}
+
+/// Mean squared residual, `None` if there are too few residuals.
+fn variance(residuals: &VecDeque<f64>) -> Option<f64> {
+    if residuals.len() < MIN_RESIDUALS {
+        return None;
+    }
+
+    let sum: f64 = residuals.iter().map(|r| r * r).sum();
+    Some((sum / residuals.len() as f64).max(MIN_VARIANCE))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FuseGroup, FusionEstimator};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn estimator() -> FusionEstimator {
+        FusionEstimator::new(&["a".to_owned(), "b".to_owned()], 10, Duration::from_secs(60))
+    }
+
+    fn weight(estimator: &FusionEstimator, now: SystemTime, sensor: &str) -> f64 {
+        estimator
+            .estimate(now)
+            .unwrap()
+            .weights
+            .into_iter()
+            .find(|(s, _)| s == sensor)
+            .unwrap()
+            .1
+    }
+
+    #[test]
+    fn test_fuse_group_parse() {
+        let group: FuseGroup = "indoor_a,indoor_b=indoor".parse().unwrap();
+        assert_eq!("indoor", group.name);
+        assert_eq!(vec!["indoor_a", "indoor_b"], group.sensors);
+        assert_eq!("indoor_a,indoor_b=indoor", group.to_string());
+
+        assert!("indoor_a,indoor_b".parse::<FuseGroup>().is_err());
+        assert!("indoor_a=indoor".parse::<FuseGroup>().is_err());
+        assert!("indoor_a,indoor_a=indoor".parse::<FuseGroup>().is_err());
+        assert!("indoor_a,indoor b=indoor".parse::<FuseGroup>().is_err());
+        assert!("indoor_a,indoor_b=".parse::<FuseGroup>().is_err());
+    }
+
+    #[test]
+    fn test_fusion_estimator_no_values() {
+        let mut estimator = estimator();
+        assert_eq!(None, estimator.estimate(at(0)));
+        assert_eq!(None, estimator.update("c", 21.0, at(0)));
+    }
+
+    #[test]
+    fn test_fusion_estimator_equal_weights_initially() {
+        let mut estimator = estimator();
+        let estimate = estimator.update("a", 20.0, at(0)).unwrap();
+        assert_eq!(20.0, estimate.value);
+        assert_eq!(vec![("a".to_owned(), 1.0), ("b".to_owned(), 0.0)], estimate.weights);
+
+        let estimate = estimator.update("b", 22.0, at(1)).unwrap();
+        assert_eq!(21.0, estimate.value);
+        assert_eq!(vec![("a".to_owned(), 0.5), ("b".to_owned(), 0.5)], estimate.weights);
+    }
+
+    #[test]
+    fn test_fusion_estimator_noisy_sensor_weighted_less() {
+        let mut estimator = estimator();
+        for i in 0..20 {
+            let noise = if i % 2 == 0 { 1.0 } else { -1.0 };
+            estimator.update("a", 21.0 + noise, at(i * 30));
+            estimator.update("b", 21.0 + noise * 0.1, at(i * 30 + 1));
+        }
+
+        let now = at(20 * 30);
+        let a = weight(&estimator, now, "a");
+        let b = weight(&estimator, now, "b");
+        assert!(b > 0.9, "weight of quiet sensor {}", b);
+        assert!((a + b - 1.0).abs() < 1e-9);
+
+        let value = estimator.estimate(now).unwrap().value;
+        assert!((value - 21.0).abs() < 0.25, "estimate {}", value);
+    }
+
+    #[test]
+    fn test_fusion_estimator_sensor_drops_out() {
+        let mut estimator = estimator();
+        estimator.update("a", 20.0, at(0));
+        estimator.update("b", 22.0, at(0));
+
+        // Only the sensor that's still being read is used once the other is too old
+        let estimate = estimator.update("a", 20.5, at(90)).unwrap();
+        assert_eq!(20.5, estimate.value);
+        assert_eq!(vec![("a".to_owned(), 1.0), ("b".to_owned(), 0.0)], estimate.weights);
+        assert_eq!(None, estimator.estimate(at(200)));
+
+        // And again once the other sensor comes back
+        let estimate = estimator.update("b", 21.5, at(100)).unwrap();
+        assert_eq!(21.0, estimate.value);
+    }
+
+    #[test]
+    fn test_fusion_estimator_window() {
+        let mut estimator = FusionEstimator::new(&["a".to_owned(), "b".to_owned()], 4, Duration::from_secs(60));
+        for i in 0..10 {
+            let noise = if i % 2 == 0 { 1.0 } else { -1.0 };
+            estimator.update("a", 21.0 + noise, at(i * 30));
+            estimator.update("b", 21.0, at(i * 30 + 1));
+        }
+        assert!(weight(&estimator, at(300), "a") < 0.5);
+
+        // Once the noisy sensor settles down and its old residuals are forgotten, it's
+        // weighted about the same as the other
+        for i in 10..20 {
+            estimator.update("a", 21.0, at(i * 30));
+            estimator.update("b", 21.0, at(i * 30 + 1));
+        }
+        assert_eq!(0.5, weight(&estimator, at(600), "a"));
+    }
+}
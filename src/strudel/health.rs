@@ -0,0 +1,633 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+use crate::sensor::{RawReading, SensorError};
+use hyper::client::HttpConnector;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Method, Request, Uri};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt::{self, Formatter};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Overall health of the sensor based on recent reads
+#[derive(PartialEq, Eq, Debug, Hash, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SensorState {
+    Healthy,
+    Degraded,
+}
+
+impl SensorState {
+    pub fn as_label(&self) -> &'static str {
+        match self {
+            SensorState::Healthy => "healthy",
+            SensorState::Degraded => "degraded",
+        }
+    }
+}
+
+impl fmt::Display for SensorState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.as_label().fmt(f)
+    }
+}
+
+/// Successes and failures of reading the sensor within a sliding window of time.
+///
+/// Outcomes older than the window, relative to the most recently recorded outcome, are
+/// discarded. The number of consecutive successes or failures at the end of the window
+/// is tracked separately and isn't limited by the size of the window.
+#[derive(Debug, Clone)]
+pub struct OutcomeWindow {
+    window: Duration,
+    outcomes: VecDeque<(Instant, bool)>,
+    consecutive_failures: u32,
+    consecutive_successes: u32,
+}
+
+impl OutcomeWindow {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            outcomes: VecDeque::new(),
+            consecutive_failures: 0,
+            consecutive_successes: 0,
+        }
+    }
+
+    /// Record a successful or failed read that happened at `at`, according to the
+    /// monotonic clock.
+    pub fn record(&mut self, at: Instant, success: bool) {
+        if success {
+            self.consecutive_failures = 0;
+            self.consecutive_successes = self.consecutive_successes.saturating_add(1);
+        } else {
+            self.consecutive_successes = 0;
+            self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        }
+
+        self.outcomes.push_back((at, success));
+        if let Some(cutoff) = at.checked_sub(self.window) {
+            while self.outcomes.front().map(|(t, _)| *t < cutoff).unwrap_or(false) {
+                self.outcomes.pop_front();
+            }
+        }
+    }
+
+    /// Fraction of outcomes within the window that were failures, `None` if there are
+    /// no outcomes.
+    pub fn failure_ratio(&self) -> Option<f64> {
+        if self.outcomes.is_empty() {
+            return None;
+        }
+
+        let failures = self.outcomes.iter().filter(|(_, success)| !success).count();
+        Some(failures as f64 / self.outcomes.len() as f64)
+    }
+
+    /// Number of outcomes within the window.
+    pub fn len(&self) -> usize {
+        self.outcomes.len()
+    }
+
+    /// Return true if there are no outcomes within the window.
+    pub fn is_empty(&self) -> bool {
+        self.outcomes.is_empty()
+    }
+
+    /// Number of failures recorded since the last success.
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures
+    }
+
+    /// Number of successes recorded since the last failure.
+    pub fn consecutive_successes(&self) -> u32 {
+        self.consecutive_successes
+    }
+}
+
+/// Exponentially weighted moving average, weighting each new value by `alpha` (between
+/// zero and one) and the previous average by `1 - alpha`. Smaller values of `alpha`
+/// change more slowly, roughly averaging the last `1 / alpha` values.
+#[derive(Debug, Clone)]
+pub struct Ewma {
+    alpha: f64,
+    average: Option<f64>,
+}
+
+impl Ewma {
+    pub fn new(alpha: f64) -> Self {
+        Self { alpha, average: None }
+    }
+
+    /// Add `value` to the average, returning the ratio of `value` to the average before
+    /// it was added. The first value becomes the average so its ratio is one. The ratio
+    /// is also one while the average is zero since it can't be computed.
+    pub fn add(&mut self, value: f64) -> f64 {
+        let previous = self.average.unwrap_or(value);
+        self.average = Some(previous + self.alpha * (value - previous));
+
+        if previous == 0.0 {
+            1.0
+        } else {
+            value / previous
+        }
+    }
+
+    /// Current average, `None` if no values have been added.
+    pub fn average(&self) -> Option<f64> {
+        self.average
+    }
+}
+
+/// Change of the sensor from one state to another
+#[derive(PartialEq, Eq, Debug, Clone, Serialize)]
+pub struct Transition {
+    pub state: SensorState,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+}
+
+/// Track the health of the sensor based on the results of reading it.
+///
+/// The sensor starts out healthy and becomes degraded after a number of consecutive
+/// failed reads. It becomes healthy again after a number of consecutive successful
+/// reads. Individual failures that don't cross the threshold don't change the state.
+#[derive(Debug)]
+pub struct HealthTracker {
+    state: SensorState,
+    degraded_after: u32,
+    healthy_after: u32,
+    outcomes: OutcomeWindow,
+    last_error: Option<String>,
+}
+
+impl HealthTracker {
+    /// Create a new tracker that marks the sensor degraded after `degraded_after`
+    /// consecutive failures and healthy after `healthy_after` consecutive successes.
+    pub fn new(degraded_after: u32, healthy_after: u32) -> Self {
+        Self {
+            state: SensorState::Healthy,
+            degraded_after,
+            healthy_after,
+            // Only consecutive outcomes matter so there's no need to keep any history
+            outcomes: OutcomeWindow::new(Duration::ZERO),
+            last_error: None,
+        }
+    }
+
+    /// Current state of the sensor
+    pub fn state(&self) -> SensorState {
+        self.state
+    }
+
+    /// Record the result of a read of the sensor, returning a `Transition` if it caused
+    /// the state of the sensor to change.
+    pub fn record<T>(&mut self, result: &Result<T, SensorError>) -> Option<Transition> {
+        self.outcomes.record(Instant::now(), result.is_ok());
+
+        match result {
+            Ok(_) => {
+                if self.state == SensorState::Degraded && self.outcomes.consecutive_successes() >= self.healthy_after {
+                    return Some(self.transition(SensorState::Healthy));
+                }
+            }
+            Err(e) => {
+                self.last_error = Some(e.to_string());
+
+                if self.state == SensorState::Healthy && self.outcomes.consecutive_failures() >= self.degraded_after {
+                    return Some(self.transition(SensorState::Degraded));
+                }
+            }
+        }
+
+        None
+    }
+
+    fn transition(&mut self, state: SensorState) -> Transition {
+        self.state = state;
+        Transition {
+            state,
+            consecutive_failures: self.outcomes.consecutive_failures(),
+            last_error: self.last_error.clone(),
+        }
+    }
+}
+
+/// Detect a sensor that is stuck returning the same bytes for every read.
+///
+/// A sensor that has locked up can keep returning an identical frame with a valid
+/// checksum, so reads succeed and values look plausible but never change. Real readings
+/// fluctuate at least in their decimal digits so a long run of bit-identical frames means
+/// the sensor is stuck. Any differing frame or failed read resets the run.
+#[derive(Debug)]
+pub struct StuckDetector {
+    threshold: u32,
+    last: Option<RawReading>,
+    identical: u32,
+    stuck: bool,
+}
+
+impl StuckDetector {
+    /// Create a new detector that marks the sensor stuck after `threshold` consecutive
+    /// reads returned bit-identical frames.
+    pub fn new(threshold: u32) -> Self {
+        Self {
+            threshold,
+            last: None,
+            identical: 0,
+            stuck: false,
+        }
+    }
+
+    /// Whether the sensor is currently stuck
+    pub fn stuck(&self) -> bool {
+        self.stuck
+    }
+
+    /// Number of consecutive reads, including the most recent one, that returned the
+    /// same frame. Zero if the most recent read failed or didn't return a frame.
+    pub fn identical(&self) -> u32 {
+        self.identical
+    }
+
+    /// Record the result of a read of the sensor and the frame it returned, if any,
+    /// returning whether the sensor is stuck if it changed.
+    pub fn record<T>(&mut self, result: &Result<T, SensorError>, frame: Option<&RawReading>) -> Option<bool> {
+        match (result, frame) {
+            (Ok(_), Some(frame)) => {
+                if self.last.as_ref() == Some(frame) {
+                    self.identical += 1;
+                } else {
+                    self.last = Some(*frame);
+                    self.identical = 1;
+                }
+            }
+            _ => {
+                self.last = None;
+                self.identical = 0;
+            }
+        }
+
+        let stuck = self.identical >= self.threshold;
+        if stuck != self.stuck {
+            self.stuck = stuck;
+            Some(stuck)
+        } else {
+            None
+        }
+    }
+}
+
+/// Send a JSON `POST` request to a URL each time the state of the sensor changes.
+///
+/// Requests are sent from a separate task so that reading the sensor is never blocked.
+/// Failed requests are retried once.
+#[derive(Debug, Clone)]
+pub struct HealthWebhook {
+    client: Client<HttpConnector>,
+    uri: Arc<Uri>,
+}
+
+impl HealthWebhook {
+    pub fn new(uri: Uri) -> Self {
+        Self {
+            client: Client::new(),
+            uri: Arc::new(uri),
+        }
+    }
+
+    /// Send a notification for the transition in the background. This method must be
+    /// called from within a Tokio runtime.
+    pub fn notify(&self, transition: Transition) {
+        let hook = self.clone();
+
+        tokio::spawn(async move {
+            // Transition is only simple types that can always be serialized
+            let body = serde_json::to_vec(&transition).unwrap();
+
+            for attempt in 1..=2 {
+                match hook.send(body.clone()).await {
+                    Ok(_) => return,
+                    Err(e) => {
+                        tracing::warn!(message = "unable to send state webhook", attempt = attempt, error = %e);
+                    }
+                }
+            }
+        });
+    }
+
+    async fn send(&self, body: Vec<u8>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(self.uri.as_ref().clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body))?;
+
+        let res = self.client.request(req).await?;
+        if res.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("unexpected response status: {}", res.status()).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Ewma, HealthTracker, OutcomeWindow, SensorState, StuckDetector, Transition};
+    use crate::sensor::{RawReading, SensorError};
+    use std::time::{Duration, Instant};
+
+    fn ok() -> Result<(), SensorError> {
+        Ok(())
+    }
+
+    fn err() -> Result<(), SensorError> {
+        Err(SensorError::timeout("timeout"))
+    }
+
+    #[test]
+    fn test_health_tracker_stays_healthy_below_threshold() {
+        let mut tracker = HealthTracker::new(3, 2);
+
+        assert_eq!(None, tracker.record(&err()));
+        assert_eq!(None, tracker.record(&err()));
+        assert_eq!(None, tracker.record(&ok()));
+        assert_eq!(None, tracker.record(&err()));
+        assert_eq!(None, tracker.record(&err()));
+        assert_eq!(SensorState::Healthy, tracker.state());
+    }
+
+    #[test]
+    fn test_health_tracker_degraded_after_failures() {
+        let mut tracker = HealthTracker::new(3, 2);
+
+        assert_eq!(None, tracker.record(&err()));
+        assert_eq!(None, tracker.record(&err()));
+        assert_eq!(
+            Some(Transition {
+                state: SensorState::Degraded,
+                consecutive_failures: 3,
+                last_error: Some("timeout".to_owned()),
+            }),
+            tracker.record(&err())
+        );
+
+        // Only a single transition for more failures
+        assert_eq!(None, tracker.record(&err()));
+        assert_eq!(SensorState::Degraded, tracker.state());
+    }
+
+    #[test]
+    fn test_health_tracker_healthy_after_successes() {
+        let mut tracker = HealthTracker::new(1, 2);
+
+        assert!(tracker.record(&err()).is_some());
+        assert_eq!(None, tracker.record(&ok()));
+        assert_eq!(None, tracker.record(&err()));
+        assert_eq!(None, tracker.record(&ok()));
+        assert_eq!(
+            Some(Transition {
+                state: SensorState::Healthy,
+                consecutive_failures: 0,
+                last_error: Some("timeout".to_owned()),
+            }),
+            tracker.record(&ok())
+        );
+        assert_eq!(SensorState::Healthy, tracker.state());
+    }
+
+    #[test]
+    fn test_transition_json() {
+        let transition = Transition {
+            state: SensorState::Degraded,
+            consecutive_failures: 5,
+            last_error: Some("checksum error: expected 1, got 2".to_owned()),
+        };
+
+        assert_eq!(
+            r#"{"state":"degraded","consecutive_failures":5,"last_error":"checksum error: expected 1, got 2"}"#,
+            serde_json::to_string(&transition).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_ewma_first_value() {
+        let mut ewma = Ewma::new(0.1);
+        assert_eq!(None, ewma.average());
+
+        assert_eq!(1.0, ewma.add(400.0));
+        assert_eq!(Some(400.0), ewma.average());
+    }
+
+    #[test]
+    fn test_ewma_ratio_to_previous_average() {
+        let mut ewma = Ewma::new(0.5);
+        ewma.add(400.0);
+
+        // Compared to the average before the value is added
+        assert_eq!(0.5, ewma.add(200.0));
+        assert_eq!(Some(300.0), ewma.average());
+        assert_eq!(1.0, ewma.add(300.0));
+        assert_eq!(Some(300.0), ewma.average());
+    }
+
+    #[test]
+    fn test_ewma_slow_drift() {
+        let mut ewma = Ewma::new(0.01);
+        for _ in 0..100 {
+            ewma.add(400.0);
+        }
+
+        // A sudden drop shows up in the ratio but barely moves the long-run average
+        let ratio = ewma.add(360.0);
+        assert!((ratio - 0.9).abs() < 1e-9, "unexpected ratio {}", ratio);
+        assert!((ewma.average().unwrap() - 399.6).abs() < 1e-9);
+
+        // Values that stay low are eventually the new average
+        for _ in 0..1000 {
+            ewma.add(360.0);
+        }
+        assert!((ewma.add(360.0) - 1.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_ewma_zero_average() {
+        let mut ewma = Ewma::new(0.5);
+        ewma.add(0.0);
+        assert_eq!(1.0, ewma.add(100.0));
+        assert_eq!(Some(50.0), ewma.average());
+    }
+
+    #[test]
+    fn test_outcome_window_empty() {
+        let window = OutcomeWindow::new(Duration::from_secs(300));
+
+        assert_eq!(None, window.failure_ratio());
+        assert_eq!(0, window.len());
+        assert_eq!(0, window.consecutive_failures());
+        assert_eq!(0, window.consecutive_successes());
+    }
+
+    #[test]
+    fn test_outcome_window_failure_ratio() {
+        let mut window = OutcomeWindow::new(Duration::from_secs(300));
+        let start = Instant::now();
+
+        for (i, success) in [true, false, true, true, false, true, true, true].iter().enumerate() {
+            window.record(start + Duration::from_secs(i as u64 * 30), *success);
+        }
+
+        assert_eq!(8, window.len());
+        assert_eq!(Some(0.25), window.failure_ratio());
+        assert_eq!(3, window.consecutive_successes());
+        assert_eq!(0, window.consecutive_failures());
+    }
+
+    #[test]
+    fn test_outcome_window_prunes_old_outcomes() {
+        let mut window = OutcomeWindow::new(Duration::from_secs(300));
+        let start = Instant::now();
+
+        // Failures at the start fall out of the window once enough time has passed
+        window.record(start, false);
+        window.record(start + Duration::from_secs(60), false);
+        for i in 0..4 {
+            window.record(start + Duration::from_secs(300 + i * 60), true);
+        }
+
+        assert_eq!(4, window.len());
+        assert_eq!(Some(0.0), window.failure_ratio());
+
+        window.record(start + Duration::from_secs(600), false);
+        assert_eq!(5, window.len());
+        assert_eq!(Some(0.2), window.failure_ratio());
+        assert_eq!(1, window.consecutive_failures());
+        assert_eq!(0, window.consecutive_successes());
+    }
+
+    #[test]
+    fn test_outcome_window_consecutive_not_limited_by_window() {
+        let mut window = OutcomeWindow::new(Duration::ZERO);
+        let start = Instant::now();
+
+        for i in 0..5 {
+            window.record(start + Duration::from_secs(i * 30), false);
+        }
+
+        assert_eq!(1, window.len());
+        assert_eq!(Some(1.0), window.failure_ratio());
+        assert_eq!(5, window.consecutive_failures());
+    }
+
+    fn frame(bytes: [u8; 5]) -> RawReading {
+        RawReading { bytes }
+    }
+
+    #[test]
+    fn test_stuck_detector_stuck_after_identical_frames() {
+        let mut detector = StuckDetector::new(3);
+        let f = frame([0x02, 0x8C, 0x01, 0x5F, 0xEE]);
+
+        assert_eq!(None, detector.record(&ok(), Some(&f)));
+        assert_eq!(None, detector.record(&ok(), Some(&f)));
+        assert_eq!(Some(true), detector.record(&ok(), Some(&f)));
+
+        // Only a single change for more identical frames
+        assert_eq!(None, detector.record(&ok(), Some(&f)));
+        assert_eq!(4, detector.identical());
+        assert!(detector.stuck());
+    }
+
+    #[test]
+    fn test_stuck_detector_decimal_bits_differ() {
+        let mut detector = StuckDetector::new(3);
+
+        // Same whole number values, only the tenths (and so the checksum) change
+        assert_eq!(
+            None,
+            detector.record(&ok(), Some(&frame([0x02, 0x8C, 0x01, 0x5F, 0xEE])))
+        );
+        assert_eq!(
+            None,
+            detector.record(&ok(), Some(&frame([0x02, 0x8C, 0x01, 0x5F, 0xEE])))
+        );
+        assert_eq!(
+            None,
+            detector.record(&ok(), Some(&frame([0x02, 0x8C, 0x01, 0x60, 0xEF])))
+        );
+        assert_eq!(
+            None,
+            detector.record(&ok(), Some(&frame([0x02, 0x8C, 0x01, 0x60, 0xEF])))
+        );
+        assert_eq!(2, detector.identical());
+        assert!(!detector.stuck());
+    }
+
+    #[test]
+    fn test_stuck_detector_error_resets() {
+        let mut detector = StuckDetector::new(3);
+        let f = frame([0x02, 0x8C, 0x01, 0x5F, 0xEE]);
+
+        assert_eq!(None, detector.record(&ok(), Some(&f)));
+        assert_eq!(None, detector.record(&ok(), Some(&f)));
+        assert_eq!(None, detector.record(&err(), Some(&f)));
+        assert_eq!(0, detector.identical());
+        assert_eq!(None, detector.record(&ok(), Some(&f)));
+        assert_eq!(None, detector.record(&ok(), Some(&f)));
+        assert_eq!(Some(true), detector.record(&ok(), Some(&f)));
+    }
+
+    #[test]
+    fn test_stuck_detector_missing_frame_resets() {
+        let mut detector = StuckDetector::new(2);
+        let f = frame([0x02, 0x8C, 0x01, 0x5F, 0xEE]);
+
+        assert_eq!(None, detector.record(&ok(), Some(&f)));
+        assert_eq!(None, detector.record(&ok(), None));
+        assert_eq!(None, detector.record(&ok(), Some(&f)));
+        assert_eq!(1, detector.identical());
+    }
+
+    #[test]
+    fn test_stuck_detector_recovers_on_differing_frame() {
+        let mut detector = StuckDetector::new(2);
+        let f = frame([0x02, 0x8C, 0x01, 0x5F, 0xEE]);
+
+        assert_eq!(None, detector.record(&ok(), Some(&f)));
+        assert_eq!(Some(true), detector.record(&ok(), Some(&f)));
+        assert_eq!(
+            Some(false),
+            detector.record(&ok(), Some(&frame([0x02, 0x8D, 0x01, 0x5F, 0xEF])))
+        );
+        assert!(!detector.stuck());
+    }
+
+    #[test]
+    fn test_stuck_detector_recovers_on_error() {
+        let mut detector = StuckDetector::new(2);
+        let f = frame([0x02, 0x8C, 0x01, 0x5F, 0xEE]);
+
+        assert_eq!(None, detector.record(&ok(), Some(&f)));
+        assert_eq!(Some(true), detector.record(&ok(), Some(&f)));
+        assert_eq!(Some(false), detector.record(&err(), None));
+    }
+}
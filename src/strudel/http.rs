@@ -16,34 +16,3253 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 //
 
-use axum::extract::State;
-use axum::http::header::CONTENT_TYPE;
-use axum::http::{HeaderMap, HeaderValue, StatusCode};
-use axum::response::IntoResponse;
+use crate::exposition;
+use crate::metrics::{Encoded, HttpMetrics, Registries};
+use crate::sensor::{
+    DynPowerController, LatestReading, LatestReadingCell, NamedReading, PowerCycleReason, PowerCycleSkipped,
+    ReadRequester, ReadingEvent, ReadingHistory, SensorError, SensorSpec, TemperatureUnit,
+};
+use crate::version::VERSION;
+use axum::body::Bytes;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, State};
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE, ETAG, HOST, IF_NONE_MATCH, WWW_AUTHENTICATE};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::Router;
+use futures_util::stream::{self, Stream};
 use prometheus_client::encoding::text;
 use prometheus_client::registry::Registry;
-use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::Infallible;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex as SyncMutex, PoisonError};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tokio::sync::{oneshot, watch, Mutex, Notify, Semaphore};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::trace::TraceLayer;
 
-const METRICS_TEXT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+/// How long to wait after responding to `/-/quit` before shutting down the server
+const QUIT_DELAY: Duration = Duration::from_millis(100);
+
+pub(crate) const METRICS_TEXT: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Header listing groups of metrics that couldn't be encoded when a scrape is partial
+pub const ENCODE_ERRORS_HEADER: HeaderName = HeaderName::from_static("x-strudel-encode-errors");
+
+/// Header Prometheus sends with the number of seconds it waits for a scrape before giving up
+pub const SCRAPE_TIMEOUT_HEADER: HeaderName = HeaderName::from_static("x-prometheus-scrape-timeout-seconds");
+
+/// How long to spend on a scrape when the scraper doesn't say how long it waits
+pub const DEFAULT_SCRAPE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Header with the time, as a UNIX timestamp, to backfill from next when a backfill
+/// response had to leave out readings to stay under its size limit
+pub const BACKFILL_NEXT_HEADER: HeaderName = HeaderName::from_static("x-strudel-backfill-next");
+
+/// Largest backfill response to send by default, see `BackfillSource`.
+pub const DEFAULT_BACKFILL_MAX_BYTES: usize = 4 * 1024 * 1024;
+
+/// Most clients connected to `GET /ws` at once by default, see `LiveReadings`.
+pub const DEFAULT_LIVE_MAX_CONNECTIONS: usize = 8;
+
+/// How long to wait for a message to be sent to a client of `GET /ws` by default before
+/// disconnecting it, see `LiveReadings`.
+pub const DEFAULT_LIVE_SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Header clients send when reconnecting to `GET /events` with the ID of the last event
+/// they got
+pub const LAST_EVENT_ID_HEADER: HeaderName = HeaderName::from_static("last-event-id");
+
+/// Events kept by default for clients of `GET /events` that reconnect, see `EventLog`.
+pub const DEFAULT_EVENT_LOG_CAPACITY: usize = 100;
+
+/// How often to send clients of `GET /events` a comment so that proxies don't close the
+/// connection while there aren't any events
+const EVENTS_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Time left for the response to reach a scraper that sent its scrape timeout
+const SCRAPE_TIMEOUT_MARGIN: Duration = Duration::from_millis(250);
+
+/// Group of metrics about readings that the entity tag of `/metrics` is based on
+const ETAG_GROUP: &str = "sensor";
 
 #[derive(Debug)]
 pub struct RequestState {
-    pub registry: Registry,
+    pub registries: Registries,
+    pub metrics: HttpMetrics,
+    pub latest: Arc<LatestReadingCell>,
+    pub single_sensor: bool,
+    pub cors: Option<CorsSettings>,
+    pub lifecycle: Option<Shutdown>,
+    pub lifecycle_token: Option<String>,
+    pub sensors: Option<ManagedSensors>,
+    pub power: Option<Arc<DynPowerController>>,
+    pub backfill: Option<BackfillSource>,
+    pub slim: Option<Registry>,
+    pub live: Option<LiveReadings>,
+    pub events: Option<Arc<EventLog>>,
+    pub scrape_reads: Option<ScrapeReads>,
+    pub description: Option<SelfDescription>,
+    pub status: Option<Value>,
+    pub scrape_timeout: Duration,
+    // Entity tag of `/metrics` and the reading generation it was computed for
+    etag: SyncMutex<Option<(u64, String)>>,
 }
 
-pub async fn text_metrics_handler(State(state): State<Arc<RequestState>>) -> impl IntoResponse {
-    let mut buf = String::new();
+impl RequestState {
+    /// Create a builder for state shared by HTTP handlers, serving metrics from `registries`
+    /// and readings from `latest`.
+    pub fn builder(registries: Registries, latest: Arc<LatestReadingCell>) -> RequestStateBuilder {
+        RequestStateBuilder {
+            registries,
+            latest,
+            single_sensor: false,
+            cors: None,
+            lifecycle: None,
+            lifecycle_token: None,
+            sensors: None,
+            power: None,
+            backfill: None,
+            slim: None,
+            live: None,
+            events: None,
+            scrape_reads: None,
+            description: None,
+            status: None,
+            scrape_timeout: DEFAULT_SCRAPE_TIMEOUT,
+        }
+    }
+
+    /// Entity tag of `/metrics` for readings up to `generation`, computed from the metrics
+    /// about readings the first time it's needed for each generation. Other metrics, like
+    /// those about scrapes themselves, change on every scrape and aren't part of the tag.
+    fn metrics_etag(&self, generation: u64, encoded: &Encoded) -> String {
+        let mut cached = self.etag.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some((g, tag)) = cached.as_ref() {
+            if *g == generation {
+                return tag.clone();
+            }
+        }
+
+        // Scrapes that started before a newer generation was cached don't replace it
+        let tag = etag(generation, encoded.group(ETAG_GROUP).unwrap_or_default());
+        if !matches!(cached.as_ref(), Some((g, _)) if *g > generation) {
+            *cached = Some((generation, tag.clone()));
+        }
+        tag
+    }
+}
+
+/// Builder for `RequestState`, registering metrics about HTTP requests in the `http`
+/// group of the registries when built.
+#[derive(Debug)]
+pub struct RequestStateBuilder {
+    registries: Registries,
+    latest: Arc<LatestReadingCell>,
+    single_sensor: bool,
+    cors: Option<CorsSettings>,
+    lifecycle: Option<Shutdown>,
+    lifecycle_token: Option<String>,
+    sensors: Option<Arc<dyn SensorManager>>,
+    power: Option<Arc<DynPowerController>>,
+    backfill: Option<BackfillSource>,
+    slim: Option<Registry>,
+    live: Option<LiveReadings>,
+    events: Option<Arc<EventLog>>,
+    scrape_reads: Option<ScrapeReads>,
+    description: Option<SelfDescription>,
+    status: Option<Value>,
+    scrape_timeout: Duration,
+}
+
+impl RequestStateBuilder {
+    /// Serve the reading of the only sensor from `GET /readings` as a single object, for
+    /// deployments with a single unnamed sensor. By default, readings of every sensor are
+    /// served as an array.
+    pub fn single_sensor(mut self) -> Self {
+        self.single_sensor = true;
+        self
+    }
+
+    /// Allow browsers to make cross-origin requests to routes served using this state.
+    /// By default, no CORS headers are sent.
+    pub fn cors(mut self, cors: CorsSettings) -> Self {
+        self.cors = Some(cors);
+        self
+    }
+
+    /// Allow a graceful shutdown of the server to be requested with `POST /-/quit`,
+    /// triggering `shutdown`. By default, the endpoint responds with 403.
+    pub fn lifecycle(mut self, shutdown: Shutdown) -> Self {
+        self.lifecycle = Some(shutdown);
+        self
+    }
+
+    /// Require lifecycle endpoints to be called with `Authorization: Bearer <token>`,
+    /// responding with 401 otherwise. By default, no authorization is required except
+    /// for `POST /-/sensors` which isn't available without a token.
+    pub fn lifecycle_token(mut self, token: String) -> Self {
+        self.lifecycle_token = Some(token);
+        self
+    }
+
+    /// Allow the sensor to be replaced with `POST /-/sensors` using `manager` when
+    /// lifecycle endpoints are enabled and a token is set. By default, the endpoint
+    /// responds with 403.
+    pub fn sensors(mut self, manager: Arc<dyn SensorManager>) -> Self {
+        self.sensors = Some(manager);
+        self
+    }
+
+    /// Allow power to the sensor to be cycled with `POST /-/power-cycle` using `power` when
+    /// lifecycle endpoints are enabled. By default, the endpoint responds with 403.
+    pub fn power(mut self, power: Arc<DynPowerController>) -> Self {
+        self.power = Some(power);
+        self
+    }
+
+    /// Serve readings kept by `source` with `GET /metrics/backfill`, see `backfill_handler`.
+    /// By default, the endpoint responds with 404.
+    pub fn backfill(mut self, source: BackfillSource) -> Self {
+        self.backfill = Some(source);
+        self
+    }
+
+    /// Serve only the metrics registered in `registry` with `GET /metrics/slim`, see
+    /// `slim_metrics_handler`. By default, the endpoint responds with 404.
+    pub fn slim(mut self, registry: Registry) -> Self {
+        self.slim = Some(registry);
+        self
+    }
+
+    /// Push readings from `live` to WebSocket clients of `GET /ws`, see `ws_handler`. By
+    /// default, the endpoint responds with 404.
+    pub fn live(mut self, live: LiveReadings) -> Self {
+        self.live = Some(live);
+        self
+    }
+
+    /// Stream events from `log` as Server-Sent Events with `GET /events`, see
+    /// `events_handler`. By default, the endpoint responds with 404.
+    pub fn events(mut self, log: Arc<EventLog>) -> Self {
+        self.events = Some(log);
+        self
+    }
+
+    /// Read sensors before encoding metrics for each scrape, see `ScrapeReads`. By default,
+    /// scrapes are served from the most recent reads.
+    pub fn read_on_scrape(mut self, reads: ScrapeReads) -> Self {
+        self.scrape_reads = Some(reads);
+        self
+    }
+
+    /// Describe this instance of strudel with `GET /api/v1/self`, see `self_handler`. By
+    /// default, the endpoint responds with 404.
+    pub fn description(mut self, description: SelfDescription) -> Self {
+        self.description = Some(description);
+        self
+    }
+
+    /// Give up on scrapes that take longer than `timeout` when the scraper doesn't send
+    /// `X-Prometheus-Scrape-Timeout-Seconds`, see `text_metrics_handler`. Default 10 seconds.
+    pub fn scrape_timeout(mut self, timeout: Duration) -> Self {
+        self.scrape_timeout = timeout;
+        self
+    }
+
+    /// Report how this instance of strudel was started with `GET /status`, see
+    /// `status_handler`. By default, the endpoint responds with 404.
+    pub fn status(mut self, status: Value) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    pub fn build(self) -> RequestState {
+        let mut registries = self.registries;
+        let metrics = HttpMetrics::new(registries.group("http"));
+
+        RequestState {
+            registries,
+            metrics,
+            latest: self.latest,
+            single_sensor: self.single_sensor,
+            cors: self.cors,
+            lifecycle: self.lifecycle,
+            lifecycle_token: self.lifecycle_token,
+            sensors: self.sensors.map(ManagedSensors::new),
+            power: self.power,
+            backfill: self.backfill,
+            slim: self.slim,
+            live: self.live,
+            events: self.events,
+            scrape_reads: self.scrape_reads,
+            description: self.description,
+            status: self.status,
+            scrape_timeout: self.scrape_timeout,
+            etag: SyncMutex::new(None),
+        }
+    }
+}
+
+/// Replaces the sensor being read when requested by `sensors_handler`.
+pub trait SensorManager: fmt::Debug + Send + Sync {
+    /// Check `spec` and start replacing the sensor with one opened from it, returning a
+    /// receiver that completes once it has been replaced or opening it failed. Returns the
+    /// reasons `spec` is invalid, without replacing anything, if it can't be used.
+    fn replace(&self, spec: SensorSpec) -> Result<oneshot::Receiver<Result<(), SensorError>>, Vec<String>>;
+
+    /// Specs of the sensors currently being read, including any replacements.
+    fn sensors(&self) -> Vec<SensorSpec>;
+}
+
+/// `SensorManager` used by `sensors_handler`, allowing a single replacement at a time.
+#[derive(Debug)]
+pub struct ManagedSensors {
+    manager: Arc<dyn SensorManager>,
+    replacing: Mutex<()>,
+}
+
+impl ManagedSensors {
+    pub fn new(manager: Arc<dyn SensorManager>) -> Self {
+        Self {
+            manager,
+            replacing: Mutex::new(()),
+        }
+    }
+}
+
+/// Trigger for a graceful shutdown of the server, shared between `quit_handler` and
+/// whatever waits for the server to shut down.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    notify: Arc<Notify>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request a shutdown, waking the caller of `wait` now or when it's next called.
+    pub fn trigger(&self) {
+        self.notify.notify_one();
+    }
+
+    /// Return after a shutdown has been requested by `trigger`.
+    pub async fn wait(&self) {
+        self.notify.notified().await
+    }
+}
+
+/// Sensors to read when metrics are scraped and how long to wait for them.
+///
+/// Each scrape requests a read of every sensor and waits up to `timeout` for the reads
+/// to be handled by subscribers that update metrics before encoding them. If they take
+/// longer, the most recent readings are served instead and the timeout is counted by
+/// `HttpMetrics`. Reads within a short window of a previous read reuse it, see
+/// `SensorWorker::on_demand`.
+#[derive(Debug, Clone)]
+pub struct ScrapeReads {
+    pub readers: Vec<ReadRequester>,
+    pub timeout: Duration,
+}
+
+impl ScrapeReads {
+    /// Request reads of all sensors, returning false if any weren't handled in time.
+    async fn read(&self) -> bool {
+        let pending: Vec<_> = self.readers.iter().map(|r| r.request()).collect();
+        let wait = async {
+            for p in pending {
+                let _ = p.await;
+            }
+        };
+
+        tokio::time::timeout(self.timeout, wait).await.is_ok()
+    }
+}
+
+/// Readings served by `backfill_handler` and how they're rendered.
+#[derive(Debug, Clone)]
+pub struct BackfillSource {
+    pub history: Arc<ReadingHistory>,
+    /// Unit of temperatures, the same as for scrapes.
+    pub unit: TemperatureUnit,
+    /// Largest response to send, leaving out the newest readings if needed.
+    pub max_bytes: usize,
+}
+
+/// Events pushed to WebSocket clients by `ws_handler` and how many clients are allowed.
+///
+/// Each client is sent the most recent event when it's ready for another one: a client
+/// that falls behind skips to the latest event instead of buffering every one. Clients
+/// that take longer than the send timeout to accept a message are disconnected.
+#[derive(Debug, Clone)]
+pub struct LiveReadings {
+    events: watch::Receiver<Option<Arc<ReadingEvent>>>,
+    connections: Arc<Semaphore>,
+    send_timeout: Duration,
+}
+
+impl LiveReadings {
+    /// Push events from `events`, see `WorkerHandle::events`, to at most `max_connections`
+    /// clients at once.
+    pub fn new(events: watch::Receiver<Option<Arc<ReadingEvent>>>, max_connections: usize) -> Self {
+        Self {
+            events,
+            connections: Arc::new(Semaphore::new(max_connections)),
+            send_timeout: DEFAULT_LIVE_SEND_TIMEOUT,
+        }
+    }
+
+    /// Disconnect clients that take longer than `timeout` to accept a message. Default
+    /// 10 seconds.
+    pub fn send_timeout(mut self, timeout: Duration) -> Self {
+        self.send_timeout = timeout;
+        self
+    }
+}
+
+#[derive(Debug, Default)]
+struct LogState {
+    last_id: u64,
+    events: VecDeque<(u64, LiveEvent)>,
+}
+
+/// Recent reads of the sensor, successful or not, streamed by `events_handler`.
+///
+/// Events are numbered from 1 in the order they happen, starting over each time strudel
+/// starts. Up to `capacity` of the most recent events are kept so that clients that
+/// reconnect with the ID of the last event they got can be sent the ones they missed.
+#[derive(Debug)]
+pub struct EventLog {
+    capacity: usize,
+    state: SyncMutex<LogState>,
+    changes: watch::Sender<u64>,
+    closed: AtomicBool,
+}
+
+impl EventLog {
+    /// Create a log keeping up to `capacity` events, at least one.
+    pub fn new(capacity: usize) -> Self {
+        let (changes, _) = watch::channel(0);
+        Self {
+            capacity: capacity.max(1),
+            state: SyncMutex::new(LogState::default()),
+            changes,
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// Add an event for a read of the sensor, dropping the oldest event if the log is
+    /// full. Intended to be used as a subscriber of a `SensorWorker`.
+    pub fn update(&self, event: &ReadingEvent) {
+        let id = {
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            if state.events.len() == self.capacity {
+                state.events.pop_front();
+            }
+
+            state.last_id += 1;
+            let id = state.last_id;
+            state.events.push_back((id, LiveEvent::from_event(event)));
+            id
+        };
+
+        self.changes.send_replace(id);
+    }
+
+    /// End every stream of events so that the server can shut down, they'd never end
+    /// otherwise.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.changes.send_modify(|_| {});
+    }
+
+    /// Number of events kept.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner).events.len()
+    }
+
+    /// Return true if no events are kept.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn last_id(&self) -> u64 {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner).last_id
+    }
+
+    fn after(&self, id: u64) -> VecDeque<(u64, LiveEvent)> {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.events.iter().filter(|(i, _)| *i > id).cloned().collect()
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+}
+
+/// Origins allowed to make cross-origin `GET` requests and which routes allow them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorsSettings {
+    /// Allowed origins, for example `https://example.com`, or `*` to allow any origin.
+    pub origins: Vec<HeaderValue>,
+    /// Allow cross-origin requests to `/metrics` as well as JSON routes.
+    pub all_routes: bool,
+}
+
+impl CorsSettings {
+    fn layer(&self) -> CorsLayer {
+        let origin = if self.origins.iter().any(|o| o == "*") {
+            AllowOrigin::any()
+        } else {
+            AllowOrigin::list(self.origins.iter().cloned())
+        };
+
+        CorsLayer::new().allow_origin(origin).allow_methods([Method::GET])
+    }
+}
+
+/// Create a `Router` with all strudel routes and middleware. This is the same router
+/// used by the `strudel` binary and can be nested in other applications.
+///
+/// CORS headers are only sent for JSON routes, and for `/metrics` as well if enabled
+/// by `CorsSettings::all_routes`, when the state includes `CorsSettings`.
+pub fn router(state: Arc<RequestState>) -> Router {
+    let metrics = Router::new()
+        .route("/metrics", get(text_metrics_handler))
+        .route("/metrics/backfill", get(backfill_handler))
+        .route("/metrics/slim", get(slim_metrics_handler))
+        .route("/-/quit", post(quit_handler))
+        .route("/-/sensors", post(sensors_handler))
+        .route("/-/power-cycle", post(power_cycle_handler));
+    let json = Router::new()
+        .route("/readings", get(readings_handler))
+        .route("/ws", get(ws_handler))
+        .route("/events", get(events_handler))
+        .route("/-/check", get(check_handler))
+        .route("/api/v1/self", get(self_handler))
+        .route("/status", get(status_handler));
+
+    let routes = match &state.cors {
+        Some(cors) if cors.all_routes => metrics.merge(json).layer(cors.layer()),
+        Some(cors) => metrics.merge(json.layer(cors.layer())),
+        None => metrics.merge(json),
+    };
+
+    routes.layer(TraceLayer::new_for_http()).with_state(state)
+}
+
+/// Encode all metrics in the Prometheus text format.
+///
+/// Scrapes are abandoned with a 503 response if they would finish after the scraper gives
+/// up, based on the `X-Prometheus-Scrape-Timeout-Seconds` header less a small margin, or
+/// the configured scrape timeout when the header is missing or invalid. This includes
+/// waiting for reads of the sensor when reading on scrape.
+pub async fn text_metrics_handler(State(state): State<Arc<RequestState>>, req: HeaderMap) -> Response {
+    let deadline = scrape_deadline(&req, state.scrape_timeout);
+    let res = if deadline.is_zero() {
+        Err(())
+    } else {
+        tokio::time::timeout(deadline, encode_for_scrape(state.clone()))
+            .await
+            .map_err(|_| ())
+    };
+
+    let res = match res {
+        Ok(res) => res,
+        Err(_) => {
+            tracing::warn!(message = "abandoned scrape that would finish after its deadline", deadline = ?deadline);
+            state.metrics.deadline_exceeded();
+            let body = format!(
+                "unable to encode metrics within the scrape deadline of {:.3}s\n",
+                deadline.as_secs_f64()
+            );
+            return (StatusCode::SERVICE_UNAVAILABLE, body).into_response();
+        }
+    };
+
     let mut headers = HeaderMap::new();
+    match res {
+        Ok((generation, encoded)) => {
+            tracing::debug!(
+                message = "encoded prometheus metrics to text format",
+                bytes = encoded.text.len()
+            );
 
-    match text::encode(&mut buf, &state.registry) {
-        Ok(_) => {
-            tracing::debug!(message = "encoded prometheus metrics to text format", bytes = buf.len());
+            // Serve whatever could be encoded but let the client know it's incomplete
+            if !encoded.failed.is_empty() {
+                let failed = encoded.failed.join(",");
+                tracing::error!(message = "error encoding some metrics to text format", groups = %failed);
+                state.metrics.encode_failed(encoded.failed.len());
+                if let Ok(v) = HeaderValue::from_str(&failed) {
+                    headers.insert(ENCODE_ERRORS_HEADER, v);
+                }
+            }
+
+            // Clients that already have metrics for the current readings only need to be
+            // told that nothing has changed. The content type is included with a 304 as well.
+            let etag = state.metrics_etag(generation, &encoded);
             headers.insert(CONTENT_TYPE, HeaderValue::from_static(METRICS_TEXT));
-            (StatusCode::OK, headers, buf.into_bytes())
+            if let Ok(v) = HeaderValue::from_str(&etag) {
+                headers.insert(ETAG, v);
+            }
+
+            if if_none_match(&req, &etag) {
+                (StatusCode::NOT_MODIFIED, headers, Vec::new()).into_response()
+            } else {
+                (StatusCode::OK, headers, encoded.text.into_bytes()).into_response()
+            }
         }
         Err(e) => {
             tracing::error!(message = "error encoding metrics to text format", error = %e);
-            (StatusCode::INTERNAL_SERVER_ERROR, headers, Vec::new())
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                headers,
+                b"unable to encode any metrics\n".to_vec(),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Read sensors if reading on scrape and encode metrics on a blocking thread so that the
+/// scrape can be abandoned while encoding. Returns the generation of readings from before
+/// encoding along with the metrics.
+async fn encode_for_scrape(state: Arc<RequestState>) -> Result<(u64, Encoded), fmt::Error> {
+    if let Some(reads) = &state.scrape_reads {
+        if !reads.read().await {
+            tracing::warn!(message = "timed out reading sensor for scrape, using previous reading", timeout = ?reads.timeout);
+            state.metrics.scrape_read_timed_out();
+        }
+    }
+
+    // The registry being encoded includes the scrape metrics themselves so they have
+    // to be updated before encoding. This means the scrape counter includes the current
+    // scrape but the encode duration observed here is only visible on the next scrape.
+    // The last scrape timestamp likewise lags by one scrape: it's the time of the scrape
+    // before this one, which makes the scrape gap the time between the two.
+    state.metrics.scrape();
+    let generation = state.latest.generation();
+    let start = Instant::now();
+    let encoding = state.clone();
+    let res = tokio::task::spawn_blocking(move || encoding.registries.encode())
+        .await
+        .unwrap_or(Err(fmt::Error));
+    state.metrics.encoded(start.elapsed());
+    res.map(|encoded| (generation, encoded))
+}
+
+/// Encode only the metrics of the slim registry, temperature and humidity, in the Prometheus
+/// text format for clients that can't handle every metric. Slim scrapes read the sensor
+/// first when reading on scrape but aren't counted as scrapes by `HttpMetrics`.
+pub async fn slim_metrics_handler(State(state): State<Arc<RequestState>>) -> Response {
+    let registry = match &state.slim {
+        Some(r) => r,
+        None => return (StatusCode::NOT_FOUND, "slim metrics are not enabled\n").into_response(),
+    };
+
+    if let Some(reads) = &state.scrape_reads {
+        if !reads.read().await {
+            tracing::warn!(message = "timed out reading sensor for slim scrape, using previous reading", timeout = ?reads.timeout);
+            state.metrics.scrape_read_timed_out();
+        }
+    }
+
+    let mut buf = String::new();
+    match text::encode(&mut buf, registry) {
+        Ok(_) => {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static(METRICS_TEXT));
+            (StatusCode::OK, headers, buf.into_bytes()).into_response()
+        }
+        Err(e) => {
+            tracing::error!(message = "error encoding slim metrics to text format", error = %e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                b"unable to encode any metrics\n".to_vec(),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Time a scrape can take before the scraper gives up, based on the scrape timeout it
+/// sent in `req` less a margin for the response to reach it, or `fallback` if it didn't
+/// send a valid one.
+fn scrape_deadline(req: &HeaderMap, fallback: Duration) -> Duration {
+    req.get(SCRAPE_TIMEOUT_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<f64>().ok())
+        .filter(|t| t.is_finite() && *t > 0.0)
+        .and_then(|t| Duration::try_from_secs_f64(t).ok())
+        .map(|t| t.saturating_sub(SCRAPE_TIMEOUT_MARGIN))
+        .unwrap_or(fallback)
+}
+
+/// Encode metrics the same way as `text_metrics_handler` and validate the result, returning
+/// the number of families and samples as JSON or a 500 response with the first error found
+/// and the line it's on. Checks aren't counted as scrapes by `HttpMetrics`.
+pub async fn check_handler(State(state): State<Arc<RequestState>>) -> Response {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+    let encoded = match state.registries.encode() {
+        Ok(encoded) => encoded,
+        Err(e) => {
+            tracing::error!(message = "error encoding metrics to text format for check", error = %e);
+            let body = serde_json::json!({"error": "unable to encode any metrics"});
+            return (StatusCode::INTERNAL_SERVER_ERROR, headers, body.to_string()).into_response();
+        }
+    };
+
+    if !encoded.failed.is_empty() {
+        if let Ok(v) = HeaderValue::from_str(&encoded.failed.join(",")) {
+            headers.insert(ENCODE_ERRORS_HEADER, v);
+        }
+    }
+
+    match exposition::validate(&encoded.text) {
+        Ok(summary) => (StatusCode::OK, headers, serde_json::to_string(&summary).unwrap()).into_response(),
+        Err(e) => {
+            tracing::error!(message = "encoded metrics are not valid", error = %e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                headers,
+                serde_json::to_string(&e).unwrap(),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Query parameters for `backfill_handler`
+#[derive(Debug, Default, Deserialize)]
+pub struct BackfillQuery {
+    /// Only include readings taken at or after this UNIX timestamp.
+    pub since: Option<f64>,
+    /// Value of the `job` label of every sample, if set.
+    pub job: Option<String>,
+    /// Value of the `instance` label of every sample, if set.
+    pub instance: Option<String>,
+}
+
+/// Return readings kept in memory as OpenMetrics samples with timestamps, for backfilling
+/// scrapes that were missed with `promtool tsdb create-blocks-from openmetrics`, see
+/// `exposition::backfill`. Returns 404 if readings aren't kept and 400 if `since` isn't
+/// a valid timestamp. When readings were left out to stay under the size limit, the
+/// `X-Strudel-Backfill-Next` header is the `since` to use to get the rest.
+pub async fn backfill_handler(State(state): State<Arc<RequestState>>, Query(query): Query<BackfillQuery>) -> Response {
+    let source = match &state.backfill {
+        Some(s) => s,
+        None => return (StatusCode::NOT_FOUND, "readings are not being kept for backfilling\n").into_response(),
+    };
+
+    let since = match query.since.map(Duration::try_from_secs_f64) {
+        Some(Ok(d)) => UNIX_EPOCH + d,
+        Some(Err(_)) => return (StatusCode::BAD_REQUEST, "invalid 'since' timestamp\n").into_response(),
+        None => UNIX_EPOCH,
+    };
+
+    let labels: Vec<(&str, &str)> = [("job", &query.job), ("instance", &query.instance)]
+        .into_iter()
+        .filter_map(|(name, value)| value.as_deref().map(|v| (name, v)))
+        .collect();
+    let res = exposition::backfill(&source.history.since(since), source.unit, &labels, source.max_bytes);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(METRICS_TEXT));
+    if let Some(next) = res.next.and_then(|t| t.duration_since(UNIX_EPOCH).ok()) {
+        if let Ok(v) = HeaderValue::from_str(&format!("{:.3}", next.as_secs_f64())) {
+            headers.insert(BACKFILL_NEXT_HEADER, v);
+        }
+    }
+
+    (StatusCode::OK, headers, res.text).into_response()
+}
+
+/// Request a graceful shutdown of the server, the same as `SIGTERM`, if enabled by
+/// `RequestStateBuilder::lifecycle`. The shutdown is triggered after a short delay so
+/// that the response can be sent first. Returns 403 if not enabled.
+pub async fn quit_handler(State(state): State<Arc<RequestState>>, req: HeaderMap) -> Response {
+    if state.lifecycle.is_some() && !authorized(&state, &req) {
+        return unauthorized();
+    }
+
+    match state.lifecycle.clone() {
+        Some(shutdown) => {
+            tracing::info!("shutdown requested via lifecycle endpoint");
+            tokio::spawn(async move {
+                tokio::time::sleep(QUIT_DELAY).await;
+                shutdown.trigger();
+            });
+
+            (StatusCode::OK, "requesting shutdown\n").into_response()
+        }
+        None => (StatusCode::FORBIDDEN, "lifecycle endpoints are not enabled\n").into_response(),
+    }
+}
+
+/// Replace the sensor with one opened from the JSON `SensorSpec` in the body of the
+/// request, if enabled by `RequestStateBuilder::lifecycle` and `RequestStateBuilder::sensors`
+/// and a lifecycle token is set. Returns 403 if not enabled, 401 without the token, 409
+/// if a replacement is already in progress, 400 if the spec is invalid, and 500 if the
+/// new sensor can't be opened in which case reads fail until it's replaced again.
+pub async fn sensors_handler(State(state): State<Arc<RequestState>>, req: HeaderMap, body: Bytes) -> Response {
+    let sensors = match (&state.lifecycle, &state.lifecycle_token, &state.sensors) {
+        (None, _, _) => return (StatusCode::FORBIDDEN, "lifecycle endpoints are not enabled\n").into_response(),
+        (Some(_), Some(_), Some(sensors)) => sensors,
+        _ => return (StatusCode::FORBIDDEN, "replacing sensors is not enabled\n").into_response(),
+    };
+
+    if !authorized(&state, &req) {
+        return unauthorized();
+    }
+
+    // Held until the replacement is done so that a second request can't close the
+    // sensor the first one is still opening.
+    let _guard = match sensors.replacing.try_lock() {
+        Ok(g) => g,
+        Err(_) => return (StatusCode::CONFLICT, "sensor replacement already in progress\n").into_response(),
+    };
+
+    let spec: SensorSpec = match serde_json::from_slice(&body) {
+        Ok(spec) => spec,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("invalid sensor spec: {}\n", e)).into_response(),
+    };
+
+    let pending = match sensors.manager.replace(spec.clone()) {
+        Ok(pending) => pending,
+        Err(errors) => return (StatusCode::BAD_REQUEST, format!("{}\n", errors.join("\n"))).into_response(),
+    };
+
+    match pending.await {
+        Ok(Ok(())) => {
+            tracing::info!(message = "sensor replaced via lifecycle endpoint", bcm_pin = spec.pin, sensor = ?spec.name);
+            state.metrics.reconfigured();
+            (StatusCode::OK, "sensor replaced\n").into_response()
+        }
+        Ok(Err(e)) => {
+            tracing::error!(message = "unable to open replacement sensor", bcm_pin = spec.pin, error = %e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("unable to open replacement sensor: {}\n", e),
+            )
+                .into_response()
+        }
+        Err(_) => (StatusCode::SERVICE_UNAVAILABLE, "sensor is no longer being read\n").into_response(),
+    }
+}
+
+/// Cut power to the sensor and restore it, ignoring the cooldown between automatic power
+/// cycles, if enabled by `RequestStateBuilder::lifecycle` and `RequestStateBuilder::power`.
+/// Responds once power is restored. Returns 403 if not enabled, 401 without the lifecycle
+/// token when one is set, and 409 if a power cycle is already in progress.
+pub async fn power_cycle_handler(State(state): State<Arc<RequestState>>, req: HeaderMap) -> Response {
+    let power = match (&state.lifecycle, &state.power) {
+        (None, _) => return (StatusCode::FORBIDDEN, "lifecycle endpoints are not enabled\n").into_response(),
+        (Some(_), None) => return (StatusCode::FORBIDDEN, "power cycling is not enabled\n").into_response(),
+        (Some(_), Some(power)) => power,
+    };
+
+    if !authorized(&state, &req) {
+        return unauthorized();
+    }
+
+    match power.cycle(PowerCycleReason::Requested).await {
+        Ok(()) => (StatusCode::OK, "sensor power cycled\n").into_response(),
+        Err(e @ PowerCycleSkipped::InProgress) => (StatusCode::CONFLICT, format!("{}\n", e)).into_response(),
+        Err(e) => (StatusCode::SERVICE_UNAVAILABLE, format!("{}\n", e)).into_response(),
+    }
+}
+
+/// True if the request has the lifecycle token as a bearer token or no token is required.
+fn authorized(state: &RequestState, req: &HeaderMap) -> bool {
+    let expected = match &state.lifecycle_token {
+        Some(t) => t,
+        None => return true,
+    };
+
+    req.get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|t| constant_time_eq(t.as_bytes(), expected.as_bytes()))
+        .unwrap_or(false)
+}
+
+/// Compare tokens without returning early at the first difference so that the time taken
+/// doesn't reveal how much of a token is correct.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(WWW_AUTHENTICATE, "Bearer")],
+        "missing or invalid lifecycle token\n",
+    )
+        .into_response()
+}
+
+/// Fields of readings returned by `readings_handler`, in order.
+pub const READING_FIELDS: &[&str] = &["sensor", "temperature", "humidity", "read_at", "restored"];
+
+/// Query parameters for `readings_handler`
+#[derive(Debug, Default, Deserialize)]
+pub struct ReadingsQuery {
+    pub sensor: Option<String>,
+    #[serde(flatten)]
+    pub format: JsonFormat,
+}
+
+/// Query parameters controlling how JSON responses are formatted, shared by handlers
+/// that return JSON.
+///
+/// * `pretty` - Pretty-print the response when `1` or `true`, or when given without a value.
+/// * `fields` - Comma separated names of fields to include in each object of the response.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct JsonFormat {
+    pub pretty: Option<String>,
+    pub fields: Option<String>,
+}
+
+/// Error formatting a JSON response because of unknown field names in the `fields`
+/// query parameter.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UnknownFields {
+    pub error: String,
+    pub unknown: Vec<String>,
+    pub valid: Vec<String>,
+}
+
+/// Serialize `value` as JSON based on the `pretty` and `fields` query parameters in
+/// `format`. Objects in `value`, or in an array if `value` is one, only include the
+/// selected fields, or all fields if none are selected. Fields must be included in `valid`, otherwise an error listing the
+/// unknown and valid field names is returned.
+pub fn format_json<T: Serialize>(value: &T, format: &JsonFormat, valid: &[&str]) -> Result<String, UnknownFields> {
+    let fields: Option<Vec<&str>> = format
+        .fields
+        .as_deref()
+        .map(|f| {
+            f.split(',')
+                .map(str::trim)
+                .filter(|f| !f.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .filter(|f| !f.is_empty());
+
+    let mut value = serde_json::to_value(value).expect("values returned by handlers must be serializable");
+    if let Some(fields) = fields {
+        let unknown: Vec<String> = fields
+            .iter()
+            .filter(|f| !valid.contains(f))
+            .map(|f| f.to_string())
+            .collect();
+
+        if !unknown.is_empty() {
+            return Err(UnknownFields {
+                error: format!("unknown fields: {}", unknown.join(",")),
+                unknown,
+                valid: valid.iter().map(|f| f.to_string()).collect(),
+            });
+        }
+
+        select_fields(&mut value, &fields);
+    }
+
+    let pretty = matches!(format.pretty.as_deref(), Some("" | "1" | "true"));
+    Ok(if pretty {
+        serde_json::to_string_pretty(&value)
+    } else {
+        serde_json::to_string(&value)
+    }
+    .expect("JSON values can always be serialized"))
+}
+
+fn select_fields(value: &mut Value, fields: &[&str]) {
+    match value {
+        Value::Object(m) => m.retain(|k, _| fields.contains(&k.as_str())),
+        Value::Array(a) => a.iter_mut().for_each(|v| select_fields(v, fields)),
+        _ => {}
+    }
+}
+
+/// Create a JSON response for `value` formatted by `format_json`, or a 400 response with
+/// a JSON error body if any of the selected fields are unknown.
+fn json_response<T: Serialize>(value: &T, format: &JsonFormat, valid: &[&str]) -> Response {
+    let (status, body) = match format_json(value, format, valid) {
+        Ok(body) => (StatusCode::OK, body),
+        Err(e) => (StatusCode::BAD_REQUEST, serde_json::to_string(&e).unwrap()),
+    };
+
+    (
+        status,
+        [(CONTENT_TYPE, HeaderValue::from_static("application/json"))],
+        body,
+    )
+        .into_response()
+}
+
+/// Return the most recent reading of each sensor as JSON.
+///
+/// When the state is configured for a single sensor, see `RequestStateBuilder::single_sensor`,
+/// its reading is returned as a single object, or 404 if it hasn't been read yet. Otherwise,
+/// readings are returned as an array of objects that include the name of each sensor,
+/// ordered by name. Readings of a single sensor can be selected using the `sensor` query
+/// parameter, returning 404 if it hasn't been read. The response can be formatted using
+/// the query parameters of `JsonFormat`.
+pub async fn readings_handler(State(state): State<Arc<RequestState>>, Query(query): Query<ReadingsQuery>) -> Response {
+    let format = &query.format;
+    if let Some(name) = query.sensor.as_deref() {
+        return match state.latest.get_named(name) {
+            Some(r) => json_response(&r.named(Some(name)), format, READING_FIELDS),
+            None => (StatusCode::NOT_FOUND, format!("no readings for sensor '{}'\n", name)).into_response(),
+        };
+    }
+
+    // The only sensor may have been replaced by one with a name, it's still the only one
+    if state.single_sensor {
+        return match state.latest.newest() {
+            Some(r) => json_response(&r, format, READING_FIELDS),
+            None => (StatusCode::NOT_FOUND, "no readings yet\n").into_response(),
+        };
+    }
+
+    let all = state.latest.all();
+    json_response(
+        &all.iter().map(|(k, r)| r.named(k.as_deref())).collect::<Vec<_>>(),
+        format,
+        READING_FIELDS,
+    )
+}
+
+/// Query parameters for `ws_handler`
+#[derive(Debug, Default, Deserialize)]
+pub struct LiveQuery {
+    /// Push failed reads as well when `1` or `true`, or when given without a value.
+    pub errors: Option<String>,
+}
+
+/// A read of the sensor pushed to clients by `ws_handler` and `events_handler`.
+#[derive(Debug, Clone)]
+enum LiveEvent {
+    Reading(LatestReading),
+    Error(FailedRead),
+}
+
+impl LiveEvent {
+    fn from_event(event: &ReadingEvent) -> Self {
+        match &event.result {
+            Ok(m) => LiveEvent::Reading(LatestReading::new(*m, event.timestamp, event.instant)),
+            Err(e) => LiveEvent::Error(FailedRead {
+                kind: e.kind().as_label(),
+                error: e.to_string(),
+                at: event
+                    .timestamp
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs_f64())
+                    .unwrap_or(0.0),
+            }),
+        }
+    }
+}
+
+/// A failed read of the sensor, with the time it finished as a UNIX timestamp.
+#[derive(Debug, Clone, Serialize)]
+struct FailedRead {
+    kind: &'static str,
+    error: String,
+    at: f64,
+}
+
+/// Message pushed to WebSocket clients by `ws_handler`, serialized as JSON with its kind
+/// in the `type` field.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum LiveMessage<'a> {
+    /// Most recent reading of each sensor when the client connected, formatted the same
+    /// as the readings of `readings_handler`.
+    Snapshot { readings: Vec<NamedReading<'a>> },
+    /// A successful read of the sensor.
+    Reading(LatestReading),
+    /// A failed read of the sensor, only sent when requested.
+    Error(FailedRead),
+}
+
+impl From<LiveEvent> for LiveMessage<'static> {
+    fn from(event: LiveEvent) -> Self {
+        match event {
+            LiveEvent::Reading(r) => LiveMessage::Reading(r),
+            LiveEvent::Error(e) => LiveMessage::Error(e),
         }
     }
 }
+
+/// Push readings to WebSocket clients as they happen, if enabled by
+/// `RequestStateBuilder::live`.
+///
+/// Clients are sent a `snapshot` message with the most recent reading of each sensor
+/// when they connect, followed by a `reading` message for each successful read of the
+/// sensor, and an `error` message for each failed read if the `errors` query parameter
+/// is set. Returns 404 if not enabled and 503 if the maximum number of clients are
+/// already connected.
+pub async fn ws_handler(
+    State(state): State<Arc<RequestState>>,
+    Query(query): Query<LiveQuery>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let live = match &state.live {
+        Some(l) => l,
+        None => return (StatusCode::NOT_FOUND, "live readings are not enabled\n").into_response(),
+    };
+
+    let permit = match live.connections.clone().try_acquire_owned() {
+        Ok(p) => p,
+        Err(_) => return (StatusCode::SERVICE_UNAVAILABLE, "too many live reading clients\n").into_response(),
+    };
+
+    let errors = matches!(query.errors.as_deref(), Some("" | "1" | "true"));
+    let events = live.events.clone();
+    let timeout = live.send_timeout;
+    let latest = state.latest.clone();
+
+    ws.on_upgrade(move |socket| async move {
+        push_readings(socket, &latest, events, errors, timeout).await;
+        drop(permit);
+    })
+}
+
+async fn push_readings(
+    mut socket: WebSocket,
+    latest: &LatestReadingCell,
+    mut events: watch::Receiver<Option<Arc<ReadingEvent>>>,
+    errors: bool,
+    timeout: Duration,
+) {
+    // Anything that happened before connecting is covered by the snapshot
+    events.borrow_and_update();
+    let all = latest.all();
+    let snapshot = LiveMessage::Snapshot {
+        readings: all.iter().map(|(k, r)| r.named(k.as_deref())).collect(),
+    };
+    if !send_live(&mut socket, &snapshot, timeout).await {
+        return;
+    }
+
+    loop {
+        tokio::select! {
+            res = events.changed() => {
+                // The worker has stopped, there won't be any more events
+                if res.is_err() {
+                    break;
+                }
+
+                let msg = events
+                    .borrow_and_update()
+                    .as_deref()
+                    .map(LiveEvent::from_event)
+                    .filter(|e| errors || matches!(e, LiveEvent::Reading(_)))
+                    .map(LiveMessage::from);
+                if let Some(msg) = msg {
+                    if !send_live(&mut socket, &msg, timeout).await {
+                        break;
+                    }
+                }
+            }
+            msg = socket.recv() => {
+                // Messages from clients are ignored, they only need to be read to notice
+                // when the client disconnects and to answer pings.
+                match msg {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                }
+            }
+        }
+    }
+}
+
+/// Send `msg` as JSON, returning false if the client is gone or didn't accept it in time.
+async fn send_live(socket: &mut WebSocket, msg: &LiveMessage<'_>, timeout: Duration) -> bool {
+    let text = serde_json::to_string(msg).expect("live messages can always be serialized");
+    match tokio::time::timeout(timeout, socket.send(Message::Text(text))).await {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            tracing::debug!(message = "live readings client disconnected", error = %e);
+            false
+        }
+        Err(_) => {
+            tracing::warn!(message = "disconnecting live readings client that stopped accepting messages");
+            false
+        }
+    }
+}
+
+/// Stream reads of the sensor as Server-Sent Events, if enabled by
+/// `RequestStateBuilder::events`.
+///
+/// Each successful read is a `reading` event with the reading as JSON data, formatted the
+/// same as by `readings_handler`, and each failed read is an `error` event. A comment is
+/// sent every 15 seconds without events to keep the connection open. Clients that send
+/// a `Last-Event-ID` header are first sent the events after it that are still kept in
+/// the `EventLog`, IDs from before strudel restarted are ignored. Returns 404 if not
+/// enabled.
+pub async fn events_handler(State(state): State<Arc<RequestState>>, req: HeaderMap) -> Response {
+    let log = match &state.events {
+        Some(l) => l.clone(),
+        None => return (StatusCode::NOT_FOUND, "events are not enabled\n").into_response(),
+    };
+
+    let last_event_id = req
+        .get(LAST_EVENT_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok());
+
+    Sse::new(event_stream(log, last_event_id))
+        .keep_alive(KeepAlive::new().interval(EVENTS_KEEP_ALIVE))
+        .into_response()
+}
+
+fn event_stream(log: Arc<EventLog>, last_event_id: Option<u64>) -> impl Stream<Item = Result<Event, Infallible>> {
+    // Subscribe before finding the latest event so that nothing is missed in between
+    let mut changes = log.changes.subscribe();
+    changes.borrow_and_update();
+    let latest = log.last_id();
+    let last = last_event_id.filter(|id| *id <= latest).unwrap_or(latest);
+    let pending = log.after(last);
+
+    stream::unfold(
+        (log, changes, pending, last),
+        |(log, mut changes, mut pending, mut last)| async move {
+            loop {
+                if log.is_closed() {
+                    return None;
+                }
+
+                if let Some((id, event)) = pending.pop_front() {
+                    last = id;
+                    return Some((Ok(sse_event(id, &event)), (log, changes, pending, last)));
+                }
+
+                if changes.changed().await.is_err() {
+                    return None;
+                }
+
+                pending = log.after(last);
+            }
+        },
+    )
+}
+
+fn sse_event(id: u64, event: &LiveEvent) -> Event {
+    let (name, data) = match event {
+        LiveEvent::Reading(r) => ("reading", serde_json::to_string(r)),
+        LiveEvent::Error(e) => ("error", serde_json::to_string(e)),
+    };
+
+    Event::default()
+        .id(id.to_string())
+        .event(name)
+        .data(data.expect("live events can always be serialized"))
+}
+
+/// Configuration of this instance of strudel returned by `self_handler`, set once it
+/// has been validated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfDescription {
+    /// Identifier of this instance, see `identity::instance_id`.
+    pub instance_id: String,
+    /// Address metrics are served on, `host:port`, used as the target when requests
+    /// don't include a `Host` header.
+    pub address: Option<String>,
+    /// Sensors read at startup, used unless sensors can be replaced with `SensorManager`.
+    pub sensors: Vec<SensorSpec>,
+    /// Labels configured for this instance, like Pushgateway grouping labels.
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Fields of the native response of `self_handler`, in order.
+pub const SELF_FIELDS: &[&str] = &["instance_id", "version", "sensors", "labels", "endpoints"];
+
+/// Fields of the `prom_sd` response of `self_handler`, in order.
+pub const TARGET_GROUP_FIELDS: &[&str] = &["targets", "labels"];
+
+/// Query parameters for `self_handler`
+#[derive(Debug, Default, Deserialize)]
+pub struct SelfQuery {
+    pub format: Option<String>,
+    #[serde(flatten)]
+    pub json: JsonFormat,
+}
+
+/// Native response of `self_handler`
+#[derive(Debug, Serialize)]
+struct SelfResponse<'a> {
+    instance_id: &'a str,
+    version: &'a str,
+    sensors: Vec<SensorSpec>,
+    labels: &'a BTreeMap<String, String>,
+    endpoints: Vec<&'static str>,
+}
+
+/// Target group in the format used by Prometheus HTTP service discovery
+#[derive(Debug, Serialize)]
+struct TargetGroup {
+    targets: Vec<String>,
+    labels: BTreeMap<String, String>,
+}
+
+/// Describe this instance of strudel as JSON: its instance ID, version, sensors, configured
+/// labels, and the endpoints it serves. Responds with 404 if there's no `SelfDescription`.
+///
+/// With `format=prom_sd`, a single target group for Prometheus HTTP service discovery is
+/// returned instead, to be collected into the list that Prometheus expects. The target is
+/// the `Host` of the request, or the configured address if there isn't one. Its labels are
+/// the configured labels along with `__metrics_path__` and `__meta_strudel_*` labels for
+/// the instance ID, version, and sensors that can be used when relabeling. The response
+/// can be formatted using the query parameters of `JsonFormat`.
+pub async fn self_handler(
+    State(state): State<Arc<RequestState>>,
+    Query(query): Query<SelfQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let description = match &state.description {
+        Some(d) => d,
+        None => return (StatusCode::NOT_FOUND, "no description of this instance\n").into_response(),
+    };
+
+    let sensors = match &state.sensors {
+        Some(s) => s.manager.sensors(),
+        None => description.sensors.clone(),
+    };
+
+    match query.format.as_deref() {
+        None | Some("native") => {
+            let mut endpoints = vec!["/metrics", "/readings", "/-/check", "/api/v1/self"];
+            if state.status.is_some() {
+                endpoints.push("/status");
+            }
+            if state.backfill.is_some() {
+                endpoints.push("/metrics/backfill");
+            }
+            if state.slim.is_some() {
+                endpoints.push("/metrics/slim");
+            }
+            if state.live.is_some() {
+                endpoints.push("/ws");
+            }
+            if state.events.is_some() {
+                endpoints.push("/events");
+            }
+            if state.lifecycle.is_some() {
+                endpoints.extend(["/-/quit", "/-/sensors"]);
+                if state.power.is_some() {
+                    endpoints.push("/-/power-cycle");
+                }
+            }
+
+            let res = SelfResponse {
+                instance_id: &description.instance_id,
+                version: VERSION,
+                sensors,
+                labels: &description.labels,
+                endpoints,
+            };
+            json_response(&res, &query.json, SELF_FIELDS)
+        }
+        Some("prom_sd") => {
+            let target = headers
+                .get(HOST)
+                .and_then(|h| h.to_str().ok())
+                .map(|h| h.to_owned())
+                .or_else(|| description.address.clone());
+
+            let mut labels = description.labels.clone();
+            labels.insert("__metrics_path__".to_owned(), "/metrics".to_owned());
+            labels.insert("__meta_strudel_instance_id".to_owned(), description.instance_id.clone());
+            labels.insert("__meta_strudel_version".to_owned(), VERSION.to_owned());
+            labels.insert(
+                "__meta_strudel_sensors".to_owned(),
+                sensors
+                    .iter()
+                    .map(|s| match &s.name {
+                        Some(name) => format!("{}:{}:{}", name, s.kind, s.pin),
+                        None => format!("{}:{}", s.kind, s.pin),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(","),
+            );
+
+            let res = TargetGroup {
+                targets: target.into_iter().collect(),
+                labels,
+            };
+            json_response(&res, &query.json, TARGET_GROUP_FIELDS)
+        }
+        Some(other) => (
+            StatusCode::BAD_REQUEST,
+            format!("unknown format '{}', expected 'native' or 'prom_sd'\n", other),
+        )
+            .into_response(),
+    }
+}
+
+/// Return the status of this instance, usually its configuration and the result of
+/// reading the sensor at startup, as JSON. Any top-level field of the status can be
+/// selected using the query parameters of `JsonFormat`. Responds with 404 if there's no
+/// status.
+pub async fn status_handler(State(state): State<Arc<RequestState>>, Query(format): Query<JsonFormat>) -> Response {
+    let status = match &state.status {
+        Some(s) => s,
+        None => return (StatusCode::NOT_FOUND, "no status of this instance\n").into_response(),
+    };
+
+    let fields: Vec<&str> = status
+        .as_object()
+        .map(|o| o.keys().map(|k| k.as_str()).collect())
+        .unwrap_or_default();
+    json_response(status, &format, &fields)
+}
+
+/// Strong entity tag for a generation of readings and the metrics about them. The hash is
+/// only used to tell if they've changed so it doesn't need to be stable across versions
+/// of strudel.
+fn etag(generation: u64, readings: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    generation.hash(&mut hasher);
+    readings.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+/// Return true if any entity tag in the `If-None-Match` headers of a request matches `etag`,
+/// using the weak comparison that RFC 9110 requires for `If-None-Match`.
+fn if_none_match(req: &HeaderMap, etag: &str) -> bool {
+    req.get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|t| t.trim())
+        .any(|t| t == "*" || t.strip_prefix("W/").unwrap_or(t) == etag)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        format_json, readings_handler, router, scrape_deadline, text_metrics_handler, BackfillSource, CorsSettings,
+        EventLog, JsonFormat, LiveReadings, ReadingsQuery, RequestState, ScrapeReads, SelfDescription, SensorManager,
+        Shutdown, BACKFILL_NEXT_HEADER, DEFAULT_SCRAPE_TIMEOUT, ENCODE_ERRORS_HEADER, METRICS_TEXT, READING_FIELDS,
+        SCRAPE_TIMEOUT_HEADER,
+    };
+    use crate::metrics::{HttpMetrics, PowerMetrics, Registries, TemperatureMetrics};
+    use crate::sensor::test::NopDataPin;
+    use crate::sensor::{
+        Calibration, Clamped, DataPin, Humidity, LatestReading, LatestReadingCell, Measurement, PowerController,
+        ReadingEvent, ReadingHistory, Sensor, SensorError, SensorErrorKind, SensorSpec, SensorSwap, SensorSwapper,
+        SensorWorker, TemperatureCelsius, TemperatureUnit, WorkerHandle,
+    };
+    use crate::version::VERSION;
+    use axum::body::{Body, BoxBody};
+    use axum::extract::{Query, State};
+    use axum::http::header::{
+        ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_REQUEST_METHOD, AUTHORIZATION,
+        CONTENT_TYPE, ETAG, HOST, IF_NONE_MATCH, ORIGIN, WWW_AUTHENTICATE,
+    };
+    use axum::http::{HeaderMap, HeaderValue, Request, StatusCode};
+    use axum::response::IntoResponse;
+    use hyper::body::HttpBody;
+    use prometheus_client::encoding::{EncodeMetric, MetricEncoder};
+    use prometheus_client::metrics::gauge::Gauge;
+    use prometheus_client::metrics::MetricType;
+    use prometheus_client::registry::Registry;
+    use std::collections::BTreeMap;
+    use std::fmt;
+    use std::net::TcpListener;
+    use std::sync::atomic::AtomicU64;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+    use tokio::net::TcpStream;
+    use tokio::sync::{oneshot, watch};
+    use tokio_stream::StreamExt;
+    use tokio_tungstenite::tungstenite::{self, Message};
+    use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+    use tower::ServiceExt;
+    use tracing::Span;
+
+    /// Metric that always fails to encode
+    #[derive(Debug)]
+    struct BrokenMetric;
+
+    impl EncodeMetric for BrokenMetric {
+        fn encode(&self, _encoder: MetricEncoder) -> Result<(), fmt::Error> {
+            Err(fmt::Error)
+        }
+
+        fn metric_type(&self) -> MetricType {
+            MetricType::Gauge
+        }
+    }
+
+    async fn scrape(state: Arc<RequestState>) -> String {
+        let res = text_metrics_handler(State(state), HeaderMap::new())
+            .await
+            .into_response();
+        assert_eq!(StatusCode::OK, res.status());
+
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_text_metrics_handler_scrapes() {
+        let mut registries = Registries::new();
+        let metrics = HttpMetrics::new(registries.group("http"));
+        let state = Arc::new(RequestState {
+            registries,
+            metrics,
+            latest: Arc::new(LatestReadingCell::new()),
+            single_sensor: false,
+            cors: None,
+            lifecycle: None,
+            lifecycle_token: None,
+            sensors: None,
+            power: None,
+            backfill: None,
+            slim: None,
+            live: None,
+            events: None,
+            scrape_reads: None,
+            description: None,
+            status: None,
+            scrape_timeout: DEFAULT_SCRAPE_TIMEOUT,
+            etag: Mutex::new(None),
+        });
+
+        let first = scrape(state.clone()).await;
+        assert!(first.contains("strudel_scrapes_total 1\n"));
+        assert!(first.contains("strudel_scrape_encode_duration_seconds_count 0\n"));
+
+        let second = scrape(state.clone()).await;
+        assert!(second.contains("strudel_scrapes_total 2\n"));
+        assert!(second.contains("strudel_scrape_encode_duration_seconds_count 1\n"));
+    }
+
+    #[test]
+    fn test_scrape_deadline() {
+        let fallback = Duration::from_secs(10);
+        let deadline = |v: &'static str| {
+            let mut req = HeaderMap::new();
+            req.insert(SCRAPE_TIMEOUT_HEADER, HeaderValue::from_static(v));
+            scrape_deadline(&req, fallback)
+        };
+
+        assert_eq!(fallback, scrape_deadline(&HeaderMap::new(), fallback));
+        assert_eq!(Duration::from_millis(4750), deadline("5"));
+        assert_eq!(Duration::from_millis(1250), deadline(" 1.5 "));
+        assert_eq!(Duration::ZERO, deadline("0.0001"));
+
+        for invalid in ["", "soon", "0", "-5", "NaN", "inf", "1e300"] {
+            assert_eq!(fallback, deadline(invalid), "header {:?}", invalid);
+        }
+    }
+
+    async fn scrape_with_timeout(state: Arc<RequestState>, timeout: Option<&'static str>) -> (StatusCode, String) {
+        let mut req = HeaderMap::new();
+        if let Some(t) = timeout {
+            req.insert(SCRAPE_TIMEOUT_HEADER, HeaderValue::from_static(t));
+        }
+
+        let res = text_metrics_handler(State(state), req).await;
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_text_metrics_handler_scrape_timeout() {
+        let state = state();
+
+        // Absent and present but generous timeouts leave plenty of time to encode
+        let (status, _) = scrape_with_timeout(state.clone(), None).await;
+        assert_eq!(StatusCode::OK, status);
+        let (status, _) = scrape_with_timeout(state.clone(), Some("10")).await;
+        assert_eq!(StatusCode::OK, status);
+
+        // Nothing can be done before an absurdly short timeout
+        let (status, body) = scrape_with_timeout(state.clone(), Some("0.0001")).await;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status);
+        assert_eq!("unable to encode metrics within the scrape deadline of 0.000s\n", body);
+
+        let (status, body) = scrape_with_timeout(state, Some("not a number")).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(1.0, metric_value(&body, "strudel_scrape_deadline_exceeded_total"));
+        assert_eq!(3.0, metric_value(&body, "strudel_scrapes_total"));
+    }
+
+    /// Parse the value of the series `name`, including any labels, from text exposition format.
+    fn metric_value(body: &str, name: &str) -> f64 {
+        let prefix = format!("{} ", name);
+        body.lines()
+            .find_map(|l| l.strip_prefix(&prefix))
+            .unwrap_or_else(|| panic!("metric {} not found in {}", name, body))
+            .parse()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_text_metrics_handler_last_scrape() {
+        let mut registries = Registries::new();
+        let metrics = HttpMetrics::new(registries.group("http"));
+        let state = Arc::new(RequestState {
+            registries,
+            metrics,
+            latest: Arc::new(LatestReadingCell::new()),
+            single_sensor: false,
+            cors: None,
+            lifecycle: None,
+            lifecycle_token: None,
+            sensors: None,
+            power: None,
+            backfill: None,
+            slim: None,
+            live: None,
+            events: None,
+            scrape_reads: None,
+            description: None,
+            status: None,
+            scrape_timeout: DEFAULT_SCRAPE_TIMEOUT,
+            etag: Mutex::new(None),
+        });
+
+        // Nothing has been scraped before the first scrape
+        let first = scrape(state.clone()).await;
+        assert_eq!(0.0, metric_value(&first, "strudel_last_scrape_timestamp"));
+        assert_eq!(0.0, metric_value(&first, "strudel_scrape_gap_seconds"));
+        let first_at = state
+            .metrics
+            .latest_scrape()
+            .unwrap()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+
+        // The timestamp lags by one scrape so the second scrape reports the first
+        let second = scrape(state.clone()).await;
+        assert_eq!(first_at, metric_value(&second, "strudel_last_scrape_timestamp"));
+        assert!(metric_value(&second, "strudel_scrape_gap_seconds") >= 0.0);
+    }
+
+    /// State with metrics about readings that don't change between scrapes, along with
+    /// metrics about scrapes that do
+    fn unchanging_state() -> Arc<RequestState> {
+        let mut registries = Registries::new();
+        registries
+            .group("sensor")
+            .register("working", "Always works", Gauge::<i64>::default());
+
+        Arc::new(RequestState::builder(registries, Arc::new(LatestReadingCell::new())).build())
+    }
+
+    async fn conditional_scrape(
+        state: Arc<RequestState>,
+        if_none_match: Option<&str>,
+    ) -> (StatusCode, HeaderMap, Vec<u8>) {
+        let mut req = HeaderMap::new();
+        if let Some(v) = if_none_match {
+            req.insert(IF_NONE_MATCH, HeaderValue::from_str(v).unwrap());
+        }
+
+        let res = text_metrics_handler(State(state), req).await.into_response();
+        let status = res.status();
+        let headers = res.headers().clone();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        (status, headers, body.to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_text_metrics_handler_etag_missing_header() {
+        let (status, headers, body) = conditional_scrape(unchanging_state(), None).await;
+
+        assert_eq!(StatusCode::OK, status);
+        assert!(headers.get(ETAG).is_some());
+        assert!(!body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_text_metrics_handler_etag_match() {
+        let state = unchanging_state();
+        let (_, headers, _) = conditional_scrape(state.clone(), None).await;
+        let etag = headers.get(ETAG).unwrap().to_str().unwrap().to_owned();
+
+        let (status, headers, body) = conditional_scrape(state.clone(), Some(&etag)).await;
+        assert_eq!(StatusCode::NOT_MODIFIED, status);
+        assert_eq!(etag, headers.get(ETAG).unwrap().to_str().unwrap());
+        assert_eq!(METRICS_TEXT, headers.get(CONTENT_TYPE).unwrap());
+        assert!(body.is_empty());
+
+        // Any of multiple tags may match and weak tags match too
+        let tags = format!("\"abc\", W/{}", etag);
+        let (status, _, body) = conditional_scrape(state, Some(&tags)).await;
+        assert_eq!(StatusCode::NOT_MODIFIED, status);
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_text_metrics_handler_etag_mismatch() {
+        let (status, headers, body) = conditional_scrape(unchanging_state(), Some("\"abc\"")).await;
+
+        assert_eq!(StatusCode::OK, status);
+        assert_ne!("\"abc\"", headers.get(ETAG).unwrap());
+        assert!(!body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_text_metrics_handler_etag_ignores_scrapes() {
+        let state = unchanging_state();
+        let (_, _, first) = conditional_scrape(state.clone(), None).await;
+        let (_, _, second) = conditional_scrape(state.clone(), None).await;
+
+        // The scrape counter changes every scrape but isn't part of the tag
+        assert_ne!(first, second);
+        let (_, headers, _) = conditional_scrape(state.clone(), None).await;
+        let etag = headers.get(ETAG).unwrap().to_str().unwrap().to_owned();
+        let (status, _, _) = conditional_scrape(state, Some(&etag)).await;
+        assert_eq!(StatusCode::NOT_MODIFIED, status);
+    }
+
+    #[tokio::test]
+    async fn test_text_metrics_handler_etag_changes() {
+        let state = unchanging_state();
+        let (_, first, _) = conditional_scrape(state.clone(), None).await;
+        let etag = first.get(ETAG).unwrap().to_str().unwrap().to_owned();
+
+        // Storing a reading changes the tag even if no metrics about readings changed
+        state.latest.set(reading(21.5, 1000));
+        let (status, second, body) = conditional_scrape(state, Some(&etag)).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_ne!(first.get(ETAG), second.get(ETAG));
+        assert!(!body.is_empty());
+    }
+
+    fn state() -> Arc<RequestState> {
+        Arc::new(RequestState::builder(Registries::new(), Arc::new(LatestReadingCell::new())).build())
+    }
+
+    fn backfill_state(max_bytes: usize) -> Arc<RequestState> {
+        let history = ReadingHistory::new(10);
+        for (secs, temperature) in [(1_665_400_000, 21.5), (1_665_400_030, 21.6), (1_665_400_060, 21.7)] {
+            history.push(LatestReading::new(
+                Measurement {
+                    temperature: TemperatureCelsius::from(temperature),
+                    humidity: Humidity::from(40.0),
+                },
+                UNIX_EPOCH + Duration::from_secs(secs),
+                Instant::now(),
+            ));
+        }
+
+        Arc::new(
+            RequestState::builder(Registries::new(), Arc::new(LatestReadingCell::new()))
+                .backfill(BackfillSource {
+                    history: Arc::new(history),
+                    unit: TemperatureUnit::Celsius,
+                    max_bytes,
+                })
+                .build(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_router_backfill_disabled() {
+        let req = Request::get("/metrics/backfill").body(Body::empty()).unwrap();
+        let res = router(state()).oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+    }
+
+    #[tokio::test]
+    async fn test_router_backfill() {
+        let req = Request::get("/metrics/backfill?since=1665400030&job=strudel&instance=pi%3A9781")
+            .body(Body::empty())
+            .unwrap();
+        let res = router(backfill_state(usize::MAX)).oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(METRICS_TEXT, res.headers().get(CONTENT_TYPE).unwrap());
+        assert!(res.headers().get(BACKFILL_NEXT_HEADER).is_none());
+
+        let body = response_body(res).await;
+        assert!(!body.contains("21.5"), "{}", body);
+        assert!(
+            body.contains("strudel_temperature_degrees{job=\"strudel\",instance=\"pi:9781\"} 21.6 1665400030.000\n"),
+            "{}",
+            body
+        );
+        assert_eq!(4, crate::exposition::validate(&body).unwrap().samples);
+
+        let req = Request::get("/metrics/backfill?since=-1").body(Body::empty()).unwrap();
+        let res = router(backfill_state(usize::MAX)).oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+    }
+
+    #[tokio::test]
+    async fn test_router_backfill_truncated() {
+        // Room for the HELP and TYPE lines and a single reading
+        let req = Request::get("/metrics/backfill").body(Body::empty()).unwrap();
+        let res = router(backfill_state(300)).oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!("1665400030.000", res.headers().get(BACKFILL_NEXT_HEADER).unwrap());
+
+        let body = response_body(res).await;
+        assert_eq!(2, crate::exposition::validate(&body).unwrap().samples);
+    }
+
+    #[tokio::test]
+    async fn test_router_metrics() {
+        let req = Request::get("/metrics").body(Body::empty()).unwrap();
+        let res = router(state()).oneshot(req).await.unwrap();
+
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(METRICS_TEXT, res.headers().get(CONTENT_TYPE).unwrap());
+
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("strudel_scrapes_total 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_router_slim_disabled() {
+        let req = Request::get("/metrics/slim").body(Body::empty()).unwrap();
+        let res = router(state()).oneshot(req).await.unwrap();
+
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+    }
+
+    #[tokio::test]
+    async fn test_router_slim() {
+        let mut registries = Registries::new();
+        let mut slim = Registry::default();
+        let metrics = TemperatureMetrics::new(registries.group("sensor"))
+            .sensor_name(Some("indoor".to_owned()))
+            .slim(&mut slim);
+        let reading = |temperature: f64| {
+            LatestReading::new(
+                Measurement {
+                    temperature: TemperatureCelsius::from(temperature),
+                    humidity: Humidity::from(40.0),
+                },
+                UNIX_EPOCH + Duration::from_secs(1_665_400_000),
+                Instant::now(),
+            )
+        };
+        // Only the reading of the configured sensor is exposed
+        metrics.latest().set_named("indoor", reading(21.5));
+        metrics.latest().set_named("outdoor", reading(5.0));
+        let state = Arc::new(RequestState::builder(registries, metrics.latest()).slim(slim).build());
+
+        let req = Request::get("/metrics/slim").body(Body::empty()).unwrap();
+        let res = router(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(METRICS_TEXT, res.headers().get(CONTENT_TYPE).unwrap());
+
+        let body = response_body(res).await;
+        assert!(body.len() < 512, "{} bytes", body.len());
+        assert_eq!(2, crate::exposition::validate(&body).unwrap().families);
+        assert_eq!(
+            vec![
+                "strudel_temperature_degrees{sensor=\"indoor\"} 21.5",
+                "strudel_relative_humidity{sensor=\"indoor\"} 40.0"
+            ],
+            body.lines().filter(|l| !l.starts_with('#')).collect::<Vec<_>>()
+        );
+
+        // Samples are the same as those of the same series with all metrics
+        let req = Request::get("/metrics").body(Body::empty()).unwrap();
+        let full = response_body(router(state).oneshot(req).await.unwrap()).await;
+        for line in body.lines().filter(|l| !l.starts_with("# EOF")) {
+            assert!(full.lines().any(|l| l == line), "{} missing from all metrics", line);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_router_not_found() {
+        let req = Request::get("/nope").body(Body::empty()).unwrap();
+        let res = router(state()).oneshot(req).await.unwrap();
+
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+    }
+
+    #[tokio::test]
+    async fn test_router_method_not_allowed() {
+        let req = Request::post("/metrics").body(Body::empty()).unwrap();
+        let res = router(state()).oneshot(req).await.unwrap();
+
+        assert_eq!(StatusCode::METHOD_NOT_ALLOWED, res.status());
+    }
+
+    #[tokio::test]
+    async fn test_router_metrics_partial_encode() {
+        let mut registries = Registries::new();
+        registries
+            .group("broken")
+            .register("broken", "Always fails", BrokenMetric);
+        registries
+            .group("sensor")
+            .register("working", "Always works", Gauge::<i64>::default());
+        let state = Arc::new(RequestState::builder(registries, Arc::new(LatestReadingCell::new())).build());
+
+        let req = Request::get("/metrics").body(Body::empty()).unwrap();
+        let res = router(state.clone()).oneshot(req).await.unwrap();
+
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!("broken", res.headers().get(ENCODE_ERRORS_HEADER).unwrap());
+
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(!body.contains("broken"));
+        assert!(body.contains("working 0\n"));
+        assert!(body.ends_with("# EOF\n"));
+
+        // Failures are counted after encoding so they're visible on the next scrape
+        let req = Request::get("/metrics").body(Body::empty()).unwrap();
+        let res = router(state).oneshot(req).await.unwrap();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("strudel_encode_failures_total 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_router_metrics_encode_failure() {
+        // HTTP metrics aren't exposed so that the only group is the broken one
+        let metrics = HttpMetrics::new(&mut Registry::default());
+        let mut registries = Registries::new();
+        registries
+            .group("broken")
+            .register("broken", "Always fails", BrokenMetric);
+        let state = Arc::new(RequestState {
+            registries,
+            metrics,
+            latest: Arc::new(LatestReadingCell::new()),
+            single_sensor: false,
+            cors: None,
+            lifecycle: None,
+            lifecycle_token: None,
+            sensors: None,
+            power: None,
+            backfill: None,
+            slim: None,
+            live: None,
+            events: None,
+            scrape_reads: None,
+            description: None,
+            status: None,
+            scrape_timeout: DEFAULT_SCRAPE_TIMEOUT,
+            etag: Mutex::new(None),
+        });
+
+        let req = Request::get("/metrics").body(Body::empty()).unwrap();
+        let res = router(state).oneshot(req).await.unwrap();
+
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, res.status());
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(b"unable to encode any metrics\n", &body[..]);
+    }
+
+    #[tokio::test]
+    async fn test_router_check() {
+        let req = Request::get("/-/check").body(Body::empty()).unwrap();
+        let res = router(state()).oneshot(req).await.unwrap();
+
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!("application/json", res.headers().get(CONTENT_TYPE).unwrap());
+
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let summary: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(summary["families"].as_u64().unwrap() > 0);
+        assert!(summary["samples"].as_u64().unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn test_router_check_not_scrape() {
+        let state = state();
+        let req = Request::get("/-/check").body(Body::empty()).unwrap();
+        let res = router(state.clone()).oneshot(req).await.unwrap();
+
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(None, state.metrics.latest_scrape());
+    }
+
+    #[tokio::test]
+    async fn test_router_check_invalid() {
+        let metrics = HttpMetrics::new(&mut Registry::default());
+        let mut registries = Registries::new();
+        // Infinite values are encoded as `inf` rather than `+Inf` which isn't valid
+        let gauge = Gauge::<f64, AtomicU64>::default();
+        gauge.set(f64::INFINITY);
+        registries.group("sensor").register("bad", "Not a number", gauge);
+        let state = Arc::new(RequestState {
+            registries,
+            metrics,
+            latest: Arc::new(LatestReadingCell::new()),
+            single_sensor: false,
+            cors: None,
+            lifecycle: None,
+            lifecycle_token: None,
+            sensors: None,
+            power: None,
+            backfill: None,
+            slim: None,
+            live: None,
+            events: None,
+            scrape_reads: None,
+            description: None,
+            status: None,
+            scrape_timeout: DEFAULT_SCRAPE_TIMEOUT,
+            etag: Mutex::new(None),
+        });
+
+        let req = Request::get("/-/check").body(Body::empty()).unwrap();
+        let res = router(state).oneshot(req).await.unwrap();
+
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, res.status());
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let err: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(3, err["line"]);
+        assert_eq!("invalid value 'inf'", err["error"]);
+    }
+
+    #[tokio::test]
+    async fn test_router_check_encode_failure() {
+        let metrics = HttpMetrics::new(&mut Registry::default());
+        let mut registries = Registries::new();
+        registries
+            .group("broken")
+            .register("broken", "Always fails", BrokenMetric);
+        let state = Arc::new(RequestState {
+            registries,
+            metrics,
+            latest: Arc::new(LatestReadingCell::new()),
+            single_sensor: false,
+            cors: None,
+            lifecycle: None,
+            lifecycle_token: None,
+            sensors: None,
+            power: None,
+            backfill: None,
+            slim: None,
+            live: None,
+            events: None,
+            scrape_reads: None,
+            description: None,
+            status: None,
+            scrape_timeout: DEFAULT_SCRAPE_TIMEOUT,
+            etag: Mutex::new(None),
+        });
+
+        let req = Request::get("/-/check").body(Body::empty()).unwrap();
+        let res = router(state).oneshot(req).await.unwrap();
+
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, res.status());
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(br#"{"error":"unable to encode any metrics"}"#, &body[..]);
+    }
+
+    #[tokio::test]
+    async fn test_router_quit_disabled() {
+        let req = Request::post("/-/quit").body(Body::empty()).unwrap();
+        let res = router(state()).oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, res.status());
+
+        let req = Request::get("/-/quit").body(Body::empty()).unwrap();
+        let res = router(state()).oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::METHOD_NOT_ALLOWED, res.status());
+    }
+
+    #[tokio::test]
+    async fn test_server_quit() {
+        let shutdown = Shutdown::new();
+        let state = Arc::new(
+            RequestState::builder(Registries::new(), Arc::new(LatestReadingCell::new()))
+                .lifecycle(shutdown.clone())
+                .build(),
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(router(state).into_make_service())
+            .with_graceful_shutdown(async move { shutdown.wait().await });
+        let server = tokio::spawn(server);
+
+        let req = Request::post(format!("http://{}/-/quit", address))
+            .body(hyper::Body::empty())
+            .unwrap();
+        let res = hyper::Client::new().request(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+
+        let res = tokio::time::timeout(Duration::from_secs(5), server).await;
+        assert!(matches!(res, Ok(Ok(Ok(())))), "server didn't shut down: {:?}", res);
+    }
+
+    type LiveEvents = watch::Sender<Option<Arc<ReadingEvent>>>;
+    type LiveClient = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    fn reading_event(secs: u64, result: Result<Measurement, SensorError>) -> ReadingEvent {
+        ReadingEvent {
+            timestamp: UNIX_EPOCH + Duration::from_secs(secs),
+            instant: Instant::now(),
+            result,
+            clamped: Clamped::default(),
+            attempts: 1,
+            retried_errors: Vec::new(),
+            duration: Duration::ZERO,
+            raw: Vec::new(),
+            pulses: None,
+            span: Span::none(),
+        }
+    }
+
+    fn live_event(secs: u64, result: Result<Measurement, SensorError>) -> Option<Arc<ReadingEvent>> {
+        Some(Arc::new(reading_event(secs, result)))
+    }
+
+    fn measured(temperature: f64) -> Result<Measurement, SensorError> {
+        Ok(Measurement {
+            temperature: TemperatureCelsius::from(temperature),
+            humidity: Humidity::from(45.0),
+        })
+    }
+
+    fn events_request(last_event_id: Option<&str>) -> Request<Body> {
+        let req = Request::get("/events");
+        let req = match last_event_id {
+            Some(id) => req.header("last-event-id", id),
+            None => req,
+        };
+        req.body(Body::empty()).unwrap()
+    }
+
+    /// Read from `body` until it has `count` more events, returning their text.
+    async fn next_events(body: &mut BoxBody, count: usize) -> String {
+        let mut text = String::new();
+        while text.matches("\n\n").count() < count {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), body.data())
+                .await
+                .expect("timed out waiting for events")
+                .unwrap()
+                .unwrap();
+            text.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+        text
+    }
+
+    #[tokio::test]
+    async fn test_router_events_disabled() {
+        let res = router(state()).oneshot(events_request(None)).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+    }
+
+    #[tokio::test]
+    async fn test_router_events() {
+        let log = Arc::new(EventLog::new(10));
+        log.update(&reading_event(1000, measured(21.0)));
+        let state = Arc::new(
+            RequestState::builder(Registries::new(), Arc::new(LatestReadingCell::new()))
+                .events(log.clone())
+                .build(),
+        );
+
+        let res = router(state).oneshot(events_request(None)).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!("text/event-stream", res.headers().get(CONTENT_TYPE).unwrap());
+
+        // Events from before connecting aren't sent without a Last-Event-ID
+        let mut body = res.into_body();
+        log.update(&reading_event(1010, measured(22.0)));
+        log.update(&reading_event(
+            1020,
+            Err(SensorError::new(SensorErrorKind::Checksum, "invalid checksum")),
+        ));
+        assert_eq!(
+            concat!(
+                "id:2\n",
+                "event:reading\n",
+                "data:{\"temperature\":22.0,\"humidity\":45.0,\"read_at\":1010.0}\n\n",
+                "id:3\n",
+                "event:error\n",
+                "data:{\"kind\":\"checksum\",\"error\":\"invalid checksum\",\"at\":1020.0}\n\n",
+            ),
+            next_events(&mut body, 2).await
+        );
+
+        // Closing the log ends the stream so the server can shut down
+        log.close();
+        let res = tokio::time::timeout(Duration::from_secs(5), body.data()).await.unwrap();
+        assert!(res.is_none(), "unexpected data after close: {:?}", res);
+    }
+
+    #[tokio::test]
+    async fn test_router_events_replay() {
+        let log = Arc::new(EventLog::new(2));
+        for (secs, temperature) in [(1000, 21.0), (1010, 21.5), (1020, 22.0)] {
+            log.update(&reading_event(secs, measured(temperature)));
+        }
+        assert_eq!(2, log.len());
+        let state = Arc::new(
+            RequestState::builder(Registries::new(), Arc::new(LatestReadingCell::new()))
+                .events(log.clone())
+                .build(),
+        );
+
+        // Only events that are still kept can be replayed
+        let res = router(state.clone()).oneshot(events_request(Some("1"))).await.unwrap();
+        let mut body = res.into_body();
+        let text = next_events(&mut body, 2).await;
+        assert!(text.starts_with("id:2\n"), "unexpected events: {}", text);
+        assert!(text.contains("id:3\n"), "unexpected events: {}", text);
+
+        // IDs from a previous run of strudel aren't replayed
+        let res = router(state.clone()).oneshot(events_request(Some("99"))).await.unwrap();
+        let mut restarted = res.into_body();
+
+        log.update(&reading_event(1030, measured(22.5)));
+        let text = next_events(&mut body, 1).await;
+        assert!(text.starts_with("id:4\n"), "unexpected events: {}", text);
+        let text = next_events(&mut restarted, 1).await;
+        assert!(text.starts_with("id:4\n"), "unexpected events: {}", text);
+    }
+
+    /// Serve `state` on an ephemeral port, returning the URL of live readings.
+    fn live_url(state: Arc<RequestState>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(router(state).into_make_service());
+        tokio::spawn(server);
+
+        format!("ws://{}/ws", address)
+    }
+
+    /// Serve live readings on an ephemeral port, returning their URL and the sender of
+    /// events pushed to clients.
+    fn live_server(latest: LatestReadingCell, max_connections: usize) -> (String, LiveEvents) {
+        let (tx, rx) = watch::channel(None);
+        let state = Arc::new(
+            RequestState::builder(Registries::new(), Arc::new(latest))
+                .live(LiveReadings::new(rx, max_connections))
+                .build(),
+        );
+
+        (live_url(state), tx)
+    }
+
+    async fn next_message(client: &mut LiveClient) -> serde_json::Value {
+        let msg = tokio::time::timeout(Duration::from_secs(5), client.next())
+            .await
+            .expect("timed out waiting for message")
+            .unwrap()
+            .unwrap();
+        match msg {
+            Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            m => panic!("unexpected message: {:?}", m),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_live_disabled() {
+        match tokio_tungstenite::connect_async(live_url(state())).await {
+            Err(tungstenite::Error::Http(res)) => assert_eq!(StatusCode::NOT_FOUND, res.status()),
+            res => panic!("unexpected result: {:?}", res.map(|(_, r)| r)),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_live_snapshot() {
+        let latest = LatestReadingCell::new();
+        latest.set(reading(21.5, 1000));
+        let (url, _events) = live_server(latest, 8);
+
+        let (mut client, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let msg = next_message(&mut client).await;
+        assert_eq!(
+            serde_json::json!({
+                "type": "snapshot",
+                "readings": [{"sensor": null, "temperature": 21.5, "humidity": 40.0, "read_at": 1000.0}],
+            }),
+            msg
+        );
+    }
+
+    #[tokio::test]
+    async fn test_server_live_push() {
+        let (url, events) = live_server(LatestReadingCell::new(), 8);
+
+        let (mut client, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let (mut with_errors, _) = tokio_tungstenite::connect_async(format!("{}?errors=1", url))
+            .await
+            .unwrap();
+        assert_eq!(serde_json::json!([]), next_message(&mut client).await["readings"]);
+        assert_eq!(serde_json::json!([]), next_message(&mut with_errors).await["readings"]);
+
+        events.send_replace(live_event(
+            1010,
+            Err(SensorError::new(SensorErrorKind::Checksum, "invalid checksum")),
+        ));
+        let msg = next_message(&mut with_errors).await;
+        assert_eq!("error", msg["type"]);
+        assert_eq!("checksum", msg["kind"]);
+        assert_eq!(1010.0, msg["at"]);
+
+        events.send_replace(live_event(
+            1020,
+            Ok(Measurement {
+                temperature: TemperatureCelsius::from(22.0),
+                humidity: Humidity::from(45.0),
+            }),
+        ));
+        let expected = serde_json::json!({"type": "reading", "temperature": 22.0, "humidity": 45.0, "read_at": 1020.0});
+        // Clients that didn't ask for errors only see the reading
+        assert_eq!(expected, next_message(&mut client).await);
+        assert_eq!(expected, next_message(&mut with_errors).await);
+    }
+
+    #[tokio::test]
+    async fn test_server_live_connection_limit() {
+        let (url, _events) = live_server(LatestReadingCell::new(), 2);
+
+        let (mut first, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let (mut second, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        next_message(&mut first).await;
+        next_message(&mut second).await;
+
+        match tokio_tungstenite::connect_async(&url).await {
+            Err(tungstenite::Error::Http(res)) => assert_eq!(StatusCode::SERVICE_UNAVAILABLE, res.status()),
+            res => panic!("unexpected result: {:?}", res.map(|(_, r)| r)),
+        }
+
+        // Connections are allowed again once a client disconnects
+        first.close(None).await.unwrap();
+        drop(first);
+        let mut attempts = 0;
+        let (mut third, _) = loop {
+            match tokio_tungstenite::connect_async(&url).await {
+                Ok(c) => break c,
+                Err(_) if attempts < 50 => {
+                    attempts += 1;
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+                Err(e) => panic!("unable to connect after a client disconnected: {}", e),
+            }
+        };
+        next_message(&mut third).await;
+    }
+
+    /// Manager that replaces the sensor of a worker with a `CountingSensor` starting at
+    /// the pin number, rejecting specs for pins above 27.
+    #[derive(Debug)]
+    struct SwappingManager {
+        swapper: SensorSwapper<CountingSensor>,
+    }
+
+    impl SensorManager for SwappingManager {
+        fn replace(&self, spec: SensorSpec) -> Result<oneshot::Receiver<Result<(), SensorError>>, Vec<String>> {
+            if spec.pin > 27 {
+                return Err(vec![format!("invalid BCM pin {}, must be at most 27", spec.pin)]);
+            }
+
+            Ok(self.swapper.swap(move || {
+                let sensor = CountingSensor {
+                    reads: u64::from(spec.pin),
+                };
+
+                Ok(SensorSwap {
+                    calibration: Calibration::new(sensor.ranges()),
+                    interval: spec.refresh_or(Duration::from_secs(3600)),
+                    sensor,
+                })
+            }))
+        }
+
+        fn sensors(&self) -> Vec<SensorSpec> {
+            Vec::new()
+        }
+    }
+
+    /// Manager that never finishes replacing the sensor until its senders are taken, or
+    /// fails to open it if `fail` is set.
+    #[derive(Debug, Default)]
+    struct PendingManager {
+        fail: bool,
+        pending: Mutex<Vec<oneshot::Sender<Result<(), SensorError>>>>,
+        sensors: Vec<SensorSpec>,
+    }
+
+    impl SensorManager for PendingManager {
+        fn replace(&self, _spec: SensorSpec) -> Result<oneshot::Receiver<Result<(), SensorError>>, Vec<String>> {
+            let (tx, rx) = oneshot::channel();
+            if self.fail {
+                let _ = tx.send(Err(SensorError::initialization("no such pin")));
+            } else {
+                self.pending.lock().unwrap().push(tx);
+            }
+
+            Ok(rx)
+        }
+
+        fn sensors(&self) -> Vec<SensorSpec> {
+            self.sensors.clone()
+        }
+    }
+
+    fn sensors_state(manager: Arc<dyn SensorManager>) -> Arc<RequestState> {
+        Arc::new(
+            RequestState::builder(Registries::new(), Arc::new(LatestReadingCell::new()))
+                .lifecycle(Shutdown::new())
+                .lifecycle_token("secret".to_owned())
+                .sensors(manager)
+                .build(),
+        )
+    }
+
+    fn sensors_request(token: Option<&str>, body: &'static str) -> Request<Body> {
+        let mut req = Request::post("/-/sensors");
+        if let Some(t) = token {
+            req = req.header(AUTHORIZATION, format!("Bearer {}", t));
+        }
+
+        req.body(Body::from(body)).unwrap()
+    }
+
+    async fn response_body(res: axum::response::Response) -> String {
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_router_sensors_disabled() {
+        let req = sensors_request(Some("secret"), r#"{"pin": 17}"#);
+        let res = router(state()).oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, res.status());
+
+        // Lifecycle endpoints are enabled but there's no token
+        let state = Arc::new(
+            RequestState::builder(Registries::new(), Arc::new(LatestReadingCell::new()))
+                .lifecycle(Shutdown::new())
+                .sensors(Arc::new(PendingManager::default()))
+                .build(),
+        );
+        let req = sensors_request(None, r#"{"pin": 17}"#);
+        let res = router(state).oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, res.status());
+    }
+
+    fn power_state(lifecycle: bool) -> Arc<RequestState> {
+        let mut registries = Registries::new();
+        let metrics = PowerMetrics::new(registries.group("health"));
+        let pin: Box<dyn DataPin + Send + Sync> = Box::new(NopDataPin);
+        let power = PowerController::new(pin, Duration::from_millis(1), Duration::from_secs(3600), &metrics);
+
+        let mut builder = RequestState::builder(registries, Arc::new(LatestReadingCell::new())).power(Arc::new(power));
+        if lifecycle {
+            builder = builder.lifecycle(Shutdown::new()).lifecycle_token("secret".to_owned());
+        }
+
+        Arc::new(builder.build())
+    }
+
+    #[tokio::test]
+    async fn test_router_power_cycle_disabled() {
+        let req = Request::post("/-/power-cycle").body(Body::empty()).unwrap();
+        let res = router(power_state(false)).oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, res.status());
+
+        // Lifecycle endpoints are enabled but there's nothing to power cycle
+        let req = Request::post("/-/power-cycle").body(Body::empty()).unwrap();
+        let res = router(sensors_state(Arc::new(PendingManager::default())))
+            .oneshot(req)
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, res.status());
+        assert_eq!("power cycling is not enabled\n", response_body(res).await);
+    }
+
+    #[tokio::test]
+    async fn test_router_power_cycle() {
+        let state = power_state(true);
+
+        let req = Request::post("/-/power-cycle").body(Body::empty()).unwrap();
+        let res = router(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+
+        let req = Request::post("/-/power-cycle")
+            .header(AUTHORIZATION, "Bearer secret")
+            .body(Body::empty())
+            .unwrap();
+        let res = router(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+
+        // Requested power cycles aren't subject to the cooldown
+        let req = Request::post("/-/power-cycle")
+            .header(AUTHORIZATION, "Bearer secret")
+            .body(Body::empty())
+            .unwrap();
+        let res = router(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+
+        let buf = state.registries.encode().unwrap().text;
+        assert!(
+            buf.contains("strudel_power_cycles_total{reason=\"requested\"} 2\n"),
+            "{}",
+            buf
+        );
+    }
+
+    #[tokio::test]
+    async fn test_router_lifecycle_unauthorized() {
+        let state = sensors_state(Arc::new(PendingManager::default()));
+
+        for token in [None, Some("wrong"), Some("secre"), Some("secrets")] {
+            let req = sensors_request(token, r#"{"pin": 17}"#);
+            let res = router(state.clone()).oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::UNAUTHORIZED, res.status(), "token {:?}", token);
+            assert_eq!("Bearer", res.headers().get(WWW_AUTHENTICATE).unwrap());
+        }
+
+        let req = Request::post("/-/quit").body(Body::empty()).unwrap();
+        let res = router(state).oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+    }
+
+    #[tokio::test]
+    async fn test_router_sensors_swap() {
+        let worker = SensorWorker::new(CountingSensor::default(), Duration::from_secs(3600));
+        let manager = SwappingManager {
+            swapper: worker.swapper(),
+        };
+        let handle = worker.start();
+        let mut latest = handle.latest();
+        latest.changed().await.unwrap();
+        assert_eq!(
+            Some(TemperatureCelsius::from(1.0)),
+            latest.borrow().map(|m| m.temperature)
+        );
+
+        let state = sensors_state(Arc::new(manager));
+        let req = sensors_request(Some("secret"), r#"{"pin": 22, "name": "outdoor"}"#);
+        let res = router(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+
+        // The replacement is read right away
+        latest.changed().await.unwrap();
+        assert_eq!(
+            Some(TemperatureCelsius::from(23.0)),
+            latest.borrow().map(|m| m.temperature)
+        );
+
+        let body = scrape(state).await;
+        assert!(body.contains("strudel_sensor_reconfigurations_total 1\n"));
+
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_router_sensors_invalid_spec() {
+        let worker = SensorWorker::new(CountingSensor::default(), Duration::from_secs(3600));
+        let manager = SwappingManager {
+            swapper: worker.swapper(),
+        };
+        let handle = worker.start();
+        let state = sensors_state(Arc::new(manager));
+
+        for body in [
+            "",
+            "{}",
+            r#"{"pin": 17, "color": "red"}"#,
+            r#"{"pin": 17, "name": "in door"}"#,
+        ] {
+            let req = sensors_request(Some("secret"), body);
+            let res = router(state.clone()).oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::BAD_REQUEST, res.status(), "body {:?}", body);
+        }
+
+        let req = sensors_request(Some("secret"), r#"{"pin": 40}"#);
+        let res = router(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+        assert_eq!("invalid BCM pin 40, must be at most 27\n", response_body(res).await);
+
+        let body = scrape(state).await;
+        assert!(body.contains("strudel_sensor_reconfigurations_total 0\n"));
+
+        handle.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_router_sensors_concurrent() {
+        let manager = Arc::new(PendingManager::default());
+        let state = sensors_state(manager.clone());
+
+        let first = tokio::spawn(router(state.clone()).oneshot(sensors_request(Some("secret"), r#"{"pin": 17}"#)));
+        while manager.pending.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+
+        // Rejected while the first replacement is in progress
+        let req = sensors_request(Some("secret"), r#"{"pin": 22}"#);
+        let res = router(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CONFLICT, res.status());
+
+        for tx in manager.pending.lock().unwrap().drain(..) {
+            tx.send(Ok(())).unwrap();
+        }
+        let res = first.await.unwrap().unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+
+        // Allowed again once it's done
+        let second = tokio::spawn(router(state).oneshot(sensors_request(Some("secret"), r#"{"pin": 22}"#)));
+        while manager.pending.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        manager.pending.lock().unwrap().clear();
+        let res = second.await.unwrap().unwrap();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, res.status());
+    }
+
+    #[tokio::test]
+    async fn test_router_sensors_open_failure() {
+        let manager = PendingManager {
+            fail: true,
+            ..Default::default()
+        };
+        let state = sensors_state(Arc::new(manager));
+
+        let req = sensors_request(Some("secret"), r#"{"pin": 17}"#);
+        let res = router(state).oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, res.status());
+        assert_eq!(
+            "unable to open replacement sensor: no such pin\n",
+            response_body(res).await
+        );
+    }
+
+    /// Sensor that returns the number of reads so far as the temperature
+    #[derive(Debug, Default)]
+    struct CountingSensor {
+        reads: u64,
+    }
+
+    impl Sensor for CountingSensor {
+        fn read(&mut self) -> Result<Measurement, SensorError> {
+            self.reads += 1;
+            Ok(Measurement {
+                temperature: TemperatureCelsius::from(self.reads as f64),
+                humidity: Humidity::from(50.0),
+            })
+        }
+    }
+
+    /// State that reads `CountingSensor` for each scrape, blocking subscribers while
+    /// handling the read number `block` until the returned sender is dropped. Real time
+    /// is used since waiting for subscriber threads would let paused time skip ahead.
+    fn read_on_scrape_state(
+        reuse: Duration,
+        timeout: Duration,
+        block: u64,
+    ) -> (Arc<RequestState>, WorkerHandle, mpsc::Sender<()>) {
+        let mut registries = Registries::new();
+        let metrics = TemperatureMetrics::new(registries.group("sensor"));
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let mut handled = 0;
+
+        let worker = SensorWorker::new(CountingSensor::default(), Duration::from_secs(30))
+            .on_demand(reuse)
+            .subscribe(move |event| {
+                handled += 1;
+                if handled == block {
+                    let _ = release_rx.recv();
+                }
+                metrics.update(event);
+            })
+            .start();
+
+        let reads = ScrapeReads {
+            readers: vec![worker.requester()],
+            timeout,
+        };
+        let state = RequestState::builder(registries, Arc::new(LatestReadingCell::new()))
+            .read_on_scrape(reads)
+            .build();
+
+        (Arc::new(state), worker, release_tx)
+    }
+
+    #[tokio::test]
+    async fn test_text_metrics_handler_read_on_scrape() {
+        let (state, worker, _release) = read_on_scrape_state(Duration::from_millis(200), Duration::from_secs(5), 0);
+
+        let body = scrape(state.clone()).await;
+        assert_eq!(1.0, metric_value(&body, "strudel_temperature_degrees{sensor=\"\"}"));
+
+        // Reads within the reuse window of the previous one are reused
+        let body = scrape(state.clone()).await;
+        assert_eq!(1.0, metric_value(&body, "strudel_temperature_degrees{sensor=\"\"}"));
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let body = scrape(state.clone()).await;
+        assert_eq!(2.0, metric_value(&body, "strudel_temperature_degrees{sensor=\"\"}"));
+        assert_eq!(0.0, metric_value(&body, "strudel_scrape_read_timeouts_total"));
+
+        worker.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_text_metrics_handler_read_on_scrape_timeout() {
+        let (state, worker, release) = read_on_scrape_state(Duration::ZERO, Duration::from_millis(500), 2);
+
+        let body = scrape(state.clone()).await;
+        assert_eq!(1.0, metric_value(&body, "strudel_temperature_degrees{sensor=\"\"}"));
+
+        // The second read isn't handled in time so the first one is used
+        let body = scrape(state.clone()).await;
+        assert_eq!(1.0, metric_value(&body, "strudel_temperature_degrees{sensor=\"\"}"));
+        assert_eq!(1.0, metric_value(&body, "strudel_scrape_read_timeouts_total"));
+
+        drop(release);
+        worker.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_text_metrics_handler_read_on_scrape_deadline() {
+        let (state, worker, release) = read_on_scrape_state(Duration::ZERO, Duration::from_secs(30), 2);
+        scrape(state.clone()).await;
+
+        // The second read isn't handled before the scraper gives up, well before the
+        // read budget runs out
+        let mut req = HeaderMap::new();
+        req.insert(SCRAPE_TIMEOUT_HEADER, HeaderValue::from_static("1"));
+        let start = Instant::now();
+        let res = text_metrics_handler(State(state.clone()), req).await;
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, res.status());
+
+        drop(release);
+        let body = scrape(state).await;
+        assert_eq!(1.0, metric_value(&body, "strudel_scrape_deadline_exceeded_total"));
+        assert_eq!(0.0, metric_value(&body, "strudel_scrape_read_timeouts_total"));
+
+        worker.shutdown().await;
+    }
+
+    fn reading(temperature: f64, secs: u64) -> LatestReading {
+        LatestReading::new(
+            Measurement {
+                temperature: TemperatureCelsius::from(temperature),
+                humidity: Humidity::from(40.0),
+            },
+            UNIX_EPOCH + Duration::from_secs(secs),
+            Instant::now(),
+        )
+    }
+
+    async fn readings(latest: LatestReadingCell, sensor: Option<&str>) -> (StatusCode, String) {
+        readings_formatted(latest, sensor, JsonFormat::default()).await
+    }
+
+    async fn readings_formatted(
+        latest: LatestReadingCell,
+        sensor: Option<&str>,
+        format: JsonFormat,
+    ) -> (StatusCode, String) {
+        let state = RequestState::builder(Registries::new(), Arc::new(latest)).build();
+        readings_from(state, sensor, format).await
+    }
+
+    async fn single_sensor_readings(latest: LatestReadingCell) -> (StatusCode, String) {
+        let state = RequestState::builder(Registries::new(), Arc::new(latest))
+            .single_sensor()
+            .build();
+        readings_from(state, None, JsonFormat::default()).await
+    }
+
+    async fn readings_from(state: RequestState, sensor: Option<&str>, format: JsonFormat) -> (StatusCode, String) {
+        let query = ReadingsQuery {
+            sensor: sensor.map(|s| s.to_owned()),
+            format,
+        };
+
+        let res = readings_handler(State(Arc::new(state)), Query(query)).await;
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_readings_handler_single_unnamed_sensor() {
+        let latest = LatestReadingCell::new();
+        latest.set(reading(21.5, 1000));
+
+        let (status, body) = single_sensor_readings(latest).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(r#"{"temperature":21.5,"humidity":40.0,"read_at":1000.0}"#, body);
+    }
+
+    #[tokio::test]
+    async fn test_readings_handler_single_sensor_no_readings() {
+        let (status, _) = single_sensor_readings(LatestReadingCell::new()).await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+    }
+
+    #[tokio::test]
+    async fn test_readings_handler_single_sensor_renamed() {
+        // Replacing the only sensor with a named one doesn't change the shape
+        let latest = LatestReadingCell::new();
+        latest.set_named("indoor", reading(21.5, 1000));
+
+        let (status, body) = single_sensor_readings(latest).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(r#"{"temperature":21.5,"humidity":40.0,"read_at":1000.0}"#, body);
+    }
+
+    #[tokio::test]
+    async fn test_readings_handler_multiple_sensors() {
+        let latest = LatestReadingCell::new();
+        latest.set_named("outdoor", reading(5.0, 1010));
+        latest.set_named("indoor", reading(21.5, 1000));
+
+        let (status, body) = readings(latest, None).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(
+            concat!(
+                r#"[{"sensor":"indoor","temperature":21.5,"humidity":40.0,"read_at":1000.0},"#,
+                r#"{"sensor":"outdoor","temperature":5.0,"humidity":40.0,"read_at":1010.0}]"#
+            ),
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn test_readings_handler_no_readings() {
+        // Sensors that haven't been read yet are left out
+        let (status, body) = readings(LatestReadingCell::new(), None).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!("[]", body);
+    }
+
+    #[tokio::test]
+    async fn test_readings_handler_filter_hit() {
+        let latest = LatestReadingCell::new();
+        latest.set_named("outdoor", reading(5.0, 1010));
+        latest.set_named("indoor", reading(21.5, 1000));
+
+        let (status, body) = readings(latest, Some("indoor")).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(
+            r#"{"sensor":"indoor","temperature":21.5,"humidity":40.0,"read_at":1000.0}"#,
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn test_readings_handler_filter_miss() {
+        let latest = LatestReadingCell::new();
+        latest.set_named("indoor", reading(21.5, 1000));
+
+        let (status, _) = readings(latest, Some("garage")).await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+    }
+
+    fn format(pretty: Option<&str>, fields: Option<&str>) -> JsonFormat {
+        JsonFormat {
+            pretty: pretty.map(|s| s.to_owned()),
+            fields: fields.map(|s| s.to_owned()),
+        }
+    }
+
+    #[test]
+    fn test_format_json_default() {
+        let body = format_json(&reading(21.5, 1000), &JsonFormat::default(), READING_FIELDS).unwrap();
+        assert_eq!(r#"{"temperature":21.5,"humidity":40.0,"read_at":1000.0}"#, body);
+    }
+
+    #[test]
+    fn test_format_json_pretty() {
+        let expected = "{\n  \"temperature\": 21.5,\n  \"humidity\": 40.0,\n  \"read_at\": 1000.0\n}";
+        for pretty in ["1", "true", ""] {
+            let body = format_json(&reading(21.5, 1000), &format(Some(pretty), None), READING_FIELDS).unwrap();
+            assert_eq!(expected, body, "pretty: '{}'", pretty);
+        }
+
+        let body = format_json(&reading(21.5, 1000), &format(Some("0"), None), READING_FIELDS).unwrap();
+        assert_eq!(r#"{"temperature":21.5,"humidity":40.0,"read_at":1000.0}"#, body);
+    }
+
+    #[test]
+    fn test_format_json_fields() {
+        let r = reading(21.5, 1000);
+        let body = format_json(&r, &format(None, Some("humidity, temperature")), READING_FIELDS).unwrap();
+        assert_eq!(r#"{"temperature":21.5,"humidity":40.0}"#, body);
+
+        // Arrays of objects have fields selected in each object
+        let named = vec![r.named(Some("indoor")), r.named(Some("outdoor"))];
+        let body = format_json(&named, &format(None, Some("sensor,read_at,")), READING_FIELDS).unwrap();
+        assert_eq!(
+            r#"[{"sensor":"indoor","read_at":1000.0},{"sensor":"outdoor","read_at":1000.0}]"#,
+            body
+        );
+
+        // Selecting no fields is the same as not selecting any
+        let body = format_json(&r, &format(None, Some("")), READING_FIELDS).unwrap();
+        assert_eq!(r#"{"temperature":21.5,"humidity":40.0,"read_at":1000.0}"#, body);
+    }
+
+    #[test]
+    fn test_format_json_unknown_fields() {
+        let err = format_json(
+            &reading(21.5, 1000),
+            &format(None, Some("temperature_c,humidity,humidity_percent")),
+            READING_FIELDS,
+        )
+        .unwrap_err();
+
+        assert_eq!("unknown fields: temperature_c,humidity_percent", err.error);
+        assert_eq!(vec!["temperature_c", "humidity_percent"], err.unknown);
+        assert_eq!(READING_FIELDS, err.valid.as_slice());
+    }
+
+    #[tokio::test]
+    async fn test_readings_handler_formatted() {
+        let latest = LatestReadingCell::new();
+        latest.set_named("indoor", reading(21.5, 1000));
+
+        let (status, body) = readings_formatted(latest, Some("indoor"), format(Some("1"), Some("temperature"))).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!("{\n  \"temperature\": 21.5\n}", body);
+    }
+
+    #[tokio::test]
+    async fn test_readings_handler_unknown_fields() {
+        let latest = LatestReadingCell::new();
+        latest.set(reading(21.5, 1000));
+
+        let (status, body) = readings_formatted(latest, None, format(None, Some("temperature_c"))).await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+        assert_eq!(
+            concat!(
+                r#"{"error":"unknown fields: temperature_c","unknown":["temperature_c"],"#,
+                r#""valid":["sensor","temperature","humidity","read_at","restored"]}"#
+            ),
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn test_router_readings_query() {
+        let latest = LatestReadingCell::new();
+        latest.set(reading(21.5, 1000));
+        let state = Arc::new(
+            RequestState::builder(Registries::new(), Arc::new(latest))
+                .single_sensor()
+                .build(),
+        );
+
+        let req = Request::get("/readings?pretty&fields=humidity")
+            .body(Body::empty())
+            .unwrap();
+        let res = router(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!("application/json", res.headers().get(CONTENT_TYPE).unwrap());
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(&b"{\n  \"humidity\": 40.0\n}"[..], &body[..]);
+
+        let req = Request::get("/readings?fields=bogus").body(Body::empty()).unwrap();
+        let res = router(state).oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+        assert_eq!("application/json", res.headers().get(CONTENT_TYPE).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_router_readings() {
+        let latest = LatestReadingCell::new();
+        latest.set_named("indoor", reading(21.5, 1000));
+        let state = Arc::new(RequestState::builder(Registries::new(), Arc::new(latest)).build());
+
+        let req = Request::get("/readings?sensor=indoor").body(Body::empty()).unwrap();
+        let res = router(state).oneshot(req).await.unwrap();
+
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!("application/json", res.headers().get(CONTENT_TYPE).unwrap());
+    }
+
+    fn description() -> SelfDescription {
+        let mut sensor = SensorSpec::new(17);
+        sensor.name = Some("garage".to_owned());
+
+        SelfDescription {
+            instance_id: "pi-garage".to_owned(),
+            address: Some("192.168.1.10:9781".to_owned()),
+            sensors: vec![sensor],
+            labels: BTreeMap::from([("site".to_owned(), "home".to_owned())]),
+        }
+    }
+
+    async fn get_body(state: Arc<RequestState>, req: Request<Body>) -> (StatusCode, String) {
+        let res = router(state).oneshot(req).await.unwrap();
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_self_handler_native() {
+        let state = Arc::new(
+            RequestState::builder(Registries::new(), Arc::new(LatestReadingCell::new()))
+                .description(description())
+                .build(),
+        );
+
+        let req = Request::get("/api/v1/self").body(Body::empty()).unwrap();
+        let (status, body) = get_body(state, req).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(
+            format!(
+                concat!(
+                    r#"{{"instance_id":"pi-garage","version":"{}","#,
+                    r#""sensors":[{{"pin":17,"name":"garage","type":"dht22","refresh_secs":null,"temp_offset":0.0}}],"#,
+                    r#""labels":{{"site":"home"}},"endpoints":["/metrics","/readings","/-/check","/api/v1/self"]}}"#
+                ),
+                VERSION
+            ),
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn test_self_handler_native_managed_sensors() {
+        let manager = PendingManager {
+            sensors: vec![SensorSpec::new(22)],
+            ..PendingManager::default()
+        };
+        let state = Arc::new(
+            RequestState::builder(Registries::new(), Arc::new(LatestReadingCell::new()))
+                .lifecycle(Shutdown::new())
+                .sensors(Arc::new(manager))
+                .description(description())
+                .build(),
+        );
+
+        let req = Request::get("/api/v1/self?fields=sensors,endpoints")
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = get_body(state, req).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(
+            concat!(
+                r#"{"sensors":[{"pin":22,"name":null,"type":"dht22","refresh_secs":null,"temp_offset":0.0}],"#,
+                r#""endpoints":["/metrics","/readings","/-/check","/api/v1/self","/-/quit","/-/sensors"]}"#
+            ),
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn test_self_handler_prom_sd() {
+        let state = Arc::new(
+            RequestState::builder(Registries::new(), Arc::new(LatestReadingCell::new()))
+                .description(description())
+                .build(),
+        );
+
+        let req = Request::get("/api/v1/self?format=prom_sd")
+            .header(HOST, "pi-garage.local:9781")
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = get_body(state.clone(), req).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(
+            format!(
+                concat!(
+                    r#"{{"targets":["pi-garage.local:9781"],"labels":{{"__meta_strudel_instance_id":"pi-garage","#,
+                    r#""__meta_strudel_sensors":"garage:dht22:17","__meta_strudel_version":"{}","#,
+                    r#""__metrics_path__":"/metrics","site":"home"}}}}"#
+                ),
+                VERSION
+            ),
+            body
+        );
+
+        // The configured address is used when the request doesn't include a host
+        let req = Request::get("/api/v1/self?format=prom_sd&fields=targets")
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = get_body(state, req).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(r#"{"targets":["192.168.1.10:9781"]}"#, body);
+    }
+
+    #[tokio::test]
+    async fn test_self_handler_errors() {
+        let req = Request::get("/api/v1/self").body(Body::empty()).unwrap();
+        let (status, _) = get_body(state(), req).await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+
+        let state = Arc::new(
+            RequestState::builder(Registries::new(), Arc::new(LatestReadingCell::new()))
+                .description(description())
+                .build(),
+        );
+        let req = Request::get("/api/v1/self?format=file_sd").body(Body::empty()).unwrap();
+        let (status, body) = get_body(state.clone(), req).await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+        assert_eq!("unknown format 'file_sd', expected 'native' or 'prom_sd'\n", body);
+
+        let req = Request::get("/api/v1/self?format=prom_sd&fields=sensors")
+            .body(Body::empty())
+            .unwrap();
+        let (status, _) = get_body(state, req).await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+    }
+
+    #[tokio::test]
+    async fn test_status_handler() {
+        let req = Request::get("/status").body(Body::empty()).unwrap();
+        let (status, _) = get_body(state(), req).await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+
+        let state = Arc::new(
+            RequestState::builder(Registries::new(), Arc::new(LatestReadingCell::new()))
+                .description(description())
+                .status(serde_json::json!({"version": "1.0.0", "bcm_pin": 17, "probe": {"result": "skipped"}}))
+                .build(),
+        );
+
+        let req = Request::get("/status").body(Body::empty()).unwrap();
+        let (status, body) = get_body(state.clone(), req).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(r#"{"version":"1.0.0","bcm_pin":17,"probe":{"result":"skipped"}}"#, body);
+
+        let req = Request::get("/status?fields=probe").body(Body::empty()).unwrap();
+        let (status, body) = get_body(state.clone(), req).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(r#"{"probe":{"result":"skipped"}}"#, body);
+
+        let req = Request::get("/status?fields=password").body(Body::empty()).unwrap();
+        let (status, _) = get_body(state.clone(), req).await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+
+        // The status endpoint is advertised when there is a status
+        let req = Request::get("/api/v1/self?fields=endpoints")
+            .body(Body::empty())
+            .unwrap();
+        let (_, body) = get_body(state, req).await;
+        assert_eq!(
+            r#"{"endpoints":["/metrics","/readings","/-/check","/api/v1/self","/status"]}"#,
+            body
+        );
+    }
+
+    fn cors_state(origins: &[&'static str], all_routes: bool) -> Arc<RequestState> {
+        let cors = CorsSettings {
+            origins: origins.iter().map(|o| HeaderValue::from_static(o)).collect(),
+            all_routes,
+        };
+
+        Arc::new(
+            RequestState::builder(Registries::new(), Arc::new(LatestReadingCell::new()))
+                .cors(cors)
+                .build(),
+        )
+    }
+
+    fn cors_get(path: &str, origin: &str) -> Request<Body> {
+        Request::get(path).header(ORIGIN, origin).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_router_cors_preflight() {
+        let state = cors_state(&["http://dashboard.local"], false);
+        let req = Request::options("/readings")
+            .header(ORIGIN, "http://dashboard.local")
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .body(Body::empty())
+            .unwrap();
+        let res = router(state).oneshot(req).await.unwrap();
+
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(
+            "http://dashboard.local",
+            res.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap()
+        );
+        assert_eq!("GET", res.headers().get(ACCESS_CONTROL_ALLOW_METHODS).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_router_cors_simple_request() {
+        let state = cors_state(&["http://dashboard.local", "http://other.local"], false);
+
+        let res = router(state.clone())
+            .oneshot(cors_get("/readings", "http://other.local"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(
+            "http://other.local",
+            res.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap()
+        );
+
+        // Origins that aren't allowed don't get the header
+        let res = router(state.clone())
+            .oneshot(cors_get("/readings", "http://evil.local"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(None, res.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        // Metrics aren't CORS enabled unless all routes are
+        let res = router(state)
+            .oneshot(cors_get("/metrics", "http://dashboard.local"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(None, res.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    #[tokio::test]
+    async fn test_router_cors_any_origin_all_routes() {
+        let state = cors_state(&["*"], true);
+        let res = router(state)
+            .oneshot(cors_get("/metrics", "http://dashboard.local"))
+            .await
+            .unwrap();
+
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!("*", res.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_router_cors_disabled() {
+        let latest = Arc::new(LatestReadingCell::new());
+        let state = Arc::new(RequestState::builder(Registries::new(), latest).build());
+
+        let res = router(state.clone())
+            .oneshot(cors_get("/readings", "http://dashboard.local"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(None, res.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        // Preflight requests aren't answered either
+        let req = Request::options("/readings")
+            .header(ORIGIN, "http://dashboard.local")
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .body(Body::empty())
+            .unwrap();
+        let res = router(state).oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::METHOD_NOT_ALLOWED, res.status());
+    }
+}
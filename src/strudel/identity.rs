@@ -0,0 +1,157 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! Stable identity of the machine running `strudel`, used to tell pushed outputs of
+//! many machines apart.
+
+use std::fs;
+use std::path::Path;
+
+/// Instance ID used when neither the hostname nor machine ID can be determined.
+pub const UNKNOWN_INSTANCE: &str = "unknown";
+
+const MACHINE_ID_PATH: &str = "/etc/machine-id";
+const MACHINE_ID_LEN: usize = 8;
+
+/// Get the hostname of the local machine, if it can be determined.
+pub fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if res != 0 {
+        return None;
+    }
+
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..len].to_vec()).ok().filter(|h| !h.is_empty())
+}
+
+/// Get the first eight characters of the machine ID from `/etc/machine-id`, if it exists.
+pub fn machine_id() -> Option<String> {
+    machine_id_from(Path::new(MACHINE_ID_PATH))
+}
+
+fn machine_id_from(path: &Path) -> Option<String> {
+    let contents = fs::read_to_string(path).ok()?;
+    let id: String = contents.trim().chars().take(MACHINE_ID_LEN).collect();
+    if id.is_empty() {
+        None
+    } else {
+        Some(id)
+    }
+}
+
+/// Determine the instance ID of the local machine: the hostname, the machine ID if
+/// the hostname isn't available, or `unknown` if neither are.
+pub fn instance_id() -> String {
+    detect_instance_id(hostname, machine_id)
+}
+
+/// Determine an instance ID using the given sources of the hostname and machine ID,
+/// sanitized so that it can be used as a label value, in Graphite paths, and in MQTT
+/// topics. The machine ID is only used when there is no usable hostname.
+pub fn detect_instance_id<H, M>(hostname: H, machine_id: M) -> String
+where
+    H: FnOnce() -> Option<String>,
+    M: FnOnce() -> Option<String>,
+{
+    hostname()
+        .map(|h| sanitize(&h))
+        .filter(|h| !h.is_empty())
+        .or_else(|| machine_id().map(|m| sanitize(&m)).filter(|m| !m.is_empty()))
+        .unwrap_or_else(|| UNKNOWN_INSTANCE.to_owned())
+}
+
+/// Replace any characters other than ASCII letters, numbers, `.`, `-`, and `_` with
+/// underscores and trim surrounding whitespace. Wildcards and separators used by MQTT
+/// topics (`+`, `#`, and `/`) are replaced along with whitespace and control characters.
+pub fn sanitize(id: &str) -> String {
+    id.trim()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{detect_instance_id, machine_id_from, sanitize, UNKNOWN_INSTANCE};
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn test_detect_hostname() {
+        let id = detect_instance_id(|| Some("pi-office".to_owned()), || panic!("machine ID not needed"));
+        assert_eq!("pi-office", id);
+    }
+
+    #[test]
+    fn test_detect_machine_id_fallback() {
+        let id = detect_instance_id(|| None, || Some("8f2a1c3d".to_owned()));
+        assert_eq!("8f2a1c3d", id);
+    }
+
+    #[test]
+    fn test_detect_empty_hostname_fallback() {
+        let id = detect_instance_id(|| Some(" ".to_owned()), || Some("8f2a1c3d".to_owned()));
+        assert_eq!("8f2a1c3d", id);
+    }
+
+    #[test]
+    fn test_detect_unknown() {
+        assert_eq!(UNKNOWN_INSTANCE, detect_instance_id(|| None, || None));
+        assert_eq!(
+            UNKNOWN_INSTANCE,
+            detect_instance_id(|| Some(String::new()), || Some(String::new()))
+        );
+    }
+
+    #[test]
+    fn test_detect_sanitized() {
+        let id = detect_instance_id(|| Some("living room/pi#1".to_owned()), || None);
+        assert_eq!("living_room_pi_1", id);
+    }
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!("pi.example.com", sanitize("pi.example.com"));
+        assert_eq!("pi_4-b", sanitize(" pi_4-b\n"));
+        assert_eq!("a_b_c_d", sanitize("a+b#c/d"));
+        assert_eq!("caf_", sanitize("café"));
+        assert_eq!("", sanitize(""));
+    }
+
+    #[test]
+    fn test_machine_id_from() {
+        let path = env::temp_dir().join(format!("strudel-machine-id-{}", process::id()));
+
+        fs::write(&path, "8f2a1c3d9e7b4a6f8f2a1c3d9e7b4a6f\n").unwrap();
+        assert_eq!(Some("8f2a1c3d".to_owned()), machine_id_from(&path));
+
+        fs::write(&path, "\n").unwrap();
+        assert_eq!(None, machine_id_from(&path));
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(None, machine_id_from(&path));
+    }
+}
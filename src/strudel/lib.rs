@@ -25,11 +25,65 @@
 //!
 //! The following metrics are exported:
 //!
-//! * `strudel_temperature_degrees` - Degrees celsius measured by the sensor.
+//! * `strudel_temperature_degrees` - Degrees celsius measured by the sensor (or fahrenheit or kelvin, based on `--temperature-unit`).
 //! * `strudel_relative_humidity` - Relative humidity (from 0 to 100) measured by the sensor.
-//! * `strudel_last_read_timestamp` - UNIX timestamp of the last time the sensor was correctly read.
+//! * `strudel_vapour_pressure_deficit_kpa` - Vapour pressure deficit in kilopascals, adjusted by `--leaf-temp-offset`.
+//! * `strudel_temperature_change_per_hour` - Rate of change of temperature per hour over the last `--trend-window-secs`.
+//! * `strudel_relative_humidity_change_per_hour` - Rate of change of relative humidity per hour over the last `--trend-window-secs`.
+//! * `strudel_temperature_celsius_distribution` - Histogram of temperature readings in celsius, buckets set by `--temp-buckets`.
+//! * `strudel_relative_humidity_distribution` - Histogram of relative humidity readings, buckets set by `--humidity-buckets`.
+//! * `strudel_last_read_timestamp` - UNIX timestamp of the last time the sensor was correctly read. Missing if the system clock wasn't synchronized when it was read.
+//! * `strudel_clock_synchronized` - Whether the system clock is synchronized (1) or not (0), based on whether it's later than when `strudel` was built.
 //! * `strudel_collections_total` - Total number of attempts to read the sensor.
-//! * `strudel_errors_total` - Total errors by type while trying to read the sensor.
+//! * `strudel_reads_total` - Total reads of the sensor by outcome: succeeded on the first try, succeeded after retries, or failed.
+//! * `strudel_errors_total` - Total errors by type while trying to read the sensor, labeled by attempt number (`1`, `2`, ...) or `final` when all attempts failed. Every type is exported as `0` for `final` before its first error.
+//! * `strudel_error_ratio_5m` - Fraction of read attempts, including retries, that failed in the last five minutes.
+//! * `strudel_calibration_clamped_total` - Total calibrated readings outside the range of the sensor that were clamped to it, by `value` (`temperature` or `humidity`).
+//! * `strudel_timing_cycles_per_us` - Number of times the data pin can be checked per microsecond, measured by `--dht-calibrate-timing`. Zero if calibration failed.
+//! * `strudel_pulse_width_ratio` - Average width of high pulses of the last read that captured every pulse relative to their long-run average. Values drifting well below 1.0 suggest the supply voltage of the sensor is sagging. 1.0 until pulses are captured.
+//! * `strudel_lock_recoveries_total` - Total times a lock used by metrics, the state file, or outputs was poisoned by a panic and recovered. Metrics keep working but values may be inconsistent.
+//! * `strudel_scrapes_total` - Total number of times metrics have been scraped.
+//! * `strudel_scrape_encode_duration_seconds` - Time taken to encode metrics for a scrape, in seconds.
+//! * `strudel_encode_failures_total` - Total groups of metrics that could not be encoded for a scrape and were left out of it.
+//! * `strudel_last_scrape_timestamp` - UNIX timestamp of the scrape before the current one (it lags by one scrape).
+//! * `strudel_scrape_gap_seconds` - Time since the previous scrape, in seconds. Keeps growing when nothing scrapes `strudel`.
+//! * `strudel_scrape_read_timeouts_total` - Total scrapes that gave up waiting for a fresh read of the sensor (`--read-on-scrape`).
+//! * `strudel_scrape_deadline_exceeded_total` - Total scrapes abandoned with a `503` response because they would have finished after the scraper gave up.
+//! * `strudel_sensor_reconfigurations_total` - Total times the sensor was replaced at runtime with `POST /-/sensors`.
+//! * `strudel_process_start_time_seconds` - UNIX timestamp of when the process started.
+//! * `strudel_process_uptime_seconds` - Time since the process started, in seconds.
+//! * `strudel_process_cpu_seconds_total` - Total user and system CPU time, in seconds (Linux only).
+//! * `strudel_process_resident_memory_bytes` - Resident memory size, in bytes (Linux only).
+//! * `strudel_process_open_fds` - Number of open file descriptors (Linux only).
+//! * `strudel_bcm_pin` - BCM GPIO pin number the sensor is configured to use.
+//! * `strudel_refresh_interval_seconds` - Effective interval the sensor is read at, in seconds.
+//! * `strudel_build_info` - Version, git commit, and other build information as labels.
+//! * `strudel_device_info` - Model and SoC of the Raspberry Pi `strudel` is running on as labels, `unknown` if they can't be detected.
+//! * `strudel_push_errors_total` - Total failed or dropped pushes of readings or metrics by target.
+//! * `strudel_sensor_healthy` - Whether the sensor is healthy (1) or degraded (0) based on recent reads.
+//! * `strudel_sensor_stuck` - Whether the sensor is stuck returning identical bytes for every read (1) or not (0).
+//! * `strudel_humidity_saturated_seconds` - How long relative humidity has been pinned at 100%, or 0 if it isn't.
+//! * `strudel_humidity_zeroed_seconds` - How long relative humidity has been pinned at 0%, or 0 if it isn't.
+//! * `strudel_power_cycles_total` - Total times power to the sensor was cut by `--power-pin`, by `reason` (`stuck`, `saturated`, or `requested`).
+//! * `strudel_state_transitions_total` - Total changes of the sensor between healthy and degraded states.
+//! * `strudel_healthy` - Whether a read of the sensor, successful or not, was attempted within twice the refresh interval (1) or not (0).
+//! * `strudel_read_loop_alive` - UNIX timestamp of the last time the loop reading the sensor woke up.
+//! * `strudel_fusion_weight` - Weight (0-1) of each sensor in the fused value of its group set by `--fuse`, by `fused`, `sensor`, and `reading`.
+//! * `strudel_canary_temp_delta` - Temperature of the most recent successful canary read set by `--canary-strategy` minus the reading it was compared to.
+//! * `strudel_canary_agreement_total` - Number of canary reads by whether they agreed with the reading they were compared to as `agree` (`true` or `false`).
+//! * `strudel_spikes_suppressed_total` - Number of readings replaced by the average of the readings before and after them with `--publish-delay-one-sample`.
+//!
+//! When the `--debug-metrics` flag is set, the bytes decoded from the most recent attempt to read the
+//! sensor are also exposed, including attempts with an invalid checksum: `strudel_debug_raw_byte` by
+//! `index` (`0` to `4`), and the 16-bit words `strudel_debug_raw_temperature_word` and
+//! `strudel_debug_raw_humidity_word`. These are meant for diagnosing readings with unexpected values
+//! and aren't exposed by default.
+//!
+//! To reduce the number of series exposed, families of metrics can be left out entirely with
+//! `--disable-metric`, which takes a comma separated list and may be repeated. Disabled metrics
+//! aren't exposed at all rather than reported as zero. The families are `vapour_pressure_deficit`,
+//! `histograms`, `error_ratio`, `pulse_width_ratio`, `trend`, `debug`, `process`, `build`,
+//! `config`, `read_loop`, `health`, `push`, `http`, `fusion`, `canary`, and `spikes`.
 //!
 //! ## Build
 //!
@@ -78,6 +132,19 @@
 //! sudo systemctl start strudel.serivce
 //! ```
 //!
+//! ### Socket Activation
+//!
+//! `strudel` optionally supports systemd socket activation. Using the [provided socket file](ext/strudel.socket),
+//! systemd will listen on port `9781` and pass the socket to `strudel` when it starts. The `--bind` option
+//! must not be used in this case.
+//!
+//! ```text
+//! sudo cp ext/strudel.socket /etc/systemd/system/strudel.socket
+//! sudo systemctl daemon-reload
+//! sudo systemctl enable strudel.socket
+//! sudo systemctl start strudel.socket
+//! ```
+//!
 //! ### Prometheus
 //!
 //! Prometheus metrics are exposed on port `9781` at `/metrics`. Once `strudel`
@@ -106,6 +173,29 @@
 //! ```
 //!
 
+pub mod app;
+pub mod cli;
+pub mod clock;
+pub mod device;
+pub mod exposition;
+pub mod fusion;
+pub mod health;
 pub mod http;
+pub mod identity;
 pub mod metrics;
+#[cfg(feature = "modbus")]
+pub mod modbus;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+pub mod prelude;
+pub mod process;
+pub mod push;
 pub mod sensor;
+pub mod sink;
+pub mod state;
+pub mod summary;
+pub mod systemd;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod udp;
+pub mod version;
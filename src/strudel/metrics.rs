@@ -16,88 +16,3957 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 //
 
-use crate::sensor::{Humidity, SensorError, TemperatureCelsius};
-use prometheus_client::encoding::EncodeLabelSet;
+use crate::clock::{Clock, ClockCheck, SystemClock};
+use crate::fusion::{FuseGroup, FusionEstimator};
+use crate::health::{Ewma, OutcomeWindow, SensorState};
+use crate::sensor::{
+    Humidity, LatestReading, LatestReadingCell, Measurement, PowerCycleReason, ReadingEvent, SensorError,
+    SensorErrorKind, TemperatureCelsius, TemperatureUnit, VapourPressureDeficit,
+};
+use crate::version;
+use prometheus_client::collector::Collector;
+use prometheus_client::encoding::{text, EncodeLabelSet, EncodeLabelValue};
 use prometheus_client::metrics::counter::Counter;
-use prometheus_client::metrics::family::Family;
-use prometheus_client::metrics::gauge::Gauge;
-use prometheus_client::registry::Registry;
-use std::sync::atomic::AtomicU64;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tracing;
+#[cfg(feature = "otel")]
+use prometheus_client::metrics::exemplar::CounterWithExemplar;
+use prometheus_client::metrics::family::{Family, MetricConstructor};
+use prometheus_client::metrics::gauge::{ConstGauge, Gauge};
+use prometheus_client::metrics::histogram::{exponential_buckets, linear_buckets, Histogram};
+use prometheus_client::registry::{Descriptor, LocalMetric, Registry};
+use prometheus_client::MaybeOwned;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::iter;
+use std::ops::{BitOr, Range};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{self, Span};
 
 #[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
 struct ErrorsLabels {
-    kind: String,
+    sensor: String,
+    kind: ErrorKindLabel,
+    attempt: String,
+}
+
+/// Value of the `kind` label of error counters, one per `SensorErrorKind`, so that the
+/// number of series is bounded no matter what errors happen. Variants are named after the
+/// label values they're encoded as.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, EncodeLabelValue)]
+enum ErrorKindLabel {
+    initialization,
+    timeout,
+    checksum,
+    no_response,
+    implausible,
+    frame,
+    internal,
+}
+
+impl ErrorKindLabel {
+    /// Every label, created for the final attempt when error counters are registered so
+    /// that they're exported as zero before the first error of each kind.
+    const ALL: [Self; 7] = [
+        Self::initialization,
+        Self::timeout,
+        Self::checksum,
+        Self::no_response,
+        Self::implausible,
+        Self::frame,
+        Self::internal,
+    ];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::initialization => "initialization",
+            Self::timeout => "timeout",
+            Self::checksum => "checksum",
+            Self::no_response => "no_response",
+            Self::implausible => "implausible",
+            Self::frame => "frame",
+            Self::internal => "internal",
+        }
+    }
+
+    /// Label with the given value, `None` if it isn't the label of any kind of error.
+    fn from_label(label: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|l| l.as_str() == label)
+    }
+}
+
+impl From<SensorErrorKind> for ErrorKindLabel {
+    // No wildcard: a new kind of error doesn't compile until it has a label
+    fn from(kind: SensorErrorKind) -> Self {
+        match kind {
+            SensorErrorKind::Initialization => Self::initialization,
+            SensorErrorKind::ReadTimeout => Self::timeout,
+            SensorErrorKind::Checksum => Self::checksum,
+            SensorErrorKind::NoResponse => Self::no_response,
+            SensorErrorKind::Implausible => Self::implausible,
+            SensorErrorKind::FrameError => Self::frame,
+            SensorErrorKind::Internal => Self::internal,
+        }
+    }
+}
+
+/// Labels for exemplars attached to error counters, linking them to the trace of the read.
+#[cfg(feature = "otel")]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct TraceLabels {
+    trace_id: String,
+}
+
+/// Error counters carry the trace ID of the failed read as an exemplar when tracing via
+/// OpenTelemetry is enabled and are plain counters otherwise.
+#[cfg(feature = "otel")]
+type ErrorCounter = CounterWithExemplar<TraceLabels>;
+#[cfg(not(feature = "otel"))]
+type ErrorCounter = Counter;
+
+/// Increment an error counter restored from a previous run by its saved value.
+#[cfg(feature = "otel")]
+fn restore_error_counter(counter: &ErrorCounter, value: u64) {
+    counter.inc_by(value, None);
+}
+
+/// Increment an error counter restored from a previous run by its saved value.
+#[cfg(not(feature = "otel"))]
+fn restore_error_counter(counter: &ErrorCounter, value: u64) {
+    counter.inc_by(value);
+}
+
+/// Values of the counters of `TemperatureMetrics` saved so that they can be restored after
+/// a restart. Fields missing from files written by other versions are treated as zero.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CounterValues {
+    #[serde(default)]
+    pub collections: u64,
+    /// Reads by outcome.
+    #[serde(default)]
+    pub reads: BTreeMap<String, u64>,
+    /// Errors by kind and then attempt.
+    #[serde(default)]
+    pub errors: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+impl CounterValues {
+    fn add(&mut self, other: &CounterValues) {
+        self.collections += other.collections;
+        for (outcome, v) in other.reads.iter() {
+            *self.reads.entry(outcome.clone()).or_default() += v;
+        }
+
+        for (kind, attempts) in other.errors.iter() {
+            let entry = self.errors.entry(kind.clone()).or_default();
+            for (attempt, v) in attempts.iter() {
+                *entry.entry(attempt.clone()).or_default() += v;
+            }
+        }
+    }
+
+    fn inc_error(&mut self, labels: &ErrorsLabels) {
+        *self
+            .errors
+            .entry(labels.kind.as_str().to_owned())
+            .or_default()
+            .entry(labels.attempt.clone())
+            .or_default() += 1;
+    }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ReadsLabels {
+    sensor: String,
+    outcome: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ClampedLabels {
+    sensor: String,
+    value: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct SensorLabels {
+    sensor: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct TransitionLabels {
+    sensor: String,
+    to: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct PowerCycleLabels {
+    reason: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct FusionWeightLabels {
+    fused: String,
+    sensor: String,
+    reading: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct AgreementLabels {
+    agree: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct BuildLabels {
+    version: String,
+    commit: String,
+    target: String,
+    rustc: String,
+    features: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct PushLabels {
+    target: String,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RawByteLabels {
+    index: String,
 }
 
 /// Collection of Prometheus metrics updated based on DHT22 sensor temperature and
-/// humidity readings. Temperature in degrees celsius and relative humidity will be
-/// emitted as gauges.
+/// humidity readings. Temperature in degrees celsius (or another unit if configured)
+/// and relative humidity will be emitted as gauges. Every successful reading is also
+/// observed into histograms of temperature (always in celsius) and humidity.
+///
+/// Successful readings are stored in a `LatestReadingCell` and the gauges for them are
+/// computed from a single snapshot of the cell when metrics are collected. Use `latest`
+/// to share the cell with anything else reporting readings, like the JSON endpoints.
+///
+/// Every metric is labeled with the name of the sensor, empty if it doesn't have one. Use
+/// `sensor` to get metrics for each other sensor, sharing the same families.
+#[derive(Debug)]
 pub struct TemperatureMetrics {
-    temperature: Gauge<f64, AtomicU64>,
-    humidity: Gauge<f64, AtomicU64>,
-    last_reading: Gauge<f64, AtomicU64>,
-    collections: Counter,
-    errors: Family<ErrorsLabels, Counter>,
+    gauges: Arc<ReadingGauges>,
+    // Position of the name of the sensor in the settings shared with other sensors
+    index: usize,
+    temperature_distribution: Family<SensorLabels, Histogram, Buckets>,
+    humidity_distribution: Family<SensorLabels, Histogram, Buckets>,
+    collections: Family<SensorLabels, Counter>,
+    reads: Family<ReadsLabels, Counter>,
+    errors: Family<ErrorsLabels, ErrorCounter>,
+    error_ratio: Family<SensorLabels, Gauge<f64, AtomicU64>>,
+    clamped: Family<ClampedLabels, Counter>,
+    locks: Locks,
+    attempts: Mutex<OutcomeWindow>,
+    pulse_width_ratio: Family<SensorLabels, Gauge<f64, AtomicU64>>,
+    pulse_widths: Mutex<Ewma>,
+    // Families of counters can't be iterated so their values are tracked separately
+    counter_values: Mutex<CounterValues>,
 }
 
 impl TemperatureMetrics {
     pub fn new(reg: &mut Registry) -> Self {
-        let temperature = Gauge::<f64, AtomicU64>::default();
-        let humidity = Gauge::<f64, AtomicU64>::default();
-        let last_reading = Gauge::<f64, AtomicU64>::default();
-        let collections = Counter::default();
-        let errors = Family::<ErrorsLabels, Counter>::default();
+        Self::with_unit(reg, TemperatureUnit::Celsius)
+    }
+
+    /// Create metrics that report temperature in the given unit instead of celsius.
+    pub fn with_unit(reg: &mut Registry, unit: TemperatureUnit) -> Self {
+        Self::with_buckets(
+            reg,
+            unit,
+            &Self::default_temperature_buckets(),
+            &Self::default_humidity_buckets(),
+        )
+    }
+
+    /// Create metrics that report temperature in the given unit and use the given upper
+    /// bounds for buckets of the temperature (in celsius) and humidity histograms.
+    pub fn with_buckets(
+        reg: &mut Registry,
+        unit: TemperatureUnit,
+        temperature_buckets: &[f64],
+        humidity_buckets: &[f64],
+    ) -> Self {
+        Self::with_config(
+            reg,
+            unit,
+            temperature_buckets,
+            humidity_buckets,
+            MetricsConfig::default(),
+        )
+    }
+
+    /// Create metrics like `with_buckets` that leave out the metrics of any families
+    /// disabled by `config`.
+    pub fn with_config(
+        reg: &mut Registry,
+        unit: TemperatureUnit,
+        temperature_buckets: &[f64],
+        humidity_buckets: &[f64],
+        config: MetricsConfig,
+    ) -> Self {
+        let locks = Locks::default();
+        let gauges = Arc::new(ReadingGauges {
+            locks: locks.clone(),
+            unit,
+            latest: Arc::new(LatestReadingCell::new()),
+            settings: RwLock::new(GaugeSettings {
+                sensors: vec![None],
+                leaf_offset: 0.0,
+                clock: SystemClock::shared(),
+                clock_check: None,
+            }),
+        });
+        let temperature_distribution = Family::new_with_constructor(Buckets(temperature_buckets.into()));
+        let humidity_distribution = Family::new_with_constructor(Buckets(humidity_buckets.into()));
+        let collections = Family::<SensorLabels, Counter>::default();
+        let reads = Family::<ReadsLabels, Counter>::default();
+        let errors = Family::<ErrorsLabels, ErrorCounter>::default();
+        let error_ratio = Family::<SensorLabels, Gauge<f64, AtomicU64>>::default();
+        let clamped = Family::<ClampedLabels, Counter>::default();
+        let pulse_width_ratio = Family::<SensorLabels, Gauge<f64, AtomicU64>>::default();
 
+        reg.register_collector(Box::new(ReadingCollector {
+            gauges: gauges.clone(),
+            legacy: false,
+            vpd: config.is_enabled(MetricsConfig::VAPOUR_PRESSURE_DEFICIT),
+            last_read: true,
+        }));
+        if config.is_enabled(MetricsConfig::HISTOGRAMS) {
+            reg.register(
+                "strudel_temperature_celsius_distribution",
+                "Distribution of temperature readings in celsius",
+                temperature_distribution.clone(),
+            );
+            reg.register(
+                "strudel_relative_humidity_distribution",
+                "Distribution of relative humidity readings (0-100)",
+                humidity_distribution.clone(),
+            );
+        }
+        reg.register("strudel_collections", "Number of attempted reads", collections.clone());
         reg.register(
-            "strudel_temperature_degrees",
-            "Temperature in celsius",
-            temperature.clone(),
+            "strudel_reads",
+            "Number of reads by outcome, including if retries were needed",
+            reads.clone(),
         );
         reg.register(
-            "strudel_relative_humidity",
-            "Relative humidity (0-100)",
-            humidity.clone(),
+            "strudel_errors",
+            "Number of failed read attempts by type and attempt number",
+            errors.clone(),
         );
+        if config.is_enabled(MetricsConfig::ERROR_RATIO) {
+            reg.register(
+                "strudel_error_ratio_5m",
+                "Fraction of read attempts in the last five minutes that failed",
+                error_ratio.clone(),
+            );
+        }
         reg.register(
-            "strudel_last_read_timestamp",
-            "Timestamp of last successful read",
-            last_reading.clone(),
+            "strudel_calibration_clamped",
+            "Number of calibrated values outside the range of the sensor that were clamped to it",
+            clamped.clone(),
+        );
+        if config.is_enabled(MetricsConfig::PULSE_WIDTH_RATIO) {
+            reg.register(
+                "strudel_pulse_width_ratio",
+                "Average width of high pulses of the last read that captured all of them relative to their long-run average",
+                pulse_width_ratio.clone(),
+            );
+        }
+        reg.register(
+            "strudel_lock_recoveries",
+            "Number of times a lock used by metrics, the state file, or outputs was poisoned by a panic and recovered",
+            locks.recoveries.clone(),
         );
-        reg.register("strudel_collections", "Number of attempted reads", collections.clone());
-        reg.register("strudel_errors", "Number of failed reads by type", errors.clone());
 
-        Self {
-            temperature,
-            humidity,
-            last_reading,
+        let metrics = Self {
+            gauges,
+            index: 0,
+            temperature_distribution,
+            humidity_distribution,
             collections,
+            reads,
             errors,
+            error_ratio,
+            clamped,
+            locks,
+            attempts: Mutex::new(OutcomeWindow::new(ERROR_RATIO_WINDOW)),
+            pulse_width_ratio,
+            pulse_widths: Mutex::new(Ewma::new(PULSE_WIDTH_EWMA_ALPHA)),
+            counter_values: Mutex::new(CounterValues::default()),
+        };
+
+        metrics.create_series(&metrics.labels());
+        metrics
+    }
+
+    /// Metrics for another sensor, labeled with `name` and sharing the families, cell of
+    /// latest readings, and settings of these metrics. Names must be unique.
+    pub fn sensor(&self, name: Option<String>) -> Self {
+        let index = {
+            let mut settings = self.gauges.settings_mut();
+            settings.sensors.push(name);
+            settings.sensors.len() - 1
+        };
+
+        let metrics = Self {
+            gauges: self.gauges.clone(),
+            index,
+            temperature_distribution: self.temperature_distribution.clone(),
+            humidity_distribution: self.humidity_distribution.clone(),
+            collections: self.collections.clone(),
+            reads: self.reads.clone(),
+            errors: self.errors.clone(),
+            error_ratio: self.error_ratio.clone(),
+            clamped: self.clamped.clone(),
+            locks: self.locks.clone(),
+            attempts: Mutex::new(OutcomeWindow::new(ERROR_RATIO_WINDOW)),
+            pulse_width_ratio: self.pulse_width_ratio.clone(),
+            pulse_widths: Mutex::new(Ewma::new(PULSE_WIDTH_EWMA_ALPHA)),
+            counter_values: Mutex::new(CounterValues::default()),
+        };
+
+        metrics.create_series(&metrics.labels());
+        metrics
+    }
+
+    /// Additionally register the metrics used by `pitemp`, the predecessor of `strudel`,
+    /// so that existing dashboards and alerts continue to work. Aliases are backed by the
+    /// same values as the canonical metrics. Temperature is only aliased when reported in
+    /// celsius since that's the only unit `pitemp` supported.
+    ///
+    /// Like the canonical metrics, counters are registered without a `_total` suffix since
+    /// it's added when they are encoded.
+    pub fn legacy_names(self, reg: &mut Registry) -> Self {
+        reg.register_collector(Box::new(ReadingCollector {
+            gauges: self.gauges.clone(),
+            legacy: true,
+            vpd: false,
+            last_read: true,
+        }));
+        reg.register(
+            "pitemp_collections",
+            "Number of attempted reads",
+            self.collections.clone(),
+        );
+        reg.register("pitemp_errors", "Number of failed reads by type", self.errors.clone());
+        self
+    }
+
+    /// Additionally register only `strudel_temperature_degrees` and `strudel_relative_humidity`
+    /// in `reg`, for clients that can't handle all metrics. They're computed from the same
+    /// snapshot of the latest reading as the canonical gauges, so nothing is copied to keep
+    /// the two registries in sync.
+    pub fn slim(self, reg: &mut Registry) -> Self {
+        reg.register_collector(Box::new(ReadingCollector {
+            gauges: self.gauges.clone(),
+            legacy: false,
+            vpd: false,
+            last_read: false,
+        }));
+        self
+    }
+
+    /// Default temperature histogram buckets, from -10c to 40c in steps of 2c.
+    pub fn default_temperature_buckets() -> Vec<f64> {
+        linear_buckets(-10.0, 2.0, 26).collect()
+    }
+
+    /// Default humidity histogram buckets, from 0% to 100% in steps of 5%.
+    pub fn default_humidity_buckets() -> Vec<f64> {
+        linear_buckets(0.0, 5.0, 21).collect()
+    }
+
+    /// Compute vapour pressure deficit for leaves `offset` degrees celsius warmer (or
+    /// cooler, when negative) than the air. Default zero.
+    pub fn leaf_temp_offset(self, offset: f64) -> Self {
+        self.gauges.settings_mut().leaf_offset = offset;
+        self
+    }
+
+    /// Label metrics with `name` and store readings under it in the cell of latest readings
+    /// instead of as the unnamed sensor. Default unnamed.
+    pub fn sensor_name(self, name: Option<String>) -> Self {
+        self.remove_series(&self.labels());
+        self.gauges.settings_mut().sensors[self.index] = name;
+        self.create_series(&self.labels());
+        self
+    }
+
+    /// Label metrics with `name` and store readings after this under it instead of the
+    /// current name of the sensor, e.g. after replacing it. Metrics labeled with the current
+    /// name and its most recent reading are removed so that gauges are missing until the
+    /// renamed sensor is read. Counters start from zero, like those of a new sensor.
+    pub fn rename(&self, name: Option<String>) {
+        let previous = self.labels();
+        {
+            let mut settings = self.gauges.settings_mut();
+            if settings.sensors[self.index] == name {
+                return;
+            }
+
+            self.gauges.latest.remove(settings.sensors[self.index].as_deref());
+            settings.sensors[self.index] = name;
+        }
+
+        self.remove_series(&previous);
+        *self.locks.lock(&self.counter_values) = CounterValues::default();
+        *self.locks.lock(&self.attempts) = OutcomeWindow::new(ERROR_RATIO_WINDOW);
+        *self.locks.lock(&self.pulse_widths) = Ewma::new(PULSE_WIDTH_EWMA_ALPHA);
+        self.create_series(&self.labels());
+    }
+
+    /// Don't trust the time of readings taken while the system clock isn't synchronized
+    /// according to `check`. The last read timestamp isn't exposed for these readings until
+    /// the clock is synchronized and they're restamped based on how long ago they were
+    /// taken. By default, the clock is always trusted.
+    pub fn clock_check(self, check: ClockCheck) -> Self {
+        self.gauges.settings_mut().clock_check = Some(check);
+        self
+    }
+
+    /// Use `clock` for the current time when restamping readings taken while the system
+    /// clock wasn't synchronized. Defaults to the system clock.
+    pub fn clock(self, clock: Arc<dyn Clock>) -> Self {
+        self.gauges.settings_mut().clock = clock;
+        self
+    }
+
+    /// Current name of the sensor, see `sensor_name` and `rename`.
+    pub fn name(&self) -> Option<String> {
+        self.gauges.settings().sensors[self.index].clone()
+    }
+
+    /// Cell the most recent successful reading is stored in.
+    pub fn latest(&self) -> Arc<LatestReadingCell> {
+        self.gauges.latest.clone()
+    }
+
+    /// Store `reading`, restored from a previous run, as the most recent reading so that
+    /// the gauges have values before the sensor is read for the first time.
+    pub fn restore(&self, reading: LatestReading) {
+        let settings = self.gauges.settings();
+        match &settings.sensors[self.index] {
+            Some(name) => self.gauges.latest.set_named(name, reading),
+            None => self.gauges.latest.set(reading),
+        }
+    }
+
+    /// Current values of the counters for the number of reads and errors, including any
+    /// restored by `restore_counters`.
+    pub fn counter_values(&self) -> CounterValues {
+        self.locks.lock(&self.counter_values).clone()
+    }
+
+    /// Increment the counters for the number of reads and errors by `values`, saved by a
+    /// previous run, so that they don't reset when `strudel` restarts. Meant to be called
+    /// once, before the first update.
+    pub fn restore_counters(&self, values: &CounterValues) {
+        let labels = self.labels();
+        self.collections.get_or_create(&labels).inc_by(values.collections);
+        for (outcome, v) in values.reads.iter().filter(|(_, v)| **v > 0) {
+            self.reads
+                .get_or_create(&ReadsLabels {
+                    sensor: labels.sensor.clone(),
+                    outcome: outcome.clone(),
+                })
+                .inc_by(*v);
+        }
+
+        for (kind, attempts) in values.errors.iter() {
+            let Some(label) = ErrorKindLabel::from_label(kind) else {
+                tracing::warn!(message = "not restoring errors of unknown kind", kind = %kind);
+                continue;
+            };
+
+            for (attempt, v) in attempts.iter().filter(|(_, v)| **v > 0) {
+                let labels = ErrorsLabels {
+                    sensor: labels.sensor.clone(),
+                    kind: label,
+                    attempt: attempt.clone(),
+                };
+
+                restore_error_counter(&self.errors.get_or_create(&labels), *v);
+            }
         }
+
+        self.locks.lock(&self.counter_values).add(values);
     }
 
-    pub fn update(&self, result: Result<(TemperatureCelsius, Humidity), SensorError>) {
-        self.collections.inc();
+    /// Update metrics based on the result of a read. Intended to be used as a read
+    /// handler of a `SensorWorker`, see `SensorWorker::on_read`, so that counters see
+    /// every read.
+    pub fn update(&self, event: &ReadingEvent) {
+        let mut values = self.locks.lock(&self.counter_values);
+        let labels = self.labels();
+        let outcome = Self::outcome(event);
+        self.collections.get_or_create(&labels).inc();
+        self.reads
+            .get_or_create(&ReadsLabels {
+                sensor: labels.sensor.clone(),
+                outcome: outcome.to_owned(),
+            })
+            .inc();
+        values.collections += 1;
+        *values.reads.entry(outcome.to_owned()).or_default() += 1;
+
+        for (i, kind) in event.retried_errors.iter().enumerate() {
+            let errors = ErrorsLabels {
+                sensor: labels.sensor.clone(),
+                kind: ErrorKindLabel::from(*kind),
+                attempt: (i + 1).to_string(),
+            };
 
-        match result {
-            Ok((temp, humidity)) => {
-                self.temperature.set(temp.into());
-                self.humidity.set(humidity.into());
+            self.record_error(&errors, &event.span);
+            values.inc_error(&errors);
+        }
+
+        self.update_error_ratio(&labels, event);
+        self.update_pulse_width_ratio(&labels, event);
+
+        match &event.result {
+            Ok(m) => {
+                self.temperature_distribution
+                    .get_or_create(&labels)
+                    .observe(m.temperature.into());
+                self.humidity_distribution
+                    .get_or_create(&labels)
+                    .observe(m.humidity.into());
+
+                for (value, clamped) in [
+                    ("temperature", event.clamped.temperature),
+                    ("humidity", event.clamped.humidity),
+                ] {
+                    if clamped {
+                        self.clamped
+                            .get_or_create(&ClampedLabels {
+                                sensor: labels.sensor.clone(),
+                                value: value.to_owned(),
+                            })
+                            .inc();
+                    }
+                }
 
-                // If we can't get the number of seconds since the epoch, skip the update
-                let _ = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .map(|d| self.last_reading.set(d.as_secs_f64()));
+                let settings = self.gauges.settings();
+                let reading = settings.reading(*m, event);
+                match &settings.sensors[self.index] {
+                    Some(name) => self.gauges.latest.set_named(name, reading),
+                    None => self.gauges.latest.set(reading),
+                }
             }
             Err(e) => {
-                let labels = ErrorsLabels {
-                    kind: e.kind().as_label().to_owned(),
+                let errors = ErrorsLabels {
+                    sensor: labels.sensor.clone(),
+                    kind: ErrorKindLabel::from(e.kind()),
+                    attempt: "final".to_owned(),
+                };
+
+                self.record_error(&errors, &event.span);
+                values.inc_error(&errors);
+            }
+        };
+    }
+
+    /// Labels of the series for the current name of the sensor.
+    fn labels(&self) -> SensorLabels {
+        SensorLabels {
+            sensor: self.gauges.settings().sensors[self.index].clone().unwrap_or_default(),
+        }
+    }
+
+    /// Create the series that are exposed before the sensor is read, so that they're
+    /// present with zero values rather than missing.
+    fn create_series(&self, labels: &SensorLabels) {
+        let _ = self.temperature_distribution.get_or_create(labels);
+        let _ = self.humidity_distribution.get_or_create(labels);
+        let _ = self.collections.get_or_create(labels);
+        let _ = self.error_ratio.get_or_create(labels);
+        for kind in ErrorKindLabel::ALL {
+            let _ = self.errors.get_or_create(&ErrorsLabels {
+                sensor: labels.sensor.clone(),
+                kind,
+                attempt: "final".to_owned(),
+            });
+        }
+        // There's no reason to suspect undervoltage until pulses have been captured
+        self.pulse_width_ratio.get_or_create(labels).set(1.0);
+    }
+
+    /// Remove every series labeled with `labels`, including those of any counters that
+    /// have been incremented.
+    fn remove_series(&self, labels: &SensorLabels) {
+        self.temperature_distribution.remove(labels);
+        self.humidity_distribution.remove(labels);
+        self.collections.remove(labels);
+        self.error_ratio.remove(labels);
+        self.pulse_width_ratio.remove(labels);
+        for outcome in ["failure", "success_first_try", "success_retried"] {
+            self.reads.remove(&ReadsLabels {
+                sensor: labels.sensor.clone(),
+                outcome: outcome.to_owned(),
+            });
+        }
+
+        for value in ["temperature", "humidity"] {
+            self.clamped.remove(&ClampedLabels {
+                sensor: labels.sensor.clone(),
+                value: value.to_owned(),
+            });
+        }
+
+        let values = self.locks.lock(&self.counter_values);
+        for kind in ErrorKindLabel::ALL {
+            let attempts = values.errors.get(kind.as_str());
+            for attempt in iter::once("final").chain(attempts.into_iter().flat_map(|a| a.keys().map(String::as_str))) {
+                self.errors.remove(&ErrorsLabels {
+                    sensor: labels.sensor.clone(),
+                    kind,
+                    attempt: attempt.to_owned(),
+                });
+            }
+        }
+    }
+
+    #[cfg(feature = "otel")]
+    fn record_error(&self, labels: &ErrorsLabels, span: &Span) {
+        use opentelemetry::trace::TraceContextExt;
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        let context = span.context();
+        let span_ref = context.span();
+        let span_context = span_ref.span_context();
+        let exemplar = if span_context.is_valid() {
+            Some(TraceLabels {
+                trace_id: span_context.trace_id().to_string(),
+            })
+        } else {
+            None
+        };
+
+        self.errors.get_or_create(labels).inc_by(1, exemplar);
+    }
+
+    #[cfg(not(feature = "otel"))]
+    fn record_error(&self, labels: &ErrorsLabels, _span: &Span) {
+        self.errors.get_or_create(labels).inc();
+    }
+
+    fn update_error_ratio(&self, labels: &SensorLabels, event: &ReadingEvent) {
+        let mut attempts = self.locks.lock(&self.attempts);
+        for _ in event.retried_errors.iter() {
+            attempts.record(event.instant, false);
+        }
+
+        attempts.record(event.instant, event.result.is_ok());
+        if let Some(ratio) = attempts.failure_ratio() {
+            self.error_ratio.get_or_create(labels).set(ratio);
+        }
+    }
+
+    /// Compare the width of high pulses to their long-run average. Pulses shorten when
+    /// the supply voltage of the sensor sags so a ratio well below one suggests it.
+    fn update_pulse_width_ratio(&self, labels: &SensorLabels, event: &ReadingEvent) {
+        if let Some(stats) = event.pulses {
+            let ratio = self.locks.lock(&self.pulse_widths).add(stats.mean_high);
+            self.pulse_width_ratio.get_or_create(labels).set(ratio);
+        }
+    }
+
+    fn outcome(event: &ReadingEvent) -> &'static str {
+        // Reading several samples takes more than one attempt even when none of them
+        // fail so only failed reads make a success count as retried.
+        match (&event.result, event.retried_errors.is_empty()) {
+            (Err(_), _) => "failure",
+            (Ok(_), true) => "success_first_try",
+            (Ok(_), false) => "success_retried",
+        }
+    }
+}
+
+/// Creates histograms for each sensor with the same buckets.
+#[derive(Debug, Clone)]
+struct Buckets(Arc<[f64]>);
+
+impl MetricConstructor<Histogram> for Buckets {
+    fn new_metric(&self) -> Histogram {
+        Histogram::new(self.0.iter().copied())
+    }
+}
+
+#[derive(Debug)]
+struct GaugeSettings {
+    // Names of every sensor sharing the gauges, in the order they were added
+    sensors: Vec<Option<String>>,
+    leaf_offset: f64,
+    clock: Arc<dyn Clock>,
+    clock_check: Option<ClockCheck>,
+}
+
+impl GaugeSettings {
+    /// Most recent reading for `m`, the result of `event`, marked as taken while the clock
+    /// wasn't synchronized if it wasn't according to the clock check.
+    fn reading(&self, m: Measurement, event: &ReadingEvent) -> LatestReading {
+        let reading = LatestReading::new(m, event.timestamp, event.instant);
+        match self.clock_check {
+            Some(c) if !c.is_synchronized_at(event.timestamp) => reading.unsynced(),
+            _ => reading,
+        }
+    }
+}
+
+/// Source of gauges for the most recent reading, shared by `TemperatureMetrics` and the
+/// collectors that emit the gauges.
+#[derive(Debug)]
+struct ReadingGauges {
+    locks: Locks,
+    unit: TemperatureUnit,
+    latest: Arc<LatestReadingCell>,
+    settings: RwLock<GaugeSettings>,
+}
+
+impl ReadingGauges {
+    fn settings(&self) -> RwLockReadGuard<'_, GaugeSettings> {
+        self.locks.read(&self.settings)
+    }
+
+    fn settings_mut(&self) -> RwLockWriteGuard<'_, GaugeSettings> {
+        self.locks.write(&self.settings)
+    }
+}
+
+/// Recoveries of every lock in the process, shared by each `Locks`.
+static SHARED_LOCKS: OnceLock<Locks> = OnceLock::new();
+
+/// Held by tests that poison locks so that they can count recoveries exactly.
+#[cfg(test)]
+pub(crate) static POISONING_TEST: Mutex<()> = Mutex::new(());
+
+/// Poison-tolerant access to locks used by metrics and by subscribers of readings.
+///
+/// A panic while holding a lock poisons it and would otherwise make every later update
+/// or scrape panic too. The guard is recovered instead, the lock is cleared so that it's
+/// only counted once per panic, and a warning is logged the first time it happens.
+///
+/// Every `Locks` shares the same count, exposed as `strudel_lock_recoveries_total` by
+/// `TemperatureMetrics`, so recoveries are counted and logged no matter which struct's
+/// lock was poisoned.
+#[derive(Debug, Clone)]
+pub(crate) struct Locks {
+    recoveries: Counter,
+    logged: Arc<AtomicBool>,
+}
+
+impl Default for Locks {
+    fn default() -> Self {
+        SHARED_LOCKS
+            .get_or_init(|| Locks {
+                recoveries: Counter::default(),
+                logged: Arc::default(),
+            })
+            .clone()
+    }
+}
+
+impl Locks {
+    pub(crate) fn lock<'a, T>(&self, lock: &'a Mutex<T>) -> MutexGuard<'a, T> {
+        lock.lock().unwrap_or_else(|e| {
+            lock.clear_poison();
+            self.recovered(e)
+        })
+    }
+
+    pub(crate) fn read<'a, T>(&self, lock: &'a RwLock<T>) -> RwLockReadGuard<'a, T> {
+        lock.read().unwrap_or_else(|e| {
+            lock.clear_poison();
+            self.recovered(e)
+        })
+    }
+
+    pub(crate) fn write<'a, T>(&self, lock: &'a RwLock<T>) -> RwLockWriteGuard<'a, T> {
+        lock.write().unwrap_or_else(|e| {
+            lock.clear_poison();
+            self.recovered(e)
+        })
+    }
+
+    /// Number of locks recovered so far, by any `Locks`.
+    #[cfg(test)]
+    pub(crate) fn recoveries(&self) -> u64 {
+        self.recoveries.get()
+    }
+
+    fn recovered<G>(&self, e: PoisonError<G>) -> G {
+        self.recoveries.inc();
+        if !self.logged.swap(true, Ordering::Relaxed) {
+            tracing::warn!(message = "recovered lock used by metrics after a panic, values may be inconsistent");
+        }
+
+        e.into_inner()
+    }
+}
+
+/// Emit temperature, humidity, vapour pressure deficit, and time of the most recent
+/// reading of each sensor from the same snapshot, or zeros if there hasn't been a
+/// successful read. The time is left out if the reading was taken before the clock was
+/// synchronized.
+#[derive(Debug)]
+struct ReadingCollector {
+    gauges: Arc<ReadingGauges>,
+    legacy: bool,
+    // Vapour pressure deficit is only emitted with canonical names, when enabled
+    vpd: bool,
+    // Time of the reading is left out of slim metrics
+    last_read: bool,
+}
+
+/// Values of the gauges for the most recent reading of a single sensor.
+struct ReadingValues {
+    labels: SensorLabels,
+    temperature: f64,
+    humidity: f64,
+    vpd: f64,
+    // Missing when the reading was taken before the clock was synchronized
+    read_at: Option<f64>,
+}
+
+impl ReadingCollector {
+    fn gauge<'a>(
+        name: &str,
+        help: &str,
+        values: impl Iterator<Item = (&'a SensorLabels, f64)>,
+    ) -> (Descriptor, Box<dyn LocalMetric>) {
+        let family = Family::<SensorLabels, Gauge<f64, AtomicU64>>::default();
+        for (labels, value) in values {
+            family.get_or_create(labels).set(value);
+        }
+
+        (Descriptor::new(name, help, None, None, Vec::new()), Box::new(family))
+    }
+
+    fn values(&self) -> Vec<ReadingValues> {
+        let settings = self.gauges.settings();
+        let now = settings.clock.now_wall();
+        if settings.clock_check.map(|c| c.is_synchronized_at(now)).unwrap_or(false) {
+            let restamped = self.gauges.latest.restamp_at(now, settings.clock.now_monotonic());
+            if restamped > 0 {
+                tracing::info!(
+                    message = "system clock synchronized, restamped readings",
+                    readings = restamped
+                );
+            }
+        }
+
+        let unit = self.gauges.unit;
+        settings
+            .sensors
+            .iter()
+            .map(|sensor| {
+                let labels = SensorLabels {
+                    sensor: sensor.clone().unwrap_or_default(),
                 };
 
-                self.errors.get_or_create(&labels).inc();
-                tracing::error!(message = "unable to read sensor for metric collection", error = %e);
+                match self.gauges.latest.snapshot(sensor.as_deref()) {
+                    Some(s) => ReadingValues {
+                        labels,
+                        temperature: unit.convert(s.reading.temperature),
+                        humidity: f64::from(s.reading.humidity),
+                        vpd: f64::from(VapourPressureDeficit::with_leaf_offset(
+                            s.reading.temperature,
+                            s.reading.humidity,
+                            settings.leaf_offset,
+                        )),
+                        // If we can't get the number of seconds since the epoch, report zero
+                        read_at: s.reading.is_synced().then(|| {
+                            s.reading
+                                .read_at
+                                .duration_since(UNIX_EPOCH)
+                                .map(|d| d.as_secs_f64())
+                                .unwrap_or(0.0)
+                        }),
+                    },
+                    None => ReadingValues {
+                        labels,
+                        temperature: 0.0,
+                        humidity: 0.0,
+                        vpd: 0.0,
+                        read_at: Some(0.0),
+                    },
+                }
+            })
+            .collect()
+    }
+}
+
+impl Collector for ReadingCollector {
+    fn collect<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = (Cow<'a, Descriptor>, MaybeOwned<'a, Box<dyn LocalMetric>>)> + 'a> {
+        let values = self.values();
+        let unit = self.gauges.unit;
+        let temperature = || values.iter().map(|v| (&v.labels, v.temperature));
+        let humidity = || values.iter().map(|v| (&v.labels, v.humidity));
+        // A timestamp from a clock that isn't synchronized is worse than none at all
+        let read_at = values.iter().filter_map(|v| v.read_at.map(|t| (&v.labels, t)));
+        let any_read_at = values.iter().any(|v| v.read_at.is_some());
+
+        let metrics = if self.legacy {
+            let mut metrics = Vec::new();
+            if unit == TemperatureUnit::Celsius {
+                metrics.push(Self::gauge(
+                    "pitemp_temperature_celsius",
+                    "Temperature in celsius",
+                    temperature(),
+                ));
             }
+
+            metrics.push(Self::gauge(
+                "pitemp_relative_humidity",
+                "Relative humidity (0-100)",
+                humidity(),
+            ));
+            if any_read_at && self.last_read {
+                metrics.push(Self::gauge(
+                    "pitemp_last_read_timestamp",
+                    "Timestamp of last successful read",
+                    read_at,
+                ));
+            }
+            metrics
+        } else {
+            let mut metrics = vec![
+                Self::gauge(
+                    "strudel_temperature_degrees",
+                    &format!("Temperature in {}", unit),
+                    temperature(),
+                ),
+                Self::gauge("strudel_relative_humidity", "Relative humidity (0-100)", humidity()),
+            ];
+            if self.vpd {
+                metrics.push(Self::gauge(
+                    "strudel_vapour_pressure_deficit_kpa",
+                    "Vapour pressure deficit in kilopascals",
+                    values.iter().map(|v| (&v.labels, v.vpd)),
+                ));
+            }
+            if any_read_at && self.last_read {
+                metrics.push(Self::gauge(
+                    "strudel_last_read_timestamp",
+                    "Timestamp of last successful read",
+                    read_at,
+                ));
+            }
+            metrics
         };
+
+        Box::new(
+            metrics
+                .into_iter()
+                .map(|(desc, metric)| (Cow::Owned(desc), MaybeOwned::Owned(metric))),
+        )
+    }
+}
+
+const SECS_PER_HOUR: f64 = 3600.0;
+
+/// How far back read attempts are used to compute the error ratio.
+const ERROR_RATIO_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// Weight of each read in the long-run average width of high pulses, roughly averaging
+/// the last 100 reads that captured every pulse.
+const PULSE_WIDTH_EWMA_ALPHA: f64 = 0.01;
+
+/// Gauges for how quickly temperature and humidity are changing, per hour.
+///
+/// Successful readings within a window (15 minutes by default) are kept and the slope
+/// of each is computed using linear regression after every update. Gauges are not
+/// updated until there are at least two readings within the window.
+#[derive(Debug)]
+pub struct TrendTracker {
+    unit: TemperatureUnit,
+    window: Duration,
+    locks: Locks,
+    samples: Mutex<VecDeque<TrendSample>>,
+    temperature: Gauge<f64, AtomicU64>,
+    humidity: Gauge<f64, AtomicU64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TrendSample {
+    instant: Instant,
+    temperature: f64,
+    humidity: f64,
+}
+
+impl TrendTracker {
+    pub const DEFAULT_WINDOW: Duration = Duration::from_secs(15 * 60);
+
+    pub fn new(reg: &mut Registry, unit: TemperatureUnit) -> Self {
+        let temperature = Gauge::<f64, AtomicU64>::default();
+        let humidity = Gauge::<f64, AtomicU64>::default();
+
+        reg.register(
+            "strudel_temperature_change_per_hour",
+            format!("Rate of change of temperature in {} per hour", unit),
+            temperature.clone(),
+        );
+        reg.register(
+            "strudel_relative_humidity_change_per_hour",
+            "Rate of change of relative humidity per hour",
+            humidity.clone(),
+        );
+
+        Self {
+            unit,
+            window: Self::DEFAULT_WINDOW,
+            locks: Locks::default(),
+            samples: Mutex::new(VecDeque::new()),
+            temperature,
+            humidity,
+        }
+    }
+
+    /// Set how far back readings are used to compute the rate of change.
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Update trends based on the result of a read, ignoring failed reads. Intended
+    /// to be used as a subscriber of a `SensorWorker`.
+    pub fn update(&self, event: &ReadingEvent) {
+        let m = match &event.result {
+            Ok(m) => m,
+            Err(_) => return,
+        };
+
+        let mut samples = self.locks.lock(&self.samples);
+        samples.push_back(TrendSample {
+            instant: event.instant,
+            temperature: self.unit.convert(m.temperature),
+            humidity: m.humidity.into(),
+        });
+
+        // Drop anything that has fallen out of the window, relative to the newest reading
+        // instead of the current time so that trends don't depend on when we're called.
+        if let Some(cutoff) = event.instant.checked_sub(self.window) {
+            while samples.front().map(|s| s.instant < cutoff).unwrap_or(false) {
+                samples.pop_front();
+            }
+        }
+
+        let first = samples[0].instant;
+        let offset = |s: &TrendSample| s.instant.saturating_duration_since(first).as_secs_f64();
+
+        let temperature: Vec<(f64, f64)> = samples.iter().map(|s| (offset(s), s.temperature)).collect();
+        let humidity: Vec<(f64, f64)> = samples.iter().map(|s| (offset(s), s.humidity)).collect();
+
+        if let Some(slope) = slope_per_hour(&temperature) {
+            self.temperature.set(slope);
+        }
+
+        if let Some(slope) = slope_per_hour(&humidity) {
+            self.humidity.set(slope);
+        }
+    }
+}
+
+/// Compute the slope of a least squares fit of `(seconds, value)` pairs, converted
+/// to change in value per hour. `None` is returned if there are fewer than two points
+/// or all points were taken at the same time.
+fn slope_per_hour(points: &[(f64, f64)]) -> Option<f64> {
+    if points.len() < 2 {
+        return None;
+    }
+
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+
+    let (cov, var) = points.iter().fold((0.0, 0.0), |(cov, var), (x, y)| {
+        let dx = x - mean_x;
+        (cov + dx * (y - mean_y), var + dx * dx)
+    });
+
+    if var == 0.0 {
+        return None;
+    }
+
+    Some(cov / var * SECS_PER_HOUR)
+}
+
+/// Humidity at or above this is saturated, see `SaturationTracker`.
+const HUMIDITY_SATURATED: f64 = 99.9;
+
+/// Humidity at or below this is zeroed, see `SaturationTracker`.
+const HUMIDITY_ZEROED: f64 = 0.1;
+
+/// Extreme of the humidity range a reading is pinned at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HumidityRail {
+    /// At or above 99.9%.
+    Saturated,
+    /// At or below 0.1%.
+    Zeroed,
+}
+
+impl HumidityRail {
+    /// Extreme `humidity` is pinned at, if any.
+    pub fn of(humidity: f64) -> Option<Self> {
+        if humidity >= HUMIDITY_SATURATED {
+            Some(HumidityRail::Saturated)
+        } else if humidity <= HUMIDITY_ZEROED {
+            Some(HumidityRail::Zeroed)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HumidityRail::Saturated => "saturated",
+            HumidityRail::Zeroed => "zeroed",
+        }
+    }
+}
+
+/// How long humidity has been pinned at one extreme, see `SaturationTracker::update`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pinned {
+    pub rail: HumidityRail,
+    /// Time since the first reading at the extreme, zero for the first reading.
+    pub duration: Duration,
+    /// True for the first reading at least the warning threshold after the first one.
+    pub warn: bool,
+}
+
+/// Tracks how long humidity has continuously been at either extreme of its range.
+///
+/// Sensors stuck at 100% after condensation, or at 0%, keep returning valid readings so
+/// they can only be told apart from real conditions by how long they stay there. The
+/// duration resets as soon as a reading moves off the extreme.
+#[derive(Debug, Clone)]
+pub struct SaturationTracker {
+    warn_after: Duration,
+    current: Option<(HumidityRail, Instant, bool)>,
+}
+
+impl SaturationTracker {
+    /// Default time humidity can stay at an extreme before warning about it.
+    pub const DEFAULT_WARN_AFTER: Duration = Duration::from_secs(60 * 60);
+
+    pub fn new(warn_after: Duration) -> Self {
+        Self {
+            warn_after,
+            current: None,
+        }
+    }
+
+    /// Record a reading of `humidity` at `now`, returning the extreme it's pinned at and
+    /// for how long, `None` if it isn't pinned at either.
+    pub fn update(&mut self, humidity: f64, now: Instant) -> Option<Pinned> {
+        let Some(rail) = HumidityRail::of(humidity) else {
+            self.current = None;
+            return None;
+        };
+
+        let (since, warned) = match self.current {
+            Some((r, since, warned)) if r == rail => (since, warned),
+            _ => (now, false),
+        };
+
+        let duration = now.saturating_duration_since(since);
+        let warn = !warned && duration >= self.warn_after;
+        self.current = Some((rail, since, warned || warn));
+        Some(Pinned { rail, duration, warn })
+    }
+}
+
+/// Gauges for how long humidity has been saturated or zeroed, see `SaturationTracker`.
+/// A warning is logged once each time humidity stays at either extreme for longer than
+/// the threshold.
+#[derive(Debug)]
+pub struct SaturationMetrics {
+    locks: Locks,
+    tracker: Mutex<SaturationTracker>,
+    clock: Arc<dyn Clock>,
+    saturated: Gauge<f64, AtomicU64>,
+    zeroed: Gauge<f64, AtomicU64>,
+}
+
+impl SaturationMetrics {
+    /// Create metrics that warn when humidity stays at an extreme for `warn_after`.
+    pub fn new(reg: &mut Registry, warn_after: Duration) -> Self {
+        let saturated = Gauge::<f64, AtomicU64>::default();
+        let zeroed = Gauge::<f64, AtomicU64>::default();
+
+        reg.register(
+            "strudel_humidity_saturated_seconds",
+            "Time in seconds relative humidity has continuously been at or above 99.9%",
+            saturated.clone(),
+        );
+        reg.register(
+            "strudel_humidity_zeroed_seconds",
+            "Time in seconds relative humidity has continuously been at or below 0.1%",
+            zeroed.clone(),
+        );
+
+        Self {
+            locks: Locks::default(),
+            tracker: Mutex::new(SaturationTracker::new(warn_after)),
+            clock: SystemClock::shared(),
+            saturated,
+            zeroed,
+        }
+    }
+
+    /// Use `clock` for the time of each reading. Defaults to the system clock.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Update durations based on the result of a read, ignoring failed reads. Intended
+    /// to be used as a subscriber of a `SensorWorker`. Returns true when humidity has just
+    /// been pinned for long enough to log a warning, once each time it gets pinned.
+    pub fn update(&self, event: &ReadingEvent) -> bool {
+        let Ok(m) = &event.result else {
+            return false;
+        };
+
+        let mut tracker = self.locks.lock(&self.tracker);
+        let pinned = tracker.update(f64::from(m.humidity), self.clock.now_monotonic());
+        let (saturated, zeroed) = match pinned {
+            Some(p) if p.rail == HumidityRail::Saturated => (p.duration, Duration::ZERO),
+            Some(p) => (Duration::ZERO, p.duration),
+            None => (Duration::ZERO, Duration::ZERO),
+        };
+
+        self.saturated.set(saturated.as_secs_f64());
+        self.zeroed.set(zeroed.as_secs_f64());
+
+        match pinned.filter(|p| p.warn) {
+            Some(p) => {
+                tracing::warn!(
+                    message = "humidity has been pinned at an extreme for a long time, the sensor may have failed",
+                    humidity = %m.humidity,
+                    rail = p.rail.as_str(),
+                    duration_secs = p.duration.as_secs(),
+                );
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Collection of Prometheus metrics about the exposition of metrics themselves: how
+/// many times metrics have been scraped, how long encoding them takes, and when the
+/// last scrape happened.
+#[derive(Debug)]
+pub struct HttpMetrics {
+    scrapes: Counter,
+    encode_duration: Histogram,
+    encode_failures: Counter,
+    last_scrape: Gauge<f64, AtomicU64>,
+    locks: Locks,
+    latest_scrape: Mutex<Option<(SystemTime, Instant)>>,
+    previous_scrape: Arc<Mutex<Option<Instant>>>,
+    scrape_read_timeouts: Counter,
+    deadlines_exceeded: Counter,
+    reconfigurations: Counter,
+    clock: Arc<dyn Clock>,
+}
+
+impl HttpMetrics {
+    pub fn new(reg: &mut Registry) -> Self {
+        Self::with_clock(reg, SystemClock::shared())
+    }
+
+    /// Create metrics that use `clock` for the time of scrapes instead of the system clock.
+    pub fn with_clock(reg: &mut Registry, clock: Arc<dyn Clock>) -> Self {
+        let scrapes = Counter::default();
+        // Encoding is expected to take well under a millisecond so buckets start
+        // at 100us and go up to about 200ms.
+        let encode_duration = Histogram::new(exponential_buckets(0.0001, 2.0, 12));
+        let encode_failures = Counter::default();
+        let last_scrape = Gauge::<f64, AtomicU64>::default();
+        let locks = Locks::default();
+        let previous_scrape = Arc::new(Mutex::new(None));
+        let scrape_read_timeouts = Counter::default();
+        let deadlines_exceeded = Counter::default();
+        let reconfigurations = Counter::default();
+
+        reg.register("strudel_scrapes", "Number of metrics scrapes", scrapes.clone());
+        reg.register(
+            "strudel_scrape_encode_duration_seconds",
+            "Time spent encoding metrics for a scrape",
+            encode_duration.clone(),
+        );
+        reg.register(
+            "strudel_encode_failures",
+            "Number of groups of metrics that could not be encoded for a scrape",
+            encode_failures.clone(),
+        );
+        reg.register(
+            "strudel_last_scrape_timestamp",
+            "Timestamp of the previous scrape of metrics",
+            last_scrape.clone(),
+        );
+        reg.register_collector(Box::new(ScrapeGapCollector {
+            locks: locks.clone(),
+            previous_scrape: previous_scrape.clone(),
+            clock: clock.clone(),
+        }));
+        reg.register(
+            "strudel_scrape_read_timeouts",
+            "Number of scrapes that timed out waiting for a fresh read of the sensor",
+            scrape_read_timeouts.clone(),
+        );
+        reg.register(
+            "strudel_scrape_deadline_exceeded",
+            "Number of scrapes abandoned because they would finish after the scraper gave up",
+            deadlines_exceeded.clone(),
+        );
+        reg.register(
+            "strudel_sensor_reconfigurations",
+            "Number of times the sensor was replaced at runtime via the HTTP API",
+            reconfigurations.clone(),
+        );
+
+        Self {
+            scrapes,
+            encode_duration,
+            encode_failures,
+            last_scrape,
+            locks,
+            latest_scrape: Mutex::new(None),
+            previous_scrape,
+            scrape_read_timeouts,
+            deadlines_exceeded,
+            reconfigurations,
+            clock,
+        }
+    }
+
+    /// Record that a scrape of metrics has been started.
+    pub fn scrape(&self) {
+        self.scrape_at(self.clock.now_wall(), self.clock.now_monotonic());
+    }
+
+    /// Record that a scrape of metrics has been started at a particular time according
+    /// to the system clock (`at`) and the monotonic clock (`instant`).
+    ///
+    /// Scrapes are recorded before metrics are encoded so the timestamp exposed by
+    /// `strudel_last_scrape_timestamp` is the time of the scrape before the one that
+    /// is being encoded: it lags by one scrape. This makes the gap computed while
+    /// encoding the time between the previous scrape and this one.
+    pub fn scrape_at(&self, at: SystemTime, instant: Instant) {
+        self.scrapes.inc();
+        let previous = self.locks.lock(&self.latest_scrape).replace((at, instant));
+        self.last_scrape.set(previous.map(|(t, _)| unix_secs(t)).unwrap_or(0.0));
+        *self.locks.lock(&self.previous_scrape) = previous.map(|(_, i)| i);
+    }
+
+    /// Get the time of the most recent scrape of metrics, if there has been one.
+    pub fn latest_scrape(&self) -> Option<SystemTime> {
+        self.locks.lock(&self.latest_scrape).map(|(t, _)| t)
+    }
+
+    /// Record how long encoding metrics for a scrape took.
+    pub fn encoded(&self, duration: Duration) {
+        self.encode_duration.observe(duration.as_secs_f64());
+    }
+
+    /// Record groups of metrics that couldn't be encoded for a scrape.
+    pub fn encode_failed(&self, groups: usize) {
+        self.encode_failures.inc_by(groups as u64);
+    }
+
+    /// Record a scrape that gave up waiting for a fresh read of the sensor.
+    pub fn scrape_read_timed_out(&self) {
+        self.scrape_read_timeouts.inc();
+    }
+
+    /// Record a scrape that was abandoned because it didn't finish before its deadline.
+    pub fn deadline_exceeded(&self) {
+        self.deadlines_exceeded.inc();
+    }
+
+    /// Record the sensor being replaced at runtime.
+    pub fn reconfigured(&self) {
+        self.reconfigurations.inc();
+    }
+}
+
+/// Compute the time since the previous scrape when metrics are collected instead of
+/// when scraped so that the gap keeps growing when nothing is scraping `strudel`.
+#[derive(Debug)]
+struct ScrapeGapCollector {
+    locks: Locks,
+    previous_scrape: Arc<Mutex<Option<Instant>>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl ScrapeGapCollector {
+    fn gap_at(&self, now: Instant) -> f64 {
+        self.locks
+            .lock(&self.previous_scrape)
+            .map(|t| now.saturating_duration_since(t).as_secs_f64())
+            .unwrap_or(0.0)
+    }
+}
+
+impl Collector for ScrapeGapCollector {
+    fn collect<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = (Cow<'a, Descriptor>, MaybeOwned<'a, Box<dyn LocalMetric>>)> + 'a> {
+        let desc = Descriptor::new(
+            "strudel_scrape_gap_seconds",
+            "Time since the previous scrape of metrics, in seconds",
+            None,
+            None,
+            Vec::new(),
+        );
+        let metric: Box<dyn LocalMetric> = Box::new(ConstGauge::new(self.gap_at(self.clock.now_monotonic())));
+        Box::new(std::iter::once((Cow::Owned(desc), MaybeOwned::Owned(metric))))
+    }
+}
+
+/// Seconds since the UNIX epoch, zero if the time is before it.
+fn unix_secs(t: SystemTime) -> f64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or(0.0)
+}
+
+const EOF_MARKER: &str = "# EOF\n";
+
+/// Families of metrics that are registered, all of them by default.
+///
+/// Families are combined like bit flags, e.g. `MetricsConfig::PROCESS | MetricsConfig::DEBUG`,
+/// and disabled with `disable`. Some families are groups of `Registries` and are left out
+/// of the output by `Registries::with_config`, the rest are individual metrics left out by
+/// `TemperatureMetrics::with_config`. Disabled families aren't registered at all rather than
+/// staying at zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MetricsConfig {
+    enabled: u32,
+}
+
+impl MetricsConfig {
+    /// `strudel_vapour_pressure_deficit_kpa`
+    pub const VAPOUR_PRESSURE_DEFICIT: Self = Self { enabled: 1 << 0 };
+    /// `strudel_temperature_celsius_distribution` and `strudel_relative_humidity_distribution`
+    pub const HISTOGRAMS: Self = Self { enabled: 1 << 1 };
+    /// `strudel_error_ratio_5m`
+    pub const ERROR_RATIO: Self = Self { enabled: 1 << 2 };
+    /// `strudel_pulse_width_ratio`
+    pub const PULSE_WIDTH_RATIO: Self = Self { enabled: 1 << 3 };
+    /// The `trend` group, see `TrendTracker`
+    pub const TREND: Self = Self { enabled: 1 << 4 };
+    /// The `debug` group, see `DebugMetrics`
+    pub const DEBUG: Self = Self { enabled: 1 << 5 };
+    /// The `process` group, see `ProcessMetrics` and `ClockMetrics`
+    pub const PROCESS: Self = Self { enabled: 1 << 6 };
+    /// The `build` group, see `BuildMetrics`
+    pub const BUILD: Self = Self { enabled: 1 << 7 };
+    /// The `config` group, see `ConfigMetrics`
+    pub const CONFIG: Self = Self { enabled: 1 << 8 };
+    /// The `read_loop` group, see `ReadLoopMetrics`
+    pub const READ_LOOP: Self = Self { enabled: 1 << 9 };
+    /// The `health` group, see `HealthMetrics`
+    pub const HEALTH: Self = Self { enabled: 1 << 10 };
+    /// The `push` group, see `PushMetrics`
+    pub const PUSH: Self = Self { enabled: 1 << 11 };
+    /// The `http` group, see `HttpMetrics`
+    pub const HTTP: Self = Self { enabled: 1 << 12 };
+    /// The `fusion` group, see `FusionMetrics`
+    pub const FUSION: Self = Self { enabled: 1 << 13 };
+    /// The `canary` group, see `CanaryMetrics`
+    pub const CANARY: Self = Self { enabled: 1 << 14 };
+    /// The `spikes` group, see `SpikeMetrics`
+    pub const SPIKES: Self = Self { enabled: 1 << 15 };
+
+    /// Names of every family, as used by `--disable-metric`, in the order they're listed.
+    pub const FAMILIES: &'static [(&'static str, Self)] = &[
+        ("vapour_pressure_deficit", Self::VAPOUR_PRESSURE_DEFICIT),
+        ("histograms", Self::HISTOGRAMS),
+        ("error_ratio", Self::ERROR_RATIO),
+        ("pulse_width_ratio", Self::PULSE_WIDTH_RATIO),
+        ("trend", Self::TREND),
+        ("debug", Self::DEBUG),
+        ("process", Self::PROCESS),
+        ("build", Self::BUILD),
+        ("config", Self::CONFIG),
+        ("read_loop", Self::READ_LOOP),
+        ("health", Self::HEALTH),
+        ("push", Self::PUSH),
+        ("http", Self::HTTP),
+        ("fusion", Self::FUSION),
+        ("canary", Self::CANARY),
+        ("spikes", Self::SPIKES),
+    ];
+
+    /// Every family enabled.
+    pub fn all() -> Self {
+        Self::FAMILIES.iter().fold(Self { enabled: 0 }, |acc, (_, f)| acc | *f)
+    }
+
+    /// Family with the given name, `None` if there's no such family.
+    pub fn family(name: &str) -> Option<Self> {
+        Self::FAMILIES.iter().find(|(n, _)| *n == name).map(|(_, f)| *f)
+    }
+
+    /// Don't register `families`.
+    pub fn disable(self, families: Self) -> Self {
+        Self {
+            enabled: self.enabled & !families.enabled,
+        }
+    }
+
+    /// Return true if all of `families` are registered.
+    pub fn is_enabled(&self, families: Self) -> bool {
+        self.enabled & families.enabled == families.enabled
+    }
+
+    /// Names of families that aren't registered.
+    pub fn disabled_names(&self) -> Vec<&'static str> {
+        Self::FAMILIES
+            .iter()
+            .filter(|(_, f)| !self.is_enabled(*f))
+            .map(|(n, _)| *n)
+            .collect()
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+impl BitOr for MetricsConfig {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self {
+            enabled: self.enabled | rhs.enabled,
+        }
+    }
+}
+
+/// Metrics registered in a separate `Registry` per group so that an error encoding
+/// one group doesn't prevent the others from being exposed.
+#[derive(Debug, Default)]
+pub struct Registries {
+    groups: Vec<(String, Registry)>,
+    config: MetricsConfig,
+    // Registries of disabled groups, never encoded
+    disabled: Vec<(String, Registry)>,
+}
+
+impl Registries {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create registries that leave out groups named after families disabled by `config`.
+    pub fn with_config(config: MetricsConfig) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    /// Get the registry for the named group of metrics, creating it if needed. Metrics
+    /// registered in a group disabled by `MetricsConfig` are never encoded.
+    pub fn group(&mut self, name: &str) -> &mut Registry {
+        let enabled = MetricsConfig::family(name)
+            .map(|f| self.config.is_enabled(f))
+            .unwrap_or(true);
+        let groups = if enabled { &mut self.groups } else { &mut self.disabled };
+
+        let idx = match groups.iter().position(|(n, _)| n == name) {
+            Some(i) => i,
+            None => {
+                groups.push((name.to_owned(), Registry::default()));
+                groups.len() - 1
+            }
+        };
+
+        &mut groups[idx].1
+    }
+
+    /// Encode all groups in the OpenMetrics text format, skipping groups that can't be
+    /// encoded. An error is returned only if there are groups and none can be encoded.
+    pub fn encode(&self) -> Result<Encoded, fmt::Error> {
+        let mut encoded = Encoded::default();
+
+        for (name, registry) in self.groups.iter() {
+            let mut buf = String::new();
+            match text::encode(&mut buf, registry) {
+                Ok(_) => {
+                    let start = encoded.text.len();
+                    encoded.text.push_str(buf.strip_suffix(EOF_MARKER).unwrap_or(&buf));
+                    encoded.groups.push((name.clone(), start..encoded.text.len()));
+                }
+                Err(_) => encoded.failed.push(name.clone()),
+            }
+        }
+
+        if !self.groups.is_empty() && encoded.failed.len() == self.groups.len() {
+            return Err(fmt::Error);
+        }
+
+        encoded.text.push_str(EOF_MARKER);
+        Ok(encoded)
+    }
+}
+
+/// Metrics encoded by `Registries::encode` and the names of any groups that couldn't be.
+#[derive(Debug, Default)]
+pub struct Encoded {
+    pub text: String,
+    pub failed: Vec<String>,
+    groups: Vec<(String, Range<usize>)>,
+}
+
+impl Encoded {
+    /// Text of the named group of metrics, `None` if there's no such group or it couldn't
+    /// be encoded.
+    pub fn group(&self, name: &str) -> Option<&str> {
+        self.groups
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, r)| &self.text[r.clone()])
+    }
+}
+
+/// Collection of Prometheus metrics about the overall health of the sensor.
+///
+/// Every metric is labeled with the name of the sensor, empty if it doesn't have one. Use
+/// `sensor` to get metrics for each other sensor, sharing the same families.
+#[derive(Debug, Clone)]
+pub struct HealthMetrics {
+    labels: Arc<RwLock<SensorLabels>>,
+    locks: Locks,
+    healthy: Family<SensorLabels, Gauge>,
+    stuck: Family<SensorLabels, Gauge>,
+    transitions: Family<TransitionLabels, Counter>,
+}
+
+impl HealthMetrics {
+    pub fn new(reg: &mut Registry) -> Self {
+        let healthy = Family::<SensorLabels, Gauge>::default();
+        let stuck = Family::<SensorLabels, Gauge>::default();
+        let transitions = Family::<TransitionLabels, Counter>::default();
+
+        reg.register(
+            "strudel_sensor_healthy",
+            "Whether the sensor is healthy (1) or degraded (0)",
+            healthy.clone(),
+        );
+        reg.register(
+            "strudel_sensor_stuck",
+            "Whether the sensor is stuck returning identical frames for every read (1) or not (0)",
+            stuck.clone(),
+        );
+        reg.register(
+            "strudel_state_transitions",
+            "Number of sensor state changes by new state",
+            transitions.clone(),
+        );
+
+        let metrics = Self {
+            labels: Arc::new(RwLock::new(SensorLabels { sensor: String::new() })),
+            locks: Locks::default(),
+            healthy,
+            stuck,
+            transitions,
+        };
+
+        metrics.create_series(&metrics.labels());
+        metrics
+    }
+
+    /// Metrics for another sensor, labeled with `name` and sharing the families of these
+    /// metrics. Names must be unique.
+    pub fn sensor(&self, name: Option<String>) -> Self {
+        let labels = SensorLabels {
+            sensor: name.unwrap_or_default(),
+        };
+
+        self.create_series(&labels);
+        Self {
+            labels: Arc::new(RwLock::new(labels)),
+            ..self.clone()
+        }
+    }
+
+    /// Label metrics with `name` instead of leaving them unnamed. Default unnamed.
+    pub fn sensor_name(self, name: Option<String>) -> Self {
+        self.rename(name);
+        self
+    }
+
+    /// Label metrics with `name` instead of the current name of the sensor, e.g. after
+    /// replacing it. Metrics labeled with the current name are removed and the renamed
+    /// sensor is assumed to be healthy, like a new sensor.
+    pub fn rename(&self, name: Option<String>) {
+        let labels = SensorLabels {
+            sensor: name.unwrap_or_default(),
+        };
+
+        let previous = std::mem::replace(&mut *self.locks.write(&self.labels), labels.clone());
+        if previous == labels {
+            return;
+        }
+
+        self.healthy.remove(&previous);
+        self.stuck.remove(&previous);
+        for state in [SensorState::Healthy, SensorState::Degraded] {
+            self.transitions.remove(&TransitionLabels {
+                sensor: previous.sensor.clone(),
+                to: state.as_label().to_owned(),
+            });
+        }
+
+        self.create_series(&labels);
+    }
+
+    /// Record the sensor changing to a new state.
+    pub fn transition(&self, to: SensorState) {
+        let labels = self.labels();
+        let transition = TransitionLabels {
+            sensor: labels.sensor.clone(),
+            to: to.as_label().to_owned(),
+        };
+
+        self.transitions.get_or_create(&transition).inc();
+        self.healthy
+            .get_or_create(&labels)
+            .set(if to == SensorState::Healthy { 1 } else { 0 });
+    }
+
+    /// Record whether the sensor is stuck, see `StuckDetector`.
+    pub fn stuck(&self, stuck: bool) {
+        self.stuck.get_or_create(&self.labels()).set(if stuck { 1 } else { 0 });
+    }
+
+    fn labels(&self) -> SensorLabels {
+        self.locks.read(&self.labels).clone()
+    }
+
+    fn create_series(&self, labels: &SensorLabels) {
+        // The sensor is assumed to be healthy until enough reads fail
+        self.healthy.get_or_create(labels).set(1);
+        let _ = self.stuck.get_or_create(labels);
+    }
+}
+
+/// Power cycles of the sensor by a `PowerController`.
+#[derive(Debug, Clone)]
+pub struct PowerMetrics {
+    cycles: Family<PowerCycleLabels, Counter>,
+}
+
+impl PowerMetrics {
+    pub fn new(reg: &mut Registry) -> Self {
+        let cycles = Family::<PowerCycleLabels, Counter>::default();
+        reg.register(
+            "strudel_power_cycles",
+            "Number of times power to the sensor was cut by reason",
+            cycles.clone(),
+        );
+
+        Self { cycles }
+    }
+
+    /// Record power to the sensor being cut for `reason`.
+    pub fn cycled(&self, reason: PowerCycleReason) {
+        let labels = PowerCycleLabels {
+            reason: reason.as_label().to_owned(),
+        };
+
+        self.cycles.get_or_create(&labels).inc();
+    }
+}
+
+/// Timing calibration of the data pin of the sensor, see `TimingCalibration`.
+#[derive(Debug, Clone)]
+pub struct TimingMetrics {
+    cycles_per_us: Gauge<f64, AtomicU64>,
+}
+
+impl TimingMetrics {
+    pub fn new(reg: &mut Registry) -> Self {
+        let cycles_per_us = Gauge::<f64, AtomicU64>::default();
+
+        reg.register(
+            "strudel_timing_cycles_per_us",
+            "Number of times the data pin can be checked per microsecond, zero if timing calibration failed",
+            cycles_per_us.clone(),
+        );
+
+        Self { cycles_per_us }
+    }
+
+    /// Set the timing calibration in effect, `None` if calibration failed.
+    pub fn set(&self, cycles_per_us: Option<f64>) {
+        self.cycles_per_us.set(cycles_per_us.unwrap_or(0.0));
+    }
+}
+
+/// Metrics about whether the loop reading the sensor is still running, regardless of
+/// whether reads of the sensor are succeeding.
+///
+/// The read loop is healthy if a read has been attempted within twice the refresh
+/// interval. This is computed each time metrics are collected (i.e. when Prometheus
+/// scrapes `strudel`) so that a loop that has stopped entirely is detected.
+#[derive(Debug, Clone)]
+pub struct ReadLoopMetrics {
+    inner: Arc<ReadLoopState>,
+}
+
+#[derive(Debug)]
+struct ReadLoopState {
+    max_age: Duration,
+    locks: Locks,
+    heartbeat: Mutex<Option<SystemTime>>,
+    last_attempt: Mutex<Option<Instant>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl ReadLoopMetrics {
+    pub fn new(reg: &mut Registry, refresh_interval: Duration) -> Self {
+        Self::with_clock(reg, refresh_interval, SystemClock::shared())
+    }
+
+    /// Create metrics that use `clock` for the current time instead of the system clock.
+    pub fn with_clock(reg: &mut Registry, refresh_interval: Duration, clock: Arc<dyn Clock>) -> Self {
+        let metrics = Self {
+            inner: Arc::new(ReadLoopState {
+                max_age: refresh_interval * 2,
+                locks: Locks::default(),
+                heartbeat: Mutex::new(None),
+                last_attempt: Mutex::new(None),
+                clock,
+            }),
+        };
+
+        reg.register_collector(Box::new(metrics.clone()));
+        metrics
+    }
+
+    /// Record the read loop waking up to read the sensor.
+    pub fn tick(&self) {
+        self.tick_at(self.inner.clock.now_wall());
+    }
+
+    /// Record the read loop waking up to read the sensor at a particular time.
+    pub fn tick_at(&self, at: SystemTime) {
+        *self.inner.locks.lock(&self.inner.heartbeat) = Some(at);
+    }
+
+    /// Record a read of the sensor being attempted, successful or not.
+    pub fn attempted(&self) {
+        self.attempted_at(self.inner.clock.now_monotonic());
+    }
+
+    /// Record a read of the sensor being attempted at a particular monotonic time.
+    pub fn attempted_at(&self, at: Instant) {
+        *self.inner.locks.lock(&self.inner.last_attempt) = Some(at);
+    }
+
+    fn is_healthy_at(&self, now: Instant) -> bool {
+        self.inner
+            .locks
+            .lock(&self.inner.last_attempt)
+            .map(|t| now.saturating_duration_since(t) <= self.inner.max_age)
+            .unwrap_or(false)
+    }
+
+    fn heartbeat_secs(&self) -> f64 {
+        self.inner
+            .locks
+            .lock(&self.inner.heartbeat)
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs_f64())
+            .unwrap_or(0.0)
+    }
+}
+
+impl Collector for ReadLoopMetrics {
+    fn collect<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = (Cow<'a, Descriptor>, MaybeOwned<'a, Box<dyn LocalMetric>>)> + 'a> {
+        let healthy = if self.is_healthy_at(self.inner.clock.now_monotonic()) {
+            1
+        } else {
+            0
+        };
+        let metrics: Vec<(Descriptor, Box<dyn LocalMetric>)> = vec![
+            (
+                Descriptor::new(
+                    "strudel_healthy",
+                    "Whether a read of the sensor was attempted within twice the refresh interval",
+                    None,
+                    None,
+                    Vec::new(),
+                ),
+                Box::new(ConstGauge::new(healthy)),
+            ),
+            (
+                Descriptor::new(
+                    "strudel_read_loop_alive",
+                    "Timestamp of the last time the read loop woke up to read the sensor",
+                    None,
+                    None,
+                    Vec::new(),
+                ),
+                Box::new(ConstGauge::new(self.heartbeat_secs())),
+            ),
+        ];
+
+        Box::new(
+            metrics
+                .into_iter()
+                .map(|(desc, metric)| (Cow::Owned(desc), MaybeOwned::Owned(metric))),
+        )
+    }
+}
+
+/// Collection of Prometheus metrics about pushing readings to external systems.
+#[derive(Debug, Clone)]
+pub struct PushMetrics {
+    errors: Family<PushLabels, Counter>,
+}
+
+impl PushMetrics {
+    pub fn new(reg: &mut Registry) -> Self {
+        let errors = Family::<PushLabels, Counter>::default();
+
+        reg.register(
+            "strudel_push_errors",
+            "Number of failed or dropped pushes by target",
+            errors.clone(),
+        );
+
+        Self { errors }
+    }
+
+    /// Get the error counter for a particular push target.
+    pub fn errors(&self, target: &str) -> Counter {
+        let labels = PushLabels {
+            target: target.to_owned(),
+        };
+
+        self.errors.get_or_create(&labels).clone()
+    }
+}
+
+/// Effective configuration of `strudel` exposed as metrics by `ConfigMetrics`.
+///
+/// Values should be set after any validation or adjustment of user provided
+/// options has been done.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigOptions {
+    pub sensor: Option<String>,
+    pub bcm_pin: u8,
+    pub refresh_interval: Duration,
+}
+
+/// Gauges for the effective configuration of `strudel`, set at startup and each time
+/// a sensor is replaced.
+///
+/// Per-sensor values are labeled with the name of the sensor, empty if it doesn't have one.
+/// There's a series for each configured sensor.
+#[derive(Debug, Clone)]
+pub struct ConfigMetrics {
+    bcm_pin: Family<SensorLabels, Gauge>,
+    refresh_interval: Family<SensorLabels, Gauge<f64, AtomicU64>>,
+}
+
+impl ConfigMetrics {
+    pub fn register(reg: &mut Registry, sensors: &[ConfigOptions]) -> Self {
+        let metrics = Self {
+            bcm_pin: Family::default(),
+            refresh_interval: Family::default(),
+        };
+
+        for opts in sensors {
+            metrics.set(opts);
+        }
+
+        reg.register(
+            "strudel_bcm_pin",
+            "BCM GPIO pin number the sensor is connected to",
+            metrics.bcm_pin.clone(),
+        );
+        reg.register(
+            "strudel_refresh_interval_seconds",
+            "Interval the sensor is read at in seconds",
+            metrics.refresh_interval.clone(),
+        );
+
+        metrics
+    }
+
+    /// Replace the configuration exposed for the sensor named `previous`, removing values
+    /// labeled with its name. Other sensors are left alone.
+    pub fn update(&self, previous: Option<&str>, opts: &ConfigOptions) {
+        let labels = SensorLabels {
+            sensor: previous.unwrap_or_default().to_owned(),
+        };
+
+        self.bcm_pin.remove(&labels);
+        self.refresh_interval.remove(&labels);
+        self.set(opts);
+    }
+
+    fn set(&self, opts: &ConfigOptions) {
+        let labels = SensorLabels {
+            sensor: opts.sensor.clone().unwrap_or_default(),
+        };
+
+        self.bcm_pin.get_or_create(&labels).set(opts.bcm_pin as i64);
+        self.refresh_interval
+            .get_or_create(&labels)
+            .set(opts.refresh_interval.as_secs_f64());
+    }
+}
+
+/// Collection of Prometheus metrics exposing the bytes decoded from the sensor, for
+/// diagnosing readings that have a valid checksum but unexpected values. Gauges are
+/// updated for every attempt to read the sensor that decoded bytes, including attempts
+/// with an invalid checksum.
+#[derive(Debug, Clone)]
+pub struct DebugMetrics {
+    bytes: Family<RawByteLabels, Gauge>,
+    temperature_word: Gauge,
+    humidity_word: Gauge,
+}
+
+impl DebugMetrics {
+    pub fn new(reg: &mut Registry) -> Self {
+        let bytes = Family::<RawByteLabels, Gauge>::default();
+        let temperature_word = Gauge::default();
+        let humidity_word = Gauge::default();
+
+        // Create every byte up front so that all of them are exposed before the first read
+        for i in 0..5 {
+            bytes.get_or_create(&RawByteLabels { index: i.to_string() }).set(0);
+        }
+
+        reg.register(
+            "strudel_debug_raw_byte",
+            "Bytes decoded from the most recent read of the sensor by index",
+            bytes.clone(),
+        );
+        reg.register(
+            "strudel_debug_raw_temperature_word",
+            "Temperature word decoded from the most recent read of the sensor",
+            temperature_word.clone(),
+        );
+        reg.register(
+            "strudel_debug_raw_humidity_word",
+            "Humidity word decoded from the most recent read of the sensor",
+            humidity_word.clone(),
+        );
+
+        Self {
+            bytes,
+            temperature_word,
+            humidity_word,
+        }
+    }
+
+    /// Update gauges based on the bytes decoded by each attempt of a read.
+    pub fn update(&self, event: &ReadingEvent) {
+        for raw in event.raw.iter() {
+            for (i, b) in raw.bytes.iter().enumerate() {
+                self.bytes
+                    .get_or_create(&RawByteLabels { index: i.to_string() })
+                    .set(i64::from(*b));
+            }
+
+            self.temperature_word.set(i64::from(raw.temperature_word()));
+            self.humidity_word.set(i64::from(raw.humidity_word()));
+        }
+    }
+}
+
+/// Estimators for the temperature and humidity of a group of fused sensors
+#[derive(Debug)]
+struct FusedSensors {
+    name: String,
+    temperature: FusionEstimator,
+    humidity: FusionEstimator,
+}
+
+/// Collection of Prometheus metrics for estimates fused from the readings of groups of
+/// sensors measuring the same thing, see `FusionEstimator`.
+///
+/// Fused readings are stored with the readings of the sensors they're fused from, under
+/// the name of the group, so they're exposed by the same gauges labeled with the name of
+/// the group as `sensor`. The weight of each sensor in a group is labeled with the name of
+/// the group as `fused`.
+#[derive(Debug)]
+pub struct FusionMetrics {
+    gauges: Arc<ReadingGauges>,
+    window: usize,
+    locks: Locks,
+    groups: Mutex<Vec<FusedSensors>>,
+    weights: Family<FusionWeightLabels, Gauge<f64, AtomicU64>>,
+}
+
+impl FusionMetrics {
+    /// Create metrics fusing readings of sensors that share the gauges of `metrics`, see
+    /// `TemperatureMetrics::sensor`. Variance of each sensor is estimated from its last
+    /// `window` readings.
+    pub fn new(reg: &mut Registry, metrics: &TemperatureMetrics, window: usize) -> Self {
+        let weights = Family::<FusionWeightLabels, Gauge<f64, AtomicU64>>::default();
+        reg.register(
+            "strudel_fusion_weight",
+            "Weight (0-1) of each sensor in the fused value of its group",
+            weights.clone(),
+        );
+
+        Self {
+            gauges: metrics.gauges.clone(),
+            window,
+            locks: Locks::default(),
+            groups: Mutex::new(Vec::new()),
+            weights,
+        }
+    }
+
+    /// Fuse readings of the sensors in `group`, leaving out readings older than `max_age`.
+    /// Gauges for the group are zero until one of its sensors has been read.
+    pub fn group(self, group: &FuseGroup, max_age: Duration) -> Self {
+        self.gauges.settings_mut().sensors.push(Some(group.name.clone()));
+        for reading in ["temperature", "humidity"] {
+            for sensor in group.sensors.iter() {
+                let _ = self.weights.get_or_create(&FusionWeightLabels {
+                    fused: group.name.clone(),
+                    sensor: sensor.clone(),
+                    reading: reading.to_owned(),
+                });
+            }
+        }
+
+        self.locks.lock(&self.groups).push(FusedSensors {
+            name: group.name.clone(),
+            temperature: FusionEstimator::new(&group.sensors, self.window, max_age),
+            humidity: FusionEstimator::new(&group.sensors, self.window, max_age),
+        });
+        self
+    }
+
+    /// Update the estimates of every group that includes `sensor` with a successful
+    /// reading of it. Failed reads are ignored, the sensor drops out of the estimate
+    /// once its last successful reading is too old.
+    pub fn update(&self, sensor: &str, event: &ReadingEvent) {
+        let Ok(m) = &event.result else {
+            return;
+        };
+
+        let mut groups = self.locks.lock(&self.groups);
+        for group in groups.iter_mut() {
+            let temperature = group
+                .temperature
+                .update(sensor, f64::from(m.temperature), event.timestamp);
+            let humidity = group.humidity.update(sensor, f64::from(m.humidity), event.timestamp);
+            let (Some(temperature), Some(humidity)) = (temperature, humidity) else {
+                continue;
+            };
+
+            let fused = Measurement {
+                temperature: TemperatureCelsius::from(temperature.value),
+                humidity: Humidity::from(humidity.value),
+            };
+            let reading = self.gauges.settings().reading(fused, event);
+            self.gauges.latest.set_named(&group.name, reading);
+
+            for (reading, estimate) in [("temperature", &temperature), ("humidity", &humidity)] {
+                for (sensor, weight) in estimate.weights.iter() {
+                    self.weights
+                        .get_or_create(&FusionWeightLabels {
+                            fused: group.name.clone(),
+                            sensor: sensor.clone(),
+                            reading: reading.to_owned(),
+                        })
+                        .set(*weight);
+                }
+            }
+        }
+    }
+}
+
+/// Largest difference in temperature, in degrees celsius, between a canary read and the
+/// reading it's compared to for them to agree. Readings are only precise to 0.1 degrees
+/// and the temperature may change a little between reads.
+const CANARY_AGREEMENT_CELSIUS: f64 = 0.5;
+
+/// Collection of Prometheus metrics comparing canary reads of the sensor, decoded with
+/// another pulse timing, to the readings published just before them, see
+/// `SensorWorker::canary`.
+///
+/// Canary reads agree with the published reading if they succeed and the temperatures are
+/// within half a degree celsius. Failed canary reads disagree and don't change the delta.
+#[derive(Debug)]
+pub struct CanaryMetrics {
+    unit: TemperatureUnit,
+    delta: Gauge<f64, AtomicU64>,
+    agreement: Family<AgreementLabels, Counter>,
+}
+
+impl CanaryMetrics {
+    pub fn new(reg: &mut Registry, unit: TemperatureUnit) -> Self {
+        let delta = Gauge::<f64, AtomicU64>::default();
+        let agreement = Family::<AgreementLabels, Counter>::default();
+
+        // Initialize both outcomes so that they're exported before any canary reads
+        for agree in ["true", "false"] {
+            agreement
+                .get_or_create(&AgreementLabels {
+                    agree: agree.to_owned(),
+                })
+                .inc_by(0);
+        }
+
+        reg.register(
+            "strudel_canary_temp_delta",
+            format!(
+                "Temperature in {} of the most recent successful canary read minus the reading it was compared to",
+                unit
+            ),
+            delta.clone(),
+        );
+        reg.register(
+            "strudel_canary_agreement",
+            "Number of canary reads by whether they agreed with the reading they were compared to",
+            agreement.clone(),
+        );
+
+        Self { unit, delta, agreement }
+    }
+
+    /// Compare the result of a canary read to the `primary` reading published before it.
+    pub fn update(&self, primary: &Measurement, canary: &Result<Measurement, SensorError>) {
+        let agree = match canary {
+            Ok(m) => {
+                self.delta
+                    .set(self.unit.convert(m.temperature) - self.unit.convert(primary.temperature));
+                (f64::from(m.temperature) - f64::from(primary.temperature)).abs() <= CANARY_AGREEMENT_CELSIUS
+            }
+            Err(_) => false,
+        };
+
+        self.agreement
+            .get_or_create(&AgreementLabels {
+                agree: agree.to_string(),
+            })
+            .inc();
+    }
+}
+
+/// Counter of readings replaced because they were spikes, see `SensorWorker::suppress_spikes`.
+#[derive(Debug)]
+pub struct SpikeMetrics {
+    suppressed: Counter,
+}
+
+impl SpikeMetrics {
+    pub fn new(reg: &mut Registry) -> Self {
+        let suppressed = Counter::default();
+        reg.register(
+            "strudel_spikes_suppressed",
+            "Number of readings replaced by the average of the readings before and after them",
+            suppressed.clone(),
+        );
+
+        Self { suppressed }
+    }
+
+    /// Record a reading being replaced.
+    pub fn suppressed(&self) {
+        self.suppressed.inc();
+    }
+}
+
+/// Gauge with a constant value of `1` and labels describing how `strudel` was built.
+#[derive(Debug)]
+pub struct BuildMetrics;
+
+impl BuildMetrics {
+    pub fn register(reg: &mut Registry) {
+        Self::register_with(
+            reg,
+            version::VERSION,
+            version::GIT_COMMIT,
+            version::TARGET,
+            version::RUSTC_VERSION,
+            version::FEATURES,
+        );
+    }
+
+    /// Register build information with the given values instead of those of the current
+    /// build, for output that doesn't depend on how `strudel` was built.
+    pub fn register_with(reg: &mut Registry, version: &str, commit: &str, target: &str, rustc: &str, features: &str) {
+        let info = Family::<BuildLabels, Gauge>::default();
+        let labels = BuildLabels {
+            version: version.to_owned(),
+            commit: commit.to_owned(),
+            target: target.to_owned(),
+            rustc: rustc.to_owned(),
+            features: features.to_owned(),
+        };
+
+        info.get_or_create(&labels).set(1);
+        reg.register("strudel_build_info", "Build information about strudel", info);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        slope_per_hour, BuildMetrics, CanaryMetrics, ConfigMetrics, ConfigOptions, CounterValues, DebugMetrics,
+        ErrorKindLabel, FusionMetrics, HealthMetrics, HttpMetrics, HumidityRail, MetricsConfig, Pinned, PushMetrics,
+        ReadLoopMetrics, Registries, SaturationMetrics, SaturationTracker, SpikeMetrics, TemperatureMetrics,
+        TimingMetrics, TrendTracker, POISONING_TEST,
+    };
+    use crate::clock::{Clock, ClockCheck, MockClock};
+    use crate::health::SensorState;
+    use crate::process::ProcessMetrics;
+    use crate::sensor::{
+        Clamped, Humidity, LatestReading, Measurement, PulseStats, RawReading, ReadingEvent, SensorError,
+        SensorErrorKind, TemperatureCelsius, TemperatureUnit,
+    };
+    use prometheus_client::encoding::text;
+    use prometheus_client::registry::Registry;
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, PoisonError};
+    use std::thread;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+    use tracing::Span;
+
+    fn event(ok: bool, attempts: u32) -> ReadingEvent {
+        let result = if ok {
+            Ok(Measurement {
+                temperature: TemperatureCelsius::from(21.0),
+                humidity: Humidity::from(40.0),
+            })
+        } else {
+            Err(SensorError::timeout("timeout"))
+        };
+
+        ReadingEvent {
+            timestamp: SystemTime::now(),
+            instant: Instant::now(),
+            result,
+            clamped: Clamped::default(),
+            attempts,
+            retried_errors: vec![SensorErrorKind::Checksum; attempts.saturating_sub(1) as usize],
+            duration: Duration::ZERO,
+            raw: Vec::new(),
+            pulses: None,
+            span: Span::none(),
+        }
+    }
+
+    #[test]
+    fn test_registries_empty() {
+        let encoded = Registries::new().encode().unwrap();
+        assert_eq!("# EOF\n", encoded.text);
+        assert!(encoded.failed.is_empty());
+    }
+
+    #[test]
+    fn test_registries_groups() {
+        let mut registries = Registries::new();
+        BuildMetrics::register(registries.group("build"));
+        TemperatureMetrics::new(registries.group("sensor"));
+        // Existing groups are reused rather than replaced
+        ConfigMetrics::register(
+            registries.group("build"),
+            &[ConfigOptions {
+                sensor: None,
+                bcm_pin: 17,
+                refresh_interval: Duration::from_secs(30),
+            }],
+        );
+
+        let encoded = registries.encode().unwrap();
+        assert!(encoded.failed.is_empty());
+        assert!(encoded.text.contains("strudel_build_info{"));
+        assert!(encoded.text.contains("strudel_bcm_pin{sensor=\"\"} 17\n"));
+        assert!(encoded.text.contains("strudel_collections_total{sensor=\"\"} 0\n"));
+        assert_eq!(1, encoded.text.matches("# EOF\n").count());
+        assert!(encoded.text.ends_with("# EOF\n"));
+
+        let sensor = encoded.group("sensor").unwrap();
+        assert!(sensor.contains("strudel_collections_total{sensor=\"\"} 0\n"));
+        assert!(!sensor.contains("strudel_build_info{"));
+        assert!(!sensor.contains("# EOF"));
+        assert!(encoded.group("http").is_none());
+    }
+
+    /// Register every group of metrics the way the binary does and encode them
+    fn encode_with_config(config: MetricsConfig) -> String {
+        let mut registries = Registries::with_config(config);
+        let metrics = TemperatureMetrics::with_config(
+            registries.group("sensor"),
+            TemperatureUnit::Celsius,
+            &TemperatureMetrics::default_temperature_buckets(),
+            &TemperatureMetrics::default_humidity_buckets(),
+            config,
+        );
+        TrendTracker::new(registries.group("trend"), TemperatureUnit::Celsius);
+        DebugMetrics::new(registries.group("debug"));
+        ProcessMetrics::register(registries.group("process"));
+        BuildMetrics::register(registries.group("build"));
+        ConfigMetrics::register(
+            registries.group("config"),
+            &[ConfigOptions {
+                sensor: None,
+                bcm_pin: 17,
+                refresh_interval: Duration::from_secs(30),
+            }],
+        );
+        ReadLoopMetrics::new(registries.group("read_loop"), Duration::from_secs(30));
+        HealthMetrics::new(registries.group("health"));
+        SaturationMetrics::new(registries.group("health"), SaturationTracker::DEFAULT_WARN_AFTER);
+        PushMetrics::new(registries.group("push"));
+        HttpMetrics::new(registries.group("http"));
+        FusionMetrics::new(registries.group("fusion"), &metrics, 10)
+            .group(&"a,b=indoor".parse().unwrap(), Duration::from_secs(60));
+        CanaryMetrics::new(registries.group("canary"), TemperatureUnit::Celsius);
+        SpikeMetrics::new(registries.group("spikes"));
+        metrics.update(&event(true, 1));
+
+        let encoded = registries.encode().unwrap();
+        assert!(encoded.failed.is_empty());
+        encoded.text
+    }
+
+    #[test]
+    fn test_metrics_config_families() {
+        let expected: &[(&str, &[&str])] = &[
+            ("vapour_pressure_deficit", &["strudel_vapour_pressure_deficit_kpa"]),
+            (
+                "histograms",
+                &[
+                    "strudel_temperature_celsius_distribution",
+                    "strudel_relative_humidity_distribution",
+                ],
+            ),
+            ("error_ratio", &["strudel_error_ratio_5m"]),
+            ("pulse_width_ratio", &["strudel_pulse_width_ratio"]),
+            ("trend", &["strudel_temperature_change_per_hour"]),
+            ("debug", &["strudel_debug_raw_byte"]),
+            ("process", &["strudel_process_start_time_seconds"]),
+            ("build", &["strudel_build_info"]),
+            ("config", &["strudel_bcm_pin"]),
+            ("read_loop", &["strudel_healthy"]),
+            (
+                "health",
+                &["strudel_sensor_healthy", "strudel_humidity_saturated_seconds"],
+            ),
+            ("push", &["strudel_push_errors"]),
+            ("http", &["strudel_scrapes"]),
+            ("fusion", &["strudel_fusion_weight"]),
+            ("canary", &["strudel_canary_temp_delta", "strudel_canary_agreement"]),
+            ("spikes", &["strudel_spikes_suppressed"]),
+        ];
+        assert_eq!(
+            MetricsConfig::FAMILIES.iter().map(|(n, _)| *n).collect::<Vec<_>>(),
+            expected.iter().map(|(n, _)| *n).collect::<Vec<_>>(),
+        );
+
+        let all = encode_with_config(MetricsConfig::default());
+        for (name, metrics) in expected {
+            let family = MetricsConfig::family(name).unwrap();
+            let config = MetricsConfig::default().disable(family);
+            assert_eq!(vec![*name], config.disabled_names());
+
+            let encoded = encode_with_config(config);
+            for metric in *metrics {
+                let help = format!("# HELP {} ", metric);
+                assert!(all.contains(&help), "{} missing when enabled", metric);
+                assert!(!encoded.contains(&help), "{} present when {} disabled", metric, name);
+            }
+
+            // Metrics outside of the family are unaffected
+            assert!(encoded.contains("# HELP strudel_temperature_degrees "));
+            assert!(encoded.contains("strudel_collections_total{sensor=\"\"} 1\n"));
+        }
+
+        assert_eq!(None, MetricsConfig::family("temperature_fahrenheit"));
+    }
+
+    fn fusion_event(temperature: f64, secs: u64) -> ReadingEvent {
+        ReadingEvent {
+            timestamp: UNIX_EPOCH + Duration::from_secs(secs),
+            result: Ok(Measurement {
+                temperature: TemperatureCelsius::from(temperature),
+                humidity: Humidity::from(temperature * 2.0),
+            }),
+            ..event(true, 1)
+        }
+    }
+
+    #[test]
+    fn test_fusion_metrics_update() {
+        let mut registry = <Registry>::default();
+        let metrics = TemperatureMetrics::with_unit(&mut registry, TemperatureUnit::Fahrenheit)
+            .sensor_name(Some("indoor_a".to_owned()));
+        let other = metrics.sensor(Some("indoor_b".to_owned()));
+        let fusion = FusionMetrics::new(&mut registry, &metrics, 10)
+            .group(&"indoor_a,indoor_b=fused".parse().unwrap(), Duration::from_secs(60));
+
+        // The fused value is zero until a sensor in the group has been read
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+        assert!(buf.contains("strudel_temperature_degrees{sensor=\"fused\"} 0.0\n"));
+        assert!(
+            buf.contains("strudel_fusion_weight{fused=\"fused\",sensor=\"indoor_a\",reading=\"temperature\"} 0.0\n")
+        );
+
+        // Sensors outside of the group and failed reads are ignored
+        fusion.update("outdoor", &fusion_event(5.0, 0));
+        fusion.update("indoor_a", &event(false, 1));
+        for (sensor, event) in [(&metrics, fusion_event(20.0, 0)), (&other, fusion_event(22.0, 1))] {
+            sensor.update(&event);
+            fusion.update(&sensor.name().unwrap(), &event);
+        }
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+        assert!(buf.contains("strudel_temperature_degrees{sensor=\"fused\"} 69.8\n"));
+        assert!(buf.contains("strudel_relative_humidity{sensor=\"fused\"} 42.0\n"));
+        assert!(buf.contains("strudel_last_read_timestamp{sensor=\"fused\"} 1.0\n"));
+        assert!(buf.contains("strudel_temperature_degrees{sensor=\"indoor_a\"} 68.0\n"));
+        assert!(buf.contains("strudel_temperature_degrees{sensor=\"indoor_b\"} 71.6\n"));
+        assert!(
+            buf.contains("strudel_fusion_weight{fused=\"fused\",sensor=\"indoor_a\",reading=\"temperature\"} 0.5\n")
+        );
+        assert!(buf.contains("strudel_fusion_weight{fused=\"fused\",sensor=\"indoor_b\",reading=\"humidity\"} 0.5\n"));
+        assert!(!buf.contains("outdoor"));
+        // Only the gauges of readings are exposed for the group, nothing is read from it
+        assert!(!buf.contains("strudel_collections_total{sensor=\"fused\"}"));
+        assert_eq!(
+            Some(TemperatureCelsius::from(21.0)),
+            metrics.latest().get_named("fused").map(|r| r.temperature)
+        );
+    }
+
+    #[test]
+    fn test_canary_metrics_update() {
+        let mut registry = <Registry>::default();
+        let metrics = CanaryMetrics::new(&mut registry, TemperatureUnit::Fahrenheit);
+        let reading = |t: f64| Measurement {
+            temperature: TemperatureCelsius::from(t),
+            humidity: Humidity::from(40.0),
+        };
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+        assert!(buf.contains("strudel_canary_temp_delta 0.0\n"));
+        assert!(buf.contains("strudel_canary_agreement_total{agree=\"true\"} 0\n"));
+        assert!(buf.contains("strudel_canary_agreement_total{agree=\"false\"} 0\n"));
+
+        // A delta of half a degree celsius still agrees, 5C (9F) doesn't
+        metrics.update(&reading(20.0), &Ok(reading(20.5)));
+        metrics.update(&reading(20.0), &Ok(reading(25.0)));
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+        assert!(buf.contains("strudel_canary_temp_delta 9.0\n"));
+
+        // Failed canary reads disagree without changing the delta
+        metrics.update(&reading(20.0), &Err(SensorError::timeout("timeout")));
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+        assert!(buf.contains("strudel_canary_temp_delta 9.0\n"));
+        assert!(buf.contains("strudel_canary_agreement_total{agree=\"true\"} 1\n"));
+        assert!(buf.contains("strudel_canary_agreement_total{agree=\"false\"} 2\n"));
+    }
+
+    #[test]
+    fn test_spike_metrics_suppressed() {
+        let mut registry = <Registry>::default();
+        let metrics = SpikeMetrics::new(&mut registry);
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+        assert!(buf.contains("strudel_spikes_suppressed_total 0\n"));
+
+        metrics.suppressed();
+        metrics.suppressed();
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+        assert!(buf.contains("strudel_spikes_suppressed_total 2\n"));
+    }
+
+    #[test]
+    fn test_metrics_config_combine() {
+        let disabled = MetricsConfig::PROCESS | MetricsConfig::DEBUG;
+        let config = MetricsConfig::default().disable(disabled);
+
+        assert!(!config.is_enabled(MetricsConfig::PROCESS));
+        assert!(!config.is_enabled(MetricsConfig::DEBUG));
+        assert!(!config.is_enabled(disabled | MetricsConfig::HTTP));
+        assert!(config.is_enabled(MetricsConfig::HTTP));
+        assert_eq!(vec!["debug", "process"], config.disabled_names());
+        assert!(MetricsConfig::all().disabled_names().is_empty());
+    }
+
+    #[test]
+    fn test_temperature_metrics_default_unit() {
+        let mut registry = <Registry>::default();
+        let metrics = TemperatureMetrics::new(&mut registry);
+        metrics.update(&event(true, 1));
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("# HELP strudel_temperature_degrees Temperature in celsius.\n"));
+        assert!(buf.contains("strudel_temperature_degrees{sensor=\"\"} 21.0\n"));
+        assert!(buf.contains("strudel_vapour_pressure_deficit_kpa{sensor=\"\"} 1.49"));
+    }
+
+    #[test]
+    fn test_temperature_metrics_poisoned_locks() {
+        let _poisoning = POISONING_TEST.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut registry = <Registry>::default();
+        let metrics = TemperatureMetrics::new(&mut registry);
+        let before = metrics.locks.recoveries();
+
+        // Poison locks used by updates and by scrapes by panicking while holding them
+        thread::scope(|s| {
+            let res = s
+                .spawn(|| {
+                    let _values = metrics.counter_values.lock().unwrap();
+                    let _settings = metrics.gauges.settings.write().unwrap();
+                    panic!("poisoning metric locks");
+                })
+                .join();
+            assert!(res.is_err());
+        });
+
+        assert!(metrics.counter_values.is_poisoned());
+        assert!(metrics.gauges.settings.is_poisoned());
+
+        metrics.update(&event(true, 1));
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("strudel_collections_total{sensor=\"\"} 1\n"));
+        assert!(buf.contains("strudel_temperature_degrees{sensor=\"\"} 21.0\n"));
+        assert!(buf.contains(&format!("strudel_lock_recoveries_total {}\n", before + 2)));
+        assert_eq!(1, metrics.counter_values().collections);
+
+        // Locks are cleared once recovered so later updates aren't counted again
+        assert!(!metrics.counter_values.is_poisoned());
+        assert!(!metrics.gauges.settings.is_poisoned());
+        metrics.update(&event(true, 1));
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+        assert!(buf.contains(&format!("strudel_lock_recoveries_total {}\n", before + 2)));
+    }
+
+    #[test]
+    fn test_temperature_metrics_vpd_not_updated_on_failure() {
+        let mut registry = <Registry>::default();
+        let metrics = TemperatureMetrics::new(&mut registry).leaf_temp_offset(-2.0);
+        metrics.update(&event(false, 1));
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("strudel_vapour_pressure_deficit_kpa{sensor=\"\"} 0.0\n"));
+    }
+
+    #[test]
+    fn test_temperature_metrics_default_buckets() {
+        let temperature = TemperatureMetrics::default_temperature_buckets();
+        assert_eq!(26, temperature.len());
+        assert_eq!(Some(&-10.0), temperature.first());
+        assert_eq!(Some(&40.0), temperature.last());
+
+        let humidity = TemperatureMetrics::default_humidity_buckets();
+        assert_eq!(21, humidity.len());
+        assert_eq!(Some(&0.0), humidity.first());
+        assert_eq!(Some(&100.0), humidity.last());
+    }
+
+    #[test]
+    fn test_temperature_metrics_distribution() {
+        let mut registry = <Registry>::default();
+        let metrics =
+            TemperatureMetrics::with_buckets(&mut registry, TemperatureUnit::Fahrenheit, &[0.0, 20.0, 25.0], &[50.0]);
+
+        // Observed in celsius regardless of the unit used for the gauge
+        metrics.update(&event(true, 1));
+        metrics.update(&event(true, 1));
+        metrics.update(&event(false, 1));
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("strudel_temperature_celsius_distribution_count{sensor=\"\"} 2\n"));
+        assert!(buf.contains("strudel_temperature_celsius_distribution_sum{sensor=\"\"} 42.0\n"));
+        assert!(buf.contains("strudel_temperature_celsius_distribution_bucket{le=\"20.0\",sensor=\"\"} 0\n"));
+        assert!(buf.contains("strudel_temperature_celsius_distribution_bucket{le=\"25.0\",sensor=\"\"} 2\n"));
+        assert!(buf.contains("strudel_relative_humidity_distribution_bucket{le=\"50.0\",sensor=\"\"} 2\n"));
+        assert!(buf.contains("strudel_relative_humidity_distribution_bucket{le=\"+Inf\",sensor=\"\"} 2\n"));
+    }
+
+    #[cfg(feature = "otel")]
+    #[test]
+    fn test_temperature_metrics_errors_exemplar() {
+        use opentelemetry::trace::{TraceContextExt, TracerProvider as _};
+        use opentelemetry_sdk::trace::TracerProvider;
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+        use tracing_subscriber::layer::SubscriberExt;
+
+        // Provider without any exporters, spans get IDs but aren't sent anywhere
+        let provider = TracerProvider::builder().build();
+        let subscriber =
+            tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+        let mut registry = <Registry>::default();
+        let metrics = TemperatureMetrics::new(&mut registry);
+
+        let trace_id = tracing::subscriber::with_default(subscriber, || {
+            let mut failed = event(false, 1);
+            failed.span = tracing::debug_span!("sensor_read");
+            metrics.update(&failed);
+            failed.span.context().span().span_context().trace_id().to_string()
+        });
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains(&format!(
+            "strudel_errors_total{{sensor=\"\",kind=\"timeout\",attempt=\"final\"}} 1 # {{trace_id=\"{}\"}} 1",
+            trace_id
+        )));
+    }
+
+    #[cfg(feature = "otel")]
+    #[test]
+    fn test_temperature_metrics_errors_no_exemplar_without_trace() {
+        let mut registry = <Registry>::default();
+        let metrics = TemperatureMetrics::new(&mut registry);
+        metrics.update(&event(false, 1));
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("strudel_errors_total{sensor=\"\",kind=\"timeout\",attempt=\"final\"} 1\n"));
+    }
+
+    #[test]
+    fn test_temperature_metrics_canonical_names_only() {
+        let mut registry = <Registry>::default();
+        let metrics = TemperatureMetrics::new(&mut registry);
+        metrics.update(&event(true, 1));
+        metrics.update(&event(false, 1));
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("strudel_temperature_degrees{sensor=\"\"} 21.0\n"));
+        assert!(buf.contains("strudel_collections_total{sensor=\"\"} 2\n"));
+        assert!(!buf.contains("pitemp_"));
+    }
+
+    #[test]
+    fn test_temperature_metrics_legacy_names() {
+        let mut registry = <Registry>::default();
+        let metrics = TemperatureMetrics::new(&mut registry).legacy_names(&mut registry);
+        metrics.update(&event(true, 1));
+        metrics.update(&event(false, 1));
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("strudel_temperature_degrees{sensor=\"\"} 21.0\n"));
+        assert!(buf.contains("pitemp_temperature_celsius{sensor=\"\"} 21.0\n"));
+        assert!(buf.contains("strudel_relative_humidity{sensor=\"\"} 40.0\n"));
+        assert!(buf.contains("pitemp_relative_humidity{sensor=\"\"} 40.0\n"));
+        assert!(buf.contains("strudel_last_read_timestamp{sensor=\"\"} "));
+        assert!(buf.contains("pitemp_last_read_timestamp{sensor=\"\"} "));
+        assert!(buf.contains("strudel_collections_total{sensor=\"\"} 2\n"));
+        assert!(buf.contains("pitemp_collections_total{sensor=\"\"} 2\n"));
+        assert!(buf.contains("strudel_errors_total{sensor=\"\",kind=\"timeout\",attempt=\"final\"} 1\n"));
+        assert!(buf.contains("pitemp_errors_total{sensor=\"\",kind=\"timeout\",attempt=\"final\"} 1\n"));
+    }
+
+    #[test]
+    fn test_temperature_metrics_legacy_names_not_celsius() {
+        let mut registry = <Registry>::default();
+        let metrics =
+            TemperatureMetrics::with_unit(&mut registry, TemperatureUnit::Fahrenheit).legacy_names(&mut registry);
+        metrics.update(&event(true, 1));
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(!buf.contains("pitemp_temperature_celsius"));
+        assert!(buf.contains("pitemp_relative_humidity{sensor=\"\"} 40.0\n"));
+    }
+
+    #[test]
+    fn test_temperature_metrics_slim() {
+        let mut registry = <Registry>::default();
+        let mut slim = <Registry>::default();
+        let metrics = TemperatureMetrics::with_unit(&mut registry, TemperatureUnit::Fahrenheit)
+            .sensor_name(Some("indoor".to_owned()))
+            .slim(&mut slim);
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &slim).unwrap();
+        assert!(buf.contains("strudel_temperature_degrees{sensor=\"indoor\"} 0.0\n"));
+
+        metrics.update(&event(true, 1));
+        let mut buf = String::new();
+        text::encode(&mut buf, &slim).unwrap();
+
+        // Only the two gauges, as they're exposed with all metrics
+        assert_eq!(
+            vec!["strudel_temperature_degrees", "strudel_relative_humidity"],
+            buf.lines()
+                .filter_map(|l| l.strip_prefix("# TYPE "))
+                .filter_map(|l| l.split(' ').next())
+                .collect::<Vec<_>>()
+        );
+        assert!(buf.contains("# HELP strudel_temperature_degrees Temperature in fahrenheit.\n"));
+        assert!(buf.contains("strudel_temperature_degrees{sensor=\"indoor\"} 69.8\n"));
+        assert!(buf.contains("strudel_relative_humidity{sensor=\"indoor\"} 40.0\n"));
+
+        let mut full = String::new();
+        text::encode(&mut full, &registry).unwrap();
+        assert!(full.contains("strudel_temperature_degrees{sensor=\"indoor\"} 69.8\n"));
+        assert!(full.contains("strudel_last_read_timestamp{sensor=\"indoor\"} "));
+    }
+
+    #[test]
+    fn test_temperature_metrics_slim_size() {
+        // Values that take the most room to format still fit in 512 bytes
+        let mut slim = <Registry>::default();
+        let metrics =
+            TemperatureMetrics::with_unit(&mut <Registry>::default(), TemperatureUnit::Fahrenheit).slim(&mut slim);
+        let mut e = event(true, 1);
+        e.result = Ok(Measurement {
+            temperature: TemperatureCelsius::from(-39.9 + f64::EPSILON * 3.0),
+            humidity: Humidity::from(0.1 + 0.2),
+        });
+        metrics.update(&e);
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &slim).unwrap();
+        assert!(buf.len() < 512, "{} bytes: {}", buf.len(), buf);
+    }
+
+    #[test]
+    fn test_temperature_metrics_latest_named() {
+        let mut registry = <Registry>::default();
+        let metrics = TemperatureMetrics::new(&mut registry).sensor_name(Some("indoor".to_owned()));
+        let latest = metrics.latest();
+        metrics.update(&event(true, 1));
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert_eq!(None, latest.get());
+        assert_eq!(
+            Some(TemperatureCelsius::from(21.0)),
+            latest.get_named("indoor").map(|r| r.temperature)
+        );
+        assert!(buf.contains("strudel_temperature_degrees{sensor=\"indoor\"} 21.0\n"));
+    }
+
+    #[test]
+    fn test_temperature_metrics_rename() {
+        let mut registry = <Registry>::default();
+        let metrics = TemperatureMetrics::new(&mut registry).sensor_name(Some("indoor".to_owned()));
+        let latest = metrics.latest();
+        metrics.update(&event(true, 1));
+
+        // Renaming to the same name keeps the reading
+        metrics.rename(Some("indoor".to_owned()));
+        assert!(latest.get_named("indoor").is_some());
+
+        metrics.rename(Some("garage".to_owned()));
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+        assert_eq!(None, latest.get_named("indoor"));
+        assert!(!buf.contains("sensor=\"indoor\""));
+        assert!(buf.contains("strudel_temperature_degrees{sensor=\"garage\"} 0.0\n"));
+        assert!(buf.contains("strudel_collections_total{sensor=\"garage\"} 0\n"));
+        assert_eq!(CounterValues::default(), metrics.counter_values());
+
+        metrics.update(&event(true, 1));
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+        assert!(latest.get_named("garage").is_some());
+        assert!(buf.contains("strudel_temperature_degrees{sensor=\"garage\"} 21.0\n"));
+        assert!(buf.contains("strudel_collections_total{sensor=\"garage\"} 1\n"));
+    }
+
+    #[test]
+    fn test_temperature_metrics_sensor() {
+        let mut registry = <Registry>::default();
+        let indoor = TemperatureMetrics::new(&mut registry).sensor_name(Some("indoor".to_owned()));
+        let outdoor = indoor.sensor(Some("outdoor".to_owned()));
+        indoor.update(&event(true, 1));
+        indoor.update(&event(false, 1));
+        outdoor.update(&event(false, 2));
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        // Each sensor has its own series in the same families
+        assert_eq!(1, buf.matches("# TYPE strudel_temperature_degrees ").count());
+        assert!(buf.contains("strudel_temperature_degrees{sensor=\"indoor\"} 21.0\n"));
+        assert!(buf.contains("strudel_temperature_degrees{sensor=\"outdoor\"} 0.0\n"));
+        assert!(buf.contains("strudel_collections_total{sensor=\"indoor\"} 2\n"));
+        assert!(buf.contains("strudel_collections_total{sensor=\"outdoor\"} 1\n"));
+        assert!(buf.contains("strudel_reads_total{sensor=\"outdoor\",outcome=\"failure\"} 1\n"));
+        assert!(buf.contains("strudel_errors_total{sensor=\"outdoor\",kind=\"checksum\",attempt=\"1\"} 1\n"));
+        assert!(buf.contains("strudel_error_ratio_5m{sensor=\"indoor\"} 0.5\n"));
+        assert!(buf.contains("strudel_error_ratio_5m{sensor=\"outdoor\"} 1.0\n"));
+        assert!(!buf.contains("sensor=\"\""));
+        assert_eq!(2, indoor.counter_values().collections);
+        assert_eq!(1, outdoor.counter_values().collections);
+    }
+
+    #[test]
+    fn test_temperature_metrics_pulse_width_ratio() {
+        let mut registry = <Registry>::default();
+        let metrics = TemperatureMetrics::new(&mut registry);
+        let ratio = |registry: &Registry| {
+            let mut buf = String::new();
+            text::encode(&mut buf, registry).unwrap();
+            sample_value(&buf, "strudel_pulse_width_ratio")
+        };
+
+        let with_pulses = |ok: bool, mean_high: f64| {
+            let mut e = event(ok, 1);
+            e.pulses = Some(PulseStats {
+                mean_low: 400.0,
+                mean_high,
+            });
+            e
+        };
+
+        // No pulses captured yet
+        metrics.update(&event(true, 1));
+        assert_eq!(1.0, ratio(&registry));
+
+        metrics.update(&with_pulses(true, 400.0));
+        assert_eq!(1.0, ratio(&registry));
+
+        // Failed reads count as long as every pulse was captured
+        metrics.update(&with_pulses(false, 360.0));
+        assert_eq!(0.9, ratio(&registry));
+
+        // Reads without full pulse data don't change the ratio
+        metrics.update(&event(false, 1));
+        assert_eq!(0.9, ratio(&registry));
+    }
+
+    /// Value of the first sample of the metric `name`, whatever its labels, in the text
+    /// exposition format.
+    fn sample_value(buf: &str, name: &str) -> f64 {
+        buf.lines()
+            .filter_map(|l| l.strip_prefix(name))
+            .map(|l| match l.strip_prefix('{') {
+                Some(labels) => labels.split_once('}').map(|(_, rest)| rest).unwrap_or_default(),
+                None => l,
+            })
+            .find_map(|v| v.strip_prefix(' '))
+            .unwrap_or_else(|| panic!("no sample for {} in {}", name, buf))
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_temperature_metrics_clock_pre_sync() {
+        let mut registry = <Registry>::default();
+        let check = ClockCheck::new(SystemTime::now() + Duration::from_secs(86400));
+        let metrics = TemperatureMetrics::new(&mut registry)
+            .legacy_names(&mut registry)
+            .clock_check(check);
+        metrics.update(&event(true, 1));
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(!metrics.latest().get().unwrap().is_synced());
+        assert!(buf.contains(
+            "strudel_temperature_degrees{sensor=\"\"} 21.0
+"
+        ));
+        assert!(!buf.contains("strudel_last_read_timestamp"));
+        assert!(!buf.contains("pitemp_last_read_timestamp"));
+    }
+
+    #[test]
+    fn test_temperature_metrics_clock_jump() {
+        let mut registry = <Registry>::default();
+        let check = ClockCheck::new(UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(20));
+        let metrics = TemperatureMetrics::new(&mut registry)
+            .clock_check(check)
+            .clock(Arc::new(clock.clone()));
+
+        // Read shortly after boot, before the clock has been set
+        let mut early = event(true, 1);
+        early.timestamp = clock.now_wall();
+        early.instant = clock.now_monotonic();
+        metrics.update(&early);
+        assert!(!metrics.latest().get().unwrap().is_synced());
+
+        // The clock is set ten seconds later so the reading is restamped when collected
+        clock.advance(Duration::from_secs(10));
+        clock.step_wall(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        let reading = metrics.latest().get().unwrap();
+        assert!(reading.is_synced());
+        assert_eq!(UNIX_EPOCH + Duration::from_secs(1_699_999_990), reading.read_at);
+        assert_eq!(1_699_999_990.0, sample_value(&buf, "strudel_last_read_timestamp"));
+    }
+
+    #[test]
+    fn test_temperature_metrics_restore() {
+        let mut registry = <Registry>::default();
+        let metrics = TemperatureMetrics::new(&mut registry).sensor_name(Some("indoor".to_owned()));
+        let measurement = Measurement {
+            temperature: TemperatureCelsius::from(18.5),
+            humidity: Humidity::from(55.0),
+        };
+        let read_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        metrics.restore(LatestReading::new(measurement, read_at, Instant::now()).restored());
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(metrics.latest().get_named("indoor").unwrap().restored);
+        assert_eq!(18.5, sample_value(&buf, "strudel_temperature_degrees"));
+        assert_eq!(55.0, sample_value(&buf, "strudel_relative_humidity"));
+        assert_eq!(1_700_000_000.0, sample_value(&buf, "strudel_last_read_timestamp"));
+
+        // Replaced by the next reading
+        metrics.update(&event(true, 1));
+        assert!(!metrics.latest().get_named("indoor").unwrap().restored);
+    }
+
+    #[test]
+    fn test_temperature_metrics_clock_already_synced() {
+        let mut registry = <Registry>::default();
+        let check = ClockCheck::new(UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        let metrics = TemperatureMetrics::new(&mut registry).clock_check(check);
+
+        let mut synced = event(true, 1);
+        synced.timestamp = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        metrics.update(&synced);
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        let reading = metrics.latest().get().unwrap();
+        assert!(reading.is_synced());
+        assert_eq!(synced.timestamp, reading.read_at);
+        assert_eq!(1_700_000_000.0, sample_value(&buf, "strudel_last_read_timestamp"));
+    }
+
+    #[test]
+    fn test_temperature_metrics_snapshot_not_torn() {
+        let mut registry = <Registry>::default();
+        let metrics = TemperatureMetrics::new(&mut registry).legacy_names(&mut registry);
+        let latest = metrics.latest();
+        let done = AtomicBool::new(false);
+
+        // Every reading uses the same value for temperature, humidity, and the time it was
+        // taken (in seconds) so any mix of values from different readings is detectable.
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                for i in 1..=2000 {
+                    metrics.update(&ReadingEvent {
+                        timestamp: UNIX_EPOCH + Duration::from_secs(i),
+                        instant: Instant::now(),
+                        result: Ok(Measurement {
+                            temperature: TemperatureCelsius::from(i as f64),
+                            humidity: Humidity::from(i as f64),
+                        }),
+                        clamped: Clamped::default(),
+                        attempts: 1,
+                        retried_errors: Vec::new(),
+                        duration: Duration::ZERO,
+                        raw: Vec::new(),
+                        pulses: None,
+                        span: Span::none(),
+                    });
+                }
+
+                done.store(true, Ordering::SeqCst);
+            });
+
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    let mut last_generation = 0;
+                    while !done.load(Ordering::SeqCst) {
+                        let mut buf = String::new();
+                        text::encode(&mut buf, &registry).unwrap();
+
+                        let read_at = sample_value(&buf, "strudel_last_read_timestamp");
+                        assert_eq!(read_at, sample_value(&buf, "strudel_temperature_degrees"));
+                        assert_eq!(read_at, sample_value(&buf, "strudel_relative_humidity"));
+
+                        // Legacy names are emitted by a separate collector which may see a
+                        // newer reading but must still be consistent with itself.
+                        let legacy_read_at = sample_value(&buf, "pitemp_last_read_timestamp");
+                        assert!(legacy_read_at >= read_at);
+                        assert_eq!(legacy_read_at, sample_value(&buf, "pitemp_temperature_celsius"));
+                        assert_eq!(legacy_read_at, sample_value(&buf, "pitemp_relative_humidity"));
+
+                        if let Some(snapshot) = latest.snapshot(None) {
+                            let r = snapshot.reading;
+                            let read_at = r.read_at.duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+                            assert_eq!(read_at, f64::from(r.temperature));
+                            assert_eq!(read_at, f64::from(r.humidity));
+                            assert_eq!(snapshot.generation as f64, read_at);
+                            assert!(snapshot.generation >= last_generation);
+                            last_generation = snapshot.generation;
+                        }
+                    }
+                });
+            }
+        });
+
+        assert_eq!(2000, latest.generation());
+    }
+
+    #[test]
+    fn test_temperature_metrics_kelvin() {
+        let mut registry = <Registry>::default();
+        let metrics = TemperatureMetrics::with_unit(&mut registry, TemperatureUnit::Kelvin);
+        metrics.update(&event(true, 1));
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("# HELP strudel_temperature_degrees Temperature in kelvin.\n"));
+        assert!(buf.contains("strudel_temperature_degrees{sensor=\"\"} 294.15\n"));
+    }
+
+    #[test]
+    fn test_temperature_metrics_reads_outcome() {
+        let mut registry = <Registry>::default();
+        let metrics = TemperatureMetrics::new(&mut registry);
+
+        metrics.update(&event(true, 1));
+        metrics.update(&event(true, 1));
+        metrics.update(&event(true, 2));
+        metrics.update(&event(true, 3));
+        metrics.update(&event(true, 3));
+        metrics.update(&event(true, 3));
+        metrics.update(&event(false, 1));
+        metrics.update(&event(false, 4));
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("strudel_reads_total{sensor=\"\",outcome=\"success_first_try\"} 2\n"));
+        assert!(buf.contains("strudel_reads_total{sensor=\"\",outcome=\"success_retried\"} 4\n"));
+        assert!(buf.contains("strudel_reads_total{sensor=\"\",outcome=\"failure\"} 2\n"));
+        assert!(buf.contains("strudel_collections_total{sensor=\"\"} 8\n"));
+    }
+
+    #[test]
+    fn test_temperature_metrics_reads_outcome_samples() {
+        let mut registry = <Registry>::default();
+        let metrics = TemperatureMetrics::new(&mut registry);
+
+        // Three samples read without any failures
+        let mut samples = event(true, 3);
+        samples.retried_errors.clear();
+        metrics.update(&samples);
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("strudel_reads_total{sensor=\"\",outcome=\"success_first_try\"} 1\n"));
+        assert!(buf
+            .lines()
+            .filter(|l| l.starts_with("strudel_errors_total{sensor=\"\","))
+            .all(|l| l.ends_with("attempt=\"final\"} 0")));
+    }
+
+    #[test]
+    fn test_temperature_metrics_errors_before_first_error() {
+        let mut registry = <Registry>::default();
+        let _metrics = TemperatureMetrics::new(&mut registry);
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        // Every kind is exported as zero for the final attempt and nothing else
+        let series: Vec<&str> = buf.lines().filter(|l| l.starts_with("strudel_errors_total")).collect();
+        assert_eq!(ErrorKindLabel::ALL.len(), series.len());
+        for kind in [
+            SensorErrorKind::Initialization,
+            SensorErrorKind::ReadTimeout,
+            SensorErrorKind::Checksum,
+            SensorErrorKind::NoResponse,
+            SensorErrorKind::Implausible,
+            SensorErrorKind::FrameError,
+            SensorErrorKind::Internal,
+        ] {
+            let line = format!(
+                "strudel_errors_total{{sensor=\"\",kind=\"{}\",attempt=\"final\"}} 0",
+                kind.as_label()
+            );
+            assert!(series.contains(&line.as_str()), "{} missing", line);
+            assert_eq!(kind.as_label(), ErrorKindLabel::from(kind).as_str());
+        }
+    }
+
+    #[test]
+    fn test_temperature_metrics_restore_unknown_error_kind() {
+        let mut registry = <Registry>::default();
+        let metrics = TemperatureMetrics::new(&mut registry);
+        let mut values = CounterValues::default();
+        values
+            .errors
+            .insert("timeout".to_owned(), BTreeMap::from([("final".to_owned(), 3)]));
+        values
+            .errors
+            .insert("gremlins".to_owned(), BTreeMap::from([("final".to_owned(), 2)]));
+        metrics.restore_counters(&values);
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+        assert!(buf.contains("strudel_errors_total{sensor=\"\",kind=\"timeout\",attempt=\"final\"} 3\n"));
+        assert!(!buf.contains("gremlins"));
+    }
+
+    #[test]
+    fn test_temperature_metrics_errors_by_attempt() {
+        let mut registry = <Registry>::default();
+        let metrics = TemperatureMetrics::new(&mut registry);
+
+        metrics.update(&event(true, 1));
+        metrics.update(&event(true, 2));
+        metrics.update(&event(false, 3));
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("strudel_errors_total{sensor=\"\",kind=\"checksum\",attempt=\"1\"} 2\n"));
+        assert!(buf.contains("strudel_errors_total{sensor=\"\",kind=\"checksum\",attempt=\"2\"} 1\n"));
+        assert!(buf.contains("strudel_errors_total{sensor=\"\",kind=\"timeout\",attempt=\"final\"} 1\n"));
+        // Four of six attempts failed
+        assert!(buf.contains("strudel_error_ratio_5m{sensor=\"\"} 0.666"));
+    }
+
+    #[test]
+    fn test_temperature_metrics_calibration_clamped() {
+        let mut registry = <Registry>::default();
+        let metrics = TemperatureMetrics::new(&mut registry);
+        metrics.update(&event(true, 1));
+
+        let mut clamped = event(true, 1);
+        clamped.clamped = Clamped {
+            temperature: false,
+            humidity: true,
+        };
+        metrics.update(&clamped);
+        metrics.update(&clamped);
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("strudel_calibration_clamped_total{sensor=\"\",value=\"humidity\"} 2\n"));
+        assert!(!buf.contains("strudel_calibration_clamped_total{sensor=\"\",value=\"temperature\"}"));
+    }
+
+    #[test]
+    fn test_temperature_metrics_counters_survive_restart() {
+        let mut registry = <Registry>::default();
+        let before = TemperatureMetrics::new(&mut registry);
+        before.update(&event(true, 1));
+        before.update(&event(true, 2));
+        before.update(&event(false, 3));
+
+        // Counter values make a round trip through JSON like they would via the state file
+        let json = serde_json::to_string(&before.counter_values()).unwrap();
+        let values: CounterValues = serde_json::from_str(&json).unwrap();
+        assert_eq!(before.counter_values(), values);
+
+        let mut registry = <Registry>::default();
+        let after = TemperatureMetrics::new(&mut registry);
+        after.restore_counters(&values);
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("strudel_collections_total{sensor=\"\"} 3\n"));
+        assert!(buf.contains("strudel_reads_total{sensor=\"\",outcome=\"success_first_try\"} 1\n"));
+        assert!(buf.contains("strudel_reads_total{sensor=\"\",outcome=\"success_retried\"} 1\n"));
+        assert!(buf.contains("strudel_reads_total{sensor=\"\",outcome=\"failure\"} 1\n"));
+        assert!(buf.contains("strudel_errors_total{sensor=\"\",kind=\"checksum\",attempt=\"1\"} 2\n"));
+        assert!(buf.contains("strudel_errors_total{sensor=\"\",kind=\"timeout\",attempt=\"final\"} 1\n"));
+
+        after.update(&event(true, 1));
+        after.update(&event(false, 2));
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("strudel_collections_total{sensor=\"\"} 5\n"));
+        assert!(buf.contains("strudel_reads_total{sensor=\"\",outcome=\"success_first_try\"} 2\n"));
+        assert!(buf.contains("strudel_reads_total{sensor=\"\",outcome=\"failure\"} 2\n"));
+        assert!(buf.contains("strudel_errors_total{sensor=\"\",kind=\"checksum\",attempt=\"1\"} 3\n"));
+        assert!(buf.contains("strudel_errors_total{sensor=\"\",kind=\"timeout\",attempt=\"final\"} 2\n"));
+
+        // Saved values include everything from before the restart
+        let saved = after.counter_values();
+        assert_eq!(5, saved.collections);
+        assert_eq!(Some(&2), saved.reads.get("failure"));
+    }
+
+    #[test]
+    fn test_counter_values_missing_fields() {
+        let values: CounterValues = serde_json::from_str(r#"{"collections":4}"#).unwrap();
+        assert_eq!(4, values.collections);
+        assert!(values.reads.is_empty());
+        assert!(values.errors.is_empty());
+    }
+
+    fn reading(instant: Instant, temperature: f64, humidity: f64) -> ReadingEvent {
+        ReadingEvent {
+            timestamp: SystemTime::now(),
+            instant,
+            result: Ok(Measurement {
+                temperature: TemperatureCelsius::from(temperature),
+                humidity: Humidity::from(humidity),
+            }),
+            clamped: Clamped::default(),
+            attempts: 1,
+            retried_errors: Vec::new(),
+            duration: Duration::ZERO,
+            raw: Vec::new(),
+            pulses: None,
+            span: Span::none(),
+        }
+    }
+
+    fn assert_near(expected: f64, actual: f64) {
+        assert!(
+            (expected - actual).abs() < 1e-6,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn test_slope_per_hour_not_enough_points() {
+        assert_eq!(None, slope_per_hour(&[]));
+        assert_eq!(None, slope_per_hour(&[(0.0, 20.0)]));
+        assert_eq!(None, slope_per_hour(&[(60.0, 20.0), (60.0, 21.0)]));
+    }
+
+    #[test]
+    fn test_slope_per_hour_flat() {
+        let points: Vec<(f64, f64)> = (0..10).map(|i| (i as f64 * 60.0, 4.0)).collect();
+        assert_near(0.0, slope_per_hour(&points).unwrap());
+    }
+
+    #[test]
+    fn test_slope_per_hour_rising() {
+        // 0.1 degrees every minute is six degrees an hour
+        let points: Vec<(f64, f64)> = (0..10).map(|i| (i as f64 * 60.0, 4.0 + i as f64 * 0.1)).collect();
+        assert_near(6.0, slope_per_hour(&points).unwrap());
+    }
+
+    #[test]
+    fn test_slope_per_hour_noisy() {
+        // Alternating noise around a rising line that cancels out in the fit
+        let noise = [0.3, -0.3, 0.3, -0.3, -0.3, 0.3, -0.3, 0.3];
+        let points: Vec<(f64, f64)> = noise
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (i as f64 * 60.0, 4.0 + i as f64 * 0.05 + n))
+            .collect();
+
+        assert_near(3.0, slope_per_hour(&points).unwrap());
+    }
+
+    #[test]
+    fn test_trend_tracker_wall_clock_step() {
+        let mut registry = <Registry>::default();
+        let trend = TrendTracker::new(&mut registry, TemperatureUnit::Celsius);
+        let start = Instant::now();
+
+        // The system clock being stepped back an hour halfway through doesn't affect the
+        // time between readings used for the trend.
+        for i in 0..10 {
+            let mut r = reading(start + Duration::from_secs(i * 60), 4.0 + i as f64 * 0.1, 40.0);
+            r.timestamp = UNIX_EPOCH + Duration::from_secs(1_600_000_000 + i * 60);
+            if i >= 5 {
+                r.timestamp -= Duration::from_secs(3600);
+            }
+
+            trend.update(&r);
+        }
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("strudel_temperature_change_per_hour 6.0"));
+    }
+
+    #[test]
+    fn test_trend_tracker_single_reading() {
+        let mut registry = <Registry>::default();
+        let trend = TrendTracker::new(&mut registry, TemperatureUnit::Celsius);
+        trend.update(&reading(Instant::now(), 4.0, 40.0));
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("strudel_temperature_change_per_hour 0.0\n"));
+        assert!(buf.contains("strudel_relative_humidity_change_per_hour 0.0\n"));
+    }
+
+    #[test]
+    fn test_trend_tracker_window() {
+        let mut registry = <Registry>::default();
+        let trend = TrendTracker::new(&mut registry, TemperatureUnit::Celsius).window(Duration::from_secs(600));
+        let start = Instant::now();
+
+        // A sudden drop that falls outside the window once enough time has passed
+        trend.update(&reading(start, 20.0, 60.0));
+        for i in 1..=20 {
+            let ts = start + Duration::from_secs(i * 60);
+            trend.update(&reading(ts, 4.0 + i as f64 * 0.1, 40.0 - i as f64 * 0.05));
+        }
+
+        // Failures don't change the trend
+        let mut failed = event(false, 1);
+        failed.instant = start + Duration::from_secs(21 * 60);
+        trend.update(&failed);
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("strudel_temperature_change_per_hour 6.0"));
+        assert!(buf.contains("strudel_relative_humidity_change_per_hour -3.0"));
+    }
+
+    #[test]
+    fn test_saturation_tracker_transitions() {
+        let mut tracker = SaturationTracker::new(Duration::from_secs(600));
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let pinned = |rail: HumidityRail, secs: u64, warn: bool| {
+            Some(Pinned {
+                rail,
+                duration: Duration::from_secs(secs),
+                warn,
+            })
+        };
+
+        assert_eq!(None, tracker.update(99.8, at(0)));
+
+        // Enter, stay, and warn only once when staying past the threshold
+        assert_eq!(pinned(HumidityRail::Saturated, 0, false), tracker.update(100.0, at(60)));
+        assert_eq!(
+            pinned(HumidityRail::Saturated, 300, false),
+            tracker.update(99.9, at(360))
+        );
+        assert_eq!(
+            pinned(HumidityRail::Saturated, 600, true),
+            tracker.update(100.0, at(660))
+        );
+        assert_eq!(
+            pinned(HumidityRail::Saturated, 900, false),
+            tracker.update(100.0, at(960))
+        );
+
+        // Exit resets the duration and the warning
+        assert_eq!(None, tracker.update(98.0, at(1020)));
+        assert_eq!(
+            pinned(HumidityRail::Saturated, 0, false),
+            tracker.update(100.0, at(1080))
+        );
+        assert_eq!(
+            pinned(HumidityRail::Saturated, 600, true),
+            tracker.update(100.0, at(1680))
+        );
+
+        // Moving straight to the other extreme starts over
+        assert_eq!(pinned(HumidityRail::Zeroed, 0, false), tracker.update(0.0, at(1740)));
+        assert_eq!(pinned(HumidityRail::Zeroed, 60, false), tracker.update(0.1, at(1800)));
+        assert_eq!(None, tracker.update(0.2, at(1860)));
+    }
+
+    #[test]
+    fn test_saturation_metrics_update() {
+        let mut registry = <Registry>::default();
+        let clock = MockClock::new(UNIX_EPOCH);
+        let metrics = SaturationMetrics::new(&mut registry, Duration::from_secs(3600)).clock(Arc::new(clock.clone()));
+        let encode = |registry: &Registry| {
+            let mut buf = String::new();
+            text::encode(&mut buf, registry).unwrap();
+            buf
+        };
+
+        metrics.update(&reading(clock.now_monotonic(), 20.0, 100.0));
+        clock.advance(Duration::from_secs(90));
+        metrics.update(&reading(clock.now_monotonic(), 20.0, 100.0));
+
+        // Failed reads don't change how long humidity has been saturated
+        clock.advance(Duration::from_secs(30));
+        metrics.update(&event(false, 1));
+
+        let buf = encode(&registry);
+        assert!(buf.contains("strudel_humidity_saturated_seconds 90.0\n"), "{}", buf);
+        assert!(buf.contains("strudel_humidity_zeroed_seconds 0.0\n"), "{}", buf);
+
+        clock.advance(Duration::from_secs(30));
+        assert!(!metrics.update(&reading(clock.now_monotonic(), 20.0, 0.0)));
+        clock.advance(Duration::from_secs(4000));
+        assert!(metrics.update(&reading(clock.now_monotonic(), 20.0, 0.0)));
+        clock.advance(Duration::from_secs(30));
+        assert!(!metrics.update(&reading(clock.now_monotonic(), 20.0, 0.0)));
+
+        let buf = encode(&registry);
+        assert!(buf.contains("strudel_humidity_saturated_seconds 0.0\n"), "{}", buf);
+        assert!(buf.contains("strudel_humidity_zeroed_seconds 4030.0\n"), "{}", buf);
+
+        metrics.update(&reading(clock.now_monotonic(), 20.0, 45.0));
+        let buf = encode(&registry);
+        assert!(buf.contains("strudel_humidity_zeroed_seconds 0.0\n"), "{}", buf);
+    }
+
+    #[test]
+    fn test_read_loop_metrics_dead_loop() {
+        let mut registry = <Registry>::default();
+        let _metrics = ReadLoopMetrics::new(&mut registry, Duration::from_secs(30));
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("strudel_healthy 0\n"));
+        assert!(buf.contains("strudel_read_loop_alive 0.0\n"));
+    }
+
+    #[test]
+    fn test_read_loop_metrics_recent_attempt() {
+        let mut registry = <Registry>::default();
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        let metrics = ReadLoopMetrics::with_clock(&mut registry, Duration::from_secs(30), Arc::new(clock.clone()));
+        metrics.tick();
+        metrics.attempted();
+        clock.advance(Duration::from_secs(45));
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("strudel_healthy 1\n"));
+        assert!(buf.contains("strudel_read_loop_alive 1600000000.0\n"));
+    }
+
+    #[test]
+    fn test_read_loop_metrics_stale_attempt() {
+        let mut registry = <Registry>::default();
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        let metrics = ReadLoopMetrics::with_clock(&mut registry, Duration::from_secs(30), Arc::new(clock.clone()));
+        metrics.tick();
+        metrics.attempted();
+        clock.advance(Duration::from_secs(90));
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("strudel_healthy 0\n"));
+    }
+
+    #[test]
+    fn test_read_loop_metrics_wall_clock_step() {
+        let mut registry = <Registry>::default();
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        let metrics = ReadLoopMetrics::with_clock(&mut registry, Duration::from_secs(30), Arc::new(clock.clone()));
+        metrics.tick();
+        metrics.attempted();
+
+        // Stepping the system clock an hour forward doesn't make the attempt stale
+        clock.advance(Duration::from_secs(10));
+        clock.step_wall(UNIX_EPOCH + Duration::from_secs(1_600_003_610));
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("strudel_healthy 1\n"));
+        assert!(buf.contains("strudel_read_loop_alive 1600000000.0\n"));
+    }
+
+    #[test]
+    fn test_http_metrics_scrape_gap() {
+        let mut registry = <Registry>::default();
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        let metrics = HttpMetrics::with_clock(&mut registry, Arc::new(clock.clone()));
+        metrics.scrape();
+        clock.advance(Duration::from_secs(300));
+        metrics.scrape();
+        clock.advance(Duration::from_secs(300));
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        // The gap is from the previous scrape, not the latest, and keeps growing until
+        // the next one.
+        assert_eq!(600.0, sample_value(&buf, "strudel_scrape_gap_seconds"));
+        assert_eq!(1_600_000_000.0, sample_value(&buf, "strudel_last_scrape_timestamp"));
+        assert_eq!(
+            Some(UNIX_EPOCH + Duration::from_secs(1_600_000_300)),
+            metrics.latest_scrape()
+        );
+    }
+
+    #[test]
+    fn test_http_metrics_scrape_gap_wall_clock_step() {
+        let mut registry = <Registry>::default();
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        let metrics = HttpMetrics::with_clock(&mut registry, Arc::new(clock.clone()));
+        metrics.scrape();
+        metrics.scrape();
+
+        // Stepping the system clock backwards or forwards doesn't change the gap
+        clock.advance(Duration::from_secs(15));
+        clock.step_wall(UNIX_EPOCH + Duration::from_secs(1_500_000_000));
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+        assert_eq!(15.0, sample_value(&buf, "strudel_scrape_gap_seconds"));
+
+        clock.step_wall(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+        assert_eq!(15.0, sample_value(&buf, "strudel_scrape_gap_seconds"));
+    }
+
+    #[test]
+    fn test_debug_metrics_disabled() {
+        let mut registry = <Registry>::default();
+        let metrics = TemperatureMetrics::new(&mut registry);
+        let mut e = event(false, 1);
+        e.raw.push(RawReading {
+            bytes: [2, 140, 1, 95, 0],
+        });
+        metrics.update(&e);
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(!buf.contains("strudel_debug"));
+    }
+
+    #[test]
+    fn test_debug_metrics_update() {
+        let mut registry = <Registry>::default();
+        let metrics = DebugMetrics::new(&mut registry);
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+        assert!(buf.contains("strudel_debug_raw_byte{index=\"4\"} 0\n"));
+        assert!(buf.contains("strudel_debug_raw_temperature_word 0\n"));
+
+        // Two attempts that decoded bytes, the most recent one is exposed
+        let mut e = event(false, 2);
+        e.raw.push(RawReading { bytes: [0, 0, 0, 0, 1] });
+        e.raw.push(RawReading {
+            bytes: [2, 140, 1, 95, 0],
+        });
+        metrics.update(&e);
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+        assert!(buf.contains("strudel_debug_raw_byte{index=\"0\"} 2\n"));
+        assert!(buf.contains("strudel_debug_raw_byte{index=\"1\"} 140\n"));
+        assert!(buf.contains("strudel_debug_raw_byte{index=\"2\"} 1\n"));
+        assert!(buf.contains("strudel_debug_raw_byte{index=\"3\"} 95\n"));
+        assert!(buf.contains("strudel_debug_raw_byte{index=\"4\"} 0\n"));
+        assert!(buf.contains("strudel_debug_raw_temperature_word 351\n"));
+        assert!(buf.contains("strudel_debug_raw_humidity_word 652\n"));
+
+        // Reads that didn't decode any bytes don't change anything
+        metrics.update(&event(false, 1));
+        let mut after = String::new();
+        text::encode(&mut after, &registry).unwrap();
+        assert_eq!(buf, after);
+    }
+
+    #[test]
+    fn test_config_metrics_register() {
+        let mut registry = <Registry>::default();
+        let opts = ConfigOptions {
+            sensor: None,
+            bcm_pin: 17,
+            refresh_interval: Duration::from_millis(2500),
+        };
+
+        ConfigMetrics::register(&mut registry, &[opts]);
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("strudel_bcm_pin{sensor=\"\"} 17\n"));
+        assert!(buf.contains("strudel_refresh_interval_seconds{sensor=\"\"} 2.5\n"));
+    }
+
+    #[test]
+    fn test_config_metrics_register_named() {
+        let mut registry = <Registry>::default();
+        let opts = ConfigOptions {
+            sensor: Some("outdoor".to_owned()),
+            bcm_pin: 4,
+            refresh_interval: Duration::from_secs(120),
+        };
+
+        ConfigMetrics::register(&mut registry, &[opts]);
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("strudel_bcm_pin{sensor=\"outdoor\"} 4\n"));
+        assert!(buf.contains("strudel_refresh_interval_seconds{sensor=\"outdoor\"} 120.0\n"));
+    }
+
+    #[test]
+    fn test_config_metrics_update() {
+        let mut registry = <Registry>::default();
+        let opts = ConfigOptions {
+            sensor: Some("outdoor".to_owned()),
+            bcm_pin: 4,
+            refresh_interval: Duration::from_secs(120),
+        };
+
+        let config = ConfigMetrics::register(&mut registry, &[opts]);
+        config.update(
+            Some("outdoor"),
+            &ConfigOptions {
+                sensor: Some("indoor".to_owned()),
+                bcm_pin: 17,
+                refresh_interval: Duration::from_secs(30),
+            },
+        );
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("strudel_bcm_pin{sensor=\"indoor\"} 17\n"));
+        assert!(buf.contains("strudel_refresh_interval_seconds{sensor=\"indoor\"} 30.0\n"));
+        assert!(!buf.contains("outdoor"));
+    }
+
+    #[test]
+    fn test_config_metrics_multiple_sensors() {
+        let mut registry = <Registry>::default();
+        let sensors = [
+            ConfigOptions {
+                sensor: Some("indoor".to_owned()),
+                bcm_pin: 17,
+                refresh_interval: Duration::from_secs(15),
+            },
+            ConfigOptions {
+                sensor: Some("outdoor".to_owned()),
+                bcm_pin: 4,
+                refresh_interval: Duration::from_secs(120),
+            },
+        ];
+
+        let config = ConfigMetrics::register(&mut registry, &sensors);
+        config.update(
+            Some("indoor"),
+            &ConfigOptions {
+                sensor: Some("office".to_owned()),
+                bcm_pin: 22,
+                refresh_interval: Duration::from_secs(30),
+            },
+        );
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("strudel_bcm_pin{sensor=\"office\"} 22\n"));
+        assert!(buf.contains("strudel_bcm_pin{sensor=\"outdoor\"} 4\n"));
+        assert!(buf.contains("strudel_refresh_interval_seconds{sensor=\"outdoor\"} 120.0\n"));
+        assert!(!buf.contains("indoor"));
+    }
+
+    #[test]
+    fn test_health_metrics_sensor() {
+        let mut registry = <Registry>::default();
+        let indoor = HealthMetrics::new(&mut registry).sensor_name(Some("indoor".to_owned()));
+        let outdoor = indoor.sensor(Some("outdoor".to_owned()));
+        outdoor.transition(SensorState::Degraded);
+        indoor.stuck(true);
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+        assert!(buf.contains("strudel_sensor_healthy{sensor=\"indoor\"} 1\n"));
+        assert!(buf.contains("strudel_sensor_healthy{sensor=\"outdoor\"} 0\n"));
+        assert!(buf.contains("strudel_sensor_stuck{sensor=\"indoor\"} 1\n"));
+        assert!(buf.contains("strudel_sensor_stuck{sensor=\"outdoor\"} 0\n"));
+        assert!(buf.contains("strudel_state_transitions_total{sensor=\"outdoor\",to=\"degraded\"} 1\n"));
+        assert!(!buf.contains("sensor=\"\""));
+
+        // Renaming moves the series of the sensor and assumes the new one is healthy
+        outdoor.rename(Some("garage".to_owned()));
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+        assert!(!buf.contains("sensor=\"outdoor\""));
+        assert!(buf.contains("strudel_sensor_healthy{sensor=\"garage\"} 1\n"));
+    }
+
+    #[test]
+    fn test_timing_metrics() {
+        let mut registry = <Registry>::default();
+        let timing = TimingMetrics::new(&mut registry);
+
+        timing.set(Some(8.5));
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+        assert!(buf.contains("strudel_timing_cycles_per_us 8.5\n"));
+
+        timing.set(None);
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+        assert!(buf.contains("strudel_timing_cycles_per_us 0.0\n"));
+    }
+
+    #[test]
+    fn test_build_metrics_register() {
+        let mut registry = <Registry>::default();
+        BuildMetrics::register(&mut registry);
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("strudel_build_info{version=\""));
+        assert!(buf.contains("} 1\n"));
     }
 }
@@ -0,0 +1,239 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! Modbus TCP server exposing the most recent reading as holding registers, for PLCs
+//! and other industrial equipment that only speak Modbus.
+//!
+//! Registers are read-only, requests to write them are rejected with an illegal function
+//! exception. All registers can be read in a single request starting at address 0.
+//!
+//! | Address | Register                 | Value                                              |
+//! |---------|--------------------------|----------------------------------------------------|
+//! | 0       | `TEMPERATURE_REGISTER`   | Temperature in tenths of degrees celsius, as `i16` |
+//! | 1       | `HUMIDITY_REGISTER`      | Relative humidity in tenths of a percent, as `i16` |
+//! | 2       | `AGE_REGISTER`           | Seconds since the reading was taken, as `u16`      |
+
+use crate::clock::{Clock, SystemClock};
+use crate::sensor::{LatestReading, LatestReadingCell};
+use std::future::{self, Ready};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_modbus::server::tcp::Server;
+use tokio_modbus::server::Service;
+use tokio_modbus::{Address, Exception, Quantity, Request, Response};
+
+/// Holding register with the temperature of the most recent reading in tenths of degrees
+/// celsius, as a two's complement `i16`, or `NO_READING` before the sensor has been read.
+pub const TEMPERATURE_REGISTER: Address = 0;
+
+/// Holding register with the relative humidity of the most recent reading in tenths of a
+/// percent, as a two's complement `i16`, or `NO_READING` before the sensor has been read.
+pub const HUMIDITY_REGISTER: Address = 1;
+
+/// Holding register with the number of seconds since the most recent reading was taken,
+/// capped at `u16::MAX`, which is also its value before the sensor has been read.
+pub const AGE_REGISTER: Address = 2;
+
+/// Number of holding registers, starting at address 0.
+pub const REGISTER_COUNT: Quantity = 3;
+
+/// Value of the temperature and humidity registers before the sensor has been read,
+/// `i16::MIN`. Scaled readings are clamped to the range above it.
+pub const NO_READING: u16 = i16::MIN as u16;
+
+/// Modbus service answering requests to read holding registers from the most recent
+/// reading in a `LatestReadingCell`.
+///
+/// When more than one sensor has been read, registers are based on the reading that
+/// was stored last, see `LatestReadingCell::newest`.
+#[derive(Debug, Clone)]
+pub struct ReadingRegisters {
+    latest: Arc<LatestReadingCell>,
+    clock: Arc<dyn Clock>,
+}
+
+impl ReadingRegisters {
+    pub fn new(latest: Arc<LatestReadingCell>) -> Self {
+        Self {
+            latest,
+            clock: SystemClock::shared(),
+        }
+    }
+
+    /// Use `clock` to compute how long ago readings were taken. Defaults to the system clock.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Current values of all registers, in order of their addresses.
+    pub fn values(&self) -> [u16; REGISTER_COUNT as usize] {
+        match self.latest.newest() {
+            Some(r) => [
+                scaled(f64::from(r.temperature)),
+                scaled(f64::from(r.humidity)),
+                age_secs(&r, self.clock.as_ref()),
+            ],
+            None => [NO_READING, NO_READING, u16::MAX],
+        }
+    }
+
+    fn read(&self, addr: Address, count: Quantity) -> Result<Vec<u16>, Exception> {
+        let start = usize::from(addr);
+        let end = start + usize::from(count);
+        if count == 0 || end > usize::from(REGISTER_COUNT) {
+            return Err(Exception::IllegalDataAddress);
+        }
+
+        Ok(self.values()[start..end].to_vec())
+    }
+}
+
+impl Service for ReadingRegisters {
+    type Request = Request<'static>;
+    type Response = Response;
+    type Exception = Exception;
+    type Future = Ready<Result<Response, Exception>>;
+
+    fn call(&self, req: Self::Request) -> Self::Future {
+        let res = match req {
+            Request::ReadHoldingRegisters(addr, count) => self.read(addr, count).map(Response::ReadHoldingRegisters),
+            // Everything else, including writes to the holding registers, isn't supported
+            _ => Err(Exception::IllegalFunction),
+        };
+
+        future::ready(res)
+    }
+}
+
+/// Scale `value` to tenths as a two's complement `i16`, clamped to stay clear of `NO_READING`.
+fn scaled(value: f64) -> u16 {
+    let tenths = (value * 10.0)
+        .round()
+        .clamp(f64::from(i16::MIN + 1), f64::from(i16::MAX));
+    tenths as i16 as u16
+}
+
+fn age_secs(reading: &LatestReading, clock: &dyn Clock) -> u16 {
+    u16::try_from(reading.age(clock).as_secs()).unwrap_or(u16::MAX)
+}
+
+/// Serve `registers` to Modbus TCP clients connecting to `listener` until an error
+/// accepting connections. Errors handling a single client only disconnect that client.
+pub async fn serve(listener: TcpListener, registers: ReadingRegisters) -> io::Result<()> {
+    let server = Server::new(listener);
+    let on_connected = |stream: TcpStream, _addr: SocketAddr| {
+        let registers = registers.clone();
+        async move { Ok(Some((registers, stream))) }
+    };
+    let on_process_error = |e: io::Error| {
+        tracing::debug!(message = "modbus client disconnected", error = %e);
+    };
+
+    server.serve(&on_connected, on_process_error).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::{serve, ReadingRegisters, AGE_REGISTER, NO_READING, REGISTER_COUNT, TEMPERATURE_REGISTER};
+    use crate::clock::{Clock, MockClock};
+    use crate::sensor::{Humidity, LatestReading, LatestReadingCell, Measurement, TemperatureCelsius};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+    use tokio::net::TcpListener;
+    use tokio_modbus::client::{tcp, Reader, Writer};
+    use tokio_modbus::Exception;
+
+    async fn start(registers: ReadingRegisters) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, registers));
+        address
+    }
+
+    fn reading(temperature: f64, humidity: f64, clock: &MockClock) -> LatestReading {
+        LatestReading::new(
+            Measurement {
+                temperature: TemperatureCelsius::from(temperature),
+                humidity: Humidity::from(humidity),
+            },
+            UNIX_EPOCH + Duration::from_secs(1000),
+            clock.now_monotonic(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_no_reading() {
+        let address = start(ReadingRegisters::new(Arc::new(LatestReadingCell::new()))).await;
+        let mut ctx = tcp::connect(address).await.unwrap();
+
+        let values = ctx.read_holding_registers(0, REGISTER_COUNT).await.unwrap().unwrap();
+        assert_eq!(vec![NO_READING, NO_READING, u16::MAX], values);
+    }
+
+    #[tokio::test]
+    async fn test_reading() {
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1000));
+        let latest = Arc::new(LatestReadingCell::new());
+        latest.set(reading(-4.56, 41.04, &clock));
+        clock.advance(Duration::from_secs(90));
+
+        let registers = ReadingRegisters::new(latest.clone()).clock(Arc::new(clock.clone()));
+        let address = start(registers).await;
+        let mut ctx = tcp::connect(address).await.unwrap();
+
+        let values = ctx.read_holding_registers(0, REGISTER_COUNT).await.unwrap().unwrap();
+        assert_eq!(vec![-46i16 as u16, 410, 90], values);
+
+        let values = ctx.read_holding_registers(AGE_REGISTER, 1).await.unwrap().unwrap();
+        assert_eq!(vec![90], values);
+
+        // Age is capped instead of wrapping around
+        clock.advance(Duration::from_secs(100_000));
+        let values = ctx.read_holding_registers(AGE_REGISTER, 1).await.unwrap().unwrap();
+        assert_eq!(vec![u16::MAX], values);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_requests() {
+        let address = start(ReadingRegisters::new(Arc::new(LatestReadingCell::new()))).await;
+        let mut ctx = tcp::connect(address).await.unwrap();
+
+        let res = ctx.read_holding_registers(AGE_REGISTER, 2).await.unwrap();
+        assert_eq!(Err(Exception::IllegalDataAddress), res);
+
+        let res = ctx.read_holding_registers(0, 0).await.unwrap();
+        assert_eq!(Err(Exception::IllegalDataAddress), res);
+
+        let res = ctx.write_single_register(TEMPERATURE_REGISTER, 215).await.unwrap();
+        assert_eq!(Err(Exception::IllegalFunction), res);
+
+        let res = ctx
+            .write_multiple_registers(TEMPERATURE_REGISTER, &[215, 400])
+            .await
+            .unwrap();
+        assert_eq!(Err(Exception::IllegalFunction), res);
+
+        // Registers can still be read after rejected requests
+        let values = ctx.read_holding_registers(0, REGISTER_COUNT).await.unwrap().unwrap();
+        assert_eq!(vec![NO_READING, NO_READING, u16::MAX], values);
+    }
+}
@@ -0,0 +1,438 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+use crate::metrics::{Locks, PushMetrics};
+use crate::sensor::{Measurement, ReadingEvent};
+use async_trait::async_trait;
+use opentelemetry::metrics::{Counter, MeterProvider as _, Result as MetricsResult};
+#[cfg(feature = "otel")]
+use opentelemetry::trace::TraceError;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{MetricsExporter, MetricsExporterBuilder, WithExportConfig};
+use opentelemetry_sdk::metrics::data::{ResourceMetrics, Temporality};
+use opentelemetry_sdk::metrics::exporter::PushMetricsExporter;
+use opentelemetry_sdk::metrics::reader::{
+    AggregationSelector, DefaultAggregationSelector, DefaultTemporalitySelector, TemporalitySelector,
+};
+use opentelemetry_sdk::metrics::{Aggregation, InstrumentKind, MeterProvider, PeriodicReader};
+#[cfg(feature = "otel")]
+use opentelemetry_sdk::trace::Tracer;
+use opentelemetry_sdk::{runtime, Resource};
+use std::fmt::{self, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const TARGET: &str = "otlp";
+const SERVICE_INSTANCE_ID: &str = "service.instance.id";
+
+/// Protocol used to send metrics to an OpenTelemetry collector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OtlpProtocol {
+    /// OTLP over gRPC, usually port 4317
+    Grpc,
+    /// OTLP with protobuf payloads over HTTP, usually port 4318
+    HttpProto,
+}
+
+/// Export temperature and humidity readings as OpenTelemetry metrics using OTLP.
+///
+/// Metrics mirror the Prometheus metrics for readings and are pushed to a collector
+/// periodically from a background task. Failed exports are counted as push errors for
+/// the `otlp` target. `shutdown` must be called before exiting to flush any metrics
+/// that haven't been sent yet.
+pub struct OtlpExporter {
+    provider: MeterProvider,
+    latest: Arc<Mutex<Option<(Measurement, SystemTime)>>>,
+    locks: Locks,
+    collections: Counter<u64>,
+    errors: Counter<u64>,
+}
+
+impl OtlpExporter {
+    /// Create a new exporter sending metrics to `endpoint` every `interval`. Metrics are
+    /// sent with `instance` as the `service.instance.id` resource attribute. This method
+    /// must be called from within a Tokio runtime.
+    pub fn new(
+        endpoint: &str,
+        protocol: OtlpProtocol,
+        instance: &str,
+        interval: Duration,
+        metrics: &PushMetrics,
+    ) -> MetricsResult<Self> {
+        let builder: MetricsExporterBuilder = match protocol {
+            OtlpProtocol::Grpc => opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint)
+                .into(),
+            OtlpProtocol::HttpProto => opentelemetry_otlp::new_exporter().http().with_endpoint(endpoint).into(),
+        };
+
+        let exporter = CountingExporter {
+            inner: builder.build_metrics_exporter(
+                Box::new(DefaultTemporalitySelector::new()),
+                Box::new(DefaultAggregationSelector::new()),
+            )?,
+            errors: metrics.errors(TARGET),
+        };
+
+        let reader = PeriodicReader::builder(exporter, runtime::Tokio)
+            .with_interval(interval)
+            .build();
+        let provider = MeterProvider::builder()
+            .with_reader(reader)
+            .with_resource(resource(instance))
+            .build();
+        let meter = provider.meter("strudel");
+        let latest: Arc<Mutex<Option<(Measurement, SystemTime)>>> = Arc::new(Mutex::new(None));
+        // A panic while updating the latest reading mustn't stop later collections
+        let locks = Locks::default();
+
+        let (latest_ref, locks_ref) = (latest.clone(), locks.clone());
+        meter
+            .f64_observable_gauge("strudel_temperature_degrees")
+            .with_description("Temperature in celsius")
+            .with_callback(move |obs| {
+                if let Some((m, _)) = *locks_ref.lock(&latest_ref) {
+                    obs.observe(m.temperature.into(), &[]);
+                }
+            })
+            .init();
+
+        let (latest_ref, locks_ref) = (latest.clone(), locks.clone());
+        meter
+            .f64_observable_gauge("strudel_relative_humidity")
+            .with_description("Relative humidity (0-100)")
+            .with_callback(move |obs| {
+                if let Some((m, _)) = *locks_ref.lock(&latest_ref) {
+                    obs.observe(m.humidity.into(), &[]);
+                }
+            })
+            .init();
+
+        let (latest_ref, locks_ref) = (latest.clone(), locks.clone());
+        meter
+            .f64_observable_gauge("strudel_last_read_timestamp")
+            .with_description("Timestamp of last successful read")
+            .with_callback(move |obs| {
+                if let Some((_, ts)) = *locks_ref.lock(&latest_ref) {
+                    if let Ok(d) = ts.duration_since(UNIX_EPOCH) {
+                        obs.observe(d.as_secs_f64(), &[]);
+                    }
+                }
+            })
+            .init();
+
+        let collections = meter
+            .u64_counter("strudel_collections")
+            .with_description("Number of attempted reads")
+            .init();
+        let errors = meter
+            .u64_counter("strudel_errors")
+            .with_description("Number of failed reads by type")
+            .init();
+
+        Ok(Self {
+            provider,
+            latest,
+            locks,
+            collections,
+            errors,
+        })
+    }
+
+    /// Record the result of a read. Intended to be used as a read handler of a
+    /// `SensorWorker`, see `SensorWorker::on_read`, so that counters see every read.
+    pub fn update(&self, event: &ReadingEvent) {
+        self.collections.add(1, &[]);
+
+        match &event.result {
+            Ok(m) => {
+                *self.locks.lock(&self.latest) = Some((*m, event.timestamp));
+            }
+            Err(e) => {
+                self.errors.add(1, &[KeyValue::new("kind", e.kind().as_label())]);
+            }
+        }
+    }
+
+    /// Export any metrics that haven't been sent yet. This method blocks until the
+    /// export is done and so should be called via `spawn_blocking` or similar. Failed
+    /// exports are counted and logged but not returned.
+    pub fn flush(&self) -> MetricsResult<()> {
+        self.provider.force_flush()
+    }
+
+    /// Export any metrics that haven't been sent yet and stop the background task that
+    /// exports metrics. This method blocks until the export is done and so should be
+    /// called via `spawn_blocking` or similar.
+    pub fn shutdown(&self) -> MetricsResult<()> {
+        // Shutting down the provider doesn't export pending metrics since the reader is
+        // marked as shut down before its final collection (and so always returns an error)
+        // so flush explicitly first.
+        let res = self.provider.force_flush();
+        if let Err(e) = self.provider.shutdown() {
+            tracing::debug!(message = "error shutting down otlp meter provider", error = %e);
+        }
+
+        res
+    }
+}
+
+/// Create a tracer that exports spans to `endpoint` from a background task, so that the
+/// trace IDs attached to error counters as exemplars point to traces the collector has.
+/// Spans are sent with `instance` as the `service.instance.id` resource attribute.
+///
+/// The tracer provider is installed globally and
+/// `opentelemetry::global::shutdown_tracer_provider` must be called before exiting to
+/// export any spans that haven't been sent yet. This method must be called from within
+/// a Tokio runtime.
+#[cfg(feature = "otel")]
+pub fn span_tracer(endpoint: &str, protocol: OtlpProtocol, instance: &str) -> Result<Tracer, TraceError> {
+    let builder: opentelemetry_otlp::SpanExporterBuilder = match protocol {
+        OtlpProtocol::Grpc => opentelemetry_otlp::new_exporter()
+            .tonic()
+            .with_endpoint(endpoint)
+            .into(),
+        OtlpProtocol::HttpProto => opentelemetry_otlp::new_exporter().http().with_endpoint(endpoint).into(),
+    };
+
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(builder)
+        .with_trace_config(opentelemetry_sdk::trace::config().with_resource(resource(instance)))
+        .install_batch(runtime::Tokio)
+}
+
+/// Attributes of everything exported, identifying this instance of strudel.
+fn resource(instance: &str) -> Resource {
+    Resource::default().merge(&Resource::new([KeyValue::new(
+        SERVICE_INSTANCE_ID,
+        instance.to_owned(),
+    )]))
+}
+
+impl fmt::Debug for OtlpExporter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OtlpExporter")
+            .field("latest", &self.latest)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Exporter that counts failed exports before passing them along to the SDK, which
+/// only logs them.
+#[derive(Debug)]
+struct CountingExporter {
+    inner: MetricsExporter,
+    errors: prometheus_client::metrics::counter::Counter,
+}
+
+impl TemporalitySelector for CountingExporter {
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        self.inner.temporality(kind)
+    }
+}
+
+impl AggregationSelector for CountingExporter {
+    fn aggregation(&self, kind: InstrumentKind) -> Aggregation {
+        self.inner.aggregation(kind)
+    }
+}
+
+#[async_trait]
+impl PushMetricsExporter for CountingExporter {
+    async fn export(&self, metrics: &mut ResourceMetrics) -> MetricsResult<()> {
+        let res = self.inner.export(metrics).await;
+        if let Err(e) = &res {
+            self.errors.inc();
+            tracing::warn!(message = "unable to export metrics via otlp", error = %e);
+        }
+
+        res
+    }
+
+    async fn force_flush(&self) -> MetricsResult<()> {
+        self.inner.force_flush().await
+    }
+
+    fn shutdown(&self) -> MetricsResult<()> {
+        self.inner.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "otel")]
+    use super::span_tracer;
+    use super::{OtlpExporter, OtlpProtocol};
+    use crate::metrics::PushMetrics;
+    use crate::sensor::{Clamped, Humidity, Measurement, ReadingEvent, SensorError, TemperatureCelsius};
+    use axum::body::Bytes;
+    use axum::extract::State;
+    use axum::routing::post;
+    use axum::Router;
+    use prometheus_client::encoding::text;
+    use prometheus_client::registry::Registry;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant, SystemTime};
+    use tracing::Span;
+
+    type Requests = Arc<Mutex<Vec<usize>>>;
+
+    /// Start a fake collector that records the size of each OTLP/HTTP metrics request
+    async fn stub_collector() -> (SocketAddr, Requests) {
+        stub_collector_at("/v1/metrics").await
+    }
+
+    /// Start a fake collector that records the size of each OTLP/HTTP request to `path`
+    async fn stub_collector_at(path: &str) -> (SocketAddr, Requests) {
+        let requests = Requests::default();
+        let app = Router::new()
+            .route(
+                path,
+                post(|State(requests): State<Requests>, body: Bytes| async move {
+                    requests.lock().unwrap().push(body.len());
+                }),
+            )
+            .with_state(requests.clone());
+
+        let server = axum::Server::bind(&([127, 0, 0, 1], 0).into()).serve(app.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, requests)
+    }
+
+    fn event(ok: bool) -> ReadingEvent {
+        let result = if ok {
+            Ok(Measurement {
+                temperature: TemperatureCelsius::from(21.0),
+                humidity: Humidity::from(40.0),
+            })
+        } else {
+            Err(SensorError::timeout("timeout"))
+        };
+
+        ReadingEvent {
+            timestamp: SystemTime::now(),
+            instant: Instant::now(),
+            result,
+            clamped: Clamped::default(),
+            attempts: 1,
+            retried_errors: Vec::new(),
+            duration: Duration::ZERO,
+            raw: Vec::new(),
+            pulses: None,
+            span: Span::none(),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_otlp_exporter_shutdown_flushes() {
+        let (addr, requests) = stub_collector().await;
+        let mut registry = <Registry>::default();
+        let metrics = PushMetrics::new(&mut registry);
+
+        // Interval is long enough that the only exports are the one done when the
+        // reader starts and the one done on shutdown
+        let exporter = Arc::new(
+            OtlpExporter::new(
+                &format!("http://{}", addr),
+                OtlpProtocol::HttpProto,
+                "pi",
+                Duration::from_secs(3600),
+                &metrics,
+            )
+            .unwrap(),
+        );
+
+        exporter.update(&event(true));
+        exporter.update(&event(false));
+
+        let exporter_ref = exporter.clone();
+        tokio::task::spawn_blocking(move || exporter_ref.shutdown())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert!(!requests.is_empty());
+        assert!(requests.last().copied().unwrap_or(0) > 0);
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+        assert!(buf.contains("strudel_push_errors_total{target=\"otlp\"} 0\n"));
+    }
+
+    #[cfg(feature = "otel")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_span_tracer_exports_spans() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let (addr, requests) = stub_collector_at("/v1/traces").await;
+        let tracer = span_tracer(&format!("http://{}", addr), OtlpProtocol::HttpProto, "pi").unwrap();
+        let subscriber = tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+
+        // Spans recorded by the tracer are exported when the provider is shut down
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("sensor_read").entered();
+        });
+        tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider)
+            .await
+            .unwrap();
+
+        let requests = requests.lock().unwrap();
+        assert!(!requests.is_empty());
+        assert!(requests.iter().all(|len| *len > 0));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_otlp_exporter_counts_failures() {
+        // Bind and immediately drop a listener to get a port that nothing is listening on
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut registry = <Registry>::default();
+        let metrics = PushMetrics::new(&mut registry);
+        let exporter = Arc::new(
+            OtlpExporter::new(
+                &format!("http://{}", addr),
+                OtlpProtocol::HttpProto,
+                "pi",
+                Duration::from_secs(3600),
+                &metrics,
+            )
+            .unwrap(),
+        );
+
+        exporter.update(&event(true));
+
+        let exporter_ref = exporter.clone();
+        tokio::task::spawn_blocking(move || exporter_ref.flush())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+        // The reader also exports once when started so there may be more than one failure
+        assert!(buf.contains("strudel_push_errors_total{target=\"otlp\"} "));
+        assert!(!buf.contains("strudel_push_errors_total{target=\"otlp\"} 0\n"));
+    }
+}
@@ -0,0 +1,32 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! Items needed to build an exporter from a sensor, for importing all at once with
+//! `use strudel::prelude::*`.
+//!
+//! Everything here is also available from the module it's defined in, which has the
+//! less common items as well, like `strudel::sensor::Calibration` or
+//! `strudel::http::CorsSettings`.
+
+pub use crate::http::{router, RequestState, RequestStateBuilder};
+pub use crate::metrics::{Registries, TemperatureMetrics};
+pub use crate::sensor::{
+    DHT22Sensor, DHT22SensorBuilder, DataPin, DynDHT22Sensor, Humidity, Level, Measurement, PinMode, ReadingEvent,
+    Sensor, SensorError, SensorErrorKind, SensorWorker, TemperatureCelsius, TemperatureFahrenheit, TemperatureKelvin,
+    TemperatureUnit, VapourPressureDeficit, WorkerHandle,
+};
@@ -0,0 +1,253 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+use prometheus_client::collector::Collector;
+use prometheus_client::metrics::counter::ConstCounter;
+use prometheus_client::metrics::gauge::ConstGauge;
+use prometheus_client::registry::{Descriptor, LocalMetric, Registry};
+use prometheus_client::MaybeOwned;
+use std::borrow::Cow;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Metrics about the `strudel` process itself: start time, uptime, and on Linux, memory
+/// usage, CPU usage, and open file descriptors.
+///
+/// Values are computed each time metrics are collected (i.e. when Prometheus scrapes
+/// `strudel`) instead of being updated in the background.
+#[derive(Debug)]
+pub struct ProcessMetrics {
+    start_time: f64,
+    start: Instant,
+}
+
+impl ProcessMetrics {
+    /// Register process metrics with the given registry. Process start time is
+    /// assumed to be the time this method is called.
+    pub fn register(reg: &mut Registry) {
+        let start_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or(0.0);
+
+        reg.register_collector(Box::new(Self {
+            start_time,
+            start: Instant::now(),
+        }));
+    }
+
+    fn gauge(name: &str, help: &str, value: f64) -> (Descriptor, Box<dyn LocalMetric>) {
+        (
+            Descriptor::new(name, help, None, None, Vec::new()),
+            Box::new(ConstGauge::new(value)),
+        )
+    }
+
+    fn counter(name: &str, help: &str, value: f64) -> (Descriptor, Box<dyn LocalMetric>) {
+        (
+            Descriptor::new(name, help, None, None, Vec::new()),
+            Box::new(ConstCounter::new(value)),
+        )
+    }
+
+    #[cfg(target_os = "linux")]
+    fn platform_metrics(&self) -> Vec<(Descriptor, Box<dyn LocalMetric>)> {
+        let mut out = Vec::new();
+
+        match std::fs::read_to_string("/proc/self/stat").map(|s| linux::parse_stat(&s)) {
+            Ok(Some(stat)) => {
+                let ticks = linux::clock_ticks();
+                let page_size = linux::page_size();
+
+                out.push(Self::counter(
+                    "strudel_process_cpu_seconds",
+                    "Total user and system CPU time spent in seconds",
+                    (stat.utime + stat.stime) as f64 / ticks,
+                ));
+                out.push(Self::gauge(
+                    "strudel_process_resident_memory_bytes",
+                    "Resident memory size in bytes",
+                    (stat.rss * page_size) as f64,
+                ));
+            }
+            Ok(None) => {
+                tracing::warn!(message = "unable to parse process stat file");
+            }
+            Err(e) => {
+                tracing::warn!(message = "unable to read process stat file", error = %e);
+            }
+        }
+
+        match std::fs::read_dir("/proc/self/fd") {
+            Ok(entries) => {
+                out.push(Self::gauge(
+                    "strudel_process_open_fds",
+                    "Number of open file descriptors",
+                    entries.count() as f64,
+                ));
+            }
+            Err(e) => {
+                tracing::warn!(message = "unable to read process file descriptors", error = %e);
+            }
+        }
+
+        out
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn platform_metrics(&self) -> Vec<(Descriptor, Box<dyn LocalMetric>)> {
+        Vec::new()
+    }
+}
+
+impl Collector for ProcessMetrics {
+    fn collect<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = (Cow<'a, Descriptor>, MaybeOwned<'a, Box<dyn LocalMetric>>)> + 'a> {
+        let mut metrics = vec![
+            Self::gauge(
+                "strudel_process_start_time_seconds",
+                "Start time of the process since the UNIX epoch in seconds",
+                self.start_time,
+            ),
+            Self::gauge(
+                "strudel_process_uptime_seconds",
+                "Time since the process started in seconds",
+                self.start.elapsed().as_secs_f64(),
+            ),
+        ];
+
+        metrics.extend(self.platform_metrics());
+        Box::new(
+            metrics
+                .into_iter()
+                .map(|(desc, metric)| (Cow::Owned(desc), MaybeOwned::Owned(metric))),
+        )
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    /// Fields from `/proc/self/stat` used for process metrics.
+    #[derive(Debug, PartialEq, Eq)]
+    pub(super) struct Stat {
+        /// Time spent in user mode, in clock ticks
+        pub(super) utime: u64,
+        /// Time spent in kernel mode, in clock ticks
+        pub(super) stime: u64,
+        /// Resident set size, in pages
+        pub(super) rss: u64,
+    }
+
+    /// Parse the contents of `/proc/self/stat`, returning `None` if it is malformed.
+    ///
+    /// See `proc(5)` for the format of this file.
+    pub(super) fn parse_stat(contents: &str) -> Option<Stat> {
+        // The second field is the name of the executable in parens and may contain
+        // spaces or parens itself. Skip to the last paren so that the rest of the
+        // fields can be split on whitespace.
+        let (_, rest) = contents.rsplit_once(')')?;
+        let fields: Vec<&str> = rest.split_whitespace().collect();
+
+        // Field numbers in proc(5) are 1-based and start before the executable name
+        // so `state` (field 3) is at index 0 here.
+        let utime = fields.get(11)?.parse().ok()?;
+        let stime = fields.get(12)?.parse().ok()?;
+        let rss = fields.get(21)?.parse().ok()?;
+
+        Some(Stat { utime, stime, rss })
+    }
+
+    /// Number of clock ticks per second, used for CPU time in `/proc/self/stat`.
+    pub(super) fn clock_ticks() -> f64 {
+        let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+        if ticks > 0 {
+            ticks as f64
+        } else {
+            100.0
+        }
+    }
+
+    /// Size of a memory page in bytes, used for resident memory in `/proc/self/stat`.
+    pub(super) fn page_size() -> u64 {
+        let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        if size > 0 {
+            size as u64
+        } else {
+            4096
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ProcessMetrics;
+    use prometheus_client::encoding::text;
+    use prometheus_client::registry::Registry;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_parse_stat() {
+        let contents = "12345 (strudel) S 1 12345 12345 0 -1 4194560 1296 0 0 0 27 14 0 0 20 0 \
+            6 0 3735 1158561792 2316 18446744073709551615 1 1 0 0 0 0 0 4096 0 0 0 0 17 2 0 0 0 0 0 \
+            0 0 0 0 0 0 0 0\n";
+
+        let stat = super::linux::parse_stat(contents).unwrap();
+        assert_eq!(27, stat.utime);
+        assert_eq!(14, stat.stime);
+        assert_eq!(2316, stat.rss);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_parse_stat_name_with_spaces() {
+        let contents = "12345 (str (u) del) R 1 12345 12345 0 -1 4194560 1296 0 0 0 5 3 0 0 20 0 \
+            6 0 3735 1158561792 100 18446744073709551615 1 1 0 0 0 0 0 4096 0 0 0 0 17 2 0 0 0 0 0 \
+            0 0 0 0 0 0 0 0\n";
+
+        let stat = super::linux::parse_stat(contents).unwrap();
+        assert_eq!(5, stat.utime);
+        assert_eq!(3, stat.stime);
+        assert_eq!(100, stat.rss);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_parse_stat_truncated() {
+        assert_eq!(None, super::linux::parse_stat("12345 (strudel) S 1 12345"));
+        assert_eq!(None, super::linux::parse_stat(""));
+    }
+
+    #[test]
+    fn test_process_metrics_encoded() {
+        let mut registry = <Registry>::default();
+        ProcessMetrics::register(&mut registry);
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("strudel_process_start_time_seconds "));
+        assert!(buf.contains("strudel_process_uptime_seconds "));
+
+        #[cfg(target_os = "linux")]
+        {
+            assert!(buf.contains("strudel_process_cpu_seconds_total "));
+            assert!(buf.contains("strudel_process_resident_memory_bytes "));
+            assert!(buf.contains("strudel_process_open_fds "));
+        }
+    }
+}
@@ -0,0 +1,283 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+use crate::http::METRICS_TEXT;
+use crate::metrics::{PushMetrics, Registries};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE};
+use base64::Engine;
+use hyper::client::HttpConnector;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::http::uri::InvalidUri;
+use hyper::{Body, Client, Method, Request, StatusCode, Uri};
+use prometheus_client::metrics::counter::Counter;
+use std::error::Error;
+use std::fmt::{self, Formatter};
+use std::time::Duration;
+
+const TARGET: &str = "pushgateway";
+const JOB: &str = "strudel";
+const MAX_ATTEMPTS: u32 = 3;
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Error pushing metrics to or deleting metrics from a Pushgateway
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum PushError {
+    Uri(InvalidUri),
+    Encode(fmt::Error),
+    Request(hyper::Error),
+    Status(StatusCode),
+}
+
+impl fmt::Display for PushError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PushError::Uri(e) => write!(f, "invalid pushgateway URL: {}", e),
+            PushError::Encode(e) => write!(f, "unable to encode metrics: {}", e),
+            PushError::Request(e) => write!(f, "unable to make pushgateway request: {}", e),
+            PushError::Status(s) => write!(f, "unexpected pushgateway response status: {}", s),
+        }
+    }
+}
+
+impl Error for PushError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PushError::Uri(e) => Some(e),
+            PushError::Encode(e) => Some(e),
+            PushError::Request(e) => Some(e),
+            PushError::Status(_) => None,
+        }
+    }
+}
+
+/// Push the contents of a registry to a Prometheus Pushgateway.
+///
+/// Metrics are pushed to the group `job=strudel,instance=<instance>` plus any extra
+/// grouping labels. Failed requests are retried with backoff and counted as push errors
+/// for the `pushgateway` target.
+#[derive(Debug)]
+pub struct PushgatewayClient {
+    client: Client<HttpConnector>,
+    uri: Uri,
+    auth: Option<String>,
+    errors: Counter,
+    backoff: Duration,
+}
+
+impl PushgatewayClient {
+    /// Create a new client for the Pushgateway at `url` (e.g. `http://localhost:9091`),
+    /// optionally authenticating with a username and password.
+    pub fn new(
+        url: &str,
+        instance: &str,
+        groups: &[(String, String)],
+        auth: Option<(&str, &str)>,
+        metrics: &PushMetrics,
+    ) -> Result<Self, PushError> {
+        // Label values are always base64 encoded since they may contain slashes or
+        // other characters that aren't valid in a path segment.
+        let mut path = format!(
+            "{}/metrics/job/{}/instance@base64/{}",
+            url.trim_end_matches('/'),
+            JOB,
+            URL_SAFE.encode(instance)
+        );
+
+        for (k, v) in groups {
+            path.push_str(&format!("/{}@base64/{}", k, URL_SAFE.encode(v)));
+        }
+
+        let uri = path.parse::<Uri>().map_err(PushError::Uri)?;
+        let auth = auth.map(|(user, pass)| format!("Basic {}", STANDARD.encode(format!("{}:{}", user, pass))));
+
+        Ok(Self {
+            client: Client::new(),
+            uri,
+            auth,
+            errors: metrics.errors(TARGET),
+            backoff: MIN_BACKOFF,
+        })
+    }
+
+    /// Replace all metrics in the group with the contents of `registries`. Groups of
+    /// metrics that can't be encoded are skipped.
+    pub async fn push(&self, registries: &Registries) -> Result<(), PushError> {
+        let encoded = registries.encode().map_err(PushError::Encode)?;
+        if !encoded.failed.is_empty() {
+            tracing::warn!(message = "unable to encode some metrics for pushgateway", groups = ?encoded.failed);
+        }
+
+        self.send_with_retry(Method::PUT, encoded.text).await
+    }
+
+    /// Delete all metrics in the group.
+    pub async fn delete(&self) -> Result<(), PushError> {
+        self.send_with_retry(Method::DELETE, String::new()).await
+    }
+
+    async fn send_with_retry(&self, method: Method, body: String) -> Result<(), PushError> {
+        let mut backoff = self.backoff;
+        let mut attempt = 1;
+
+        loop {
+            match self.send(method.clone(), body.clone()).await {
+                Ok(_) => return Ok(()),
+                Err(e) => {
+                    self.errors.inc();
+                    if attempt >= MAX_ATTEMPTS {
+                        return Err(e);
+                    }
+
+                    tracing::warn!(message = "pushgateway request failed, retrying", attempt = attempt, error = %e);
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    async fn send(&self, method: Method, body: String) -> Result<(), PushError> {
+        let mut req = Request::builder()
+            .method(method)
+            .uri(self.uri.clone())
+            .header(CONTENT_TYPE, METRICS_TEXT);
+
+        if let Some(auth) = &self.auth {
+            req = req.header(AUTHORIZATION, auth);
+        }
+
+        // Only the method, URI, and headers that are known to be valid are set
+        let req = req.body(Body::from(body)).unwrap();
+        let res = self.client.request(req).await.map_err(PushError::Request)?;
+
+        if res.status().is_success() {
+            Ok(())
+        } else {
+            Err(PushError::Status(res.status()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PushError, PushgatewayClient};
+    use crate::metrics::{PushMetrics, Registries};
+    use axum::body::Bytes;
+    use axum::extract::State;
+    use axum::http::{HeaderMap, Method, StatusCode, Uri};
+    use axum::Router;
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[derive(Debug)]
+    struct Recorded {
+        method: Method,
+        path: String,
+        auth: Option<String>,
+        body: String,
+    }
+
+    #[derive(Debug, Default)]
+    struct Stub {
+        requests: Mutex<Vec<Recorded>>,
+        status: Mutex<Option<StatusCode>>,
+    }
+
+    async fn handler(
+        State(stub): State<Arc<Stub>>,
+        method: Method,
+        uri: Uri,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> StatusCode {
+        stub.requests.lock().unwrap().push(Recorded {
+            method,
+            path: uri.path().to_owned(),
+            auth: headers.get("authorization").map(|v| v.to_str().unwrap().to_owned()),
+            body: String::from_utf8(body.to_vec()).unwrap(),
+        });
+
+        stub.status.lock().unwrap().unwrap_or(StatusCode::OK)
+    }
+
+    fn stub_server(stub: Arc<Stub>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().fallback(handler).with_state(stub);
+
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()));
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_pushgateway_push_and_delete() {
+        let stub = Arc::new(Stub::default());
+        let addr = stub_server(stub.clone());
+
+        let mut registries = Registries::new();
+        let metrics = PushMetrics::new(registries.group("push"));
+        let groups = vec![("room".to_owned(), "office".to_owned())];
+        let client = PushgatewayClient::new(
+            &format!("http://{}/", addr),
+            "pi",
+            &groups,
+            Some(("user", "pass")),
+            &metrics,
+        )
+        .unwrap();
+
+        client.push(&registries).await.unwrap();
+        client.delete().await.unwrap();
+
+        let requests = stub.requests.lock().unwrap();
+        assert_eq!(2, requests.len());
+
+        assert_eq!(Method::PUT, requests[0].method);
+        assert_eq!(
+            "/metrics/job/strudel/instance@base64/cGk=/room@base64/b2ZmaWNl",
+            requests[0].path
+        );
+        assert_eq!(Some("Basic dXNlcjpwYXNz".to_owned()), requests[0].auth);
+        assert!(requests[0].body.contains("strudel_push_errors_total"));
+
+        assert_eq!(Method::DELETE, requests[1].method);
+        assert_eq!(requests[0].path, requests[1].path);
+    }
+
+    #[tokio::test]
+    async fn test_pushgateway_push_retries() {
+        let stub = Arc::new(Stub::default());
+        *stub.status.lock().unwrap() = Some(StatusCode::SERVICE_UNAVAILABLE);
+        let addr = stub_server(stub.clone());
+
+        let mut registries = Registries::new();
+        let metrics = PushMetrics::new(registries.group("push"));
+        let mut client = PushgatewayClient::new(&format!("http://{}", addr), "pi", &[], None, &metrics).unwrap();
+        client.backoff = Duration::from_millis(1);
+
+        let res = client.push(&registries).await;
+
+        assert!(matches!(res, Err(PushError::Status(StatusCode::SERVICE_UNAVAILABLE))));
+        assert_eq!(3, stub.requests.lock().unwrap().len());
+        assert_eq!(3, metrics.errors("pushgateway").get());
+        assert_eq!(None, stub.requests.lock().unwrap()[0].auth);
+    }
+}
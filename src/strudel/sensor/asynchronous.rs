@@ -0,0 +1,632 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! Async facade for blocking sensors.
+
+use crate::device::PulseTiming;
+use crate::sensor::core::{Measurement, PulseStats, RawReading, Sensor, SensorError, SensorErrorKind, SensorRanges};
+use crate::sensor::probe::probe_with_retries;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tokio::sync::{oneshot, Mutex as AsyncMutex};
+use tokio::task;
+
+/// Name of the thread sensors are read from.
+const THREAD_NAME: &str = "strudel-sensor";
+
+/// Work run with the sensor on its thread.
+type Command<S> = Box<dyn FnOnce(&mut S) + Send>;
+
+/// Result of a read by `AsyncSensor::read_raw` and what the sensor captured during it.
+#[derive(Debug)]
+pub struct RawRead {
+    pub result: Result<Measurement, SensorError>,
+    /// Bytes decoded by the read, see `Sensor::last_raw`.
+    pub raw: Option<RawReading>,
+    /// Statistics of the pulses captured by the read, see `Sensor::last_pulses`.
+    pub pulses: Option<PulseStats>,
+    /// Kinds of errors of failed reads made before the result, in order. Only probes,
+    /// see `AsyncSensor::probe`, read the sensor more than once.
+    pub retried_errors: Vec<SensorErrorKind>,
+}
+
+impl RawRead {
+    fn failed(e: SensorError) -> Self {
+        Self {
+            result: Err(e),
+            raw: None,
+            pulses: None,
+            retried_errors: Vec::new(),
+        }
+    }
+}
+
+/// Read a blocking `Sensor` from async code.
+///
+/// The sensor is owned by a dedicated thread, named `strudel-sensor`, that's spawned when
+/// the `AsyncSensor` is created and runs reads one at a time in the order they're made,
+/// so that reads always happen on the same thread and only a single read of the sensor is
+/// ever in progress, even if callers give up waiting because of a timeout. Cloning an
+/// `AsyncSensor` shares the same thread and sensor.
+///
+/// Panics while reading the sensor are caught on the thread and returned as
+/// `SensorErrorKind::Internal` errors. The thread keeps running and reads the same sensor
+/// for the commands after it.
+///
+/// Sensors that never block can be read inline on the calling task instead, see
+/// `AsyncSensor::inline`.
+#[derive(Debug)]
+pub struct AsyncSensor<S> {
+    runner: Runner<S>,
+    ranges: SensorRanges,
+    timeout: Option<Duration>,
+}
+
+/// Where commands are run with the sensor.
+#[derive(Debug)]
+enum Runner<S> {
+    /// On a dedicated thread that commands are sent to.
+    Thread {
+        // Shared by clones so that the last one to be closed knows it's the last
+        commands: Arc<Sender<Command<S>>>,
+        thread: Arc<SensorThread>,
+    },
+    /// On the calling task, one command at a time.
+    Inline(Arc<AsyncMutex<S>>),
+}
+
+impl<S> Clone for Runner<S> {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Thread { commands, thread } => Self::Thread {
+                commands: commands.clone(),
+                thread: thread.clone(),
+            },
+            Self::Inline(sensor) => Self::Inline(sensor.clone()),
+        }
+    }
+}
+
+/// The thread reading the sensor, shared by clones of an `AsyncSensor`.
+#[derive(Debug)]
+struct SensorThread {
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl SensorThread {
+    fn spawn<S>(sensor: S, commands: Receiver<Command<S>>) -> Self
+    where
+        S: Sensor,
+    {
+        let handle = thread::Builder::new()
+            .name(THREAD_NAME.to_owned())
+            .spawn(move || Self::run(sensor, commands))
+            .expect("unable to spawn sensor thread");
+
+        Self {
+            handle: Mutex::new(Some(handle)),
+        }
+    }
+
+    /// Run commands until every `AsyncSensor` sending them has been dropped.
+    fn run<S>(mut sensor: S, commands: Receiver<Command<S>>)
+    where
+        S: Sensor,
+    {
+        while let Ok(command) = commands.recv() {
+            command(&mut sensor);
+        }
+    }
+
+    /// Wait for the thread to exit.
+    fn join(&self) {
+        let handle = self.handle.lock().unwrap_or_else(PoisonError::into_inner).take();
+        // Panics are caught while running commands so the thread can't panic
+        if let Some(h) = handle {
+            let _ = h.join();
+        }
+    }
+}
+
+impl<S> AsyncSensor<S>
+where
+    S: Sensor,
+{
+    pub fn new(sensor: S) -> Self {
+        let ranges = sensor.ranges();
+        let (commands, rx) = mpsc::channel();
+        let thread = Arc::new(SensorThread::spawn(sensor, rx));
+
+        Self {
+            runner: Runner::Thread {
+                commands: Arc::new(commands),
+                thread,
+            },
+            ranges,
+            timeout: None,
+        }
+    }
+
+    /// Read the sensor on the task awaiting each read instead of on its own thread, blocking
+    /// that task for as long as the read takes. Only suitable for sensors that return right
+    /// away, like fakes in tests, where it keeps reads visible to a paused Tokio clock.
+    pub fn inline(sensor: S) -> Self {
+        Self {
+            ranges: sensor.ranges(),
+            runner: Runner::Inline(Arc::new(AsyncMutex::new(sensor))),
+            timeout: None,
+        }
+    }
+
+    /// True if the sensor is read inline, see `AsyncSensor::inline`.
+    pub fn is_inline(&self) -> bool {
+        matches!(self.runner, Runner::Inline(_))
+    }
+
+    /// Give up on reads that take longer than `timeout`, including time spent waiting
+    /// for other reads to complete, and return a `SensorErrorKind::ReadTimeout` error.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Range of values the sensor can measure, see `Sensor::ranges`.
+    pub fn ranges(&self) -> SensorRanges {
+        self.ranges
+    }
+
+    /// Read the sensor without blocking the calling task.
+    pub async fn read(&self) -> Result<Measurement, SensorError> {
+        self.read_raw().await.result
+    }
+
+    /// Read the sensor without blocking the calling task, also returning the bytes
+    /// decoded and pulses captured by the read if the sensor exposes them.
+    pub async fn read_raw(&self) -> RawRead {
+        match self.timeout {
+            Some(t) => tokio::time::timeout(t, self.read_serialized())
+                .await
+                .unwrap_or_else(|_| RawRead::failed(SensorError::timeout("timeout waiting for sensor read"))),
+            None => self.read_serialized().await,
+        }
+    }
+
+    /// Read the sensor without blocking the calling task, decoding pulses with `timing`
+    /// instead of the way the sensor is set up to, see `Sensor::read_with_timing`.
+    pub async fn read_with_timing(&self, timing: PulseTiming) -> Result<Measurement, SensorError> {
+        let read = self.serialized(move |s| s.read_with_timing(timing));
+        let res = match self.timeout {
+            Some(t) => tokio::time::timeout(t, read)
+                .await
+                .unwrap_or_else(|_| Err(SensorError::timeout("timeout waiting for sensor read"))),
+            None => read.await,
+        };
+
+        res.and_then(|r| r)
+    }
+
+    /// Read the sensor until a read succeeds or `attempts` reads have failed, waiting
+    /// `delay` between them on the sensor thread, see `startup_probe`. The timeout for
+    /// reads doesn't apply.
+    pub async fn probe(&self, attempts: u32, delay: Duration) -> RawRead {
+        self.serialized(move |s| {
+            let (result, retried_errors) = probe_with_retries(s, attempts, delay);
+            RawRead {
+                result,
+                raw: s.last_raw(),
+                pulses: s.last_pulses(),
+                retried_errors,
+            }
+        })
+        .await
+        .unwrap_or_else(RawRead::failed)
+    }
+
+    async fn read_serialized(&self) -> RawRead {
+        self.serialized(|s| RawRead {
+            result: s.read(),
+            raw: s.last_raw(),
+            pulses: s.last_pulses(),
+            retried_errors: Vec::new(),
+        })
+        .await
+        .unwrap_or_else(RawRead::failed)
+    }
+
+    /// Reset the sensor, see `Sensor::reset`, once any read in progress is complete.
+    /// Returns the timing calibration of the sensor after resetting it, if any.
+    pub async fn reset(&self) -> Option<f64> {
+        let res = self
+            .serialized(|s| {
+                s.reset();
+                s.cycles_per_us()
+            })
+            .await;
+
+        match res {
+            Ok(cycles_per_us) => cycles_per_us,
+            Err(e) => {
+                tracing::error!(message = "sensor panicked while resetting", error = %e);
+                None
+            }
+        }
+    }
+
+    /// Run `f` with the sensor on its thread once any read in progress is complete,
+    /// returning an error if it panicked.
+    async fn serialized<F, T>(&self, f: F) -> Result<T, SensorError>
+    where
+        F: FnOnce(&mut S) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let command: Command<S> = Box::new(move |s| {
+            let res = panic::catch_unwind(AssertUnwindSafe(|| f(s))).map_err(panic_error);
+            if res.is_err() {
+                tracing::warn!(message = "sensor panicked, continuing with the next command");
+            }
+            // The caller may have stopped waiting for the result
+            let _ = tx.send(res);
+        });
+
+        match &self.runner {
+            Runner::Thread { commands, .. } => {
+                if commands.send(command).is_err() {
+                    return Err(SensorError::internal("sensor thread exited"));
+                }
+            }
+            Runner::Inline(sensor) => command(&mut *sensor.lock().await),
+        }
+
+        rx.await
+            .unwrap_or_else(|_| Err(SensorError::internal("sensor thread exited")))
+    }
+
+    /// Drop the sensor once any read in progress is complete, including reads that callers
+    /// gave up waiting for, so that anything it holds like a GPIO pin is released when this
+    /// returns. Other clones of this `AsyncSensor` keep the sensor from being dropped, in
+    /// which case this only waits for reads in progress.
+    pub async fn close(self) {
+        // Commands are run in order so this completes after any made before it
+        let _ = self.serialized(|_| ()).await;
+        // Dropping the last sender stops the thread once it has run every command, so
+        // only the last clone has a thread to wait for. Inline sensors are dropped along
+        // with the last clone.
+        if let Runner::Thread { commands, thread } = self.runner {
+            if Arc::into_inner(commands).is_some() {
+                let _ = task::spawn_blocking(move || thread.join()).await;
+            }
+        }
+    }
+}
+
+/// Convert the payload of a panic into an error, including the panic message if any
+fn panic_error(payload: Box<dyn Any + Send>) -> SensorError {
+    let msg = payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned());
+
+    match msg {
+        Some(m) => SensorError::with_cause(SensorErrorKind::Internal, "sensor panicked", m),
+        None => SensorError::internal("sensor panicked"),
+    }
+}
+
+impl<S> Clone for AsyncSensor<S> {
+    fn clone(&self) -> Self {
+        Self {
+            runner: self.runner.clone(),
+            ranges: self.ranges,
+            timeout: self.timeout,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AsyncSensor;
+    use crate::device::PulseTiming;
+    use crate::sensor::core::{Humidity, Measurement, Sensor, SensorError, SensorErrorKind, TemperatureCelsius};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread::{self, ThreadId};
+    use std::time::Duration;
+
+    /// Sensor that sleeps during reads and tracks the maximum number of concurrent reads
+    #[derive(Debug, Default)]
+    struct SlowSensor {
+        delay: Duration,
+        active: Arc<AtomicUsize>,
+        max_active: Arc<AtomicUsize>,
+    }
+
+    impl Sensor for SlowSensor {
+        fn read(&mut self) -> Result<Measurement, SensorError> {
+            let active = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_active.fetch_max(active, Ordering::SeqCst);
+            thread::sleep(self.delay);
+            self.active.fetch_sub(1, Ordering::SeqCst);
+
+            Ok(Measurement {
+                temperature: TemperatureCelsius::from(21.0),
+                humidity: Humidity::from(40.0),
+            })
+        }
+    }
+
+    /// Sensor that panics on the first read and succeeds afterwards
+    #[derive(Debug, Default)]
+    struct PanicSensor {
+        reads: usize,
+    }
+
+    impl Sensor for PanicSensor {
+        fn read(&mut self) -> Result<Measurement, SensorError> {
+            self.reads += 1;
+            if self.reads == 1 {
+                panic!("sensor exploded");
+            }
+
+            Ok(Measurement {
+                temperature: TemperatureCelsius::from(21.0),
+                humidity: Humidity::from(40.0),
+            })
+        }
+    }
+
+    /// Method called, and the name and ID of the thread it was called from
+    type Call = (&'static str, Option<String>, ThreadId);
+
+    /// Sensor that records each call made to it and the thread it was made from, reading
+    /// the number of calls made so far as the temperature
+    #[derive(Debug, Default)]
+    struct RecordingSensor {
+        calls: Arc<Mutex<Vec<Call>>>,
+        panic_on: Option<usize>,
+    }
+
+    impl RecordingSensor {
+        fn record(&mut self, call: &'static str) -> Result<Measurement, SensorError> {
+            let current = thread::current();
+            let mut calls = self.calls.lock().unwrap();
+            calls.push((call, current.name().map(|n| n.to_owned()), current.id()));
+            if self.panic_on == Some(calls.len()) {
+                drop(calls);
+                panic!("sensor exploded");
+            }
+
+            Ok(Measurement {
+                temperature: TemperatureCelsius::from(calls.len() as f64),
+                humidity: Humidity::from(40.0),
+            })
+        }
+    }
+
+    impl Sensor for RecordingSensor {
+        fn read(&mut self) -> Result<Measurement, SensorError> {
+            self.record("read")
+        }
+
+        fn read_with_timing(&mut self, _timing: PulseTiming) -> Result<Measurement, SensorError> {
+            self.record("read_with_timing")
+        }
+
+        fn reset(&mut self) {
+            let _ = self.record("reset");
+        }
+    }
+
+    /// Sensor that records when it has been dropped
+    #[derive(Debug, Default)]
+    struct DropSensor {
+        inner: SlowSensor,
+        dropped: Arc<AtomicBool>,
+    }
+
+    impl Sensor for DropSensor {
+        fn read(&mut self) -> Result<Measurement, SensorError> {
+            self.inner.read()
+        }
+    }
+
+    impl Drop for DropSensor {
+        fn drop(&mut self) {
+            self.dropped.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_async_sensor_serialized() {
+        let sensor = SlowSensor {
+            delay: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let max_active = sensor.max_active.clone();
+        let async_sensor = AsyncSensor::new(sensor);
+
+        let other = async_sensor.clone();
+        let (a, b, c) = tokio::join!(async_sensor.read(), other.read(), async_sensor.read());
+
+        assert!(a.is_ok());
+        assert!(b.is_ok());
+        assert!(c.is_ok());
+        assert_eq!(1, max_active.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_async_sensor_timeout() {
+        let sensor = SlowSensor {
+            delay: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let async_sensor = AsyncSensor::new(sensor).read_timeout(Duration::from_millis(20));
+
+        let res = async_sensor.read().await;
+        assert_eq!(SensorErrorKind::ReadTimeout, res.unwrap_err().kind());
+    }
+
+    #[tokio::test]
+    async fn test_async_sensor_within_timeout() {
+        let sensor = SlowSensor {
+            delay: Duration::from_millis(5),
+            ..Default::default()
+        };
+        let async_sensor = AsyncSensor::new(sensor).read_timeout(Duration::from_secs(5));
+
+        let res = async_sensor.read().await.unwrap();
+        assert_eq!(TemperatureCelsius::from(21.0), res.temperature);
+    }
+
+    #[tokio::test]
+    async fn test_async_sensor_panic() {
+        let async_sensor = AsyncSensor::new(PanicSensor::default());
+
+        let err = async_sensor.read().await.unwrap_err();
+        assert_eq!(SensorErrorKind::Internal, err.kind());
+        assert_eq!("sensor panicked: sensor exploded", err.to_string());
+
+        let res = async_sensor.read().await.unwrap();
+        assert_eq!(TemperatureCelsius::from(21.0), res.temperature);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_async_sensor_dedicated_thread() {
+        let sensor = RecordingSensor::default();
+        let calls = sensor.calls.clone();
+        let async_sensor = AsyncSensor::new(sensor);
+
+        // Each caller gets the result of its own command, run in the order they were made
+        let first = async_sensor.read().await.unwrap();
+        async_sensor.reset().await;
+        let timed = async_sensor.read_with_timing(PulseTiming::Calibrated).await.unwrap();
+        assert_eq!(TemperatureCelsius::from(1.0), first.temperature);
+        assert_eq!(TemperatureCelsius::from(3.0), timed.temperature);
+
+        // Every command runs on the same, named, thread rather than the blocking pool
+        let calls = calls.lock().unwrap().clone();
+        assert_eq!(
+            vec!["read", "reset", "read_with_timing"],
+            calls.iter().map(|(c, _, _)| *c).collect::<Vec<_>>()
+        );
+        assert!(calls
+            .iter()
+            .all(|(_, name, _)| name.as_deref() == Some("strudel-sensor")));
+        assert!(calls.iter().all(|(_, _, id)| *id == calls[0].2));
+        assert_ne!(thread::current().id(), calls[0].2);
+    }
+
+    #[tokio::test]
+    async fn test_async_sensor_read_after_panic() {
+        let sensor = RecordingSensor {
+            panic_on: Some(2),
+            ..Default::default()
+        };
+        let calls = sensor.calls.clone();
+        let async_sensor = AsyncSensor::new(sensor);
+
+        assert!(async_sensor.read().await.is_ok());
+        let err = async_sensor.read().await.unwrap_err();
+        assert_eq!(SensorErrorKind::Internal, err.kind());
+
+        // The same sensor keeps being read from the same thread
+        let res = async_sensor.read().await.unwrap();
+        assert_eq!(TemperatureCelsius::from(3.0), res.temperature);
+
+        let calls = calls.lock().unwrap().clone();
+        assert_eq!(Some("strudel-sensor"), calls[2].1.as_deref());
+        assert!(calls.iter().all(|(_, _, id)| *id == calls[0].2));
+    }
+
+    #[tokio::test]
+    async fn test_async_sensor_close_clone() {
+        let sensor = DropSensor::default();
+        let dropped = sensor.dropped.clone();
+        let async_sensor = AsyncSensor::new(sensor);
+        let other = async_sensor.clone();
+
+        // The sensor is only dropped, and its thread joined, once the last clone is closed
+        async_sensor.close().await;
+        assert!(!dropped.load(Ordering::SeqCst));
+        assert!(other.read().await.is_ok());
+
+        other.close().await;
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_async_sensor_close() {
+        let sensor = DropSensor {
+            inner: SlowSensor {
+                delay: Duration::from_millis(100),
+                ..Default::default()
+            },
+            dropped: Arc::default(),
+        };
+        let dropped = sensor.dropped.clone();
+        let async_sensor = AsyncSensor::new(sensor).read_timeout(Duration::from_millis(10));
+
+        // The read continues after timing out and the sensor is only dropped after it
+        let res = async_sensor.read().await;
+        assert_eq!(SensorErrorKind::ReadTimeout, res.unwrap_err().kind());
+        assert!(!dropped.load(Ordering::SeqCst));
+
+        async_sensor.close().await;
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_async_sensor_inline() {
+        let sensor = RecordingSensor {
+            panic_on: Some(2),
+            ..Default::default()
+        };
+        let calls = sensor.calls.clone();
+        let async_sensor = AsyncSensor::inline(sensor);
+        assert!(async_sensor.is_inline());
+
+        // Panics are still caught and every command runs on the calling thread
+        assert!(async_sensor.read().await.is_ok());
+        let err = async_sensor.read().await.unwrap_err();
+        assert_eq!(SensorErrorKind::Internal, err.kind());
+        let res = async_sensor.read().await.unwrap();
+        assert_eq!(TemperatureCelsius::from(3.0), res.temperature);
+
+        let calls = calls.lock().unwrap().clone();
+        assert!(calls.iter().all(|(_, _, id)| *id == thread::current().id()));
+    }
+
+    #[tokio::test]
+    async fn test_async_sensor_inline_close() {
+        let sensor = DropSensor::default();
+        let dropped = sensor.dropped.clone();
+        let async_sensor = AsyncSensor::inline(sensor);
+        let other = async_sensor.clone();
+
+        async_sensor.close().await;
+        assert!(!dropped.load(Ordering::SeqCst));
+        assert!(other.read().await.is_ok());
+
+        other.close().await;
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+}
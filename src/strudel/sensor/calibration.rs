@@ -0,0 +1,251 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+use crate::sensor::core::{Humidity, Measurement, SensorError, SensorRanges, TemperatureCelsius};
+
+/// Which values of a measurement were outside the range of the sensor after being
+/// calibrated and were clamped to it.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Clamped {
+    pub temperature: bool,
+    pub humidity: bool,
+}
+
+impl Clamped {
+    /// True if any value was clamped.
+    pub fn any(&self) -> bool {
+        self.temperature || self.humidity
+    }
+}
+
+/// Adjustments applied to every measurement read from a sensor.
+///
+/// Calibrated values are clamped to the range the sensor can measure since values
+/// outside of it, like negative humidity from an offset applied near 0%, are nonsense.
+/// A warning is logged when a value is clamped unless the same value was clamped last
+/// time, so that a steady bad calibration doesn't log every read.
+#[derive(Debug, Clone)]
+pub struct Calibration {
+    temp_offset: f64,
+    ranges: SensorRanges,
+    last_temperature: Option<f64>,
+    last_humidity: Option<f64>,
+}
+
+impl Calibration {
+    /// Create a calibration that only clamps values to `ranges`, usually those of the
+    /// sensor from `Sensor::ranges`.
+    pub fn new(ranges: SensorRanges) -> Self {
+        Self {
+            temp_offset: 0.0,
+            ranges,
+            last_temperature: None,
+            last_humidity: None,
+        }
+    }
+
+    /// Degrees celsius to add to every temperature read, to calibrate sensors that
+    /// read consistently high or low. Default zero.
+    pub fn temp_offset(mut self, offset: f64) -> Self {
+        self.temp_offset = offset;
+        self
+    }
+
+    /// Apply the calibration to `m`, returning the calibrated measurement and which of its
+    /// values were clamped to the range of the sensor.
+    pub fn apply(&mut self, m: Measurement) -> (Measurement, Clamped) {
+        let temperature = f64::from(m.temperature) + self.temp_offset;
+        let humidity = f64::from(m.humidity);
+        let r = &self.ranges;
+
+        let temp_in_range = temperature >= r.min_temperature && temperature <= r.max_temperature;
+        let humidity_in_range = humidity >= r.min_humidity && humidity <= r.max_humidity;
+        if temp_in_range && humidity_in_range {
+            self.last_temperature = None;
+            self.last_humidity = None;
+            return (
+                Measurement {
+                    temperature: TemperatureCelsius::from(temperature),
+                    humidity: m.humidity,
+                },
+                Clamped::default(),
+            );
+        }
+
+        let clamped = Clamped {
+            temperature: !temp_in_range,
+            humidity: !humidity_in_range,
+        };
+
+        let temperature = clamp(
+            "temperature",
+            temperature,
+            r.min_temperature,
+            r.max_temperature,
+            &mut self.last_temperature,
+        );
+        let humidity = clamp(
+            "humidity",
+            humidity,
+            r.min_humidity,
+            r.max_humidity,
+            &mut self.last_humidity,
+        );
+
+        (
+            Measurement {
+                temperature: TemperatureCelsius::from(temperature),
+                humidity: Humidity::from(humidity),
+            },
+            clamped,
+        )
+    }
+
+    /// Apply the calibration to the measurement of a successful read, leaving errors as
+    /// they are. Nothing is clamped for errors.
+    pub fn apply_result(
+        &mut self,
+        res: Result<Measurement, SensorError>,
+    ) -> (Result<Measurement, SensorError>, Clamped) {
+        match res {
+            Ok(m) => {
+                let (m, clamped) = self.apply(m);
+                (Ok(m), clamped)
+            }
+            Err(e) => (Err(e), Clamped::default()),
+        }
+    }
+}
+
+/// Clamp `value` to `min` and `max`, logging a warning if it's outside of them and
+/// different from `last`, the previous value that was clamped.
+fn clamp(name: &'static str, value: f64, min: f64, max: f64, last: &mut Option<f64>) -> f64 {
+    if value >= min && value <= max {
+        *last = None;
+        return value;
+    }
+
+    if *last != Some(value) {
+        tracing::warn!(
+            message = "calibrated value outside the range of the sensor, check calibration",
+            value = name,
+            calibrated = value,
+            min = min,
+            max = max,
+        );
+    }
+
+    *last = Some(value);
+    value.clamp(min, max)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Calibration, Clamped};
+    use crate::sensor::core::{Humidity, Measurement, SensorRanges, TemperatureCelsius};
+
+    fn ranges() -> SensorRanges {
+        SensorRanges {
+            min_temperature: -40.0,
+            max_temperature: 80.0,
+            min_humidity: 0.0,
+            max_humidity: 100.0,
+        }
+    }
+
+    fn measurement(temperature: f64, humidity: f64) -> Measurement {
+        Measurement {
+            temperature: TemperatureCelsius::from(temperature),
+            humidity: Humidity::from(humidity),
+        }
+    }
+
+    #[test]
+    fn test_calibration_in_range() {
+        let mut calibration = Calibration::new(ranges());
+        let (m, clamped) = calibration.apply(measurement(21.5, 40.0));
+
+        assert_eq!(measurement(21.5, 40.0), m);
+        assert_eq!(Clamped::default(), clamped);
+        assert!(!clamped.any());
+    }
+
+    #[test]
+    fn test_calibration_temp_offset() {
+        let mut calibration = Calibration::new(ranges()).temp_offset(-0.5);
+        let (m, clamped) = calibration.apply(measurement(35.1, 65.2));
+
+        assert_eq!(measurement(34.6, 65.2), m);
+        assert!(!clamped.any());
+    }
+
+    #[test]
+    fn test_calibration_clamp_below() {
+        let mut calibration = Calibration::new(ranges()).temp_offset(-5.0);
+        let (m, clamped) = calibration.apply(measurement(-38.0, -1.0));
+
+        assert_eq!(measurement(-40.0, 0.0), m);
+        assert_eq!(
+            Clamped {
+                temperature: true,
+                humidity: true
+            },
+            clamped
+        );
+    }
+
+    #[test]
+    fn test_calibration_clamp_above() {
+        let mut calibration = Calibration::new(ranges()).temp_offset(5.0);
+        let (m, clamped) = calibration.apply(measurement(78.0, 40.0));
+
+        assert_eq!(measurement(80.0, 40.0), m);
+        assert_eq!(
+            Clamped {
+                temperature: true,
+                humidity: false
+            },
+            clamped
+        );
+
+        let (m, clamped) = calibration.apply(measurement(21.5, 6553.5));
+        assert_eq!(measurement(26.5, 100.0), m);
+        assert_eq!(
+            Clamped {
+                temperature: false,
+                humidity: true
+            },
+            clamped
+        );
+    }
+
+    #[test]
+    fn test_calibration_default_ranges() {
+        let mut calibration = Calibration::new(SensorRanges::default());
+        let (m, clamped) = calibration.apply(measurement(-90.0, 101.0));
+
+        assert_eq!(measurement(-90.0, 100.0), m);
+        assert_eq!(
+            Clamped {
+                temperature: false,
+                humidity: true
+            },
+            clamped
+        );
+    }
+}
@@ -16,9 +16,15 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 //
 
-use rppal::gpio::{Gpio, IoPin, Mode};
+use crate::device::PulseTiming;
+#[cfg(feature = "rppal")]
+use rppal::gpio::{Gpio, IoPin, Mode, PullUpDown};
+#[cfg(feature = "rppal")]
+use rppal::i2c::I2c;
+use std::borrow::Cow;
 use std::error::Error;
 use std::fmt::{self, Formatter};
+use std::str::FromStr;
 
 /// Temperature, in degrees celsius
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -43,6 +49,123 @@ impl fmt::Display for TemperatureCelsius {
     }
 }
 
+/// Temperature, in kelvin
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[repr(transparent)]
+pub struct TemperatureKelvin(f64);
+
+impl From<TemperatureKelvin> for f64 {
+    fn from(v: TemperatureKelvin) -> Self {
+        v.0
+    }
+}
+
+impl From<f64> for TemperatureKelvin {
+    fn from(v: f64) -> Self {
+        Self(v)
+    }
+}
+
+impl From<TemperatureCelsius> for TemperatureKelvin {
+    fn from(v: TemperatureCelsius) -> Self {
+        Self(v.0 + 273.15)
+    }
+}
+
+impl From<TemperatureKelvin> for TemperatureCelsius {
+    fn from(v: TemperatureKelvin) -> Self {
+        Self(v.0 - 273.15)
+    }
+}
+
+impl fmt::Display for TemperatureKelvin {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}K", self.0)
+    }
+}
+
+/// Temperature, in degrees fahrenheit
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[repr(transparent)]
+pub struct TemperatureFahrenheit(f64);
+
+impl From<TemperatureFahrenheit> for f64 {
+    fn from(v: TemperatureFahrenheit) -> Self {
+        v.0
+    }
+}
+
+impl From<f64> for TemperatureFahrenheit {
+    fn from(v: f64) -> Self {
+        Self(v)
+    }
+}
+
+impl From<TemperatureCelsius> for TemperatureFahrenheit {
+    fn from(v: TemperatureCelsius) -> Self {
+        Self(v.0 * 9.0 / 5.0 + 32.0)
+    }
+}
+
+impl From<TemperatureFahrenheit> for TemperatureCelsius {
+    fn from(v: TemperatureFahrenheit) -> Self {
+        Self((v.0 - 32.0) * 5.0 / 9.0)
+    }
+}
+
+impl fmt::Display for TemperatureFahrenheit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}f", self.0)
+    }
+}
+
+/// Unit temperatures are reported in
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TemperatureUnit {
+    #[default]
+    Celsius,
+    Fahrenheit,
+    Kelvin,
+}
+
+impl TemperatureUnit {
+    /// Convert a temperature in celsius to this unit
+    pub fn convert(&self, v: TemperatureCelsius) -> f64 {
+        match self {
+            TemperatureUnit::Celsius => v.into(),
+            TemperatureUnit::Fahrenheit => TemperatureFahrenheit::from(v).into(),
+            TemperatureUnit::Kelvin => TemperatureKelvin::from(v).into(),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TemperatureUnit::Celsius => "celsius",
+            TemperatureUnit::Fahrenheit => "fahrenheit",
+            TemperatureUnit::Kelvin => "kelvin",
+        }
+    }
+}
+
+impl fmt::Display for TemperatureUnit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.as_str().fmt(f)
+    }
+}
+
+impl FromStr for TemperatureUnit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "celsius" => Ok(TemperatureUnit::Celsius),
+            "fahrenheit" => Ok(TemperatureUnit::Fahrenheit),
+            "kelvin" => Ok(TemperatureUnit::Kelvin),
+            _ => Err(format!("expected 'celsius', 'fahrenheit', or 'kelvin', got '{}'", s)),
+        }
+    }
+}
+
 /// Relative humidity (from 0 to 100)
 #[derive(Copy, Clone, Debug, PartialEq)]
 #[repr(transparent)]
@@ -66,12 +189,108 @@ impl fmt::Display for Humidity {
     }
 }
 
+/// Difference between how much moisture the air could hold and how much it does hold,
+/// in kilopascals. Commonly used to control growing conditions in greenhouses.
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[repr(transparent)]
+pub struct VapourPressureDeficit(f64);
+
+impl VapourPressureDeficit {
+    /// Compute the deficit for air at the given temperature and relative humidity.
+    pub fn new(temperature: TemperatureCelsius, humidity: Humidity) -> Self {
+        Self::with_leaf_offset(temperature, humidity, 0.0)
+    }
+
+    /// Compute the deficit between a leaf `leaf_offset` degrees celsius warmer (or cooler,
+    /// when negative) than the air and the air at the given temperature and humidity.
+    pub fn with_leaf_offset(temperature: TemperatureCelsius, humidity: Humidity, leaf_offset: f64) -> Self {
+        let leaf = TemperatureCelsius::from(temperature.0 + leaf_offset);
+        let actual = saturation_vapour_pressure(temperature) * humidity.0 / 100.0;
+        Self(saturation_vapour_pressure(leaf) - actual)
+    }
+}
+
+impl From<VapourPressureDeficit> for f64 {
+    fn from(v: VapourPressureDeficit) -> Self {
+        v.0
+    }
+}
+
+impl fmt::Display for VapourPressureDeficit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}kPa", self.0)
+    }
+}
+
+/// Saturation vapour pressure of water at the given temperature in kilopascals, using
+/// the Tetens equation.
+pub fn saturation_vapour_pressure(temperature: TemperatureCelsius) -> f64 {
+    let t = temperature.0;
+    0.61078 * (17.27 * t / (t + 237.3)).exp()
+}
+
+/// Temperature and humidity measured by a sensor at the same time
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Measurement {
+    pub temperature: TemperatureCelsius,
+    pub humidity: Humidity,
+}
+
+impl From<(TemperatureCelsius, Humidity)> for Measurement {
+    fn from((temperature, humidity): (TemperatureCelsius, Humidity)) -> Self {
+        Self { temperature, humidity }
+    }
+}
+
+impl fmt::Display for Measurement {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.temperature, self.humidity)
+    }
+}
+
+/// Bytes decoded from a read of a sensor before the checksum is verified: two bytes
+/// of humidity, two bytes of temperature, and a checksum byte.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RawReading {
+    pub bytes: [u8; 5],
+}
+
+impl RawReading {
+    /// Humidity as read from the sensor: tenths of a percent.
+    pub fn humidity_word(&self) -> u16 {
+        u16::from_be_bytes([self.bytes[0], self.bytes[1]])
+    }
+
+    /// Temperature as read from the sensor: tenths of a degree celsius with the highest
+    /// bit indicating a negative value.
+    pub fn temperature_word(&self) -> u16 {
+        u16::from_be_bytes([self.bytes[2], self.bytes[3]])
+    }
+}
+
+/// Average widths of the pulses sent for the 40 bits of a read, in cycles of checking the
+/// data pin. Cycles depend on the speed of the device so these are only comparable between
+/// reads on the same one. High pulses are longer for 1 bits than 0 bits so their average
+/// also varies a little with the values read.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PulseStats {
+    pub mean_low: f64,
+    pub mean_high: f64,
+}
+
 /// Potential kinds of errors that can be encountered reading from the DHT sensor
 #[derive(PartialEq, Eq, Debug, Hash, Clone, Copy)]
+#[non_exhaustive]
 pub enum SensorErrorKind {
     Initialization,
     ReadTimeout,
     Checksum,
+    NoResponse,
+    Implausible,
+    /// Pulses were captured but there weren't enough of them to decode, or they couldn't
+    /// tell 0 and 1 bits apart.
+    FrameError,
+    Internal,
 }
 
 impl SensorErrorKind {
@@ -80,24 +299,123 @@ impl SensorErrorKind {
             SensorErrorKind::Initialization => "initialization",
             SensorErrorKind::ReadTimeout => "timeout",
             SensorErrorKind::Checksum => "checksum",
+            SensorErrorKind::NoResponse => "no_response",
+            SensorErrorKind::Implausible => "implausible",
+            SensorErrorKind::FrameError => "frame",
+            SensorErrorKind::Internal => "internal",
+        }
+    }
+
+    /// Stable exit status used when `strudel` exits because of an error of this kind.
+    ///
+    /// Codes must not change once assigned. `1` is used for errors unrelated to the
+    /// sensor and `2` for invalid usage.
+    pub fn code(&self) -> u8 {
+        match self {
+            SensorErrorKind::Initialization => 3,
+            SensorErrorKind::ReadTimeout => 4,
+            SensorErrorKind::Checksum => 5,
+            SensorErrorKind::Implausible => 6,
+            SensorErrorKind::NoResponse => 7,
+            SensorErrorKind::FrameError => 8,
+            SensorErrorKind::Internal => 10,
         }
     }
 }
 
 /// Error initializing or reading the DHT22 sensor via a GPIO pin
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum SensorError {
     CheckSum(u8, u8),
-    KindMsg(SensorErrorKind, &'static str),
-    KindMsgCause(SensorErrorKind, &'static str, Box<dyn Error + Send + Sync>),
+    KindMsg(SensorErrorKind, Cow<'static, str>),
+    KindMsgCause(SensorErrorKind, Cow<'static, str>, Box<dyn Error + Send + Sync>),
+    /// The data pin didn't change from `phase` in time while reading transition `bit`.
+    /// Transition zero is the response of the sensor to the start signal and 1 to 40 are
+    /// data bits.
+    PulseTimeout {
+        bit: usize,
+        phase: Level,
+    },
+    /// The sensor didn't answer the start signal with a low and a high pulse of the
+    /// expected length. Widths are in microseconds, `None` if the pin didn't change
+    /// in time.
+    NoResponse {
+        low_us: Option<f64>,
+        high_us: Option<f64>,
+    },
 }
 
 impl SensorError {
+    /// Create an error of the given kind with a message.
+    pub fn new<M>(kind: SensorErrorKind, msg: M) -> Self
+    where
+        M: Into<Cow<'static, str>>,
+    {
+        SensorError::KindMsg(kind, msg.into())
+    }
+
+    /// Create an error of the given kind with a message and underlying cause.
+    pub fn with_cause<M, E>(kind: SensorErrorKind, msg: M, cause: E) -> Self
+    where
+        M: Into<Cow<'static, str>>,
+        E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        SensorError::KindMsgCause(kind, msg.into(), cause.into())
+    }
+
+    /// Create a `SensorErrorKind::Initialization` error with a message.
+    pub fn initialization<M>(msg: M) -> Self
+    where
+        M: Into<Cow<'static, str>>,
+    {
+        Self::new(SensorErrorKind::Initialization, msg)
+    }
+
+    /// Create a `SensorErrorKind::ReadTimeout` error with a message.
+    pub fn timeout<M>(msg: M) -> Self
+    where
+        M: Into<Cow<'static, str>>,
+    {
+        Self::new(SensorErrorKind::ReadTimeout, msg)
+    }
+
+    /// Create a `SensorErrorKind::Implausible` error with a message.
+    pub fn implausible<M>(msg: M) -> Self
+    where
+        M: Into<Cow<'static, str>>,
+    {
+        Self::new(SensorErrorKind::Implausible, msg)
+    }
+
+    /// Create a `SensorErrorKind::FrameError` error with a message.
+    pub fn frame<M>(msg: M) -> Self
+    where
+        M: Into<Cow<'static, str>>,
+    {
+        Self::new(SensorErrorKind::FrameError, msg)
+    }
+
+    /// Create a `SensorErrorKind::Internal` error with a message.
+    pub fn internal<M>(msg: M) -> Self
+    where
+        M: Into<Cow<'static, str>>,
+    {
+        Self::new(SensorErrorKind::Internal, msg)
+    }
+
+    /// Stable exit status for this error, see `SensorErrorKind::code`.
+    pub fn code(&self) -> u8 {
+        self.kind().code()
+    }
+
     pub fn kind(&self) -> SensorErrorKind {
         match self {
             SensorError::CheckSum(_, _) => SensorErrorKind::Checksum,
             SensorError::KindMsg(kind, _) => *kind,
             SensorError::KindMsgCause(kind, _, _) => *kind,
+            SensorError::PulseTimeout { .. } => SensorErrorKind::ReadTimeout,
+            SensorError::NoResponse { .. } => SensorErrorKind::NoResponse,
         }
     }
 }
@@ -110,10 +428,42 @@ impl fmt::Display for SensorError {
             }
             SensorError::KindMsg(_, msg) => msg.fmt(f),
             SensorError::KindMsgCause(_, msg, ref e) => write!(f, "{}: {}", msg, e),
+            SensorError::PulseTimeout { bit, phase } => {
+                let phase = match phase {
+                    Level::Low => "low",
+                    Level::High => "high",
+                };
+
+                if *bit == 0 {
+                    write!(f, "timeout waiting for {} pulse capture of sensor response", phase)
+                } else {
+                    write!(f, "timeout waiting for {} pulse capture of bit {} of 40", phase, bit)
+                }
+            }
+            SensorError::NoResponse { low_us, high_us } => {
+                let width = |us: &Option<f64>| match us {
+                    Some(us) => format!("{:.1}us", us),
+                    None => "timeout".to_owned(),
+                };
+
+                write!(
+                    f,
+                    "no valid response from sensor to start signal: low {}, high {}",
+                    width(low_us),
+                    width(high_us)
+                )
+            }
         }
     }
 }
 
+#[cfg(feature = "rppal")]
+impl From<rppal::gpio::Error> for SensorError {
+    fn from(e: rppal::gpio::Error) -> Self {
+        Self::with_cause(SensorErrorKind::Initialization, "GPIO error", e)
+    }
+}
+
 impl Error for SensorError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
@@ -123,6 +473,108 @@ impl Error for SensorError {
     }
 }
 
+/// Range of values a sensor can measure, in degrees celsius and percent relative humidity.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SensorRanges {
+    pub min_temperature: f64,
+    pub max_temperature: f64,
+    pub min_humidity: f64,
+    pub max_humidity: f64,
+}
+
+impl Default for SensorRanges {
+    /// Any temperature and relative humidity from 0 to 100, the only physically possible
+    /// values.
+    fn default() -> Self {
+        Self {
+            min_temperature: f64::NEG_INFINITY,
+            max_temperature: f64::INFINITY,
+            min_humidity: 0.0,
+            max_humidity: 100.0,
+        }
+    }
+}
+
+/// Source of temperature and humidity measurements.
+///
+/// Reads are blocking and may take a relatively long time (tens of milliseconds)
+/// so callers in an async context should use `spawn_blocking` or similar.
+pub trait Sensor: Send + 'static {
+    fn read(&mut self) -> Result<Measurement, SensorError>;
+
+    /// Read the sensor decoding pulses with `timing` instead of the way the sensor is set
+    /// up to, used to compare pulse timings. Doesn't change what `last_raw` and `last_pulses`
+    /// return. Sensors that don't decode pulses return a `SensorErrorKind::Internal` error.
+    fn read_with_timing(&mut self, timing: PulseTiming) -> Result<Measurement, SensorError> {
+        Err(SensorError::internal(format!(
+            "sensor can't decode pulses with {:?} timing",
+            timing
+        )))
+    }
+
+    /// Range of values the sensor can measure. Calibrated measurements are clamped to
+    /// this range, see `Calibration`. Defaults to any physically possible value.
+    fn ranges(&self) -> SensorRanges {
+        SensorRanges::default()
+    }
+
+    /// Bytes decoded by the most recent read, if it got far enough to decode any, even
+    /// if the checksum was invalid. Sensors that don't decode bytes return `None`.
+    fn last_raw(&self) -> Option<RawReading> {
+        None
+    }
+
+    /// Statistics of the pulses captured by the most recent read if it captured every
+    /// one of them, even if decoding them failed. Sensors that don't capture pulses
+    /// return `None`.
+    fn last_pulses(&self) -> Option<PulseStats> {
+        None
+    }
+
+    /// Redo any setup that depends on the hardware the sensor is connected to, like
+    /// calibrating timing, on demand. Sensors without any setup do nothing.
+    fn reset(&mut self) {}
+
+    /// Number of times the data pin can be checked per microsecond if the sensor uses
+    /// timing calibration and it succeeded, see `TimingCalibration`.
+    fn cycles_per_us(&self) -> Option<f64> {
+        None
+    }
+}
+
+impl<S> Sensor for Box<S>
+where
+    S: Sensor + ?Sized,
+{
+    fn read(&mut self) -> Result<Measurement, SensorError> {
+        (**self).read()
+    }
+
+    fn read_with_timing(&mut self, timing: PulseTiming) -> Result<Measurement, SensorError> {
+        (**self).read_with_timing(timing)
+    }
+
+    fn ranges(&self) -> SensorRanges {
+        (**self).ranges()
+    }
+
+    fn last_raw(&self) -> Option<RawReading> {
+        (**self).last_raw()
+    }
+
+    fn last_pulses(&self) -> Option<PulseStats> {
+        (**self).last_pulses()
+    }
+
+    fn reset(&mut self) {
+        (**self).reset();
+    }
+
+    fn cycles_per_us(&self) -> Option<f64> {
+        (**self).cycles_per_us()
+    }
+}
+
 /// Create a new `IoPin` based on the BCM GPIO pin number of the data wire of a
 /// sensor.
 ///
@@ -130,37 +582,117 @@ impl Error for SensorError {
 /// See [pinout] for more information.
 ///
 /// [pinout]: https://www.raspberrypi.com/documentation/computers/os.html#gpio-and-the-40-pin-header
+#[cfg(feature = "rppal")]
 pub fn open_pin(bcm_gpio_pin: u8) -> Result<IoPin, SensorError> {
-    let controller = Gpio::new().map_err(|e| {
-        SensorError::KindMsgCause(
-            SensorErrorKind::Initialization,
-            "unable to create GPIO controller",
-            Box::new(e),
-        )
-    })?;
-
-    let pin = controller.get(bcm_gpio_pin).map_err(|e| {
-        SensorError::KindMsgCause(
-            SensorErrorKind::Initialization,
-            "unable to acquire pin from controller",
-            Box::new(e),
-        )
-    })?;
-
+    let controller = Gpio::new()?;
+    let pin = controller.get(bcm_gpio_pin)?;
     let io_pin = pin.into_io(Mode::Input);
     Ok(io_pin)
 }
 
-/// Abstraction around an `rppal::gpio::IoPin` to allow for easier testing.
+/// Whether a `DataPin` is being read from or written to
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PinMode {
+    Input,
+    Output,
+}
+
+/// Logic level of a `DataPin`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Level {
+    Low,
+    High,
+}
+
+/// Error returned when a `DataPin` stayed at the same level for too long
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WaitTimeout;
+
+impl fmt::Display for WaitTimeout {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("timeout waiting for pin level to change")
+    }
+}
+
+impl Error for WaitTimeout {}
+
+/// Abstraction around a GPIO pin, e.g. an `rppal::gpio::IoPin`, to allow for
+/// different GPIO implementations and easier testing.
 pub trait DataPin {
     fn is_low(&self) -> bool;
     fn is_high(&self) -> bool;
     fn pin(&self) -> u8;
     fn set_high(&mut self);
     fn set_low(&mut self);
-    fn set_mode(&mut self, mode: Mode);
+    fn set_mode(&mut self, mode: PinMode);
+
+    /// Stop driving the data line, returning the pin to input mode. Called after each
+    /// read, successful or not, and when a sensor is dropped.
+    fn release(&mut self) {
+        self.set_mode(PinMode::Input);
+    }
+
+    /// Count the number of times the pin is checked while it stays at `level`, returning
+    /// an error if it stays there for `max_cycles` checks.
+    ///
+    /// Sensors call this once per transition instead of calling `is_low` or `is_high`
+    /// through a trait object for every check. The default implementation is compiled
+    /// for each implementation of the trait so the checks aren't dynamic calls but it
+    /// may be overridden to read the pin more directly.
+    fn wait_while_level(&self, level: Level, max_cycles: u32) -> Result<u32, WaitTimeout> {
+        let mut cycles = 0;
+        while match level {
+            Level::Low => self.is_low(),
+            Level::High => self.is_high(),
+        } {
+            cycles += 1;
+            if cycles >= max_cycles {
+                return Err(WaitTimeout);
+            }
+        }
+
+        Ok(cycles)
+    }
+}
+
+impl<T> DataPin for Box<T>
+where
+    T: DataPin + ?Sized,
+{
+    fn is_low(&self) -> bool {
+        (**self).is_low()
+    }
+
+    fn is_high(&self) -> bool {
+        (**self).is_high()
+    }
+
+    fn pin(&self) -> u8 {
+        (**self).pin()
+    }
+
+    fn set_high(&mut self) {
+        (**self).set_high();
+    }
+
+    fn set_low(&mut self) {
+        (**self).set_low();
+    }
+
+    fn set_mode(&mut self, mode: PinMode) {
+        (**self).set_mode(mode);
+    }
+
+    fn release(&mut self) {
+        (**self).release();
+    }
+
+    fn wait_while_level(&self, level: Level, max_cycles: u32) -> Result<u32, WaitTimeout> {
+        (**self).wait_while_level(level, max_cycles)
+    }
 }
 
+#[cfg(feature = "rppal")]
 impl DataPin for IoPin {
     fn is_low(&self) -> bool {
         IoPin::is_low(self)
@@ -182,7 +714,514 @@ impl DataPin for IoPin {
         IoPin::set_low(self);
     }
 
-    fn set_mode(&mut self, mode: Mode) {
+    fn set_mode(&mut self, mode: PinMode) {
+        let mode = match mode {
+            PinMode::Input => Mode::Input,
+            PinMode::Output => Mode::Output,
+        };
+
         IoPin::set_mode(self, mode);
     }
+
+    fn release(&mut self) {
+        IoPin::set_mode(self, Mode::Input);
+        IoPin::set_pullupdown(self, PullUpDown::PullUp);
+    }
+
+    fn wait_while_level(&self, level: Level, max_cycles: u32) -> Result<u32, WaitTimeout> {
+        let expected = match level {
+            Level::Low => rppal::gpio::Level::Low,
+            Level::High => rppal::gpio::Level::High,
+        };
+
+        let mut cycles = 0;
+        while IoPin::read(self) == expected {
+            cycles += 1;
+            if cycles >= max_cycles {
+                return Err(WaitTimeout);
+            }
+        }
+
+        Ok(cycles)
+    }
+}
+
+/// Error performing a transaction on an `I2cBus`.
+///
+/// Converting to a `SensorError` maps a device that didn't acknowledge the transaction
+/// to `SensorErrorKind::NoResponse` and any other error to `SensorErrorKind::Initialization`
+/// so that every sensor read over I2C reports errors the same way.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum I2cError {
+    /// The device at the address didn't acknowledge the transaction, e.g. because
+    /// nothing is connected at that address or the device is asleep.
+    Nack(u8),
+    /// Any other error using the bus to talk to the device at the address.
+    Bus(u8, Box<dyn Error + Send + Sync>),
+}
+
+impl I2cError {
+    /// Address of the device the transaction was for.
+    pub fn address(&self) -> u8 {
+        match self {
+            I2cError::Nack(address) => *address,
+            I2cError::Bus(address, _) => *address,
+        }
+    }
+}
+
+impl fmt::Display for I2cError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            I2cError::Nack(address) => write!(f, "no acknowledgement from I2C device {:#04x}", address),
+            I2cError::Bus(address, e) => write!(f, "I2C bus error talking to device {:#04x}: {}", address, e),
+        }
+    }
+}
+
+impl Error for I2cError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            I2cError::Bus(_, ref e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<I2cError> for SensorError {
+    fn from(e: I2cError) -> Self {
+        let kind = match e {
+            I2cError::Nack(_) => SensorErrorKind::NoResponse,
+            I2cError::Bus(_, _) => SensorErrorKind::Initialization,
+        };
+
+        SensorError::with_cause(kind, "I2C transaction failed", e)
+    }
+}
+
+/// Abstraction around an I2C bus, e.g. an `rppal::i2c::I2c`, to allow for different
+/// I2C implementations and easier testing of sensors read over I2C.
+///
+/// Each transaction is addressed to the 7-bit address of a device on the bus.
+pub trait I2cBus {
+    /// Write all of `bytes` to the device at `address`.
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), I2cError>;
+
+    /// Fill `buffer` with bytes read from the device at `address`.
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), I2cError>;
+
+    /// Write all of `bytes` to the device at `address` and then fill `buffer` with bytes
+    /// read from it, without releasing the bus in between.
+    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), I2cError>;
+}
+
+impl<T> I2cBus for Box<T>
+where
+    T: I2cBus + ?Sized,
+{
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), I2cError> {
+        (**self).write(address, bytes)
+    }
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), I2cError> {
+        (**self).read(address, buffer)
+    }
+
+    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), I2cError> {
+        (**self).write_read(address, bytes, buffer)
+    }
+}
+
+/// Fill `buffer` with the contents of consecutive registers of the device at `address`
+/// starting from `register`, the way most I2C sensors expose their readings.
+pub fn read_registers<B>(bus: &mut B, address: u8, register: u8, buffer: &mut [u8]) -> Result<(), SensorError>
+where
+    B: I2cBus + ?Sized,
+{
+    Ok(bus.write_read(address, &[register], buffer)?)
+}
+
+/// Create a new `I2c` for the I2C bus of the Raspberry PI that's on the GPIO header,
+/// used by sensors read over I2C instead of a single data pin.
+#[cfg(feature = "rppal")]
+pub fn open_i2c() -> Result<I2c, SensorError> {
+    I2c::new().map_err(|e| SensorError::with_cause(SensorErrorKind::Initialization, "unable to open I2C bus", e))
+}
+
+#[cfg(feature = "rppal")]
+fn rppal_i2c_error(address: u8, e: rppal::i2c::Error) -> I2cError {
+    match e {
+        // The kernel reports a device that doesn't acknowledge its address as one of these,
+        // depending on the driver for the bus.
+        rppal::i2c::Error::Io(ref io) if matches!(io.raw_os_error(), Some(libc::ENXIO) | Some(libc::EREMOTEIO)) => {
+            I2cError::Nack(address)
+        }
+        e => I2cError::Bus(address, Box::new(e)),
+    }
+}
+
+#[cfg(feature = "rppal")]
+fn rppal_i2c_len(address: u8, expected: usize, actual: usize) -> Result<(), I2cError> {
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(I2cError::Bus(
+            address,
+            format!("transferred {} of {} bytes", actual, expected).into(),
+        ))
+    }
+}
+
+#[cfg(feature = "rppal")]
+impl I2cBus for I2c {
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), I2cError> {
+        self.set_slave_address(u16::from(address))
+            .map_err(|e| rppal_i2c_error(address, e))?;
+        let written = I2c::write(self, bytes).map_err(|e| rppal_i2c_error(address, e))?;
+        rppal_i2c_len(address, bytes.len(), written)
+    }
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), I2cError> {
+        self.set_slave_address(u16::from(address))
+            .map_err(|e| rppal_i2c_error(address, e))?;
+        let read = I2c::read(self, buffer).map_err(|e| rppal_i2c_error(address, e))?;
+        rppal_i2c_len(address, buffer.len(), read)
+    }
+
+    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), I2cError> {
+        self.set_slave_address(u16::from(address))
+            .map_err(|e| rppal_i2c_error(address, e))?;
+        I2c::write_read(self, bytes, buffer).map_err(|e| rppal_i2c_error(address, e))
+    }
+}
+
+/// Name used for lines requested from a GPIO character device, visible via `gpioinfo`.
+#[cfg(feature = "cdev")]
+const CDEV_CONSUMER: &str = "strudel";
+
+/// Create a new `CdevPin` for a line of a GPIO character device (e.g. `/dev/gpiochip0`)
+/// connected to the data wire of a sensor.
+///
+/// Unlike `open_pin`, this uses the kernel GPIO character device interface instead of
+/// `/dev/gpiomem` so it works on boards other than the Raspberry PI and doesn't require
+/// running as `root`, only access to the GPIO chip device.
+#[cfg(feature = "cdev")]
+pub fn open_pin_cdev(chip: &str, line: u32) -> Result<CdevPin, SensorError> {
+    let mut chip = gpio_cdev::Chip::new(chip).map_err(|e| {
+        SensorError::with_cause(
+            SensorErrorKind::Initialization,
+            format!("unable to open GPIO chip {}", chip),
+            e,
+        )
+    })?;
+
+    let offset = line;
+    let line = chip.get_line(offset).map_err(|e| {
+        SensorError::with_cause(
+            SensorErrorKind::Initialization,
+            format!("unable to get line {} from GPIO chip", offset),
+            e,
+        )
+    })?;
+
+    let handle = line
+        .request(gpio_cdev::LineRequestFlags::INPUT, 0, CDEV_CONSUMER)
+        .map_err(|e| {
+            SensorError::with_cause(
+                SensorErrorKind::Initialization,
+                format!("unable to request line {} from GPIO chip", offset),
+                e,
+            )
+        })?;
+
+    Ok(CdevPin {
+        line,
+        handle: Some(handle),
+        level: 0,
+    })
+}
+
+/// `DataPin` implementation using a line of a GPIO character device.
+///
+/// Switching between input and output requires releasing the line and requesting it
+/// again. Errors doing so or reading the line are logged and the pin reads as neither
+/// high nor low which causes reads of the sensor to time out.
+#[cfg(feature = "cdev")]
+#[derive(Debug)]
+pub struct CdevPin {
+    line: gpio_cdev::Line,
+    handle: Option<gpio_cdev::LineHandle>,
+    level: u8,
+}
+
+#[cfg(feature = "cdev")]
+impl CdevPin {
+    fn value(&self) -> Option<u8> {
+        let handle = self.handle.as_ref()?;
+        handle
+            .get_value()
+            .map_err(|e| tracing::warn!(message = "unable to read GPIO line", line = self.line.offset(), error = %e))
+            .ok()
+    }
+
+    fn set_value(&mut self, level: u8) {
+        self.level = level;
+        if let Some(handle) = self.handle.as_ref() {
+            if let Err(e) = handle.set_value(level) {
+                tracing::warn!(message = "unable to set GPIO line", line = self.line.offset(), error = %e);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "cdev")]
+impl DataPin for CdevPin {
+    fn is_low(&self) -> bool {
+        self.value() == Some(0)
+    }
+
+    fn is_high(&self) -> bool {
+        self.value() == Some(1)
+    }
+
+    fn pin(&self) -> u8 {
+        u8::try_from(self.line.offset()).unwrap_or(u8::MAX)
+    }
+
+    fn set_high(&mut self) {
+        self.set_value(1);
+    }
+
+    fn set_low(&mut self) {
+        self.set_value(0);
+    }
+
+    fn set_mode(&mut self, mode: PinMode) {
+        let flags = match mode {
+            PinMode::Input => gpio_cdev::LineRequestFlags::INPUT,
+            PinMode::Output => gpio_cdev::LineRequestFlags::OUTPUT,
+        };
+
+        // The line must be released before it can be requested with different flags
+        self.handle = None;
+        self.handle = self
+            .line
+            .request(flags, self.level, CDEV_CONSUMER)
+            .map_err(|e| tracing::warn!(message = "unable to request GPIO line", line = self.line.offset(), error = %e))
+            .ok();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        read_registers, saturation_vapour_pressure, Humidity, I2cBus, I2cError, SensorError, SensorErrorKind,
+        TemperatureCelsius, TemperatureFahrenheit, TemperatureKelvin, TemperatureUnit, VapourPressureDeficit,
+    };
+    use crate::sensor::test::{I2cTransaction, MockI2cBus};
+    use std::error::Error;
+    use std::io;
+
+    fn assert_close(expected: f64, actual: f64) {
+        assert!(
+            (expected - actual).abs() < 1e-9,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn test_temperature_kelvin() {
+        assert_close(0.0, TemperatureKelvin::from(TemperatureCelsius::from(-273.15)).into());
+        assert_close(253.15, TemperatureKelvin::from(TemperatureCelsius::from(-20.0)).into());
+        assert_close(294.65, TemperatureKelvin::from(TemperatureCelsius::from(21.5)).into());
+        assert_close(-273.15, TemperatureCelsius::from(TemperatureKelvin::from(0.0)).into());
+    }
+
+    #[test]
+    fn test_temperature_fahrenheit() {
+        assert_close(
+            -459.67,
+            TemperatureFahrenheit::from(TemperatureCelsius::from(-273.15)).into(),
+        );
+        assert_close(
+            -40.0,
+            TemperatureFahrenheit::from(TemperatureCelsius::from(-40.0)).into(),
+        );
+        assert_close(32.0, TemperatureFahrenheit::from(TemperatureCelsius::from(0.0)).into());
+        assert_close(
+            -20.0,
+            TemperatureCelsius::from(TemperatureFahrenheit::from(-4.0)).into(),
+        );
+    }
+
+    fn assert_near(expected: f64, actual: f64) {
+        assert!(
+            (expected - actual).abs() < 0.005,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn test_saturation_vapour_pressure() {
+        assert_near(0.611, saturation_vapour_pressure(TemperatureCelsius::from(0.0)));
+        assert_near(2.338, saturation_vapour_pressure(TemperatureCelsius::from(20.0)));
+        assert_near(3.168, saturation_vapour_pressure(TemperatureCelsius::from(25.0)));
+    }
+
+    #[test]
+    fn test_vapour_pressure_deficit() {
+        let vpd = |t: f64, rh: f64| f64::from(VapourPressureDeficit::new(t.into(), Humidity::from(rh)));
+
+        assert_near(1.169, vpd(20.0, 50.0));
+        assert_near(1.267, vpd(25.0, 60.0));
+        assert_near(1.273, vpd(30.0, 70.0));
+        assert_near(0.0, vpd(25.0, 100.0));
+    }
+
+    #[test]
+    fn test_vapour_pressure_deficit_leaf_offset() {
+        let vpd = VapourPressureDeficit::with_leaf_offset(25.0.into(), Humidity::from(60.0), -2.0);
+        assert_near(0.909, vpd.into());
+    }
+
+    #[test]
+    fn test_temperature_unit() {
+        let v = TemperatureCelsius::from(-10.0);
+        assert_close(-10.0, TemperatureUnit::Celsius.convert(v));
+        assert_close(14.0, TemperatureUnit::Fahrenheit.convert(v));
+        assert_close(263.15, TemperatureUnit::Kelvin.convert(v));
+
+        assert_eq!(Ok(TemperatureUnit::Kelvin), "Kelvin".parse());
+        assert!("rankine".parse::<TemperatureUnit>().is_err());
+    }
+
+    #[test]
+    fn test_sensor_error_owned_message() {
+        let err = SensorError::timeout(format!("no response after {} attempts", 3));
+        assert_eq!(SensorErrorKind::ReadTimeout, err.kind());
+        assert_eq!("no response after 3 attempts", err.to_string());
+        assert!(err.source().is_none());
+
+        let err = SensorError::with_cause(
+            SensorErrorKind::Initialization,
+            "unable to open pin",
+            io::Error::from(io::ErrorKind::NotFound),
+        );
+        assert_eq!(SensorErrorKind::Initialization, err.kind());
+        assert_eq!("unable to open pin: entity not found", err.to_string());
+        assert!(err.source().is_some());
+    }
+
+    #[test]
+    fn test_sensor_error_kind_code() {
+        // Exit codes are relied on by scripts and must never change
+        assert_eq!(3, SensorErrorKind::Initialization.code());
+        assert_eq!(4, SensorErrorKind::ReadTimeout.code());
+        assert_eq!(5, SensorErrorKind::Checksum.code());
+        assert_eq!(6, SensorErrorKind::Implausible.code());
+        assert_eq!(7, SensorErrorKind::NoResponse.code());
+        assert_eq!(8, SensorErrorKind::FrameError.code());
+        assert_eq!(10, SensorErrorKind::Internal.code());
+        assert_eq!(5, SensorError::CheckSum(1, 2).code());
+    }
+
+    #[test]
+    fn test_mock_i2c_bus_replies_in_order() {
+        let mut bus = MockI2cBus::default().reply(&[]).reply(&[0x01, 0x02]).reply(&[0x03]);
+        let transactions = bus.transactions();
+
+        let mut two = [0; 2];
+        let mut one = [0; 1];
+        bus.write(0x44, &[0x24, 0x00]).unwrap();
+        bus.read(0x44, &mut two).unwrap();
+        bus.write_read(0x5C, &[0x03], &mut one).unwrap();
+
+        assert_eq!([0x01, 0x02], two);
+        assert_eq!([0x03], one);
+        assert_eq!(
+            vec![
+                I2cTransaction::Write {
+                    address: 0x44,
+                    bytes: vec![0x24, 0x00]
+                },
+                I2cTransaction::Read { address: 0x44, len: 2 },
+                I2cTransaction::WriteRead {
+                    address: 0x5C,
+                    bytes: vec![0x03],
+                    len: 1
+                },
+            ],
+            *transactions.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_mock_i2c_bus_errors() {
+        let mut bus = MockI2cBus::default().nack().bus_error().reply(&[]);
+        let transactions = bus.transactions();
+
+        assert!(matches!(bus.write(0x5C, &[0x00]), Err(I2cError::Nack(0x5C))));
+        assert!(matches!(bus.read(0x5C, &mut [0; 4]), Err(I2cError::Bus(0x5C, _))));
+        assert!(bus.write(0x5C, &[0x00]).is_ok());
+
+        // Failed transactions are recorded too
+        assert_eq!(3, transactions.lock().unwrap().len());
+    }
+
+    #[test]
+    #[should_panic(expected = "no reply scripted")]
+    fn test_mock_i2c_bus_unexpected_transaction() {
+        let mut bus = MockI2cBus::default();
+        let _ = bus.write(0x44, &[0x00]);
+    }
+
+    #[test]
+    fn test_i2c_error_into_sensor_error() {
+        let err = SensorError::from(I2cError::Nack(0x5C));
+        assert_eq!(SensorErrorKind::NoResponse, err.kind());
+        assert_eq!(
+            "I2C transaction failed: no acknowledgement from I2C device 0x5c",
+            err.to_string()
+        );
+
+        let err = SensorError::from(I2cError::Bus(0x44, "arbitration lost".into()));
+        assert_eq!(SensorErrorKind::Initialization, err.kind());
+        assert_eq!(
+            "I2C transaction failed: I2C bus error talking to device 0x44: arbitration lost",
+            err.to_string()
+        );
+        assert!(err.source().is_some());
+    }
+
+    #[test]
+    fn test_read_registers() {
+        let mut bus = MockI2cBus::default().reply(&[0x02, 0x8C, 0x01, 0x5F]);
+        let transactions = bus.transactions();
+
+        let mut buffer = [0; 4];
+        read_registers(&mut bus, 0x5C, 0x03, &mut buffer).unwrap();
+
+        assert_eq!([0x02, 0x8C, 0x01, 0x5F], buffer);
+        assert_eq!(
+            vec![I2cTransaction::WriteRead {
+                address: 0x5C,
+                bytes: vec![0x03],
+                len: 4
+            }],
+            *transactions.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_read_registers_nack() {
+        let mut bus: Box<dyn I2cBus> = Box::new(MockI2cBus::default().nack());
+
+        let err = read_registers(&mut bus, 0x5C, 0x03, &mut [0; 4]).unwrap_err();
+        assert_eq!(SensorErrorKind::NoResponse, err.kind());
+    }
 }
@@ -0,0 +1,266 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+use crate::clock::Clock;
+use crate::sensor::core::{Measurement, SensorError, SensorErrorKind};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Default minimum time between logging the same error while it keeps repeating.
+pub const DEFAULT_ERROR_LOG_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// What should be logged for the result of a read, see `DedupLogger::observe`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogDecision {
+    /// Log the error. `repeated` is the number of times it happened since it was last
+    /// logged without being logged, zero unless it's been repeating.
+    Error { repeated: u64 },
+    /// Don't log the error since the same one was logged recently.
+    Suppress,
+    /// Log that reads succeed again after `failures` consecutive failed reads.
+    Recovered { failures: u64 },
+    /// Nothing to log, the read succeeded like the one before it.
+    Nothing,
+}
+
+/// The error currently repeating and when it was last logged.
+#[derive(Debug, Clone)]
+struct Repeating {
+    kind: SensorErrorKind,
+    message: String,
+    logged: Instant,
+    suppressed: u64,
+    failures: u64,
+}
+
+/// Logs failed reads without logging the same error over and over.
+///
+/// A sensor that's unplugged fails every read the same way, forever. The first time an
+/// error happens it's logged right away. While the same error, by kind and message, keeps
+/// happening it's logged at most once per `interval` along with how many times it was
+/// repeated in the meantime. A different error is logged right away, as is the first
+/// successful read after any errors.
+#[derive(Debug, Clone)]
+pub struct DedupLogger {
+    interval: Duration,
+    clock: Arc<dyn Clock>,
+    current: Option<Repeating>,
+}
+
+impl DedupLogger {
+    /// Create a logger that logs the same error at most once per `interval`, using `clock`
+    /// to decide when that is.
+    pub fn new(interval: Duration, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            interval,
+            clock,
+            current: None,
+        }
+    }
+
+    /// Log the result of a read if it should be, see `observe`.
+    pub fn log(&mut self, res: &Result<Measurement, SensorError>) {
+        match (self.observe(res), res) {
+            (LogDecision::Error { repeated: 0 }, Err(e)) => {
+                tracing::error!(message = "unable to read sensor", error = %e);
+            }
+            (LogDecision::Error { repeated }, Err(e)) => {
+                tracing::error!(message = "unable to read sensor", error = %e, repeated = repeated);
+            }
+            (LogDecision::Recovered { failures }, _) => {
+                tracing::info!(message = "sensor read succeeded after failures", failures = failures);
+            }
+            _ => {}
+        }
+    }
+
+    /// Decide what to log for the result of a read, counting it toward the current error.
+    pub fn observe(&mut self, res: &Result<Measurement, SensorError>) -> LogDecision {
+        let now = self.clock.now_monotonic();
+        let e = match res {
+            Ok(_) => {
+                return match self.current.take() {
+                    Some(r) => LogDecision::Recovered { failures: r.failures },
+                    None => LogDecision::Nothing,
+                }
+            }
+            Err(e) => e,
+        };
+
+        let message = e.to_string();
+        match &mut self.current {
+            Some(r) if r.kind == e.kind() && r.message == message => {
+                r.failures += 1;
+                if now.saturating_duration_since(r.logged) < self.interval {
+                    r.suppressed += 1;
+                    return LogDecision::Suppress;
+                }
+
+                let repeated = r.suppressed;
+                r.suppressed = 0;
+                r.logged = now;
+                LogDecision::Error { repeated }
+            }
+            current => {
+                let failures = current.as_ref().map(|r| r.failures).unwrap_or(0) + 1;
+                *current = Some(Repeating {
+                    kind: e.kind(),
+                    message,
+                    logged: now,
+                    suppressed: 0,
+                    failures,
+                });
+                LogDecision::Error { repeated: 0 }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DedupLogger, LogDecision};
+    use crate::clock::MockClock;
+    use crate::sensor::core::{Humidity, Measurement, SensorError, SensorErrorKind, TemperatureCelsius};
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    const INTERVAL: Duration = Duration::from_secs(300);
+
+    fn ok() -> Result<Measurement, SensorError> {
+        Ok(Measurement {
+            temperature: TemperatureCelsius::from(21.0),
+            humidity: Humidity::from(40.0),
+        })
+    }
+
+    fn timeout() -> Result<Measurement, SensorError> {
+        Err(SensorError::timeout("timeout waiting for low pulse capture"))
+    }
+
+    fn checksum() -> Result<Measurement, SensorError> {
+        Err(SensorError::new(SensorErrorKind::Checksum, "checksum mismatch"))
+    }
+
+    fn setup() -> (MockClock, DedupLogger) {
+        let clock = MockClock::new(SystemTime::UNIX_EPOCH);
+        let logger = DedupLogger::new(INTERVAL, Arc::new(clock.clone()));
+        (clock, logger)
+    }
+
+    /// Observe each result five seconds apart, returning the decisions
+    fn script(
+        clock: &MockClock,
+        logger: &mut DedupLogger,
+        results: &[Result<Measurement, SensorError>],
+    ) -> Vec<LogDecision> {
+        results
+            .iter()
+            .map(|res| {
+                clock.advance(Duration::from_secs(5));
+                logger.observe(res)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_dedup_logger_success() {
+        let (clock, mut logger) = setup();
+        let decisions = script(&clock, &mut logger, &[ok(), ok(), ok()]);
+        assert_eq!(vec![LogDecision::Nothing; 3], decisions);
+    }
+
+    #[test]
+    fn test_dedup_logger_repeated_error() {
+        let (clock, mut logger) = setup();
+
+        // An error every five seconds for ten minutes is only logged at the start and after five minutes
+        let results: Vec<_> = (0..120).map(|_| timeout()).collect();
+        let decisions = script(&clock, &mut logger, &results);
+        let logged: Vec<_> = decisions
+            .iter()
+            .enumerate()
+            .filter(|(_, d)| **d != LogDecision::Suppress)
+            .collect();
+
+        assert_eq!(
+            vec![
+                (0, &LogDecision::Error { repeated: 0 }),
+                (60, &LogDecision::Error { repeated: 59 }),
+            ],
+            logged
+        );
+    }
+
+    #[test]
+    fn test_dedup_logger_kind_changes() {
+        let (clock, mut logger) = setup();
+        let decisions = script(
+            &clock,
+            &mut logger,
+            &[timeout(), timeout(), checksum(), checksum(), timeout()],
+        );
+
+        assert_eq!(
+            vec![
+                LogDecision::Error { repeated: 0 },
+                LogDecision::Suppress,
+                LogDecision::Error { repeated: 0 },
+                LogDecision::Suppress,
+                LogDecision::Error { repeated: 0 },
+            ],
+            decisions
+        );
+    }
+
+    #[test]
+    fn test_dedup_logger_recovery() {
+        let (clock, mut logger) = setup();
+        let decisions = script(
+            &clock,
+            &mut logger,
+            &[timeout(), timeout(), checksum(), ok(), ok(), timeout()],
+        );
+
+        assert_eq!(
+            vec![
+                LogDecision::Error { repeated: 0 },
+                LogDecision::Suppress,
+                LogDecision::Error { repeated: 0 },
+                LogDecision::Recovered { failures: 3 },
+                LogDecision::Nothing,
+                LogDecision::Error { repeated: 0 },
+            ],
+            decisions
+        );
+    }
+
+    #[test]
+    fn test_dedup_logger_interval_elapsed() {
+        let (clock, mut logger) = setup();
+        assert_eq!(LogDecision::Error { repeated: 0 }, logger.observe(&timeout()));
+
+        clock.advance(INTERVAL - Duration::from_secs(1));
+        assert_eq!(LogDecision::Suppress, logger.observe(&timeout()));
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(LogDecision::Error { repeated: 1 }, logger.observe(&timeout()));
+
+        clock.advance(INTERVAL * 2);
+        assert_eq!(LogDecision::Error { repeated: 0 }, logger.observe(&timeout()));
+    }
+}
@@ -16,37 +16,151 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 //
 
-use crate::sensor::core::{DataPin, Humidity, SensorError, SensorErrorKind, TemperatureCelsius};
-use rppal::gpio::Mode;
+use crate::device::PulseTiming;
+use crate::sensor::core::{
+    DataPin, Humidity, Level, Measurement, PinMode, PulseStats, RawReading, Sensor, SensorError, SensorRanges,
+    TemperatureCelsius, WaitTimeout,
+};
+use crate::sensor::frame::TwoMeans;
 use std::fmt::{Debug, Formatter};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-pub(crate) const DHT_MAX_COUNT: u32 = 32_000;
-pub(crate) const DHT_PULSES: usize = 41;
+/// Maximum number of cycles to wait for the sensor to answer the start signal, which can
+/// legitimately take a while, and for each pulse of the data bits after it, which should
+/// follow each other closely. The same by default.
+pub(crate) const DHT_RESPONSE_MAX_COUNT: u32 = 32_000;
+pub(crate) const DHT_BIT_MAX_COUNT: u32 = 32_000;
+pub(crate) const DEFAULT_WAKE_HIGH: Duration = Duration::from_millis(10);
+pub(crate) const DEFAULT_START_LOW: Duration = Duration::from_millis(20);
+pub(crate) const DEFAULT_START_HIGH: Duration = Duration::from_micros(30);
+pub(crate) const DHT_PULSES: usize = 40;
 pub(crate) const DATA_SIZE: usize = 5;
+pub(crate) const TIMING_CALIBRATION_DURATION: Duration = Duration::from_millis(10);
+
+/// Number of times the pin is checked between looking at the clock while calibrating
+/// timing, so that looking at the clock doesn't slow down the checks being counted.
+const TIMING_CALIBRATION_CHUNK: u32 = 1_000;
+
+/// How long the sensor holds the data pin high for a 0 bit (26-28us) and a 1 bit (70us),
+/// in microseconds, from its datasheet.
+const BIT_ZERO_HIGH_US: f64 = 28.0;
+const BIT_ONE_HIGH_US: f64 = 70.0;
+
+/// Range of lengths of the low and high pulses the sensor answers the start signal with
+/// that are accepted when validating the response, in microseconds. Both are 80us in
+/// its datasheet.
+const RESPONSE_MIN_US: f64 = 60.0;
+const RESPONSE_MAX_US: f64 = 100.0;
+
+/// Range of values the DHT22 can measure, from its datasheet.
+const RANGES: SensorRanges = SensorRanges {
+    min_temperature: -40.0,
+    max_temperature: 80.0,
+    min_humidity: 0.0,
+    max_humidity: 100.0,
+};
+
+/// Cycle counts of the low and high pulses the sensor answers the start signal with,
+/// `None` if the pin didn't change from that level in time.
+///
+/// The sensor holds the data pin low and then high for 80us each before sending data
+/// bits, according to its datasheet. A line that's floating or held high without a
+/// sensor attached never gets this response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ResponsePulse {
+    low: Option<u32>,
+    high: Option<u32>,
+}
+
+impl ResponsePulse {
+    /// Count the number of cycles the given pin spends in the low and then high state
+    /// after the start signal. There's no wait for the high pulse if the low one didn't
+    /// end in time.
+    fn from_data_pin<P>(pin: &P, max_count: u32) -> Self
+    where
+        P: DataPin + ?Sized,
+    {
+        let low = pin.wait_while_level(Level::Low, max_count).ok();
+        let high = match low {
+            Some(_) => pin.wait_while_level(Level::High, max_count).ok(),
+            None => None,
+        };
+
+        Self { low, high }
+    }
+
+    /// Return an error if either pulse didn't end in time.
+    fn check(&self) -> Result<(), SensorError> {
+        match (self.low, self.high) {
+            (None, _) => Err(SensorError::PulseTimeout {
+                bit: 0,
+                phase: Level::Low,
+            }),
+            (_, None) => Err(SensorError::PulseTimeout {
+                bit: 0,
+                phase: Level::High,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Return an error with the width of each pulse if either of them didn't end in time
+    /// or lasted more or less than the range allowed around the 80us from the datasheet.
+    fn validate(&self, timing: &TimingCalibration) -> Result<(), SensorError> {
+        let min = timing.cycles(RESPONSE_MIN_US);
+        let max = timing.cycles(RESPONSE_MAX_US);
+        let valid = |count: Option<u32>| count.map(|c| c >= min && c <= max).unwrap_or(false);
+
+        if valid(self.low) && valid(self.high) {
+            Ok(())
+        } else {
+            Err(SensorError::NoResponse {
+                low_us: self.low.map(|c| timing.micros(c)),
+                high_us: self.high.map(|c| timing.micros(c)),
+            })
+        }
+    }
+}
 
 /// Cycle counts of how long the sensor data pin spent low and high states.
 ///
-/// There are 40 low/high transitions we count cycles for. These counts are
-/// used to read 40 bits of information from the sensor.
+/// There are 40 low/high transitions after the response to the start signal that we
+/// count cycles for. These counts are used to read 40 bits of information from the sensor.
 #[derive(Debug)]
 struct Pulses {
-    // We store counts for 41 transitions but don't use the first low/high transition
     counts: [u32; DHT_PULSES * 2],
 }
 
 impl Pulses {
     /// Count the number of cycles the given pin spends in the low and high states for
-    /// 40 low/high transitions.
+    /// the response to the start signal and 40 low/high transitions after it.
+    ///
+    /// An error will be returned if the pin didn't transition in time or, when timing
+    /// calibration is given, if the response to the start signal wasn't the expected
+    /// length. The read will have to be retried in this case.
     ///
-    /// An error will be returned if the pin didn't transition in time. The read will have
-    /// to be retried in this case.
+    /// The pin may stay at the same level for up to `response_max` cycles during each
+    /// pulse of the response and up to `bit_max` cycles during each pulse after it.
     ///
     /// NOTE: This method assumes the pin as already been prepared for reading by sending
     /// and initial high-low-high transition with timings corresponding to the DHT22
     /// datasheet.
-    fn from_data_pin(pin: &dyn DataPin) -> Result<Self, SensorError> {
+    fn from_data_pin<P>(
+        pin: &P,
+        response_max: u32,
+        bit_max: u32,
+        validate: Option<&TimingCalibration>,
+    ) -> Result<Self, SensorError>
+    where
+        P: DataPin + ?Sized,
+    {
+        let response = ResponsePulse::from_data_pin(pin, response_max);
+        match validate {
+            Some(timing) => response.validate(timing)?,
+            None => response.check()?,
+        }
+
         // Create an array with 2x the number of pulses we're going to measure so that we can
         // store the number of cycles the pin spent high and low for each pulse.
         let mut counts: [u32; DHT_PULSES * 2] = [0; DHT_PULSES * 2];
@@ -54,50 +168,126 @@ impl Pulses {
         // Store counts for both high and low states of the pin in the same array. We advance
         // by two entries each iteration of the loop but use (i + 1) to access the odd entries.
         //
-        // We only store up to DHT_MAX_COUNT which is a much much higher number of cycles than
+        // We only store up to `bit_max` which is a much much higher number of cycles than
         // we expect to get in practice (normal number of cycles at high or low is < 1000).
         // This is done to enforce a timeout while waiting for the pin to switch between low
         // and high states. In this case, the read will have to be retried.
         for i in (0..counts.len()).step_by(2) {
-            while pin.is_low() {
-                counts[i] += 1;
-                if counts[i] >= DHT_MAX_COUNT {
-                    return Err(SensorError::KindMsg(
-                        SensorErrorKind::ReadTimeout,
-                        "timeout waiting for low pulse capture",
-                    ));
-                }
-            }
+            counts[i] = pin
+                .wait_while_level(Level::Low, bit_max)
+                .map_err(|_| SensorError::PulseTimeout {
+                    bit: i / 2 + 1,
+                    phase: Level::Low,
+                })?;
 
-            while pin.is_high() {
-                counts[i + 1] += 1;
-                if counts[i + 1] >= DHT_MAX_COUNT {
-                    return Err(SensorError::KindMsg(
-                        SensorErrorKind::ReadTimeout,
-                        "timeout waiting for high pulse capture",
-                    ));
-                }
-            }
+            counts[i + 1] = pin
+                .wait_while_level(Level::High, bit_max)
+                .map_err(|_| SensorError::PulseTimeout {
+                    bit: i / 2 + 1,
+                    phase: Level::High,
+                })?;
         }
 
-        tracing::trace!(message = "reading low/high pulse counts", counts = ?counts);
+        tracing::trace!(message = "reading low/high pulse counts", response = ?response, counts = ?counts);
         Ok(Self { counts })
     }
 
     /// Return an iterator over 40 cycle counts for the pin in the low state.
     fn low(&self) -> impl ExactSizeIterator<Item = &u32> {
-        // Start from the 3rd element (first valid low count), emitting only low counts.
-        // We're skipping the first low/high transition since the pin starts in the low
-        // state when reading data and thus the first cycle count is always zero.
-        self.counts.iter().skip(2).step_by(2)
+        self.counts.iter().step_by(2)
     }
 
     /// Return an iterator over 40 cycle counts for the pin in the high state.
     fn high(&self) -> impl ExactSizeIterator<Item = &u32> {
-        // Start from the 4th element (first valid high count), emitting only high counts.
-        // We're skipping the first low/high transition since the pin starts in the low
-        // state when reading data and thus the first cycle count is always zero.
-        self.counts.iter().skip(3).step_by(2)
+        self.counts.iter().skip(1).step_by(2)
+    }
+
+    /// Average number of cycles of the low and high pulses.
+    fn stats(&self) -> PulseStats {
+        let mean = |counts: &mut dyn ExactSizeIterator<Item = &u32>| {
+            let n = counts.len() as f64;
+            counts.map(|c| f64::from(*c)).sum::<f64>() / n
+        };
+
+        PulseStats {
+            mean_low: mean(&mut self.low()),
+            mean_high: mean(&mut self.high()),
+        }
+    }
+}
+
+/// Number of times a data pin can be checked per microsecond, used to convert timings
+/// from the DHT22 datasheet into cycle counts for a particular device.
+///
+/// The number of cycles a pulse lasts depends on the speed of the CPU and the GPIO
+/// backend, so the same pulse is many more cycles on a Pi 4 than on a Pi Zero.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TimingCalibration {
+    cycles_per_us: f64,
+}
+
+impl TimingCalibration {
+    pub fn new(cycles_per_us: f64) -> Self {
+        Self { cycles_per_us }
+    }
+
+    /// Measure how many times `pin` can be checked per microsecond by holding it high in
+    /// output mode for `duration` and counting checks of its level, releasing it after.
+    ///
+    /// Returns `None` if the pin didn't stay high, for example because it's connected to
+    /// something driving it low, since checks of the pin can't be counted.
+    pub fn measure<P>(pin: &mut P, duration: Duration) -> Option<Self>
+    where
+        P: DataPin + ?Sized,
+    {
+        pin.set_mode(PinMode::Output);
+        pin.set_high();
+
+        // Checks are counted using the same method as reads of the sensor so that the
+        // cost of each check is the same as when counting the length of pulses.
+        let start = Instant::now();
+        let mut cycles: u64 = 0;
+        let mut stayed_high = true;
+        while start.elapsed() < duration {
+            match pin.wait_while_level(Level::High, TIMING_CALIBRATION_CHUNK) {
+                Err(WaitTimeout) => cycles += u64::from(TIMING_CALIBRATION_CHUNK),
+                Ok(_) => {
+                    stayed_high = false;
+                    break;
+                }
+            }
+        }
+
+        let elapsed = start.elapsed();
+        pin.release();
+
+        let micros = elapsed.as_secs_f64() * 1_000_000.0;
+        if !stayed_high || cycles == 0 || micros == 0.0 {
+            return None;
+        }
+
+        Some(Self::new(cycles as f64 / micros))
+    }
+
+    pub fn cycles_per_us(&self) -> f64 {
+        self.cycles_per_us
+    }
+
+    /// Number of cycles the pin is checked for during a pulse of `us` microseconds.
+    pub fn cycles(&self, us: f64) -> u32 {
+        // Casts from floats saturate so very large values are u32::MAX
+        (us * self.cycles_per_us).round() as u32
+    }
+
+    /// Number of microseconds a pulse of `cycles` cycles lasts.
+    pub fn micros(&self, cycles: u32) -> f64 {
+        f64::from(cycles) / self.cycles_per_us
+    }
+
+    /// Number of cycles a high pulse must last to be a 1 bit, halfway between the length
+    /// of the high pulses for 0 and 1 bits.
+    pub fn bit_threshold(&self) -> u32 {
+        self.cycles((BIT_ZERO_HIGH_US + BIT_ONE_HIGH_US) / 2.0)
     }
 }
 
@@ -112,15 +302,61 @@ struct Reading {
 }
 
 impl Reading {
-    fn from_pulses(pulses: &Pulses) -> Result<Self, SensorError> {
-        let mut bytes: [u8; DATA_SIZE] = [0; DATA_SIZE];
+    fn decode(pulses: &Pulses, timing: Option<&TimingCalibration>) -> Result<[u8; DATA_SIZE], SensorError> {
+        let threshold = Self::threshold(pulses.low(), timing)?;
+        Self::classify(pulses.high(), threshold)
+    }
 
-        // Find the average low pin cycle count so that we can determine if each high
-        // pin cycle count is meant to be a 0 bit (lower than the threshold) or a 1 bit
-        // (higher than the threshold).
-        let threshold = pulses.low().sum::<u32>() / pulses.low().len() as u32;
+    /// Number of cycles a high pulse must last to be a 1 bit rather than a 0 bit.
+    ///
+    /// Use the threshold from timing calibration if there is one. Otherwise, use the average
+    /// low pin cycle count since the low pulse before each bit (50us) is between the lengths
+    /// of 0 and 1 bits. Returns a `SensorErrorKind::FrameError` error if fewer than 40 low
+    /// pulses were captured or the threshold is zero, since every bit would be a 1 bit.
+    fn threshold<'a, I>(low: I, timing: Option<&TimingCalibration>) -> Result<u32, SensorError>
+    where
+        I: ExactSizeIterator<Item = &'a u32>,
+    {
+        let captured = low.len();
+        if captured < DHT_PULSES {
+            return Err(SensorError::frame(format!(
+                "only {} of {} low pulses captured",
+                captured, DHT_PULSES
+            )));
+        }
+
+        // The average is computed as a u64 since the sum of 40 counts can overflow a u32
+        // even though the average of them can't.
+        let threshold = match timing {
+            Some(t) => t.bit_threshold(),
+            None => (low.map(|c| u64::from(*c)).sum::<u64>() / captured as u64) as u32,
+        };
+
+        if threshold == 0 {
+            return Err(SensorError::frame("threshold between 0 and 1 bits is zero cycles"));
+        }
+
+        Ok(threshold)
+    }
+
+    /// Decode the 40 bits of a read from the cycle counts of their high pulses: 1 bits
+    /// if the pulse lasted at least `threshold` cycles, 0 bits otherwise. Returns a
+    /// `SensorErrorKind::FrameError` error if fewer than 40 high pulses were captured.
+    /// Any pulses after the first 40 are ignored.
+    fn classify<'a, I>(high: I, threshold: u32) -> Result<[u8; DATA_SIZE], SensorError>
+    where
+        I: ExactSizeIterator<Item = &'a u32>,
+    {
+        let captured = high.len();
+        if captured < DHT_PULSES {
+            return Err(SensorError::frame(format!(
+                "only {} of {} high pulses captured",
+                captured, DHT_PULSES
+            )));
+        }
 
-        for (i, &v) in pulses.high().enumerate() {
+        let mut bytes: [u8; DATA_SIZE] = [0; DATA_SIZE];
+        for (i, &v) in high.take(DHT_PULSES).enumerate() {
             // There are 40 low/high transition cycle counts and hence 40 bits of data
             // that we need to parse. Divide by eight to figure out which byte this bit
             // will end up in and shift the current value left (we only operate on the
@@ -133,6 +369,10 @@ impl Reading {
             }
         }
 
+        Ok(bytes)
+    }
+
+    fn from_bytes(bytes: [u8; DATA_SIZE]) -> Result<Self, SensorError> {
         // Byte five is a checksum of the first four bytes, return an error if it indicates
         // the data we've read is corrupt somehow.
         Self::checksum_bytes(&bytes)?;
@@ -159,6 +399,52 @@ impl Reading {
             Ok(())
         }
     }
+
+    /// Check a reading more strictly than its checksum can, since a frame shifted by a bit
+    /// can still have a valid checksum. Returns a `SensorErrorKind::FrameError` error unless
+    /// the reading was decoded from exactly one low and one high pulse per bit, is within
+    /// the range the DHT22 can measure, and its high pulses form two clearly separated
+    /// clusters for 0 and 1 bits that the threshold used to decode them falls between.
+    fn check_strict(&self, pulses: &Pulses, timing: Option<&TimingCalibration>) -> Result<(), SensorError> {
+        let (low, high) = (pulses.low().len(), pulses.high().len());
+        if low != DHT_PULSES || high != DHT_PULSES {
+            return Err(SensorError::frame(format!(
+                "{} low and {} high pulses captured for {} bits",
+                low, high, DHT_PULSES
+            )));
+        }
+
+        let (temperature, humidity): (TemperatureCelsius, Humidity) = Reading { bytes: self.bytes }.into();
+        let (t, h) = (f64::from(temperature), f64::from(humidity));
+        if !(RANGES.min_temperature..=RANGES.max_temperature).contains(&t)
+            || !(RANGES.min_humidity..=RANGES.max_humidity).contains(&h)
+        {
+            return Err(SensorError::frame(format!(
+                "decoded {:.1} degrees and {:.1}% humidity, outside of the range of the sensor",
+                t, h
+            )));
+        }
+
+        let counts: Vec<u32> = pulses.high().copied().collect();
+        let clusters = TwoMeans::split(&counts)
+            .ok_or_else(|| SensorError::frame("high pulses are all the same length, not 0 and 1 bits"))?;
+        if !clusters.is_separated() {
+            return Err(SensorError::frame(format!(
+                "high pulses aren't clearly 0 or 1 bits, separation {:.2}",
+                clusters.separation()
+            )));
+        }
+
+        let threshold = f64::from(Self::threshold(pulses.low(), timing)?);
+        if threshold <= clusters.low.max || threshold > clusters.high.min {
+            return Err(SensorError::frame(format!(
+                "threshold of {} cycles doesn't separate 0 bits ({}-{}) from 1 bits ({}-{})",
+                threshold, clusters.low.min, clusters.low.max, clusters.high.min, clusters.high.max
+            )));
+        }
+
+        Ok(())
+    }
 }
 
 impl From<Reading> for (TemperatureCelsius, Humidity) {
@@ -196,46 +482,403 @@ impl From<Reading> for (TemperatureCelsius, Humidity) {
     }
 }
 
+/// Decode temperature and humidity from the cycle counts of the low and high pulses the
+/// sensor answers the start signal with followed by the low and high pulse of each of the
+/// 40 data bits, the same way as a read of the sensor. The response to the start signal is
+/// only validated when `timing` is given. Used to fuzz decoding.
+#[cfg(feature = "fuzzing")]
+pub fn decode_counts(
+    counts: &[u32; DHT_PULSES * 2 + 2],
+    timing: Option<&TimingCalibration>,
+) -> Result<(TemperatureCelsius, Humidity), SensorError> {
+    let response = ResponsePulse {
+        low: Some(counts[0]),
+        high: Some(counts[1]),
+    };
+    if let Some(t) = timing {
+        response.validate(t)?;
+    }
+
+    let mut pulses = Pulses {
+        counts: [0; DHT_PULSES * 2],
+    };
+    pulses.counts.copy_from_slice(&counts[2..]);
+    let bytes = Reading::decode(&pulses, timing)?;
+    let data = Reading::from_bytes(bytes)?;
+    Ok(data.into())
+}
+
+/// Builder for a `DHT22Sensor` with non-default timings.
+///
+/// Some sensors need different timings than the defaults to reliably start a read. All
+/// defaults may be used by calling `DHT22Sensor::from_pin` instead.
+pub struct DHT22SensorBuilder<P: DataPin> {
+    pin: P,
+    wake_high: Duration,
+    start_low: Duration,
+    start_high: Duration,
+    response_max_cycles: u32,
+    bit_max_cycles: u32,
+    min_read_interval: Duration,
+    calibrate_timing: bool,
+    timing: Option<TimingCalibration>,
+    validate_response: bool,
+    strict_frames: bool,
+}
+
+impl<P: DataPin> DHT22SensorBuilder<P> {
+    /// How long to hold the pin high to wake the sensor up from low-power mode,
+    /// in milliseconds. Default 10ms.
+    pub fn wake_high_ms(mut self, ms: u64) -> Self {
+        self.wake_high = Duration::from_millis(ms);
+        self
+    }
+
+    /// How long to hold the pin low to signal the start of a read, in milliseconds.
+    /// Default 20ms.
+    pub fn start_low_ms(mut self, ms: u64) -> Self {
+        self.start_low = Duration::from_millis(ms);
+        self
+    }
+
+    /// How long to hold the pin high before waiting for the sensor to respond, in
+    /// microseconds. Default 30us.
+    pub fn start_high_us(mut self, us: u64) -> Self {
+        self.start_high = Duration::from_micros(us);
+        self
+    }
+
+    /// Maximum number of cycles to wait for the pin to change state before giving up
+    /// and returning a timeout error, for both the response to the start signal and the
+    /// data bits after it. Default 32,000.
+    pub fn max_cycles(mut self, cycles: u32) -> Self {
+        self.response_max_cycles = cycles;
+        self.bit_max_cycles = cycles;
+        self
+    }
+
+    /// Maximum number of cycles to wait for each of the low and high pulses the sensor
+    /// answers the start signal with before giving up and returning a timeout error.
+    /// Default 32,000.
+    pub fn response_max_cycles(mut self, cycles: u32) -> Self {
+        self.response_max_cycles = cycles;
+        self
+    }
+
+    /// Maximum number of cycles to wait for the pin to change state partway through the
+    /// data bits before giving up and returning a timeout error. Lower than the limit for
+    /// the response to fail fast when the line freezes mid-read. Default 32,000.
+    pub fn bit_max_cycles(mut self, cycles: u32) -> Self {
+        self.bit_max_cycles = cycles;
+        self
+    }
+
+    /// Minimum time between the start of reads. Reads more frequent than this will
+    /// block until enough time has passed. Default zero (no minimum).
+    pub fn min_read_interval(mut self, interval: Duration) -> Self {
+        self.min_read_interval = interval;
+        self
+    }
+
+    /// Measure how many times the data pin can be checked per microsecond when the sensor
+    /// is built and again each time it's reset, see `DHT22Sensor::calibrate_timing`. Bits
+    /// are decoded using timings from the datasheet converted to cycles instead of the
+    /// average length of low pulses of each read. Default false.
+    pub fn calibrate_timing(mut self, enabled: bool) -> Self {
+        self.calibrate_timing = enabled;
+        self
+    }
+
+    /// Decode bits using the given timing calibration instead of measuring it or using
+    /// the average length of low pulses of each read. Replaced by measurements if timing
+    /// calibration is enabled.
+    pub fn timing(mut self, timing: TimingCalibration) -> Self {
+        self.timing = Some(timing);
+        self
+    }
+
+    /// Require the low and high pulses the sensor answers the start signal with to each
+    /// last 60-100us, returning a `SensorErrorKind::NoResponse` error otherwise. Only
+    /// used when there's timing calibration since the pulses can't be converted to
+    /// microseconds without it. Default false.
+    pub fn validate_response(mut self, enabled: bool) -> Self {
+        self.validate_response = enabled;
+        self
+    }
+
+    /// Check reads with valid checksums more strictly, returning a `SensorErrorKind::FrameError`
+    /// error if they were decoded from the wrong number of pulses, are outside the range of
+    /// the sensor, or have high pulses that aren't clearly either 0 or 1 bits. Default false.
+    pub fn strict_frames(mut self, enabled: bool) -> Self {
+        self.strict_frames = enabled;
+        self
+    }
+
+    pub fn build(self) -> DHT22Sensor<P> {
+        let mut sensor = DHT22Sensor {
+            pin: self.pin,
+            wake_high: self.wake_high,
+            start_low: self.start_low,
+            start_high: self.start_high,
+            response_max_cycles: self.response_max_cycles,
+            bit_max_cycles: self.bit_max_cycles,
+            min_read_interval: self.min_read_interval,
+            calibrate_timing: self.calibrate_timing,
+            timing: self.timing,
+            validate_response: self.validate_response,
+            strict_frames: self.strict_frames,
+            measured_timing: None,
+            last_read: None,
+            last_raw: None,
+            last_pulses: None,
+        };
+
+        if sensor.calibrate_timing {
+            sensor.calibrate_timing();
+        }
+
+        sensor
+    }
+}
+
+impl<P: DataPin> Debug for DHT22SensorBuilder<P> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DHT22SensorBuilder")
+            .field("pin", &self.pin.pin())
+            .field("wake_high", &self.wake_high)
+            .field("start_low", &self.start_low)
+            .field("start_high", &self.start_high)
+            .field("response_max_cycles", &self.response_max_cycles)
+            .field("bit_max_cycles", &self.bit_max_cycles)
+            .field("min_read_interval", &self.min_read_interval)
+            .field("calibrate_timing", &self.calibrate_timing)
+            .field("timing", &self.timing)
+            .field("validate_response", &self.validate_response)
+            .field("strict_frames", &self.strict_frames)
+            .finish()
+    }
+}
+
 /// Read temperature in degrees celsius and relative humidity from a DHT22 sensor
-pub struct DHT22Sensor {
-    pin: Box<dyn DataPin + Send + Sync + 'static>,
+/// connected to a pin of type `P`.
+///
+/// Use `DynDHT22Sensor` when the type of the pin is only known at runtime.
+pub struct DHT22Sensor<P: DataPin> {
+    pin: P,
+    wake_high: Duration,
+    start_low: Duration,
+    start_high: Duration,
+    response_max_cycles: u32,
+    bit_max_cycles: u32,
+    min_read_interval: Duration,
+    calibrate_timing: bool,
+    timing: Option<TimingCalibration>,
+    validate_response: bool,
+    strict_frames: bool,
+    // Measured the first time a read with `PulseTiming::Calibrated` is requested if the
+    // sensor doesn't otherwise use timing calibration.
+    measured_timing: Option<TimingCalibration>,
+    last_read: Option<Instant>,
+    last_raw: Option<RawReading>,
+    last_pulses: Option<PulseStats>,
 }
 
-impl DHT22Sensor {
-    pub fn from_pin<T>(pin: T) -> Self
-    where
-        T: DataPin + Send + Sync + 'static,
-    {
-        Self { pin: Box::new(pin) }
+/// A `DHT22Sensor` using a boxed pin, for when the type of pin is only known at runtime.
+pub type DynDHT22Sensor = DHT22Sensor<Box<dyn DataPin + Send + Sync>>;
+
+impl<P: DataPin> DHT22Sensor<P> {
+    /// Create a new sensor using the given pin and default timings.
+    pub fn from_pin(pin: P) -> Self {
+        Self::builder(pin).build()
     }
 
-    fn prepare_for_read(&mut self) {
-        // https://cdn-shop.adafruit.com/datasheets/Digital+humidity+and+temperature+sensor+AM2302.pdf
-        // Host needs to set the sensor:
-        // * high to start the read process, waking the sensor up from low-power mode
-        // * low for at least 1ms to ensure the sensor detected the start of this process
-        // * high for 20-40us to then wait for the sensor's response
-        self.pin.set_mode(Mode::Output);
-        self.pin.set_high();
-        thread::sleep(Duration::from_millis(10));
-        self.pin.set_low();
-        thread::sleep(Duration::from_millis(20));
-        self.pin.set_high();
-        thread::sleep(Duration::from_micros(30));
-        self.pin.set_mode(Mode::Input);
+    /// Create a builder for a sensor using the given pin to customize timings.
+    pub fn builder(pin: P) -> DHT22SensorBuilder<P> {
+        DHT22SensorBuilder {
+            pin,
+            wake_high: DEFAULT_WAKE_HIGH,
+            start_low: DEFAULT_START_LOW,
+            start_high: DEFAULT_START_HIGH,
+            response_max_cycles: DHT_RESPONSE_MAX_COUNT,
+            bit_max_cycles: DHT_BIT_MAX_COUNT,
+            min_read_interval: Duration::ZERO,
+            calibrate_timing: false,
+            timing: None,
+            validate_response: false,
+            strict_frames: false,
+        }
+    }
+
+    /// Measure how many times the data pin can be checked per microsecond, replacing any
+    /// previous calibration. Bits are decoded using the average length of low pulses of
+    /// each read if the pin couldn't be calibrated.
+    pub fn calibrate_timing(&mut self) -> Option<TimingCalibration> {
+        self.timing = TimingCalibration::measure(&mut self.pin, TIMING_CALIBRATION_DURATION);
+        match &self.timing {
+            Some(t) => tracing::info!(
+                message = "calibrated timing of data pin",
+                bcm_pin = self.pin.pin(),
+                cycles_per_us = t.cycles_per_us(),
+                bit_threshold = t.bit_threshold(),
+            ),
+            None => tracing::warn!(
+                message = "unable to calibrate timing of data pin, using timing of each read instead",
+                bcm_pin = self.pin.pin(),
+            ),
+        }
+
+        self.timing
+    }
+
+    /// Timing calibration used to decode bits, if any.
+    pub fn timing(&self) -> Option<TimingCalibration> {
+        self.timing
+    }
+
+    fn wait_for_interval(&mut self) {
+        if let Some(last) = self.last_read {
+            let elapsed = last.elapsed();
+            if elapsed < self.min_read_interval {
+                thread::sleep(self.min_read_interval - elapsed);
+            }
+        }
+
+        self.last_read = Some(Instant::now());
     }
 
     /// Read temperature and humidity from the sensor or return an error if the
     /// read failed with details about what caused the read to fail.
     pub fn read(&mut self) -> Result<(TemperatureCelsius, Humidity), SensorError> {
-        self.prepare_for_read();
-        let pulses = Pulses::from_data_pin(self.pin.as_ref())?;
-        let data = Reading::from_pulses(&pulses)?;
+        self.last_raw = None;
+        self.last_pulses = None;
+
+        let pulses = self.capture()?;
+        self.last_pulses = Some(pulses.stats());
+        let bytes = Reading::decode(&pulses, self.timing.as_ref())?;
+        self.last_raw = Some(RawReading { bytes });
+        let data = Reading::from_bytes(bytes)?;
+        if self.strict_frames {
+            data.check_strict(&pulses, self.timing.as_ref())?;
+        }
+
+        Ok(data.into())
+    }
+
+    /// Read temperature and humidity from the sensor, decoding bits with `timing` instead
+    /// of the way the sensor was built to. Bytes and pulses of the read aren't recorded, see
+    /// `Sensor::read_with_timing`.
+    ///
+    /// When the sensor doesn't use timing calibration, reads with `PulseTiming::Calibrated`
+    /// measure it the first time and fail if the pin can't be calibrated.
+    pub fn read_with_timing(&mut self, timing: PulseTiming) -> Result<(TemperatureCelsius, Humidity), SensorError> {
+        let calibration = match timing {
+            PulseTiming::Relative => None,
+            PulseTiming::Calibrated => Some(self.calibrated_timing()?),
+        };
+
+        let pulses = self.capture()?;
+        let bytes = Reading::decode(&pulses, calibration.as_ref())?;
+        let data = Reading::from_bytes(bytes)?;
+        if self.strict_frames {
+            data.check_strict(&pulses, calibration.as_ref())?;
+        }
+
         Ok(data.into())
     }
+
+    /// Timing calibration the sensor uses, if any, otherwise the one measured for reads
+    /// with `PulseTiming::Calibrated`, measuring it if it hasn't been yet.
+    fn calibrated_timing(&mut self) -> Result<TimingCalibration, SensorError> {
+        if let Some(t) = self.timing.or(self.measured_timing) {
+            return Ok(t);
+        }
+
+        let measured = TimingCalibration::measure(&mut self.pin, TIMING_CALIBRATION_DURATION)
+            .ok_or_else(|| SensorError::initialization("unable to calibrate timing of data pin"))?;
+        self.measured_timing = Some(measured);
+        Ok(measured)
+    }
+
+    /// Signal the sensor to start a read and capture the pulses it sends back.
+    fn capture(&mut self) -> Result<Pulses, SensorError> {
+        self.wait_for_interval();
+
+        // Release the pin no matter how the read ends, including errors and panics,
+        // so that it isn't left driving the data line.
+        let pin = ReleaseGuard(&mut self.pin);
+        prepare_for_read(&mut *pin.0, self.wake_high, self.start_low, self.start_high);
+        let validate = self.timing.as_ref().filter(|_| self.validate_response);
+        Pulses::from_data_pin(&*pin.0, self.response_max_cycles, self.bit_max_cycles, validate)
+    }
+}
+
+impl<P: DataPin> Drop for DHT22Sensor<P> {
+    fn drop(&mut self) {
+        self.pin.release();
+    }
+}
+
+/// Release a pin when dropped, returning it to input mode.
+struct ReleaseGuard<'a, P: DataPin>(&'a mut P);
+
+impl<P: DataPin> Drop for ReleaseGuard<'_, P> {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+fn prepare_for_read<P: DataPin>(pin: &mut P, wake_high: Duration, start_low: Duration, start_high: Duration) {
+    // https://cdn-shop.adafruit.com/datasheets/Digital+humidity+and+temperature+sensor+AM2302.pdf
+    // Host needs to set the sensor:
+    // * high to start the read process, waking the sensor up from low-power mode
+    // * low for at least 1ms to ensure the sensor detected the start of this process
+    // * high for 20-40us to then wait for the sensor's response
+    pin.set_mode(PinMode::Output);
+    pin.set_high();
+    thread::sleep(wake_high);
+    pin.set_low();
+    thread::sleep(start_low);
+    pin.set_high();
+    thread::sleep(start_high);
+    pin.set_mode(PinMode::Input);
+}
+
+impl<P> Sensor for DHT22Sensor<P>
+where
+    P: DataPin + Send + 'static,
+{
+    fn read(&mut self) -> Result<Measurement, SensorError> {
+        DHT22Sensor::read(self).map(Measurement::from)
+    }
+
+    fn read_with_timing(&mut self, timing: PulseTiming) -> Result<Measurement, SensorError> {
+        DHT22Sensor::read_with_timing(self, timing).map(Measurement::from)
+    }
+
+    fn ranges(&self) -> SensorRanges {
+        RANGES
+    }
+
+    fn last_raw(&self) -> Option<RawReading> {
+        self.last_raw
+    }
+
+    fn last_pulses(&self) -> Option<PulseStats> {
+        self.last_pulses
+    }
+
+    fn reset(&mut self) {
+        if self.calibrate_timing {
+            self.calibrate_timing();
+        }
+    }
+
+    fn cycles_per_us(&self) -> Option<f64> {
+        self.timing.map(|t| t.cycles_per_us())
+    }
 }
 
-impl Debug for DHT22Sensor {
+impl<P: DataPin> Debug for DHT22Sensor<P> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("DHT22Sensor").field("pin", &self.pin.pin()).finish()
     }
@@ -243,27 +886,378 @@ impl Debug for DHT22Sensor {
 
 #[cfg(test)]
 mod test {
-    use super::{DHT22Sensor, Pulses, Reading, DATA_SIZE};
-    use crate::sensor::core::{Humidity, SensorError, SensorErrorKind, TemperatureCelsius};
-    use crate::sensor::test::{MockDataPin, NopDataPin, TimeoutDataPin};
+    use super::{
+        DHT22Sensor, Pulses, Reading, TimingCalibration, DATA_SIZE, DHT_BIT_MAX_COUNT, DHT_PULSES,
+        DHT_RESPONSE_MAX_COUNT,
+    };
+    use crate::device::PulseTiming;
+    use crate::sensor::core::{
+        Humidity, Level, PinMode, PulseStats, RawReading, Sensor, SensorError, SensorErrorKind, TemperatureCelsius,
+    };
+    use crate::sensor::test::{
+        CountingTimeoutDataPin, MockDataPin, NopDataPin, PinEvent, RecordingDataPin, StallDataPin, TimeoutDataPin,
+    };
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, Instant};
+
+    /// Example data, from the datasheet: https://cdn-shop.adafruit.com/datasheets/Digital+humidity+and+temperature+sensor+AM2302.pdf
+    const DATASHEET_BYTES: [u8; DATA_SIZE] = [0b0000_0010, 0b1000_1100, 0b0000_0001, 0b0101_1111, 0b1110_1110];
+
+    /// Pulses for `bytes` with low pulses of `low` cycles and high pulses of the length
+    /// returned by `high` for the index and value of each bit.
+    fn pulses_for<F>(bytes: [u8; DATA_SIZE], low: u32, high: F) -> Pulses
+    where
+        F: Fn(usize, bool) -> u32,
+    {
+        let mut counts = [0; DHT_PULSES * 2];
+        for i in 0..DHT_PULSES {
+            let bit = bytes[i / 8] & (0b1000_0000 >> (i % 8)) != 0;
+            counts[i * 2] = low;
+            counts[i * 2 + 1] = high(i, bit);
+        }
+
+        Pulses { counts }
+    }
 
     #[test]
     fn test_pulses_timeout() {
         let pin = TimeoutDataPin;
-        let res = Pulses::from_data_pin(&pin);
+        let res = Pulses::from_data_pin(&pin, DHT_RESPONSE_MAX_COUNT, DHT_BIT_MAX_COUNT, None);
 
-        assert!(res.is_err());
-        assert_eq!(SensorErrorKind::ReadTimeout, res.unwrap_err().kind());
+        let err = res.unwrap_err();
+        assert_eq!(SensorErrorKind::ReadTimeout, err.kind());
+        assert!(matches!(
+            err,
+            SensorError::PulseTimeout {
+                bit: 0,
+                phase: Level::Low
+            }
+        ));
+        assert_eq!(
+            "timeout waiting for low pulse capture of sensor response",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_pulses_timeout_data_bit() {
+        let pin = MockDataPin::new([0b0000_0010, 0b1000_1100, 0b0000_0001, 0b0101_1111, 0b1110_1110]).stall_at(37);
+        let res = Pulses::from_data_pin(&pin, DHT_RESPONSE_MAX_COUNT, DHT_BIT_MAX_COUNT, None);
+
+        let err = res.unwrap_err();
+        assert_eq!(SensorErrorKind::ReadTimeout, err.kind());
+        assert!(matches!(
+            err,
+            SensorError::PulseTimeout {
+                bit: 38,
+                phase: Level::High
+            }
+        ));
+        assert_eq!(
+            "timeout waiting for high pulse capture of bit 38 of 40",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_pulses_timeout_limits() {
+        // Transitions 0 and 1 are the response to the start signal, the rest are data bits
+        for (stall_at, bit, phase, limit) in [
+            (0, 0, Level::Low, 100),
+            (1, 0, Level::High, 100),
+            (2, 1, Level::Low, 20),
+            (3, 1, Level::High, 20),
+            (81, 40, Level::High, 20),
+        ] {
+            let pin = StallDataPin::new(stall_at);
+            let stalled_max = pin.stalled_max();
+            let err = Pulses::from_data_pin(&pin, 100, 20, None).unwrap_err();
+
+            assert!(
+                matches!(err, SensorError::PulseTimeout { bit: b, phase: p } if b == bit && p == phase),
+                "stall at {}: {:?}",
+                stall_at,
+                err
+            );
+            assert_eq!(limit, stalled_max.load(Ordering::SeqCst), "stall at {}", stall_at);
+        }
     }
 
     #[test]
     fn test_pulses_nop() {
         let pin = NopDataPin;
-        let res = Pulses::from_data_pin(&pin);
+        let res = Pulses::from_data_pin(&pin, DHT_RESPONSE_MAX_COUNT, DHT_BIT_MAX_COUNT, None);
 
         assert!(res.is_ok());
     }
 
+    #[test]
+    fn test_pulses_response_valid() {
+        // 640 cycles at 8 cycles per microsecond is the 80us from the datasheet
+        let pin = MockDataPin::new(DATASHEET_BYTES).response(640, 640);
+        let timing = TimingCalibration::new(8.0);
+        let pulses = Pulses::from_data_pin(&pin, DHT_RESPONSE_MAX_COUNT, DHT_BIT_MAX_COUNT, Some(&timing)).unwrap();
+
+        assert_eq!(DATASHEET_BYTES, Reading::decode(&pulses, Some(&timing)).unwrap());
+    }
+
+    #[test]
+    fn test_pulses_response_absent() {
+        // Line floating high: the low pulse ends immediately and the high one never does
+        let pin = MockDataPin::new(DATASHEET_BYTES).response(0, u32::MAX);
+        let timing = TimingCalibration::new(8.0);
+        let err = Pulses::from_data_pin(&pin, DHT_RESPONSE_MAX_COUNT, DHT_BIT_MAX_COUNT, Some(&timing)).unwrap_err();
+
+        assert_eq!(SensorErrorKind::NoResponse, err.kind());
+        assert!(matches!(
+            err,
+            SensorError::NoResponse {
+                low_us: Some(l),
+                high_us: None,
+            } if l == 0.0
+        ));
+        assert_eq!(
+            "no valid response from sensor to start signal: low 0.0us, high timeout",
+            err.to_string()
+        );
+
+        // Without validation, this is only a timeout
+        let pin = MockDataPin::new(DATASHEET_BYTES).response(0, u32::MAX);
+        let err = Pulses::from_data_pin(&pin, DHT_RESPONSE_MAX_COUNT, DHT_BIT_MAX_COUNT, None).unwrap_err();
+        assert!(matches!(
+            err,
+            SensorError::PulseTimeout {
+                bit: 0,
+                phase: Level::High
+            }
+        ));
+    }
+
+    #[test]
+    fn test_pulses_response_truncated() {
+        let pin = MockDataPin::new(DATASHEET_BYTES).response(640, 100);
+        let timing = TimingCalibration::new(8.0);
+        let err = Pulses::from_data_pin(&pin, DHT_RESPONSE_MAX_COUNT, DHT_BIT_MAX_COUNT, Some(&timing)).unwrap_err();
+
+        assert_eq!(SensorErrorKind::NoResponse, err.kind());
+        assert_eq!(
+            "no valid response from sensor to start signal: low 80.0us, high 12.5us",
+            err.to_string()
+        );
+
+        // Too long is as invalid as too short
+        let pin = MockDataPin::new(DATASHEET_BYTES).response(1000, 640);
+        let err = Pulses::from_data_pin(&pin, DHT_RESPONSE_MAX_COUNT, DHT_BIT_MAX_COUNT, Some(&timing)).unwrap_err();
+        assert_eq!(
+            "no valid response from sensor to start signal: low 125.0us, high 80.0us",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_timing_calibration_cycles() {
+        let timing = TimingCalibration::new(8.0);
+        assert_eq!(224, timing.cycles(28.0));
+        assert_eq!(560, timing.cycles(70.0));
+        assert_eq!(392, timing.bit_threshold());
+
+        let timing = TimingCalibration::new(1.5);
+        assert_eq!(74, timing.bit_threshold());
+
+        let timing = TimingCalibration::new(f64::MAX);
+        assert_eq!(u32::MAX, timing.bit_threshold());
+    }
+
+    #[test]
+    fn test_timing_calibration_measure() {
+        // Pin that's always high, checks are counted until the duration elapses
+        let timing = TimingCalibration::measure(&mut TimeoutDataPin, Duration::from_millis(1)).unwrap();
+        assert!(timing.cycles_per_us() > 0.0);
+
+        // Pin that never reads high, nothing can be counted
+        assert_eq!(
+            None,
+            TimingCalibration::measure(&mut NopDataPin, Duration::from_millis(1))
+        );
+    }
+
+    #[test]
+    fn test_timing_calibration_measure_releases_pin() {
+        let mut pin = RecordingDataPin::default();
+        let events = pin.events();
+        let _ = TimingCalibration::measure(&mut pin, Duration::from_millis(1));
+
+        let events = events.lock().unwrap();
+        assert_eq!(
+            vec![
+                PinEvent::Mode(PinMode::Output),
+                PinEvent::High,
+                PinEvent::Mode(PinMode::Input)
+            ],
+            events.iter().map(|(e, _)| *e).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_reading_decode_large_counts() {
+        // The sum of these doesn't fit in a u32 but their average does
+        let mut pulses = Pulses {
+            counts: [u32::MAX; DHT_PULSES * 2],
+        };
+        assert_eq!([0xFF; DATA_SIZE], Reading::decode(&pulses, None).unwrap());
+
+        // High pulses shorter than the average low pulse are 0 bits
+        for high in pulses.counts.iter_mut().skip(1).step_by(2) {
+            *high = u32::MAX - 1;
+        }
+        assert_eq!([0; DATA_SIZE], Reading::decode(&pulses, None).unwrap());
+    }
+
+    #[test]
+    fn test_reading_threshold_empty() {
+        let err = Reading::threshold([].iter(), None).unwrap_err();
+        assert_eq!(SensorErrorKind::FrameError, err.kind());
+        assert_eq!("only 0 of 40 low pulses captured", err.to_string());
+
+        let err = Reading::classify([].iter(), 400).unwrap_err();
+        assert_eq!(SensorErrorKind::FrameError, err.kind());
+        assert_eq!("only 0 of 40 high pulses captured", err.to_string());
+    }
+
+    #[test]
+    fn test_reading_threshold_short() {
+        let counts = [400; DHT_PULSES - 1];
+        let timing = TimingCalibration::new(8.0);
+
+        let err = Reading::threshold(counts.iter(), None).unwrap_err();
+        assert_eq!(SensorErrorKind::FrameError, err.kind());
+        assert_eq!("only 39 of 40 low pulses captured", err.to_string());
+
+        // Truncated captures are rejected even when low pulses aren't used for the threshold
+        let err = Reading::threshold(counts.iter(), Some(&timing)).unwrap_err();
+        assert_eq!(SensorErrorKind::FrameError, err.kind());
+
+        let err = Reading::classify(counts.iter(), 400).unwrap_err();
+        assert_eq!(SensorErrorKind::FrameError, err.kind());
+        assert_eq!("only 39 of 40 high pulses captured", err.to_string());
+    }
+
+    #[test]
+    fn test_reading_threshold_zero() {
+        let counts = [0; DHT_PULSES];
+        let err = Reading::threshold(counts.iter(), None).unwrap_err();
+        assert_eq!(SensorErrorKind::FrameError, err.kind());
+        assert_eq!("threshold between 0 and 1 bits is zero cycles", err.to_string());
+
+        // Calibration too slow for a single cycle during the high pulse of a bit
+        let timing = TimingCalibration::new(0.001);
+        let err = Reading::threshold([400; DHT_PULSES].iter(), Some(&timing)).unwrap_err();
+        assert_eq!(SensorErrorKind::FrameError, err.kind());
+
+        // An all-zero capture is a frame error rather than all 1 bits and a bad checksum
+        let pulses = Pulses {
+            counts: [0; DHT_PULSES * 2],
+        };
+        let err = Reading::decode(&pulses, None).unwrap_err();
+        assert_eq!(SensorErrorKind::FrameError, err.kind());
+    }
+
+    #[test]
+    fn test_reading_classify_extra_pulses() {
+        let counts = [600; DHT_PULSES + 8];
+        assert_eq!([0xFF; DATA_SIZE], Reading::classify(counts.iter(), 400).unwrap());
+    }
+
+    #[test]
+    fn test_reading_check_strict_valid() {
+        // Pulses vary a little from bit to bit in practice
+        let pulses = pulses_for(DATASHEET_BYTES, 400, |i, bit| {
+            let jitter = (i % 7) as u32 * 5;
+            if bit {
+                580 + jitter
+            } else {
+                190 + jitter
+            }
+        });
+        let bytes = Reading::decode(&pulses, None).unwrap();
+        let reading = Reading::from_bytes(bytes).unwrap();
+
+        assert!(reading.check_strict(&pulses, None).is_ok());
+    }
+
+    #[test]
+    fn test_reading_check_strict_out_of_range() {
+        // 100.1% humidity with a valid checksum
+        let bytes = [0x03, 0xE9, 0x00, 0xD7, 0xC3];
+        let pulses = pulses_for(bytes, 400, |_, bit| if bit { 600 } else { 200 });
+        let reading = Reading::from_bytes(Reading::decode(&pulses, None).unwrap()).unwrap();
+
+        let err = reading.check_strict(&pulses, None).unwrap_err();
+        assert_eq!(SensorErrorKind::FrameError, err.kind());
+        assert_eq!(
+            "decoded 21.5 degrees and 100.1% humidity, outside of the range of the sensor",
+            err.to_string()
+        );
+
+        // -40.1 degrees
+        let bytes = [0x01, 0xC2, 0x81, 0x91, 0xD5];
+        let pulses = pulses_for(bytes, 400, |_, bit| if bit { 600 } else { 200 });
+        let reading = Reading::from_bytes(Reading::decode(&pulses, None).unwrap()).unwrap();
+
+        let err = reading.check_strict(&pulses, None).unwrap_err();
+        assert_eq!(SensorErrorKind::FrameError, err.kind());
+    }
+
+    #[test]
+    fn test_reading_check_strict_ambiguous() {
+        // Every pulse decodes to the right bit but they're spread out between the lengths
+        // of 0 and 1 bits rather than clustered around them
+        let pulses = pulses_for(DATASHEET_BYTES, 400, |i, bit| {
+            let spread = (i % 10) as u32 * 20;
+            if bit {
+                410 + spread
+            } else {
+                210 + spread
+            }
+        });
+        let bytes = Reading::decode(&pulses, None).unwrap();
+        let reading = Reading::from_bytes(bytes).unwrap();
+
+        let err = reading.check_strict(&pulses, None).unwrap_err();
+        assert_eq!(SensorErrorKind::FrameError, err.kind());
+        assert!(
+            err.to_string().starts_with("high pulses aren't clearly 0 or 1 bits"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_reading_check_strict_single_cluster() {
+        // All zero bytes have a valid checksum but every bit is the same
+        let pulses = pulses_for([0; DATA_SIZE], 400, |_, _| 200);
+        let reading = Reading::from_bytes(Reading::decode(&pulses, None).unwrap()).unwrap();
+
+        let err = reading.check_strict(&pulses, None).unwrap_err();
+        assert_eq!(SensorErrorKind::FrameError, err.kind());
+        assert_eq!("high pulses are all the same length, not 0 and 1 bits", err.to_string());
+    }
+
+    #[test]
+    fn test_reading_check_strict_threshold() {
+        // Clean pulses, but calibrated timing puts the threshold inside the cluster of 1 bits
+        let pulses = pulses_for(DATASHEET_BYTES, 400, |i, bit| if bit { 600 + i as u32 } else { 200 });
+        let reading = Reading { bytes: DATASHEET_BYTES };
+        let timing = TimingCalibration::new(12.7);
+
+        let err = reading.check_strict(&pulses, Some(&timing)).unwrap_err();
+        assert_eq!(SensorErrorKind::FrameError, err.kind());
+        assert_eq!(
+            "threshold of 622 cycles doesn't separate 0 bits (200-200) from 1 bits (606-638)",
+            err.to_string()
+        );
+    }
+
     #[test]
     fn test_reading_checksum_valid() {
         // Example data, from the datasheet: https://cdn-shop.adafruit.com/datasheets/Digital+humidity+and+temperature+sensor+AM2302.pdf
@@ -301,6 +1295,9 @@ mod test {
             SensorError::KindMsgCause(kind, msg, cause) => {
                 panic!("Unexpected error. kind: {:?}, message: {}, cause: {}", kind, msg, cause);
             }
+            e @ (SensorError::PulseTimeout { .. } | SensorError::NoResponse { .. }) => {
+                panic!("Unexpected error: {}", e);
+            }
         }
     }
 
@@ -355,6 +1352,135 @@ mod test {
         assert_eq!(Humidity::from(65.2), h);
     }
 
+    #[test]
+    fn test_dht22_sensor_read_calibrated() {
+        let bytes = [0b0000_0010, 0b1000_1100, 0b0000_0001, 0b0101_1111, 0b1110_1110];
+
+        // Mock pins hold the pin high for 200 cycles for a 0 bit and 600 cycles for a 1
+        // bit, so the threshold of 392 cycles at 8 cycles per microsecond separates them.
+        let mut sensor = DHT22Sensor::builder(MockDataPin::new(bytes))
+            .timing(TimingCalibration::new(8.0))
+            .build();
+        let (t, h) = sensor.read().unwrap();
+
+        assert_eq!(TemperatureCelsius::from(35.1), t);
+        assert_eq!(Humidity::from(65.2), h);
+        assert_eq!(Some(8.0), Sensor::cycles_per_us(&sensor));
+
+        // At 1 cycle per microsecond, the threshold is 49 cycles so every bit is a 1
+        let mut sensor = DHT22Sensor::builder(MockDataPin::new(bytes))
+            .timing(TimingCalibration::new(1.0))
+            .build();
+        let res = sensor.read();
+
+        assert_eq!(SensorErrorKind::Checksum, res.unwrap_err().kind());
+        assert_eq!(
+            Some(RawReading {
+                bytes: [0xFF; DATA_SIZE]
+            }),
+            Sensor::last_raw(&sensor)
+        );
+    }
+
+    #[test]
+    fn test_dht22_sensor_read_with_timing() {
+        let bytes = [0b0000_0010, 0b1000_1100, 0b0000_0001, 0b0101_1111, 0b1110_1110];
+
+        // Reads of this sensor fail since every bit is a 1 at 1 cycle per microsecond but
+        // decoding the same pulses relative to each other works.
+        let mut sensor = DHT22Sensor::builder(MockDataPin::new(bytes))
+            .timing(TimingCalibration::new(1.0))
+            .build();
+        let (t, h) = sensor.read_with_timing(PulseTiming::Relative).unwrap();
+
+        assert_eq!(TemperatureCelsius::from(35.1), t);
+        assert_eq!(Humidity::from(65.2), h);
+        assert_eq!(None, Sensor::last_raw(&sensor));
+        assert_eq!(None, Sensor::last_pulses(&sensor));
+
+        let mut sensor = DHT22Sensor::builder(MockDataPin::new(bytes))
+            .timing(TimingCalibration::new(1.0))
+            .build();
+        let res = sensor.read_with_timing(PulseTiming::Calibrated);
+
+        assert_eq!(SensorErrorKind::Checksum, res.unwrap_err().kind());
+        assert_eq!(None, Sensor::last_raw(&sensor));
+    }
+
+    #[test]
+    fn test_dht22_sensor_read_with_timing_uncalibrated() {
+        // Pins that can't be calibrated can't be read with calibrated timing
+        let mut sensor = DHT22Sensor::from_pin(NopDataPin);
+        let res = sensor.read_with_timing(PulseTiming::Calibrated);
+
+        assert_eq!(SensorErrorKind::Initialization, res.unwrap_err().kind());
+        assert_eq!(None, sensor.timing());
+    }
+
+    #[test]
+    fn test_dht22_sensor_validate_response() {
+        let mut sensor = DHT22Sensor::builder(MockDataPin::new(DATASHEET_BYTES).response(640, 100))
+            .timing(TimingCalibration::new(8.0))
+            .validate_response(true)
+            .build();
+        let res = sensor.read();
+
+        assert_eq!(SensorErrorKind::NoResponse, res.unwrap_err().kind());
+        assert_eq!(None, Sensor::last_raw(&sensor));
+
+        // The response can't be validated without timing calibration
+        let mut sensor = DHT22Sensor::builder(MockDataPin::new(DATASHEET_BYTES).response(640, 100))
+            .validate_response(true)
+            .build();
+        assert!(sensor.read().is_ok());
+    }
+
+    #[test]
+    fn test_dht22_sensor_calibrate_timing() {
+        let mut sensor = DHT22Sensor::builder(TimeoutDataPin).calibrate_timing(true).build();
+        let first = sensor.timing().unwrap();
+        assert!(first.cycles_per_us() > 0.0);
+
+        // Resetting the sensor measures timing again
+        Sensor::reset(&mut sensor);
+        assert!(sensor.timing().is_some());
+
+        // Calibration fails for pins that don't stay high, falling back to the timing of each read
+        let sensor = DHT22Sensor::builder(NopDataPin).calibrate_timing(true).build();
+        assert_eq!(None, sensor.timing());
+        assert_eq!(None, Sensor::cycles_per_us(&sensor));
+    }
+
+    #[test]
+    fn test_dht22_sensor_reset_without_calibration() {
+        let mut sensor = DHT22Sensor::builder(TimeoutDataPin)
+            .timing(TimingCalibration::new(8.0))
+            .build();
+
+        Sensor::reset(&mut sensor);
+        assert_eq!(Some(TimingCalibration::new(8.0)), sensor.timing());
+    }
+
+    #[test]
+    fn test_dht22_sensor_read_strict_frames() {
+        let pin = MockDataPin::new(DATASHEET_BYTES);
+        let mut sensor = DHT22Sensor::builder(pin).strict_frames(true).build();
+        let (t, h) = sensor.read().unwrap();
+
+        assert_eq!(TemperatureCelsius::from(35.1), t);
+        assert_eq!(Humidity::from(65.2), h);
+
+        // Valid checksum but nothing else about it is plausible
+        let pin = MockDataPin::new([0; DATA_SIZE]);
+        let mut sensor = DHT22Sensor::builder(pin).strict_frames(true).build();
+        assert_eq!(SensorErrorKind::FrameError, sensor.read().unwrap_err().kind());
+
+        // Not checked unless enabled
+        let pin = MockDataPin::new([0; DATA_SIZE]);
+        let mut sensor = DHT22Sensor::from_pin(pin);
+        assert!(sensor.read().is_ok());
+    }
+
     #[test]
     fn test_dht22_sensor_read_invalid() {
         // Example data, from the datasheet: https://cdn-shop.adafruit.com/datasheets/Digital+humidity+and+temperature+sensor+AM2302.pdf
@@ -372,4 +1498,170 @@ mod test {
         assert!(res.is_err());
         assert_eq!(SensorErrorKind::Checksum, res.unwrap_err().kind());
     }
+
+    #[test]
+    fn test_dht22_sensor_last_raw() {
+        let mut bytes = [0; DATA_SIZE];
+        bytes[0] = 0b0000_0010;
+        bytes[1] = 0b1000_1100;
+        bytes[2] = 0b1000_0001; // negative temperature
+        bytes[3] = 0b0101_1111;
+        bytes[4] = 0b0000_0000; // checksum, invalid
+
+        let mut sensor = DHT22Sensor::from_pin(MockDataPin::new(bytes));
+        assert_eq!(None, Sensor::last_raw(&sensor));
+
+        // Bytes are available even though the checksum is invalid
+        assert!(sensor.read().is_err());
+        let raw = Sensor::last_raw(&sensor).unwrap();
+        assert_eq!(RawReading { bytes }, raw);
+        assert_eq!(652, raw.humidity_word());
+        assert_eq!(0x815F, raw.temperature_word());
+    }
+
+    #[test]
+    fn test_dht22_sensor_last_raw_timeout() {
+        let mut sensor = DHT22Sensor::from_pin(TimeoutDataPin);
+        assert!(sensor.read().is_err());
+        assert_eq!(None, Sensor::last_raw(&sensor));
+    }
+
+    #[test]
+    fn test_dht22_sensor_last_pulses() {
+        let mut sensor = DHT22Sensor::from_pin(MockDataPin::new(DATASHEET_BYTES));
+        assert_eq!(None, Sensor::last_pulses(&sensor));
+
+        // 17 of the 40 bits are 1 bits, 600 cycles high instead of 200
+        assert!(sensor.read().is_ok());
+        assert_eq!(
+            Some(PulseStats {
+                mean_low: 400.0,
+                mean_high: 370.0,
+            }),
+            Sensor::last_pulses(&sensor)
+        );
+    }
+
+    #[test]
+    fn test_dht22_sensor_last_pulses_partial() {
+        // Pulses that were captured before the read timed out aren't used
+        let mut sensor = DHT22Sensor::from_pin(MockDataPin::new(DATASHEET_BYTES).stall_at(20));
+        assert!(sensor.read().is_err());
+        assert_eq!(None, Sensor::last_pulses(&sensor));
+    }
+
+    #[test]
+    fn test_dht22_sensor_builder_start_timings() {
+        let pin = RecordingDataPin::default();
+        let events = pin.events();
+        let mut sensor = DHT22Sensor::builder(pin)
+            .wake_high_ms(5)
+            .start_low_ms(40)
+            .start_high_us(100)
+            .build();
+        let _ = sensor.read();
+
+        let events = events.lock().unwrap();
+        let low = events.iter().find(|(e, _)| *e == PinEvent::Low).unwrap().1;
+        let high = events.iter().filter(|(e, _)| *e == PinEvent::High).nth(1).unwrap().1;
+        let held_low = high - low;
+
+        assert!(held_low >= Duration::from_millis(40), "held low for {:?}", held_low);
+        assert!(held_low < Duration::from_millis(60), "held low for {:?}", held_low);
+    }
+
+    #[test]
+    fn test_dht22_sensor_builder_default_timings() {
+        let pin = RecordingDataPin::default();
+        let events = pin.events();
+        let mut sensor = DHT22Sensor::from_pin(pin);
+        let _ = sensor.read();
+
+        let events = events.lock().unwrap();
+        let low = events.iter().find(|(e, _)| *e == PinEvent::Low).unwrap().1;
+        let high = events.iter().filter(|(e, _)| *e == PinEvent::High).nth(1).unwrap().1;
+        let held_low = high - low;
+
+        assert!(held_low >= Duration::from_millis(20), "held low for {:?}", held_low);
+        assert!(held_low < Duration::from_millis(40), "held low for {:?}", held_low);
+    }
+
+    #[test]
+    fn test_dht22_sensor_builder_max_cycles() {
+        let pin = CountingTimeoutDataPin::default();
+        let checks = pin.checks();
+        let mut sensor = DHT22Sensor::builder(pin).max_cycles(100).build();
+        let res = sensor.read();
+
+        assert_eq!(SensorErrorKind::ReadTimeout, res.unwrap_err().kind());
+        assert_eq!(100, checks.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_dht22_sensor_builder_response_bit_max_cycles() {
+        let build = |stall_at| {
+            let pin = StallDataPin::new(stall_at);
+            let stalled_max = pin.stalled_max();
+            let sensor = DHT22Sensor::builder(pin)
+                .wake_high_ms(0)
+                .start_low_ms(0)
+                .start_high_us(0)
+                .response_max_cycles(5_000)
+                .bit_max_cycles(50)
+                .build();
+            (sensor, stalled_max)
+        };
+
+        let (mut sensor, stalled_max) = build(1);
+        assert_eq!(SensorErrorKind::ReadTimeout, sensor.read().unwrap_err().kind());
+        assert_eq!(5_000, stalled_max.load(Ordering::SeqCst));
+
+        let (mut sensor, stalled_max) = build(30);
+        assert_eq!(SensorErrorKind::ReadTimeout, sensor.read().unwrap_err().kind());
+        assert_eq!(50, stalled_max.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_dht22_sensor_builder_min_read_interval() {
+        let mut sensor = DHT22Sensor::builder(NopDataPin)
+            .wake_high_ms(0)
+            .start_low_ms(0)
+            .start_high_us(0)
+            .min_read_interval(Duration::from_millis(50))
+            .build();
+
+        let start = Instant::now();
+        let _ = sensor.read();
+        let _ = sensor.read();
+
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_dht22_sensor_releases_pin_after_timeout() {
+        let pin = RecordingDataPin::stuck_low();
+        let events = pin.events();
+        let mut sensor = DHT22Sensor::builder(pin).wake_high_ms(0).start_low_ms(0).build();
+
+        let res = sensor.read();
+        assert_eq!(SensorErrorKind::ReadTimeout, res.unwrap_err().kind());
+
+        let events = events.lock().unwrap();
+        assert_eq!(PinEvent::Mode(PinMode::Input), events.last().unwrap().0);
+    }
+
+    #[test]
+    fn test_dht22_sensor_releases_pin_on_drop() {
+        let pin = RecordingDataPin::default();
+        let events = pin.events();
+        let sensor = DHT22Sensor::from_pin(pin);
+
+        drop(sensor);
+
+        let events = events.lock().unwrap();
+        assert_eq!(
+            vec![PinEvent::Mode(PinMode::Input)],
+            events.iter().map(|(e, _)| *e).collect::<Vec<_>>()
+        );
+    }
 }
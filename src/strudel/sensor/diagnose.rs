@@ -0,0 +1,378 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! Startup checks for common problems accessing GPIO pins.
+
+use crate::sensor::core::{SensorError, SensorErrorKind};
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_SYSFS_GPIO: &str = "/sys/class/gpio";
+const DEFAULT_DEBUGFS_GPIO: &str = "/sys/kernel/debug/gpio";
+const DEFAULT_GPIOMEM: &str = "/dev/gpiomem";
+const DEFAULT_CHIP: &str = "gpiochip0";
+
+/// Check for problems that would prevent the sensor from being read using the given
+/// BCM GPIO pin: the pin being held by another process or `/dev/gpiomem` not being
+/// accessible.
+///
+/// Another process using the pin doesn't cause opening the pin to fail, only reads of
+/// the sensor to time out, so this should be called before opening the pin.
+pub fn diagnose_pin(bcm_pin: u8) -> Result<(), SensorError> {
+    PinDiagnostics::new().check(bcm_pin)
+}
+
+/// Configurable checks for problems accessing a GPIO pin.
+///
+/// By default, checks if the pin is exported via sysfs, requested by another process
+/// via the GPIO character device (when debugfs is readable), and if `/dev/gpiomem` can
+/// be opened.
+#[derive(Debug, Clone)]
+pub struct PinDiagnostics {
+    sysfs_gpio: PathBuf,
+    debugfs_gpio: PathBuf,
+    gpiomem: Option<PathBuf>,
+    chip: String,
+}
+
+impl PinDiagnostics {
+    pub fn new() -> Self {
+        Self {
+            sysfs_gpio: PathBuf::from(DEFAULT_SYSFS_GPIO),
+            debugfs_gpio: PathBuf::from(DEFAULT_DEBUGFS_GPIO),
+            gpiomem: Some(PathBuf::from(DEFAULT_GPIOMEM)),
+            chip: DEFAULT_CHIP.to_owned(),
+        }
+    }
+
+    /// Directory of the sysfs GPIO interface, `/sys/class/gpio` by default.
+    pub fn sysfs_gpio<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.sysfs_gpio = path.into();
+        self
+    }
+
+    /// Debugfs file listing requested GPIO lines, `/sys/kernel/debug/gpio` by default.
+    pub fn debugfs_gpio<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.debugfs_gpio = path.into();
+        self
+    }
+
+    /// GPIO memory device to check permissions of, or `None` to skip the check when
+    /// not using `/dev/gpiomem` to access pins. `/dev/gpiomem` by default.
+    pub fn gpiomem(mut self, path: Option<PathBuf>) -> Self {
+        self.gpiomem = path;
+        self
+    }
+
+    /// Name of the GPIO chip the pin belongs to, `gpiochip0` by default.
+    pub fn chip<S: Into<String>>(mut self, chip: S) -> Self {
+        self.chip = chip.into();
+        self
+    }
+
+    /// Run all checks for `pin`, returning an error describing the first problem found.
+    pub fn check(&self, pin: u8) -> Result<(), SensorError> {
+        // Debugfs is usually only readable by root so skip the check if it's missing
+        // or can't be read, it's only used to find more conflicts.
+        let debugfs = fs::read_to_string(&self.debugfs_gpio).ok();
+        let lines = debugfs.as_deref().map(|s| parse_debugfs(s, &self.chip));
+        let base = lines.as_ref().and_then(|l| l.base);
+
+        if let Some(path) = sysfs_export(&self.sysfs_gpio, pin, base) {
+            return Err(SensorError::initialization(format!(
+                "pin {} is held by another process (exported via sysfs at {})",
+                pin,
+                path.display()
+            )));
+        }
+
+        if let Some(consumer) = lines.as_ref().and_then(|l| l.consumer(pin)) {
+            return Err(SensorError::initialization(format!(
+                "pin {} is held by another process (requested by '{}')",
+                pin, consumer
+            )));
+        }
+
+        if let Some(path) = &self.gpiomem {
+            if let Err(e) = OpenOptions::new().read(true).write(true).open(path) {
+                return Err(gpiomem_error(path, e));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for PinDiagnostics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn gpiomem_error(path: &Path, e: io::Error) -> SensorError {
+    let msg = match e.kind() {
+        io::ErrorKind::PermissionDenied => format!(
+            "permission denied opening {}, run as root or add user to the gpio group",
+            path.display()
+        ),
+        io::ErrorKind::NotFound => format!(
+            "{} does not exist, use the cdev GPIO backend on boards other than a Raspberry PI",
+            path.display()
+        ),
+        _ => format!("unable to open {}", path.display()),
+    };
+
+    SensorError::with_cause(SensorErrorKind::Initialization, msg, e)
+}
+
+/// Return the path of the sysfs export of `pin` if it exists. Newer kernels number
+/// pins globally so the base of the chip is added to the pin number when known.
+fn sysfs_export(root: &Path, pin: u8, base: Option<u32>) -> Option<PathBuf> {
+    let mut candidates = vec![u32::from(pin)];
+    if let Some(b) = base.filter(|b| *b != 0) {
+        candidates.push(b + u32::from(pin));
+    }
+
+    candidates
+        .into_iter()
+        .map(|n| root.join(format!("gpio{}", n)))
+        .find(|p| p.exists())
+}
+
+/// Lines of a single GPIO chip from the debugfs GPIO file.
+#[derive(Debug, Default, PartialEq)]
+struct DebugfsLines {
+    base: Option<u32>,
+    used: Vec<(u32, String)>,
+}
+
+impl DebugfsLines {
+    /// Consumer of the line with the given offset within the chip, if it's in use
+    fn consumer(&self, offset: u8) -> Option<&str> {
+        let global = self.base.unwrap_or(0) + u32::from(offset);
+        self.used.iter().find(|(n, _)| *n == global).map(|(_, c)| c.as_str())
+    }
+}
+
+/// Parse the lines of `chip` from the debugfs GPIO file, formatted like:
+///
+/// ```text
+/// gpiochip0: GPIOs 512-569, parent: platform/fe200000.gpio, pinctrl-bcm2711:
+///  gpio-529 (GPIO17              |sysfs               ) in  hi
+/// ```
+///
+/// Only lines with a consumer (after the `|`) are in use.
+fn parse_debugfs(contents: &str, chip: &str) -> DebugfsLines {
+    let mut out = DebugfsLines::default();
+    let mut in_chip = false;
+
+    for line in contents.lines() {
+        if let Some((name, rest)) = line.split_once(':').filter(|(n, _)| n.starts_with("gpiochip")) {
+            in_chip = name == chip;
+            if in_chip {
+                out.base = rest
+                    .trim()
+                    .strip_prefix("GPIOs ")
+                    .and_then(|r| r.split('-').next())
+                    .and_then(|n| n.parse().ok());
+            }
+            continue;
+        }
+
+        if !in_chip {
+            continue;
+        }
+
+        let line = line.trim_start();
+        let number = line
+            .strip_prefix("gpio-")
+            .and_then(|r| r.split_whitespace().next())
+            .and_then(|n| n.parse::<u32>().ok());
+        let consumer = line
+            .split_once('(')
+            .and_then(|(_, r)| r.split_once(')'))
+            .and_then(|(names, _)| names.split_once('|'))
+            .map(|(_, c)| c.trim())
+            .filter(|c| !c.is_empty());
+
+        if let (Some(n), Some(c)) = (number, consumer) {
+            out.used.push((n, c.to_owned()));
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{gpiomem_error, parse_debugfs, DebugfsLines, PinDiagnostics};
+    use crate::sensor::core::SensorErrorKind;
+    use std::fs;
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const DEBUGFS_NEW: &str = "gpiochip0: GPIOs 512-569, parent: platform/fe200000.gpio, pinctrl-bcm2711:
+ gpio-528 (GPIO16              )
+ gpio-529 (GPIO17              |pigpio              ) in  hi
+ gpio-530 (GPIO18              )
+
+gpiochip1: GPIOs 570-577, parent: platform/soc:firmware:gpio, raspberrypi-exp-gpio, can sleep:
+ gpio-571 (GPIO17              |led1                ) out hi ACTIVE LOW
+";
+
+    const DEBUGFS_OLD: &str = "gpiochip0: GPIOs 0-53, parent: platform/3f200000.gpio, pinctrl-bcm2835:
+ gpio-22  (                    |sysfs               ) in  lo
+";
+
+    /// Temporary directory with a fake sysfs layout, removed when dropped
+    struct FakeRoot {
+        path: PathBuf,
+    }
+
+    impl FakeRoot {
+        fn new() -> Self {
+            static COUNTER: AtomicUsize = AtomicUsize::new(0);
+            let path = std::env::temp_dir().join(format!(
+                "strudel-diagnose-{}-{}",
+                process::id(),
+                COUNTER.fetch_add(1, Ordering::SeqCst)
+            ));
+
+            fs::create_dir_all(path.join("sys/class/gpio")).unwrap();
+            fs::create_dir_all(path.join("sys/kernel/debug")).unwrap();
+            fs::create_dir_all(path.join("dev")).unwrap();
+            fs::write(path.join("dev/gpiomem"), "").unwrap();
+            Self { path }
+        }
+
+        fn join(&self, p: &str) -> PathBuf {
+            self.path.join(p)
+        }
+
+        fn diagnostics(&self) -> PinDiagnostics {
+            PinDiagnostics::new()
+                .sysfs_gpio(self.join("sys/class/gpio"))
+                .debugfs_gpio(self.join("sys/kernel/debug/gpio"))
+                .gpiomem(Some(self.join("dev/gpiomem")))
+        }
+    }
+
+    impl Drop for FakeRoot {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+
+    #[test]
+    fn test_parse_debugfs_new_kernel() {
+        let lines = parse_debugfs(DEBUGFS_NEW, "gpiochip0");
+        assert_eq!(
+            DebugfsLines {
+                base: Some(512),
+                used: vec![(529, "pigpio".to_owned())],
+            },
+            lines
+        );
+        assert_eq!(Some("pigpio"), lines.consumer(17));
+        assert_eq!(None, lines.consumer(16));
+    }
+
+    #[test]
+    fn test_parse_debugfs_old_kernel() {
+        let lines = parse_debugfs(DEBUGFS_OLD, "gpiochip0");
+        assert_eq!(Some(0), lines.base);
+        assert_eq!(Some("sysfs"), lines.consumer(22));
+    }
+
+    #[test]
+    fn test_parse_debugfs_other_chip() {
+        let lines = parse_debugfs(DEBUGFS_NEW, "gpiochip1");
+        assert_eq!(Some(570), lines.base);
+        assert_eq!(Some("led1"), lines.consumer(1));
+        assert_eq!(None, lines.consumer(17));
+    }
+
+    #[test]
+    fn test_check_no_conflicts() {
+        let root = FakeRoot::new();
+        assert!(root.diagnostics().check(17).is_ok());
+    }
+
+    #[test]
+    fn test_check_sysfs_export() {
+        let root = FakeRoot::new();
+        fs::create_dir(root.join("sys/class/gpio/gpio17")).unwrap();
+
+        let err = root.diagnostics().check(17).unwrap_err();
+        assert_eq!(SensorErrorKind::Initialization, err.kind());
+        assert!(err.to_string().contains("pin 17 is held by another process"));
+        assert!(root.diagnostics().check(18).is_ok());
+    }
+
+    #[test]
+    fn test_check_sysfs_export_global_number() {
+        let root = FakeRoot::new();
+        fs::write(
+            root.join("sys/kernel/debug/gpio"),
+            DEBUGFS_OLD.replace("0-53", "512-565"),
+        )
+        .unwrap();
+        fs::create_dir(root.join("sys/class/gpio/gpio529")).unwrap();
+
+        let err = root.diagnostics().check(17).unwrap_err();
+        assert!(err.to_string().contains("pin 17 is held by another process"));
+    }
+
+    #[test]
+    fn test_check_chardev_busy() {
+        let root = FakeRoot::new();
+        fs::write(root.join("sys/kernel/debug/gpio"), DEBUGFS_NEW).unwrap();
+
+        let err = root.diagnostics().check(17).unwrap_err();
+        assert_eq!(
+            "pin 17 is held by another process (requested by 'pigpio')",
+            err.to_string()
+        );
+        assert!(root.diagnostics().check(16).is_ok());
+    }
+
+    #[test]
+    fn test_check_gpiomem_missing() {
+        let root = FakeRoot::new();
+        fs::remove_file(root.join("dev/gpiomem")).unwrap();
+
+        let err = root.diagnostics().check(17).unwrap_err();
+        assert!(err.to_string().contains("does not exist"));
+        assert!(root.diagnostics().gpiomem(None).check(17).is_ok());
+    }
+
+    #[test]
+    fn test_gpiomem_error_permission_denied() {
+        let err = gpiomem_error(
+            Path::new("/dev/gpiomem"),
+            io::Error::from(io::ErrorKind::PermissionDenied),
+        );
+        assert_eq!(
+            "permission denied opening /dev/gpiomem, run as root or add user to the gpio group: permission denied",
+            err.to_string()
+        );
+    }
+}
@@ -0,0 +1,287 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+use crate::sensor::core::{Measurement, SensorError};
+use crate::sensor::worker::median;
+use std::collections::VecDeque;
+
+/// Default number of median absolute deviations a reading may be from the median.
+pub const DEFAULT_MAD_THRESHOLD: f64 = 5.0;
+
+/// Default number of consecutive rejected readings after which they're accepted.
+pub const DEFAULT_MAD_REACCEPT_AFTER: u32 = 3;
+
+/// Smallest median absolute deviation used, the resolution of the DHT22 in both degrees
+/// celsius and percent relative humidity. Without it, a sensor reporting the same value
+/// for a while would have any change at all rejected.
+const MIN_MAD: f64 = 0.1;
+
+/// Rejects readings that are statistical outliers compared to recently accepted readings.
+///
+/// The median and median absolute deviation (MAD) of temperature and humidity are computed
+/// from the last `window` accepted readings. A reading is rejected if either value deviates
+/// from its median by more than `threshold` times its MAD. Every reading is accepted until
+/// there are `window` of them. After `reaccept_after` consecutive rejected readings, the
+/// latest is accepted and the rejected readings replace the accepted ones, so that a real
+/// step change, like opening a window, eventually gets through and becomes the new baseline.
+#[derive(Debug, Clone)]
+pub struct MadFilter {
+    window: usize,
+    threshold: f64,
+    reaccept_after: u32,
+    accepted: VecDeque<Measurement>,
+    rejected: Vec<Measurement>,
+    // Set once there have been enough readings to reject any
+    primed: bool,
+}
+
+impl MadFilter {
+    /// Create a filter comparing readings to the last `window` accepted readings, using
+    /// the default threshold and number of rejections before readings are accepted again.
+    pub fn new(window: usize) -> Self {
+        let window = window.max(1);
+        Self {
+            window,
+            threshold: DEFAULT_MAD_THRESHOLD,
+            reaccept_after: DEFAULT_MAD_REACCEPT_AFTER,
+            accepted: VecDeque::with_capacity(window),
+            rejected: Vec::new(),
+            primed: false,
+        }
+    }
+
+    /// Reject readings that deviate from the median by more than `threshold` times the
+    /// median absolute deviation. Default 5.
+    pub fn threshold(mut self, threshold: f64) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Accept readings again after `rejections` consecutive readings have been rejected.
+    /// Default 3.
+    pub fn reaccept_after(mut self, rejections: u32) -> Self {
+        self.reaccept_after = rejections.max(1);
+        self
+    }
+
+    /// Return `m` if it's plausible given recently accepted readings or a
+    /// `SensorErrorKind::Implausible` error if it isn't.
+    pub fn check(&mut self, m: Measurement) -> Result<Measurement, SensorError> {
+        if !self.primed {
+            self.accept(m);
+            self.primed = self.accepted.len() == self.window;
+            return Ok(m);
+        }
+
+        let outlier = self
+            .outlier("temperature", |m| f64::from(m.temperature), &m)
+            .or_else(|| self.outlier("humidity", |m| f64::from(m.humidity), &m));
+
+        let Some(reason) = outlier else {
+            self.accept(m);
+            return Ok(m);
+        };
+
+        self.rejected.push(m);
+        if self.rejected.len() < self.reaccept_after as usize {
+            tracing::debug!(message = "rejected implausible reading", reason = %reason);
+            return Err(SensorError::implausible(reason));
+        }
+
+        tracing::info!(
+            message = "accepting readings after consecutive rejections",
+            rejections = self.rejected.len()
+        );
+        self.accepted.clear();
+        for r in std::mem::take(&mut self.rejected) {
+            self.push(r);
+        }
+
+        Ok(m)
+    }
+
+    /// Check the measurement of a successful read, leaving errors as they are.
+    pub fn apply_result(&mut self, res: Result<Measurement, SensorError>) -> Result<Measurement, SensorError> {
+        res.and_then(|m| self.check(m))
+    }
+
+    /// Forget all readings, accepting every reading until there are enough of them again.
+    pub fn reset(&mut self) {
+        self.accepted.clear();
+        self.rejected.clear();
+        self.primed = false;
+    }
+
+    fn accept(&mut self, m: Measurement) {
+        self.rejected.clear();
+        self.push(m);
+    }
+
+    fn push(&mut self, m: Measurement) {
+        if self.accepted.len() == self.window {
+            self.accepted.pop_front();
+        }
+
+        self.accepted.push_back(m);
+    }
+
+    /// Describe why the value of `m` selected by `value` is an outlier, `None` if it isn't.
+    fn outlier<F>(&self, name: &str, value: F, m: &Measurement) -> Option<String>
+    where
+        F: Fn(&Measurement) -> f64,
+    {
+        let values: Vec<f64> = self.accepted.iter().map(&value).collect();
+        let med = median(&values)?;
+        let deviations: Vec<f64> = values.iter().map(|v| (v - med).abs()).collect();
+        let mad = median(&deviations)?.max(MIN_MAD);
+
+        let v = value(m);
+        if (v - med).abs() <= self.threshold * mad {
+            return None;
+        }
+
+        Some(format!(
+            "{} {:.1} deviates from median {:.1} by more than {} times the median absolute deviation {:.2}",
+            name, v, med, self.threshold, mad
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MadFilter;
+    use crate::sensor::core::{Humidity, Measurement, SensorError, SensorErrorKind, TemperatureCelsius};
+
+    fn measurement(temperature: f64, humidity: f64) -> Measurement {
+        Measurement {
+            temperature: TemperatureCelsius::from(temperature),
+            humidity: Humidity::from(humidity),
+        }
+    }
+
+    /// Fill the filter with readings alternating slightly around 21c and 40%
+    fn steady(filter: &mut MadFilter, n: usize) {
+        for i in 0..n {
+            let noise = if i % 2 == 0 { 0.1 } else { -0.1 };
+            assert!(filter.check(measurement(21.0 + noise, 40.0 - noise)).is_ok());
+        }
+    }
+
+    fn is_implausible(res: Result<Measurement, SensorError>) -> bool {
+        matches!(res, Err(e) if e.kind() == SensorErrorKind::Implausible)
+    }
+
+    #[test]
+    fn test_mad_filter_accepts_until_window_full() {
+        let mut filter = MadFilter::new(5);
+        for t in [21.0, 35.0, -5.0, 21.0, 80.0] {
+            assert_eq!(measurement(t, 40.0), filter.check(measurement(t, 40.0)).unwrap());
+        }
+
+        assert!(is_implausible(filter.check(measurement(150.0, 40.0))));
+    }
+
+    #[test]
+    fn test_mad_filter_single_spike() {
+        let mut filter = MadFilter::new(10);
+        steady(&mut filter, 10);
+
+        assert!(is_implausible(filter.check(measurement(35.0, 40.0))));
+        assert!(filter.check(measurement(21.0, 40.0)).is_ok());
+
+        // Spikes separated by good readings aren't consecutive so are never accepted
+        for _ in 0..5 {
+            assert!(is_implausible(filter.check(measurement(35.0, 40.0))));
+            assert!(is_implausible(filter.check(measurement(21.0, 95.0))));
+            assert!(filter.check(measurement(21.1, 40.1)).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_mad_filter_step_change() {
+        let mut filter = MadFilter::new(10).reaccept_after(3);
+        steady(&mut filter, 10);
+
+        assert!(is_implausible(filter.check(measurement(15.0, 55.0))));
+        assert!(is_implausible(filter.check(measurement(15.1, 55.0))));
+        assert_eq!(measurement(14.9, 55.0), filter.check(measurement(14.9, 55.0)).unwrap());
+
+        // Rejected readings count toward the new level so it keeps being accepted
+        for _ in 0..20 {
+            assert!(filter.check(measurement(15.0, 55.0)).is_ok());
+        }
+
+        // Returning to the old level is a step change of its own
+        assert!(is_implausible(filter.check(measurement(21.0, 40.0))));
+    }
+
+    #[test]
+    fn test_mad_filter_noisy_drift() {
+        let mut filter = MadFilter::new(10);
+
+        // Warming by 0.05c per reading with +/- 0.2c of noise, as on a sunny morning
+        for i in 0..200 {
+            let noise = [0.2, -0.1, 0.0, -0.2, 0.1][i % 5];
+            let t = 10.0 + 0.05 * i as f64 + noise;
+            assert!(
+                filter.check(measurement(t, 60.0 - noise)).is_ok(),
+                "rejected reading {}",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn test_mad_filter_threshold() {
+        let mut filter = MadFilter::new(10).threshold(20.0);
+        steady(&mut filter, 10);
+
+        // The MAD of the steady readings is 0.1 so 2c is within 20 MADs
+        assert!(filter.check(measurement(23.0, 40.0)).is_ok());
+        assert!(is_implausible(filter.check(measurement(23.5, 40.0))));
+    }
+
+    #[test]
+    fn test_mad_filter_constant_readings() {
+        let mut filter = MadFilter::new(5);
+        for _ in 0..5 {
+            assert!(filter.check(measurement(21.0, 40.0)).is_ok());
+        }
+
+        // Identical readings have a MAD of zero, small changes are still accepted
+        assert!(filter.check(measurement(21.3, 40.4)).is_ok());
+        assert!(is_implausible(filter.check(measurement(22.0, 40.0))));
+    }
+
+    #[test]
+    fn test_mad_filter_reset() {
+        let mut filter = MadFilter::new(5);
+        steady(&mut filter, 5);
+        assert!(is_implausible(filter.check(measurement(35.0, 40.0))));
+
+        filter.reset();
+        assert!(filter.check(measurement(35.0, 40.0)).is_ok());
+    }
+
+    #[test]
+    fn test_mad_filter_apply_result_error() {
+        let mut filter = MadFilter::new(5);
+        let res = filter.apply_result(Err(SensorError::timeout("timeout")));
+        assert_eq!(SensorErrorKind::ReadTimeout, res.unwrap_err().kind());
+    }
+}
@@ -0,0 +1,168 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! Checks of the pulses of a read beyond the checksum of the bits decoded from them, used
+//! by strict frame validation of DHT22 sensors.
+
+/// Separation two clusters of high pulses must have to be told apart, see `TwoMeans::separation`.
+/// Pulses of a clean read are usually much further apart than this.
+pub(crate) const MIN_SEPARATION: f64 = 0.5;
+
+/// Values of one side of a `TwoMeans` split.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Cluster {
+    pub count: usize,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+}
+
+impl Cluster {
+    fn new(values: &[f64]) -> Self {
+        Self {
+            count: values.len(),
+            min: values[0],
+            max: values[values.len() - 1],
+            mean: values.iter().sum::<f64>() / values.len() as f64,
+        }
+    }
+}
+
+/// Values split into the two clusters that minimize the sum of squared distances of each
+/// value from the mean of its cluster, the optimal 1-D two-means split.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct TwoMeans {
+    pub low: Cluster,
+    pub high: Cluster,
+}
+
+impl TwoMeans {
+    /// Split `values`, in any order, into two clusters. Equal values are always in the same
+    /// cluster so `None` is returned if there are fewer than two distinct values.
+    pub(crate) fn split(values: &[u32]) -> Option<Self> {
+        let mut sorted: Vec<f64> = values.iter().map(|v| f64::from(*v)).collect();
+        sorted.sort_by(f64::total_cmp);
+
+        // Sums of the first `i` values and their squares, so that the error of each
+        // candidate split can be computed without another pass over the values
+        let mut sums = vec![(0.0, 0.0); sorted.len() + 1];
+        for (i, v) in sorted.iter().enumerate() {
+            let (sum, squares) = sums[i];
+            sums[i + 1] = (sum + v, squares + v * v);
+        }
+
+        let error = |from: usize, to: usize| {
+            let n = (to - from) as f64;
+            let sum = sums[to].0 - sums[from].0;
+            let squares = sums[to].1 - sums[from].1;
+            squares - sum * sum / n
+        };
+
+        let split = (1..sorted.len())
+            .filter(|&i| sorted[i - 1] < sorted[i])
+            .map(|i| (i, error(0, i) + error(i, sorted.len())))
+            .min_by(|a, b| a.1.total_cmp(&b.1))?
+            .0;
+
+        Some(Self {
+            low: Cluster::new(&sorted[..split]),
+            high: Cluster::new(&sorted[split..]),
+        })
+    }
+
+    /// Fraction of the distance between the means of the clusters that no value falls in,
+    /// from close to 0 when values are spread evenly between them to 1 when every value of
+    /// each cluster is the same. A single value between the clusters is enough to make it
+    /// small since it could belong to either of them.
+    pub(crate) fn separation(&self) -> f64 {
+        (self.high.min - self.low.max) / (self.high.mean - self.low.mean)
+    }
+
+    /// True if the clusters are far enough apart to be told apart, see `MIN_SEPARATION`.
+    pub(crate) fn is_separated(&self) -> bool {
+        self.separation() > MIN_SEPARATION
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TwoMeans;
+
+    #[test]
+    fn test_two_means_clean() {
+        let values = [198, 600, 203, 201, 596, 604, 199, 605, 202, 597];
+        let split = TwoMeans::split(&values).unwrap();
+
+        assert_eq!(5, split.low.count);
+        assert_eq!(198.0, split.low.min);
+        assert_eq!(203.0, split.low.max);
+        assert_eq!(200.6, split.low.mean);
+        assert_eq!(5, split.high.count);
+        assert_eq!(596.0, split.high.min);
+        assert_eq!(605.0, split.high.max);
+        assert_eq!(600.4, split.high.mean);
+        assert!(split.separation() > 0.95, "separation {}", split.separation());
+        assert!(split.is_separated());
+    }
+
+    #[test]
+    fn test_two_means_exact() {
+        let split = TwoMeans::split(&[200, 600, 200, 600]).unwrap();
+
+        assert_eq!(1.0, split.separation());
+        assert!(split.is_separated());
+    }
+
+    #[test]
+    fn test_two_means_uneven() {
+        // A single 1 bit in a frame of 0 bits is still its own cluster
+        let split = TwoMeans::split(&[200, 210, 190, 205, 195, 600]).unwrap();
+
+        assert_eq!(5, split.low.count);
+        assert_eq!(1, split.high.count);
+        assert!(split.is_separated());
+    }
+
+    #[test]
+    fn test_two_means_ambiguous() {
+        // Pulses evenly spread between the lengths of 0 and 1 bits
+        let values: Vec<u32> = (0..40).map(|i| 200 + i * 10).collect();
+        let split = TwoMeans::split(&values).unwrap();
+
+        assert_eq!(20, split.low.count);
+        assert_eq!(20, split.high.count);
+        assert!(split.separation() < 0.1, "separation {}", split.separation());
+        assert!(!split.is_separated());
+    }
+
+    #[test]
+    fn test_two_means_overlapping() {
+        // Noisy pulses where the longest 0 bits are close to the shortest 1 bits
+        let values = [150, 250, 380, 200, 420, 550, 450, 650, 600, 330];
+        let split = TwoMeans::split(&values).unwrap();
+
+        assert!(!split.is_separated(), "separation {}", split.separation());
+    }
+
+    #[test]
+    fn test_two_means_single_value() {
+        assert_eq!(None, TwoMeans::split(&[]));
+        assert_eq!(None, TwoMeans::split(&[200]));
+        assert_eq!(None, TwoMeans::split(&[200, 200, 200]));
+    }
+}
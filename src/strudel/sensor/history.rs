@@ -0,0 +1,183 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+use crate::clock::ClockCheck;
+use crate::sensor::latest::LatestReading;
+use crate::sensor::worker::ReadingEvent;
+use std::collections::VecDeque;
+use std::sync::{Mutex, PoisonError};
+use std::time::SystemTime;
+
+/// Successful readings kept in memory, oldest first, so that they can be backfilled
+/// into Prometheus after scrapes were missed.
+///
+/// Only readings taken while the system clock was synchronized are kept since the time
+/// of the others isn't meaningful. Once `capacity` readings are kept the oldest one is
+/// dropped for each new one.
+#[derive(Debug)]
+pub struct ReadingHistory {
+    capacity: usize,
+    clock_check: Option<ClockCheck>,
+    readings: Mutex<VecDeque<LatestReading>>,
+}
+
+impl ReadingHistory {
+    /// Create a history keeping up to `capacity` readings.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            clock_check: None,
+            readings: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Skip readings taken before `check` considers the system clock synchronized. By
+    /// default, every successful reading is kept.
+    pub fn clock_check(mut self, check: ClockCheck) -> Self {
+        self.clock_check = Some(check);
+        self
+    }
+
+    /// Keep the reading from a successful read, ignoring failed reads. Intended to be used
+    /// as a subscriber of a `SensorWorker`.
+    pub fn update(&self, event: &ReadingEvent) {
+        let Ok(m) = &event.result else {
+            return;
+        };
+
+        if self
+            .clock_check
+            .map(|c| !c.is_synchronized_at(event.timestamp))
+            .unwrap_or(false)
+        {
+            return;
+        }
+
+        self.push(LatestReading::new(*m, event.timestamp, event.instant));
+    }
+
+    /// Keep `reading`, dropping the oldest reading if the history is full.
+    pub fn push(&self, reading: LatestReading) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut readings = self.readings.lock().unwrap_or_else(PoisonError::into_inner);
+        if readings.len() == self.capacity {
+            readings.pop_front();
+        }
+
+        readings.push_back(reading);
+    }
+
+    /// Readings taken at or after `since`, oldest first.
+    pub fn since(&self, since: SystemTime) -> Vec<LatestReading> {
+        let readings = self.readings.lock().unwrap_or_else(PoisonError::into_inner);
+        readings.iter().filter(|r| r.read_at >= since).copied().collect()
+    }
+
+    /// Number of readings kept.
+    pub fn len(&self) -> usize {
+        self.readings.lock().unwrap_or_else(PoisonError::into_inner).len()
+    }
+
+    /// Return true if no readings are kept.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ReadingHistory;
+    use crate::clock::ClockCheck;
+    use crate::sensor::calibration::Clamped;
+    use crate::sensor::core::{Humidity, Measurement, SensorError, TemperatureCelsius};
+    use crate::sensor::latest::LatestReading;
+    use crate::sensor::worker::ReadingEvent;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+    use tracing::Span;
+
+    fn event(secs: u64, result: Result<Measurement, SensorError>) -> ReadingEvent {
+        ReadingEvent {
+            timestamp: UNIX_EPOCH + Duration::from_secs(secs),
+            instant: Instant::now(),
+            result,
+            clamped: Clamped::default(),
+            attempts: 1,
+            retried_errors: Vec::new(),
+            duration: Duration::ZERO,
+            raw: Vec::new(),
+            pulses: None,
+            span: Span::none(),
+        }
+    }
+
+    fn measurement(temperature: f64) -> Result<Measurement, SensorError> {
+        Ok(Measurement {
+            temperature: TemperatureCelsius::from(temperature),
+            humidity: Humidity::from(40.0),
+        })
+    }
+
+    fn times(readings: &[LatestReading]) -> Vec<SystemTime> {
+        readings.iter().map(|r| r.read_at).collect()
+    }
+
+    #[test]
+    fn test_reading_history_capacity() {
+        let history = ReadingHistory::new(3);
+        for secs in 1..=5 {
+            history.update(&event(secs, measurement(20.0)));
+        }
+        history.update(&event(6, Err(SensorError::timeout("no response"))));
+
+        assert_eq!(3, history.len());
+        assert_eq!(
+            vec![
+                UNIX_EPOCH + Duration::from_secs(3),
+                UNIX_EPOCH + Duration::from_secs(4),
+                UNIX_EPOCH + Duration::from_secs(5),
+            ],
+            times(&history.since(UNIX_EPOCH))
+        );
+        assert_eq!(
+            vec![UNIX_EPOCH + Duration::from_secs(5)],
+            times(&history.since(UNIX_EPOCH + Duration::from_secs(5)))
+        );
+    }
+
+    #[test]
+    fn test_reading_history_unsynchronized() {
+        let history = ReadingHistory::new(10).clock_check(ClockCheck::new(UNIX_EPOCH + Duration::from_secs(100)));
+        history.update(&event(50, measurement(20.0)));
+        history.update(&event(150, measurement(21.0)));
+
+        assert_eq!(
+            vec![UNIX_EPOCH + Duration::from_secs(150)],
+            times(&history.since(UNIX_EPOCH))
+        );
+    }
+
+    #[test]
+    fn test_reading_history_disabled() {
+        let history = ReadingHistory::new(0);
+        history.update(&event(1, measurement(20.0)));
+        assert!(history.is_empty());
+    }
+}
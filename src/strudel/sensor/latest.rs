@@ -0,0 +1,524 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+use crate::clock::Clock;
+use crate::sensor::core::{Humidity, Measurement, TemperatureCelsius};
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt::{self, Formatter};
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The most recent successful reading of a sensor and when it was taken.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LatestReading {
+    pub temperature: TemperatureCelsius,
+    pub humidity: Humidity,
+    /// When the reading was taken according to the system clock. Only used for exporting
+    /// the time of the reading since the system clock can be stepped at any time.
+    pub read_at: SystemTime,
+    /// When the reading was taken according to the monotonic clock, used for its age.
+    pub taken: Instant,
+    /// False if the system clock wasn't synchronized when the reading was taken. `read_at`
+    /// isn't meaningful for these readings until they're restamped by
+    /// `LatestReadingCell::restamp_at`.
+    pub synced: bool,
+    /// True if the reading was restored from a previous run of `strudel` instead of being
+    /// read by this one.
+    pub restored: bool,
+}
+
+impl LatestReading {
+    pub fn new(measurement: Measurement, read_at: SystemTime, taken: Instant) -> Self {
+        Self {
+            temperature: measurement.temperature,
+            humidity: measurement.humidity,
+            read_at,
+            taken,
+            synced: true,
+            restored: false,
+        }
+    }
+
+    /// Mark the reading as restored from a previous run instead of read by this one.
+    pub fn restored(mut self) -> Self {
+        self.restored = true;
+        self
+    }
+
+    /// Mark the reading as taken while the system clock wasn't synchronized.
+    pub fn unsynced(mut self) -> Self {
+        self.synced = false;
+        self
+    }
+
+    /// Return true if the system clock was synchronized when the reading was taken, or
+    /// the reading has been restamped since.
+    pub fn is_synced(&self) -> bool {
+        self.synced
+    }
+
+    /// Time elapsed since the reading was taken according to `clock`.
+    pub fn age(&self, clock: &dyn Clock) -> Duration {
+        self.age_at(clock.now_monotonic())
+    }
+
+    /// Time elapsed between the reading being taken and `now`, a monotonic time. If
+    /// `now` is before the reading, the age is zero.
+    pub fn age_at(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.taken)
+    }
+
+    /// Return true if the reading is older than `max_age` according to `clock`.
+    pub fn is_stale(&self, max_age: Duration, clock: &dyn Clock) -> bool {
+        self.is_stale_at(max_age, clock.now_monotonic())
+    }
+
+    /// Return true if the reading is older than `max_age` as of `now`, a monotonic time.
+    pub fn is_stale_at(&self, max_age: Duration, now: Instant) -> bool {
+        self.age_at(now) > max_age
+    }
+
+    /// Pair the reading with the name of the sensor it came from for serialization.
+    pub fn named<'a>(&'a self, sensor: Option<&'a str>) -> NamedReading<'a> {
+        NamedReading { sensor, reading: self }
+    }
+
+    fn read_at_secs(&self) -> f64 {
+        self.read_at
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or(0.0)
+    }
+
+    fn synced_read_at_secs(&self) -> Option<f64> {
+        self.is_synced().then(|| self.read_at_secs())
+    }
+}
+
+impl fmt::Display for LatestReading {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} at {:.3}",
+            self.temperature,
+            self.humidity,
+            self.read_at_secs()
+        )
+    }
+}
+
+/// Serialized with temperature and humidity as plain numbers and the time of the
+/// reading as a UNIX timestamp in seconds, or `null` if the clock wasn't synchronized.
+/// Readings restored from a previous run also have `restored` set to `true`.
+impl Serialize for LatestReading {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_struct("LatestReading", 4)?;
+        s.serialize_field("temperature", &f64::from(self.temperature))?;
+        s.serialize_field("humidity", &f64::from(self.humidity))?;
+        s.serialize_field("read_at", &self.synced_read_at_secs())?;
+        if self.restored {
+            s.serialize_field("restored", &true)?;
+        } else {
+            s.skip_field("restored")?;
+        }
+        s.end()
+    }
+}
+
+/// A `LatestReading` and the name of the sensor it came from, serialized with the
+/// name of the sensor (`null` if unnamed) followed by the fields of the reading.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct NamedReading<'a> {
+    pub sensor: Option<&'a str>,
+    pub reading: &'a LatestReading,
+}
+
+impl<'a> Serialize for NamedReading<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_struct("NamedReading", 5)?;
+        s.serialize_field("sensor", &self.sensor)?;
+        s.serialize_field("temperature", &f64::from(self.reading.temperature))?;
+        s.serialize_field("humidity", &f64::from(self.reading.humidity))?;
+        s.serialize_field("read_at", &self.reading.synced_read_at_secs())?;
+        if self.reading.restored {
+            s.serialize_field("restored", &true)?;
+        } else {
+            s.skip_field("restored")?;
+        }
+        s.end()
+    }
+}
+
+/// A reading and the generation of the `LatestReadingCell` it was stored in. The
+/// generation increases by one each time any reading is stored in the cell.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub generation: u64,
+    pub reading: LatestReading,
+}
+
+#[derive(Debug, Default)]
+struct CellState {
+    generation: u64,
+    readings: BTreeMap<Option<String>, Snapshot>,
+}
+
+/// Thread-safe holder for the most recent successful reading of each sensor, shared
+/// between the tasks reading sensors and anything that needs to report on them.
+///
+/// All values of a reading are stored and read together under a single lock so that
+/// readers, like Prometheus scrapes and the JSON endpoints, never see a mix of values
+/// from different readings.
+///
+/// Sensors are identified by an optional name, deployments with a single sensor
+/// don't need to name it.
+#[derive(Debug, Default)]
+pub struct LatestReadingCell {
+    inner: RwLock<CellState>,
+}
+
+impl LatestReadingCell {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the most recent reading of the unnamed sensor.
+    pub fn set(&self, reading: LatestReading) {
+        self.set_entry(None, reading);
+    }
+
+    /// Set the most recent reading of the sensor named `sensor`.
+    pub fn set_named(&self, sensor: &str, reading: LatestReading) {
+        self.set_entry(Some(sensor.to_owned()), reading);
+    }
+
+    /// Remove the most recent reading of the sensor named `sensor`, or the unnamed sensor
+    /// if `None`, returning it if there was one.
+    pub fn remove(&self, sensor: Option<&str>) -> Option<LatestReading> {
+        let mut state = self.state_mut();
+        let removed = state.readings.remove(&sensor.map(str::to_owned))?;
+        state.generation += 1;
+        Some(removed.reading)
+    }
+
+    fn set_entry(&self, sensor: Option<String>, reading: LatestReading) {
+        let mut state = self.state_mut();
+        state.generation += 1;
+        let generation = state.generation;
+        state.readings.insert(sensor, Snapshot { generation, reading });
+    }
+
+    /// Get the most recent reading of the unnamed sensor, `None` if there have been no
+    /// successful reads of it yet.
+    pub fn get(&self) -> Option<LatestReading> {
+        self.snapshot(None).map(|s| s.reading)
+    }
+
+    /// Get the most recent reading of the sensor named `sensor`, `None` if there have
+    /// been no successful reads of it yet.
+    pub fn get_named(&self, sensor: &str) -> Option<LatestReading> {
+        self.snapshot(Some(sensor)).map(|s| s.reading)
+    }
+
+    /// Get the most recent reading of the sensor named `sensor`, or the unnamed sensor
+    /// if `None`, and the generation it was stored at.
+    pub fn snapshot(&self, sensor: Option<&str>) -> Option<Snapshot> {
+        self.state().readings.get(&sensor.map(str::to_owned)).copied()
+    }
+
+    /// Set the time of readings taken while the system clock wasn't synchronized based on
+    /// how long ago they were taken, once the clock is synchronized. `now` and `instant` are
+    /// the current system and monotonic time. Returns the number of readings restamped.
+    /// Generations aren't changed since these are the same readings.
+    pub fn restamp_at(&self, now: SystemTime, instant: Instant) -> usize {
+        // Avoid taking the write lock for every check once everything is synchronized
+        if self.state().readings.values().all(|s| s.reading.is_synced()) {
+            return 0;
+        }
+
+        let mut state = self.state_mut();
+        let mut restamped = 0;
+        for snapshot in state.readings.values_mut() {
+            if !snapshot.reading.synced {
+                let age = snapshot.reading.age_at(instant);
+                snapshot.reading.read_at = now.checked_sub(age).unwrap_or(now);
+                snapshot.reading.synced = true;
+                restamped += 1;
+            }
+        }
+
+        restamped
+    }
+
+    /// Generation of the most recently stored reading, zero if nothing has been stored.
+    pub fn generation(&self) -> u64 {
+        self.state().generation
+    }
+
+    /// Get the reading stored most recently, of any sensor, `None` if there have been no
+    /// successful reads yet.
+    pub fn newest(&self) -> Option<LatestReading> {
+        self.state()
+            .readings
+            .values()
+            .max_by_key(|s| s.generation)
+            .map(|s| s.reading)
+    }
+
+    /// Get the most recent reading of every sensor that has been read, ordered by name
+    /// with the unnamed sensor first.
+    pub fn all(&self) -> Vec<(Option<String>, LatestReading)> {
+        self.state()
+            .readings
+            .iter()
+            .map(|(k, v)| (k.clone(), v.reading))
+            .collect()
+    }
+
+    // Readings are replaced whole while the lock is held so a panic elsewhere while holding
+    // it can't leave a partially updated reading. Recover from poisoning rather than making
+    // every later scrape panic.
+    fn state(&self) -> RwLockReadGuard<'_, CellState> {
+        self.inner.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn state_mut(&self) -> RwLockWriteGuard<'_, CellState> {
+        self.inner.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LatestReading, LatestReadingCell, Snapshot};
+    use crate::clock::{Clock, MockClock};
+    use crate::sensor::core::{Humidity, Measurement, TemperatureCelsius};
+    use std::sync::OnceLock;
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    fn measurement() -> Measurement {
+        Measurement {
+            temperature: TemperatureCelsius::from(21.5),
+            humidity: Humidity::from(40.0),
+        }
+    }
+
+    /// Monotonic time every reading is relative to.
+    fn start() -> Instant {
+        static START: OnceLock<Instant> = OnceLock::new();
+        *START.get_or_init(Instant::now)
+    }
+
+    fn reading(secs: u64) -> LatestReading {
+        LatestReading::new(
+            measurement(),
+            UNIX_EPOCH + Duration::from_secs(secs),
+            start() + Duration::from_secs(secs),
+        )
+    }
+
+    #[test]
+    fn test_latest_reading_age() {
+        let r = reading(1000);
+        assert_eq!(Duration::from_secs(30), r.age_at(start() + Duration::from_secs(1030)));
+        assert_eq!(Duration::ZERO, r.age_at(start() + Duration::from_secs(1000)));
+        assert_eq!(Duration::ZERO, r.age_at(start() + Duration::from_secs(900)));
+    }
+
+    #[test]
+    fn test_latest_reading_age_wall_clock_step() {
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        let r = LatestReading::new(measurement(), clock.now_wall(), clock.now_monotonic());
+        let max_age = Duration::from_secs(60);
+
+        // Stepping the system clock backwards or forwards doesn't change the age
+        clock.advance(Duration::from_secs(30));
+        clock.step_wall(UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        assert_eq!(Duration::from_secs(30), r.age(&clock));
+        assert!(!r.is_stale(max_age, &clock));
+
+        clock.step_wall(UNIX_EPOCH + Duration::from_secs(1_800_000_000));
+        assert_eq!(Duration::from_secs(30), r.age(&clock));
+        assert!(!r.is_stale(max_age, &clock));
+
+        clock.advance(Duration::from_secs(31));
+        assert!(r.is_stale(max_age, &clock));
+    }
+
+    #[test]
+    fn test_latest_reading_is_stale() {
+        let r = reading(1000);
+        let max_age = Duration::from_secs(60);
+
+        assert!(!r.is_stale_at(max_age, start() + Duration::from_secs(1059)));
+        assert!(!r.is_stale_at(max_age, start() + Duration::from_secs(1060)));
+        assert!(r.is_stale_at(max_age, start() + Duration::from_secs(1061)));
+    }
+
+    #[test]
+    fn test_latest_reading_serialize() {
+        let json = serde_json::to_string(&reading(1000)).unwrap();
+        assert_eq!(r#"{"temperature":21.5,"humidity":40.0,"read_at":1000.0}"#, json);
+    }
+
+    #[test]
+    fn test_named_reading_serialize() {
+        let r = reading(1000);
+        let json = serde_json::to_string(&r.named(Some("indoor"))).unwrap();
+        assert_eq!(
+            r#"{"sensor":"indoor","temperature":21.5,"humidity":40.0,"read_at":1000.0}"#,
+            json
+        );
+
+        let json = serde_json::to_string(&r.named(None)).unwrap();
+        assert_eq!(
+            r#"{"sensor":null,"temperature":21.5,"humidity":40.0,"read_at":1000.0}"#,
+            json
+        );
+    }
+
+    #[test]
+    fn test_latest_reading_serialize_unsynced() {
+        let r = reading(10).unsynced();
+        assert!(!r.is_synced());
+
+        let json = serde_json::to_string(&r).unwrap();
+        assert_eq!(r#"{"temperature":21.5,"humidity":40.0,"read_at":null}"#, json);
+
+        let json = serde_json::to_string(&r.named(Some("indoor"))).unwrap();
+        assert_eq!(
+            r#"{"sensor":"indoor","temperature":21.5,"humidity":40.0,"read_at":null}"#,
+            json
+        );
+    }
+
+    #[test]
+    fn test_latest_reading_serialize_restored() {
+        let r = reading(1000).restored();
+
+        let json = serde_json::to_string(&r).unwrap();
+        assert_eq!(
+            r#"{"temperature":21.5,"humidity":40.0,"read_at":1000.0,"restored":true}"#,
+            json
+        );
+
+        let json = serde_json::to_string(&r.named(None)).unwrap();
+        assert_eq!(
+            r#"{"sensor":null,"temperature":21.5,"humidity":40.0,"read_at":1000.0,"restored":true}"#,
+            json
+        );
+    }
+
+    #[test]
+    fn test_latest_reading_cell_restamp() {
+        let cell = LatestReadingCell::new();
+        cell.set(reading(10).unsynced());
+        cell.set_named("outdoor", reading(1000));
+
+        // Restamped based on the monotonic time elapsed since the reading was taken
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(1, cell.restamp_at(now, start() + Duration::from_secs(15)));
+
+        let restamped = cell.get().unwrap();
+        assert!(restamped.is_synced());
+        assert_eq!(UNIX_EPOCH + Duration::from_secs(1_699_999_995), restamped.read_at);
+        assert_eq!(Some(reading(1000)), cell.get_named("outdoor"));
+        assert_eq!(2, cell.generation());
+
+        // Nothing left to restamp
+        assert_eq!(0, cell.restamp_at(now, start() + Duration::from_secs(20)));
+        assert_eq!(restamped, cell.get().unwrap());
+    }
+
+    #[test]
+    fn test_latest_reading_cell_named() {
+        let cell = LatestReadingCell::new();
+        cell.set_named("outdoor", reading(1000));
+        cell.set_named("indoor", reading(1010));
+        cell.set(reading(1020));
+
+        assert_eq!(Some(reading(1020)), cell.get());
+        assert_eq!(Some(reading(1010)), cell.get_named("indoor"));
+        assert_eq!(None, cell.get_named("garage"));
+        assert_eq!(Some(reading(1020)), cell.newest());
+        assert_eq!(
+            vec![
+                (None, reading(1020)),
+                (Some("indoor".to_owned()), reading(1010)),
+                (Some("outdoor".to_owned()), reading(1000)),
+            ],
+            cell.all()
+        );
+    }
+
+    #[test]
+    fn test_latest_reading_cell_remove() {
+        let cell = LatestReadingCell::new();
+        cell.set_named("outdoor", reading(1000));
+        cell.set(reading(1010));
+
+        assert_eq!(Some(reading(1000)), cell.remove(Some("outdoor")));
+        assert_eq!(None, cell.remove(Some("outdoor")));
+        assert_eq!(vec![(None, reading(1010))], cell.all());
+        assert_eq!(3, cell.generation());
+    }
+
+    #[test]
+    fn test_latest_reading_cell() {
+        let cell = LatestReadingCell::new();
+        assert_eq!(None, cell.get());
+
+        cell.set(reading(1000));
+        cell.set(reading(1030));
+        assert_eq!(Some(reading(1030)), cell.get());
+    }
+
+    #[test]
+    fn test_latest_reading_cell_generation() {
+        let cell = LatestReadingCell::new();
+        assert_eq!(0, cell.generation());
+        assert_eq!(None, cell.snapshot(None));
+
+        cell.set(reading(1000));
+        cell.set_named("outdoor", reading(1010));
+        cell.set(reading(1020));
+
+        assert_eq!(3, cell.generation());
+        assert_eq!(
+            Some(Snapshot {
+                generation: 3,
+                reading: reading(1020)
+            }),
+            cell.snapshot(None)
+        );
+        assert_eq!(
+            Some(Snapshot {
+                generation: 2,
+                reading: reading(1010)
+            }),
+            cell.snapshot(Some("outdoor"))
+        );
+    }
+}
@@ -16,9 +16,49 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 //
 
+mod asynchronous;
+mod calibration;
 mod core;
+mod dedup;
 mod dht22;
-mod test;
+mod diagnose;
+mod filter;
+mod frame;
+mod history;
+mod latest;
+mod power;
+mod probe;
+mod spec;
+mod spike;
+pub(crate) mod test;
+mod worker;
 
-pub use crate::sensor::core::{open_pin, DataPin, Humidity, SensorError, SensorErrorKind, TemperatureCelsius};
-pub use crate::sensor::dht22::DHT22Sensor;
+pub use crate::sensor::asynchronous::{AsyncSensor, RawRead};
+pub use crate::sensor::calibration::{Calibration, Clamped};
+#[cfg(feature = "rppal")]
+pub use crate::sensor::core::{open_i2c, open_pin};
+#[cfg(feature = "cdev")]
+pub use crate::sensor::core::{open_pin_cdev, CdevPin};
+pub use crate::sensor::core::{
+    read_registers, saturation_vapour_pressure, DataPin, Humidity, I2cBus, I2cError, Level, Measurement, PinMode,
+    PulseStats, RawReading, Sensor, SensorError, SensorErrorKind, SensorRanges, TemperatureCelsius,
+    TemperatureFahrenheit, TemperatureKelvin, TemperatureUnit, VapourPressureDeficit, WaitTimeout,
+};
+pub use crate::sensor::dedup::{DedupLogger, LogDecision, DEFAULT_ERROR_LOG_INTERVAL};
+#[cfg(feature = "fuzzing")]
+pub use crate::sensor::dht22::decode_counts;
+pub use crate::sensor::dht22::{DHT22Sensor, DHT22SensorBuilder, DynDHT22Sensor, TimingCalibration};
+pub use crate::sensor::diagnose::{diagnose_pin, PinDiagnostics};
+pub use crate::sensor::filter::{MadFilter, DEFAULT_MAD_REACCEPT_AFTER, DEFAULT_MAD_THRESHOLD};
+pub use crate::sensor::history::ReadingHistory;
+pub use crate::sensor::latest::{LatestReading, LatestReadingCell, NamedReading, Snapshot};
+pub use crate::sensor::power::{
+    DynPowerController, PowerController, PowerCycleReason, PowerCycleSkipped, DEFAULT_POWER_CYCLE_COOLDOWN,
+    DEFAULT_POWER_OFF,
+};
+pub use crate::sensor::probe::startup_probe;
+pub use crate::sensor::spec::{SensorKind, SensorSpec, SensorSpecError};
+pub use crate::sensor::spike::{Released, SpikeSuppressor, DEFAULT_SPIKE_THRESHOLD};
+pub use crate::sensor::worker::{
+    median, LatestStream, ReadRequester, ReadingEvent, SensorSwap, SensorSwapper, SensorWorker, WorkerHandle,
+};
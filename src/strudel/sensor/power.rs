@@ -0,0 +1,300 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+use crate::clock::{Clock, SystemClock};
+use crate::metrics::PowerMetrics;
+use crate::sensor::core::{DataPin, PinMode};
+use std::fmt::{self, Debug, Formatter};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, MutexGuard};
+
+/// Default time power to the sensor is cut for by each power cycle.
+pub const DEFAULT_POWER_OFF: Duration = Duration::from_secs(2);
+
+/// Default minimum time between automatic power cycles.
+pub const DEFAULT_POWER_CYCLE_COOLDOWN: Duration = Duration::from_secs(60 * 60);
+
+/// Why power to the sensor is being cut, see `PowerController::cycle`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerCycleReason {
+    /// The sensor is stuck returning identical bytes for every read.
+    Stuck,
+    /// Humidity has been pinned at 0% or 100% for a long time.
+    Saturated,
+    /// Someone asked for it, bypassing the cooldown.
+    Requested,
+}
+
+impl PowerCycleReason {
+    pub fn as_label(&self) -> &'static str {
+        match self {
+            PowerCycleReason::Stuck => "stuck",
+            PowerCycleReason::Saturated => "saturated",
+            PowerCycleReason::Requested => "requested",
+        }
+    }
+}
+
+/// Why a power cycle didn't happen, see `PowerController::cycle`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerCycleSkipped {
+    /// Another power cycle is in progress.
+    InProgress,
+    /// The sensor was power cycled too recently and can be again after `remaining`.
+    CoolingDown { remaining: Duration },
+}
+
+impl fmt::Display for PowerCycleSkipped {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PowerCycleSkipped::InProgress => write!(f, "power cycle already in progress"),
+            PowerCycleSkipped::CoolingDown { remaining } => {
+                write!(f, "sensor was power cycled recently, next allowed in {:?}", remaining)
+            }
+        }
+    }
+}
+
+/// A `PowerController` using a boxed pin, for when the type of pin is only known at runtime.
+pub type DynPowerController = PowerController<Box<dyn DataPin + Send + Sync>>;
+
+/// The power pin and when it was last pulled low.
+struct PowerState<P> {
+    pin: P,
+    last_cycle: Option<Instant>,
+}
+
+/// Power to the sensor stays cut while this is held, restored when it's dropped even if
+/// the power cycle is abandoned part way through.
+struct PoweredOff<'a, P: DataPin> {
+    state: MutexGuard<'a, PowerState<P>>,
+}
+
+impl<P: DataPin> Drop for PoweredOff<'_, P> {
+    fn drop(&mut self) {
+        self.state.pin.set_high();
+    }
+}
+
+/// Cuts power to a sensor whose VCC is switched by a GPIO pin, for example through a
+/// transistor or relay, to recover it when it gets stuck.
+///
+/// The pin is driven high to power the sensor and pulled low for the duration of each power
+/// cycle. Power cycles for a reason other than `PowerCycleReason::Requested` happen at most
+/// once per cooldown, counted from the most recent power cycle of any reason.
+pub struct PowerController<P> {
+    bcm_pin: u8,
+    state: Mutex<PowerState<P>>,
+    off_for: Duration,
+    cooldown: Duration,
+    metrics: PowerMetrics,
+    clock: Arc<dyn Clock>,
+}
+
+impl<P: DataPin> PowerController<P> {
+    /// Create a controller that powers the sensor on using `pin` right away, cutting power
+    /// for `off_for` during each power cycle and automatic power cycles at most once per
+    /// `cooldown`. Power cycles are counted by `metrics`.
+    pub fn new(mut pin: P, off_for: Duration, cooldown: Duration, metrics: &PowerMetrics) -> Self {
+        // Set the level before switching to output so the sensor isn't briefly unpowered
+        pin.set_high();
+        pin.set_mode(PinMode::Output);
+
+        Self {
+            bcm_pin: pin.pin(),
+            state: Mutex::new(PowerState { pin, last_cycle: None }),
+            off_for,
+            cooldown,
+            metrics: metrics.clone(),
+            clock: SystemClock::shared(),
+        }
+    }
+
+    /// Use `clock` to enforce the cooldown between power cycles. Defaults to the system clock.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Cut power to the sensor, wait, and restore it, returning once it's restored. Reads
+    /// of the sensor while power is cut will fail.
+    ///
+    /// Returns an error without doing anything if a power cycle is already in progress or,
+    /// unless `reason` is `PowerCycleReason::Requested`, the cooldown hasn't elapsed since
+    /// the last one.
+    pub async fn cycle(&self, reason: PowerCycleReason) -> Result<(), PowerCycleSkipped> {
+        let mut state = self.state.try_lock().map_err(|_| PowerCycleSkipped::InProgress)?;
+        let now = self.clock.now_monotonic();
+        if reason != PowerCycleReason::Requested {
+            if let Some(remaining) = self.cooldown_remaining(state.last_cycle, now) {
+                return Err(PowerCycleSkipped::CoolingDown { remaining });
+            }
+        }
+
+        tracing::info!(
+            message = "cutting power to sensor",
+            reason = reason.as_label(),
+            power_pin = self.bcm_pin,
+            off_ms = self.off_for.as_millis() as u64,
+        );
+
+        state.last_cycle = Some(now);
+        state.pin.set_low();
+        self.metrics.cycled(reason);
+
+        let off = PoweredOff { state };
+        tokio::time::sleep(self.off_for).await;
+        drop(off);
+
+        tracing::info!(message = "restored power to sensor", power_pin = self.bcm_pin);
+        Ok(())
+    }
+
+    /// How long until an automatic power cycle is allowed again, `None` if it is now.
+    fn cooldown_remaining(&self, last_cycle: Option<Instant>, now: Instant) -> Option<Duration> {
+        let elapsed = now.saturating_duration_since(last_cycle?);
+        self.cooldown.checked_sub(elapsed).filter(|d| !d.is_zero())
+    }
+}
+
+impl<P> Debug for PowerController<P> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PowerController")
+            .field("pin", &self.bcm_pin)
+            .field("off_for", &self.off_for)
+            .field("cooldown", &self.cooldown)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PowerController, PowerCycleReason, PowerCycleSkipped};
+    use crate::clock::MockClock;
+    use crate::metrics::PowerMetrics;
+    use crate::sensor::core::PinMode;
+    use crate::sensor::test::{PinEvent, RecordingDataPin};
+    use prometheus_client::encoding::text;
+    use prometheus_client::registry::Registry;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    type Events = Arc<Mutex<Vec<(PinEvent, Instant)>>>;
+
+    fn controller(clock: &MockClock, registry: &mut Registry) -> (PowerController<RecordingDataPin>, Events) {
+        let pin = RecordingDataPin::default();
+        let events = pin.events();
+        let metrics = PowerMetrics::new(registry);
+        let controller = PowerController::new(pin, Duration::from_secs(2), Duration::from_secs(3600), &metrics)
+            .clock(Arc::new(clock.clone()));
+
+        (controller, events)
+    }
+
+    fn levels(events: &Events) -> Vec<PinEvent> {
+        events.lock().unwrap().iter().map(|(e, _)| *e).collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_power_controller_cycle() {
+        let mut registry = <Registry>::default();
+        let clock = MockClock::new(UNIX_EPOCH);
+        let (controller, events) = controller(&clock, &mut registry);
+        assert_eq!(vec![PinEvent::High, PinEvent::Mode(PinMode::Output)], levels(&events));
+
+        controller.cycle(PowerCycleReason::Stuck).await.unwrap();
+        assert_eq!(
+            vec![
+                PinEvent::High,
+                PinEvent::Mode(PinMode::Output),
+                PinEvent::Low,
+                PinEvent::High
+            ],
+            levels(&events)
+        );
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+        assert!(
+            buf.contains("strudel_power_cycles_total{reason=\"stuck\"} 1\n"),
+            "{}",
+            buf
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_power_controller_cooldown() {
+        let mut registry = <Registry>::default();
+        let clock = MockClock::new(UNIX_EPOCH);
+        let (controller, _events) = controller(&clock, &mut registry);
+
+        controller.cycle(PowerCycleReason::Stuck).await.unwrap();
+        clock.advance(Duration::from_secs(600));
+        assert_eq!(
+            Err(PowerCycleSkipped::CoolingDown {
+                remaining: Duration::from_secs(3000)
+            }),
+            controller.cycle(PowerCycleReason::Saturated).await
+        );
+
+        // Requested power cycles ignore the cooldown but still start it over
+        controller.cycle(PowerCycleReason::Requested).await.unwrap();
+        clock.advance(Duration::from_secs(3000));
+        assert_eq!(
+            Err(PowerCycleSkipped::CoolingDown {
+                remaining: Duration::from_secs(600)
+            }),
+            controller.cycle(PowerCycleReason::Stuck).await
+        );
+
+        clock.advance(Duration::from_secs(600));
+        controller.cycle(PowerCycleReason::Saturated).await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_power_controller_in_progress() {
+        let mut registry = <Registry>::default();
+        let clock = MockClock::new(UNIX_EPOCH);
+        let (controller, _events) = controller(&clock, &mut registry);
+        let controller = Arc::new(controller);
+
+        let first = tokio::spawn({
+            let controller = controller.clone();
+            async move { controller.cycle(PowerCycleReason::Stuck).await }
+        });
+        tokio::task::yield_now().await;
+
+        assert_eq!(
+            Err(PowerCycleSkipped::InProgress),
+            controller.cycle(PowerCycleReason::Requested).await
+        );
+        assert_eq!(Ok(()), first.await.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_power_controller_abandoned() {
+        let mut registry = <Registry>::default();
+        let clock = MockClock::new(UNIX_EPOCH);
+        let (controller, events) = controller(&clock, &mut registry);
+
+        let res = tokio::time::timeout(Duration::from_secs(1), controller.cycle(PowerCycleReason::Requested)).await;
+        assert!(res.is_err());
+        assert_eq!(Some(PinEvent::High), levels(&events).last().copied());
+    }
+}
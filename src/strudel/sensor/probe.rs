@@ -0,0 +1,133 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+use crate::sensor::core::{Measurement, Sensor, SensorError, SensorErrorKind};
+use std::thread;
+use std::time::Duration;
+
+/// Verify that a sensor can be read, making up to `attempts` reads and sleeping for
+/// `delay` between them. Returns the first successful measurement or the error from
+/// the last attempt if none succeed. At least one read is always made.
+///
+/// This method blocks and should not be called from async code without using
+/// something like `spawn_blocking`.
+pub fn startup_probe<S>(sensor: &mut S, attempts: u32, delay: Duration) -> Result<Measurement, SensorError>
+where
+    S: Sensor + ?Sized,
+{
+    probe_with_retries(sensor, attempts, delay).0
+}
+
+/// Probe the sensor like `startup_probe`, also returning the kinds of errors of the
+/// failed reads before the result, in order.
+pub(crate) fn probe_with_retries<S>(
+    sensor: &mut S,
+    attempts: u32,
+    delay: Duration,
+) -> (Result<Measurement, SensorError>, Vec<SensorErrorKind>)
+where
+    S: Sensor + ?Sized,
+{
+    let mut retried = Vec::new();
+
+    loop {
+        let attempt = retried.len() as u32 + 1;
+        match sensor.read() {
+            Ok(m) => return (Ok(m), retried),
+            Err(e) if attempt >= attempts => return (Err(e), retried),
+            Err(e) => {
+                tracing::warn!(message = "startup probe of sensor failed", attempt = attempt, error = %e);
+                retried.push(e.kind());
+                thread::sleep(delay);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{probe_with_retries, startup_probe};
+    use crate::sensor::core::{Humidity, Measurement, Sensor, SensorError, SensorErrorKind, TemperatureCelsius};
+    use std::time::Duration;
+
+    /// Sensor that fails a fixed number of times before succeeding
+    #[derive(Debug)]
+    struct FlakySensor {
+        failures: u32,
+        reads: u32,
+    }
+
+    impl Sensor for FlakySensor {
+        fn read(&mut self) -> Result<Measurement, SensorError> {
+            self.reads += 1;
+            if self.reads <= self.failures {
+                return Err(SensorError::timeout("timeout"));
+            }
+
+            Ok(Measurement {
+                temperature: TemperatureCelsius::from(20.0),
+                humidity: Humidity::from(45.0),
+            })
+        }
+    }
+
+    #[test]
+    fn test_startup_probe_success_after_failures() {
+        let mut sensor = FlakySensor { failures: 2, reads: 0 };
+        let res = startup_probe(&mut sensor, 3, Duration::ZERO);
+
+        assert_eq!(TemperatureCelsius::from(20.0), res.unwrap().temperature);
+        assert_eq!(3, sensor.reads);
+    }
+
+    #[test]
+    fn test_startup_probe_all_attempts_fail() {
+        let mut sensor = FlakySensor { failures: 5, reads: 0 };
+        let res = startup_probe(&mut sensor, 3, Duration::ZERO);
+
+        assert_eq!(SensorErrorKind::ReadTimeout, res.unwrap_err().kind());
+        assert_eq!(3, sensor.reads);
+    }
+
+    #[test]
+    fn test_probe_with_retries() {
+        let mut sensor = FlakySensor { failures: 2, reads: 0 };
+        let (res, retried) = probe_with_retries(&mut sensor, 3, Duration::ZERO);
+
+        assert!(res.is_ok());
+        assert_eq!(
+            vec![SensorErrorKind::ReadTimeout, SensorErrorKind::ReadTimeout],
+            retried
+        );
+
+        let mut sensor = FlakySensor { failures: 5, reads: 0 };
+        let (res, retried) = probe_with_retries(&mut sensor, 2, Duration::ZERO);
+
+        assert!(res.is_err());
+        assert_eq!(vec![SensorErrorKind::ReadTimeout], retried);
+    }
+
+    #[test]
+    fn test_startup_probe_zero_attempts() {
+        let mut sensor = FlakySensor { failures: 0, reads: 0 };
+        let res = startup_probe(&mut sensor, 0, Duration::ZERO);
+
+        assert!(res.is_ok());
+        assert_eq!(1, sensor.reads);
+    }
+}
@@ -0,0 +1,416 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+use crate::cli::parse_float;
+
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt::{self, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Error parsing or checking the specification of a sensor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SensorSpecError {
+    msg: String,
+}
+
+impl SensorSpecError {
+    fn new(msg: String) -> Self {
+        Self { msg }
+    }
+
+    fn invalid(key: &str, val: &str, reason: &str) -> Self {
+        Self::new(format!("invalid value '{}' for key '{}', {}", val, key, reason))
+    }
+}
+
+impl fmt::Display for SensorSpecError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.msg.fmt(f)
+    }
+}
+
+impl Error for SensorSpecError {}
+
+/// Type of sensor connected to a pin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SensorKind {
+    #[default]
+    Dht22,
+}
+
+impl fmt::Display for SensorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SensorKind::Dht22 => "dht22".fmt(f),
+        }
+    }
+}
+
+impl FromStr for SensorKind {
+    type Err = SensorSpecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "dht22" => Ok(SensorKind::Dht22),
+            _ => Err(SensorSpecError::invalid("type", s, "expected 'dht22'")),
+        }
+    }
+}
+
+/// Pin and options of a single sensor, parsed from comma separated `key=value`
+/// pairs, for example `pin=17,name=indoor,type=dht22,refresh=15,temp_offset=-0.8`.
+///
+/// Supported keys are:
+/// * `pin` - GPIO pin (or line offset) of the sensor, required.
+/// * `name` - Name of the sensor, used to label its readings. Letters, numbers, `-`,
+///   and `_` only. Default none.
+/// * `type` - Type of sensor. Default `dht22`, the only supported type.
+/// * `refresh` - Interval to read the sensor at, in whole seconds. Default none, the
+///   caller decides the interval.
+/// * `temp_offset` - Degrees celsius to add to every temperature read. Default zero.
+///
+/// Keys may be given in any order but each only once.
+///
+/// Specs can also be deserialized from JSON objects with the same fields as they're
+/// serialized with, e.g. `{"pin": 17, "name": "indoor", "refresh_secs": 15}`, which
+/// are checked the same way.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "SpecFields")]
+pub struct SensorSpec {
+    pub pin: u8,
+    pub name: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: SensorKind,
+    #[serde(rename = "refresh_secs", serialize_with = "serialize_secs")]
+    pub refresh: Option<Duration>,
+    pub temp_offset: f64,
+}
+
+impl SensorSpec {
+    /// Create a spec for a sensor on `pin` with all other fields set to their defaults.
+    pub fn new(pin: u8) -> Self {
+        Self {
+            pin,
+            name: None,
+            kind: SensorKind::default(),
+            refresh: None,
+            temp_offset: 0.0,
+        }
+    }
+
+    /// Interval to read the sensor at, `default` if the spec doesn't include one.
+    pub fn refresh_or(&self, default: Duration) -> Duration {
+        self.refresh.unwrap_or(default)
+    }
+
+    /// Return an error if more than one of `specs` has the same name.
+    pub fn check_unique_names(specs: &[SensorSpec]) -> Result<(), SensorSpecError> {
+        let mut seen = HashSet::new();
+        for name in specs.iter().filter_map(|s| s.name.as_deref()) {
+            if !seen.insert(name) {
+                return Err(SensorSpecError::new(format!("duplicate sensor name '{}'", name)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Fields of a `SensorSpec` deserialized from JSON, before being checked.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SpecFields {
+    pin: u8,
+    name: Option<String>,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    refresh_secs: Option<u64>,
+    temp_offset: Option<f64>,
+}
+
+impl TryFrom<SpecFields> for SensorSpec {
+    type Error = SensorSpecError;
+
+    fn try_from(fields: SpecFields) -> Result<Self, Self::Error> {
+        Ok(SensorSpec {
+            name: fields.name.as_deref().map(parse_name).transpose()?,
+            kind: fields.kind.as_deref().map(str::parse).transpose()?.unwrap_or_default(),
+            refresh: fields.refresh_secs.map(Duration::from_secs),
+            temp_offset: fields
+                .temp_offset
+                .map(|v| parse_temp_offset(&v.to_string()))
+                .transpose()?
+                .unwrap_or(0.0),
+            ..SensorSpec::new(fields.pin)
+        })
+    }
+}
+
+impl FromStr for SensorSpec {
+    type Err = SensorSpecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut pin = None;
+        let mut name = None;
+        let mut kind = None;
+        let mut refresh = None;
+        let mut temp_offset = None;
+
+        for pair in s.split(',') {
+            let (key, val) = pair
+                .split_once('=')
+                .map(|(k, v)| (k.trim(), v.trim()))
+                .filter(|(k, _)| !k.is_empty())
+                .ok_or_else(|| SensorSpecError::new(format!("expected key=value, got '{}'", pair)))?;
+
+            let duplicate = match key {
+                "pin" => pin.replace(parse_pin(val)?).is_some(),
+                "name" => name.replace(parse_name(val)?).is_some(),
+                "type" => kind.replace(val.parse::<SensorKind>()?).is_some(),
+                "refresh" => refresh.replace(parse_refresh(val)?).is_some(),
+                "temp_offset" => temp_offset.replace(parse_temp_offset(val)?).is_some(),
+                _ => return Err(SensorSpecError::new(format!("unknown key '{}'", key))),
+            };
+
+            if duplicate {
+                return Err(SensorSpecError::new(format!("duplicate key '{}'", key)));
+            }
+        }
+
+        let pin = pin.ok_or_else(|| SensorSpecError::new("missing required key 'pin'".to_owned()))?;
+        Ok(SensorSpec {
+            name,
+            kind: kind.unwrap_or_default(),
+            refresh,
+            temp_offset: temp_offset.unwrap_or(0.0),
+            ..SensorSpec::new(pin)
+        })
+    }
+}
+
+fn parse_pin(val: &str) -> Result<u8, SensorSpecError> {
+    val.parse()
+        .map_err(|_| SensorSpecError::invalid("pin", val, "expected a number from 0 to 255"))
+}
+
+fn parse_name(val: &str) -> Result<String, SensorSpecError> {
+    if val.is_empty() {
+        Err(SensorSpecError::invalid("name", val, "must not be empty"))
+    } else if !val.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        Err(SensorSpecError::invalid(
+            "name",
+            val,
+            "must only contain letters, numbers, '-', and '_'",
+        ))
+    } else {
+        Ok(val.to_owned())
+    }
+}
+
+fn parse_refresh(val: &str) -> Result<Duration, SensorSpecError> {
+    val.parse()
+        .map(Duration::from_secs)
+        .map_err(|_| SensorSpecError::invalid("refresh", val, "expected a whole number of seconds"))
+}
+
+fn parse_temp_offset(val: &str) -> Result<f64, SensorSpecError> {
+    parse_float(val)
+        .ok()
+        .ok_or_else(|| SensorSpecError::invalid("temp_offset", val, "expected a number of degrees celsius"))
+}
+
+fn serialize_secs<S: Serializer>(v: &Option<Duration>, s: S) -> Result<S::Ok, S::Error> {
+    match v {
+        Some(d) => s.serialize_some(&d.as_secs()),
+        None => s.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SensorKind, SensorSpec};
+    use std::time::Duration;
+
+    fn parse_err(s: &str) -> String {
+        s.parse::<SensorSpec>().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_parse_defaults() {
+        let spec: SensorSpec = "pin=17".parse().unwrap();
+        assert_eq!(SensorSpec::new(17), spec);
+        assert_eq!(None, spec.name);
+        assert_eq!(SensorKind::Dht22, spec.kind);
+        assert_eq!(None, spec.refresh);
+        assert_eq!(0.0, spec.temp_offset);
+        assert_eq!(Duration::from_secs(30), spec.refresh_or(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_parse_all_keys() {
+        let spec: SensorSpec = "pin=17, name=indoor ,type=DHT22,refresh=15,temp_offset=-0.8"
+            .parse()
+            .unwrap();
+        assert_eq!(17, spec.pin);
+        assert_eq!(Some("indoor".to_owned()), spec.name);
+        assert_eq!(SensorKind::Dht22, spec.kind);
+        assert_eq!(Some(Duration::from_secs(15)), spec.refresh);
+        assert_eq!(-0.8, spec.temp_offset);
+        assert_eq!(Duration::from_secs(15), spec.refresh_or(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_parse_any_order() {
+        let spec: SensorSpec = "temp_offset=1.5,refresh=120,name=outdoor,pin=4".parse().unwrap();
+        assert_eq!(4, spec.pin);
+        assert_eq!(Some("outdoor".to_owned()), spec.name);
+        assert_eq!(Some(Duration::from_secs(120)), spec.refresh);
+        assert_eq!(1.5, spec.temp_offset);
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        let cases = [
+            ("", "expected key=value, got ''"),
+            ("17", "expected key=value, got '17'"),
+            ("=17", "expected key=value, got '=17'"),
+            ("pin=17,", "expected key=value, got ''"),
+            ("pin=17,,name=indoor", "expected key=value, got ''"),
+            ("name=indoor", "missing required key 'pin'"),
+            ("pin=17,colour=blue", "unknown key 'colour'"),
+            ("pin=17,Name=indoor", "unknown key 'Name'"),
+            ("pin=17,pin=4", "duplicate key 'pin'"),
+            ("pin=17,name=a,name=b", "duplicate key 'name'"),
+            ("pin=17,refresh=15,refresh=15", "duplicate key 'refresh'"),
+            (
+                "pin=seventeen",
+                "invalid value 'seventeen' for key 'pin', expected a number from 0 to 255",
+            ),
+            (
+                "pin=",
+                "invalid value '' for key 'pin', expected a number from 0 to 255",
+            ),
+            (
+                "pin=256",
+                "invalid value '256' for key 'pin', expected a number from 0 to 255",
+            ),
+            (
+                "pin=-1",
+                "invalid value '-1' for key 'pin', expected a number from 0 to 255",
+            ),
+            ("pin=17,name=", "invalid value '' for key 'name', must not be empty"),
+            (
+                "pin=17,name=living room",
+                "invalid value 'living room' for key 'name', must only contain letters, numbers, '-', and '_'",
+            ),
+            (
+                "pin=17,name=a=b",
+                "invalid value 'a=b' for key 'name', must only contain letters, numbers, '-', and '_'",
+            ),
+            (
+                "pin=17,type=dht11",
+                "invalid value 'dht11' for key 'type', expected 'dht22'",
+            ),
+            (
+                "pin=17,refresh=-5",
+                "invalid value '-5' for key 'refresh', expected a whole number of seconds",
+            ),
+            (
+                "pin=17,refresh=1.5",
+                "invalid value '1.5' for key 'refresh', expected a whole number of seconds",
+            ),
+            (
+                "pin=17,temp_offset=warm",
+                "invalid value 'warm' for key 'temp_offset', expected a number of degrees celsius",
+            ),
+            (
+                "pin=17,temp_offset=NaN",
+                "invalid value 'NaN' for key 'temp_offset', expected a number of degrees celsius",
+            ),
+            (
+                "pin=17,temp_offset=inf",
+                "invalid value 'inf' for key 'temp_offset', expected a number of degrees celsius",
+            ),
+        ];
+
+        for (input, expected) in cases {
+            assert_eq!(expected, parse_err(input), "input: '{}'", input);
+        }
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let spec: SensorSpec = "pin=17,name=indoor,refresh=15,temp_offset=-0.8".parse().unwrap();
+        let json = serde_json::to_string(&spec).unwrap();
+
+        assert_eq!(
+            r#"{"pin":17,"name":"indoor","type":"dht22","refresh_secs":15,"temp_offset":-0.8}"#,
+            json
+        );
+        assert_eq!(spec, serde_json::from_str::<SensorSpec>(&json).unwrap());
+    }
+
+    #[test]
+    fn test_json_defaults() {
+        let spec: SensorSpec = serde_json::from_str(r#"{"pin": 4}"#).unwrap();
+        assert_eq!(SensorSpec::new(4), spec);
+    }
+
+    #[test]
+    fn test_json_invalid() {
+        let cases = [
+            (r#"{"name": "indoor"}"#, "missing field `pin`"),
+            (r#"{"pin": 300}"#, "invalid value: integer `300`"),
+            (r#"{"pin": 17, "colour": "blue"}"#, "unknown field `colour`"),
+            (
+                r#"{"pin": 17, "name": "living room"}"#,
+                "invalid value 'living room' for key 'name', must only contain letters, numbers, '-', and '_'",
+            ),
+            (
+                r#"{"pin": 17, "type": "dht11"}"#,
+                "invalid value 'dht11' for key 'type', expected 'dht22'",
+            ),
+        ];
+
+        for (input, expected) in cases {
+            let err = serde_json::from_str::<SensorSpec>(input).unwrap_err().to_string();
+            assert!(err.contains(expected), "input: '{}', error: '{}'", input, err);
+        }
+    }
+
+    #[test]
+    fn test_check_unique_names() {
+        let specs: Vec<SensorSpec> = ["pin=17,name=indoor", "pin=4", "pin=5", "pin=27,name=outdoor"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        assert!(SensorSpec::check_unique_names(&specs).is_ok());
+
+        let specs: Vec<SensorSpec> = ["pin=17,name=indoor", "pin=4,name=indoor"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        assert_eq!(
+            "duplicate sensor name 'indoor'",
+            SensorSpec::check_unique_names(&specs).unwrap_err().to_string()
+        );
+    }
+}
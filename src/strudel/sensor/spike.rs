@@ -0,0 +1,273 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+use crate::sensor::core::{Humidity, Measurement, TemperatureCelsius};
+
+/// Default difference in degrees celsius from both neighbouring readings for a reading
+/// to be a spike.
+pub const DEFAULT_SPIKE_THRESHOLD: f64 = 2.0;
+
+/// A reading released by a `SpikeSuppressor`, one reading after it was pushed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Released<T> {
+    /// Item pushed along with the reading.
+    pub item: T,
+    /// The reading as it was pushed, or the average of its neighbours if it was a spike.
+    pub measurement: Measurement,
+    /// The reading as it was pushed, if it was a spike and was replaced.
+    pub suppressed: Option<Measurement>,
+}
+
+/// Replaces single readings whose temperature jumps away from both of its neighbours and
+/// back again, the kind of glitch that makes it through checksums and filters.
+///
+/// Each reading is held until the next one is pushed. A held reading whose temperature is
+/// more than `threshold` degrees above both of its neighbours, or more than `threshold`
+/// below both of them, is replaced by the average temperature and humidity of its
+/// neighbours. The previous neighbour is the reading released before it, after any
+/// replacement. Two or more readings in a row away from their neighbours are treated as a
+/// genuine change and released as they are.
+#[derive(Debug, Clone)]
+pub struct SpikeSuppressor<T> {
+    threshold: f64,
+    previous: Option<Measurement>,
+    held: Option<(Measurement, T)>,
+}
+
+impl<T> SpikeSuppressor<T> {
+    /// Create a suppressor for readings more than `threshold` degrees celsius away from
+    /// both of their neighbours.
+    pub fn new(threshold: f64) -> Self {
+        Self {
+            threshold,
+            previous: None,
+            held: None,
+        }
+    }
+
+    /// Hold `m` and `item` until the next reading, releasing the reading held before them,
+    /// if any.
+    pub fn push(&mut self, m: Measurement, item: T) -> Option<Released<T>> {
+        let (held, held_item) = self.held.replace((m, item))?;
+        let replacement = match self.previous {
+            Some(previous) if self.is_spike(&previous, &held, &m) => Some(average(&previous, &m)),
+            _ => None,
+        };
+
+        Some(self.release(held, held_item, replacement))
+    }
+
+    /// Release the held reading as it is, if any, since it has no next neighbour to be
+    /// compared to, e.g. because the next read failed. The reading after it is compared
+    /// to the released one.
+    pub fn flush(&mut self) -> Option<Released<T>> {
+        let (held, item) = self.held.take()?;
+        Some(self.release(held, item, None))
+    }
+
+    /// Forget the held reading, without releasing it, and the reading before it.
+    pub fn reset(&mut self) {
+        self.previous = None;
+        self.held = None;
+    }
+
+    fn release(&mut self, held: Measurement, item: T, replacement: Option<Measurement>) -> Released<T> {
+        let measurement = replacement.unwrap_or(held);
+        self.previous = Some(measurement);
+        Released {
+            item,
+            measurement,
+            suppressed: replacement.map(|_| held),
+        }
+    }
+
+    fn is_spike(&self, previous: &Measurement, held: &Measurement, next: &Measurement) -> bool {
+        let held = f64::from(held.temperature);
+        let before = held - f64::from(previous.temperature);
+        let after = held - f64::from(next.temperature);
+
+        // Away from both neighbours, in the same direction
+        before.abs() > self.threshold && after.abs() > self.threshold && before.signum() == after.signum()
+    }
+}
+
+fn average(a: &Measurement, b: &Measurement) -> Measurement {
+    Measurement {
+        temperature: TemperatureCelsius::from((f64::from(a.temperature) + f64::from(b.temperature)) / 2.0),
+        humidity: Humidity::from((f64::from(a.humidity) + f64::from(b.humidity)) / 2.0),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Released, SpikeSuppressor};
+    use crate::sensor::core::{Humidity, Measurement, TemperatureCelsius};
+
+    fn m(temperature: f64, humidity: f64) -> Measurement {
+        Measurement {
+            temperature: TemperatureCelsius::from(temperature),
+            humidity: Humidity::from(humidity),
+        }
+    }
+
+    /// Push readings with their index as the item, returning the temperatures released
+    /// and indexes of readings that were replaced.
+    fn push_all(suppressor: &mut SpikeSuppressor<usize>, temperatures: &[f64]) -> (Vec<f64>, Vec<usize>) {
+        let mut released = Vec::new();
+        let mut suppressed = Vec::new();
+        for (i, t) in temperatures.iter().enumerate() {
+            if let Some(r) = suppressor.push(m(*t, 50.0), i) {
+                released.push(f64::from(r.measurement.temperature));
+                if r.suppressed.is_some() {
+                    suppressed.push(r.item);
+                }
+            }
+        }
+
+        (released, suppressed)
+    }
+
+    #[test]
+    fn test_spike_suppressor_holds_one_reading() {
+        let mut suppressor = SpikeSuppressor::new(2.0);
+        assert_eq!(None, suppressor.push(m(20.0, 50.0), "first"));
+        assert_eq!(
+            Some(Released {
+                item: "first",
+                measurement: m(20.0, 50.0),
+                suppressed: None,
+            }),
+            suppressor.push(m(20.5, 51.0), "second")
+        );
+        assert_eq!(
+            Some(Released {
+                item: "second",
+                measurement: m(20.5, 51.0),
+                suppressed: None,
+            }),
+            suppressor.flush()
+        );
+        assert_eq!(None, suppressor.flush());
+    }
+
+    #[test]
+    fn test_spike_suppressor_spike_up() {
+        let mut suppressor = SpikeSuppressor::new(2.0);
+        suppressor.push(m(20.0, 50.0), 0);
+        suppressor.push(m(35.0, 90.0), 1);
+
+        assert_eq!(
+            Some(Released {
+                item: 1,
+                measurement: m(20.5, 51.0),
+                suppressed: Some(m(35.0, 90.0)),
+            }),
+            suppressor.push(m(21.0, 52.0), 2)
+        );
+    }
+
+    #[test]
+    fn test_spike_suppressor_spike_down() {
+        let mut suppressor = SpikeSuppressor::new(2.0);
+        let (released, suppressed) = push_all(&mut suppressor, &[20.0, 20.0, -40.0, 20.0, 20.0]);
+        assert_eq!(vec![20.0, 20.0, 20.0, 20.0], released);
+        assert_eq!(vec![2], suppressed);
+    }
+
+    #[test]
+    fn test_spike_suppressor_within_threshold() {
+        let mut suppressor = SpikeSuppressor::new(2.0);
+        let (released, suppressed) = push_all(&mut suppressor, &[20.0, 22.0, 20.0, 17.5, 19.0, 20.0]);
+        assert_eq!(vec![20.0, 22.0, 20.0, 17.5, 19.0], released);
+        assert!(suppressed.is_empty());
+    }
+
+    #[test]
+    fn test_spike_suppressor_genuine_step() {
+        let mut suppressor = SpikeSuppressor::new(2.0);
+        let (released, suppressed) = push_all(&mut suppressor, &[20.0, 20.0, 30.0, 30.0, 30.0]);
+        assert_eq!(vec![20.0, 20.0, 30.0, 30.0], released);
+        assert!(suppressed.is_empty());
+    }
+
+    #[test]
+    fn test_spike_suppressor_ramp() {
+        // Far from both neighbours, but in opposite directions
+        let mut suppressor = SpikeSuppressor::new(2.0);
+        let (released, suppressed) = push_all(&mut suppressor, &[20.0, 25.0, 30.0, 35.0]);
+        assert_eq!(vec![20.0, 25.0, 30.0], released);
+        assert!(suppressed.is_empty());
+    }
+
+    #[test]
+    fn test_spike_suppressor_consecutive_spikes() {
+        let mut suppressor = SpikeSuppressor::new(2.0);
+        let (released, suppressed) = push_all(&mut suppressor, &[20.0, 35.0, 36.0, 20.0, 20.0]);
+        assert_eq!(vec![20.0, 35.0, 36.0, 20.0], released);
+        assert!(suppressed.is_empty());
+    }
+
+    #[test]
+    fn test_spike_suppressor_alternating_spikes() {
+        // Each spike is compared to the replacement of the one before it rather than the
+        // reading it replaced, otherwise the readings in between would look like spikes.
+        let mut suppressor = SpikeSuppressor::new(2.0);
+        let (released, suppressed) = push_all(&mut suppressor, &[20.0, 30.0, 20.0, 30.0, 20.0, 20.0]);
+        assert_eq!(vec![20.0, 20.0, 20.0, 20.0, 20.0], released);
+        assert_eq!(vec![1, 3], suppressed);
+    }
+
+    #[test]
+    fn test_spike_suppressor_first_reading() {
+        // The first reading has no previous neighbour so it's never a spike
+        let mut suppressor = SpikeSuppressor::new(2.0);
+        let (released, suppressed) = push_all(&mut suppressor, &[35.0, 20.0, 20.0]);
+        assert_eq!(vec![35.0, 20.0], released);
+        assert!(suppressed.is_empty());
+    }
+
+    #[test]
+    fn test_spike_suppressor_flush() {
+        // A flushed reading isn't compared to anything but the reading after it is
+        let mut suppressor = SpikeSuppressor::new(2.0);
+        suppressor.push(m(20.0, 50.0), 0);
+        suppressor.push(m(35.0, 50.0), 1);
+        let flushed = suppressor.flush().unwrap();
+        assert_eq!(m(35.0, 50.0), flushed.measurement);
+        assert_eq!(None, flushed.suppressed);
+
+        suppressor.push(m(20.0, 50.0), 2);
+        let released = suppressor.push(m(35.0, 50.0), 3).unwrap();
+        assert_eq!(m(35.0, 50.0), released.measurement);
+        assert_eq!(Some(m(20.0, 50.0)), released.suppressed);
+    }
+
+    #[test]
+    fn test_spike_suppressor_reset() {
+        let mut suppressor = SpikeSuppressor::new(2.0);
+        suppressor.push(m(20.0, 50.0), 0);
+        suppressor.push(m(35.0, 50.0), 1);
+        suppressor.reset();
+
+        assert_eq!(None, suppressor.flush());
+        assert_eq!(None, suppressor.push(m(20.0, 50.0), 2));
+        let released = suppressor.push(m(20.0, 50.0), 3).unwrap();
+        assert_eq!(2, released.item);
+        assert_eq!(None, released.suppressed);
+    }
+}
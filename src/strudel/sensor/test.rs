@@ -19,9 +19,11 @@
 #![cfg(test)]
 
 use crate::sensor::dht22::DATA_SIZE;
-use crate::sensor::DataPin;
-use rppal::gpio::Mode;
+use crate::sensor::{DataPin, I2cBus, I2cError, Level, PinMode, WaitTimeout};
+use std::collections::VecDeque;
 use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 const LOW_CYCLE_COUNT: u32 = 400;
 const ONE_CYCLE_COUNT: u32 = 600;
@@ -51,7 +53,7 @@ impl DataPin for TimeoutDataPin {
         // NOP
     }
 
-    fn set_mode(&mut self, _mode: Mode) {
+    fn set_mode(&mut self, _mode: PinMode) {
         // NOP
     }
 }
@@ -80,13 +82,114 @@ impl DataPin for NopDataPin {
         // NOP
     }
 
-    fn set_mode(&mut self, _mode: Mode) {
+    fn set_mode(&mut self, _mode: PinMode) {
         // NOP
     }
 }
 
+/// DataPin implementation that always times out and counts how many times the
+/// state of the pin was checked.
+#[derive(Default)]
+pub(crate) struct CountingTimeoutDataPin {
+    checks: Arc<AtomicUsize>,
+}
+
+impl CountingTimeoutDataPin {
+    pub(crate) fn checks(&self) -> Arc<AtomicUsize> {
+        self.checks.clone()
+    }
+}
+
+impl DataPin for CountingTimeoutDataPin {
+    fn is_low(&self) -> bool {
+        self.checks.fetch_add(1, Ordering::SeqCst);
+        true
+    }
+
+    fn is_high(&self) -> bool {
+        self.checks.fetch_add(1, Ordering::SeqCst);
+        true
+    }
+
+    fn pin(&self) -> u8 {
+        0
+    }
+
+    fn set_high(&mut self) {
+        // NOP
+    }
+
+    fn set_low(&mut self) {
+        // NOP
+    }
+
+    fn set_mode(&mut self, _mode: PinMode) {
+        // NOP
+    }
+}
+
+/// Change made to a `RecordingDataPin`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PinEvent {
+    High,
+    Low,
+    Mode(PinMode),
+}
+
+/// DataPin implementation that records when the pin was set high or low or had its
+/// mode changed, used to verify the timing of the start of a read.
+#[derive(Default)]
+pub(crate) struct RecordingDataPin {
+    events: Arc<Mutex<Vec<(PinEvent, Instant)>>>,
+    stuck_low: bool,
+}
+
+impl RecordingDataPin {
+    /// Create a pin that always reads low, causing reads to time out
+    pub(crate) fn stuck_low() -> Self {
+        Self {
+            stuck_low: true,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn events(&self) -> Arc<Mutex<Vec<(PinEvent, Instant)>>> {
+        self.events.clone()
+    }
+
+    fn record(&self, event: PinEvent) {
+        self.events.lock().unwrap().push((event, Instant::now()));
+    }
+}
+
+impl DataPin for RecordingDataPin {
+    fn is_low(&self) -> bool {
+        self.stuck_low
+    }
+
+    fn is_high(&self) -> bool {
+        false
+    }
+
+    fn pin(&self) -> u8 {
+        0
+    }
+
+    fn set_high(&mut self) {
+        self.record(PinEvent::High);
+    }
+
+    fn set_low(&mut self) {
+        self.record(PinEvent::Low);
+    }
+
+    fn set_mode(&mut self, mode: PinMode) {
+        self.record(PinEvent::Mode(mode));
+    }
+}
+
 /// DataPin implementation that uses expected sensor data to generate pulse counts.
-/// Used to verify behavior of Pulse::from_data_pin and Reading::from_pulses.
+/// Used to verify behavior of Pulse::from_data_pin and Reading::decode.
 pub(crate) struct MockDataPin {
     data: [u8; DATA_SIZE],
     bit_idx: AtomicUsize,
@@ -95,6 +198,9 @@ pub(crate) struct MockDataPin {
 
     init_high: AtomicU32,
     init_low: AtomicU32,
+    response_low: u32,
+    response_high: u32,
+    stall_at: Option<usize>,
 }
 
 impl MockDataPin {
@@ -106,9 +212,27 @@ impl MockDataPin {
             low_count: Default::default(),
             init_high: Default::default(),
             init_low: Default::default(),
+            response_low: 0,
+            response_high: 0,
+            stall_at: None,
         }
     }
 
+    /// Answer the start signal by staying low for `low` checks and then high for `high`
+    /// checks before sending data bits. Default zero for both.
+    pub(crate) fn response(mut self, low: u32, high: u32) -> Self {
+        self.response_low = low;
+        self.response_high = high;
+        self
+    }
+
+    /// Stay high forever once data bit `idx` (starting from zero) is reached, causing
+    /// reads to time out partway through.
+    pub(crate) fn stall_at(mut self, idx: usize) -> Self {
+        self.stall_at = Some(idx);
+        self
+    }
+
     fn is_current_bit_on(&self) -> bool {
         let idx = self.bit_idx.load(Ordering::SeqCst);
         let byte_idx = idx / 8;
@@ -126,11 +250,11 @@ impl MockDataPin {
 
 impl DataPin for MockDataPin {
     fn is_low(&self) -> bool {
-        // The initial low/high transition is discarded so immediately short-circuit
-        // here before getting into the actual pulse counts based on our data.
+        // The initial low/high transition is the response to the start signal so handle
+        // it here before getting into the actual pulse counts based on our data.
         let init = self.init_low.fetch_add(1, Ordering::SeqCst);
-        if init == 0 {
-            return false;
+        if init <= self.response_low {
+            return init < self.response_low;
         }
 
         // Return true for a fixed number of invocations then reset.
@@ -144,11 +268,15 @@ impl DataPin for MockDataPin {
     }
 
     fn is_high(&self) -> bool {
-        // The initial low/high transition is discarded so immediately short-circuit
-        // here before getting into the actual pulse counts based on our data.
+        // The initial low/high transition is the response to the start signal so handle
+        // it here before getting into the actual pulse counts based on our data.
         let init = self.init_high.fetch_add(1, Ordering::SeqCst);
-        if init == 0 {
-            return false;
+        if init <= self.response_high {
+            return init < self.response_high;
+        }
+
+        if self.stall_at == Some(self.bit_idx.load(Ordering::SeqCst)) {
+            return true;
         }
 
         // Look at the current bit and figure out if we should use the pulse count
@@ -183,7 +311,234 @@ impl DataPin for MockDataPin {
         // NOP
     }
 
-    fn set_mode(&mut self, _mode: Mode) {
+    fn set_mode(&mut self, _mode: PinMode) {
         // NOP
     }
 }
+
+/// DataPin implementation that answers every read with pulses encoding the same data,
+/// for tests that read a sensor more than once. Pulse widths are returned directly from
+/// `wait_while_level` so reads don't depend on how fast the pin is checked.
+#[derive(Debug)]
+pub(crate) struct ReplayDataPin {
+    data: [u8; DATA_SIZE],
+    step: AtomicUsize,
+}
+
+impl ReplayDataPin {
+    pub(crate) fn new(data: [u8; DATA_SIZE]) -> Self {
+        ReplayDataPin {
+            data,
+            step: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl DataPin for ReplayDataPin {
+    fn is_low(&self) -> bool {
+        false
+    }
+
+    fn is_high(&self) -> bool {
+        false
+    }
+
+    fn pin(&self) -> u8 {
+        0
+    }
+
+    fn set_high(&mut self) {
+        // NOP
+    }
+
+    fn set_low(&mut self) {
+        // NOP
+    }
+
+    fn set_mode(&mut self, mode: PinMode) {
+        // Reads start by driving the pin to signal the sensor
+        if mode == PinMode::Output {
+            self.step.store(0, Ordering::SeqCst);
+        }
+    }
+
+    fn wait_while_level(&self, level: Level, _max_cycles: u32) -> Result<u32, WaitTimeout> {
+        // The first low and high pulses are the response to the start signal, followed by
+        // a low and high pulse for each bit. High pulses longer than low ones are 1 bits.
+        let step = self.step.fetch_add(1, Ordering::SeqCst);
+        let bit = step.saturating_sub(2) / 2;
+        let on = self.data[bit / 8] & (0x80 >> (bit % 8)) != 0;
+
+        Ok(match level {
+            Level::High if step >= 2 && on => ONE_CYCLE_COUNT,
+            Level::High if step >= 2 => ZERO_CYCLE_COUNT,
+            _ => LOW_CYCLE_COUNT,
+        })
+    }
+}
+
+/// DataPin implementation that answers each wait with a short pulse until transition
+/// `stall_at` (starting from zero with the low pulse of the response to the start signal)
+/// is reached, then never changes level. Records the maximum number of cycles it was
+/// asked to wait for when it stalled, to verify which limit applies to each transition.
+#[derive(Debug)]
+pub(crate) struct StallDataPin {
+    stall_at: usize,
+    transition: AtomicUsize,
+    stalled_max: Arc<AtomicU32>,
+}
+
+impl StallDataPin {
+    pub(crate) fn new(stall_at: usize) -> Self {
+        StallDataPin {
+            stall_at,
+            transition: AtomicUsize::new(0),
+            stalled_max: Arc::new(AtomicU32::new(0)),
+        }
+    }
+
+    pub(crate) fn stalled_max(&self) -> Arc<AtomicU32> {
+        self.stalled_max.clone()
+    }
+}
+
+impl DataPin for StallDataPin {
+    fn is_low(&self) -> bool {
+        false
+    }
+
+    fn is_high(&self) -> bool {
+        false
+    }
+
+    fn pin(&self) -> u8 {
+        0
+    }
+
+    fn set_high(&mut self) {
+        // NOP
+    }
+
+    fn set_low(&mut self) {
+        // NOP
+    }
+
+    fn set_mode(&mut self, _mode: PinMode) {
+        // NOP
+    }
+
+    fn wait_while_level(&self, _level: Level, max_cycles: u32) -> Result<u32, WaitTimeout> {
+        if self.transition.fetch_add(1, Ordering::SeqCst) == self.stall_at {
+            self.stalled_max.store(max_cycles, Ordering::SeqCst);
+            Err(WaitTimeout)
+        } else {
+            Ok(LOW_CYCLE_COUNT)
+        }
+    }
+}
+
+/// Transaction performed on a `MockI2cBus`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum I2cTransaction {
+    Write { address: u8, bytes: Vec<u8> },
+    Read { address: u8, len: usize },
+    WriteRead { address: u8, bytes: Vec<u8>, len: usize },
+}
+
+/// Reply to a transaction on a `MockI2cBus`
+#[derive(Debug)]
+enum I2cReply {
+    Bytes(Vec<u8>),
+    Nack,
+    BusError,
+}
+
+/// I2cBus implementation that records each transaction and answers it with the next
+/// reply from a script, in order. Used to verify sensors that are read over I2C.
+///
+/// Writes are answered with an empty reply and reads copy the bytes of the reply into
+/// the buffer being read. Panics if there's no reply left or the length of a reply
+/// doesn't match the buffer being read.
+#[derive(Default)]
+pub(crate) struct MockI2cBus {
+    replies: VecDeque<I2cReply>,
+    transactions: Arc<Mutex<Vec<I2cTransaction>>>,
+}
+
+impl MockI2cBus {
+    /// Answer the next transaction with `bytes`, empty for writes.
+    pub(crate) fn reply(mut self, bytes: &[u8]) -> Self {
+        self.replies.push_back(I2cReply::Bytes(bytes.to_vec()));
+        self
+    }
+
+    /// Answer the next transaction as if the device didn't acknowledge it.
+    pub(crate) fn nack(mut self) -> Self {
+        self.replies.push_back(I2cReply::Nack);
+        self
+    }
+
+    /// Answer the next transaction with an error using the bus.
+    pub(crate) fn bus_error(mut self) -> Self {
+        self.replies.push_back(I2cReply::BusError);
+        self
+    }
+
+    pub(crate) fn transactions(&self) -> Arc<Mutex<Vec<I2cTransaction>>> {
+        self.transactions.clone()
+    }
+
+    fn next(&mut self, transaction: I2cTransaction, buffer: &mut [u8]) -> Result<(), I2cError> {
+        let address = match &transaction {
+            I2cTransaction::Write { address, .. } => *address,
+            I2cTransaction::Read { address, .. } => *address,
+            I2cTransaction::WriteRead { address, .. } => *address,
+        };
+
+        let reply = self
+            .replies
+            .pop_front()
+            .unwrap_or_else(|| panic!("no reply scripted for {:?}", transaction));
+        self.transactions.lock().unwrap().push(transaction);
+
+        match reply {
+            I2cReply::Bytes(bytes) => {
+                assert_eq!(buffer.len(), bytes.len(), "length of reply doesn't match buffer");
+                buffer.copy_from_slice(&bytes);
+                Ok(())
+            }
+            I2cReply::Nack => Err(I2cError::Nack(address)),
+            I2cReply::BusError => Err(I2cError::Bus(address, "arbitration lost".into())),
+        }
+    }
+}
+
+impl I2cBus for MockI2cBus {
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), I2cError> {
+        let transaction = I2cTransaction::Write {
+            address,
+            bytes: bytes.to_vec(),
+        };
+
+        self.next(transaction, &mut [])
+    }
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), I2cError> {
+        let transaction = I2cTransaction::Read {
+            address,
+            len: buffer.len(),
+        };
+
+        self.next(transaction, buffer)
+    }
+
+    fn write_read(&mut self, address: u8, bytes: &[u8], buffer: &mut [u8]) -> Result<(), I2cError> {
+        let transaction = I2cTransaction::WriteRead {
+            address,
+            bytes: bytes.to_vec(),
+            len: buffer.len(),
+        };
+
+        self.next(transaction, buffer)
+    }
+}
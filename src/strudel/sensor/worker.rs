@@ -0,0 +1,2008 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+use crate::clock::{Clock, SystemClock};
+use crate::device::PulseTiming;
+use crate::sensor::asynchronous::AsyncSensor;
+use crate::sensor::calibration::{Calibration, Clamped};
+use crate::sensor::core::{
+    Humidity, Measurement, PulseStats, RawReading, Sensor, SensorError, SensorErrorKind, TemperatureCelsius,
+};
+use crate::sensor::dedup::{DedupLogger, DEFAULT_ERROR_LOG_INTERVAL};
+use crate::sensor::filter::MadFilter;
+use crate::sensor::spike::{Released, SpikeSuppressor};
+use std::fmt::{self, Formatter};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::{oneshot, watch, Notify};
+use tokio::task::{self, JoinHandle};
+use tokio::time::MissedTickBehavior;
+use tokio_stream::wrappers::WatchStream;
+use tokio_stream::Stream;
+use tracing::{Instrument, Level, Span};
+
+/// Number of events buffered for each subscriber before new events are dropped.
+const SUBSCRIBER_BUFFER: usize = 16;
+
+type TickHandler = Box<dyn FnMut() + Send>;
+type ResetHandler = Box<dyn FnMut(Option<f64>) + Send>;
+type ReadHandler = Box<dyn FnMut(&ReadingEvent) + Send>;
+type Subscriber = Box<dyn FnMut(&ReadingEvent) + Send>;
+type CanaryHandler = Box<dyn FnMut(&Measurement, &Result<Measurement, SensorError>) + Send>;
+type SpikeHandler = Box<dyn FnMut(&Measurement, &Measurement) + Send>;
+type SwapOpener<S> = Box<dyn FnOnce() -> Result<SensorSwap<S>, SensorError> + Send>;
+type SwapRequest<S> = (SwapOpener<S>, oneshot::Sender<Result<(), SensorError>>);
+
+/// Event sent to subscribers along with the requests for a read waiting on it. Each
+/// request is notified by its sender being dropped, which happens once every subscriber
+/// has handled the event or dropped it.
+struct Delivery {
+    event: Arc<ReadingEvent>,
+    _waiting: Vec<oneshot::Sender<()>>,
+}
+
+/// Second read of the sensor after each successful read, decoded with another pulse
+/// timing, see `SensorWorker::canary`.
+struct Canary {
+    timing: PulseTiming,
+    delay: Duration,
+    handler: CanaryHandler,
+}
+
+/// Readings held for one read to replace spikes, see `SensorWorker::suppress_spikes`.
+struct Spikes {
+    threshold: f64,
+    suppressor: SpikeSuppressor<ReadingEvent>,
+    handler: SpikeHandler,
+}
+
+impl Spikes {
+    /// Hold the event of a successful read, or release the held event before that of a
+    /// failed read, returning the events to publish in order.
+    fn hold(&mut self, event: ReadingEvent) -> Vec<ReadingEvent> {
+        match event.result {
+            Ok(m) => self
+                .suppressor
+                .push(m, event)
+                .map(|r| self.replace(r))
+                .into_iter()
+                .collect(),
+            Err(_) => {
+                let held = self.suppressor.flush().map(|r| self.replace(r));
+                held.into_iter().chain(Some(event)).collect()
+            }
+        }
+    }
+
+    fn replace(&mut self, released: Released<ReadingEvent>) -> ReadingEvent {
+        let mut event = released.item;
+        if let Some(spike) = released.suppressed {
+            tracing::debug!(message = "replaced spike in readings", spike = %spike, replacement = %released.measurement);
+            (self.handler)(&spike, &released.measurement);
+            event.result = Ok(released.measurement);
+        }
+
+        event
+    }
+}
+
+/// Channels with the most recent results of reads, watched via a `WorkerHandle`.
+struct Published {
+    latest: watch::Sender<Option<Measurement>>,
+    events: watch::Sender<Option<Arc<ReadingEvent>>>,
+}
+
+/// The result of reading a sensor, when it happened, and how many attempts it took.
+///
+/// When more than one sample is read per refresh, a single event covers all of them:
+/// the result is the median of successful samples and reads of samples that failed are
+/// included in `retried_errors` unless they caused the whole read to fail.
+#[derive(Debug)]
+pub struct ReadingEvent {
+    /// When the read finished according to the system clock. Only meant to be exported,
+    /// use `instant` to compute how long ago the read happened.
+    pub timestamp: SystemTime,
+    /// When the read finished according to the monotonic clock.
+    pub instant: Instant,
+    pub result: Result<Measurement, SensorError>,
+    /// Values of a successful result that were outside the range of the sensor after
+    /// being calibrated and were clamped to it.
+    pub clamped: Clamped,
+    /// Number of times the sensor was read, including retries and every sample. Always
+    /// at least one.
+    pub attempts: u32,
+    /// Kinds of errors for each failed read before the final result, in order. Empty
+    /// unless the sensor was read more than once.
+    pub retried_errors: Vec<SensorErrorKind>,
+    /// Time taken by every attempt to read the sensor, including waiting between retries.
+    pub duration: Duration,
+    /// Bytes decoded by each attempt that got far enough to decode any, in order,
+    /// including attempts that failed because of an invalid checksum.
+    pub raw: Vec<RawReading>,
+    /// Statistics of the pulses captured by the last attempt that captured every one of
+    /// them, if the sensor exposes them, see `Sensor::last_pulses`.
+    pub pulses: Option<PulseStats>,
+    /// Span covering every attempt to read the sensor, used to link metrics to traces.
+    pub span: Span,
+}
+
+/// Periodically read a sensor in the background and notify subscribers of the readings.
+///
+/// Reads happen via an `AsyncSensor` at a fixed interval or on demand via
+/// `WorkerHandle::trigger_read` or a `ReadRequester`. Handlers added with `on_read` run inline after each
+/// read while subscribers added with `subscribe` run on their own threads. Async code can
+/// consume events via `WorkerHandle::stream` instead.
+pub struct SensorWorker<S> {
+    sensor: AsyncSensor<S>,
+    interval: Duration,
+    initial_delay: Duration,
+    retries: u32,
+    retry_delay: Duration,
+    samples: u32,
+    min_samples: u32,
+    sample_delay: Duration,
+    read_budget: Option<Duration>,
+    on_demand: Option<Duration>,
+    calibration: Calibration,
+    filter: Option<MadFilter>,
+    error_log_interval: Duration,
+    canary: Option<Canary>,
+    spikes: Option<Spikes>,
+    clock: Arc<dyn Clock>,
+    reset: Arc<AtomicBool>,
+    tick_handlers: Vec<TickHandler>,
+    reset_handlers: Vec<ResetHandler>,
+    handlers: Vec<ReadHandler>,
+    subscribers: Vec<Subscriber>,
+    swaps_tx: mpsc::UnboundedSender<SwapRequest<S>>,
+    swaps: mpsc::UnboundedReceiver<SwapRequest<S>>,
+}
+
+impl<S> SensorWorker<S>
+where
+    S: Sensor,
+{
+    pub fn new(sensor: S, interval: Duration) -> Self {
+        Self::from_async(AsyncSensor::new(sensor), interval)
+    }
+
+    /// Create a worker reading a sensor that's already owned by its own thread, for
+    /// example because it was read before starting the worker.
+    pub fn from_async(sensor: AsyncSensor<S>, interval: Duration) -> Self {
+        let calibration = Calibration::new(sensor.ranges());
+        let (swaps_tx, swaps) = mpsc::unbounded_channel();
+        Self {
+            sensor,
+            interval,
+            initial_delay: Duration::ZERO,
+            retries: 0,
+            retry_delay: Duration::ZERO,
+            samples: 1,
+            min_samples: 1,
+            sample_delay: Duration::ZERO,
+            read_budget: None,
+            on_demand: None,
+            calibration,
+            filter: None,
+            error_log_interval: DEFAULT_ERROR_LOG_INTERVAL,
+            canary: None,
+            spikes: None,
+            clock: SystemClock::shared(),
+            reset: Arc::new(AtomicBool::new(false)),
+            tick_handlers: Vec::new(),
+            reset_handlers: Vec::new(),
+            handlers: Vec::new(),
+            subscribers: Vec::new(),
+            swaps_tx,
+            swaps,
+        }
+    }
+
+    /// Create a worker and start it with no handlers or subscribers.
+    pub fn spawn(sensor: S, interval: Duration) -> WorkerHandle {
+        Self::new(sensor, interval).start()
+    }
+
+    /// Wait `delay` before the first read instead of reading immediately when started.
+    pub fn initial_delay(mut self, delay: Duration) -> Self {
+        self.initial_delay = delay;
+        self
+    }
+
+    /// Retry failed reads up to `retries` times, waiting `delay` before each retry.
+    /// Handlers and subscribers only see the result of the final attempt.
+    pub fn read_retries(mut self, retries: u32, delay: Duration) -> Self {
+        self.retries = retries;
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Read `samples` samples each interval, waiting `delay` between them, and publish
+    /// the median temperature and humidity of the successful ones. At least `min_samples`
+    /// samples must succeed, otherwise the read fails. Each sample is retried as set by
+    /// `read_retries`: retries count toward the attempts of a sample, they don't replace
+    /// samples that failed. Defaults to a single sample.
+    pub fn samples_per_refresh(mut self, samples: u32, min_samples: u32, delay: Duration) -> Self {
+        self.samples = samples.max(1);
+        self.min_samples = min_samples.clamp(1, self.samples);
+        self.sample_delay = delay;
+        self
+    }
+
+    /// Give up on reading the sensor, including any remaining retries and samples, if
+    /// reading takes longer than `budget` in total. Defaults to half the interval between
+    /// reads.
+    pub fn read_budget(mut self, budget: Duration) -> Self {
+        self.read_budget = Some(budget);
+        self
+    }
+
+    /// Only read the sensor on demand instead of every interval. Requests for a read made
+    /// via a `ReadRequester` within `reuse` of the end of the previous read are answered
+    /// with that read instead of reading the sensor again. The interval is still used for
+    /// the default read budget.
+    pub fn on_demand(mut self, reuse: Duration) -> Self {
+        self.on_demand = Some(reuse);
+        self
+    }
+
+    /// Apply `calibration` to successful reads before handlers and subscribers see them.
+    /// Defaults to only clamping measurements to the range of the sensor.
+    pub fn calibration(mut self, calibration: Calibration) -> Self {
+        self.calibration = calibration;
+        self
+    }
+
+    /// Reject calibrated readings that are outliers according to `filter`, reporting them
+    /// as `SensorErrorKind::Implausible` errors. The filter is reset when the sensor is
+    /// replaced. By default, all readings are accepted.
+    pub fn filter(mut self, filter: MadFilter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Log the same read error at most once per `interval` while it keeps repeating, see
+    /// `DedupLogger`. Handlers and subscribers still see every error. Default 5 minutes.
+    pub fn error_log_interval(mut self, interval: Duration) -> Self {
+        self.error_log_interval = interval;
+        self
+    }
+
+    /// Read the sensor again `delay` after each successful read, decoding pulses with
+    /// `timing`, see `Sensor::read_with_timing`, and run `handler` with the published
+    /// measurement and the calibrated result of the second read. Second reads aren't
+    /// retried, filtered, or seen by other handlers and subscribers. Handlers are called
+    /// from the background task and must not block.
+    pub fn canary<F>(mut self, timing: PulseTiming, delay: Duration, handler: F) -> Self
+    where
+        F: FnMut(&Measurement, &Result<Measurement, SensorError>) + Send + 'static,
+    {
+        self.canary = Some(Canary {
+            timing,
+            delay,
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    /// Hold each successful reading until the next read and replace it with the average of
+    /// the readings before and after it if it's a spike, more than `threshold` degrees
+    /// celsius away from both of them in the same direction, see `SpikeSuppressor`. Run
+    /// `handler` with each spike and its replacement.
+    ///
+    /// Handlers and subscribers see each reading one read later than they would otherwise,
+    /// with the time it was read. A failed read releases the held reading as it is, before
+    /// the error. The held reading is dropped when the sensor is replaced. Handlers are
+    /// called from the background task and must not block.
+    pub fn suppress_spikes<F>(mut self, threshold: f64, handler: F) -> Self
+    where
+        F: FnMut(&Measurement, &Measurement) + Send + 'static,
+    {
+        self.spikes = Some(Spikes {
+            threshold,
+            suppressor: SpikeSuppressor::new(threshold),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    /// Use `clock` for the times of reading events. Scheduling of reads always uses the
+    /// Tokio clock. Defaults to the system clock.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Run `handler` each time the worker wakes up to read the sensor, before reading it.
+    /// Handlers are called from the background task and must not block.
+    pub fn on_tick<F>(mut self, handler: F) -> Self
+    where
+        F: FnMut() + Send + 'static,
+    {
+        self.tick_handlers.push(Box::new(handler));
+        self
+    }
+
+    /// Run `handler` each time the sensor is reset via `WorkerHandle::reset_sensor`, with
+    /// the timing calibration of the sensor afterwards, see `Sensor::cycles_per_us`.
+    /// Handlers are called from the background task and must not block.
+    pub fn on_reset<F>(mut self, handler: F) -> Self
+    where
+        F: FnMut(Option<f64>) + Send + 'static,
+    {
+        self.reset_handlers.push(Box::new(handler));
+        self
+    }
+
+    /// Run `handler` with an event for each read of the sensor, successful or not.
+    ///
+    /// Handlers are called from the background task, before any subscriber is sent the
+    /// event, and must not block. Unlike subscribers they never miss events, so anything
+    /// that has to count every read, like the counters of `TemperatureMetrics`, should be
+    /// a handler.
+    pub fn on_read<F>(mut self, handler: F) -> Self
+    where
+        F: FnMut(&ReadingEvent) + Send + 'static,
+    {
+        self.handlers.push(Box::new(handler));
+        self
+    }
+
+    /// Run `subscriber` with an event for each read of the sensor, successful or not.
+    ///
+    /// Each subscriber runs on its own thread and is sent events over a bounded channel.
+    /// Subscribers that fall behind miss events instead of slowing down reads of the
+    /// sensor, see `WorkerHandle::dropped_events`.
+    pub fn subscribe<F>(mut self, subscriber: F) -> Self
+    where
+        F: FnMut(&ReadingEvent) + Send + 'static,
+    {
+        self.subscribers.push(Box::new(subscriber));
+        self
+    }
+
+    /// Get a `SensorSwapper` for replacing the sensor once the worker has been started.
+    pub fn swapper(&self) -> SensorSwapper<S> {
+        SensorSwapper {
+            swaps: self.swaps_tx.clone(),
+        }
+    }
+
+    /// Start reading the sensor in a background task. The first read happens immediately
+    /// unless an initial delay has been set. This method must be called from within a
+    /// Tokio runtime.
+    pub fn start(mut self) -> WorkerHandle {
+        let (latest_tx, latest_rx) = watch::channel(None);
+        let (events_tx, events_rx) = watch::channel(None);
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let (requests_tx, requests_rx) = mpsc::unbounded_channel();
+        let trigger = Arc::new(Notify::new());
+        let dropped = Arc::new(AtomicU64::new(0));
+
+        let mut senders = Vec::with_capacity(self.subscribers.len());
+        let mut tasks = Vec::with_capacity(self.subscribers.len());
+        for mut subscriber in self.subscribers.drain(..) {
+            let (tx, mut rx) = mpsc::channel::<Arc<Delivery>>(SUBSCRIBER_BUFFER);
+            senders.push(tx);
+            tasks.push(thread::spawn(move || {
+                while let Some(delivery) = rx.blocking_recv() {
+                    subscriber(&delivery.event);
+                }
+            }));
+        }
+
+        let published = Published {
+            latest: latest_tx,
+            events: events_tx,
+        };
+
+        let reset = self.reset.clone();
+        let task = tokio::spawn(self.run(
+            published,
+            senders,
+            dropped.clone(),
+            trigger.clone(),
+            requests_rx,
+            shutdown_rx,
+        ));
+
+        WorkerHandle {
+            latest: latest_rx,
+            events: events_rx,
+            trigger,
+            reset,
+            requests: requests_tx,
+            dropped,
+            shutdown: shutdown_tx,
+            task,
+            subscribers: tasks,
+        }
+    }
+
+    async fn run(
+        mut self,
+        published: Published,
+        subscribers: Vec<mpsc::Sender<Arc<Delivery>>>,
+        dropped: Arc<AtomicU64>,
+        trigger: Arc<Notify>,
+        mut requests: mpsc::UnboundedReceiver<oneshot::Sender<()>>,
+        mut shutdown: oneshot::Receiver<()>,
+    ) {
+        // There's no sensor to read if opening a replacement for it failed
+        let inline = self.sensor.is_inline();
+        let mut sensor = Some(self.sensor);
+        let start = tokio::time::Instant::now() + self.initial_delay;
+        let mut interval = tokio::time::interval_at(start, self.interval);
+        let mut budget = self.read_budget.unwrap_or(self.interval / 2);
+
+        // Reads that take longer than the interval shouldn't cause a burst of reads to
+        // catch up since the sensor can only be read every few seconds.
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let periodic = self.on_demand.is_none();
+        let mut last_read: Option<tokio::time::Instant> = None;
+        let mut errors = DedupLogger::new(self.error_log_interval, self.clock.clone());
+
+        loop {
+            let mut waiting = Vec::new();
+            tokio::select! {
+                _ = interval.tick(), if periodic => {}
+                _ = trigger.notified() => {}
+                Some(req) = requests.recv() => {
+                    // Answer every request made so far with a single read
+                    waiting.push(req);
+                    while let Ok(req) = requests.try_recv() {
+                        waiting.push(req);
+                    }
+
+                    let reuse = self.on_demand.unwrap_or(Duration::ZERO);
+                    if last_read.map(|t| t.elapsed() < reuse).unwrap_or(false) {
+                        tracing::debug!(message = "reusing recent read of sensor", requests = waiting.len());
+                        continue;
+                    }
+                }
+                Some((open, done)) = self.swaps.recv() => {
+                    // Release anything the current sensor holds, like its pin, before opening
+                    // the replacement since it may need the same ones.
+                    if let Some(old) = sensor.take() {
+                        old.close().await;
+                    }
+
+                    let res = match task::spawn_blocking(open).await {
+                        Ok(r) => r,
+                        Err(e) => Err(SensorError::with_cause(
+                            SensorErrorKind::Internal,
+                            "unable to open replacement sensor",
+                            e.to_string(),
+                        )),
+                    };
+
+                    let res = res.map(|swap| {
+                        tracing::info!(message = "replaced sensor", interval = ?swap.interval);
+                        // Replacements are read the same way as the sensor they replace
+                        sensor = Some(if inline {
+                            AsyncSensor::inline(swap.sensor)
+                        } else {
+                            AsyncSensor::new(swap.sensor)
+                        });
+                        self.interval = swap.interval;
+                        self.calibration = swap.calibration;
+                        if let Some(f) = &mut self.filter {
+                            f.reset();
+                        }
+                        if let Some(s) = &mut self.spikes {
+                            s.suppressor.reset();
+                        }
+                        budget = self.read_budget.unwrap_or(self.interval / 2);
+                        interval = tokio::time::interval_at(tokio::time::Instant::now(), self.interval);
+                        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+                    });
+
+                    if let Err(e) = &res {
+                        tracing::error!(message = "unable to open replacement sensor, reads will fail until it is replaced", error = %e);
+                    }
+
+                    let _ = done.send(res);
+                    continue;
+                }
+                _ = &mut shutdown => break,
+            }
+
+            for handler in self.tick_handlers.iter_mut() {
+                handler();
+            }
+
+            if self.reset.swap(false, Ordering::SeqCst) {
+                if let Some(s) = &sensor {
+                    tracing::info!(message = "resetting sensor");
+                    let cycles_per_us = s.reset().await;
+                    for handler in self.reset_handlers.iter_mut() {
+                        handler(cycles_per_us);
+                    }
+                }
+            }
+
+            let mut attempts = 0;
+            let mut completed = 0;
+            let mut failed = Vec::new();
+            let mut last_error = None;
+            let mut last_failed = false;
+            let mut raw = Vec::new();
+            let mut pulses = None;
+            let mut samples = Vec::with_capacity(self.samples as usize);
+            let span = tracing::span!(Level::DEBUG, "sensor_read");
+            let retries = self.retries;
+            let retry_delay = self.retry_delay;
+            let sample_delay = self.sample_delay;
+            let num_samples = self.samples;
+            let started = tokio::time::Instant::now();
+
+            let cycle = async {
+                for sample in 1..=num_samples {
+                    if sample > 1 {
+                        tokio::time::sleep(sample_delay).await;
+                    }
+
+                    let mut sample_attempts = 0;
+                    loop {
+                        attempts += 1;
+                        sample_attempts += 1;
+                        let Some(sensor) = &sensor else {
+                            last_error = Some(SensorError::initialization(
+                                "no sensor since opening its replacement failed, replace it to read again",
+                            ));
+                            break;
+                        };
+
+                        let read = sensor
+                            .read_raw()
+                            .instrument(tracing::span!(
+                                parent: &span,
+                                Level::DEBUG,
+                                "sensor_read_attempt",
+                                attempt = attempts,
+                                sample = sample,
+                            ))
+                            .await;
+                        completed += 1;
+                        last_failed = read.result.is_err();
+                        raw.extend(read.raw);
+                        pulses = read.pulses.or(pulses);
+
+                        match read.result {
+                            Ok(m) => {
+                                samples.push(m);
+                                break;
+                            }
+                            Err(e) => {
+                                failed.push(e.kind());
+                                if sample_attempts > retries {
+                                    tracing::debug!(message = "sensor sample failed", sample = sample, error = %e);
+                                    last_error = Some(e);
+                                    break;
+                                }
+
+                                tracing::debug!(message = "sensor read failed, retrying", attempt = attempts, error = %e);
+                                last_error = Some(e);
+                                tokio::time::sleep(retry_delay).await;
+                            }
+                        }
+                    }
+                }
+            };
+
+            let timed_out = tokio::time::timeout(budget, cycle).await.is_err();
+            if timed_out {
+                tracing::debug!(message = "sensor read budget exceeded", attempts = attempts, budget = ?budget);
+            }
+
+            let min_samples = self.min_samples as usize;
+            let mut retried_errors = failed;
+            let res = match median_measurement(&samples) {
+                Some(m) if samples.len() >= min_samples => Ok(m),
+                _ => {
+                    // The last failed read is the final one unless the budget ran out. If it
+                    // ran out while waiting to retry a failed read, that read is the final one
+                    // so it shouldn't be counted as retried.
+                    let waiting_after_failure = completed == attempts && last_failed;
+                    if !timed_out || waiting_after_failure {
+                        retried_errors.pop();
+                    }
+
+                    match last_error {
+                        Some(e) if !timed_out => Err(e),
+                        _ => Err(SensorError::timeout("read budget exceeded")),
+                    }
+                }
+            };
+
+            let (res, clamped) = self.calibration.apply_result(res);
+            let res = match &mut self.filter {
+                Some(f) => f.apply_result(res),
+                None => res,
+            };
+
+            errors.log(&res);
+            let primary = res.as_ref().ok().copied();
+            last_read = Some(tokio::time::Instant::now());
+            let event = ReadingEvent {
+                timestamp: self.clock.now_wall(),
+                instant: self.clock.now_monotonic(),
+                result: res,
+                clamped,
+                attempts,
+                retried_errors,
+                duration: started.elapsed(),
+                raw,
+                pulses,
+                span,
+            };
+
+            // Events held back to be compared to the next read keep the time they were read
+            let events = match &mut self.spikes {
+                Some(spikes) => spikes.hold(event),
+                None => vec![event],
+            };
+
+            // Requests for a read are answered once the events published for it have been
+            // handled, or right away if the read is being held.
+            let last = events.len().saturating_sub(1);
+            let mut waiting = Some(waiting);
+            for (i, event) in events.into_iter().enumerate() {
+                for handler in self.handlers.iter_mut() {
+                    handler(&event);
+                }
+
+                if let Ok(m) = &event.result {
+                    published.latest.send_replace(Some(*m));
+                }
+
+                let event = Arc::new(event);
+                published.events.send_replace(Some(event.clone()));
+                let delivery = Arc::new(Delivery {
+                    event,
+                    _waiting: if i == last {
+                        waiting.take().unwrap_or_default()
+                    } else {
+                        Vec::new()
+                    },
+                });
+
+                for tx in subscribers.iter() {
+                    if let Err(TrySendError::Full(_)) = tx.try_send(delivery.clone()) {
+                        dropped.fetch_add(1, Ordering::Relaxed);
+                        tracing::warn!(message = "dropped reading event for slow subscriber");
+                    }
+                }
+            }
+
+            // Read the sensor a second time with the other pulse timing once it can be read
+            // again, comparing the result to what was just published. Canary reads get the
+            // same budget as regular reads.
+            if let (Some(canary), Some(s), Some(primary)) = (&mut self.canary, &sensor, primary) {
+                let read = async {
+                    tokio::time::sleep(canary.delay).await;
+                    tokio::time::timeout(budget, s.read_with_timing(canary.timing))
+                        .await
+                        .unwrap_or_else(|_| Err(SensorError::timeout("canary read budget exceeded")))
+                };
+
+                tokio::select! {
+                    res = read => {
+                        let (res, _) = self.calibration.apply_result(res);
+                        if let Err(e) = &res {
+                            tracing::debug!(message = "canary read of sensor failed", timing = ?canary.timing, error = %e);
+                        }
+
+                        (canary.handler)(&primary, &res);
+                    }
+                    _ = &mut shutdown => break,
+                }
+            }
+        }
+    }
+}
+
+impl<S> fmt::Debug for SensorWorker<S>
+where
+    S: fmt::Debug,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SensorWorker")
+            .field("sensor", &self.sensor)
+            .field("interval", &self.interval)
+            .field("initial_delay", &self.initial_delay)
+            .field("retries", &self.retries)
+            .field("retry_delay", &self.retry_delay)
+            .field("samples", &self.samples)
+            .field("min_samples", &self.min_samples)
+            .field("sample_delay", &self.sample_delay)
+            .field("read_budget", &self.read_budget)
+            .field("on_demand", &self.on_demand)
+            .field("calibration", &self.calibration)
+            .field("filter", &self.filter)
+            .field("canary", &self.canary.as_ref().map(|c| c.timing))
+            .field("spike_threshold", &self.spikes.as_ref().map(|s| s.threshold))
+            .field("clock", &self.clock)
+            .field("tick_handlers", &self.tick_handlers.len())
+            .field("reset_handlers", &self.reset_handlers.len())
+            .field("handlers", &self.handlers.len())
+            .field("subscribers", &self.subscribers.len())
+            .finish()
+    }
+}
+
+/// Median of `values`: the middle value of an odd number of values or the mean of the
+/// two middle values of an even number. `None` if there are no values.
+pub fn median(values: &[f64]) -> Option<f64> {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+
+    let mid = sorted.len() / 2;
+    match sorted.len() {
+        0 => None,
+        n if n % 2 == 1 => Some(sorted[mid]),
+        _ => Some((sorted[mid - 1] + sorted[mid]) / 2.0),
+    }
+}
+
+/// Median temperature and humidity of `samples`, each computed independently.
+fn median_measurement(samples: &[Measurement]) -> Option<Measurement> {
+    let temperatures: Vec<f64> = samples.iter().map(|m| f64::from(m.temperature)).collect();
+    let humidities: Vec<f64> = samples.iter().map(|m| f64::from(m.humidity)).collect();
+
+    Some(Measurement {
+        temperature: TemperatureCelsius::from(median(&temperatures)?),
+        humidity: Humidity::from(median(&humidities)?),
+    })
+}
+
+/// Sensor, and settings specific to it, that replace those of a running `SensorWorker`.
+#[derive(Debug)]
+pub struct SensorSwap<S> {
+    pub sensor: S,
+    pub interval: Duration,
+    pub calibration: Calibration,
+}
+
+/// Replaces the sensor read by a running `SensorWorker`, from `SensorWorker::swapper`.
+pub struct SensorSwapper<S> {
+    swaps: mpsc::UnboundedSender<SwapRequest<S>>,
+}
+
+impl<S> SensorSwapper<S>
+where
+    S: Sensor,
+{
+    /// Replace the sensor once any read in progress is complete. The current sensor is
+    /// dropped before `open` is called, on a blocking thread, so that the replacement can
+    /// use the same pin. The first read of the replacement happens immediately and then
+    /// at its interval. If `open` fails, reads fail until the sensor is replaced again.
+    ///
+    /// Returns a receiver that completes with the result of `open`, or with an error if
+    /// the worker has stopped.
+    pub fn swap<F>(&self, open: F) -> oneshot::Receiver<Result<(), SensorError>>
+    where
+        F: FnOnce() -> Result<SensorSwap<S>, SensorError> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.swaps.send((Box::new(open), tx));
+        rx
+    }
+}
+
+impl<S> Clone for SensorSwapper<S> {
+    fn clone(&self) -> Self {
+        Self {
+            swaps: self.swaps.clone(),
+        }
+    }
+}
+
+impl<S> fmt::Debug for SensorSwapper<S> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SensorSwapper").finish_non_exhaustive()
+    }
+}
+
+/// Handle to a running `SensorWorker` used to get readings or control it.
+#[derive(Debug)]
+pub struct WorkerHandle {
+    latest: watch::Receiver<Option<Measurement>>,
+    events: watch::Receiver<Option<Arc<ReadingEvent>>>,
+    trigger: Arc<Notify>,
+    reset: Arc<AtomicBool>,
+    requests: mpsc::UnboundedSender<oneshot::Sender<()>>,
+    dropped: Arc<AtomicU64>,
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+    subscribers: Vec<thread::JoinHandle<()>>,
+}
+
+impl WorkerHandle {
+    /// Get a receiver for the most recent successful measurement, `None` if there
+    /// have been no successful reads yet.
+    pub fn latest(&self) -> watch::Receiver<Option<Measurement>> {
+        self.latest.clone()
+    }
+
+    /// Get a receiver for the event of the most recent read of the sensor, successful or
+    /// not, `None` if the sensor hasn't been read yet.
+    pub fn events(&self) -> watch::Receiver<Option<Arc<ReadingEvent>>> {
+        self.events.clone()
+    }
+
+    /// Get a stream of events for reads of the sensor, successful or not, that happen
+    /// after this is called. See `LatestStream` for how slow consumers are handled.
+    pub fn stream(&self) -> LatestStream {
+        LatestStream {
+            inner: WatchStream::from_changes(self.events.clone()),
+        }
+    }
+
+    /// Read the sensor as soon as possible instead of waiting for the next interval.
+    pub fn trigger_read(&self) {
+        self.trigger.notify_one();
+    }
+
+    /// Reset the sensor, see `Sensor::reset`, and then read it as soon as possible. The
+    /// reset happens after any read in progress.
+    pub fn reset_sensor(&self) {
+        self.reset.store(true, Ordering::SeqCst);
+        self.trigger.notify_one();
+    }
+
+    /// Get a `ReadRequester` for requesting reads and waiting for them to be handled.
+    pub fn requester(&self) -> ReadRequester {
+        ReadRequester {
+            requests: self.requests.clone(),
+        }
+    }
+
+    /// Total number of events not delivered to subscribers because they were too slow.
+    pub fn dropped_events(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Stop reading the sensor, waiting for any read in progress to complete and for
+    /// subscribers to handle all events they've been sent.
+    pub async fn shutdown(self) {
+        let _ = self.shutdown.send(());
+        if let Err(e) = self.task.await {
+            tracing::error!(message = "sensor worker failed", error = %e);
+        }
+
+        for subscriber in self.subscribers {
+            match task::spawn_blocking(move || subscriber.join()).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(_)) => tracing::error!(message = "sensor subscriber panicked"),
+                Err(e) => tracing::error!(message = "sensor subscriber failed", error = %e),
+            }
+        }
+    }
+}
+
+/// Stream of events for reads of the sensor by a `SensorWorker`, from `WorkerHandle::stream`.
+///
+/// Only the most recent event is kept for each stream: a consumer that falls behind skips
+/// to the latest event instead of seeing every one or slowing down reads of the sensor.
+/// Events are shared with subscribers since they can't be cloned. The stream ends once
+/// the worker has stopped and the latest event has been consumed.
+#[derive(Debug)]
+pub struct LatestStream {
+    inner: WatchStream<Option<Arc<ReadingEvent>>>,
+}
+
+impl Stream for LatestStream {
+    type Item = Arc<ReadingEvent>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match Pin::new(&mut self.inner).poll_next(cx) {
+                Poll::Ready(Some(Some(event))) => return Poll::Ready(Some(event)),
+                // Only the initial value is `None` and it isn't part of the stream
+                Poll::Ready(Some(None)) => continue,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Requests reads of the sensor by a `SensorWorker` and waits for them to be handled.
+#[derive(Debug, Clone)]
+pub struct ReadRequester {
+    requests: mpsc::UnboundedSender<oneshot::Sender<()>>,
+}
+
+impl ReadRequester {
+    /// Request a read of the sensor as soon as possible, returning a receiver that completes
+    /// (with an error, since nothing is ever sent) once every subscriber has handled the
+    /// event for the read. Requests made while a read is in progress are handled after it
+    /// finishes, see `SensorWorker::on_demand`. Completes immediately if the worker has
+    /// stopped.
+    pub fn request(&self) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        let _ = self.requests.send(tx);
+        rx
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{median, SensorSwap, SensorWorker, SUBSCRIBER_BUFFER};
+    use crate::clock::{Clock, MockClock};
+    use crate::device::PulseTiming;
+    use crate::metrics::TemperatureMetrics;
+    use crate::sensor::asynchronous::AsyncSensor;
+    use crate::sensor::calibration::{Calibration, Clamped};
+    use crate::sensor::core::{
+        Humidity, Measurement, RawReading, Sensor, SensorError, SensorErrorKind, SensorRanges, TemperatureCelsius,
+    };
+    use crate::sensor::filter::MadFilter;
+    use std::collections::VecDeque;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, UNIX_EPOCH};
+    use tokio_stream::StreamExt;
+
+    use prometheus_client::encoding::text;
+    use prometheus_client::registry::Registry;
+
+    /// Create a worker reading `sensor` inline rather than on its own thread so that reads
+    /// are visible to the paused clock tests run with. Otherwise Tokio would skip ahead past
+    /// timeouts and intervals while reads are in progress on the sensor thread.
+    fn worker<S: Sensor>(sensor: S, interval: Duration) -> SensorWorker<S> {
+        SensorWorker::from_async(AsyncSensor::inline(sensor), interval)
+    }
+
+    /// Sensor that fails every other read and otherwise returns the number of reads so far
+    #[derive(Debug, Default)]
+    struct CountingSensor {
+        reads: Arc<AtomicUsize>,
+    }
+
+    impl Sensor for CountingSensor {
+        fn read(&mut self) -> Result<Measurement, SensorError> {
+            // Odd reads succeed and even reads fail
+            let n = self.reads.fetch_add(1, Ordering::SeqCst) + 1;
+            if n % 2 == 1 {
+                return Ok(Measurement {
+                    temperature: TemperatureCelsius::from(n as f64),
+                    humidity: Humidity::from(50.0),
+                });
+            }
+
+            Err(SensorError::new(SensorErrorKind::Checksum, "bad checksum"))
+        }
+    }
+
+    /// Sensor that counts how many times it's been reset and always reads successfully
+    #[derive(Debug, Default)]
+    struct ResettingSensor {
+        resets: Arc<AtomicUsize>,
+    }
+
+    impl Sensor for ResettingSensor {
+        fn read(&mut self) -> Result<Measurement, SensorError> {
+            Ok(Measurement {
+                temperature: TemperatureCelsius::from(21.0),
+                humidity: Humidity::from(40.0),
+            })
+        }
+
+        fn reset(&mut self) {
+            self.resets.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn cycles_per_us(&self) -> Option<f64> {
+            Some(self.resets.load(Ordering::SeqCst) as f64 * 10.0)
+        }
+    }
+
+    /// Sensor that fails a fixed number of reads and then succeeds
+    #[derive(Debug, Default)]
+    struct FailingSensor {
+        failures: usize,
+        reads: Arc<AtomicUsize>,
+    }
+
+    impl FailingSensor {
+        fn new(failures: usize) -> Self {
+            Self {
+                failures,
+                reads: Default::default(),
+            }
+        }
+    }
+
+    impl Sensor for FailingSensor {
+        fn read(&mut self) -> Result<Measurement, SensorError> {
+            let n = self.reads.fetch_add(1, Ordering::SeqCst) + 1;
+            if n <= self.failures {
+                return Err(SensorError::new(SensorErrorKind::Checksum, "bad checksum"));
+            }
+
+            Ok(Measurement {
+                temperature: TemperatureCelsius::from(n as f64),
+                humidity: Humidity::from(50.0),
+            })
+        }
+    }
+
+    /// Sensor that returns a fixed sequence of temperatures, `None` being a failed read
+    #[derive(Debug, Default)]
+    struct ScriptedSensor {
+        temperatures: VecDeque<Option<f64>>,
+        reads: Arc<AtomicUsize>,
+    }
+
+    impl ScriptedSensor {
+        fn new(temperatures: &[Option<f64>]) -> Self {
+            Self {
+                temperatures: temperatures.iter().copied().collect(),
+                reads: Default::default(),
+            }
+        }
+    }
+
+    impl Sensor for ScriptedSensor {
+        fn read(&mut self) -> Result<Measurement, SensorError> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            match self.temperatures.pop_front().flatten() {
+                Some(t) => Ok(Measurement {
+                    temperature: TemperatureCelsius::from(t),
+                    humidity: Humidity::from(t * 2.0),
+                }),
+                None => Err(SensorError::new(SensorErrorKind::Checksum, "bad checksum")),
+            }
+        }
+    }
+
+    /// Sensor that reads slightly different temperatures depending on how it decodes pulses
+    #[derive(Debug, Default)]
+    struct TimingSensor {
+        reads: Arc<AtomicUsize>,
+        canary_reads: Arc<AtomicUsize>,
+    }
+
+    impl Sensor for TimingSensor {
+        fn read(&mut self) -> Result<Measurement, SensorError> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            Ok(Measurement {
+                temperature: TemperatureCelsius::from(21.0),
+                humidity: Humidity::from(40.0),
+            })
+        }
+
+        fn read_with_timing(&mut self, timing: PulseTiming) -> Result<Measurement, SensorError> {
+            self.canary_reads.fetch_add(1, Ordering::SeqCst);
+            match timing {
+                PulseTiming::Calibrated => Ok(Measurement {
+                    temperature: TemperatureCelsius::from(21.5),
+                    humidity: Humidity::from(41.0),
+                }),
+                PulseTiming::Relative => Err(SensorError::new(SensorErrorKind::Checksum, "bad checksum")),
+            }
+        }
+    }
+
+    /// Sensor that always decodes the same bytes with an invalid checksum
+    #[derive(Debug, Default)]
+    struct BadChecksumSensor {
+        reads: u8,
+        last: Option<RawReading>,
+    }
+
+    impl Sensor for BadChecksumSensor {
+        fn read(&mut self) -> Result<Measurement, SensorError> {
+            self.reads += 1;
+            self.last = Some(RawReading {
+                bytes: [2, 140, 1, self.reads, 0],
+            });
+            Err(SensorError::CheckSum(0, 1))
+        }
+
+        fn last_raw(&self) -> Option<RawReading> {
+            self.last
+        }
+    }
+
+    type EventSummary = (Result<f64, String>, u32, Vec<SensorErrorKind>);
+
+    /// Start a worker reading `samples` samples from `sensor` each refresh and collect a
+    /// summary of each event sent to subscribers.
+    async fn sample_events(
+        sensor: ScriptedSensor,
+        samples: u32,
+        min_samples: u32,
+        retries: u32,
+        secs: u64,
+    ) -> Vec<EventSummary> {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_ref = events.clone();
+
+        let handle = worker(sensor, Duration::from_secs(30))
+            .read_retries(retries, Duration::from_secs(2))
+            .samples_per_refresh(samples, min_samples, Duration::from_secs(2))
+            .subscribe(move |e| {
+                events_ref.lock().unwrap().push((
+                    e.result
+                        .as_ref()
+                        .map(|m| f64::from(m.temperature))
+                        .map_err(|e| e.to_string()),
+                    e.attempts,
+                    e.retried_errors.clone(),
+                ))
+            })
+            .start();
+
+        tokio::time::sleep(Duration::from_secs(secs)).await;
+        handle.shutdown().await;
+
+        let events = events.lock().unwrap().clone();
+        events
+    }
+
+    /// Sensor that panics on the first read and succeeds afterwards
+    #[derive(Debug, Default)]
+    struct PanicSensor {
+        reads: usize,
+    }
+
+    impl Sensor for PanicSensor {
+        fn read(&mut self) -> Result<Measurement, SensorError> {
+            self.reads += 1;
+            if self.reads == 1 {
+                panic!("sensor exploded");
+            }
+
+            Ok(Measurement {
+                temperature: TemperatureCelsius::from(21.0),
+                humidity: Humidity::from(40.0),
+            })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_periodic_reads() {
+        let sensor = CountingSensor::default();
+        let reads = sensor.reads.clone();
+        let handle = worker(sensor, Duration::from_secs(10)).start();
+
+        // Reads at 0s, 10s, 20s, 30s
+        tokio::time::sleep(Duration::from_secs(35)).await;
+
+        assert_eq!(4, reads.load(Ordering::SeqCst));
+        assert_eq!(
+            Some(TemperatureCelsius::from(3.0)),
+            handle.latest().borrow().map(|m| m.temperature)
+        );
+
+        handle.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_trigger_read() {
+        let sensor = CountingSensor::default();
+        let reads = sensor.reads.clone();
+        let handle = worker(sensor, Duration::from_secs(3600)).start();
+        let mut latest = handle.latest();
+
+        latest.changed().await.unwrap();
+        assert_eq!(1, reads.load(Ordering::SeqCst));
+
+        // Second read fails so there's no new measurement, trigger two reads
+        handle.trigger_read();
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        handle.trigger_read();
+        latest.changed().await.unwrap();
+
+        assert_eq!(3, reads.load(Ordering::SeqCst));
+        handle.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_reset_sensor() {
+        let sensor = ResettingSensor::default();
+        let resets = sensor.resets.clone();
+        let calibrations = Arc::new(Mutex::new(Vec::new()));
+        let calibrations_ref = calibrations.clone();
+
+        let handle = worker(sensor, Duration::from_secs(3600))
+            .on_reset(move |cycles| calibrations_ref.lock().unwrap().push(cycles))
+            .start();
+        let mut latest = handle.latest();
+
+        latest.changed().await.unwrap();
+        assert_eq!(0, resets.load(Ordering::SeqCst));
+
+        // The sensor is reset before the read triggered by resetting it
+        handle.reset_sensor();
+        latest.changed().await.unwrap();
+
+        assert_eq!(1, resets.load(Ordering::SeqCst));
+        assert_eq!(vec![Some(10.0)], *calibrations.lock().unwrap());
+        handle.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_on_demand() {
+        let sensor = CountingSensor::default();
+        let reads = sensor.reads.clone();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_ref = events.clone();
+
+        let handle = worker(sensor, Duration::from_secs(10))
+            .on_demand(Duration::from_secs(2))
+            .subscribe(move |event| events_ref.lock().unwrap().push(event.attempts))
+            .start();
+
+        // No periodic reads, only reads when requested
+        tokio::time::sleep(Duration::from_secs(25)).await;
+        assert_eq!(0, reads.load(Ordering::SeqCst));
+
+        // Subscribers have handled the read once the request completes
+        let requester = handle.requester();
+        let _ = requester.request().await;
+        assert_eq!(1, reads.load(Ordering::SeqCst));
+        assert_eq!(vec![1], *events.lock().unwrap());
+
+        // Concurrent requests after the reuse window share a single read
+        tokio::time::sleep(Duration::from_secs(4)).await;
+        let (first, second) = (requester.request(), requester.request());
+        let _ = first.await;
+        let _ = second.await;
+        assert_eq!(2, reads.load(Ordering::SeqCst));
+        assert_eq!(2, events.lock().unwrap().len());
+
+        handle.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_on_demand_reuse() {
+        let sensor = CountingSensor::default();
+        let reads = sensor.reads.clone();
+        let handle = worker(sensor, Duration::from_secs(10))
+            .on_demand(Duration::from_secs(4))
+            .start();
+        let requester = handle.requester();
+
+        let _ = requester.request().await;
+        assert_eq!(1, reads.load(Ordering::SeqCst));
+
+        // Within the reuse window the previous read is used
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let _ = requester.request().await;
+        assert_eq!(1, reads.load(Ordering::SeqCst));
+
+        // After the reuse window the sensor is read again
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        let _ = requester.request().await;
+        assert_eq!(2, reads.load(Ordering::SeqCst));
+
+        handle.shutdown().await;
+
+        // Requests to a stopped worker complete immediately
+        let _ = requester.request().await;
+        assert_eq!(2, reads.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_on_read() {
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let results_ref = results.clone();
+
+        let handle = worker(CountingSensor::default(), Duration::from_secs(10))
+            .on_read(move |e| results_ref.lock().unwrap().push(e.result.is_ok()))
+            .start();
+
+        tokio::time::sleep(Duration::from_secs(25)).await;
+        handle.shutdown().await;
+
+        assert_eq!(vec![true, false, true], *results.lock().unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_on_tick() {
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let ticks_ref = ticks.clone();
+
+        let handle = worker(CountingSensor::default(), Duration::from_secs(10))
+            .on_tick(move || {
+                ticks_ref.fetch_add(1, Ordering::SeqCst);
+            })
+            .start();
+
+        tokio::time::sleep(Duration::from_secs(25)).await;
+        handle.shutdown().await;
+
+        assert_eq!(3, ticks.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_shutdown() {
+        let sensor = CountingSensor::default();
+        let reads = sensor.reads.clone();
+        let handle = worker(sensor, Duration::from_secs(10)).start();
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        handle.shutdown().await;
+        tokio::time::sleep(Duration::from_secs(25)).await;
+
+        assert_eq!(1, reads.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_stream() {
+        let handle = worker(CountingSensor::default(), Duration::from_secs(10)).start();
+        let mut stream = handle.stream();
+
+        let mut events = Vec::new();
+        while events.len() < 3 {
+            events.push(stream.next().await.unwrap());
+        }
+
+        // Failed reads are part of the stream too
+        let results: Vec<Option<TemperatureCelsius>> = events
+            .iter()
+            .map(|e| e.result.as_ref().ok().map(|m| m.temperature))
+            .collect();
+        assert_eq!(
+            vec![
+                Some(TemperatureCelsius::from(1.0)),
+                None,
+                Some(TemperatureCelsius::from(3.0))
+            ],
+            results
+        );
+
+        handle.shutdown().await;
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_stream_slow_consumer() {
+        let sensor = CountingSensor::default();
+        let reads = sensor.reads.clone();
+        let handle = worker(sensor, Duration::from_secs(10)).start();
+        let mut stream = handle.stream();
+
+        // Reads at 0s, 10s, 20s, 30s while the stream isn't consumed
+        tokio::time::sleep(Duration::from_secs(35)).await;
+        assert_eq!(4, reads.load(Ordering::SeqCst));
+
+        // Only the most recent event is kept
+        let event = stream.next().await.unwrap();
+        assert_eq!(SensorErrorKind::Checksum, event.result.as_ref().unwrap_err().kind());
+        let event = stream.next().await.unwrap();
+        assert_eq!(
+            TemperatureCelsius::from(5.0),
+            event.result.as_ref().unwrap().temperature
+        );
+
+        handle.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_multiple_subscribers() {
+        let first = Arc::new(Mutex::new(Vec::new()));
+        let second = Arc::new(Mutex::new(Vec::new()));
+        let first_ref = first.clone();
+        let second_ref = second.clone();
+
+        let handle = worker(CountingSensor::default(), Duration::from_secs(10))
+            .subscribe(move |e| first_ref.lock().unwrap().push(e.result.is_ok()))
+            .subscribe(move |e| second_ref.lock().unwrap().push(e.result.is_ok()))
+            .start();
+
+        tokio::time::sleep(Duration::from_secs(25)).await;
+        handle.shutdown().await;
+
+        assert_eq!(vec![true, false, true], *first.lock().unwrap());
+        assert_eq!(vec![true, false, true], *second.lock().unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_slow_subscriber() {
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let received = Arc::new(AtomicUsize::new(0));
+        let received_ref = received.clone();
+        let handled = Arc::new(AtomicUsize::new(0));
+        let handled_ref = handled.clone();
+        let sensor = CountingSensor::default();
+        let reads = sensor.reads.clone();
+
+        // Subscriber blocks on the first event until released, everything after the
+        // buffer fills up while it's blocked is dropped. Handlers still see every event.
+        let handle = worker(sensor, Duration::from_secs(1))
+            .on_read(move |_| {
+                handled_ref.fetch_add(1, Ordering::SeqCst);
+            })
+            .subscribe(move |_| {
+                if received_ref.fetch_add(1, Ordering::SeqCst) == 0 {
+                    release_rx.recv().unwrap();
+                }
+            })
+            .start();
+
+        let total = SUBSCRIBER_BUFFER + 10;
+        while reads.load(Ordering::SeqCst) < total {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+
+        assert_eq!(total, reads.load(Ordering::SeqCst));
+        assert_eq!(total, handled.load(Ordering::SeqCst));
+        let dropped = handle.dropped_events();
+        assert!(dropped > 0);
+
+        release_tx.send(()).unwrap();
+        handle.shutdown().await;
+
+        assert_eq!(
+            reads.load(Ordering::SeqCst) as u64,
+            received.load(Ordering::SeqCst) as u64 + dropped
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_read_retries() {
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let results_ref = results.clone();
+        let sensor = CountingSensor::default();
+        let reads = sensor.reads.clone();
+
+        // First read succeeds, second read fails and succeeds on the first retry
+        let handle = worker(sensor, Duration::from_secs(10))
+            .read_retries(3, Duration::from_secs(2))
+            .subscribe(move |e| {
+                results_ref
+                    .lock()
+                    .unwrap()
+                    .push((e.result.is_ok(), e.attempts, e.retried_errors.clone()))
+            })
+            .start();
+
+        tokio::time::sleep(Duration::from_secs(15)).await;
+        handle.shutdown().await;
+
+        assert_eq!(3, reads.load(Ordering::SeqCst));
+        assert_eq!(
+            vec![(true, 1, vec![]), (true, 2, vec![SensorErrorKind::Checksum])],
+            *results.lock().unwrap()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_read_budget_exceeded() {
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let results_ref = results.clone();
+        let sensor = FailingSensor::new(usize::MAX);
+        let reads = sensor.reads.clone();
+
+        // Reads at 0s, 2s, and 4s fail and the budget runs out before the retry at 6s
+        let handle = worker(sensor, Duration::from_secs(30))
+            .read_retries(5, Duration::from_secs(2))
+            .read_budget(Duration::from_secs(5))
+            .subscribe(move |e| {
+                results_ref.lock().unwrap().push((
+                    e.result.as_ref().map_err(|e| e.to_string()).err(),
+                    e.attempts,
+                    e.retried_errors.len(),
+                ))
+            })
+            .start();
+
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        handle.shutdown().await;
+
+        assert_eq!(3, reads.load(Ordering::SeqCst));
+        assert_eq!(
+            vec![(Some("read budget exceeded".to_owned()), 3, 2)],
+            *results.lock().unwrap()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_read_budget_default() {
+        let sensor = FailingSensor::new(usize::MAX);
+        let reads = sensor.reads.clone();
+
+        // Default budget is half the interval, 10s, so reads at 0s, 3s, 6s, and 9s
+        let handle = worker(sensor, Duration::from_secs(20))
+            .read_retries(10, Duration::from_secs(3))
+            .start();
+
+        tokio::time::sleep(Duration::from_secs(19)).await;
+        handle.shutdown().await;
+
+        assert_eq!(4, reads.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_skips_missed_ticks() {
+        let sensor = FailingSensor::new(10);
+        let reads = sensor.reads.clone();
+
+        // The first read fails ten times, finally succeeding at 20s and missing the tick
+        // at 10s. The missed tick happens immediately but the one at 20s is skipped.
+        let handle = worker(sensor, Duration::from_secs(10))
+            .read_retries(10, Duration::from_secs(2))
+            .read_budget(Duration::from_secs(30))
+            .start();
+
+        tokio::time::sleep(Duration::from_secs(25)).await;
+        assert_eq!(12, reads.load(Ordering::SeqCst));
+
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(13, reads.load(Ordering::SeqCst));
+        handle.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_sensor_panic() {
+        let mut registry = <Registry>::default();
+        let metrics = Arc::new(TemperatureMetrics::new(&mut registry));
+        let metrics_ref = metrics.clone();
+
+        let handle = worker(PanicSensor::default(), Duration::from_secs(10))
+            .subscribe(move |e| metrics_ref.update(e))
+            .start();
+
+        tokio::time::sleep(Duration::from_secs(15)).await;
+        handle.shutdown().await;
+
+        let mut buf = String::new();
+        text::encode(&mut buf, &registry).unwrap();
+
+        assert!(buf.contains("strudel_errors_total{sensor=\"\",kind=\"internal\",attempt=\"final\"} 1"));
+        assert!(buf.contains("strudel_reads_total{sensor=\"\",outcome=\"failure\"} 1\n"));
+        assert!(buf.contains("strudel_reads_total{sensor=\"\",outcome=\"success_first_try\"} 1\n"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_initial_delay() {
+        let sensor = CountingSensor::default();
+        let reads = sensor.reads.clone();
+        let handle = worker(sensor, Duration::from_secs(30))
+            .initial_delay(Duration::from_secs(30))
+            .start();
+
+        tokio::time::sleep(Duration::from_secs(25)).await;
+        assert_eq!(0, reads.load(Ordering::SeqCst));
+
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(1, reads.load(Ordering::SeqCst));
+
+        handle.shutdown().await;
+    }
+
+    #[test]
+    fn test_median() {
+        assert_eq!(None, median(&[]));
+        assert_eq!(Some(21.5), median(&[21.5]));
+        assert_eq!(Some(21.75), median(&[21.5, 22.0]));
+        assert_eq!(Some(21.0), median(&[30.0, 21.0, 20.0]));
+        assert_eq!(Some(20.5), median(&[30.0, 21.0, -5.0, 20.0]));
+        assert_eq!(Some(4.0), median(&[5.0, 1.0, 4.0, 4.0, 2.0]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_samples_median() {
+        let sensor = ScriptedSensor::new(&[Some(21.0), Some(35.0), Some(20.0)]);
+        let reads = sensor.reads.clone();
+
+        // Samples at 0s, 2s, and 4s published as a single reading with the median of
+        // both temperature and humidity.
+        let handle = worker(sensor, Duration::from_secs(30))
+            .samples_per_refresh(3, 1, Duration::from_secs(2))
+            .start();
+        let mut latest = handle.latest();
+
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        assert_eq!(2, reads.load(Ordering::SeqCst));
+        assert_eq!(None, *latest.borrow());
+
+        latest.changed().await.unwrap();
+        assert_eq!(3, reads.load(Ordering::SeqCst));
+        assert_eq!(
+            Some(Measurement {
+                temperature: TemperatureCelsius::from(21.0),
+                humidity: Humidity::from(42.0),
+            }),
+            *latest.borrow()
+        );
+
+        handle.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_filter() {
+        let sensor = ScriptedSensor::new(&[Some(21.0), Some(21.2), Some(20.8), Some(35.0), None, Some(21.1)]);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_ref = events.clone();
+
+        let handle = worker(sensor, Duration::from_secs(10))
+            .filter(MadFilter::new(3))
+            .subscribe(move |e| {
+                events_ref.lock().unwrap().push(
+                    e.result
+                        .as_ref()
+                        .map(|m| f64::from(m.temperature))
+                        .map_err(|e| e.kind()),
+                )
+            })
+            .start();
+
+        tokio::time::sleep(Duration::from_secs(55)).await;
+        handle.shutdown().await;
+
+        // The spike is rejected, other errors are left as they are
+        assert_eq!(
+            vec![
+                Ok(21.0),
+                Ok(21.2),
+                Ok(20.8),
+                Err(SensorErrorKind::Implausible),
+                Err(SensorErrorKind::Checksum),
+                Ok(21.1)
+            ],
+            *events.lock().unwrap()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_suppress_spikes() {
+        let sensor = ScriptedSensor::new(&[Some(21.0), Some(35.0), Some(21.2), Some(21.4), None, Some(21.6)]);
+        let start = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let clock = MockClock::new(start);
+        let clock_ref = clock.clone();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_ref = events.clone();
+        let spikes = Arc::new(Mutex::new(Vec::new()));
+        let spikes_ref = spikes.clone();
+
+        let handle = worker(sensor, Duration::from_secs(10))
+            .clock(Arc::new(clock.clone()))
+            .suppress_spikes(2.0, move |spike, replacement| {
+                spikes_ref
+                    .lock()
+                    .unwrap()
+                    .push((f64::from(spike.temperature), f64::from(replacement.temperature)))
+            })
+            .on_tick(move || clock_ref.advance(Duration::from_secs(30)))
+            .subscribe(move |e| {
+                let secs = e.timestamp.duration_since(start).unwrap().as_secs();
+                let res = e
+                    .result
+                    .as_ref()
+                    .map(|m| f64::from(m.temperature))
+                    .map_err(|e| e.kind());
+                events_ref.lock().unwrap().push((secs, res));
+            })
+            .start();
+
+        let latest = handle.latest();
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        // The first reading is held until the next read
+        assert!(events.lock().unwrap().is_empty());
+        assert_eq!(None, *latest.borrow());
+
+        tokio::time::sleep(Duration::from_secs(50)).await;
+        handle.shutdown().await;
+
+        // Readings keep the time they were read. The spike is replaced and the reading
+        // before the failed read is released without a reading after it.
+        assert_eq!(
+            vec![
+                (30, Ok(21.0)),
+                (60, Ok(21.1)),
+                (90, Ok(21.2)),
+                (120, Ok(21.4)),
+                (150, Err(SensorErrorKind::Checksum)),
+            ],
+            *events.lock().unwrap()
+        );
+        assert_eq!(vec![(35.0, 21.1)], *spikes.lock().unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_samples_single_event() {
+        let sensor = ScriptedSensor::new(&[Some(21.0), None, Some(23.0), Some(22.0), Some(22.0), Some(22.0)]);
+        let events = sample_events(sensor, 3, 2, 0, 45).await;
+
+        // Failed samples are counted as failed reads of the cycle but don't fail it as
+        // long as enough samples succeed.
+        assert_eq!(
+            vec![(Ok(22.0), 3, vec![SensorErrorKind::Checksum]), (Ok(22.0), 3, vec![])],
+            events
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_samples_min_samples() {
+        let sensor = ScriptedSensor::new(&[None, Some(21.0), None]);
+        let events = sample_events(sensor, 3, 2, 0, 10).await;
+
+        // The last failed sample is the final error, the other is counted as retried
+        assert_eq!(
+            vec![(Err("bad checksum".to_owned()), 3, vec![SensorErrorKind::Checksum])],
+            events
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_samples_retries() {
+        let sensor = ScriptedSensor::new(&[Some(21.0), None, None, Some(23.0), Some(22.0)]);
+        let reads = sensor.reads.clone();
+        let events = sample_events(sensor, 3, 3, 2, 20).await;
+
+        // The second sample needs two retries, the third sample still happens after it
+        assert_eq!(5, reads.load(Ordering::SeqCst));
+        assert_eq!(
+            vec![(Ok(22.0), 5, vec![SensorErrorKind::Checksum, SensorErrorKind::Checksum])],
+            events
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_clock() {
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        let times = Arc::new(Mutex::new(Vec::new()));
+        let times_ref = times.clone();
+
+        let handle = worker(CountingSensor::default(), Duration::from_secs(30))
+            .clock(Arc::new(clock.clone()))
+            .subscribe(move |e| times_ref.lock().unwrap().push((e.timestamp, e.instant)))
+            .start();
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        handle.shutdown().await;
+
+        // Events are timestamped by the clock of the worker, not the system clock
+        assert_eq!(vec![(clock.now_wall(), clock.now_monotonic())], *times.lock().unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_calibration() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_ref = events.clone();
+
+        // Temperatures of 1, 3, 5, ... are offset by -2 and clamped to at least zero
+        let ranges = SensorRanges {
+            min_temperature: 0.0,
+            ..SensorRanges::default()
+        };
+        let handle = worker(CountingSensor::default(), Duration::from_secs(10))
+            .calibration(Calibration::new(ranges).temp_offset(-2.0))
+            .subscribe(move |e| {
+                let temperature = e.result.as_ref().ok().map(|m| f64::from(m.temperature));
+                events_ref.lock().unwrap().push((temperature, e.clamped));
+            })
+            .start();
+
+        tokio::time::sleep(Duration::from_secs(25)).await;
+        handle.shutdown().await;
+
+        assert_eq!(
+            vec![
+                (
+                    Some(0.0),
+                    Clamped {
+                        temperature: true,
+                        humidity: false
+                    }
+                ),
+                (None, Clamped::default()),
+                (Some(1.0), Clamped::default()),
+            ],
+            *events.lock().unwrap()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_canary() {
+        let sensor = TimingSensor::default();
+        let reads = sensor.reads.clone();
+        let canary_reads = sensor.canary_reads.clone();
+        let compared = Arc::new(Mutex::new(Vec::new()));
+        let compared_ref = compared.clone();
+        let events = Arc::new(AtomicUsize::new(0));
+        let events_ref = events.clone();
+
+        // Readings are offset by +1 before being published and compared
+        let handle = worker(sensor, Duration::from_secs(20))
+            .calibration(Calibration::new(SensorRanges::default()).temp_offset(1.0))
+            .canary(
+                PulseTiming::Calibrated,
+                Duration::from_secs(5),
+                move |primary, canary| {
+                    let canary = canary.as_ref().ok().map(|m| f64::from(m.temperature));
+                    compared_ref
+                        .lock()
+                        .unwrap()
+                        .push((f64::from(primary.temperature), canary));
+                },
+            )
+            .subscribe(move |_| {
+                events_ref.fetch_add(1, Ordering::SeqCst);
+            })
+            .start();
+
+        // Reads at 0s, 20s, and 40s with canary reads five seconds after the first two
+        tokio::time::sleep(Duration::from_secs(43)).await;
+        assert_eq!(
+            Some(TemperatureCelsius::from(22.0)),
+            handle.latest().borrow().map(|m| m.temperature)
+        );
+        handle.shutdown().await;
+
+        assert_eq!(3, reads.load(Ordering::SeqCst));
+        assert_eq!(2, canary_reads.load(Ordering::SeqCst));
+        assert_eq!(3, events.load(Ordering::SeqCst));
+        assert_eq!(vec![(22.0, Some(22.5)), (22.0, Some(22.5))], *compared.lock().unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_canary_after_failure() {
+        let compared = Arc::new(Mutex::new(Vec::new()));
+        let compared_ref = compared.clone();
+
+        // No canary read after the first read fails. Sensors that don't decode pulses
+        // fail every canary read.
+        let handle = worker(FailingSensor::new(1), Duration::from_secs(10))
+            .canary(PulseTiming::Relative, Duration::from_secs(2), move |primary, canary| {
+                let canary = canary.as_ref().map_err(|e| e.kind()).err();
+                compared_ref
+                    .lock()
+                    .unwrap()
+                    .push((f64::from(primary.temperature), canary));
+            })
+            .start();
+
+        tokio::time::sleep(Duration::from_secs(15)).await;
+        handle.shutdown().await;
+
+        assert_eq!(vec![(2.0, Some(SensorErrorKind::Internal))], *compared.lock().unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_raw_bytes() {
+        let raw = Arc::new(Mutex::new(Vec::new()));
+        let raw_ref = raw.clone();
+
+        let handle = worker(BadChecksumSensor::default(), Duration::from_secs(30))
+            .read_retries(1, Duration::from_secs(2))
+            .subscribe(move |e| raw_ref.lock().unwrap().push(e.raw.clone()))
+            .start();
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        handle.shutdown().await;
+
+        // Bytes from every attempt, including the retry, even though both failed
+        assert_eq!(
+            vec![vec![
+                RawReading {
+                    bytes: [2, 140, 1, 1, 0]
+                },
+                RawReading {
+                    bytes: [2, 140, 1, 2, 0]
+                },
+            ]],
+            *raw.lock().unwrap()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_swap() {
+        let worker = worker(ScriptedSensor::new(&[Some(1.0)]), Duration::from_secs(3600));
+        let swapper = worker.swapper();
+        let handle = worker.start();
+        let mut latest = handle.latest();
+
+        latest.changed().await.unwrap();
+        assert_eq!(
+            Some(TemperatureCelsius::from(1.0)),
+            latest.borrow().map(|m| m.temperature)
+        );
+
+        // The replacement is read right away and then at its own interval
+        let replacement = ScriptedSensor::new(&[Some(2.0), Some(3.0)]);
+        let reads = replacement.reads.clone();
+        swapper
+            .swap(move || {
+                Ok(SensorSwap {
+                    sensor: replacement,
+                    interval: Duration::from_secs(30),
+                    calibration: Calibration::new(SensorRanges::default()),
+                })
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        latest.changed().await.unwrap();
+        assert_eq!(
+            Some(TemperatureCelsius::from(2.0)),
+            latest.borrow().map(|m| m.temperature)
+        );
+
+        tokio::time::sleep(Duration::from_secs(45)).await;
+        assert_eq!(2, reads.load(Ordering::SeqCst));
+        assert_eq!(
+            Some(TemperatureCelsius::from(3.0)),
+            latest.borrow().map(|m| m.temperature)
+        );
+
+        handle.shutdown().await;
+
+        // Swaps for a stopped worker complete immediately
+        let res = swapper.swap(|| Err(SensorError::initialization("unused"))).await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sensor_worker_swap_failed() {
+        let worker = worker(ScriptedSensor::new(&[Some(1.0)]), Duration::from_secs(3600));
+        let swapper = worker.swapper();
+        let handle = worker.start();
+        let mut events = handle.stream();
+
+        let event = events.next().await.unwrap();
+        assert!(event.result.is_ok());
+
+        let err = swapper
+            .swap(|| Err(SensorError::initialization("no such pin")))
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(SensorErrorKind::Initialization, err.kind());
+
+        // Reads fail without a sensor until it's replaced
+        handle.trigger_read();
+        let event = events.next().await.unwrap();
+        assert_eq!(
+            SensorErrorKind::Initialization,
+            event.result.as_ref().unwrap_err().kind()
+        );
+
+        swapper
+            .swap(|| {
+                Ok(SensorSwap {
+                    sensor: ScriptedSensor::new(&[Some(4.0)]),
+                    interval: Duration::from_secs(3600),
+                    calibration: Calibration::new(SensorRanges::default()),
+                })
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let event = events.next().await.unwrap();
+        assert_eq!(
+            TemperatureCelsius::from(4.0),
+            event.result.as_ref().unwrap().temperature
+        );
+
+        handle.shutdown().await;
+    }
+}
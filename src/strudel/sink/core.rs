@@ -0,0 +1,32 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+use crate::sensor::{Humidity, TemperatureCelsius};
+
+/// Destination for successful sensor readings, in addition to Prometheus metrics.
+///
+/// Implementations are called from the background task that reads the sensor and
+/// so must not block. Any errors should be logged and counted by the implementation
+/// instead of being returned.
+pub trait ReadingSink: Send + Sync {
+    /// Name of this sink, used as the `target` label for metrics about it.
+    fn name(&self) -> &'static str;
+
+    /// Handle a successful temperature and humidity reading from the sensor.
+    fn accept(&self, temperature: TemperatureCelsius, humidity: Humidity);
+}
@@ -0,0 +1,225 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+use crate::clock::Clock;
+use crate::sensor::Measurement;
+use crate::sink::core::ReadingSink;
+use std::time::{Duration, Instant};
+
+/// Allowance for values like 21.4 - 21.3 being slightly less than 0.1 as floats, so that a
+/// change of exactly the deadband is published.
+const TOLERANCE: f64 = 1e-6;
+
+/// Decides which readings are sent to `ReadingSink`s, skipping readings that haven't
+/// changed by at least a deadband since the last reading that was sent.
+///
+/// A reading is always sent if `max_interval` has elapsed since the last one that was,
+/// so that consumers can still tell when readings stop. Failed reads aren't seen by the
+/// filter: after a gap in readings, the next one is compared to the last reading that
+/// was sent before the gap. Deadbands of zero send every reading.
+#[derive(Debug)]
+pub struct DeadbandFilter {
+    temperature: f64,
+    humidity: f64,
+    max_interval: Duration,
+    last: Option<(Measurement, Instant)>,
+}
+
+impl DeadbandFilter {
+    /// Create a filter with deadbands for temperature, in degrees Celsius, and humidity,
+    /// in percent.
+    pub fn new(temperature: f64, humidity: f64, max_interval: Duration) -> Self {
+        Self {
+            temperature,
+            humidity,
+            max_interval,
+            last: None,
+        }
+    }
+
+    /// Return true if `m` should be sent to sinks and if so, remember it as the last
+    /// reading sent.
+    pub fn should_publish(&mut self, m: &Measurement, clock: &dyn Clock) -> bool {
+        self.should_publish_at(m, clock.now_monotonic())
+    }
+
+    /// Return true if `m` should be sent to sinks as of `now` and if so, remember it as
+    /// the last reading sent.
+    pub fn should_publish_at(&mut self, m: &Measurement, now: Instant) -> bool {
+        let publish = match &self.last {
+            None => true,
+            Some((last, at)) => {
+                now.saturating_duration_since(*at) >= self.max_interval
+                    || exceeds(f64::from(m.temperature), f64::from(last.temperature), self.temperature)
+                    || exceeds(f64::from(m.humidity), f64::from(last.humidity), self.humidity)
+            }
+        };
+
+        if publish {
+            self.last = Some((*m, now));
+        }
+
+        publish
+    }
+
+    /// Send `m` to each of `sinks` if it should be published.
+    pub fn dispatch(&mut self, m: &Measurement, sinks: &[Box<dyn ReadingSink>], clock: &dyn Clock) {
+        if self.should_publish(m, clock) {
+            for sink in sinks {
+                sink.accept(m.temperature, m.humidity);
+            }
+        }
+    }
+}
+
+fn exceeds(current: f64, last: f64, deadband: f64) -> bool {
+    (current - last).abs() + TOLERANCE >= deadband
+}
+
+#[cfg(test)]
+mod test {
+    use super::DeadbandFilter;
+    use crate::clock::MockClock;
+    use crate::sensor::{Humidity, Measurement, TemperatureCelsius};
+    use crate::sink::ReadingSink;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    const MAX_INTERVAL: Duration = Duration::from_secs(300);
+
+    /// Sink that records the temperatures it's sent
+    #[derive(Debug, Default)]
+    struct RecordingSink {
+        temperatures: Arc<Mutex<Vec<f64>>>,
+    }
+
+    impl ReadingSink for RecordingSink {
+        fn name(&self) -> &'static str {
+            "recording"
+        }
+
+        fn accept(&self, temperature: TemperatureCelsius, _humidity: Humidity) {
+            self.temperatures.lock().unwrap().push(temperature.into());
+        }
+    }
+
+    fn measurement(temperature: f64, humidity: f64) -> Measurement {
+        Measurement {
+            temperature: TemperatureCelsius::from(temperature),
+            humidity: Humidity::from(humidity),
+        }
+    }
+
+    #[test]
+    fn test_deadband_filter_first_reading() {
+        let mut filter = DeadbandFilter::new(0.1, 0.5, MAX_INTERVAL);
+        assert!(filter.should_publish_at(&measurement(21.5, 40.0), Instant::now()));
+    }
+
+    #[test]
+    fn test_deadband_filter_temperature_threshold() {
+        let mut filter = DeadbandFilter::new(0.1, 0.5, MAX_INTERVAL);
+        let now = Instant::now();
+
+        assert!(filter.should_publish_at(&measurement(21.3, 40.0), now));
+        assert!(!filter.should_publish_at(&measurement(21.3, 40.0), now + Duration::from_secs(15)));
+        assert!(!filter.should_publish_at(&measurement(21.35, 40.0), now + Duration::from_secs(30)));
+        // Exactly the deadband, despite 21.4 - 21.3 being slightly less than 0.1
+        assert!(filter.should_publish_at(&measurement(21.4, 40.0), now + Duration::from_secs(45)));
+        assert!(filter.should_publish_at(&measurement(21.2, 40.0), now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_deadband_filter_humidity_threshold() {
+        let mut filter = DeadbandFilter::new(0.1, 0.5, MAX_INTERVAL);
+        let now = Instant::now();
+
+        assert!(filter.should_publish_at(&measurement(21.5, 40.0), now));
+        assert!(!filter.should_publish_at(&measurement(21.5, 40.3), now + Duration::from_secs(15)));
+        assert!(filter.should_publish_at(&measurement(21.5, 39.5), now + Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_deadband_filter_compares_to_last_published() {
+        let mut filter = DeadbandFilter::new(0.1, 0.5, MAX_INTERVAL);
+        let now = Instant::now();
+
+        // Small changes that add up are published once they reach the deadband
+        assert!(filter.should_publish_at(&measurement(21.50, 40.0), now));
+        assert!(!filter.should_publish_at(&measurement(21.55, 40.0), now + Duration::from_secs(15)));
+        assert!(!filter.should_publish_at(&measurement(21.58, 40.0), now + Duration::from_secs(30)));
+        assert!(filter.should_publish_at(&measurement(21.61, 40.0), now + Duration::from_secs(45)));
+    }
+
+    #[test]
+    fn test_deadband_filter_max_interval() {
+        let mut filter = DeadbandFilter::new(0.1, 0.5, MAX_INTERVAL);
+        let now = Instant::now();
+
+        assert!(filter.should_publish_at(&measurement(21.5, 40.0), now));
+        assert!(!filter.should_publish_at(&measurement(21.5, 40.0), now + Duration::from_secs(299)));
+        assert!(filter.should_publish_at(&measurement(21.5, 40.0), now + MAX_INTERVAL));
+        // The interval starts again from the forced publish
+        assert!(!filter.should_publish_at(&measurement(21.5, 40.0), now + Duration::from_secs(599)));
+        assert!(filter.should_publish_at(&measurement(21.5, 40.0), now + MAX_INTERVAL * 2));
+    }
+
+    #[test]
+    fn test_deadband_filter_error_gap() {
+        let mut filter = DeadbandFilter::new(0.1, 0.5, MAX_INTERVAL);
+        let now = Instant::now();
+
+        assert!(filter.should_publish_at(&measurement(21.5, 40.0), now));
+
+        // Failed reads in between aren't seen by the filter. A short gap doesn't force
+        // a publish of an unchanged reading but a gap longer than the max interval does.
+        assert!(!filter.should_publish_at(&measurement(21.5, 40.0), now + Duration::from_secs(120)));
+        assert!(filter.should_publish_at(&measurement(21.5, 40.0), now + Duration::from_secs(900)));
+
+        // Changes are compared to the reading from before the gap
+        assert!(filter.should_publish_at(&measurement(22.5, 40.0), now + Duration::from_secs(960)));
+    }
+
+    #[test]
+    fn test_deadband_filter_zero_deadband() {
+        let mut filter = DeadbandFilter::new(0.0, 0.0, MAX_INTERVAL);
+        let now = Instant::now();
+
+        assert!(filter.should_publish_at(&measurement(21.5, 40.0), now));
+        assert!(filter.should_publish_at(&measurement(21.5, 40.0), now + Duration::from_secs(15)));
+    }
+
+    #[test]
+    fn test_deadband_filter_dispatch() {
+        let clock = MockClock::new(UNIX_EPOCH);
+        let sink = RecordingSink::default();
+        let temperatures = sink.temperatures.clone();
+        let sinks: Vec<Box<dyn ReadingSink>> = vec![Box::new(sink)];
+        let mut filter = DeadbandFilter::new(0.1, 0.5, MAX_INTERVAL);
+
+        filter.dispatch(&measurement(21.5, 40.0), &sinks, &clock);
+        clock.advance(Duration::from_secs(15));
+        filter.dispatch(&measurement(21.5, 40.0), &sinks, &clock);
+        clock.advance(Duration::from_secs(15));
+        filter.dispatch(&measurement(21.7, 40.0), &sinks, &clock);
+        clock.advance(MAX_INTERVAL);
+        filter.dispatch(&measurement(21.7, 40.0), &sinks, &clock);
+
+        assert_eq!(vec![21.5, 21.7, 21.7], *temperatures.lock().unwrap());
+    }
+}
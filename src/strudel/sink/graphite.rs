@@ -0,0 +1,196 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+use crate::metrics::PushMetrics;
+use crate::sensor::{Humidity, TemperatureCelsius};
+use crate::sink::core::ReadingSink;
+use prometheus_client::metrics::counter::Counter;
+use std::cmp;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+const TARGET: &str = "graphite";
+const QUEUE_SIZE: usize = 64;
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Emit temperature and humidity to Graphite using the plaintext protocol.
+///
+/// Lines are written to Graphite from a separate task fed by a bounded queue so that
+/// reading the sensor is never blocked. Readings are dropped and counted as push errors
+/// for the `graphite` target when the queue is full or when they can't be written.
+#[derive(Debug)]
+pub struct GraphiteSink {
+    path: String,
+    tx: Sender<String>,
+    errors: Counter,
+}
+
+impl GraphiteSink {
+    /// Create a new sink writing to `addr`. Metric paths are `<prefix>.<host>.<metric>`
+    /// with any dots in `host` replaced by underscores.
+    ///
+    /// The connection to Graphite is made lazily by a task spawned on the current Tokio
+    /// runtime and so this method must be called from within a runtime.
+    pub fn new(addr: SocketAddr, prefix: &str, host: &str, metrics: &PushMetrics) -> Self {
+        Self::with_backoff(addr, prefix, host, metrics, MIN_BACKOFF)
+    }
+
+    fn with_backoff(addr: SocketAddr, prefix: &str, host: &str, metrics: &PushMetrics, backoff: Duration) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        let errors = metrics.errors(TARGET);
+        tokio::spawn(write_lines(addr, rx, errors.clone(), backoff));
+
+        Self {
+            path: format!("{}.{}", prefix, host.replace('.', "_")),
+            tx,
+            errors,
+        }
+    }
+
+    fn format(&self, temperature: TemperatureCelsius, humidity: Humidity, timestamp: u64) -> String {
+        format!(
+            "{path}.temperature {} {ts}\n{path}.humidity {} {ts}\n",
+            f64::from(temperature),
+            f64::from(humidity),
+            path = self.path,
+            ts = timestamp,
+        )
+    }
+}
+
+impl ReadingSink for GraphiteSink {
+    fn name(&self) -> &'static str {
+        TARGET
+    }
+
+    fn accept(&self, temperature: TemperatureCelsius, humidity: Humidity) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        if self.tx.try_send(self.format(temperature, humidity, timestamp)).is_err() {
+            self.errors.inc();
+            tracing::warn!(message = "dropped graphite lines, queue full or closed");
+        }
+    }
+}
+
+/// Write lines from the queue to Graphite, (re)connecting as needed with exponential backoff
+/// after failures. Lines that can't be written are dropped.
+async fn write_lines(addr: SocketAddr, mut rx: Receiver<String>, errors: Counter, min_backoff: Duration) {
+    let mut conn: Option<TcpStream> = None;
+    let mut backoff = min_backoff;
+
+    while let Some(lines) = rx.recv().await {
+        let stream = match conn {
+            Some(ref mut s) => s,
+            None => match TcpStream::connect(addr).await {
+                Ok(s) => {
+                    backoff = min_backoff;
+                    conn.insert(s)
+                }
+                Err(e) => {
+                    errors.inc();
+                    tracing::warn!(message = "unable to connect to graphite", address = %addr, error = %e);
+                    tokio::time::sleep(backoff).await;
+                    backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+                    continue;
+                }
+            },
+        };
+
+        if let Err(e) = stream.write_all(lines.as_bytes()).await {
+            errors.inc();
+            tracing::warn!(message = "unable to write to graphite", address = %addr, error = %e);
+            conn = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::GraphiteSink;
+    use crate::metrics::PushMetrics;
+    use crate::sensor::{Humidity, TemperatureCelsius};
+    use crate::sink::ReadingSink;
+    use prometheus_client::registry::Registry;
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_graphite_sink_format() {
+        let mut registry = <Registry>::default();
+        let metrics = PushMetrics::new(&mut registry);
+        let sink = GraphiteSink::new(([127, 0, 0, 1], 2003).into(), "strudel", "pi.example.com", &metrics);
+
+        assert_eq!(
+            "strudel.pi_example_com.temperature -10.1 1650000000\nstrudel.pi_example_com.humidity 65.2 1650000000\n",
+            sink.format(TemperatureCelsius::from(-10.1), Humidity::from(65.2), 1650000000)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_graphite_sink_accept() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut registry = <Registry>::default();
+        let metrics = PushMetrics::new(&mut registry);
+        let sink = GraphiteSink::new(listener.local_addr().unwrap(), "home", "pi", &metrics);
+
+        sink.accept(TemperatureCelsius::from(21.5), Humidity::from(45.0));
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        let temperature = lines.next_line().await.unwrap().unwrap();
+        let humidity = lines.next_line().await.unwrap().unwrap();
+
+        assert!(temperature.starts_with("home.pi.temperature 21.5 "));
+        assert!(humidity.starts_with("home.pi.humidity 45 "));
+    }
+
+    #[tokio::test]
+    async fn test_graphite_sink_reconnect() {
+        // Find a free port and then stop listening on it so that the first connection fails
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let mut registry = <Registry>::default();
+        let metrics = PushMetrics::new(&mut registry);
+        let sink = GraphiteSink::with_backoff(addr, "strudel", "pi", &metrics, Duration::from_millis(10));
+
+        sink.accept(TemperatureCelsius::from(20.0), Humidity::from(40.0));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(1, metrics.errors("graphite").get());
+
+        let listener = TcpListener::bind(addr).await.unwrap();
+        sink.accept(TemperatureCelsius::from(21.0), Humidity::from(41.0));
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        let temperature = lines.next_line().await.unwrap().unwrap();
+
+        assert!(temperature.starts_with("strudel.pi.temperature 21 "));
+        assert_eq!(1, metrics.errors("graphite").get());
+    }
+}
@@ -0,0 +1,156 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+use crate::metrics::PushMetrics;
+use crate::sensor::{Humidity, TemperatureCelsius};
+use crate::sink::core::ReadingSink;
+use prometheus_client::metrics::counter::Counter;
+use std::fmt::{self, Formatter};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+const TARGET: &str = "statsd";
+
+/// Emit temperature and humidity as DogStatsD gauges over UDP.
+///
+/// Sends are non-blocking: datagrams that can't be sent immediately are dropped
+/// and counted as push errors for the `statsd` target.
+pub struct StatsdSink {
+    socket: UdpSocket,
+    prefix: String,
+    tags: Vec<String>,
+    errors: Counter,
+}
+
+impl StatsdSink {
+    /// Create a new sink sending to `addr`. Metric names are prefixed with `prefix`
+    /// and each datagram includes the given `key:value` style tags.
+    pub fn new(addr: SocketAddr, prefix: &str, tags: Vec<String>, metrics: &PushMetrics) -> io::Result<Self> {
+        let local: SocketAddr = if addr.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Self {
+            socket,
+            prefix: prefix.to_owned(),
+            tags,
+            errors: metrics.errors(TARGET),
+        })
+    }
+
+    fn format(&self, name: &str, value: f64) -> String {
+        // DogStatsD treats gauge values as absolute even when they have a sign (unlike
+        // the original Etsy statsd which treats them as deltas) so negative temperatures
+        // can be sent as-is.
+        let mut out = format!("{}.{}:{}|g", self.prefix, name, value);
+        if !self.tags.is_empty() {
+            out.push_str("|#");
+            out.push_str(&self.tags.join(","));
+        }
+
+        out
+    }
+
+    fn send(&self, name: &str, value: f64) {
+        let datagram = self.format(name, value);
+        if let Err(e) = self.socket.send(datagram.as_bytes()) {
+            self.errors.inc();
+
+            if e.kind() == io::ErrorKind::WouldBlock {
+                tracing::debug!(message = "dropped statsd datagram", metric = name);
+            } else {
+                tracing::warn!(message = "unable to send statsd datagram", metric = name, error = %e);
+            }
+        }
+    }
+}
+
+impl ReadingSink for StatsdSink {
+    fn name(&self) -> &'static str {
+        TARGET
+    }
+
+    fn accept(&self, temperature: TemperatureCelsius, humidity: Humidity) {
+        self.send("temperature", temperature.into());
+        self.send("humidity", humidity.into());
+    }
+}
+
+impl fmt::Debug for StatsdSink {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StatsdSink")
+            .field("addr", &self.socket.peer_addr().ok())
+            .field("prefix", &self.prefix)
+            .field("tags", &self.tags)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::StatsdSink;
+    use crate::metrics::PushMetrics;
+    use crate::sensor::{Humidity, TemperatureCelsius};
+    use crate::sink::ReadingSink;
+    use prometheus_client::registry::Registry;
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    fn recv(socket: &UdpSocket) -> String {
+        let mut buf = [0; 512];
+        let n = socket.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..n].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_statsd_sink_accept() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let mut registry = <Registry>::default();
+        let metrics = PushMetrics::new(&mut registry);
+        let tags = vec!["sensor:indoor".to_owned(), "room:office".to_owned()];
+        let sink = StatsdSink::new(server.local_addr().unwrap(), "strudel", tags, &metrics).unwrap();
+
+        sink.accept(TemperatureCelsius::from(21.5), Humidity::from(45.0));
+
+        assert_eq!("strudel.temperature:21.5|g|#sensor:indoor,room:office", recv(&server));
+        assert_eq!("strudel.humidity:45|g|#sensor:indoor,room:office", recv(&server));
+    }
+
+    #[test]
+    fn test_statsd_sink_negative_no_tags() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let mut registry = <Registry>::default();
+        let metrics = PushMetrics::new(&mut registry);
+        let sink = StatsdSink::new(server.local_addr().unwrap(), "home", Vec::new(), &metrics).unwrap();
+
+        sink.accept(TemperatureCelsius::from(-10.1), Humidity::from(65.2));
+
+        assert_eq!("home.temperature:-10.1|g", recv(&server));
+        assert_eq!("home.humidity:65.2|g", recv(&server));
+    }
+}
@@ -0,0 +1,518 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+use crate::clock::Clock;
+use crate::metrics::{CounterValues, Locks};
+use crate::sensor::{Humidity, LatestReading, Measurement, ReadingEvent, TemperatureCelsius};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt::{self, Formatter};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Error loading or saving the state file
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum StateError {
+    Io(PathBuf, io::Error),
+    Parse(PathBuf, serde_json::Error),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::Io(path, e) => write!(f, "unable to access state file {}: {}", path.display(), e),
+            StateError::Parse(path, e) => write!(f, "invalid state file {}: {}", path.display(), e),
+        }
+    }
+}
+
+impl Error for StateError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            StateError::Io(_, e) => Some(e),
+            StateError::Parse(_, e) => Some(e),
+        }
+    }
+}
+
+/// State kept between runs of `strudel`. Every field is optional so that files written
+/// by other versions can still be loaded.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct State {
+    #[serde(default)]
+    pub reading: Option<PersistedReading>,
+    /// Values of counters, only saved when enabled since restoring them changes their
+    /// meaning from "since `strudel` started" to "since the state file was created".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub counters: Option<CounterValues>,
+}
+
+impl State {
+    /// Get the persisted reading as a restored `LatestReading` if it was taken from
+    /// `sensor` and is no older than `max_age` according to `clock`.
+    pub fn reading(&self, sensor: Option<&str>, max_age: Duration, clock: &dyn Clock) -> Option<LatestReading> {
+        self.reading_at(sensor, max_age, clock.now_wall(), clock.now_monotonic())
+    }
+
+    /// Get the persisted reading as a restored `LatestReading` if it was taken from
+    /// `sensor` and is no older than `max_age` as of `now` and `instant`, the current
+    /// system and monotonic time.
+    ///
+    /// The age of the reading can only be determined from the system clock since the
+    /// monotonic clock doesn't persist across restarts. Readings that appear to be from
+    /// the future, for example because the clock isn't synchronized yet after a reboot,
+    /// are never restored.
+    pub fn reading_at(
+        &self,
+        sensor: Option<&str>,
+        max_age: Duration,
+        now: SystemTime,
+        instant: Instant,
+    ) -> Option<LatestReading> {
+        let persisted = self.reading.as_ref().filter(|r| r.sensor.as_deref() == sensor)?;
+        let read_at = Duration::try_from_secs_f64(persisted.read_at)
+            .ok()
+            .and_then(|d| UNIX_EPOCH.checked_add(d))?;
+        let age = now.duration_since(read_at).ok().filter(|age| *age <= max_age)?;
+
+        let measurement = Measurement {
+            temperature: TemperatureCelsius::from(persisted.temperature),
+            humidity: Humidity::from(persisted.humidity),
+        };
+
+        let taken = instant.checked_sub(age).unwrap_or(instant);
+        Some(LatestReading::new(measurement, read_at, taken).restored())
+    }
+}
+
+/// Most recent successful reading of a sensor as stored in the state file. Temperature
+/// is in celsius and the time of the reading is a UNIX timestamp in seconds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PersistedReading {
+    #[serde(default)]
+    pub sensor: Option<String>,
+    pub temperature: f64,
+    pub humidity: f64,
+    pub read_at: f64,
+}
+
+impl PersistedReading {
+    /// Create a reading to persist from sensor `sensor` taken at `read_at`.
+    pub fn new(sensor: Option<&str>, measurement: Measurement, read_at: SystemTime) -> Self {
+        Self {
+            sensor: sensor.map(str::to_owned),
+            temperature: measurement.temperature.into(),
+            humidity: measurement.humidity.into(),
+            read_at: read_at
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs_f64())
+                .unwrap_or(0.0),
+        }
+    }
+}
+
+/// JSON file used to keep state between runs of `strudel`, like the most recent reading
+/// so that metrics have values immediately after a restart.
+///
+/// The file is replaced atomically when saved by writing a temporary file next to it
+/// and renaming it so a crash while saving never leaves a partially written file.
+#[derive(Debug)]
+pub struct StateFile {
+    path: PathBuf,
+    sensor: Option<String>,
+    // Most recent reading loaded or saved, kept when saving after a failed read
+    last: Mutex<Option<PersistedReading>>,
+    // A panic while saving mustn't stop later saves
+    locks: Locks,
+}
+
+impl StateFile {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            sensor: None,
+            last: Mutex::new(None),
+            locks: Locks::default(),
+        }
+    }
+
+    /// Record readings as being taken from the sensor named `name`. Default unnamed.
+    pub fn sensor_name(mut self, name: Option<String>) -> Self {
+        self.sensor = name;
+        self
+    }
+
+    /// Path of the state file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Load state from the file, `None` if it doesn't exist yet.
+    pub fn load(&self) -> Result<Option<State>, StateError> {
+        let bytes = match fs::read(&self.path) {
+            Ok(b) => b,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(StateError::Io(self.path.clone(), e)),
+        };
+
+        let state: State = serde_json::from_slice(&bytes).map_err(|e| StateError::Parse(self.path.clone(), e))?;
+        *self.locks.lock(&self.last) = state.reading.clone();
+        Ok(Some(state))
+    }
+
+    /// Atomically replace the contents of the file with `state`.
+    pub fn save(&self, state: &State) -> Result<(), StateError> {
+        let tmp = self.tmp_path();
+        let io_err = |e| StateError::Io(tmp.clone(), e);
+
+        let mut file = File::create(&tmp).map_err(io_err)?;
+        serde_json::to_writer(&mut file, state).map_err(|e| io_err(e.into()))?;
+        file.sync_all().map_err(io_err)?;
+        fs::rename(&tmp, &self.path).map_err(|e| StateError::Io(self.path.clone(), e))
+    }
+
+    /// Save the reading of a successful read, ignoring failed reads. Errors saving are
+    /// logged. Intended to be used as a subscriber of a `SensorWorker`.
+    pub fn update(&self, event: &ReadingEvent) {
+        self.save_event(event, None);
+    }
+
+    /// Save the reading of a successful read along with `counters`. Failed reads change
+    /// counters so the file is saved after them too, keeping the previous reading.
+    pub fn update_with_counters(&self, event: &ReadingEvent, counters: CounterValues) {
+        self.save_event(event, Some(counters));
+    }
+
+    fn save_event(&self, event: &ReadingEvent, counters: Option<CounterValues>) {
+        let mut last = self.locks.lock(&self.last);
+        match (&event.result, &counters) {
+            (Ok(m), _) => *last = Some(PersistedReading::new(self.sensor.as_deref(), *m, event.timestamp)),
+            (Err(_), None) => return,
+            (Err(_), Some(_)) => {}
+        }
+
+        let state = State {
+            reading: last.clone(),
+            counters,
+        };
+
+        if let Err(e) = self.save(&state) {
+            tracing::warn!(message = "unable to save state file", error = %e);
+        }
+    }
+
+    fn tmp_path(&self) -> PathBuf {
+        let mut name = self.path.file_name().map(OsString::from).unwrap_or_default();
+        name.push(".tmp");
+        self.path.with_file_name(name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PersistedReading, State, StateError, StateFile};
+    use crate::clock::{Clock, MockClock};
+    use crate::metrics::{CounterValues, Locks, POISONING_TEST};
+    use crate::sensor::{Clamped, Humidity, Measurement, ReadingEvent, SensorError, TemperatureCelsius};
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::PoisonError;
+    use std::thread;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+    use tracing::Span;
+
+    const MAX_AGE: Duration = Duration::from_secs(600);
+
+    /// Temporary directory for state files, removed when dropped
+    struct TempDir {
+        path: PathBuf,
+    }
+
+    impl TempDir {
+        fn new() -> Self {
+            static COUNTER: AtomicUsize = AtomicUsize::new(0);
+            let path = std::env::temp_dir().join(format!(
+                "strudel-state-{}-{}",
+                process::id(),
+                COUNTER.fetch_add(1, Ordering::SeqCst)
+            ));
+
+            fs::create_dir_all(&path).unwrap();
+            Self { path }
+        }
+
+        fn state_file(&self) -> StateFile {
+            StateFile::new(self.path.join("state.json"))
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+
+    fn measurement() -> Measurement {
+        Measurement {
+            temperature: TemperatureCelsius::from(21.5),
+            humidity: Humidity::from(40.0),
+        }
+    }
+
+    fn event(result: Result<Measurement, SensorError>, timestamp: SystemTime) -> ReadingEvent {
+        ReadingEvent {
+            timestamp,
+            instant: Instant::now(),
+            result,
+            clamped: Clamped::default(),
+            attempts: 1,
+            retried_errors: Vec::new(),
+            duration: Duration::ZERO,
+            raw: Vec::new(),
+            pulses: None,
+            span: Span::none(),
+        }
+    }
+
+    #[test]
+    fn test_state_file_missing() {
+        let dir = TempDir::new();
+        assert_eq!(None, dir.state_file().load().unwrap());
+    }
+
+    #[test]
+    fn test_state_file_round_trip() {
+        let dir = TempDir::new();
+        let file = dir.state_file().sensor_name(Some("indoor".to_owned()));
+        let read_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        file.update(&event(Ok(measurement()), read_at));
+
+        let state = file.load().unwrap().unwrap();
+        assert_eq!(
+            Some(PersistedReading {
+                sensor: Some("indoor".to_owned()),
+                temperature: 21.5,
+                humidity: 40.0,
+                read_at: 1_700_000_000.0,
+            }),
+            state.reading
+        );
+
+        // Nothing is left behind from writing the file atomically
+        let entries: Vec<_> = fs::read_dir(&dir.path)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(vec!["state.json"], entries);
+    }
+
+    #[test]
+    fn test_state_file_update_ignores_failures() {
+        let dir = TempDir::new();
+        let file = dir.state_file();
+        let read_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        file.update(&event(Ok(measurement()), read_at));
+        file.update(&event(Err(SensorError::timeout("timeout")), read_at + MAX_AGE));
+
+        let state = file.load().unwrap().unwrap();
+        assert_eq!(1_700_000_000.0, state.reading.unwrap().read_at);
+    }
+
+    #[test]
+    fn test_state_file_update_after_poisoned() {
+        let _poisoning = POISONING_TEST.lock().unwrap_or_else(PoisonError::into_inner);
+        let before = Locks::default().recoveries();
+        let dir = TempDir::new();
+        let file = dir.state_file();
+        let read_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        // Poison the lock by panicking while holding it
+        thread::scope(|s| {
+            let res = s
+                .spawn(|| {
+                    let _last = file.last.lock().unwrap();
+                    panic!("poisoning state file lock");
+                })
+                .join();
+            assert!(res.is_err());
+        });
+
+        file.update(&event(Ok(measurement()), read_at));
+        let state = file.load().unwrap().unwrap();
+        assert_eq!(1_700_000_000.0, state.reading.unwrap().read_at);
+        // Counted with recoveries of locks used by metrics
+        assert_eq!(before + 1, Locks::default().recoveries());
+    }
+
+    #[test]
+    fn test_state_file_update_with_counters() {
+        let dir = TempDir::new();
+        let file = dir.state_file();
+        let read_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        let mut counters = CounterValues {
+            collections: 1,
+            ..Default::default()
+        };
+        file.update_with_counters(&event(Ok(measurement()), read_at), counters.clone());
+
+        // Failed reads still save counters but keep the previous reading
+        counters.collections = 2;
+        file.update_with_counters(
+            &event(Err(SensorError::timeout("timeout")), read_at + MAX_AGE),
+            counters.clone(),
+        );
+
+        let state = file.load().unwrap().unwrap();
+        assert_eq!(1_700_000_000.0, state.reading.unwrap().read_at);
+        assert_eq!(Some(counters), state.counters);
+    }
+
+    #[test]
+    fn test_state_file_update_with_counters_after_load() {
+        let dir = TempDir::new();
+        let read_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        dir.state_file().update(&event(Ok(measurement()), read_at));
+
+        // A failed read right after a restart keeps the reading loaded from the file
+        let file = dir.state_file();
+        file.load().unwrap();
+        file.update_with_counters(
+            &event(Err(SensorError::timeout("timeout")), read_at + MAX_AGE),
+            CounterValues::default(),
+        );
+
+        let state = file.load().unwrap().unwrap();
+        assert_eq!(1_700_000_000.0, state.reading.unwrap().read_at);
+        assert_eq!(Some(CounterValues::default()), state.counters);
+    }
+
+    #[test]
+    fn test_state_file_without_counters() {
+        let dir = TempDir::new();
+        let file = dir.state_file();
+
+        // Files written before counters were persisted
+        fs::write(file.path(), r#"{"reading":null}"#).unwrap();
+        assert_eq!(None, file.load().unwrap().unwrap().counters);
+
+        fs::write(file.path(), r#"{"counters":{}}"#).unwrap();
+        assert_eq!(Some(CounterValues::default()), file.load().unwrap().unwrap().counters);
+    }
+
+    #[test]
+    fn test_state_file_corrupt() {
+        let dir = TempDir::new();
+        let file = dir.state_file();
+        fs::write(file.path(), "{\"reading\": {\"temperature\": ").unwrap();
+
+        assert!(matches!(file.load(), Err(StateError::Parse(_, _))));
+    }
+
+    #[test]
+    fn test_state_file_unreadable() {
+        let dir = TempDir::new();
+        let file = StateFile::new(&dir.path);
+
+        assert!(matches!(file.load(), Err(StateError::Io(_, _))));
+    }
+
+    #[test]
+    fn test_state_file_missing_fields() {
+        let dir = TempDir::new();
+        let file = dir.state_file();
+        fs::write(file.path(), "{}").unwrap();
+        assert_eq!(Some(State::default()), file.load().unwrap());
+
+        fs::write(
+            file.path(),
+            r#"{"reading":{"temperature":21.5,"humidity":40.0,"read_at":1700000000.0},"unknown":1}"#,
+        )
+        .unwrap();
+        assert_eq!(None, file.load().unwrap().unwrap().reading.unwrap().sensor);
+    }
+
+    #[test]
+    fn test_state_reading_restored() {
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        let state = State {
+            reading: Some(PersistedReading::new(None, measurement(), clock.now_wall())),
+            counters: None,
+        };
+
+        clock.advance(Duration::from_secs(60));
+        let reading = state.reading(None, MAX_AGE, &clock).unwrap();
+
+        assert!(reading.restored);
+        assert_eq!(TemperatureCelsius::from(21.5), reading.temperature);
+        assert_eq!(Humidity::from(40.0), reading.humidity);
+        assert_eq!(UNIX_EPOCH + Duration::from_secs(1_700_000_000), reading.read_at);
+        assert_eq!(Duration::from_secs(60), reading.age(&clock));
+    }
+
+    #[test]
+    fn test_state_reading_max_age() {
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        let state = State {
+            reading: Some(PersistedReading::new(None, measurement(), clock.now_wall())),
+            counters: None,
+        };
+
+        clock.advance(MAX_AGE);
+        assert!(state.reading(None, MAX_AGE, &clock).is_some());
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(None, state.reading(None, MAX_AGE, &clock));
+    }
+
+    #[test]
+    fn test_state_reading_from_future() {
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(30));
+        let state = State {
+            reading: Some(PersistedReading::new(
+                None,
+                measurement(),
+                UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            )),
+            counters: None,
+        };
+
+        // The clock isn't synchronized so the age of the reading can't be known
+        assert_eq!(None, state.reading(None, MAX_AGE, &clock));
+    }
+
+    #[test]
+    fn test_state_reading_other_sensor() {
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        let state = State {
+            reading: Some(PersistedReading::new(Some("indoor"), measurement(), clock.now_wall())),
+            counters: None,
+        };
+
+        assert_eq!(None, state.reading(None, MAX_AGE, &clock));
+        assert_eq!(None, state.reading(Some("outdoor"), MAX_AGE, &clock));
+        assert!(state.reading(Some("indoor"), MAX_AGE, &clock).is_some());
+    }
+}
@@ -0,0 +1,271 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+use crate::health::OutcomeWindow;
+use crate::sensor::{Measurement, ReadingEvent};
+use std::collections::BTreeMap;
+use std::fmt::{self, Formatter};
+use std::time::Duration;
+
+/// Periodically log a summary of reads of the sensor at `INFO` level so that there's
+/// some indication it's working without enabling debug logging.
+///
+/// A summary is logged after every `every` reads, successful or not, as a single event
+/// with the most recent reading, the fraction of reads that succeeded, the number of
+/// errors by kind, and the average time taken to read the sensor. The success ratio is
+/// computed over a sliding window of `every` refresh intervals while everything else
+/// covers the reads since the last summary. Intended to be used as a subscriber of a
+/// `SensorWorker`.
+#[derive(Debug)]
+pub struct ReadSummary {
+    every: u32,
+    outcomes: OutcomeWindow,
+    reads: u32,
+    errors: BTreeMap<&'static str, u64>,
+    last: Option<Measurement>,
+    total_duration: Duration,
+}
+
+impl ReadSummary {
+    /// Create a summary logged every `every` reads of a sensor read every `refresh`.
+    pub fn new(every: u32, refresh: Duration) -> Self {
+        Self {
+            every: every.max(1),
+            outcomes: OutcomeWindow::new(refresh.saturating_mul(every)),
+            reads: 0,
+            errors: BTreeMap::new(),
+            last: None,
+            total_duration: Duration::ZERO,
+        }
+    }
+
+    /// Record the result of a read, logging a summary if enough reads have happened.
+    pub fn update(&mut self, event: &ReadingEvent) {
+        if let Some(summary) = self.record(event) {
+            tracing::info!(
+                message = "sensor read summary",
+                reads = summary.reads,
+                success_ratio = summary.success_ratio,
+                errors = %summary.errors,
+                temperature = summary.last.map(|m| f64::from(m.temperature)),
+                humidity = summary.last.map(|m| f64::from(m.humidity)),
+                avg_read_secs = summary.avg_read_duration.as_secs_f64(),
+            );
+        }
+    }
+
+    fn record(&mut self, event: &ReadingEvent) -> Option<Summary> {
+        self.outcomes.record(event.instant, event.result.is_ok());
+        self.reads += 1;
+        self.total_duration += event.duration;
+
+        for kind in event.retried_errors.iter() {
+            *self.errors.entry(kind.as_label()).or_default() += 1;
+        }
+
+        match &event.result {
+            Ok(m) => self.last = Some(*m),
+            Err(e) => *self.errors.entry(e.kind().as_label()).or_default() += 1,
+        }
+
+        if self.reads < self.every {
+            return None;
+        }
+
+        let summary = Summary {
+            reads: self.reads,
+            success_ratio: self.outcomes.failure_ratio().map(|r| 1.0 - r).unwrap_or(0.0),
+            errors: ErrorCounts(std::mem::take(&mut self.errors)),
+            last: self.last.take(),
+            avg_read_duration: self.total_duration / self.reads,
+        };
+
+        self.reads = 0;
+        self.total_duration = Duration::ZERO;
+        Some(summary)
+    }
+}
+
+/// Values logged for a summary of reads.
+#[derive(Debug, Clone, PartialEq)]
+struct Summary {
+    reads: u32,
+    success_ratio: f64,
+    errors: ErrorCounts,
+    last: Option<Measurement>,
+    avg_read_duration: Duration,
+}
+
+/// Number of errors by kind, displayed as `kind=count` pairs or `none`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ErrorCounts(BTreeMap<&'static str, u64>);
+
+impl fmt::Display for ErrorCounts {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return f.write_str("none");
+        }
+
+        for (i, (kind, count)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+
+            write!(f, "{}={}", kind, count)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ReadSummary;
+    use crate::sensor::{
+        Clamped, Humidity, Measurement, ReadingEvent, SensorError, SensorErrorKind, TemperatureCelsius,
+    };
+    use std::io;
+    use std::sync::{Arc, Mutex, OnceLock};
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+    use tracing::Span;
+
+    const REFRESH: Duration = Duration::from_secs(30);
+
+    /// Monotonic time every event is relative to.
+    fn start() -> Instant {
+        static START: OnceLock<Instant> = OnceLock::new();
+        *START.get_or_init(Instant::now)
+    }
+
+    fn event(n: u64, result: Result<f64, SensorError>, retried: &[SensorErrorKind], millis: u64) -> ReadingEvent {
+        ReadingEvent {
+            timestamp: UNIX_EPOCH + REFRESH * n as u32,
+            instant: start() + REFRESH * n as u32,
+            result: result.map(|t| Measurement {
+                temperature: TemperatureCelsius::from(t),
+                humidity: Humidity::from(40.0),
+            }),
+            clamped: Clamped::default(),
+            attempts: retried.len() as u32 + 1,
+            retried_errors: retried.to_vec(),
+            duration: Duration::from_millis(millis),
+            raw: Vec::new(),
+            pulses: None,
+            span: Span::none(),
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct CaptureWriter(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for CaptureWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Run `f` with logs at `INFO` and above captured, returning the captured lines.
+    fn capture_logs<F: FnOnce()>(f: F) -> Vec<String> {
+        let writer = CaptureWriter::default();
+        let make_writer = writer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::INFO)
+            .with_ansi(false)
+            .without_time()
+            .with_writer(move || make_writer.clone())
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, f);
+        let out = String::from_utf8(writer.0.lock().unwrap().clone()).unwrap();
+        out.lines().map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn test_summary_logged_every_n_reads() {
+        let mut summary = ReadSummary::new(4, REFRESH);
+        let lines = capture_logs(|| {
+            summary.update(&event(1, Ok(21.0), &[], 100));
+            summary.update(&event(2, Err(SensorError::timeout("timeout")), &[], 300));
+            summary.update(&event(3, Ok(21.5), &[SensorErrorKind::Checksum], 500));
+            summary.update(&event(4, Ok(22.0), &[], 100));
+            // Not enough reads for another summary
+            summary.update(&event(5, Ok(22.5), &[], 100));
+        });
+
+        let summaries: Vec<&String> = lines.iter().filter(|l| l.contains("sensor read summary")).collect();
+        assert_eq!(1, summaries.len(), "unexpected logs: {:?}", lines);
+
+        let line = summaries[0];
+        assert!(line.contains("INFO"), "unexpected log: {}", line);
+        assert!(line.contains("reads=4"), "unexpected log: {}", line);
+        assert!(line.contains("success_ratio=0.75"), "unexpected log: {}", line);
+        assert!(line.contains("errors=checksum=1,timeout=1"), "unexpected log: {}", line);
+        assert!(line.contains("temperature=22.0"), "unexpected log: {}", line);
+        assert!(line.contains("humidity=40.0"), "unexpected log: {}", line);
+        assert!(line.contains("avg_read_secs=0.25"), "unexpected log: {}", line);
+    }
+
+    #[test]
+    fn test_summary_logged_when_all_reads_fail() {
+        let mut summary = ReadSummary::new(3, REFRESH);
+        let lines = capture_logs(|| {
+            for n in 1..=6 {
+                let retried = [SensorErrorKind::ReadTimeout];
+                summary.update(&event(n, Err(SensorError::CheckSum(1, 2)), &retried, 200));
+            }
+        });
+
+        let summaries: Vec<&String> = lines.iter().filter(|l| l.contains("sensor read summary")).collect();
+        assert_eq!(2, summaries.len(), "unexpected logs: {:?}", lines);
+
+        for line in summaries {
+            assert!(line.contains("reads=3"), "unexpected log: {}", line);
+            assert!(line.contains("success_ratio=0.0"), "unexpected log: {}", line);
+            assert!(line.contains("errors=checksum=3,timeout=3"), "unexpected log: {}", line);
+            assert!(!line.contains("temperature="), "unexpected log: {}", line);
+            assert!(line.contains("avg_read_secs=0.2"), "unexpected log: {}", line);
+        }
+    }
+
+    #[test]
+    fn test_summary_success_ratio_sliding_window() {
+        let mut summary = ReadSummary::new(2, REFRESH);
+        assert_eq!(
+            None,
+            summary.record(&event(1, Err(SensorError::timeout("timeout")), &[], 0))
+        );
+
+        let first = summary
+            .record(&event(2, Err(SensorError::timeout("timeout")), &[], 0))
+            .unwrap();
+        assert_eq!(0.0, first.success_ratio);
+
+        // The window covers two refresh intervals so the oldest failure falls out of it
+        // while the newer one is still included.
+        assert_eq!(None, summary.record(&event(3, Ok(21.0), &[], 0)));
+        let second = summary.record(&event(4, Ok(21.0), &[], 0)).unwrap();
+        assert_eq!(2, second.reads);
+        assert_eq!("none", second.errors.to_string());
+        assert!(second.success_ratio > 0.5 && second.success_ratio < 1.0);
+    }
+}
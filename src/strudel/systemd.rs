@@ -0,0 +1,237 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! Support for systemd socket activation.
+//!
+//! When started by a systemd `.socket` unit, the listening socket is passed to
+//! `strudel` as file descriptor 3 and described by the `LISTEN_PID` and `LISTEN_FDS`
+//! environment variables. See `sd_listen_fds(3)` for details.
+
+use std::env;
+use std::error::Error;
+use std::fmt::{self, Formatter};
+use std::io;
+use std::net::TcpListener;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::process;
+
+/// First file descriptor passed by systemd
+pub const LISTEN_FDS_START: RawFd = 3;
+
+const LISTEN_PID: &str = "LISTEN_PID";
+const LISTEN_FDS: &str = "LISTEN_FDS";
+
+/// Error using a socket passed by systemd
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ActivationError {
+    InvalidVar(&'static str, String),
+    UnexpectedCount(usize),
+    InvalidSocket(RawFd, io::Error),
+}
+
+impl fmt::Display for ActivationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ActivationError::InvalidVar(name, val) => write!(f, "invalid value for {}: '{}'", name, val),
+            ActivationError::UnexpectedCount(n) => write!(f, "expected exactly one socket from systemd, got {}", n),
+            ActivationError::InvalidSocket(fd, e) => {
+                write!(f, "file descriptor {} is not a listening socket: {}", fd, e)
+            }
+        }
+    }
+}
+
+impl Error for ActivationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ActivationError::InvalidSocket(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Return true if systemd passed sockets to this process. Invalid values for the
+/// environment variables are treated as being activated so that `take_listener` can
+/// report the problem.
+pub fn is_activated() -> bool {
+    let listen_pid = env::var(LISTEN_PID).ok();
+    let listen_fds = env::var(LISTEN_FDS).ok();
+
+    parse_listen_fds(listen_pid.as_deref(), listen_fds.as_deref(), process::id())
+        .map(|n| n > 0)
+        .unwrap_or(true)
+}
+
+/// Parse the values of `LISTEN_PID` and `LISTEN_FDS`, returning the number of sockets
+/// passed to the process with ID `pid`. Zero is returned if the variables are unset or
+/// are meant for a different process.
+pub fn parse_listen_fds(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    pid: u32,
+) -> Result<usize, ActivationError> {
+    let (listen_pid, listen_fds) = match (listen_pid, listen_fds) {
+        (Some(p), Some(f)) => (p, f),
+        _ => return Ok(0),
+    };
+
+    let target: u32 = listen_pid
+        .trim()
+        .parse()
+        .map_err(|_| ActivationError::InvalidVar(LISTEN_PID, listen_pid.to_owned()))?;
+
+    if target != pid {
+        return Ok(0);
+    }
+
+    listen_fds
+        .trim()
+        .parse()
+        .map_err(|_| ActivationError::InvalidVar(LISTEN_FDS, listen_fds.to_owned()))
+}
+
+/// Take ownership of `fd` as a TCP listener after verifying that it is a listening
+/// stream socket.
+///
+/// # Safety
+///
+/// `fd` must be an open file descriptor not owned by anything else in this process.
+pub unsafe fn listener_from_fd(fd: RawFd) -> Result<TcpListener, ActivationError> {
+    let sock_type = get_sock_opt(fd, libc::SO_TYPE).map_err(|e| ActivationError::InvalidSocket(fd, e))?;
+    if sock_type != libc::SOCK_STREAM {
+        return Err(ActivationError::InvalidSocket(
+            fd,
+            io::Error::new(io::ErrorKind::InvalidInput, "not a stream socket"),
+        ));
+    }
+
+    let listening = get_sock_opt(fd, libc::SO_ACCEPTCONN).map_err(|e| ActivationError::InvalidSocket(fd, e))?;
+    if listening == 0 {
+        return Err(ActivationError::InvalidSocket(
+            fd,
+            io::Error::new(io::ErrorKind::InvalidInput, "socket is not listening"),
+        ));
+    }
+
+    // Make sure the socket isn't leaked to any child processes.
+    libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+    Ok(TcpListener::from_raw_fd(fd))
+}
+
+/// Get the TCP listener passed to this process by systemd, if any.
+///
+/// Only a single socket is supported. The `LISTEN_PID` and `LISTEN_FDS` environment
+/// variables are removed so that they aren't inherited by child processes.
+///
+/// Dropping the returned listener only closes this process's copy of the socket,
+/// systemd keeps its own copy and can pass it to the next instance of `strudel`.
+pub fn take_listener() -> Result<Option<TcpListener>, ActivationError> {
+    let listen_pid = env::var(LISTEN_PID).ok();
+    let listen_fds = env::var(LISTEN_FDS).ok();
+    env::remove_var(LISTEN_PID);
+    env::remove_var(LISTEN_FDS);
+
+    match parse_listen_fds(listen_pid.as_deref(), listen_fds.as_deref(), process::id())? {
+        0 => Ok(None),
+        // Safe because systemd passed us this descriptor and nothing else uses it
+        1 => unsafe { listener_from_fd(LISTEN_FDS_START).map(Some) },
+        n => Err(ActivationError::UnexpectedCount(n)),
+    }
+}
+
+unsafe fn get_sock_opt(fd: RawFd, opt: libc::c_int) -> io::Result<libc::c_int> {
+    let mut val: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let res = libc::getsockopt(
+        fd,
+        libc::SOL_SOCKET,
+        opt,
+        &mut val as *mut libc::c_int as *mut libc::c_void,
+        &mut len,
+    );
+
+    if res == 0 {
+        Ok(val)
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{listener_from_fd, parse_listen_fds, ActivationError};
+    use std::net::TcpListener;
+    use std::os::unix::io::{AsRawFd, IntoRawFd};
+    use std::os::unix::net::{UnixDatagram, UnixStream};
+
+    #[test]
+    fn test_parse_listen_fds_unset() {
+        assert_eq!(0, parse_listen_fds(None, None, 123).unwrap());
+        assert_eq!(0, parse_listen_fds(Some("123"), None, 123).unwrap());
+        assert_eq!(0, parse_listen_fds(None, Some("1"), 123).unwrap());
+    }
+
+    #[test]
+    fn test_parse_listen_fds_other_pid() {
+        assert_eq!(0, parse_listen_fds(Some("456"), Some("1"), 123).unwrap());
+    }
+
+    #[test]
+    fn test_parse_listen_fds_this_pid() {
+        assert_eq!(1, parse_listen_fds(Some("123"), Some("1"), 123).unwrap());
+        assert_eq!(2, parse_listen_fds(Some("123"), Some("2"), 123).unwrap());
+    }
+
+    #[test]
+    fn test_parse_listen_fds_invalid() {
+        assert!(matches!(
+            parse_listen_fds(Some("abc"), Some("1"), 123),
+            Err(ActivationError::InvalidVar("LISTEN_PID", _))
+        ));
+        assert!(matches!(
+            parse_listen_fds(Some("123"), Some("-1"), 123),
+            Err(ActivationError::InvalidVar("LISTEN_FDS", _))
+        ));
+    }
+
+    #[test]
+    fn test_listener_from_fd_socketpair() {
+        // Connected stream sockets are valid sockets but aren't listening
+        let (a, _b) = UnixStream::pair().unwrap();
+        let res = unsafe { listener_from_fd(a.as_raw_fd()) };
+        assert!(matches!(res, Err(ActivationError::InvalidSocket(_, _))));
+    }
+
+    #[test]
+    fn test_listener_from_fd_datagram() {
+        let (a, _b) = UnixDatagram::pair().unwrap();
+        let res = unsafe { listener_from_fd(a.as_raw_fd()) };
+        assert!(matches!(res, Err(ActivationError::InvalidSocket(_, _))));
+    }
+
+    #[test]
+    fn test_listener_from_fd_listening() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let fd = listener.into_raw_fd();
+
+        let res = unsafe { listener_from_fd(fd) }.unwrap();
+        assert_eq!(addr, res.local_addr().unwrap());
+    }
+}
@@ -0,0 +1,364 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! Golden file tests for the wire format of metrics.
+//!
+//! Metrics are built deterministically, encoded, normalized, and compared to a file
+//! checked in under `testdata`. Run tests with `UPDATE_GOLDEN=1` to rewrite the files
+//! after an intended change to metrics and review the difference before committing it.
+
+use crate::clock::{Clock, MockClock};
+use crate::metrics::{BuildMetrics, HttpMetrics, Registries, TemperatureMetrics};
+use crate::sensor::{Clamped, Humidity, Measurement, ReadingEvent, SensorError, SensorErrorKind, TemperatureCelsius};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+use tracing::Span;
+
+/// Replaces the values of samples that depend on when or how fast tests run.
+const VOLATILE: &str = "<volatile>";
+
+/// Suffixes added to the names of metric families for their samples.
+const SAMPLE_SUFFIXES: &[&str] = &["_bucket", "_count", "_sum", "_total", "_created"];
+
+/// Path of a golden file in the `testdata` directory.
+pub fn golden_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join(name)
+}
+
+/// Registries of metrics with fixed build information, times, and readings so that
+/// encoding them always gives the same output.
+pub fn golden_registries() -> Registries {
+    let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    let shared: Arc<dyn Clock> = Arc::new(clock.clone());
+    let mut registries = Registries::new();
+
+    BuildMetrics::register_with(
+        registries.group("build"),
+        "1.2.3",
+        "0123456789abcdef",
+        "aarch64-unknown-linux-gnu",
+        "1.70.0",
+        "cdev,rppal",
+    );
+
+    let metrics = TemperatureMetrics::new(registries.group("sensor"))
+        .sensor_name(Some("indoor".to_owned()))
+        .clock(shared.clone())
+        .legacy_names(registries.group("legacy"));
+
+    let events = [
+        (Ok(21.5), Vec::new(), Clamped::default()),
+        (Ok(21.7), vec![SensorErrorKind::Checksum], Clamped::default()),
+        (Err(SensorError::timeout("timeout")), Vec::new(), Clamped::default()),
+        (
+            Ok(22.0),
+            Vec::new(),
+            Clamped {
+                temperature: false,
+                humidity: true,
+            },
+        ),
+    ];
+
+    for (result, retried_errors, clamped) in events {
+        metrics.update(&ReadingEvent {
+            timestamp: clock.now_wall(),
+            instant: clock.now_monotonic(),
+            result: result.map(|t| Measurement {
+                temperature: TemperatureCelsius::from(t),
+                humidity: Humidity::from(45.0),
+            }),
+            clamped,
+            attempts: retried_errors.len() as u32 + 1,
+            retried_errors,
+            duration: Duration::from_millis(250),
+            raw: Vec::new(),
+            pulses: None,
+            span: Span::none(),
+        });
+
+        clock.advance(Duration::from_secs(30));
+    }
+
+    let http = HttpMetrics::with_clock(registries.group("http"), shared);
+    http.scrape();
+    http.encoded(Duration::from_micros(300));
+    clock.advance(Duration::from_secs(15));
+    http.scrape();
+
+    registries
+}
+
+/// Replace the values of samples of timestamps and durations, metrics named with a
+/// `_timestamp` or `_seconds` suffix, and of lock recoveries, counted for the whole
+/// process, with a placeholder and sort the series of each
+/// family by their labels since families with labels are encoded in no particular order.
+pub fn normalize(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut samples: Vec<(&str, String)> = Vec::new();
+
+    for line in text.lines() {
+        match split_sample(line) {
+            Some((series, _)) if is_volatile(series) => samples.push((series, format!("{} {}", series, VOLATILE))),
+            Some((series, _)) => samples.push((series, line.to_owned())),
+            None => {
+                flush_samples(&mut samples, &mut out);
+                out.push_str(line);
+                out.push('\n');
+            }
+        }
+    }
+
+    flush_samples(&mut samples, &mut out);
+    out
+}
+
+/// Write samples of a family to `out` sorted by their labels. The sort is stable and
+/// ignores the `le` label so that the buckets of a histogram stay in order.
+fn flush_samples(samples: &mut Vec<(&str, String)>, out: &mut String) {
+    samples.sort_by_key(|(series, _)| series_key(series));
+    for (_, line) in samples.drain(..) {
+        out.push_str(&line);
+        out.push('\n');
+    }
+}
+
+fn series_key(series: &str) -> &str {
+    let labels = series.find('{').map(|i| &series[i..]).unwrap_or("");
+    // The `le` label is always last for histogram buckets
+    let labels = match labels.find("le=\"") {
+        Some(i) => &labels[..i],
+        None => labels,
+    };
+
+    labels.trim_end_matches(['{', ',', '}'])
+}
+
+/// Split a sample into its name and labels and everything after them: the value and
+/// an optional timestamp and exemplar. `None` for comments and blank lines.
+fn split_sample(line: &str) -> Option<(&str, &str)> {
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+
+    // Label values may contain spaces so only split at a space outside of quotes
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            ' ' if !quoted => return Some((&line[..i], &line[i + 1..])),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+fn is_volatile(series: &str) -> bool {
+    let name = series.split('{').next().unwrap_or(series);
+    let family = SAMPLE_SUFFIXES
+        .iter()
+        .find_map(|s| name.strip_suffix(s))
+        .unwrap_or(name);
+
+    family.ends_with("_timestamp") || family.ends_with("_seconds") || family == "strudel_lock_recoveries"
+}
+
+/// Compare `actual` to the contents of the golden file at `path`, panicking with the
+/// lines that differ if they aren't the same. The file is written instead when the
+/// `UPDATE_GOLDEN` environment variable is set to `1`.
+pub fn assert_golden(path: &Path, actual: &str) {
+    if env::var("UPDATE_GOLDEN").map(|v| v == "1").unwrap_or(false) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, actual).unwrap();
+        return;
+    }
+
+    let expected = fs::read_to_string(path).unwrap_or_else(|e| {
+        panic!(
+            "unable to read golden file {}, run with UPDATE_GOLDEN=1 to create it: {}",
+            path.display(),
+            e
+        )
+    });
+
+    if expected != actual {
+        panic!(
+            "output doesn't match golden file {}, run with UPDATE_GOLDEN=1 to update it if \
+             this is expected (- expected, + actual):\n{}",
+            path.display(),
+            diff(&expected, actual)
+        );
+    }
+}
+
+/// Lines of `expected` and `actual` that differ, prefixed with `-` for lines only in
+/// `expected` and `+` for lines only in `actual`, based on their longest common
+/// subsequence. Unchanged lines are left out.
+fn diff(expected: &str, actual: &str) -> String {
+    let a: Vec<&str> = expected.lines().collect();
+    let b: Vec<&str> = actual.lines().collect();
+
+    // Length of the longest common subsequence of a[i..] and b[j..]
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            out.push_str(&format!("- {}\n", a[i]));
+            i += 1;
+        } else {
+            out.push_str(&format!("+ {}\n", b[j]));
+            j += 1;
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{assert_golden, diff, golden_path, golden_registries, normalize, split_sample};
+
+    #[test]
+    fn test_split_sample() {
+        assert_eq!(None, split_sample("# TYPE strudel_scrapes counter"));
+        assert_eq!(None, split_sample(""));
+        assert_eq!(
+            Some(("strudel_scrapes_total", "2")),
+            split_sample("strudel_scrapes_total 2")
+        );
+        assert_eq!(
+            Some(("strudel_build_info{rustc=\"rustc 1.70.0 (a b)\"}", "1")),
+            split_sample("strudel_build_info{rustc=\"rustc 1.70.0 (a b)\"} 1")
+        );
+        assert_eq!(
+            Some(("a{b=\"c \\\" d\"}", "1 # {trace_id=\"x\"} 1")),
+            split_sample("a{b=\"c \\\" d\"} 1 # {trace_id=\"x\"} 1")
+        );
+    }
+
+    #[test]
+    fn test_normalize_volatile() {
+        let text = "# HELP strudel_last_read_timestamp Last read\n\
+                    strudel_last_read_timestamp{sensor=\"indoor\"} 1700000000.0\n\
+                    # TYPE strudel_scrape_gap_seconds gauge\n\
+                    strudel_scrape_gap_seconds 15.0\n\
+                    # TYPE strudel_scrape_encode_duration_seconds histogram\n\
+                    strudel_scrape_encode_duration_seconds_sum 0.0003\n\
+                    strudel_scrape_encode_duration_seconds_bucket{le=\"0.0001\"} 0\n\
+                    strudel_scrape_encode_duration_seconds_count 1\n\
+                    # EOF\n";
+
+        assert_eq!(
+            "# HELP strudel_last_read_timestamp Last read\n\
+             strudel_last_read_timestamp{sensor=\"indoor\"} <volatile>\n\
+             # TYPE strudel_scrape_gap_seconds gauge\n\
+             strudel_scrape_gap_seconds <volatile>\n\
+             # TYPE strudel_scrape_encode_duration_seconds histogram\n\
+             strudel_scrape_encode_duration_seconds_sum <volatile>\n\
+             strudel_scrape_encode_duration_seconds_bucket{le=\"0.0001\"} <volatile>\n\
+             strudel_scrape_encode_duration_seconds_count <volatile>\n\
+             # EOF\n",
+            normalize(text)
+        );
+    }
+
+    #[test]
+    fn test_normalize_stable() {
+        let text = "# TYPE strudel_temperature_degrees gauge\n\
+                    strudel_temperature_degrees{sensor=\"indoor\"} 21.5\n\
+                    # TYPE strudel_collections counter\n\
+                    strudel_collections_total 4\n\
+                    # TYPE strudel_reads counter\n\
+                    strudel_reads_total{outcome=\"failure\"} 1\n\
+                    # TYPE strudel_error_ratio_5m gauge\n\
+                    strudel_error_ratio_5m 0.25\n";
+
+        assert_eq!(text, normalize(text));
+    }
+
+    #[test]
+    fn test_normalize_sorts_series() {
+        let text = "# TYPE strudel_reads counter\n\
+                    strudel_reads_total{outcome=\"success_retried\"} 1\n\
+                    strudel_reads_total{outcome=\"failure\"} 1\n\
+                    # TYPE strudel_latency_seconds histogram\n\
+                    strudel_latency_seconds_sum{op=\"b\"} 1.0\n\
+                    strudel_latency_seconds_bucket{op=\"b\",le=\"2.0\"} 1\n\
+                    strudel_latency_seconds_bucket{op=\"b\",le=\"+Inf\"} 1\n\
+                    strudel_latency_seconds_sum{op=\"a\"} 1.0\n\
+                    strudel_latency_seconds_bucket{op=\"a\",le=\"2.0\"} 1\n\
+                    strudel_latency_seconds_bucket{op=\"a\",le=\"+Inf\"} 1\n";
+
+        assert_eq!(
+            "# TYPE strudel_reads counter\n\
+             strudel_reads_total{outcome=\"failure\"} 1\n\
+             strudel_reads_total{outcome=\"success_retried\"} 1\n\
+             # TYPE strudel_latency_seconds histogram\n\
+             strudel_latency_seconds_sum{op=\"a\"} <volatile>\n\
+             strudel_latency_seconds_bucket{op=\"a\",le=\"2.0\"} <volatile>\n\
+             strudel_latency_seconds_bucket{op=\"a\",le=\"+Inf\"} <volatile>\n\
+             strudel_latency_seconds_sum{op=\"b\"} <volatile>\n\
+             strudel_latency_seconds_bucket{op=\"b\",le=\"2.0\"} <volatile>\n\
+             strudel_latency_seconds_bucket{op=\"b\",le=\"+Inf\"} <volatile>\n",
+            normalize(text)
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        assert_eq!("", diff("a\nb\n", "a\nb\n"));
+        assert_eq!("- b\n+ c\n", diff("a\nb\nd\n", "a\nc\nd\n"));
+        assert_eq!("+ b\n", diff("a\nc\n", "a\nb\nc\n"));
+        assert_eq!("- b\n", diff("a\nb\nc\n", "a\nc\n"));
+    }
+
+    #[test]
+    fn test_golden_registries_deterministic() {
+        let first = normalize(&golden_registries().encode().unwrap().text);
+        let second = normalize(&golden_registries().encode().unwrap().text);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_metrics_golden() {
+        let encoded = golden_registries().encode().unwrap();
+        assert!(encoded.failed.is_empty());
+        assert_golden(&golden_path("metrics.txt"), &normalize(&encoded.text));
+    }
+}
@@ -0,0 +1,110 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+use crate::sensor::{Humidity, Measurement, Sensor, SensorError, SensorErrorKind, TemperatureCelsius};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Sensor that returns the results of a script, one per read, in order.
+///
+/// Once the script runs out, the last successful reading is repeated. If the script
+/// didn't contain any, reads fail with a `SensorErrorKind::Internal` error.
+#[derive(Debug)]
+pub struct ScriptedSensor {
+    script: VecDeque<Result<Measurement, SensorError>>,
+    last: Option<Measurement>,
+    reads: Arc<AtomicUsize>,
+}
+
+impl ScriptedSensor {
+    pub fn new(script: Vec<Result<(TemperatureCelsius, Humidity), SensorError>>) -> Self {
+        Self {
+            script: script
+                .into_iter()
+                .map(|res| res.map(|(temperature, humidity)| Measurement { temperature, humidity }))
+                .collect(),
+            last: None,
+            reads: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Number of times the sensor has been read, shared with the sensor so that it can
+    /// be checked after the sensor has been moved somewhere else.
+    pub fn reads(&self) -> Arc<AtomicUsize> {
+        self.reads.clone()
+    }
+}
+
+impl Sensor for ScriptedSensor {
+    fn read(&mut self) -> Result<Measurement, SensorError> {
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        match self.script.pop_front() {
+            Some(Ok(m)) => {
+                self.last = Some(m);
+                Ok(m)
+            }
+            Some(Err(e)) => Err(e),
+            None => self
+                .last
+                .ok_or_else(|| SensorError::new(SensorErrorKind::Internal, "no scripted readings left")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ScriptedSensor;
+    use crate::sensor::{Humidity, Sensor, SensorError, SensorErrorKind, TemperatureCelsius};
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn test_scripted_sensor_in_order() {
+        let mut sensor = ScriptedSensor::new(vec![
+            Err(SensorError::timeout("timeout")),
+            Ok((TemperatureCelsius::from(21.5), Humidity::from(45.0))),
+        ]);
+
+        assert_eq!(SensorErrorKind::ReadTimeout, sensor.read().unwrap_err().kind());
+        let m = sensor.read().unwrap();
+        assert_eq!(TemperatureCelsius::from(21.5), m.temperature);
+        assert_eq!(Humidity::from(45.0), m.humidity);
+        assert_eq!(2, sensor.reads().load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_scripted_sensor_repeats_last_reading() {
+        let mut sensor = ScriptedSensor::new(vec![
+            Ok((TemperatureCelsius::from(21.5), Humidity::from(45.0))),
+            Err(SensorError::timeout("timeout")),
+        ]);
+
+        sensor.read().unwrap();
+        sensor.read().unwrap_err();
+        assert_eq!(TemperatureCelsius::from(21.5), sensor.read().unwrap().temperature);
+        assert_eq!(TemperatureCelsius::from(21.5), sensor.read().unwrap().temperature);
+    }
+
+    #[test]
+    fn test_scripted_sensor_exhausted() {
+        let mut sensor = ScriptedSensor::new(vec![Err(SensorError::timeout("timeout"))]);
+
+        sensor.read().unwrap_err();
+        assert_eq!(SensorErrorKind::Internal, sensor.read().unwrap_err().kind());
+    }
+}
@@ -0,0 +1,352 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! Minimal UDP protocol for querying the most recent reading, for legacy monitoring
+//! systems that can only poll with a UDP probe.
+//!
+//! Each query is a single packet, `GET temperature`, `GET humidity`, or `GET all`,
+//! optionally followed by a newline. Temperature and humidity are answered with a plain
+//! number and a newline, `all` with the reading as a line of JSON. Queries that can't be
+//! answered get a line starting with `ERR`. Oversized queries and queries from sources
+//! over their rate limit are dropped without a response so that the responder can't be
+//! used to amplify traffic.
+
+use crate::sensor::{LatestReading, LatestReadingCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+/// Largest query accepted, in bytes. Longer packets are dropped.
+pub const MAX_QUERY_BYTES: usize = 64;
+
+/// Largest response sent, in bytes, small enough to fit in a single packet on any network.
+pub const MAX_RESPONSE_BYTES: usize = 512;
+
+/// Queries answered for each source address per `DEFAULT_RATE_WINDOW` by default.
+pub const DEFAULT_RATE_LIMIT: u32 = 5;
+
+/// Window rate limits for each source address apply to by default.
+pub const DEFAULT_RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Most source addresses tracked at once by a `RateLimiter`, see `RateLimiter::allow`.
+const MAX_TRACKED_SOURCES: usize = 1024;
+
+/// Value asked for by a query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Query {
+    Temperature,
+    Humidity,
+    All,
+}
+
+/// Reason a query couldn't be parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryError {
+    /// The query is longer than `MAX_QUERY_BYTES`, it's dropped without a response.
+    TooLarge,
+    /// The query isn't one of the supported queries.
+    Unknown,
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::TooLarge => write!(f, "query larger than {} bytes", MAX_QUERY_BYTES),
+            QueryError::Unknown => write!(f, "unknown query"),
+        }
+    }
+}
+
+/// Parse a query from the contents of a single packet, ignoring a trailing newline and
+/// the case of the field name.
+pub fn parse_query(packet: &[u8]) -> Result<Query, QueryError> {
+    if packet.len() > MAX_QUERY_BYTES {
+        return Err(QueryError::TooLarge);
+    }
+
+    let text = std::str::from_utf8(packet).map_err(|_| QueryError::Unknown)?;
+    let text = text.strip_suffix('\n').unwrap_or(text);
+    let text = text.strip_suffix('\r').unwrap_or(text);
+    let field = text.strip_prefix("GET ").ok_or(QueryError::Unknown)?;
+
+    match field.to_ascii_lowercase().as_str() {
+        "temperature" => Ok(Query::Temperature),
+        "humidity" => Ok(Query::Humidity),
+        "all" => Ok(Query::All),
+        _ => Err(QueryError::Unknown),
+    }
+}
+
+/// Format the response to `query` for `reading`, `None` if the sensor hasn't been read yet.
+/// Responses are at most `MAX_RESPONSE_BYTES`.
+pub fn format_response(query: Query, reading: Option<&LatestReading>) -> String {
+    let Some(r) = reading else {
+        return "ERR no reading\n".to_owned();
+    };
+
+    let res = match query {
+        Query::Temperature => format!("{}\n", f64::from(r.temperature)),
+        Query::Humidity => format!("{}\n", f64::from(r.humidity)),
+        Query::All => format!(
+            "{}\n",
+            serde_json::to_string(r).expect("readings can always be serialized")
+        ),
+    };
+
+    if res.len() > MAX_RESPONSE_BYTES {
+        "ERR response too large\n".to_owned()
+    } else {
+        res
+    }
+}
+
+/// Format the response to a query that couldn't be parsed, `None` if it shouldn't be
+/// answered at all.
+pub fn format_error(err: QueryError) -> Option<String> {
+    match err {
+        QueryError::TooLarge => None,
+        QueryError::Unknown => Some(format!("ERR {}\n", err)),
+    }
+}
+
+/// Limit on the number of queries answered for each source address within a window.
+///
+/// Only a bounded number of sources are tracked. When that many sources have queried
+/// within their current window, queries from new sources aren't answered until one of
+/// the windows ends.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: u32,
+    window: Duration,
+    sources: HashMap<IpAddr, (Instant, u32)>,
+}
+
+impl RateLimiter {
+    /// Answer at most `limit` queries from each source address per `window`.
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            sources: HashMap::new(),
+        }
+    }
+
+    /// Return true if a query from `source` at `now` should be answered, counting it
+    /// towards the limit of the source if so.
+    pub fn allow(&mut self, source: IpAddr, now: Instant) -> bool {
+        if !self.sources.contains_key(&source) && self.sources.len() >= MAX_TRACKED_SOURCES {
+            let window = self.window;
+            self.sources.retain(|_, (start, _)| now.duration_since(*start) < window);
+            if self.sources.len() >= MAX_TRACKED_SOURCES {
+                return false;
+            }
+        }
+
+        let (start, count) = self.sources.entry(source).or_insert((now, 0));
+        if now.duration_since(*start) >= self.window {
+            *start = now;
+            *count = 0;
+        }
+
+        if *count >= self.limit {
+            return false;
+        }
+
+        *count += 1;
+        true
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(DEFAULT_RATE_LIMIT, DEFAULT_RATE_WINDOW)
+    }
+}
+
+/// Answer queries sent to `socket` from the most recent reading in `latest`, see
+/// `LatestReadingCell::newest`, until `socket` can't be read anymore.
+pub async fn serve(socket: UdpSocket, latest: Arc<LatestReadingCell>, mut limiter: RateLimiter) -> io::Result<()> {
+    // One extra byte to tell queries that are exactly the maximum size from larger ones
+    let mut buf = [0u8; MAX_QUERY_BYTES + 1];
+
+    loop {
+        let (len, source) = match socket.recv_from(&mut buf).await {
+            Ok(v) => v,
+            // Errors from a previous send to a client that's gone, like ICMP port
+            // unreachable, are reported by later receives and only affect that client
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => continue,
+            Err(e) => return Err(e),
+        };
+
+        if !limiter.allow(source.ip(), Instant::now()) {
+            tracing::debug!(message = "dropping udp query over rate limit", source = %source);
+            continue;
+        }
+
+        let res = match parse_query(&buf[..len]) {
+            Ok(query) => Some(format_response(query, latest.newest().as_ref())),
+            Err(e) => {
+                tracing::debug!(message = "invalid udp query", source = %source, error = %e);
+                format_error(e)
+            }
+        };
+
+        if let Some(res) = res {
+            if let Err(e) = socket.send_to(res.as_bytes(), source).await {
+                tracing::debug!(message = "unable to answer udp query", source = %source, error = %e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        format_error, format_response, parse_query, serve, Query, QueryError, RateLimiter, MAX_QUERY_BYTES,
+        MAX_TRACKED_SOURCES,
+    };
+    use crate::sensor::{Humidity, LatestReading, LatestReadingCell, Measurement, TemperatureCelsius};
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+    use tokio::net::UdpSocket;
+
+    fn reading() -> LatestReading {
+        LatestReading::new(
+            Measurement {
+                temperature: TemperatureCelsius::from(-4.5),
+                humidity: Humidity::from(41.25),
+            },
+            UNIX_EPOCH + Duration::from_secs(1_665_400_000),
+            Instant::now(),
+        )
+    }
+
+    #[test]
+    fn test_parse_query() {
+        assert_eq!(Ok(Query::Temperature), parse_query(b"GET temperature\n"));
+        assert_eq!(Ok(Query::Humidity), parse_query(b"GET humidity\r\n"));
+        assert_eq!(Ok(Query::All), parse_query(b"GET all"));
+        assert_eq!(Ok(Query::All), parse_query(b"GET ALL\n"));
+
+        assert_eq!(Err(QueryError::Unknown), parse_query(b""));
+        assert_eq!(Err(QueryError::Unknown), parse_query(b"GET pressure\n"));
+        assert_eq!(Err(QueryError::Unknown), parse_query(b"get all\n"));
+        assert_eq!(Err(QueryError::Unknown), parse_query(b"GET all\n\n"));
+        assert_eq!(Err(QueryError::Unknown), parse_query(b"GET \xff\n"));
+        assert_eq!(Err(QueryError::TooLarge), parse_query(&[b' '; MAX_QUERY_BYTES + 1]));
+    }
+
+    #[test]
+    fn test_format_response() {
+        let r = reading();
+        assert_eq!("-4.5\n", format_response(Query::Temperature, Some(&r)));
+        assert_eq!("41.25\n", format_response(Query::Humidity, Some(&r)));
+        assert_eq!(
+            "{\"temperature\":-4.5,\"humidity\":41.25,\"read_at\":1665400000.0}\n",
+            format_response(Query::All, Some(&r))
+        );
+        assert_eq!("ERR no reading\n", format_response(Query::All, None));
+    }
+
+    #[test]
+    fn test_format_error() {
+        assert_eq!(
+            Some("ERR unknown query\n".to_owned()),
+            format_error(QueryError::Unknown)
+        );
+        assert_eq!(None, format_error(QueryError::TooLarge));
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(2, Duration::from_secs(1));
+        let first = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10));
+        let second = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 11));
+        let now = Instant::now();
+
+        assert!(limiter.allow(first, now));
+        assert!(limiter.allow(first, now + Duration::from_millis(100)));
+        assert!(!limiter.allow(first, now + Duration::from_millis(200)));
+        // Each source has its own limit
+        assert!(limiter.allow(second, now + Duration::from_millis(200)));
+        // The limit resets once the window ends
+        assert!(limiter.allow(first, now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn test_rate_limiter_max_sources() {
+        let mut limiter = RateLimiter::new(1, Duration::from_secs(1));
+        let now = Instant::now();
+        for i in 0..MAX_TRACKED_SOURCES as u32 {
+            assert!(limiter.allow(IpAddr::V4(Ipv4Addr::from(i)), now));
+        }
+
+        let extra = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10));
+        assert!(!limiter.allow(extra, now));
+        // Sources whose windows have ended make room for new ones
+        assert!(limiter.allow(extra, now + Duration::from_secs(1)));
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let latest = Arc::new(LatestReadingCell::new());
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        tokio::spawn(serve(
+            socket,
+            latest.clone(),
+            RateLimiter::new(4, Duration::from_secs(60)),
+        ));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(address).await.unwrap();
+        let mut buf = [0u8; 1024];
+
+        client.send(b"GET temperature\n").await.unwrap();
+        let len = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(b"ERR no reading\n", &buf[..len]);
+
+        latest.set(reading());
+        client.send(b"GET humidity\n").await.unwrap();
+        let len = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(b"41.25\n", &buf[..len]);
+
+        // Oversized queries and queries over the rate limit aren't answered, both count towards the limit
+        client.send(&[b' '; MAX_QUERY_BYTES + 1]).await.unwrap();
+        client.send(b"GET all\n").await.unwrap();
+        client.send(b"GET all\n").await.unwrap();
+        let len = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(buf[..len].starts_with(b"{\"temperature\":-4.5"));
+        let res = tokio::time::timeout(Duration::from_millis(200), client.recv(&mut buf)).await;
+        assert!(res.is_err(), "unexpected response: {:?}", res);
+    }
+}
@@ -0,0 +1,60 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! Information about how `strudel` was built, captured at compile time.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Version of `strudel`
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Short git commit `strudel` was built from or `unknown`
+pub const GIT_COMMIT: &str = env!("STRUDEL_GIT_COMMIT");
+
+/// Target triple `strudel` was built for
+pub const TARGET: &str = env!("STRUDEL_BUILD_TARGET");
+
+/// Version of the Rust compiler used to build `strudel`
+pub const RUSTC_VERSION: &str = env!("STRUDEL_RUSTC_VERSION");
+
+/// Comma separated list of enabled cargo features
+pub const FEATURES: &str = env!("STRUDEL_FEATURES");
+
+/// UNIX timestamp in seconds of when `strudel` was built, or `SOURCE_DATE_EPOCH` if set
+pub const BUILD_TIMESTAMP: &str = env!("STRUDEL_BUILD_TIMESTAMP");
+
+/// Time `strudel` was built as set by `BUILD_TIMESTAMP`, the UNIX epoch if it's invalid
+pub fn build_time() -> SystemTime {
+    BUILD_TIMESTAMP
+        .parse::<u64>()
+        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+        .unwrap_or(UNIX_EPOCH)
+}
+
+/// Multi-line version information including all build details
+pub const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    "\ncommit: ",
+    env!("STRUDEL_GIT_COMMIT"),
+    "\ntarget: ",
+    env!("STRUDEL_BUILD_TARGET"),
+    "\nrustc: ",
+    env!("STRUDEL_RUSTC_VERSION"),
+    "\nfeatures: ",
+    env!("STRUDEL_FEATURES"),
+);
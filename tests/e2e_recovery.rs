@@ -0,0 +1,172 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! Boot the whole exporter with a sensor that times out for a few reads before recovering,
+//! fast-forwarding through refresh intervals with paused time, and check what's served at
+//! each stage. Requires the `testing` feature for `ScriptedSensor`.
+
+use clap::Parser;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, StatusCode};
+use std::net::{SocketAddr, TcpListener};
+use std::time::Duration;
+use strudel::app::Application;
+use strudel::cli::{self, StrudelApplication};
+use strudel::sensor::{Humidity, SensorError, TemperatureCelsius};
+use strudel::testing::ScriptedSensor;
+use tokio::sync::oneshot;
+
+const REFRESH: Duration = Duration::from_secs(30);
+const FAILURES: u64 = 3;
+
+/// Client for the HTTP server of a running `Application`
+struct Exporter {
+    client: Client<HttpConnector, Body>,
+    addr: SocketAddr,
+}
+
+impl Exporter {
+    async fn get(&self, path: &str) -> (StatusCode, String) {
+        let url = format!("http://{}{}", self.addr, path);
+        let res = self.client.get(url.parse().unwrap()).await.unwrap();
+        let status = res.status();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        (status, String::from_utf8(bytes.to_vec()).unwrap())
+    }
+
+    /// Scrape metrics once the sensor has been read `collections` times. Reads are done
+    /// on a blocking thread so they may not be finished as soon as they're due.
+    async fn metrics_after(&self, collections: u64) -> String {
+        for _ in 0..1000 {
+            let (status, body) = self.get("/metrics").await;
+            assert_eq!(StatusCode::OK, status);
+
+            let current = sample(&body, "strudel_collections_total").unwrap_or(0.0) as u64;
+            assert!(current <= collections, "sensor read too many times: {}", body);
+            if current == collections {
+                return body;
+            }
+
+            tokio::task::yield_now().await;
+        }
+
+        panic!("sensor wasn't read {} times", collections);
+    }
+}
+
+/// Value of the sample for `series` of the unnamed sensor, including any other labels, if
+/// there is one
+fn sample(body: &str, series: &str) -> Option<f64> {
+    let series = match series.split_once('{') {
+        Some((name, labels)) => format!("{}{{sensor=\"\",{}", name, labels),
+        None => format!("{}{{sensor=\"\"}}", series),
+    };
+
+    body.lines()
+        .filter_map(|line| line.strip_prefix(series.as_str())?.strip_prefix(' '))
+        .find_map(|value| value.parse().ok())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_recovery_after_timeouts() {
+    let mut script: Vec<_> = (0..FAILURES).map(|_| Err(SensorError::timeout("timeout"))).collect();
+    script.push(Ok((TemperatureCelsius::from(21.5), Humidity::from(45.0))));
+    let sensor = ScriptedSensor::new(script);
+
+    let args = StrudelApplication::try_parse_from([
+        "strudel",
+        "--bcm-pin",
+        "17",
+        "--refresh-secs",
+        "30",
+        "--read-retries",
+        "0",
+        "--degraded-after-failures",
+        "3",
+        "--healthy-after-successes",
+        "1",
+    ])
+    .unwrap();
+    let opts = cli::validate(args, false).unwrap();
+    let app = Application::builder(opts)
+        .sensor(Box::new(sensor))
+        .listener(TcpListener::bind("127.0.0.1:0").unwrap())
+        .build()
+        .await
+        .unwrap();
+
+    let exporter = Exporter {
+        client: Client::new(),
+        addr: app.local_addr().unwrap(),
+    };
+    let (stop, stopped) = oneshot::channel::<()>();
+    let running = tokio::spawn(app.run(async move {
+        let _ = stopped.await;
+    }));
+
+    // The first read happens as soon as the application is built, then once per refresh
+    for failures in 1..=FAILURES {
+        if failures > 1 {
+            tokio::time::advance(REFRESH).await;
+        }
+
+        let body = exporter.metrics_after(failures).await;
+        let errors = sample(&body, r#"strudel_errors_total{kind="timeout",attempt="final"}"#);
+        assert_eq!(Some(failures as f64), errors, "{}", body);
+        let failed = sample(&body, r#"strudel_reads_total{outcome="failure"}"#);
+        assert_eq!(Some(failures as f64), failed, "{}", body);
+        let succeeded = sample(&body, r#"strudel_reads_total{outcome="success_first_try"}"#);
+        assert_eq!(None, succeeded, "{}", body);
+
+        // Nothing has been read so values are zero and there are no readings to return
+        assert_eq!(Some(0.0), sample(&body, "strudel_temperature_degrees"), "{}", body);
+        assert_eq!(Some(0.0), sample(&body, "strudel_last_read_timestamp"), "{}", body);
+        assert_eq!(StatusCode::NOT_FOUND, exporter.get("/readings").await.0);
+
+        // Degraded only once there have been enough failures in a row
+        let degraded = failures >= FAILURES;
+        let healthy = sample(&body, "strudel_sensor_healthy");
+        assert_eq!(Some(if degraded { 0.0 } else { 1.0 }), healthy, "{}", body);
+        let transitions = sample(&body, r#"strudel_state_transitions_total{to="degraded"}"#);
+        assert_eq!(degraded.then_some(1.0), transitions, "{}", body);
+    }
+
+    tokio::time::advance(REFRESH).await;
+    let body = exporter.metrics_after(FAILURES + 1).await;
+    let errors = sample(&body, r#"strudel_errors_total{kind="timeout",attempt="final"}"#);
+    assert_eq!(Some(FAILURES as f64), errors, "{}", body);
+    let succeeded = sample(&body, r#"strudel_reads_total{outcome="success_first_try"}"#);
+    assert_eq!(Some(1.0), succeeded, "{}", body);
+    assert_eq!(Some(21.5), sample(&body, "strudel_temperature_degrees"), "{}", body);
+    assert_eq!(Some(45.0), sample(&body, "strudel_relative_humidity"), "{}", body);
+    assert_ne!(Some(0.0), sample(&body, "strudel_last_read_timestamp"), "{}", body);
+    assert_eq!(Some(1.0), sample(&body, "strudel_sensor_healthy"), "{}", body);
+    let transitions = sample(&body, r#"strudel_state_transitions_total{to="healthy"}"#);
+    assert_eq!(Some(1.0), transitions, "{}", body);
+
+    let (status, readings) = exporter.get("/readings").await;
+    assert_eq!(StatusCode::OK, status);
+    assert!(
+        readings.starts_with(r#"{"temperature":21.5,"humidity":45.0,"#),
+        "{}",
+        readings
+    );
+
+    stop.send(()).unwrap();
+    running.await.unwrap().unwrap();
+}
@@ -0,0 +1,86 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! Read a DHT22 using a pin implemented with only public items of this crate, built
+//! without the `rppal` feature, to make sure the public API doesn't depend on it.
+
+#![cfg(not(feature = "rppal"))]
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use strudel::sensor::{DHT22Sensor, DataPin, Humidity, Level, PinMode, Sensor, TemperatureCelsius, WaitTimeout};
+
+/// Example data, from the datasheet: 65.2% humidity and 35.1 degrees celsius
+const DATASHEET_BYTES: [u8; 5] = [0b0000_0010, 0b1000_1100, 0b0000_0001, 0b0101_1111, 0b1110_1110];
+
+/// Pin that answers every read with pulses encoding `DATASHEET_BYTES`
+#[derive(Debug, Default)]
+struct ReplayPin {
+    step: AtomicUsize,
+}
+
+impl DataPin for ReplayPin {
+    fn is_low(&self) -> bool {
+        false
+    }
+
+    fn is_high(&self) -> bool {
+        false
+    }
+
+    fn pin(&self) -> u8 {
+        17
+    }
+
+    fn set_high(&mut self) {}
+
+    fn set_low(&mut self) {}
+
+    fn set_mode(&mut self, mode: PinMode) {
+        // Reads start by driving the pin to signal the sensor
+        if mode == PinMode::Output {
+            self.step.store(0, Ordering::SeqCst);
+        }
+    }
+
+    fn wait_while_level(&self, level: Level, _max_cycles: u32) -> Result<u32, WaitTimeout> {
+        // The first low and high pulses are the response to the start signal, followed by
+        // a low and high pulse for each bit. High pulses longer than low ones are 1 bits.
+        let step = self.step.fetch_add(1, Ordering::SeqCst);
+        let bit = step.saturating_sub(2) / 2;
+        let on = DATASHEET_BYTES[bit / 8] & (0x80 >> (bit % 8)) != 0;
+
+        Ok(match level {
+            Level::High if step >= 2 && on => 600,
+            Level::High if step >= 2 => 200,
+            _ => 400,
+        })
+    }
+}
+
+#[test]
+fn test_dht22_sensor_without_rppal() {
+    let mut sensor = DHT22Sensor::builder(ReplayPin::default())
+        .wake_high_ms(0)
+        .start_low_ms(0)
+        .start_high_us(0)
+        .build();
+    let measurement = Sensor::read(&mut sensor).unwrap();
+
+    assert_eq!(TemperatureCelsius::from(35.1), measurement.temperature);
+    assert_eq!(Humidity::from(65.2), measurement.humidity);
+}
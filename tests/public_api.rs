@@ -0,0 +1,121 @@
+// Strudel - Temperature and humidity metrics exporter for Prometheus
+//
+// Copyright 2022 Nick Pillitteri
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! Build an exporter using only the prelude, to make sure everything needed to do so
+//! is public. Sensors are mocks so no GPIO pins are needed.
+
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use std::sync::Arc;
+use std::time::Duration;
+use strudel::prelude::*;
+use tower::ServiceExt;
+
+/// Sensor that always reads the same temperature and humidity
+#[derive(Debug)]
+struct FixedSensor;
+
+impl Sensor for FixedSensor {
+    fn read(&mut self) -> Result<Measurement, SensorError> {
+        Ok(Measurement {
+            temperature: TemperatureCelsius::from(21.5),
+            humidity: Humidity::from(40.0),
+        })
+    }
+}
+
+/// Pin that's always high so that reads of a DHT22 using it time out
+#[derive(Debug)]
+struct HighPin;
+
+impl DataPin for HighPin {
+    fn is_low(&self) -> bool {
+        false
+    }
+
+    fn is_high(&self) -> bool {
+        true
+    }
+
+    fn pin(&self) -> u8 {
+        17
+    }
+
+    fn set_high(&mut self) {}
+
+    fn set_low(&mut self) {}
+
+    fn set_mode(&mut self, _mode: PinMode) {}
+}
+
+async fn get(state: Arc<RequestState>, uri: &str) -> (StatusCode, String) {
+    let req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+    let res = router(state).oneshot(req).await.unwrap();
+    let status = res.status();
+    let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn test_prelude_exporter() {
+    let mut registries = Registries::new();
+    let metrics = Arc::new(TemperatureMetrics::with_unit(
+        registries.group("sensor"),
+        TemperatureUnit::Fahrenheit,
+    ));
+    let metrics_ref = metrics.clone();
+
+    let handle: WorkerHandle = SensorWorker::new(FixedSensor, Duration::from_secs(3600))
+        .on_read(move |event: &ReadingEvent| metrics_ref.update(event))
+        .start();
+
+    // Wait for the first read to be handled by every subscriber
+    let _ = handle.requester().request().await;
+    let state = Arc::new(RequestState::builder(registries, metrics.latest()).build());
+
+    let (status, body) = get(state.clone(), "/metrics").await;
+    assert_eq!(StatusCode::OK, status);
+    assert!(
+        body.contains("strudel_temperature_degrees{sensor=\"\"} 70.7\n"),
+        "{}",
+        body
+    );
+    assert!(
+        body.contains("strudel_relative_humidity{sensor=\"\"} 40.0\n"),
+        "{}",
+        body
+    );
+
+    let (status, body) = get(state, "/readings").await;
+    assert_eq!(StatusCode::OK, status);
+    assert!(body.contains("40.0"), "{}", body);
+
+    handle.shutdown().await;
+}
+
+#[test]
+fn test_prelude_dht22_sensor() {
+    let mut sensor: DHT22Sensor<HighPin> = DHT22Sensor::builder(HighPin).max_cycles(100).build();
+    let err = Sensor::read(&mut sensor).unwrap_err();
+
+    // New kinds of errors may be added so matching on them needs a wildcard
+    let retry = matches!(err.kind(), SensorErrorKind::ReadTimeout | SensorErrorKind::Checksum);
+
+    assert!(retry);
+    assert_eq!(SensorErrorKind::ReadTimeout, err.kind());
+}